@@ -0,0 +1,103 @@
+//! Multi-threaded increment throughput of plain prometheus `IntCounter` and
+//! `HistogramVec` versus `ShardedCounter` and `ShardedHistogramVec`.
+//!
+//! Run with `cargo bench --bench metric_contention`. Set `BENCH_THREADS` and
+//! `BENCH_ITERATIONS` to change the load.
+
+use prom_otel::sharded::{ShardedCounter, ShardedHistogramVec};
+use prometheus::{HistogramOpts, HistogramVec, IntCounter};
+use std::{
+    env,
+    hint::black_box,
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Runs `op` `iterations` times on each of `threads` threads, started together,
+/// and returns the wall-clock time from the first thread starting to the last
+/// one finishing. Timestamps are taken on the workers so the result doesn't
+/// depend on when the coordinating thread gets scheduled.
+fn contend<F>(threads: usize, iterations: usize, op: F) -> Duration
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let op = Arc::new(op);
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let op = op.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                let started = Instant::now();
+                for _ in 0..iterations {
+                    op();
+                }
+                (started, Instant::now())
+            })
+        })
+        .collect();
+
+    let spans: Vec<(Instant, Instant)> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    let first_start = spans.iter().map(|(start, _)| *start).min().unwrap();
+    let last_end = spans.iter().map(|(_, end)| *end).max().unwrap();
+    last_end - first_start
+}
+
+fn report(name: &str, threads: usize, iterations: usize, elapsed: Duration) {
+    let ops = (threads * iterations) as f64;
+    println!(
+        "{name:<16} threads={threads:<3} {:>8.2} ns/op {:>8.1} Mops/s",
+        elapsed.as_nanos() as f64 / ops,
+        ops / elapsed.as_secs_f64() / 1e6,
+    );
+}
+
+fn main() {
+    let max_threads = env_or(
+        "BENCH_THREADS",
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(4).max(4),
+    );
+    let iterations = env_or("BENCH_ITERATIONS", 2_000_000);
+
+    let mut threads = 1;
+    while threads <= max_threads {
+        let plain = IntCounter::new("plain", "plain").unwrap();
+        let p = plain.clone();
+        let elapsed = contend(threads, iterations, move || black_box(&p).inc());
+        assert_eq!(plain.get() as usize, threads * iterations);
+        report("IntCounter", threads, iterations, elapsed);
+
+        let sharded = ShardedCounter::new("sharded", "sharded").unwrap();
+        let s = sharded.clone();
+        let elapsed = contend(threads, iterations, move || black_box(&s).inc());
+        assert_eq!(sharded.get() as usize, threads * iterations);
+        report("ShardedCounter", threads, iterations, elapsed);
+
+        // Same shape as the request middleware: label lookup plus observe.
+        let opts = HistogramOpts::new("plain_seconds", "plain");
+        let plain = HistogramVec::new(opts, &["method", "route"]).unwrap();
+        let p = plain.clone();
+        let elapsed = contend(threads, iterations, move || {
+            black_box(&p).with_label_values(&["GET", "/"]).observe(0.004)
+        });
+        assert_eq!(plain.with_label_values(&["GET", "/"]).get_sample_count() as usize, threads * iterations);
+        report("HistogramVec", threads, iterations, elapsed);
+
+        let opts = HistogramOpts::new("sharded_seconds", "sharded");
+        let sharded = ShardedHistogramVec::new(opts, &["method", "route"]).unwrap();
+        let s = sharded.clone();
+        let elapsed = contend(threads, iterations, move || {
+            black_box(&s).with_label_values(&["GET", "/"]).observe(0.004)
+        });
+        assert_eq!(sharded.with_label_values(&["GET", "/"]).get_sample_count() as usize, threads * iterations);
+        report("ShardedHistogram", threads, iterations, elapsed);
+
+        threads *= 2;
+    }
+}
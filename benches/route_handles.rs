@@ -0,0 +1,75 @@
+//! Per-request metric overhead of `track_requests`: recording a request
+//! through the labeled vecs, as for routes resolved after startup, versus
+//! through the `RouteHandles` resolved by `AppMetrics::resolve_routes`.
+//!
+//! Run with `cargo bench --bench route_handles`. Set `BENCH_ROUTES` and
+//! `BENCH_ITERATIONS` to change the load.
+
+use prom_otel::{
+    config::Config,
+    metrics::AppMetrics,
+    middleware::RequestTracking,
+    responses::status_class,
+};
+use std::{
+    env,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// What `track_requests` records for a route without handles.
+fn observe_vecs(metrics: &AppMetrics, tracking: &RequestTracking, method: &str, route: &str, status: u16, elapsed: Duration) {
+    let labels = metrics.cardinality.limit("http_request_size_bytes", [method, route]);
+    metrics.body_sizes.request_histogram(&labels).observe(512.0);
+    let labels = metrics.cardinality.limit("http_response_size_bytes", [method, route]);
+    metrics.body_sizes.response_histogram(&labels).observe(2048.0);
+    let labels = metrics.cardinality.limit("http_request_duration_seconds", [method, route]);
+    metrics.http_request_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    metrics.responses.observe(metrics.cardinality.limit("http_responses_total", [method, route, status_class(status)]));
+    let [sli_route] = metrics.cardinality.limit("sli_requests_total", [route]);
+    metrics.sli.observe(&tracking.sli, sli_route, status, elapsed);
+    let failed = tracking.sli.bad_statuses.iter().any(|m| m.matches(status));
+    let [apdex_route] = metrics.cardinality.limit("apdex_total", [route]);
+    metrics.apdex.observe(&tracking.apdex, apdex_route, elapsed, failed);
+}
+
+/// The same through the route's handles.
+fn observe_handles(metrics: &AppMetrics, tracking: &RequestTracking, method: &str, route: &str, status: u16, elapsed: Duration) {
+    let handles = metrics.route_handles(method, route).unwrap();
+    handles.request_size(metrics).observe(512.0);
+    handles.response_size(metrics).observe(2048.0);
+    handles.observe(metrics, tracking, status, elapsed);
+}
+
+type Observe = fn(&AppMetrics, &RequestTracking, &str, &str, u16, Duration);
+
+fn run(name: &str, observe: Observe, metrics: &AppMetrics, tracking: &RequestTracking, routes: &[String], iterations: usize) {
+    let elapsed = Duration::from_millis(12);
+    let started = Instant::now();
+    for i in 0..iterations {
+        let route = &routes[i % routes.len()];
+        observe(black_box(metrics), tracking, "GET", black_box(route), 200, elapsed);
+    }
+    let took = started.elapsed();
+    println!("{name:<8} routes={:<4} {:>8.1} ns/request", routes.len(), took.as_nanos() as f64 / iterations as f64);
+}
+
+fn main() {
+    let routes: Vec<String> = (0..env_or("BENCH_ROUTES", 32)).map(|i| format!("/api/v1/resource{i}/{{id}}")).collect();
+    let iterations = env_or("BENCH_ITERATIONS", 1_000_000);
+    let tracking = RequestTracking::from_config(&Config::default());
+
+    let metrics = AppMetrics::new();
+    for route in &routes {
+        metrics.prime_route("GET", route);
+    }
+    run("vecs", observe_vecs, &metrics, &tracking, &routes, iterations);
+
+    let metrics = AppMetrics::new();
+    metrics.resolve_routes(routes.iter().map(|route| ("GET", route.as_str())));
+    run("handles", observe_handles, &metrics, &tracking, &routes, iterations);
+}
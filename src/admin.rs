@@ -0,0 +1,142 @@
+//! Admin API under `/admin`, mounted only when `ADMIN_TOKEN` is set. Every
+//! request must present it as a bearer token.
+
+use crate::{
+    audit::ChangeSource,
+    auth::EndpointAuth,
+    capabilities,
+    debug_session::{DebugSession, DebugSessionRequest, DebugSessions},
+    flags::SampleRatio,
+    metrics::AppMetrics,
+    switches::{self, SwitchesUpdate, TelemetrySwitches},
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use std::time::Instant;
+
+fn session_json(session: &DebugSession) -> Value {
+    json!({
+        "id": session.id,
+        "routes": session.routes,
+        "sample": session.sample,
+        "level": session.level.as_str().to_lowercase(),
+        "expires_in_secs": session.expires_at.saturating_duration_since(Instant::now()).as_secs(),
+        "matched_requests": session.matched_requests(),
+    })
+}
+
+/// `POST /admin/debug-session`: starts a time-boxed debug session, see
+/// `DebugSessionRequest::from_json` for the body.
+async fn start_debug_session(
+    req: HttpRequest,
+    body: web::Bytes,
+    auth: web::Data<EndpointAuth>,
+    sessions: web::Data<DebugSessions>,
+    metrics: web::Data<AppMetrics>,
+) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    match DebugSessionRequest::from_json(&body) {
+        Ok(request) => {
+            let session = sessions.start(request);
+            metrics.config_changes.record(ChangeSource::Admin, "debug_session", "none", &session.to_string());
+            HttpResponse::Created().json(session_json(&session))
+        }
+        Err(e) => HttpResponse::BadRequest().json(json!({ "error": e })),
+    }
+}
+
+/// `GET /admin/debug-session`: lists the sessions still running.
+async fn list_debug_sessions(
+    req: HttpRequest,
+    auth: web::Data<EndpointAuth>,
+    sessions: web::Data<DebugSessions>,
+) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    let active: Vec<Value> = sessions.active().iter().map(|session| session_json(session)).collect();
+    HttpResponse::Ok().json(active)
+}
+
+/// `GET /admin/capabilities`: the cargo features compiled in, see
+/// `capabilities::report`.
+async fn get_capabilities(req: HttpRequest, auth: web::Data<EndpointAuth>) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    HttpResponse::Ok().json(capabilities::report())
+}
+
+/// `GET /admin/telemetry`: whether spans and logs are exported and the
+/// runtime sample ratio, see `switches`.
+async fn get_telemetry(
+    req: HttpRequest,
+    auth: web::Data<EndpointAuth>,
+    switches: web::Data<TelemetrySwitches>,
+    sample_ratio: web::Data<SampleRatio>,
+) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    HttpResponse::Ok().json(switches::state_json(&switches, &sample_ratio))
+}
+
+/// `PUT /admin/telemetry`: changes the settings present in the body, see
+/// `SwitchesUpdate::from_json`, and returns the new state.
+async fn put_telemetry(
+    req: HttpRequest,
+    body: web::Bytes,
+    auth: web::Data<EndpointAuth>,
+    switches: web::Data<TelemetrySwitches>,
+    sample_ratio: web::Data<SampleRatio>,
+    metrics: web::Data<AppMetrics>,
+) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    let update = match SwitchesUpdate::from_json(&body) {
+        Ok(update) => update,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e })),
+    };
+    let changes = &metrics.config_changes;
+    if let Some(enabled) = update.traces_export {
+        let old = switches.traces_export();
+        switches.set_traces_export(enabled);
+        changes.record(ChangeSource::Admin, "traces_export", &old.to_string(), &enabled.to_string());
+    }
+    if let Some(enabled) = update.logs_export {
+        let old = switches.logs_export();
+        switches.set_logs_export(enabled);
+        changes.record(ChangeSource::Admin, "logs_export", &old.to_string(), &enabled.to_string());
+    }
+    if let Some(ratio) = update.sample_ratio {
+        let describe = |ratio: Option<f64>| ratio.map_or_else(|| "configured".to_string(), |ratio| ratio.to_string());
+        let old = sample_ratio.get();
+        sample_ratio.set(ratio);
+        changes.record(ChangeSource::Admin, "trace_sample_ratio", &describe(old), &describe(ratio));
+    }
+    HttpResponse::Ok().json(switches::state_json(&switches, &sample_ratio))
+}
+
+/// Methods and route templates of `scope`.
+pub const ROUTES: &[(&str, &str)] = &[
+    ("POST", "/admin/debug-session"),
+    ("GET", "/admin/debug-session"),
+    ("GET", "/admin/capabilities"),
+    ("GET", "/admin/telemetry"),
+    ("PUT", "/admin/telemetry"),
+];
+
+/// The `/admin` scope. `auth` is scoped to it, so it doesn't clash with the
+/// `/metrics` credentials registered on the app.
+pub fn scope(auth: web::Data<EndpointAuth>) -> actix_web::Scope {
+    web::scope("/admin")
+        .app_data(auth)
+        .route("/debug-session", web::post().to(start_debug_session))
+        .route("/debug-session", web::get().to(list_debug_sessions))
+        .route("/capabilities", web::get().to(get_capabilities))
+        .route("/telemetry", web::get().to(get_telemetry))
+        .route("/telemetry", web::put().to(put_telemetry))
+}
@@ -0,0 +1,187 @@
+//! Recommended Prometheus recording and alerting rules.
+//!
+//! The rules reference the exact metric names registered in `metrics`,
+//! `sli`, `slo`, `apdex`, `dependencies` and `pipeline`, and take their
+//! thresholds from the effective configuration, so
+//! `app emit-alerts > rules.yaml`, or `app --emit-alerts > rules.yaml`,
+//! gives a working starting point. Adjust the `for:` durations and ratios to
+//! your own SLOs.
+
+use crate::config::Config;
+
+/// Share of bad SLI requests (5 minute rate) above which the error rate alert fires.
+const BAD_REQUEST_RATIO: f64 = 0.05;
+/// Share of the file descriptor limit above which the FD alert fires.
+const FD_SATURATION_RATIO: f64 = 0.8;
+/// Share of the cgroup memory limit above which the memory alert fires.
+const MEMORY_SATURATION_RATIO: f64 = 0.9;
+/// Seconds without a successful export before an exporter counts as stuck.
+const EXPORT_STALE_SECS: u64 = 300;
+/// Share of successful calls to a dependency (5 minute window) below which
+/// the dependency alert fires.
+const DEPENDENCY_AVAILABILITY_RATIO: f64 = 0.95;
+/// Burn rates over the 1h and 5m windows above which an SLO pages: 2% of a
+/// 30-day error budget spent in an hour.
+const FAST_BURN_RATE: f64 = 14.4;
+/// Burn rates over the 6h and 30m windows above which an SLO opens a ticket:
+/// 5% of a 30-day error budget spent in six hours.
+const SLOW_BURN_RATE: f64 = 6.0;
+
+/// The rules as a Prometheus rule file (YAML).
+pub fn rules(config: &Config) -> String {
+    let service = yaml_string(&config.service_name);
+    let latency_threshold = config.sli_latency_threshold.as_secs_f64();
+    let mut rules = format!(
+        r#"# Generated by `emit-alerts` for service {service}.
+groups:
+  - name: prom_otel.recording
+    rules:
+      - record: route:sli_requests:rate5m
+        expr: sum by (route) (rate(sli_requests_total[5m]))
+      - record: route:sli_requests_good:ratio_rate5m
+        expr: |
+          sum by (route) (rate(sli_requests_good_total[5m]))
+            / sum by (route) (rate(sli_requests_total[5m]))
+      - record: route:http_request_duration_seconds:p99_5m
+        expr: histogram_quantile(0.99, sum by (le, route) (rate(http_request_duration_seconds_bucket[5m])))
+      - record: route:apdex:score_rate5m
+        expr: |
+          (
+            sum by (route) (rate(apdex_satisfied_total[5m]))
+              + sum by (route) (rate(apdex_tolerating_total[5m])) / 2
+          ) / sum by (route) (rate(apdex_total[5m]))
+
+  - name: prom_otel.alerts
+    rules:
+      - alert: HighErrorRate
+        expr: 1 - route:sli_requests_good:ratio_rate5m > {BAD_REQUEST_RATIO}
+        for: 10m
+        labels:
+          severity: page
+          service: {service}
+        annotations:
+          summary: "More than {bad_pct}% of requests to {{{{ $labels.route }}}} are bad"
+          description: "Requests count as bad when they return one of {bad_statuses} or take longer than {latency_ms}ms."
+      - alert: LatencySloBreached
+        expr: route:http_request_duration_seconds:p99_5m > {latency_threshold}
+        for: 10m
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "p99 latency of {{{{ $labels.route }}}} is above {latency_ms}ms"
+      - alert: TelemetryExportFailing
+        expr: increase(otel_exporter_failures_total[10m]) > 0
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "OTLP {{{{ $labels.signal }}}} exports are failing after retries"
+      - alert: TelemetryExportStale
+        expr: |
+          otel_exporter_last_success_timestamp_seconds > 0
+            and time() - otel_exporter_last_success_timestamp_seconds > {EXPORT_STALE_SECS}
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "No successful OTLP {{{{ $labels.signal }}}} export for {EXPORT_STALE_SECS}s"
+      - alert: TelemetryQueueDropping
+        expr: increase(otel_exporter_queue_dropped_total[10m]) > 0
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "{{{{ $labels.signal }}}} are dropped because the export queue is full"
+      - alert: MetricCardinalityOverflow
+        expr: increase(metrics_cardinality_dropped_total[1h]) > 0
+        labels:
+          severity: info
+          service: {service}
+        annotations:
+          summary: "{{{{ $labels.metric }}}} hit its limit of {max_label_sets} label sets"
+      - alert: AppPanicking
+        expr: increase(app_panics_total[10m]) > 0
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "The app panicked; see the `Panic` error logs for the message and backtrace"
+      - alert: FileDescriptorSaturation
+        expr: process_open_fds / process_max_fds > {FD_SATURATION_RATIO}
+        for: 5m
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "More than {fd_pct}% of the file descriptor limit is in use"
+      - alert: ContainerMemoryNearLimit
+        expr: container_memory_utilization_ratio > {MEMORY_SATURATION_RATIO}
+        for: 5m
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "Memory usage is above {memory_pct}% of the container limit; an OOM kill is likely"
+      - alert: DependencyDegraded
+        expr: dependency_availability_ratio < {DEPENDENCY_AVAILABILITY_RATIO}
+        for: 5m
+        labels:
+          severity: warning
+          service: {service}
+        annotations:
+          summary: "Fewer than {dependency_pct}% of the calls to {{{{ $labels.dependency }}}} succeed, retries included"
+"#,
+        bad_pct = BAD_REQUEST_RATIO * 100.0,
+        fd_pct = FD_SATURATION_RATIO * 100.0,
+        memory_pct = MEMORY_SATURATION_RATIO * 100.0,
+        dependency_pct = DEPENDENCY_AVAILABILITY_RATIO * 100.0,
+        latency_ms = config.sli_latency_threshold.as_millis(),
+        bad_statuses = config
+            .sli_bad_statuses
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", "),
+        max_label_sets = config.metrics_max_label_sets,
+    );
+    if !config.slos.is_empty() {
+        rules.push_str(&format!(
+            r#"      - alert: SloFastBurn
+        expr: slo_burn_rate{{window="1h"}} > {FAST_BURN_RATE} and ignoring(window) slo_burn_rate{{window="5m"}} > {FAST_BURN_RATE}
+        labels:
+          severity: page
+          service: {service}
+        annotations:
+          summary: "The {{{{ $labels.objective }}}} SLO of {{{{ $labels.route }}}} burns its error budget {FAST_BURN_RATE} times too fast"
+      - alert: SloSlowBurn
+        expr: slo_burn_rate{{window="6h"}} > {SLOW_BURN_RATE} and ignoring(window) slo_burn_rate{{window="30m"}} > {SLOW_BURN_RATE}
+        labels:
+          severity: ticket
+          service: {service}
+        annotations:
+          summary: "The {{{{ $labels.objective }}}} SLO of {{{{ $labels.route }}}} burns its error budget {SLOW_BURN_RATE} times too fast"
+"#
+        ));
+    }
+    rules
+}
+
+/// `value` as a double-quoted YAML scalar.
+fn yaml_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,72 @@
+//! jemalloc statistics (`jemalloc` feature).
+//!
+//! RSS alone can't tell live heap data from memory the allocator holds on to
+//! after fragmentation. The binary uses jemalloc as its global allocator when
+//! the feature is enabled; embedders of the library have to install
+//! `tikv_jemallocator::Jemalloc` themselves, otherwise every gauge reads 0.
+
+use crate::registry::Registry;
+use prometheus::Gauge;
+use tikv_jemalloc_ctl::{epoch, stats};
+
+#[derive(Clone, Debug)]
+pub struct AllocatorMetrics {
+    pub allocated_bytes: Gauge,
+    pub active_bytes: Gauge,
+    pub resident_bytes: Gauge,
+    pub retained_bytes: Gauge,
+    pub fragmentation_ratio: Gauge,
+}
+
+impl AllocatorMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let allocated_bytes = Gauge::new("allocator_allocated_bytes", "Bytes allocated by the application")?;
+        let active_bytes = Gauge::new("allocator_active_bytes", "Bytes in pages the allocator uses for allocations")?;
+        let resident_bytes = Gauge::new(
+            "allocator_resident_bytes",
+            "Bytes in physically resident pages mapped by the allocator, including metadata",
+        )?;
+        let retained_bytes =
+            Gauge::new("allocator_retained_bytes", "Bytes in virtual memory the allocator kept instead of unmapping")?;
+        let fragmentation_ratio = Gauge::new(
+            "allocator_fragmentation_ratio",
+            "Share of active pages not holding allocations, 1 - allocated / active",
+        )?;
+
+        registry.register(Box::new(allocated_bytes.clone()))?;
+        registry.register(Box::new(active_bytes.clone()))?;
+        registry.register(Box::new(resident_bytes.clone()))?;
+        registry.register(Box::new(retained_bytes.clone()))?;
+        registry.register(Box::new(fragmentation_ratio.clone()))?;
+
+        Ok(Self {
+            allocated_bytes,
+            active_bytes,
+            resident_bytes,
+            retained_bytes,
+            fragmentation_ratio,
+        })
+    }
+
+    /// Refreshes jemalloc's cached statistics and updates the gauges.
+    pub fn sample(&self) {
+        if epoch::advance().is_err() {
+            return;
+        }
+        let (Ok(allocated), Ok(active), Ok(resident), Ok(retained)) = (
+            stats::allocated::read(),
+            stats::active::read(),
+            stats::resident::read(),
+            stats::retained::read(),
+        ) else {
+            return;
+        };
+        self.allocated_bytes.set(allocated as f64);
+        self.active_bytes.set(active as f64);
+        self.resident_bytes.set(resident as f64);
+        self.retained_bytes.set(retained as f64);
+        if active > 0 {
+            self.fragmentation_ratio.set(1.0 - allocated as f64 / active as f64);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Per-route Apdex counters.
+//!
+//! Each request is counted in `apdex_total{route}` and, depending on its
+//! latency, in `apdex_satisfied_total{route}` or `apdex_tolerating_total{route}`;
+//! the score is `(satisfied + tolerating / 2) / total`. Requests with a status
+//! that counts against the SLI are always frustrated.
+
+use crate::registry::Registry;
+use prometheus::{IntCounter, IntCounterVec, Opts};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// Latency up to which a request is satisfied, and up to which it is still
+/// tolerated. Apdex defines the latter as four times the former.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApdexThresholds {
+    pub satisfied: Duration,
+    pub tolerating: Duration,
+}
+
+impl ApdexThresholds {
+    /// Thresholds for target `t`, tolerating up to `4t`.
+    pub fn from_target(t: Duration) -> Self {
+        Self { satisfied: t, tolerating: t * 4 }
+    }
+}
+
+impl fmt::Display for ApdexThresholds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.satisfied.as_millis(), self.tolerating.as_millis())
+    }
+}
+
+/// Thresholds for one route template, written `/route=satisfied_ms` or
+/// `/route=satisfied_ms:tolerating_ms`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteApdex {
+    pub route: String,
+    pub thresholds: ApdexThresholds,
+}
+
+impl FromStr for RouteApdex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("expected `/route=satisfied_ms` or `/route=satisfied_ms:tolerating_ms`, got {s:?}");
+        let (route, thresholds) = s.rsplit_once('=').ok_or_else(invalid)?;
+        let ms = |v: &str| v.trim().parse::<u64>().map(Duration::from_millis).map_err(|_| invalid());
+        let thresholds = match thresholds.split_once(':') {
+            Some((satisfied, tolerating)) => ApdexThresholds {
+                satisfied: ms(satisfied)?,
+                tolerating: ms(tolerating)?,
+            },
+            None => ApdexThresholds::from_target(ms(thresholds)?),
+        };
+        if route.trim().is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self { route: route.trim().to_string(), thresholds })
+    }
+}
+
+impl fmt::Display for RouteApdex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.route, self.thresholds)
+    }
+}
+
+/// Default thresholds plus per-route overrides.
+#[derive(Clone, Debug)]
+pub struct ApdexCriteria {
+    pub default: ApdexThresholds,
+    pub routes: Vec<RouteApdex>,
+}
+
+impl ApdexCriteria {
+    pub fn thresholds(&self, route: &str) -> ApdexThresholds {
+        self.routes
+            .iter()
+            .find(|r| r.route == route)
+            .map_or(self.default, |r| r.thresholds)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ApdexMetrics {
+    satisfied: IntCounterVec,
+    tolerating: IntCounterVec,
+    total: IntCounterVec,
+}
+
+impl ApdexMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let satisfied = IntCounterVec::new(
+            Opts::new("apdex_satisfied_total", "Requests within the satisfied Apdex threshold"),
+            &["route"],
+        )?;
+        let tolerating = IntCounterVec::new(
+            Opts::new("apdex_tolerating_total", "Requests within the tolerating but not the satisfied Apdex threshold"),
+            &["route"],
+        )?;
+        let total = IntCounterVec::new(Opts::new("apdex_total", "Requests considered for Apdex"), &["route"])?;
+        registry.register(Box::new(satisfied.clone()))?;
+        registry.register(Box::new(tolerating.clone()))?;
+        registry.register(Box::new(total.clone()))?;
+        Ok(Self { satisfied, tolerating, total })
+    }
+
+    /// Creates the series for `route` at zero.
+    pub fn prime(&self, route: &str) {
+        self.satisfied.with_label_values(&[route]);
+        self.tolerating.with_label_values(&[route]);
+        self.total.with_label_values(&[route]);
+    }
+
+    /// Records one request; `failed` requests are frustrated whatever their
+    /// latency.
+    pub fn observe(&self, criteria: &ApdexCriteria, route: &str, elapsed: Duration, failed: bool) {
+        self.handles(route).observe(criteria.thresholds(route), elapsed, failed);
+    }
+
+    /// Every child of `route`, creating them at zero so the score can be
+    /// computed from the first request on.
+    pub fn handles(&self, route: &str) -> ApdexHandles {
+        ApdexHandles {
+            satisfied: self.satisfied.with_label_values(&[route]),
+            tolerating: self.tolerating.with_label_values(&[route]),
+            total: self.total.with_label_values(&[route]),
+        }
+    }
+}
+
+/// The children of one route, see `route_handles`.
+#[derive(Clone, Debug)]
+pub struct ApdexHandles {
+    satisfied: IntCounter,
+    tolerating: IntCounter,
+    total: IntCounter,
+}
+
+impl ApdexHandles {
+    /// Records one request against `thresholds`, see `ApdexMetrics::observe`.
+    pub fn observe(&self, thresholds: ApdexThresholds, elapsed: Duration, failed: bool) {
+        self.total.inc();
+        if failed {
+            return;
+        }
+        if elapsed <= thresholds.satisfied {
+            self.satisfied.inc();
+        } else if elapsed <= thresholds.tolerating {
+            self.tolerating.inc();
+        }
+    }
+}
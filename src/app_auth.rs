@@ -0,0 +1,488 @@
+//! Bearer token authentication of application routes (`APP_AUTH_TOKENS`,
+//! `APP_AUTH_JWKS_URL`).
+//!
+//! `authenticate`, wrapped around the routes of the app (not the built-in
+//! endpoints, see `auth`), requires an `Authorization: Bearer` header
+//! carrying either one of the static tokens of `APP_AUTH_TOKENS`, each
+//! naming its principal, or, with the `oidc` feature, a JWT signed by a key
+//! of the JWKS at `APP_AUTH_JWKS_URL` (RS256/384/512, PS256/384/512,
+//! ES256/384). A JWT must carry `exp`, must not be expired or not yet valid
+//! (with a minute of leeway), and must carry `APP_AUTH_ISSUER` and
+//! `APP_AUTH_AUDIENCE` when they are set; its principal is the
+//! `APP_AUTH_PRINCIPAL_CLAIM` claim (`sub` by default). The key set is
+//! fetched on first use, refreshed every 10 minutes and, at most every 30 s,
+//! when a token names a key it lacks. Tokens whose key is known are checked
+//! against the current keys while a refresh is in flight.
+//!
+//! Every decision is counted in `auth_requests_total{outcome}`:
+//! `authenticated`, `missing` (no bearer token), `invalid`, `expired` or
+//! `unavailable` (the key set couldn't be fetched, answered `503`). The
+//! others are answered `401`. With the `traces` feature the server span gets
+//! `auth.outcome`, `auth.method` (`token` or `jwt`) and `enduser.id`, which
+//! the span exporter redacts like any attribute (see `redact`), so listing
+//! `enduser.id` in `REDACT_FIELDS` keeps principals out of traces. Handlers
+//! find the `Principal` in the request extensions.
+
+use crate::{config::Config, registry::Registry};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+#[cfg(feature = "traces")]
+use opentelemetry::{trace::get_active_span, KeyValue};
+use prometheus::{IntCounterVec, Opts};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "oidc")]
+use serde_json::Value;
+use std::{fmt, str::FromStr};
+
+pub const DEFAULT_PRINCIPAL_CLAIM: &str = "sub";
+
+/// A static token and the principal it authenticates, written
+/// `principal=token`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppToken {
+    pub principal: String,
+    pub token: String,
+}
+
+impl FromStr for AppToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (principal, token) = s.split_once('=').ok_or_else(|| "expected principal=token".to_string())?;
+        if principal.is_empty() || token.is_empty() {
+            return Err("principal and token must not be empty".to_string());
+        }
+        Ok(Self { principal: principal.to_string(), token: token.to_string() })
+    }
+}
+
+// Keeps tokens out of `Debug` output and `validate-config`.
+impl fmt::Debug for AppToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for AppToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=***", self.principal)
+    }
+}
+
+/// The authenticated caller, in the request extensions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    pub id: String,
+    pub method: AuthMethod,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    Token,
+    Jwt,
+}
+
+impl AuthMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Token => "token",
+            Self::Jwt => "jwt",
+        }
+    }
+}
+
+/// Why a request wasn't authenticated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Missing,
+    Invalid,
+    Expired,
+    Unavailable,
+}
+
+impl Rejection {
+    pub const ALL: [Rejection; 4] = [Rejection::Missing, Rejection::Invalid, Rejection::Expired, Rejection::Unavailable];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Missing => "missing",
+            Self::Invalid => "invalid",
+            Self::Expired => "expired",
+            Self::Unavailable => "unavailable",
+        }
+    }
+
+    fn response(self) -> HttpResponse {
+        let challenge = match self {
+            Self::Missing => "Bearer realm=\"app\"".to_string(),
+            Self::Invalid | Self::Expired | Self::Unavailable => {
+                format!("Bearer realm=\"app\", error=\"invalid_token\", error_description=\"{}\"", self.as_str())
+            }
+        };
+        match self {
+            Self::Unavailable => HttpResponse::ServiceUnavailable().finish(),
+            _ => HttpResponse::Unauthorized().insert_header((header::WWW_AUTHENTICATE, challenge)).finish(),
+        }
+    }
+}
+
+/// The tokens and key set requests are checked against; shared by the
+/// workers.
+#[derive(Debug)]
+pub struct AppAuth {
+    tokens: Vec<AppToken>,
+    #[cfg(feature = "oidc")]
+    jwt: Option<jwt::JwtValidator>,
+    requests: IntCounterVec,
+}
+
+impl AppAuth {
+    /// `None` unless `APP_AUTH_TOKENS` or `APP_AUTH_JWKS_URL` is set.
+    pub fn from_config(config: &Config, registry: &Registry) -> prometheus::Result<Option<Self>> {
+        if !config.app_auth_enabled() {
+            return Ok(None);
+        }
+        let requests = IntCounterVec::new(
+            Opts::new("auth_requests_total", "Authentication decisions on application routes by outcome"),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        requests.with_label_values(&["authenticated"]);
+        for rejection in Rejection::ALL {
+            requests.with_label_values(&[rejection.as_str()]);
+        }
+        Ok(Some(Self {
+            tokens: config.app_auth_tokens.clone(),
+            #[cfg(feature = "oidc")]
+            jwt: config.app_auth_jwks_url.as_ref().map(|url| jwt::JwtValidator::new(url.clone(), config)),
+            requests,
+        }))
+    }
+
+    /// Checks the `Authorization` header value `authorization`.
+    pub async fn check(&self, authorization: Option<&str>) -> Result<Principal, Rejection> {
+        let token = authorization
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token)
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .ok_or(Rejection::Missing)?;
+        // Every static token is compared, so timing doesn't tell which
+        // one a guess was close to.
+        let matched = self.tokens.iter().fold(None, |matched, known| {
+            if crate::auth::constant_time_eq(known.token.as_bytes(), token.as_bytes()) {
+                Some(known)
+            } else {
+                matched
+            }
+        });
+        if let Some(known) = matched {
+            return Ok(Principal { id: known.principal.clone(), method: AuthMethod::Token });
+        }
+        #[cfg(feature = "oidc")]
+        if let Some(jwt) = &self.jwt {
+            return jwt.validate(token).await.map(|id| Principal { id, method: AuthMethod::Jwt });
+        }
+        Err(Rejection::Invalid)
+    }
+}
+
+/// Authenticates the request against the `AppAuth` in the app data, if
+/// any, answering `401` (or `503`) when it can't be.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(auth) = req.app_data::<web::Data<AppAuth>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let outcome = auth.check(authorization).await;
+    let name = outcome.as_ref().map_or_else(|rejection| rejection.as_str(), |_| "authenticated");
+    auth.requests.with_label_values(&[name]).inc();
+    #[cfg(feature = "traces")]
+    get_active_span(|span| {
+        span.set_attribute(KeyValue::new("auth.outcome", name));
+        if let Ok(principal) = &outcome {
+            span.set_attribute(KeyValue::new("auth.method", principal.method.as_str()));
+            span.set_attribute(KeyValue::new("enduser.id", principal.id.clone()));
+        }
+    });
+    match outcome {
+        Ok(principal) => {
+            req.extensions_mut().insert(principal);
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+        Err(rejection) => Ok(req.into_response(rejection.response()).map_into_right_body()),
+    }
+}
+
+#[cfg(feature = "oidc")]
+mod jwt {
+    use super::{Rejection, Value};
+    use crate::config::Config;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+    use serde::Deserialize;
+    use std::{
+        collections::HashMap,
+        sync::Mutex,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+    use tracing::warn;
+
+    /// How long a fetched key set is used before it is fetched again.
+    const REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+    /// Minimum delay between two fetches for keys a token names.
+    const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+    const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Clock skew tolerated on `exp` and `nbf`.
+    const LEEWAY_SECS: f64 = 60.0;
+
+    #[derive(Debug, Deserialize)]
+    struct Jwks {
+        keys: Vec<Jwk>,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct Jwk {
+        kid: Option<String>,
+        kty: String,
+        alg: Option<String>,
+        #[serde(rename = "use")]
+        usage: Option<String>,
+        n: Option<String>,
+        e: Option<String>,
+        crv: Option<String>,
+        x: Option<String>,
+        y: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Header {
+        alg: String,
+        kid: Option<String>,
+    }
+
+    #[derive(Debug, Default)]
+    struct Keys {
+        by_kid: HashMap<Option<String>, Vec<Jwk>>,
+        fetched: Option<Instant>,
+    }
+
+    #[derive(Debug)]
+    pub struct JwtValidator {
+        client: reqwest::Client,
+        url: String,
+        issuer: Option<String>,
+        audience: Option<String>,
+        principal_claim: String,
+        keys: Mutex<Keys>,
+        /// Held while fetching, so one request fetches at a time.
+        refresh: tokio::sync::Mutex<()>,
+    }
+
+    impl JwtValidator {
+        pub fn new(url: String, config: &Config) -> Self {
+            Self {
+                client: reqwest::Client::new(),
+                url,
+                issuer: config.app_auth_issuer.clone(),
+                audience: config.app_auth_audience.clone(),
+                principal_claim: config.app_auth_principal_claim.clone(),
+                keys: Mutex::new(Keys::default()),
+                refresh: tokio::sync::Mutex::new(()),
+            }
+        }
+
+        /// The principal of `token`, after checking its signature and claims.
+        pub async fn validate(&self, token: &str) -> Result<String, Rejection> {
+            let mut parts = token.split('.');
+            let (Some(header), Some(payload), Some(signature), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(Rejection::Invalid);
+            };
+            let header: Header = decode_json(header)?;
+            let claims: Value = decode_json(payload)?;
+            let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| Rejection::Invalid)?;
+            let signed = &token[..header_and_payload_len(token)];
+            let candidates = self.keys_for(&header.kid).await?;
+            let verified = candidates.iter().any(|key| verify(&header.alg, key, signed.as_bytes(), &signature));
+            if !verified {
+                return Err(Rejection::Invalid);
+            }
+            self.check_claims(&claims)?;
+            match claims.get(&self.principal_claim) {
+                Some(Value::String(id)) if !id.is_empty() => Ok(id.clone()),
+                Some(Value::Number(id)) => Ok(id.to_string()),
+                _ => Err(Rejection::Invalid),
+            }
+        }
+
+        fn check_claims(&self, claims: &Value) -> Result<(), Rejection> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+            // A token without `exp` would be valid forever.
+            let exp = claims.get("exp").and_then(Value::as_f64).ok_or(Rejection::Invalid)?;
+            if exp + LEEWAY_SECS < now {
+                return Err(Rejection::Expired);
+            }
+            if claims.get("nbf").and_then(Value::as_f64).is_some_and(|nbf| nbf - LEEWAY_SECS > now) {
+                return Err(Rejection::Invalid);
+            }
+            if let Some(issuer) = &self.issuer
+                && claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str())
+            {
+                return Err(Rejection::Invalid);
+            }
+            if let Some(audience) = &self.audience {
+                let matches = match claims.get("aud") {
+                    Some(Value::String(aud)) => aud == audience,
+                    Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+                    _ => false,
+                };
+                if !matches {
+                    return Err(Rejection::Invalid);
+                }
+            }
+            Ok(())
+        }
+
+        /// The keys `kid` may refer to, fetching the key set when it is stale
+        /// or lacks `kid`. A failed fetch keeps the previous keys. The keys
+        /// aren't locked during the fetch, and tokens whose key is known
+        /// don't wait for one in flight.
+        async fn keys_for(&self, kid: &Option<String>) -> Result<Vec<Jwk>, Rejection> {
+            let (candidates, due) = self.snapshot(kid);
+            if due {
+                let _refresh = match self.refresh.try_lock() {
+                    Ok(refresh) => refresh,
+                    Err(_) if !candidates.is_empty() => return Ok(candidates),
+                    Err(_) => self.refresh.lock().await,
+                };
+                // Another request may have fetched while this one waited.
+                if self.snapshot(kid).1 {
+                    self.refresh().await?;
+                }
+            }
+            let (candidates, _) = self.snapshot(kid);
+            if candidates.is_empty() {
+                return Err(Rejection::Invalid);
+            }
+            Ok(candidates)
+        }
+
+        /// The keys `kid` may refer to, and whether the key set should be
+        /// fetched for it.
+        fn snapshot(&self, kid: &Option<String>) -> (Vec<Jwk>, bool) {
+            let keys = self.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let age = keys.fetched.map(|fetched| fetched.elapsed());
+            let candidates = lookup(&keys, kid);
+            let stale = age.is_none_or(|age| age >= REFRESH_INTERVAL);
+            let unknown = candidates.is_empty() && age.is_none_or(|age| age >= MIN_REFETCH_INTERVAL);
+            (candidates, stale || unknown)
+        }
+
+        /// Fetches the key set and swaps it in; `Unavailable` when it has
+        /// never been fetched.
+        async fn refresh(&self) -> Result<(), Rejection> {
+            match self.fetch().await {
+                Ok(jwks) => {
+                    let mut by_kid: HashMap<Option<String>, Vec<Jwk>> = HashMap::new();
+                    let signing = |key: &Jwk| key.usage.as_deref().is_none_or(|usage| usage == "sig");
+                    for key in jwks.keys.into_iter().filter(signing) {
+                        by_kid.entry(key.kid.clone()).or_default().push(key);
+                    }
+                    *self.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                        Keys { by_kid, fetched: Some(Instant::now()) };
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!(url = %self.url, error = %e, "Cannot fetch the JWKS");
+                    let keys = self.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    if keys.fetched.is_none() {
+                        return Err(Rejection::Unavailable);
+                    }
+                    Ok(())
+                }
+            }
+        }
+
+        async fn fetch(&self) -> Result<Jwks, String> {
+            let response = self
+                .client
+                .get(&self.url)
+                .timeout(FETCH_TIMEOUT)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| e.to_string())?;
+            let body = response.bytes().await.map_err(|e| e.to_string())?;
+            serde_json::from_slice(&body).map_err(|e| format!("invalid JWKS: {e}"))
+        }
+    }
+
+    /// The keys with id `kid` or, for a token without one, every key.
+    fn lookup(keys: &Keys, kid: &Option<String>) -> Vec<Jwk> {
+        match kid {
+            Some(_) => keys.by_kid.get(kid).cloned().unwrap_or_default(),
+            None => keys.by_kid.values().flatten().cloned().collect(),
+        }
+    }
+
+    fn header_and_payload_len(token: &str) -> usize {
+        token.rfind('.').unwrap_or(0)
+    }
+
+    fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, Rejection> {
+        let bytes = URL_SAFE_NO_PAD.decode(part).map_err(|_| Rejection::Invalid)?;
+        serde_json::from_slice(&bytes).map_err(|_| Rejection::Invalid)
+    }
+
+    fn decode(value: &Option<String>) -> Option<Vec<u8>> {
+        URL_SAFE_NO_PAD.decode(value.as_deref()?).ok()
+    }
+
+    /// Whether `signature` of `message` checks out with `key` under `alg`;
+    /// `none` and the HMAC algorithms, which a key set can't serve, never do.
+    fn verify(alg: &str, key: &Jwk, message: &[u8], signature: &[u8]) -> bool {
+        if key.alg.as_deref().is_some_and(|key_alg| key_alg != alg) {
+            return false;
+        }
+        let rsa: Option<&signature::RsaParameters> = match alg {
+            "RS256" => Some(&signature::RSA_PKCS1_2048_8192_SHA256),
+            "RS384" => Some(&signature::RSA_PKCS1_2048_8192_SHA384),
+            "RS512" => Some(&signature::RSA_PKCS1_2048_8192_SHA512),
+            "PS256" => Some(&signature::RSA_PSS_2048_8192_SHA256),
+            "PS384" => Some(&signature::RSA_PSS_2048_8192_SHA384),
+            "PS512" => Some(&signature::RSA_PSS_2048_8192_SHA512),
+            _ => None,
+        };
+        if let Some(params) = rsa {
+            let (Some(n), Some(e)) = (decode(&key.n), decode(&key.e)) else {
+                return false;
+            };
+            return key.kty == "RSA" && RsaPublicKeyComponents { n, e }.verify(params, message, signature).is_ok();
+        }
+        let (ec, curve): (&signature::EcdsaVerificationAlgorithm, _) = match alg {
+            "ES256" => (&signature::ECDSA_P256_SHA256_FIXED, "P-256"),
+            "ES384" => (&signature::ECDSA_P384_SHA384_FIXED, "P-384"),
+            _ => return false,
+        };
+        let (Some(x), Some(y)) = (decode(&key.x), decode(&key.y)) else {
+            return false;
+        };
+        if key.kty != "EC" || key.crv.as_deref() != Some(curve) {
+            return false;
+        }
+        // Uncompressed SEC1 point.
+        let point = [&[0x04], x.as_slice(), y.as_slice()].concat();
+        UnparsedPublicKey::new(ec, point).verify(message, signature).is_ok()
+    }
+}
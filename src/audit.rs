@@ -0,0 +1,219 @@
+//! Audit trail of security-relevant events.
+//!
+//! `audit!` logs an event at `info` under the `audit` target with an
+//! `audit.event` field naming it. The crate audits rejected credentials and
+//! peers (`access_denied`, see `EndpointAuth::check`), every authorized use
+//! of the admin endpoints (`admin_access`) and configuration changes made at
+//! runtime (`config_change`).
+//!
+//! Every configuration change (a debug session started through the admin
+//! API, a TLS certificate reloaded on `SIGHUP`, a flag applied by
+//! `flags::watch`, a sampling strategy applied by `remote_sampling::watch`,
+//! a setting of a reloaded `CONFIG_FILE`) is logged with its source, setting and old and new values, counted in
+//! `config_changes_total{source}` and, with the `traces` feature, exported as
+//! a `config_change` span. Values of settings that look like secrets are
+//! masked.
+//!
+//! With `AUDIT_LOGS` set, audit events leave the OTLP log stream for one of
+//! their own: a logger provider whose resource carries `log.stream="audit"`
+//! and whose `AuditLogProcessor` exports each record as soon as it is
+//! emitted. They bypass `RUST_LOG`, log sampling and the admin API's
+//! `logs_export` switch, and a flush or shutdown returns only once they are
+//! exported.
+
+use crate::{redact::REDACTED, registry::Registry};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{Span as _, Tracer},
+    KeyValue,
+};
+#[cfg(feature = "logs")]
+use opentelemetry::InstrumentationScope;
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::{
+    error::{OTelSdkError, OTelSdkResult},
+    logs::{LogExporter, LogProcessor, SdkLogRecord, SimpleLogProcessor},
+    Resource,
+};
+use prometheus::{IntCounterVec, Opts};
+use std::fmt;
+#[cfg(feature = "logs")]
+use std::{sync::mpsc, thread, time::Duration};
+
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+/// Target of audit events.
+pub const TARGET: &str = "audit";
+
+/// Logs a security-relevant event to the audit trail, e.g.
+/// `audit!("role_granted", user.id = id, role, "Role granted")`: an `info`
+/// event under the `audit` target with `audit.event` set to the first
+/// argument, the other ones passed to `tracing::info!`.
+#[macro_export]
+macro_rules! audit {
+    ($event:literal, $($arg:tt)+) => {
+        $crate::audit::__tracing::event!(target: $crate::audit::TARGET, $crate::audit::__tracing::Level::INFO, audit.event = $event, $($arg)+)
+    };
+}
+
+/// Where a change came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeSource {
+    Admin,
+    Sighup,
+    Flags,
+    RemoteSampling,
+    ConfigFile,
+}
+
+impl ChangeSource {
+    pub const ALL: [ChangeSource; 5] = [
+        ChangeSource::Admin,
+        ChangeSource::Sighup,
+        ChangeSource::Flags,
+        ChangeSource::RemoteSampling,
+        ChangeSource::ConfigFile,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeSource::Admin => "admin",
+            ChangeSource::Sighup => "sighup",
+            ChangeSource::Flags => "flags",
+            ChangeSource::RemoteSampling => "remote_sampling",
+            ChangeSource::ConfigFile => "config_file",
+        }
+    }
+}
+
+impl fmt::Display for ChangeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Name fragments of settings whose values must not be logged.
+const SECRET_MARKERS: &[&str] = &["token", "secret", "password", "credential", "key", "auth"];
+
+fn is_secret(setting: &str) -> bool {
+    let setting = setting.to_ascii_lowercase();
+    SECRET_MARKERS.iter().any(|marker| setting.contains(marker))
+}
+
+/// Records configuration changes; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct ConfigChanges {
+    total: IntCounterVec,
+}
+
+impl ConfigChanges {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let total = IntCounterVec::new(
+            Opts::new("config_changes_total", "Configuration changes applied at runtime"),
+            &["source"],
+        )?;
+        registry.register(Box::new(total.clone()))?;
+        for source in ChangeSource::ALL {
+            total.with_label_values(&[source.as_str()]);
+        }
+        Ok(Self { total })
+    }
+
+    /// Records that `setting` went from `old` to `new`.
+    pub fn record(&self, source: ChangeSource, setting: &str, old: &str, new: &str) {
+        let (old, new) = if is_secret(setting) { (REDACTED, REDACTED) } else { (old, new) };
+        self.total.with_label_values(&[source.as_str()]).inc();
+        crate::audit!("config_change", source = source.as_str(), setting, old, new, "Config changed");
+        #[cfg(feature = "traces")]
+        {
+            let tracer = global::tracer("prom_otel");
+            tracer
+                .span_builder("config_change")
+                .with_attributes([
+                    KeyValue::new("config.source", source.as_str()),
+                    KeyValue::new("config.setting", setting.to_string()),
+                    KeyValue::new("config.old_value", old.to_string()),
+                    KeyValue::new("config.new_value", new.to_string()),
+                ])
+                .start(&tracer)
+                .end();
+        }
+    }
+}
+
+#[cfg(feature = "logs")]
+enum Command {
+    Emit(Box<SdkLogRecord>, InstrumentationScope),
+    SetResource(Resource),
+    Flush(mpsc::Sender<OTelSdkResult>),
+    Shutdown(Duration, mpsc::Sender<OTelSdkResult>),
+}
+
+/// Exports every record on its own as soon as it is emitted, without a
+/// queue that could drop it. Records are handed to an export thread, since
+/// the blocking HTTP client can't run on the async threads emitting them;
+/// `force_flush` and shutdown wait for that thread to catch up.
+#[cfg(feature = "logs")]
+#[derive(Debug)]
+pub struct AuditLogProcessor {
+    commands: mpsc::Sender<Command>,
+}
+
+#[cfg(feature = "logs")]
+impl AuditLogProcessor {
+    pub fn new<E: LogExporter + 'static>(exporter: E) -> Self {
+        let (commands, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("audit-log-export".to_string())
+            .spawn(move || export_each(SimpleLogProcessor::new(exporter), received))
+            .expect("Failed to start the audit log export thread");
+        Self { commands }
+    }
+
+    /// Sends `command` built around a reply channel and waits up to `timeout`
+    /// for the reply.
+    fn request(&self, command: impl FnOnce(mpsc::Sender<OTelSdkResult>) -> Command, timeout: Duration) -> OTelSdkResult {
+        let (reply, outcome) = mpsc::channel();
+        self.commands.send(command(reply)).map_err(|_| OTelSdkError::AlreadyShutdown)?;
+        outcome.recv_timeout(timeout).unwrap_or(Err(OTelSdkError::Timeout(timeout)))
+    }
+}
+
+#[cfg(feature = "logs")]
+fn export_each<E: LogExporter>(mut processor: SimpleLogProcessor<E>, commands: mpsc::Receiver<Command>) {
+    for command in commands {
+        match command {
+            Command::Emit(mut record, scope) => processor.emit(&mut record, &scope),
+            Command::SetResource(resource) => processor.set_resource(&resource),
+            Command::Flush(reply) => {
+                let _ = reply.send(processor.force_flush());
+            }
+            Command::Shutdown(timeout, reply) => {
+                let _ = reply.send(processor.shutdown_with_timeout(timeout));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "logs")]
+impl LogProcessor for AuditLogProcessor {
+    fn emit(&self, record: &mut SdkLogRecord, instrumentation: &InstrumentationScope) {
+        let _ = self.commands.send(Command::Emit(Box::new(record.clone()), instrumentation.clone()));
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        // Records are exported in order, so the reply comes after theirs.
+        self.request(Command::Flush, Duration::MAX)
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.request(|reply| Command::Shutdown(timeout, reply), timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        let _ = self.commands.send(Command::SetResource(resource.clone()));
+    }
+}
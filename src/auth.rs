@@ -0,0 +1,263 @@
+//! Access control for the `/metrics` and admin endpoints.
+//!
+//! Callers can be required to present a bearer token or basic auth
+//! credentials and/or to come from an allowlisted network. Both checks are
+//! off unless configured. Every rejected request and every authorized use
+//! of the admin endpoints is audited (see `audit`).
+
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// An IPv4 or IPv6 network in CIDR notation; a bare address is a /32 or /128.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Match IPv4 clients that reach a dual-stack listener as ::ffff:a.b.c.d.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(u32::from(net).into(), u32::from(addr).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(u128::from(net), u128::from(addr), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    net >> shift == addr >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| format!("invalid IP address {addr:?}"))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("invalid prefix length {len:?} for {addr}"))?,
+            None => max,
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Credentials a scrape must present in its `Authorization` header.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrapeCredentials {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl ScrapeCredentials {
+    /// Parses `user:password` as used by `METRICS_BASIC_AUTH`.
+    pub fn basic(s: &str) -> Result<Self, String> {
+        let (username, password) = s
+            .split_once(':')
+            .ok_or_else(|| "expected user:password".to_string())?;
+        if username.is_empty() {
+            return Err("username must not be empty".to_string());
+        }
+        Ok(Self::Basic { username: username.to_string(), password: password.to_string() })
+    }
+
+    /// The `Authorization` header value for these credentials.
+    pub(crate) fn expected_header(&self) -> String {
+        match self {
+            Self::Bearer(token) => format!("Bearer {token}"),
+            Self::Basic { username, password } => format!("Basic {}", STANDARD.encode(format!("{username}:{password}"))),
+        }
+    }
+
+    fn scheme(&self) -> &'static str {
+        match self {
+            Self::Bearer(_) => "Bearer",
+            Self::Basic { .. } => "Basic",
+        }
+    }
+}
+
+// Keeps secrets out of `Debug` output and `validate-config`.
+impl fmt::Debug for ScrapeCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for ScrapeCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bearer(_) => write!(f, "bearer"),
+            Self::Basic { username, .. } => write!(f, "basic ({username})"),
+        }
+    }
+}
+
+/// Why a scrape was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// Missing or wrong credentials; carries the expected auth scheme.
+    Unauthorized(&'static str),
+    /// The peer address is not in the allowlist.
+    Forbidden,
+}
+
+impl Denied {
+    /// The 401/403 response for this rejection; `realm` names the protected
+    /// area in `WWW-Authenticate`.
+    pub fn response(&self, realm: &str) -> HttpResponse {
+        match self {
+            Denied::Unauthorized(scheme) => HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, format!("{scheme} realm=\"{realm}\"")))
+                .finish(),
+            Denied::Forbidden => HttpResponse::Forbidden().finish(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self {
+            Denied::Unauthorized(_) => "unauthorized",
+            Denied::Forbidden => "forbidden",
+        }
+    }
+}
+
+/// Clones share their rules, so `set` applies to every copy.
+#[derive(Clone, Debug, Default)]
+pub struct EndpointAuth {
+    rules: Arc<RwLock<Rules>>,
+    /// Audits authorized requests too, not only rejected ones.
+    audit_access: bool,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    credentials: Option<ScrapeCredentials>,
+    expected_header: Option<String>,
+    allowed_networks: Vec<Cidr>,
+}
+
+impl Rules {
+    fn new(credentials: Option<ScrapeCredentials>, allowed_networks: Vec<Cidr>) -> Self {
+        Self { expected_header: credentials.as_ref().map(ScrapeCredentials::expected_header), credentials, allowed_networks }
+    }
+}
+
+impl EndpointAuth {
+    pub fn new(credentials: Option<ScrapeCredentials>, allowed_networks: Vec<Cidr>) -> Self {
+        Self { rules: Arc::new(RwLock::new(Rules::new(credentials, allowed_networks))), audit_access: false }
+    }
+
+    /// Replaces the credentials and allowlist, e.g. on a config reload.
+    pub fn set(&self, credentials: Option<ScrapeCredentials>, allowed_networks: Vec<Cidr>) {
+        *self.rules.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Rules::new(credentials, allowed_networks);
+    }
+
+    /// Scrape access to `/metrics`.
+    pub fn metrics(config: &crate::config::Config) -> Self {
+        Self::new(config.metrics_credentials.clone(), config.metrics_allowed_ips.clone())
+    }
+
+    /// Access to `/admin/*`; `None` when no admin token is configured, in
+    /// which case the admin API is not mounted at all.
+    pub fn admin(config: &crate::config::Config) -> Option<Self> {
+        let token = config.admin_token.clone()?;
+        Some(Self { audit_access: true, ..Self::new(Some(ScrapeCredentials::Bearer(token)), Vec::new()) })
+    }
+
+    /// Checks the peer address against the allowlist, then the credentials.
+    /// The peer is the direct TCP peer; `X-Forwarded-For` is not trusted.
+    pub fn check(&self, req: &HttpRequest) -> Result<(), Denied> {
+        let outcome = self.authorize(req);
+        let peer = req.peer_addr().map(|peer| peer.ip().to_string()).unwrap_or_default();
+        match &outcome {
+            Err(denied) => crate::audit!(
+                "access_denied",
+                http.request.method = req.method().as_str(),
+                url.path = req.path(),
+                client.address = peer,
+                reason = denied.reason(),
+                "Access denied"
+            ),
+            Ok(()) if self.audit_access => crate::audit!(
+                "admin_access",
+                http.request.method = req.method().as_str(),
+                url.path = req.path(),
+                client.address = peer,
+                "Admin endpoint used"
+            ),
+            Ok(()) => {}
+        }
+        outcome
+    }
+
+    fn authorize(&self, req: &HttpRequest) -> Result<(), Denied> {
+        let rules = self.rules.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !rules.allowed_networks.is_empty() {
+            let allowed = req
+                .peer_addr()
+                .is_some_and(|peer| rules.allowed_networks.iter().any(|net| net.contains(peer.ip())));
+            if !allowed {
+                return Err(Denied::Forbidden);
+            }
+        }
+        if let (Some(credentials), Some(expected)) = (&rules.credentials, &rules.expected_header) {
+            let presented = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .map(|value| value.as_bytes())
+                .unwrap_or_default();
+            // The scheme is case-insensitive (RFC 9110), the credentials are not.
+            let scheme = credentials.scheme().as_bytes();
+            let matches = presented.len() > scheme.len()
+                && presented[..scheme.len()].eq_ignore_ascii_case(scheme)
+                && constant_time_eq(&presented[scheme.len()..], &expected.as_bytes()[scheme.len()..]);
+            if !matches {
+                return Err(Denied::Unauthorized(credentials.scheme()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares without short-circuiting so response timing doesn't leak how much
+/// of a guessed secret was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
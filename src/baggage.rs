@@ -0,0 +1,166 @@
+//! W3C baggage.
+//!
+//! With the `traces` feature, `track_requests` extracts the `baggage` header
+//! of every request into the context the handler runs in. Handlers read
+//! entries with `get`, add entries for the calls they make with
+//! `with_entries`, and pass the baggage on to outbound requests with
+//! `inject`.
+//!
+//! The keys listed in `BAGGAGE_KEYS` are also copied onto every span started
+//! in such a context (by `BaggageSpanProcessor`) and onto the labels of
+//! `http_requests_baggage_total{method,route,<key>...}`, each key's
+//! characters other than ASCII letters and digits replaced with underscores.
+//! Baggage is set by callers, so its values go through the cardinality
+//! limiter like routes do.
+
+use crate::{cardinality::CardinalityLimiter, registry::Registry};
+use opentelemetry::{baggage::Baggage, StringValue};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    baggage::{BaggageExt, KeyValueMetadata},
+    context::{FutureExt, WithContext},
+    propagation::{Extractor, Injector, TextMapPropagator},
+    trace::Span as _,
+    Context, Key, KeyValue,
+};
+#[cfg(feature = "traces")]
+pub use opentelemetry_http::HeaderInjector;
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    propagation::BaggagePropagator,
+    trace::{Span, SpanData, SpanProcessor},
+};
+use prometheus::{IntCounterVec, Opts};
+#[cfg(feature = "traces")]
+use std::{future::Future, time::Duration};
+
+/// Prometheus label name of baggage `key`.
+pub fn label_name(key: &str) -> String {
+    let label: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if label.starts_with(|c: char| c.is_ascii_digit()) { format!("_{label}") } else { label }
+}
+
+/// Adapts actix-web's header map to the OpenTelemetry propagators.
+#[cfg(feature = "traces")]
+struct HeaderExtractor<'a>(&'a actix_web::http::header::HeaderMap);
+
+#[cfg(feature = "traces")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// The current context with the baggage of the `baggage` header in
+/// `headers`.
+#[cfg(feature = "traces")]
+pub fn extract(headers: &actix_web::http::header::HeaderMap) -> Context {
+    BaggagePropagator::new().extract(&HeaderExtractor(headers))
+}
+
+/// Value of baggage entry `key` in the current context.
+#[cfg(feature = "traces")]
+pub fn get(key: &str) -> Option<String> {
+    Context::current().baggage().get(key).map(StringValue::to_string)
+}
+
+/// Runs `future` with `entries` added to the current baggage, replacing
+/// entries with the same keys.
+#[cfg(feature = "traces")]
+pub fn with_entries<F, K, V>(entries: impl IntoIterator<Item = (K, V)>, future: F) -> WithContext<F>
+where
+    F: Future,
+    K: Into<Key>,
+    V: Into<StringValue>,
+{
+    let cx = Context::current();
+    let mut baggage: Baggage = cx
+        .baggage()
+        .iter()
+        .map(|(key, (value, metadata))| KeyValueMetadata::new(key.clone(), value.clone(), metadata.clone()))
+        .collect();
+    for (key, value) in entries {
+        baggage.insert(key, value);
+    }
+    future.with_context(cx.with_baggage(baggage))
+}
+
+/// Writes the current baggage into the headers of an outbound request, e.g.
+/// `baggage::inject(&mut HeaderInjector(request.headers_mut()))` for reqwest.
+#[cfg(feature = "traces")]
+pub fn inject(injector: &mut dyn Injector) {
+    BaggagePropagator::new().inject_context(&Context::current(), injector);
+}
+
+/// Copies the configured baggage entries of a span's parent context onto
+/// the span as attributes.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct BaggageSpanProcessor {
+    keys: Vec<String>,
+}
+
+#[cfg(feature = "traces")]
+impl BaggageSpanProcessor {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self { keys }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl SpanProcessor for BaggageSpanProcessor {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        let baggage = cx.baggage();
+        for key in &self.keys {
+            if let Some(value) = baggage.get(key) {
+                span.set_attribute(KeyValue::new(key.clone(), value.clone()));
+            }
+        }
+    }
+
+    fn on_end(&self, _span: SpanData) {}
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// `http_requests_baggage_total`, registered when `BAGGAGE_KEYS` is set.
+#[derive(Clone, Debug)]
+pub struct BaggageMetrics {
+    keys: Vec<String>,
+    requests: IntCounterVec,
+}
+
+impl BaggageMetrics {
+    pub fn new(registry: &Registry, keys: &[String]) -> prometheus::Result<Self> {
+        let labels: Vec<String> =
+            ["method".to_string(), "route".to_string()].into_iter().chain(keys.iter().map(|key| label_name(key))).collect();
+        let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_baggage_total", "HTTP requests by route template and selected baggage entries"),
+            &labels,
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        Ok(Self { keys: keys.to_vec(), requests })
+    }
+
+    /// Counts a request; keys missing from `baggage` get an empty value.
+    pub fn observe(&self, cardinality: &CardinalityLimiter, method: &str, route: &str, baggage: &Baggage) {
+        let values: Vec<&str> = [method, route]
+            .into_iter()
+            .chain(self.keys.iter().map(|key| baggage.get(key).map_or("", StringValue::as_str)))
+            .collect();
+        let values = cardinality.limit_vec("http_requests_baggage_total", values);
+        self.requests.with_label_values(&values).inc();
+    }
+}
@@ -0,0 +1,173 @@
+//! Request and response body sizes.
+//!
+//! `http_request_size_bytes{method,route}` and
+//! `http_response_size_bytes{method,route}` are histograms of the bodies of
+//! tracked requests, to find the routes that move the most data. A request
+//! with a `Content-Length` is observed at that size; a streamed (chunked)
+//! one by counting its payload as the handler reads it, observed when the
+//! payload is dropped. Response bodies are counted as they are sent, so
+//! streamed ones are measured too; a response cut short by the client is
+//! observed at the size sent until then.
+
+use crate::{registry::Registry, sharded::{ShardedHistogram, ShardedHistogramVec}};
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{Payload, ServiceRequest},
+    error::PayloadError,
+    http::header,
+    web::Bytes,
+    HttpMessage,
+};
+use futures_util::Stream;
+use prometheus::{exponential_buckets, HistogramOpts};
+use std::{
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[derive(Clone, Debug)]
+pub struct BodySizeMetrics {
+    requests: ShardedHistogramVec,
+    responses: ShardedHistogramVec,
+}
+
+impl BodySizeMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        // 64 B to 16 MiB.
+        let buckets = exponential_buckets(64.0, 4.0, 10)?;
+        let requests = ShardedHistogramVec::new(
+            HistogramOpts::new("http_request_size_bytes", "HTTP request body size by route template")
+                .buckets(buckets.clone()),
+            &["method", "route"],
+        )?;
+        let responses = ShardedHistogramVec::new(
+            HistogramOpts::new("http_response_size_bytes", "HTTP response body size by route template")
+                .buckets(buckets),
+            &["method", "route"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(responses.clone()))?;
+        Ok(Self { requests, responses })
+    }
+
+    /// Observes the body of `req` under `labels`, now if its length is
+    /// known, or else by counting its payload as it is read.
+    pub fn count_request(&self, req: &mut ServiceRequest, labels: &[&str; 2]) {
+        count_request_into(req, self.request_histogram(labels));
+    }
+
+    /// `body`, observed under `labels` once sent.
+    pub fn count_response(&self, body: impl MessageBody + 'static, labels: &[&str; 2]) -> SizedBody {
+        SizedBody::counted(body, self.response_histogram(labels))
+    }
+
+    /// The `http_request_size_bytes` child of `labels`.
+    pub fn request_histogram(&self, labels: &[&str; 2]) -> ShardedHistogram {
+        self.requests.with_label_values(labels)
+    }
+
+    /// The `http_response_size_bytes` child of `labels`.
+    pub fn response_histogram(&self, labels: &[&str; 2]) -> ShardedHistogram {
+        self.responses.with_label_values(labels)
+    }
+}
+
+/// Like `BodySizeMetrics::count_request`, into an already resolved child.
+pub fn count_request_into(req: &mut ServiceRequest, histogram: ShardedHistogram) {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    match length {
+        Some(length) => histogram.observe(length as f64),
+        None => {
+            let payload = req.take_payload();
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(CountedPayload { payload, bytes: 0, histogram }),
+            });
+        }
+    }
+}
+
+/// A request payload counting the bytes read from it.
+struct CountedPayload {
+    payload: Payload,
+    bytes: u64,
+    histogram: ShardedHistogram,
+}
+
+impl Stream for CountedPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.payload).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &polled {
+            self.bytes += chunk.len() as u64;
+        }
+        polled
+    }
+}
+
+impl Drop for CountedPayload {
+    fn drop(&mut self) {
+        self.histogram.observe(self.bytes as f64);
+    }
+}
+
+/// A response body counting the bytes sent, observed when it is dropped;
+/// `track_requests` responses on excluded routes aren't counted.
+pub struct SizedBody {
+    body: BoxBody,
+    bytes: u64,
+    histogram: Option<ShardedHistogram>,
+}
+
+impl SizedBody {
+    /// `body`, not counted.
+    pub fn uncounted(body: impl MessageBody + 'static) -> Self {
+        Self { body: body.boxed(), bytes: 0, histogram: None }
+    }
+
+    /// `body`, observed into `histogram` once sent.
+    pub fn counted(body: impl MessageBody + 'static, histogram: ShardedHistogram) -> Self {
+        Self { body: body.boxed(), bytes: 0, histogram: Some(histogram) }
+    }
+}
+
+impl MessageBody for SizedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let polled = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &polled {
+            self.bytes += chunk.len() as u64;
+        }
+        polled
+    }
+
+    fn try_into_bytes(mut self) -> Result<Bytes, Self> {
+        match mem::replace(&mut self.body, BoxBody::new(())).try_into_bytes() {
+            Ok(bytes) => {
+                self.bytes = bytes.len() as u64;
+                Ok(bytes)
+            }
+            Err(body) => {
+                self.body = body;
+                Err(self)
+            }
+        }
+    }
+}
+
+impl Drop for SizedBody {
+    fn drop(&mut self) {
+        if let Some(histogram) = &self.histogram {
+            histogram.observe(self.bytes as f64);
+        }
+    }
+}
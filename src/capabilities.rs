@@ -0,0 +1,149 @@
+//! Cargo features compiled into this binary.
+//!
+//! Settings that need a feature the build lacks fail validation, but
+//! operators shouldn't have to find out on startup which settings an
+//! artifact honors. The report is served as JSON on `GET /admin/capabilities`
+//! and printed by the `capabilities` subcommand, along with the platform and
+//! the system series its backend adds (see `platform`).
+
+use crate::platform::{Platform, PLATFORM_METRICS};
+use serde_json::{json, Value};
+
+/// A cargo feature, whether it is compiled in, and the settings and
+/// endpoints it provides.
+#[derive(Clone, Copy, Debug)]
+pub struct Capability {
+    pub feature: &'static str,
+    pub enabled: bool,
+    pub settings: &'static [&'static str],
+    pub endpoints: &'static [&'static str],
+}
+
+pub const CAPABILITIES: &[Capability] = &[
+    Capability {
+        feature: "traces",
+        enabled: cfg!(feature = "traces"),
+        settings: &["OTEL_BSP_*", "TRACE_RESPONSE_HEADER", "BAGGAGE_KEYS", "SPAN_METRICS", "TAIL_SAMPLING*"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "metrics",
+        enabled: cfg!(feature = "metrics"),
+        settings: &[
+            "OTEL_METRIC_EXPORT_INTERVAL",
+            "OTEL_METRIC_EXPORT_TIMEOUT",
+            "OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE",
+            "METRIC_VIEWS",
+            "METRICS_OTLP_BRIDGE",
+        ],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "logs",
+        enabled: cfg!(feature = "logs"),
+        settings: &["OTEL_BLRP_*", "LOG_RATE_LIMIT_PER_SEC", "LOG_DEBUG_SAMPLE_RATIO", "AUDIT_LOGS"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "tls",
+        enabled: cfg!(feature = "tls"),
+        settings: &["TLS_CERT_PATH", "TLS_KEY_PATH"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "gateway",
+        enabled: cfg!(feature = "gateway"),
+        settings: &["OTLP_RECEIVER", "OTLP_RECEIVER_ALLOWED_IPS"],
+        endpoints: &["/v1/traces", "/v1/logs", "/v1/metrics"],
+    },
+    Capability {
+        feature: "otlp-grpc",
+        enabled: cfg!(feature = "otlp-grpc"),
+        settings: &["OTEL_EXPORTER_OTLP_PROTOCOL=grpc", "OTEL_EXPORTER_OTLP_*_PROTOCOL=grpc"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "zstd",
+        enabled: cfg!(feature = "zstd"),
+        settings: &["OTEL_EXPORTER_OTLP_COMPRESSION=zstd", "OTEL_EXPORTER_OTLP_*_COMPRESSION=zstd"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "grpc-health",
+        enabled: cfg!(feature = "grpc-health"),
+        settings: &["GRPC_HEALTH_ADDR"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "federation",
+        enabled: cfg!(feature = "federation"),
+        settings: &["FEDERATE_TARGETS", "FEDERATE_INTERVAL_SECS"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "flags",
+        enabled: cfg!(feature = "flags"),
+        settings: &["FLAGS_URL", "FLAGS_SDK_KEY", "FLAGS_POLL_INTERVAL_SECS"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "remote-sampling",
+        enabled: cfg!(feature = "remote-sampling"),
+        settings: &[
+            "REMOTE_SAMPLING_URL",
+            "REMOTE_SAMPLING_INTERVAL_SECS",
+            "REMOTE_SAMPLING_INITIAL_RATIO",
+            "OTEL_TRACES_SAMPLER=jaeger_remote",
+        ],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "pprof",
+        enabled: cfg!(feature = "pprof"),
+        settings: &["SLOW_REQUEST_PROFILE"],
+        endpoints: &["/debug/pprof/profile"],
+    },
+    Capability {
+        feature: "pyroscope",
+        enabled: cfg!(feature = "pyroscope"),
+        settings: &["PYROSCOPE_URL", "PYROSCOPE_AUTH_TOKEN", "PYROSCOPE_BASIC_AUTH", "PYROSCOPE_UPLOAD_INTERVAL_SECS"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "oidc",
+        enabled: cfg!(feature = "oidc"),
+        settings: &["APP_AUTH_JWKS_URL", "APP_AUTH_ISSUER", "APP_AUTH_AUDIENCE", "APP_AUTH_PRINCIPAL_CLAIM"],
+        endpoints: &[],
+    },
+    Capability {
+        feature: "jemalloc",
+        enabled: cfg!(feature = "jemalloc"),
+        settings: &[],
+        endpoints: &["/debug/pprof/heap", "/debug/allocations"],
+    },
+    Capability { feature: "kafka", enabled: cfg!(feature = "kafka"), settings: &[], endpoints: &[] },
+    Capability { feature: "redis", enabled: cfg!(feature = "redis"), settings: &[], endpoints: &[] },
+    Capability { feature: "sqlx", enabled: cfg!(feature = "sqlx"), settings: &[], endpoints: &[] },
+    Capability { feature: "testing", enabled: cfg!(feature = "testing"), settings: &[], endpoints: &[] },
+];
+
+/// `{"version", "platform": {"os", "system_metrics"}, "features": [{"name",
+/// "enabled", "settings", "endpoints"}, ...]}`.
+pub fn report() -> Value {
+    let features: Vec<Value> = CAPABILITIES
+        .iter()
+        .map(|capability| {
+            json!({
+                "name": capability.feature,
+                "enabled": capability.enabled,
+                "settings": capability.settings,
+                "endpoints": capability.endpoints,
+            })
+        })
+        .collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "platform": {"os": Platform::name(), "system_metrics": PLATFORM_METRICS},
+        "features": features,
+    })
+}
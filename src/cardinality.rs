@@ -0,0 +1,99 @@
+//! Caps the number of distinct label sets per labeled metric.
+//!
+//! Label values derived from requests (methods, routes, later tenants or
+//! clients) can't always be trusted to stay bounded. Once a metric has seen
+//! `max_label_sets` distinct combinations, further new combinations are
+//! recorded under `other` and counted in `metrics_cardinality_dropped_total`.
+
+use crate::registry::Registry;
+use prometheus::{IntCounterVec, Opts};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::{Arc, RwLock},
+};
+
+/// Label value used for every label of an overflowing series.
+pub const OVERFLOW_LABEL_VALUE: &str = "other";
+
+pub const DEFAULT_MAX_LABEL_SETS: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct CardinalityLimiter {
+    max_label_sets: usize,
+    // Label sets are tracked by hash; a collision only means two series
+    // share one slot of the budget.
+    seen: Arc<RwLock<HashMap<&'static str, HashSet<u64>>>>,
+    dropped: IntCounterVec,
+}
+
+impl CardinalityLimiter {
+    pub fn new(registry: &Registry, max_label_sets: usize) -> prometheus::Result<Self> {
+        let dropped = IntCounterVec::new(
+            Opts::new(
+                "metrics_cardinality_dropped_total",
+                "Observations recorded under the overflow label set because the metric hit its label set limit",
+            ),
+            &["metric"],
+        )?;
+        registry.register(Box::new(dropped.clone()))?;
+        Ok(Self {
+            max_label_sets,
+            seen: Arc::new(RwLock::new(HashMap::new())),
+            dropped,
+        })
+    }
+
+    pub fn max_label_sets(&self) -> usize {
+        self.max_label_sets
+    }
+
+    /// Returns `values` if the label set is already known or still fits in
+    /// the budget for `metric`, otherwise the overflow label set.
+    pub fn limit<'a, const N: usize>(&self, metric: &'static str, values: [&'a str; N]) -> [&'a str; N] {
+        if self.admit(metric, &values) { values } else { [OVERFLOW_LABEL_VALUE; N] }
+    }
+
+    /// Like `limit`, for metrics whose number of labels is only known at
+    /// runtime.
+    pub fn limit_vec<'a>(&self, metric: &'static str, values: Vec<&'a str>) -> Vec<&'a str> {
+        if self.admit(metric, &values) { values } else { vec![OVERFLOW_LABEL_VALUE; values.len()] }
+    }
+
+    /// Whether `values` is known or fits in the budget for `metric`; counts
+    /// the drop if not.
+    fn admit(&self, metric: &'static str, values: &[&str]) -> bool {
+        let hash = hash_values(values);
+        {
+            let seen = self.seen.read().unwrap();
+            if let Some(sets) = seen.get(metric) {
+                if sets.contains(&hash) {
+                    return true;
+                }
+                if sets.len() >= self.max_label_sets {
+                    drop(seen);
+                    self.dropped.with_label_values(&[metric]).inc();
+                    return false;
+                }
+            }
+        }
+        let mut seen = self.seen.write().unwrap();
+        let sets = seen.entry(metric).or_default();
+        if sets.contains(&hash) || sets.len() < self.max_label_sets {
+            sets.insert(hash);
+            true
+        } else {
+            drop(seen);
+            self.dropped.with_label_values(&[metric]).inc();
+            false
+        }
+    }
+}
+
+fn hash_values(values: &[&str]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for value in values {
+        value.hash(&mut hasher);
+    }
+    hasher.finish()
+}
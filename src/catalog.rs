@@ -0,0 +1,81 @@
+//! Machine-readable catalog of the metric families exposed on `/metrics`.
+//!
+//! Served as JSON on `/metrics/catalog`, printed by `print-metric-catalog`
+//! and, with `METRICS_CATALOG_PATH` set, written to a file on startup, so a
+//! governance check can validate names before a deploy. The catalog lists
+//! the families the registered collectors describe (see `registry`), labeled
+//! ones included before they have a series, plus those only known from a
+//! gather, like federated families. `series` counts label sets, a
+//! histogram or summary counting once per label set however many buckets or
+//! quantiles it has, to audit cardinality.
+
+use crate::registry::Registry;
+use prometheus::proto::MetricType;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Base units spelled out as name suffixes, per the Prometheus naming
+/// conventions.
+const UNITS: &[&str] = &["seconds", "bytes", "ratio", "percent", "celsius", "meters", "volts", "amperes", "joules"];
+
+pub(crate) fn type_name(kind: MetricType) -> &'static str {
+    match kind {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::SUMMARY => "summary",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::UNTYPED => "untyped",
+    }
+}
+
+/// The unit suffix of `name`, ignoring a trailing `_total`.
+fn unit(name: &str) -> Option<&'static str> {
+    let name = name.strip_suffix("_total").unwrap_or(name);
+    UNITS.iter().copied().find(|unit| name.ends_with(&format!("_{unit}")))
+}
+
+/// `{"metrics": [{"name", "type", "help", "labels", "unit", "series"}, ...]}`,
+/// sorted by name. `unit` is `null` for unitless metrics.
+pub fn catalog(registry: &Registry) -> Value {
+    let gathered = registry.gather();
+    let series = |name: &str| {
+        gathered.iter().find(|family| family.name() == name).map_or(0, |family| family.get_metric().len())
+    };
+    let mut metrics: BTreeMap<String, Value> = registry
+        .described()
+        .into_iter()
+        .map(|family| {
+            let entry = json!({
+                "name": family.name,
+                "type": type_name(family.kind),
+                "help": family.help,
+                "labels": family.labels,
+                "unit": unit(&family.name),
+                "series": series(&family.name),
+            });
+            (family.name, entry)
+        })
+        .collect();
+    // Families no collector describes, e.g. federated or merged ones, as
+    // gathered.
+    for family in &gathered {
+        if metrics.contains_key(family.name()) {
+            continue;
+        }
+        let labels: Vec<&str> = family
+            .get_metric()
+            .first()
+            .map(|metric| metric.get_label().iter().map(|label| label.name()).collect())
+            .unwrap_or_default();
+        let entry = json!({
+            "name": family.name(),
+            "type": type_name(family.get_field_type()),
+            "help": family.help(),
+            "labels": labels,
+            "unit": unit(family.name()),
+            "series": family.get_metric().len(),
+        });
+        metrics.insert(family.name().to_string(), entry);
+    }
+    json!({ "metrics": metrics.into_values().collect::<Vec<_>>() })
+}
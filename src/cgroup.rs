@@ -0,0 +1,209 @@
+//! Memory and CPU limits of the container, read from cgroup v1 or v2.
+//!
+//! `app_memory_bytes` is the process RSS, which says nothing about how close
+//! the container is to being OOM-killed or throttled. These gauges expose the
+//! cgroup's own view: memory limit and usage, CPU quota and throttling.
+//! Limits that aren't set are reported as 0, like cAdvisor does.
+
+use crate::{registry::Registry, system::advance};
+use prometheus::{Counter, Gauge, IntCounter};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// cgroup v1 reports "no limit" as a page-aligned `i64::MAX`.
+const V1_UNLIMITED: u64 = 1 << 62;
+
+/// The cgroup directories of the current process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cgroup {
+    V1 { memory: PathBuf, cpu: PathBuf },
+    V2 { dir: PathBuf },
+}
+
+/// One reading of the cgroup files. Fields are `None` when unreadable.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CgroupStats {
+    /// `Some(0)` when unlimited.
+    pub memory_limit_bytes: Option<u64>,
+    pub memory_usage_bytes: Option<u64>,
+    /// CPU quota in cores, `Some(0.0)` when unlimited.
+    pub cpu_quota_cores: Option<f64>,
+    pub cpu_periods: Option<u64>,
+    pub cpu_throttled_periods: Option<u64>,
+    pub cpu_throttled_seconds: Option<f64>,
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    read_trimmed(path)?.parse().ok()
+}
+
+/// Looks up `key value` lines as found in `cpu.stat`.
+fn stat_field(stat: &str, key: &str) -> Option<u64> {
+    stat.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix(' ')?.trim().parse().ok())
+}
+
+/// The process's own cgroup under `mount` when it is visible there (no
+/// cgroup namespace), otherwise `mount` itself.
+fn own_dir(mount: PathBuf, relative: &str) -> PathBuf {
+    let own = mount.join(relative.trim_start_matches('/'));
+    if own.is_dir() { own } else { mount }
+}
+
+impl Cgroup {
+    /// Detects the cgroup version and directories of the current process;
+    /// `None` outside Linux or without a mounted cgroup filesystem.
+    pub fn detect() -> Option<Self> {
+        let root = Path::new(CGROUP_ROOT);
+        let membership = fs::read_to_string("/proc/self/cgroup").ok()?;
+        if root.join("cgroup.controllers").is_file() {
+            let relative = membership.lines().find_map(|line| line.strip_prefix("0::")).unwrap_or("/");
+            return Some(Cgroup::V2 { dir: own_dir(root.to_path_buf(), relative) });
+        }
+        // v1 lines look like `4:memory:/kubepods/...` or `2:cpu,cpuacct:/...`.
+        let v1_dir = |controller: &str| {
+            let relative = membership.lines().find_map(|line| {
+                let mut parts = line.splitn(3, ':');
+                let (_, controllers, path) = (parts.next()?, parts.next()?, parts.next()?);
+                controllers.split(',').any(|c| c == controller).then_some(path)
+            })?;
+            let mount = root.join(controller);
+            mount.is_dir().then(|| own_dir(mount, relative))
+        };
+        let memory = v1_dir("memory")?;
+        let cpu = v1_dir("cpu")?;
+        Some(Cgroup::V1 { memory, cpu })
+    }
+
+    pub fn read(&self) -> CgroupStats {
+        match self {
+            Cgroup::V1 { memory, cpu } => {
+                let stat = read_trimmed(&cpu.join("cpu.stat")).unwrap_or_default();
+                let quota = read_trimmed(&cpu.join("cpu.cfs_quota_us")).and_then(|q| q.parse::<i64>().ok());
+                let period = read_u64(&cpu.join("cpu.cfs_period_us"));
+                CgroupStats {
+                    memory_limit_bytes: read_u64(&memory.join("memory.limit_in_bytes"))
+                        .map(|limit| if limit >= V1_UNLIMITED { 0 } else { limit }),
+                    memory_usage_bytes: read_u64(&memory.join("memory.usage_in_bytes")),
+                    cpu_quota_cores: match (quota, period) {
+                        (Some(quota), Some(period)) if quota > 0 && period > 0 => Some(quota as f64 / period as f64),
+                        (Some(_), _) => Some(0.0),
+                        _ => None,
+                    },
+                    cpu_periods: stat_field(&stat, "nr_periods"),
+                    cpu_throttled_periods: stat_field(&stat, "nr_throttled"),
+                    cpu_throttled_seconds: stat_field(&stat, "throttled_time").map(|ns| ns as f64 / 1e9),
+                }
+            }
+            Cgroup::V2 { dir } => {
+                let stat = read_trimmed(&dir.join("cpu.stat")).unwrap_or_default();
+                CgroupStats {
+                    memory_limit_bytes: read_trimmed(&dir.join("memory.max"))
+                        .and_then(|max| if max == "max" { Some(0) } else { max.parse().ok() }),
+                    memory_usage_bytes: read_u64(&dir.join("memory.current")),
+                    // `cpu.max` is `max 100000` or `<quota> <period>`.
+                    cpu_quota_cores: read_trimmed(&dir.join("cpu.max")).and_then(|max| {
+                        let (quota, period) = max.split_once(' ')?;
+                        let period: f64 = period.parse().ok()?;
+                        match quota {
+                            "max" => Some(0.0),
+                            quota => Some(quota.parse::<f64>().ok()? / period),
+                        }
+                    }),
+                    cpu_periods: stat_field(&stat, "nr_periods"),
+                    cpu_throttled_periods: stat_field(&stat, "nr_throttled"),
+                    cpu_throttled_seconds: stat_field(&stat, "throttled_usec").map(|us| us as f64 / 1e6),
+                }
+            }
+        }
+    }
+}
+
+/// Gauges and counters fed from `CgroupStats`.
+#[derive(Clone, Debug)]
+pub struct CgroupMetrics {
+    pub memory_limit_bytes: Gauge,
+    pub memory_usage_bytes: Gauge,
+    pub memory_utilization_ratio: Gauge,
+    pub cpu_quota_cores: Gauge,
+    pub cpu_periods: IntCounter,
+    pub cpu_throttled_periods: IntCounter,
+    pub cpu_throttled_seconds: Counter,
+}
+
+impl CgroupMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let memory_limit_bytes =
+            Gauge::new("container_memory_limit_bytes", "Memory limit of the container's cgroup, 0 if unlimited")?;
+        let memory_usage_bytes =
+            Gauge::new("container_memory_usage_bytes", "Memory usage of the container's cgroup, including page cache")?;
+        let memory_utilization_ratio = Gauge::new(
+            "container_memory_utilization_ratio",
+            "Memory usage as a share of the cgroup limit, 0 if unlimited",
+        )?;
+        let cpu_quota_cores =
+            Gauge::new("container_cpu_quota_cores", "CPU quota of the container's cgroup in cores, 0 if unlimited")?;
+        let cpu_periods = IntCounter::new("container_cpu_periods_total", "Elapsed CPU quota enforcement periods")?;
+        let cpu_throttled_periods = IntCounter::new(
+            "container_cpu_throttled_periods_total",
+            "Enforcement periods in which the container was throttled",
+        )?;
+        let cpu_throttled_seconds = Counter::new(
+            "container_cpu_throttled_seconds_total",
+            "Total time the container was throttled, in seconds",
+        )?;
+
+        registry.register(Box::new(memory_limit_bytes.clone()))?;
+        registry.register(Box::new(memory_usage_bytes.clone()))?;
+        registry.register(Box::new(memory_utilization_ratio.clone()))?;
+        registry.register(Box::new(cpu_quota_cores.clone()))?;
+        registry.register(Box::new(cpu_periods.clone()))?;
+        registry.register(Box::new(cpu_throttled_periods.clone()))?;
+        registry.register(Box::new(cpu_throttled_seconds.clone()))?;
+
+        Ok(Self {
+            memory_limit_bytes,
+            memory_usage_bytes,
+            memory_utilization_ratio,
+            cpu_quota_cores,
+            cpu_periods,
+            cpu_throttled_periods,
+            cpu_throttled_seconds,
+        })
+    }
+
+    pub fn set(&self, stats: &CgroupStats) {
+        if let Some(limit) = stats.memory_limit_bytes {
+            self.memory_limit_bytes.set(limit as f64);
+        }
+        if let Some(usage) = stats.memory_usage_bytes {
+            self.memory_usage_bytes.set(usage as f64);
+        }
+        match (stats.memory_limit_bytes, stats.memory_usage_bytes) {
+            (Some(limit), Some(usage)) if limit > 0 => self.memory_utilization_ratio.set(usage as f64 / limit as f64),
+            (Some(0), _) => self.memory_utilization_ratio.set(0.0),
+            _ => {}
+        }
+        if let Some(cores) = stats.cpu_quota_cores {
+            self.cpu_quota_cores.set(cores);
+        }
+        if let Some(periods) = stats.cpu_periods {
+            advance(&self.cpu_periods, periods);
+        }
+        if let Some(periods) = stats.cpu_throttled_periods {
+            advance(&self.cpu_throttled_periods, periods);
+        }
+        if let Some(secs) = stats.cpu_throttled_seconds {
+            let current = self.cpu_throttled_seconds.get();
+            if secs > current {
+                self.cpu_throttled_seconds.inc_by(secs - current);
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! Export circuit breaker (`EXPORT_CIRCUIT_BREAKER_THRESHOLD`).
+//!
+//! When the collector slows down or goes away, every batch waits for its
+//! retries and timeouts while the SDK queues fill up and drop whatever comes
+//! next, silently and at random. With a threshold set, each signal's
+//! exporter goes through a breaker that sheds load in a fixed order as
+//! consecutive batches fail:
+//!
+//! 1. from the first failed batch, `debug` and `trace` log records are
+//!    dropped from log batches;
+//! 2. from half the threshold, spans of traces outside a
+//!    `REDUCED_SAMPLE_RATIO` sample by trace id are dropped too, except
+//!    error spans, so kept traces stay whole;
+//! 3. at the threshold the circuit opens: batches are dropped unsent for
+//!    `EXPORT_CIRCUIT_BREAKER_OPEN_SECS` (30 by default).
+//!
+//! Then the circuit is half-open: the next batch is sent as a probe, with
+//! the shedding of step 1 only so it has something to send. If it goes
+//! through the circuit closes and shedding stops; if it fails the circuit
+//! opens again.
+//!
+//! The state of each signal's circuit is exported as
+//! `otel_exporter_circuit_state{signal}` (0 closed, 1 half-open, 2 open) and
+//! the items shed in `otel_exporter_shed_total{signal,reason}`
+//! (`debug_logs`, `unsampled_spans` or `circuit_open`).
+
+use crate::{pipeline::Signal, registry::Registry};
+#[cfg(feature = "traces")]
+use crate::memory_pressure::REDUCED_SAMPLE_RATIO;
+#[cfg(feature = "logs")]
+use opentelemetry::logs::Severity;
+#[cfg(feature = "traces")]
+use opentelemetry::trace::Status;
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{LogBatch, LogExporter};
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use opentelemetry_sdk::Resource;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use opentelemetry_sdk::error::OTelSdkResult;
+use prometheus::{IntCounterVec, IntGaugeVec, Opts};
+#[cfg(feature = "metrics")]
+use std::fmt;
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i64)]
+pub enum CircuitState {
+    Closed = 0,
+    HalfOpen = 1,
+    Open = 2,
+}
+
+/// What is dropped from a batch before it is sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Shedding {
+    #[default]
+    None,
+    DebugLogs,
+    UnsampledSpans,
+}
+
+#[derive(Debug)]
+struct State {
+    circuit: CircuitState,
+    /// Batches failed in a row.
+    failures: u32,
+    opened: Instant,
+    /// Whether the probe of a half-open circuit is in flight.
+    probing: bool,
+}
+
+#[derive(Debug)]
+struct Inner {
+    signal: Signal,
+    threshold: u32,
+    open_for: Duration,
+    state: Mutex<State>,
+    gauge: IntGaugeVec,
+    shed: IntCounterVec,
+}
+
+/// The breaker of one signal's exporter; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    inner: Arc<Inner>,
+}
+
+impl CircuitBreaker {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn circuit(&self) -> CircuitState {
+        self.state().circuit
+    }
+
+    fn set(&self, state: &mut State, circuit: CircuitState) {
+        state.circuit = circuit;
+        self.inner.gauge.with_label_values(&[self.inner.signal.as_str()]).set(circuit as i64);
+    }
+
+    /// What to shed from the next batch, or `None` to drop it unsent.
+    pub fn admit(&self) -> Option<Shedding> {
+        let mut state = self.state();
+        match state.circuit {
+            CircuitState::Closed if state.failures == 0 => Some(Shedding::None),
+            CircuitState::Closed if state.failures.saturating_mul(2) < self.inner.threshold => {
+                Some(Shedding::DebugLogs)
+            }
+            CircuitState::Closed => Some(Shedding::UnsampledSpans),
+            CircuitState::Open if state.opened.elapsed() < self.inner.open_for => None,
+            CircuitState::HalfOpen if state.probing => None,
+            CircuitState::Open | CircuitState::HalfOpen => {
+                self.set(&mut state, CircuitState::HalfOpen);
+                state.probing = true;
+                Some(Shedding::DebugLogs)
+            }
+        }
+    }
+
+    /// Gives up the probe admitted last when shedding left nothing to send,
+    /// so the next batch probes instead.
+    pub fn skip(&self) {
+        self.state().probing = false;
+    }
+
+    /// Records the outcome of an admitted batch.
+    pub fn record(&self, success: bool) {
+        let mut state = self.state();
+        let signal = self.inner.signal.as_str();
+        state.probing = false;
+        if success {
+            if state.circuit != CircuitState::Closed {
+                info!(signal, "Export circuit closed, the collector accepts exports again");
+            }
+            state.failures = 0;
+            self.set(&mut state, CircuitState::Closed);
+            return;
+        }
+        state.failures = state.failures.saturating_add(1);
+        if state.circuit == CircuitState::HalfOpen || state.failures >= self.inner.threshold {
+            if state.circuit == CircuitState::Closed {
+                warn!(
+                    signal,
+                    failures = state.failures,
+                    open_secs = self.inner.open_for.as_secs(),
+                    "Export circuit opened, dropping batches until the collector recovers"
+                );
+            }
+            state.opened = Instant::now();
+            self.set(&mut state, CircuitState::Open);
+        }
+    }
+
+    /// Counts `items` shed for `reason`.
+    pub fn shed(&self, reason: &str, items: usize) {
+        if items > 0 {
+            self.inner.shed.with_label_values(&[self.inner.signal.as_str(), reason]).inc_by(items as u64);
+        }
+    }
+}
+
+/// The breakers of the three signals.
+#[derive(Clone, Debug)]
+pub struct CircuitBreakers {
+    breakers: [CircuitBreaker; 3],
+}
+
+impl CircuitBreakers {
+    /// Breakers opening after `threshold` failed batches in a row, for
+    /// `open_for`.
+    pub fn new(registry: &Registry, threshold: u32, open_for: Duration) -> prometheus::Result<Self> {
+        let gauge = IntGaugeVec::new(
+            Opts::new("otel_exporter_circuit_state", "Export circuit state: 0 closed, 1 half-open, 2 open"),
+            &["signal"],
+        )?;
+        let shed = IntCounterVec::new(
+            Opts::new("otel_exporter_shed_total", "Telemetry items dropped by the export circuit breaker, by reason"),
+            &["signal", "reason"],
+        )?;
+        registry.register(Box::new(gauge.clone()))?;
+        registry.register(Box::new(shed.clone()))?;
+        for signal in Signal::ALL {
+            gauge.with_label_values(&[signal.as_str()]);
+            shed.with_label_values(&[signal.as_str(), "circuit_open"]);
+        }
+        shed.with_label_values(&[Signal::Logs.as_str(), "debug_logs"]);
+        shed.with_label_values(&[Signal::Traces.as_str(), "unsampled_spans"]);
+        let breaker = |signal| CircuitBreaker {
+            inner: Arc::new(Inner {
+                signal,
+                threshold,
+                open_for,
+                state: Mutex::new(State {
+                    circuit: CircuitState::Closed,
+                    failures: 0,
+                    opened: Instant::now(),
+                    probing: false,
+                }),
+                gauge: gauge.clone(),
+                shed: shed.clone(),
+            }),
+        };
+        Ok(Self { breakers: Signal::ALL.map(breaker) })
+    }
+
+    pub fn get(&self, signal: Signal) -> CircuitBreaker {
+        self.breakers[signal as usize].clone()
+    }
+}
+
+/// Whether the trace is in the `REDUCED_SAMPLE_RATIO` sample, by the low
+/// half of its id like the SDK's ratio sampler.
+#[cfg(feature = "traces")]
+fn in_reduced_sample(trace_id: opentelemetry::trace::TraceId) -> bool {
+    let id = u128::from_be_bytes(trace_id.to_bytes()) as u64;
+    (id as f64) < REDUCED_SAMPLE_RATIO * u64::MAX as f64
+}
+
+/// Sends span batches through `breaker`, if any.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct BreakerSpanExporter<E> {
+    inner: E,
+    breaker: Option<CircuitBreaker>,
+}
+
+#[cfg(feature = "traces")]
+impl<E> BreakerSpanExporter<E> {
+    pub fn new(inner: E, breaker: Option<CircuitBreaker>) -> Self {
+        Self { inner, breaker }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl<E: SpanExporter> SpanExporter for BreakerSpanExporter<E> {
+    async fn export(&self, mut batch: Vec<SpanData>) -> OTelSdkResult {
+        let Some(breaker) = &self.breaker else {
+            return self.inner.export(batch).await;
+        };
+        let Some(shedding) = breaker.admit() else {
+            breaker.shed("circuit_open", batch.len());
+            return Ok(());
+        };
+        if shedding >= Shedding::UnsampledSpans {
+            let before = batch.len();
+            batch.retain(|span| {
+                matches!(span.status, Status::Error { .. }) || in_reduced_sample(span.span_context.trace_id())
+            });
+            breaker.shed("unsampled_spans", before - batch.len());
+            if batch.is_empty() {
+                breaker.skip();
+                return Ok(());
+            }
+        }
+        let result = self.inner.export(batch).await;
+        breaker.record(result.is_ok());
+        result
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Sends log batches through `breaker`, if any.
+#[cfg(feature = "logs")]
+#[derive(Debug)]
+pub struct BreakerLogExporter<E> {
+    inner: E,
+    breaker: Option<CircuitBreaker>,
+}
+
+#[cfg(feature = "logs")]
+impl<E> BreakerLogExporter<E> {
+    pub fn new(inner: E, breaker: Option<CircuitBreaker>) -> Self {
+        Self { inner, breaker }
+    }
+}
+
+#[cfg(feature = "logs")]
+impl<E: LogExporter> LogExporter for BreakerLogExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        let Some(breaker) = &self.breaker else {
+            return self.inner.export(batch).await;
+        };
+        let Some(shedding) = breaker.admit() else {
+            breaker.shed("circuit_open", batch.iter().count());
+            return Ok(());
+        };
+        if shedding == Shedding::None {
+            let result = self.inner.export(batch).await;
+            breaker.record(result.is_ok());
+            return result;
+        }
+        let mut records: Vec<_> = batch.iter().collect();
+        let before = records.len();
+        records.retain(|(record, _)| record.severity_number().is_none_or(|severity| severity >= Severity::Info));
+        breaker.shed("debug_logs", before - records.len());
+        if records.is_empty() {
+            breaker.skip();
+            return Ok(());
+        }
+        let result = self.inner.export(LogBatch::new(&records)).await;
+        breaker.record(result.is_ok());
+        result
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Sends metric exports through `breaker`, if any; metrics are never shed,
+/// only dropped while the circuit is open.
+#[cfg(feature = "metrics")]
+pub struct BreakerMetricExporter<E> {
+    inner: E,
+    breaker: Option<CircuitBreaker>,
+}
+
+#[cfg(feature = "metrics")]
+impl<E> BreakerMetricExporter<E> {
+    pub fn new(inner: E, breaker: Option<CircuitBreaker>) -> Self {
+        Self { inner, breaker }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<E> fmt::Debug for BreakerMetricExporter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BreakerMetricExporter").field("breaker", &self.breaker).finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<E: PushMetricExporter> PushMetricExporter for BreakerMetricExporter<E> {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        let Some(breaker) = &self.breaker else {
+            return self.inner.export(metrics).await;
+        };
+        if breaker.admit().is_none() {
+            breaker.shed("circuit_open", metrics.scope_metrics().map(|scope| scope.metrics().count()).sum());
+            return Ok(());
+        }
+        let result = self.inner.export(metrics).await;
+        breaker.record(result.is_ok());
+        result
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.inner.temporality()
+    }
+}
@@ -0,0 +1,1051 @@
+//! Command-line interface.
+
+use crate::{
+    apdex::RouteApdex,
+    app_auth::AppToken,
+    auth::{Cidr, ScrapeCredentials},
+    client_labels::ClientSubnet,
+    config::Config,
+    federation::FederateTarget,
+    file_sink::Rotation,
+    system_log::{Facility, SyslogTarget},
+    listener::Listener,
+    log_format::LogFormat,
+    log_metrics::LogMetricRule,
+    metric_views::{MetricTemporality, MetricView},
+    multiprocess::GaugeMode,
+    otlp_exporter::{OtlpCompression, OtlpHeader, OtlpProtocol},
+    pipeline::Signal,
+    rate_limit::RateLimit,
+    request_size::RequestSizeLimit,
+    response_cache::ResponseCacheRule,
+    slo::SloRule,
+    tenant::TenantSource,
+    timeout::RequestTimeout,
+    shutdown::FlushStep,
+    sli::StatusMatcher,
+};
+use clap::{Args, Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Actix service instrumented with OpenTelemetry and Prometheus")]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server with telemetry enabled (default).
+    Serve,
+    /// Parse and validate the configuration, then print the effective values.
+    ValidateConfig,
+    /// Gather the metrics registry once and print it in the Prometheus text format.
+    PrintMetrics,
+    /// Print the catalog of exposed metric families (name, type, help, labels, unit, series) as JSON.
+    PrintMetricCatalog,
+    /// Print recommended Prometheus recording and alerting rules for the exposed metrics.
+    #[command(long_flag = "emit-alerts")]
+    EmitAlerts,
+    /// Print the cargo features compiled into this binary, with the settings and endpoints they provide, as JSON.
+    Capabilities,
+    /// Export a tagged trace and, with `--verify`, wait until the tracing backend returns it.
+    Selftest(SelftestArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct SelftestArgs {
+    /// Query the tracing backend until the trace arrives; fails after `--timeout-secs`.
+    #[arg(long, requires = "query_url")]
+    pub verify: bool,
+
+    /// Base URL of the Tempo or Jaeger query API, e.g. `http://tempo:3200`.
+    #[arg(long, value_name = "URL")]
+    pub query_url: Option<String>,
+
+    /// Header sent with every query, as `name=value`; comma-separated or repeated.
+    #[arg(long, value_name = "HEADER", value_delimiter = ',')]
+    pub query_header: Vec<OtlpHeader>,
+
+    /// How long to wait for the trace to show up.
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub timeout_secs: u64,
+}
+
+/// Flags that take precedence over environment variables.
+#[derive(Clone, Debug, Default, Args)]
+pub struct Overrides {
+    /// JSON configuration file, reloaded on SIGHUP or when it changes.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config_file: Option<std::path::PathBuf>,
+
+    /// Addresses to listen on, comma-separated or repeated; prefix one with
+    /// `http://` or `https://` to choose its scheme.
+    #[arg(long, global = true, value_name = "ADDR", value_delimiter = ',')]
+    pub server_addr: Option<Vec<Listener>>,
+
+    /// Path prefix for the built-in endpoints, e.g. `/internal/telemetry`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub endpoint_prefix: Option<String>,
+
+    /// HTTP server worker threads.
+    #[arg(long, global = true, value_name = "N")]
+    pub server_workers: Option<usize>,
+
+    /// Idle time before a keep-alive connection is closed, in seconds; 0 disables keep-alive.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub server_keep_alive: Option<u64>,
+
+    /// Time a client has to send the request head, in milliseconds; 0 disables the timeout.
+    #[arg(long, global = true, value_name = "MS")]
+    pub server_client_request_timeout_ms: Option<u64>,
+
+    /// Concurrent connections per worker.
+    #[arg(long, global = true, value_name = "N")]
+    pub server_max_connections: Option<usize>,
+
+    /// Pending connection queue of each TCP listener.
+    #[arg(long, global = true, value_name = "N")]
+    pub server_backlog: Option<u32>,
+
+    /// Skip every OTLP exporter; `/metrics` and the local logs still work.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub telemetry_disabled: Option<bool>,
+
+    /// Base URL of the OTLP collector.
+    #[arg(long, global = true, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Unix domain socket of the collector, carrying every OTLP/HTTP export.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub otlp_unix_socket: Option<std::path::PathBuf>,
+
+    /// Protocol of every OTLP exporter: `http/protobuf`, `http/json` or `grpc`.
+    #[arg(long, global = true, value_name = "PROTOCOL")]
+    pub otlp_protocol: Option<OtlpProtocol>,
+
+    /// Compression of every OTLP exporter: `none`, `gzip` or `zstd`.
+    #[arg(long, global = true, value_name = "COMPRESSION")]
+    pub otlp_compression: Option<OtlpCompression>,
+
+    /// Header sent with every OTLP export, as `name=value`; comma-separated or repeated.
+    #[arg(long, global = true, value_name = "HEADER", value_delimiter = ',')]
+    pub otlp_headers: Option<Vec<OtlpHeader>>,
+
+    /// Full URL spans are exported to, signal path included.
+    #[arg(long, global = true, value_name = "URL")]
+    pub otlp_traces_endpoint: Option<String>,
+
+    /// Protocol of the span exporter.
+    #[arg(long, global = true, value_name = "PROTOCOL")]
+    pub otlp_traces_protocol: Option<OtlpProtocol>,
+
+    /// Compression of the span exporter.
+    #[arg(long, global = true, value_name = "COMPRESSION")]
+    pub otlp_traces_compression: Option<OtlpCompression>,
+
+    /// Headers of the span exporter, replacing `--otlp-headers`.
+    #[arg(long, global = true, value_name = "HEADER", value_delimiter = ',')]
+    pub otlp_traces_headers: Option<Vec<OtlpHeader>>,
+
+    /// Full URL log records are exported to, signal path included.
+    #[arg(long, global = true, value_name = "URL")]
+    pub otlp_logs_endpoint: Option<String>,
+
+    /// Protocol of the log exporter.
+    #[arg(long, global = true, value_name = "PROTOCOL")]
+    pub otlp_logs_protocol: Option<OtlpProtocol>,
+
+    /// Compression of the log exporter.
+    #[arg(long, global = true, value_name = "COMPRESSION")]
+    pub otlp_logs_compression: Option<OtlpCompression>,
+
+    /// Headers of the log exporter, replacing `--otlp-headers`.
+    #[arg(long, global = true, value_name = "HEADER", value_delimiter = ',')]
+    pub otlp_logs_headers: Option<Vec<OtlpHeader>>,
+
+    /// Full URL metrics are exported to, signal path included.
+    #[arg(long, global = true, value_name = "URL")]
+    pub otlp_metrics_endpoint: Option<String>,
+
+    /// Protocol of the metric exporter.
+    #[arg(long, global = true, value_name = "PROTOCOL")]
+    pub otlp_metrics_protocol: Option<OtlpProtocol>,
+
+    /// Compression of the metric exporter.
+    #[arg(long, global = true, value_name = "COMPRESSION")]
+    pub otlp_metrics_compression: Option<OtlpCompression>,
+
+    /// Headers of the metric exporter, replacing `--otlp-headers`.
+    #[arg(long, global = true, value_name = "HEADER", value_delimiter = ',')]
+    pub otlp_metrics_headers: Option<Vec<OtlpHeader>>,
+
+    /// Base URL of a second collector every signal is also exported to.
+    #[arg(long, global = true, value_name = "URL")]
+    pub otlp_shadow_endpoint: Option<String>,
+
+    /// Protocol of the shadow exporters.
+    #[arg(long, global = true, value_name = "PROTOCOL")]
+    pub otlp_shadow_protocol: Option<OtlpProtocol>,
+
+    /// Compression of the shadow exporters.
+    #[arg(long, global = true, value_name = "COMPRESSION")]
+    pub otlp_shadow_compression: Option<OtlpCompression>,
+
+    /// Headers of the shadow exporters; comma-separated or repeated.
+    #[arg(long, global = true, value_name = "HEADER", value_delimiter = ',')]
+    pub otlp_shadow_headers: Option<Vec<OtlpHeader>>,
+
+    /// Value of the `service.name` resource attribute.
+    #[arg(long, global = true, value_name = "NAME")]
+    pub service_name: Option<String>,
+
+    /// Value of the `service.version` resource attribute.
+    #[arg(long, global = true, value_name = "VERSION")]
+    pub service_version: Option<String>,
+
+    /// Value of the `service.instance.id` resource attribute.
+    #[arg(long, global = true, value_name = "ID")]
+    pub service_instance_id: Option<String>,
+
+    /// Value of the `deployment.environment.name` resource attribute.
+    #[arg(long, global = true, value_name = "NAME")]
+    pub deployment_environment: Option<String>,
+
+    /// Span batch processor queue size.
+    #[arg(long, global = true, value_name = "N")]
+    pub trace_batch_max_queue_size: Option<usize>,
+
+    /// Spans sent per export request.
+    #[arg(long, global = true, value_name = "N")]
+    pub trace_batch_max_export_batch_size: Option<usize>,
+
+    /// Delay between span exports, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub trace_batch_scheduled_delay_ms: Option<u64>,
+
+    /// Timeout of a span export request, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub trace_export_timeout_ms: Option<u64>,
+
+    /// Log record batch processor queue size.
+    #[arg(long, global = true, value_name = "N")]
+    pub log_batch_max_queue_size: Option<usize>,
+
+    /// Log records sent per export request.
+    #[arg(long, global = true, value_name = "N")]
+    pub log_batch_max_export_batch_size: Option<usize>,
+
+    /// Delay between log exports, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub log_batch_scheduled_delay_ms: Option<u64>,
+
+    /// Timeout of a log export request, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub log_export_timeout_ms: Option<u64>,
+
+    /// Interval between metric exports, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub metric_export_interval_ms: Option<u64>,
+
+    /// Timeout of a metric export request, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub metric_export_timeout_ms: Option<u64>,
+
+    /// Failed export batches in a row after which a signal's export circuit opens.
+    #[arg(long, global = true, value_name = "N")]
+    pub export_circuit_breaker_threshold: Option<u32>,
+
+    /// How long an open export circuit drops batches before probing the collector, in seconds.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub export_circuit_breaker_open: Option<u64>,
+
+    /// Temporality of exported OTLP metrics: `cumulative`, `delta` or `lowmemory`.
+    #[arg(long, global = true, value_name = "TEMPORALITY")]
+    pub metric_temporality: Option<MetricTemporality>,
+
+    /// View applied to an OTel instrument, e.g. `http.server.duration:buckets=0.1|1`; repeatable.
+    #[arg(long = "metric-view", global = true, value_name = "VIEW")]
+    pub metric_views: Option<Vec<MetricView>>,
+
+    /// Log filter directive, e.g. `info` or `prom_otel=debug`.
+    #[arg(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+
+    /// Stdout log format: `text` or `json` (one object per line).
+    #[arg(long, global = true, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Also write logs to this file.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<std::path::PathBuf>,
+
+    /// Rotate the log file at this size in MB (0 disables size rotation).
+    #[arg(long, global = true, value_name = "MB")]
+    pub log_file_max_size_mb: Option<u64>,
+
+    /// Time based log file rotation: `never`, `hourly` or `daily`.
+    #[arg(long, global = true, value_name = "PERIOD")]
+    pub log_file_rotation: Option<Rotation>,
+
+    /// Rotated log files to keep.
+    #[arg(long, global = true, value_name = "N")]
+    pub log_file_max_files: Option<usize>,
+
+    /// Write logs to stdout.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub log_stdout: Option<bool>,
+
+    /// Also send logs to syslog: `unix:<path>` or `udp:<host>:<port>`.
+    #[arg(long, global = true, value_name = "TARGET")]
+    pub log_syslog: Option<SyslogTarget>,
+
+    /// Syslog facility: `user`, `daemon` or `local0` to `local7`.
+    #[arg(long, global = true, value_name = "FACILITY")]
+    pub log_syslog_facility: Option<Facility>,
+
+    /// Also send logs to systemd-journald.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub log_journald: Option<bool>,
+
+    /// Comma-separated field/attribute names whose values are redacted.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub redact_fields: Option<Vec<String>>,
+
+    /// Regex (or preset `email`, `card_number`) to redact from values; repeatable.
+    #[arg(long = "redact-pattern", global = true, value_name = "REGEX")]
+    pub redact_patterns: Option<Vec<String>>,
+
+    /// Rule counting matching log events, e.g. `declined:target=payments,level=warn`; repeatable.
+    #[arg(long = "log-metric", global = true, value_name = "RULE")]
+    pub log_metrics: Option<Vec<LogMetricRule>>,
+
+    /// Log events exported over OTLP per second and callsite at most (`logs` feature).
+    #[arg(long, global = true, value_name = "N")]
+    pub log_rate_limit_per_sec: Option<u32>,
+
+    /// Share of debug and trace log events exported over OTLP (`logs` feature).
+    #[arg(long, global = true, value_name = "RATIO")]
+    pub log_debug_sample_ratio: Option<f64>,
+
+    /// Export audit events on a dedicated OTLP log stream (`logs` feature).
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub audit_logs: Option<bool>,
+
+    /// Refresh interval for process CPU/memory gauges, in seconds.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub system_metrics_interval: Option<u64>,
+
+    /// How often the event loop probes wake to measure runtime lag, in milliseconds (0 disables them).
+    #[arg(long, global = true, value_name = "MS")]
+    pub event_loop_probe_interval_ms: Option<u64>,
+
+    /// Time without a probe wake after which a runtime counts as blocked, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub event_loop_blocked_threshold_ms: Option<u64>,
+
+    /// Time in flight after which a request is flagged as slow, in milliseconds (0 disables the watchdog).
+    #[arg(long, global = true, value_name = "MS")]
+    pub slow_request_threshold_ms: Option<u64>,
+
+    /// Profile the worker of each slow request.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub slow_request_profile: Option<bool>,
+
+    /// RSS in MB from which telemetry is progressively shed.
+    #[arg(long, global = true, value_name = "MB")]
+    pub memory_pressure_threshold_mb: Option<u64>,
+
+    /// Latency above which a request no longer counts as good for the SLI, in milliseconds.
+    #[arg(long, global = true, value_name = "MS")]
+    pub sli_latency_threshold_ms: Option<u64>,
+
+    /// Comma-separated status codes or classes that count against the SLI, e.g. `5xx,429`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub sli_bad_statuses: Option<Vec<StatusMatcher>>,
+
+    /// Apdex target in milliseconds; requests up to four times it are tolerated.
+    #[arg(long, global = true, value_name = "MS")]
+    pub apdex_threshold_ms: Option<u64>,
+
+    /// Comma-separated per-route Apdex thresholds, e.g. `/search=1000,/users/{id}=100:300`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub apdex_route_thresholds: Option<Vec<RouteApdex>>,
+
+    /// Comma-separated per-route SLOs, e.g. `/checkout=99.9,/checkout=99:300`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub slos: Option<Vec<SloRule>>,
+
+    /// Comma-separated route patterns excluded from request metrics, e.g. `/metrics`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub metrics_excluded_routes: Option<Vec<String>>,
+
+    /// Comma-separated route patterns producing no spans, OTLP logs or request metrics, e.g. `/healthz`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub telemetry_excluded_routes: Option<Vec<String>>,
+
+    /// Comma-separated token-bucket limits, e.g. `/search=5/s:10,*=600/m@header:x-api-key`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub rate_limits: Option<Vec<RateLimit>>,
+
+    /// Comma-separated handler deadlines, e.g. `/report=2000,*=10000:503`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub request_timeouts: Option<Vec<RequestTimeout>>,
+
+    /// Comma-separated request body limits, e.g. `/upload=10m,*=64k`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub request_size_limits: Option<Vec<RequestSizeLimit>>,
+
+    /// Comma-separated response TTLs, e.g. `/catalog=30000,/prices=1000`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub response_cache: Option<Vec<ResponseCacheRule>>,
+
+    /// Responses held by the response cache.
+    #[arg(long, global = true, value_name = "N")]
+    pub response_cache_max_entries: Option<usize>,
+
+    /// Distinct label sets allowed per labeled metric before new ones are folded into `other`.
+    #[arg(long, global = true, value_name = "N")]
+    pub metrics_max_label_sets: Option<usize>,
+
+    /// Comma-separated resource attributes added to every Prometheus series as labels.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub metrics_resource_labels: Option<Vec<String>>,
+
+    /// Write the metric catalog (JSON) to this file on startup.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub metrics_catalog_path: Option<std::path::PathBuf>,
+
+    /// Reuse the encoded `/metrics` response for this many milliseconds (0 disables).
+    #[arg(long, global = true, value_name = "MS")]
+    pub metrics_cache_ttl_ms: Option<u64>,
+
+    /// Stream `/metrics` in chunks, family by family, instead of encoding it into one buffer.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub metrics_streaming: Option<bool>,
+
+    /// Reject metrics registered through `AppMetrics::register` that break the naming conventions.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub metrics_naming_strict: Option<bool>,
+
+    /// Directory shared by worker processes to serve their metrics summed.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub metrics_multiprocess_dir: Option<std::path::PathBuf>,
+
+    /// How often this process writes its metrics to the multiprocess directory.
+    #[arg(long, global = true, value_name = "MS")]
+    pub metrics_multiprocess_interval_ms: Option<u64>,
+
+    /// How gauges of several processes are combined: `sum` or `max`.
+    #[arg(long, global = true, value_name = "MODE")]
+    pub metrics_multiprocess_gauge_mode: Option<GaugeMode>,
+
+    /// Also track request latency as a native histogram (protobuf exposition and OTLP).
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub metrics_native_histograms: Option<bool>,
+
+    /// Starting resolution of native histograms, from -4 to 8.
+    #[arg(long, global = true, value_name = "SCHEMA", allow_negative_numbers = true)]
+    pub metrics_native_histogram_schema: Option<i32>,
+
+    /// Buckets a native histogram can have before its resolution is lowered.
+    #[arg(long, global = true, value_name = "N")]
+    pub metrics_native_histogram_max_buckets: Option<usize>,
+
+    /// PEM certificate chain to serve HTTPS with (needs `--tls-key-path`).
+    #[arg(long, global = true, value_name = "PATH")]
+    pub tls_cert_path: Option<std::path::PathBuf>,
+
+    /// PEM private key matching `--tls-cert-path`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub tls_key_path: Option<std::path::PathBuf>,
+
+    /// Bearer token scrapes of `/metrics` must present.
+    #[arg(long, global = true, value_name = "TOKEN", conflicts_with = "metrics_basic_auth")]
+    pub metrics_bearer_token: Option<String>,
+
+    /// Basic auth credentials scrapes of `/metrics` must present, as `user:password`.
+    #[arg(long, global = true, value_name = "USER:PASSWORD", value_parser = ScrapeCredentials::basic)]
+    pub metrics_basic_auth: Option<ScrapeCredentials>,
+
+    /// Comma-separated CIDRs allowed to scrape `/metrics`, e.g. `10.0.0.0/8,127.0.0.1`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub metrics_allowed_ips: Option<Vec<Cidr>>,
+
+    /// Accept OTLP/HTTP on `/v1/*` and forward it to the collector (`gateway` feature).
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub otlp_receiver: Option<bool>,
+
+    /// Comma-separated CIDRs allowed to send to the OTLP receiver.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub otlp_receiver_allowed_ips: Option<Vec<Cidr>>,
+
+    /// Also return the W3C `traceresponse` header with the trace ID.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub trace_response_header: Option<bool>,
+
+    /// Comma-separated baggage keys copied onto span attributes and request metric labels.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub baggage_keys: Option<Vec<String>>,
+
+    /// Label requests with their client's user agent family, subnet and TLS version.
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub client_labels: Option<bool>,
+
+    /// Comma-separated named client networks, e.g. `office=10.1.0.0/16,vpn=10.8.0.0/16`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub client_subnets: Option<Vec<ClientSubnet>>,
+
+    /// Where the tenant of a request comes from: `header:<name>`, `claim:<name>` or `path:<index>`.
+    #[arg(long, global = true, value_name = "SOURCE")]
+    pub tenant_source: Option<TenantSource>,
+
+    /// Comma-separated tenants labeled as themselves in the tenant metrics, the others as `other`.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub tenant_allowlist: Option<Vec<String>>,
+
+    /// Label tenants with one of N hash buckets instead.
+    #[arg(long, global = true, value_name = "N")]
+    pub tenant_hash_buckets: Option<u32>,
+
+    /// Derive RED metrics from server and client spans (`traces` feature).
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub span_metrics: Option<bool>,
+
+    /// Export only traces with an error or a slow span (`traces` feature).
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub tail_sampling: Option<bool>,
+
+    /// Milliseconds the spans of a trace are held before it is decided.
+    #[arg(long, global = true, value_name = "MS")]
+    pub tail_sampling_window_ms: Option<u64>,
+
+    /// Span duration in milliseconds from which a trace is exported.
+    #[arg(long, global = true, value_name = "MS")]
+    pub tail_sampling_latency_threshold_ms: Option<u64>,
+
+    /// Traces held at once by tail sampling.
+    #[arg(long, global = true, value_name = "N")]
+    pub tail_sampling_max_traces: Option<usize>,
+
+    /// Also push the Prometheus registry through the OTLP metric pipeline (`metrics` feature).
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub metrics_otlp_bridge: Option<bool>,
+
+    /// Bearer token for the `/admin` API; the API is disabled without one.
+    #[arg(long, global = true, value_name = "TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Address to serve the gRPC health service on (`grpc-health` feature).
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub grpc_health_addr: Option<String>,
+
+    /// Comma-separated `source=url` Prometheus endpoints to federate (`federation` feature).
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub federate_targets: Option<Vec<FederateTarget>>,
+
+    /// Delay between two scrapes of the federated targets, in seconds.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub federate_interval: Option<u64>,
+
+    /// UDP address to accept StatsD/DogStatsD lines on.
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub statsd_addr: Option<String>,
+
+    /// Stream finished spans and log events on the `/debug/tap` WebSocket (needs an admin token).
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub debug_tap: Option<bool>,
+
+    /// Spans and log events the debug tap keeps for new clients.
+    #[arg(long, global = true, value_name = "N")]
+    pub debug_tap_buffer: Option<usize>,
+
+    /// Share of traces and log events sent to the debug tap, from 0 to 1.
+    #[arg(long, global = true, value_name = "RATIO")]
+    pub debug_tap_sample_ratio: Option<f64>,
+
+    /// Base URL of a LaunchDarkly-compatible flag service (`flags` feature).
+    #[arg(long, global = true, value_name = "URL")]
+    pub flags_url: Option<String>,
+
+    /// SDK key for the flag service.
+    #[arg(long, global = true, value_name = "KEY")]
+    pub flags_sdk_key: Option<String>,
+
+    /// Delay between two polls of the flag service, in seconds.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub flags_poll_interval: Option<u64>,
+
+    /// Jaeger remote sampling endpoint to poll the trace sampling strategy from (`remote-sampling` feature).
+    #[arg(long, global = true, value_name = "URL")]
+    pub remote_sampling_url: Option<String>,
+
+    /// Delay between two polls of the remote sampling endpoint, in seconds.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub remote_sampling_interval: Option<u64>,
+
+    /// Share of the traces sampled until a remote strategy is fetched, from 0 to 1.
+    #[arg(long, global = true, value_name = "RATIO")]
+    pub remote_sampling_initial_ratio: Option<f64>,
+
+    /// Pyroscope server to ship continuous CPU profiles to (`pyroscope` feature).
+    #[arg(long, global = true, value_name = "URL")]
+    pub pyroscope_url: Option<String>,
+
+    /// Bearer token sent to the Pyroscope server.
+    #[arg(long, global = true, value_name = "TOKEN", conflicts_with = "pyroscope_basic_auth")]
+    pub pyroscope_auth_token: Option<String>,
+
+    /// Basic auth credentials sent to the Pyroscope server, as `user:password`.
+    #[arg(long, global = true, value_name = "USER:PASSWORD", value_parser = ScrapeCredentials::basic)]
+    pub pyroscope_basic_auth: Option<ScrapeCredentials>,
+
+    /// Length of each profile uploaded to Pyroscope, in seconds.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub pyroscope_upload_interval: Option<u64>,
+
+    /// Order of the pipeline shutdowns at exit, as `signal[:ms]` with the flush deadline; comma-separated.
+    #[arg(long, global = true, value_name = "SIGNAL[:MS]", value_delimiter = ',')]
+    pub shutdown_flush_order: Option<Vec<FlushStep>>,
+
+    /// Comma-separated `principal=token` bearer tokens required on the application routes.
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub app_auth_tokens: Option<Vec<AppToken>>,
+
+    /// JWKS URL the bearer JWTs of the application routes are verified against (`oidc` feature).
+    #[arg(long, global = true, value_name = "URL")]
+    pub app_auth_jwks_url: Option<String>,
+
+    /// Issuer the JWTs must carry in `iss`.
+    #[arg(long, global = true, value_name = "ISSUER")]
+    pub app_auth_issuer: Option<String>,
+
+    /// Audience the JWTs must list in `aud`.
+    #[arg(long, global = true, value_name = "AUDIENCE")]
+    pub app_auth_audience: Option<String>,
+
+    /// Claim of the JWTs naming the principal.
+    #[arg(long, global = true, value_name = "CLAIM")]
+    pub app_auth_principal_claim: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(self, config: &mut Config) {
+        if let Some(path) = self.config_file {
+            config.config_file = Some(path);
+        }
+        if let Some(addrs) = self.server_addr {
+            config.server_addrs = addrs;
+        }
+        if let Some(prefix) = self.endpoint_prefix {
+            config.endpoint_prefix = prefix;
+        }
+        if let Some(workers) = self.server_workers {
+            config.server_tuning.workers = workers;
+        }
+        if let Some(secs) = self.server_keep_alive {
+            config.server_tuning.keep_alive = std::time::Duration::from_secs(secs);
+        }
+        if let Some(ms) = self.server_client_request_timeout_ms {
+            config.server_tuning.client_request_timeout = std::time::Duration::from_millis(ms);
+        }
+        if let Some(max) = self.server_max_connections {
+            config.server_tuning.max_connections = max;
+        }
+        if let Some(backlog) = self.server_backlog {
+            config.server_tuning.backlog = backlog;
+        }
+        if let Some(disabled) = self.telemetry_disabled {
+            config.telemetry_disabled = disabled;
+        }
+        if let Some(endpoint) = self.otlp_endpoint {
+            config.otlp_endpoint = endpoint;
+        }
+        if let Some(path) = self.otlp_unix_socket {
+            config.otlp_unix_socket = Some(path);
+        }
+        for signal in Signal::ALL {
+            let exporter = config.exporter_mut(signal);
+            if let Some(protocol) = self.otlp_protocol {
+                exporter.protocol = protocol;
+            }
+            if let Some(compression) = self.otlp_compression {
+                exporter.compression = compression;
+            }
+            if let Some(headers) = &self.otlp_headers {
+                exporter.headers = headers.clone();
+            }
+        }
+        let signals = [
+            (
+                Signal::Traces,
+                self.otlp_traces_endpoint,
+                self.otlp_traces_protocol,
+                self.otlp_traces_compression,
+                self.otlp_traces_headers,
+            ),
+            (Signal::Logs, self.otlp_logs_endpoint, self.otlp_logs_protocol, self.otlp_logs_compression, self.otlp_logs_headers),
+            (
+                Signal::Metrics,
+                self.otlp_metrics_endpoint,
+                self.otlp_metrics_protocol,
+                self.otlp_metrics_compression,
+                self.otlp_metrics_headers,
+            ),
+        ];
+        for (signal, endpoint, protocol, compression, headers) in signals {
+            let exporter = config.exporter_mut(signal);
+            if let Some(endpoint) = endpoint {
+                exporter.endpoint = Some(endpoint).filter(|e| !e.is_empty());
+            }
+            if let Some(protocol) = protocol {
+                exporter.protocol = protocol;
+            }
+            if let Some(compression) = compression {
+                exporter.compression = compression;
+            }
+            if let Some(headers) = headers {
+                exporter.headers = headers;
+            }
+        }
+        if let Some(endpoint) = self.otlp_shadow_endpoint {
+            config.shadow_endpoint = Some(endpoint).filter(|e| !e.is_empty());
+        }
+        if let Some(protocol) = self.otlp_shadow_protocol {
+            config.shadow_exporter.protocol = protocol;
+        }
+        if let Some(compression) = self.otlp_shadow_compression {
+            config.shadow_exporter.compression = compression;
+        }
+        if let Some(headers) = self.otlp_shadow_headers {
+            config.shadow_exporter.headers = headers;
+        }
+        if let Some(name) = self.service_name {
+            config.service_name = name;
+        }
+        if let Some(version) = self.service_version {
+            config.service_version = version;
+        }
+        if let Some(id) = self.service_instance_id {
+            config.service_instance_id = id;
+        }
+        if let Some(environment) = self.deployment_environment {
+            config.deployment_environment = Some(environment).filter(|e| !e.is_empty());
+        }
+        if let Some(size) = self.trace_batch_max_queue_size {
+            config.trace_batch.max_queue_size = size;
+        }
+        if let Some(size) = self.trace_batch_max_export_batch_size {
+            config.trace_batch.max_export_batch_size = size;
+        }
+        if let Some(ms) = self.trace_batch_scheduled_delay_ms {
+            config.trace_batch.scheduled_delay = std::time::Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.trace_export_timeout_ms {
+            config.trace_batch.export_timeout = std::time::Duration::from_millis(ms);
+        }
+        if let Some(size) = self.log_batch_max_queue_size {
+            config.log_batch.max_queue_size = size;
+        }
+        if let Some(size) = self.log_batch_max_export_batch_size {
+            config.log_batch.max_export_batch_size = size;
+        }
+        if let Some(ms) = self.log_batch_scheduled_delay_ms {
+            config.log_batch.scheduled_delay = std::time::Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.log_export_timeout_ms {
+            config.log_batch.export_timeout = std::time::Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.metric_export_interval_ms {
+            config.metric_export_interval = std::time::Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.metric_export_timeout_ms {
+            config.metric_export_timeout = std::time::Duration::from_millis(ms);
+        }
+        if let Some(threshold) = self.export_circuit_breaker_threshold {
+            config.export_circuit_breaker_threshold = Some(threshold);
+        }
+        if let Some(secs) = self.export_circuit_breaker_open {
+            config.export_circuit_breaker_open = std::time::Duration::from_secs(secs);
+        }
+        if let Some(temporality) = self.metric_temporality {
+            config.metric_temporality = temporality;
+        }
+        if let Some(views) = self.metric_views {
+            config.metric_views = views;
+        }
+        if let Some(level) = self.log_level {
+            config.log_level = level;
+        }
+        if let Some(format) = self.log_format {
+            config.log_format = format;
+        }
+        if let Some(path) = self.log_file {
+            config.log_file = Some(path);
+        }
+        if let Some(mb) = self.log_file_max_size_mb {
+            config.log_file_max_size_mb = mb;
+        }
+        if let Some(rotation) = self.log_file_rotation {
+            config.log_file_rotation = rotation;
+        }
+        if let Some(max) = self.log_file_max_files {
+            config.log_file_max_files = max;
+        }
+        if let Some(enabled) = self.log_stdout {
+            config.log_stdout = enabled;
+        }
+        if let Some(target) = self.log_syslog {
+            config.log_syslog = Some(target);
+        }
+        if let Some(facility) = self.log_syslog_facility {
+            config.log_syslog_facility = facility;
+        }
+        if let Some(enabled) = self.log_journald {
+            config.log_journald = enabled;
+        }
+        if let Some(fields) = self.redact_fields {
+            config.redact_fields = fields;
+        }
+        if let Some(patterns) = self.redact_patterns {
+            config.redact_patterns = patterns;
+        }
+        if let Some(rules) = self.log_metrics {
+            config.log_metrics = rules;
+        }
+        if let Some(limit) = self.log_rate_limit_per_sec {
+            config.log_rate_limit = Some(limit);
+        }
+        if let Some(ratio) = self.log_debug_sample_ratio {
+            config.log_debug_sample_ratio = ratio;
+        }
+        if let Some(enabled) = self.audit_logs {
+            config.audit_logs = enabled;
+        }
+        if let Some(secs) = self.system_metrics_interval {
+            config.system_metrics_interval = std::time::Duration::from_secs(secs);
+        }
+        if let Some(ms) = self.event_loop_probe_interval_ms {
+            config.event_loop_probe_interval = std::time::Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.event_loop_blocked_threshold_ms {
+            config.event_loop_blocked_threshold = std::time::Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.slow_request_threshold_ms {
+            config.slow_request_threshold = std::time::Duration::from_millis(ms);
+        }
+        if let Some(enabled) = self.slow_request_profile {
+            config.slow_request_profile = enabled;
+        }
+        if let Some(mb) = self.memory_pressure_threshold_mb {
+            config.memory_pressure_threshold_mb = Some(mb);
+        }
+        if let Some(ms) = self.sli_latency_threshold_ms {
+            config.sli_latency_threshold = std::time::Duration::from_millis(ms);
+        }
+        if let Some(statuses) = self.sli_bad_statuses {
+            config.sli_bad_statuses = statuses;
+        }
+        if let Some(ms) = self.apdex_threshold_ms {
+            config.apdex_threshold = std::time::Duration::from_millis(ms);
+        }
+        if let Some(routes) = self.apdex_route_thresholds {
+            config.apdex_route_thresholds = routes;
+        }
+        if let Some(slos) = self.slos {
+            config.slos = slos;
+        }
+        if let Some(routes) = self.metrics_excluded_routes {
+            config.metrics_excluded_routes = routes;
+        }
+        if let Some(routes) = self.telemetry_excluded_routes {
+            config.telemetry_excluded_routes = routes;
+        }
+        if let Some(limits) = self.rate_limits {
+            config.rate_limits = limits;
+        }
+        if let Some(timeouts) = self.request_timeouts {
+            config.request_timeouts = timeouts;
+        }
+        if let Some(limits) = self.request_size_limits {
+            config.request_size_limits = limits;
+        }
+        if let Some(rules) = self.response_cache {
+            config.response_cache = rules;
+        }
+        if let Some(entries) = self.response_cache_max_entries {
+            config.response_cache_max_entries = entries;
+        }
+        if let Some(max) = self.metrics_max_label_sets {
+            config.metrics_max_label_sets = max;
+        }
+        if let Some(labels) = self.metrics_resource_labels {
+            config.metrics_resource_labels = labels;
+        }
+        if let Some(path) = self.metrics_catalog_path {
+            config.metrics_catalog_path = Some(path);
+        }
+        if let Some(ms) = self.metrics_cache_ttl_ms {
+            config.metrics_cache_ttl = std::time::Duration::from_millis(ms);
+        }
+        if let Some(enabled) = self.metrics_streaming {
+            config.metrics_streaming = enabled;
+        }
+        if let Some(strict) = self.metrics_naming_strict {
+            config.metrics_naming_strict = strict;
+        }
+        if let Some(dir) = self.metrics_multiprocess_dir {
+            config.metrics_multiprocess_dir = Some(dir);
+        }
+        if let Some(ms) = self.metrics_multiprocess_interval_ms {
+            config.metrics_multiprocess_interval = std::time::Duration::from_millis(ms);
+        }
+        if let Some(mode) = self.metrics_multiprocess_gauge_mode {
+            config.metrics_multiprocess_gauge_mode = mode;
+        }
+        if let Some(enabled) = self.metrics_native_histograms {
+            config.metrics_native_histograms = enabled;
+        }
+        if let Some(schema) = self.metrics_native_histogram_schema {
+            config.metrics_native_histogram_schema = schema;
+        }
+        if let Some(max) = self.metrics_native_histogram_max_buckets {
+            config.metrics_native_histogram_max_buckets = max;
+        }
+        if let Some(path) = self.tls_cert_path {
+            config.tls_cert_path = Some(path);
+        }
+        if let Some(path) = self.tls_key_path {
+            config.tls_key_path = Some(path);
+        }
+        if let Some(token) = self.metrics_bearer_token {
+            config.metrics_credentials = Some(ScrapeCredentials::Bearer(token));
+        }
+        if let Some(credentials) = self.metrics_basic_auth {
+            config.metrics_credentials = Some(credentials);
+        }
+        if let Some(ips) = self.metrics_allowed_ips {
+            config.metrics_allowed_ips = ips;
+        }
+        if let Some(enabled) = self.otlp_receiver {
+            config.otlp_receiver = enabled;
+        }
+        if let Some(ips) = self.otlp_receiver_allowed_ips {
+            config.otlp_receiver_allowed_ips = ips;
+        }
+        if let Some(enabled) = self.trace_response_header {
+            config.trace_response_header = enabled;
+        }
+        if let Some(keys) = self.baggage_keys {
+            config.baggage_keys = keys;
+        }
+        if let Some(enabled) = self.client_labels {
+            config.client_labels = enabled;
+        }
+        if let Some(subnets) = self.client_subnets {
+            config.client_subnets = subnets;
+        }
+        if let Some(source) = self.tenant_source {
+            config.tenant_source = Some(source);
+        }
+        if let Some(tenants) = self.tenant_allowlist {
+            config.tenant_allowlist = tenants;
+        }
+        if let Some(buckets) = self.tenant_hash_buckets {
+            config.tenant_hash_buckets = Some(buckets);
+        }
+        if let Some(enabled) = self.span_metrics {
+            config.span_metrics = enabled;
+        }
+        if let Some(enabled) = self.tail_sampling {
+            config.tail_sampling = enabled;
+        }
+        if let Some(ms) = self.tail_sampling_window_ms {
+            config.tail_sampling_window = std::time::Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.tail_sampling_latency_threshold_ms {
+            config.tail_sampling_latency_threshold = std::time::Duration::from_millis(ms);
+        }
+        if let Some(traces) = self.tail_sampling_max_traces {
+            config.tail_sampling_max_traces = traces;
+        }
+        if let Some(enabled) = self.metrics_otlp_bridge {
+            config.metrics_otlp_bridge = enabled;
+        }
+        if let Some(token) = self.admin_token {
+            config.admin_token = Some(token);
+        }
+        if let Some(addr) = self.grpc_health_addr {
+            config.grpc_health_addr = Some(addr);
+        }
+        if let Some(targets) = self.federate_targets {
+            config.federate_targets = targets;
+        }
+        if let Some(secs) = self.federate_interval {
+            config.federate_interval = std::time::Duration::from_secs(secs);
+        }
+        if let Some(addr) = self.statsd_addr {
+            config.statsd_addr = Some(addr);
+        }
+        if let Some(enabled) = self.debug_tap {
+            config.debug_tap = enabled;
+        }
+        if let Some(buffer) = self.debug_tap_buffer {
+            config.debug_tap_buffer = buffer;
+        }
+        if let Some(ratio) = self.debug_tap_sample_ratio {
+            config.debug_tap_sample_ratio = ratio;
+        }
+        if let Some(url) = self.flags_url {
+            config.flags_url = Some(url);
+        }
+        if let Some(key) = self.flags_sdk_key {
+            config.flags_sdk_key = Some(key);
+        }
+        if let Some(secs) = self.flags_poll_interval {
+            config.flags_poll_interval = std::time::Duration::from_secs(secs);
+        }
+        if let Some(url) = self.remote_sampling_url {
+            config.remote_sampling_url = Some(url);
+        }
+        if let Some(secs) = self.remote_sampling_interval {
+            config.remote_sampling_interval = std::time::Duration::from_secs(secs);
+        }
+        if let Some(ratio) = self.remote_sampling_initial_ratio {
+            config.remote_sampling_initial_ratio = ratio;
+        }
+        if let Some(url) = self.pyroscope_url {
+            config.pyroscope_url = Some(url);
+        }
+        if let Some(token) = self.pyroscope_auth_token {
+            config.pyroscope_credentials = Some(ScrapeCredentials::Bearer(token));
+        }
+        if let Some(credentials) = self.pyroscope_basic_auth {
+            config.pyroscope_credentials = Some(credentials);
+        }
+        if let Some(secs) = self.pyroscope_upload_interval {
+            config.pyroscope_upload_interval = std::time::Duration::from_secs(secs);
+        }
+        if let Some(order) = self.shutdown_flush_order {
+            config.shutdown_flush_order = order;
+        }
+        if let Some(tokens) = self.app_auth_tokens {
+            config.app_auth_tokens = tokens;
+        }
+        if let Some(url) = self.app_auth_jwks_url {
+            config.app_auth_jwks_url = Some(url);
+        }
+        if let Some(issuer) = self.app_auth_issuer {
+            config.app_auth_issuer = Some(issuer);
+        }
+        if let Some(audience) = self.app_auth_audience {
+            config.app_auth_audience = Some(audience);
+        }
+        if let Some(claim) = self.app_auth_principal_claim {
+            config.app_auth_principal_claim = claim;
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Low-cardinality labels for the client of a request (`CLIENT_LABELS`).
+//!
+//! Each tracked request is classified by:
+//!
+//! - `user_agent`: the family of its `User-Agent`, e.g. `chrome`, `curl`,
+//!   `python`, `kube-probe` or `bot`; `other` when none matches and `none`
+//!   without the header;
+//! - `subnet`: the name of the first `CLIENT_SUBNETS` entry (`name=cidr`)
+//!   containing the peer address, otherwise its class, `loopback`,
+//!   `private`, `link_local` or `public`; `unknown` without one (Unix
+//!   sockets);
+//! - `tls_version`: `1.2` or `1.3` for HTTPS connections (`tls` feature),
+//!   `none` otherwise. It is read once per connection.
+//!
+//! They are counted in `http_server_requests_by_client_total{user_agent,subnet,tls_version}`
+//! and set on the server span as `user_agent.name`, `client.subnet` and
+//! `tls.protocol.version`. The values come from tables interned at startup,
+//! so classifying a request allocates nothing.
+
+use crate::{auth::Cidr, registry::Registry};
+use actix_web::{dev::ServiceRequest, http::header};
+#[cfg(feature = "traces")]
+use opentelemetry::KeyValue;
+use prometheus::{IntCounterVec, Opts};
+#[cfg(feature = "tls")]
+use std::any::Any;
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+};
+
+/// `User-Agent` substrings and their family, checked in order: bots and
+/// tools before browsers, and browsers before those whose agent strings
+/// they also contain (Edge and Opera say `Chrome/`, Chrome says `Safari/`).
+const USER_AGENT_FAMILIES: &[(&str, &str)] = &[
+    ("kube-probe/", "kube-probe"),
+    ("Prometheus/", "prometheus"),
+    ("bot", "bot"),
+    ("Bot", "bot"),
+    ("spider", "bot"),
+    ("crawler", "bot"),
+    ("curl/", "curl"),
+    ("Wget/", "wget"),
+    ("PostmanRuntime/", "postman"),
+    ("python-requests/", "python"),
+    ("python-httpx/", "python"),
+    ("aiohttp/", "python"),
+    ("Python-urllib/", "python"),
+    ("Go-http-client/", "go"),
+    ("okhttp/", "okhttp"),
+    ("Java/", "java"),
+    ("Apache-HttpClient/", "java"),
+    ("node-fetch/", "node"),
+    ("axios/", "node"),
+    ("undici", "node"),
+    ("Edg/", "edge"),
+    ("OPR/", "opera"),
+    ("Firefox/", "firefox"),
+    ("Chrome/", "chrome"),
+    ("CriOS/", "chrome"),
+    ("Safari/", "safari"),
+];
+
+/// Classes of peer addresses outside every `CLIENT_SUBNETS` entry.
+const SUBNET_CLASSES: [&str; 5] = ["loopback", "private", "link_local", "public", "unknown"];
+
+/// A named client network, parsed from `name=cidr`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientSubnet {
+    pub name: String,
+    pub cidr: Cidr,
+}
+
+impl FromStr for ClientSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, cidr) = s
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("expected `name=cidr`, got {s:?}"))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(format!("{s:?}: the name must be made of letters, digits, `_` and `-`"));
+        }
+        Ok(Self { name: name.to_string(), cidr: cidr.trim().parse()? })
+    }
+}
+
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.cidr)
+    }
+}
+
+/// The TLS version of a connection, stored in its extensions by
+/// `on_connect`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TlsVersion(pub &'static str);
+
+impl TlsVersion {
+    /// The version negotiated on `connection`, if it is a rustls stream.
+    #[cfg(feature = "tls")]
+    pub fn of(connection: &dyn Any) -> Option<Self> {
+        use actix_tls::accept::rustls_0_23::TlsStream;
+        use actix_web::rt::net::TcpStream;
+        use rustls::ProtocolVersion;
+
+        let stream = connection.downcast_ref::<TlsStream<TcpStream>>()?;
+        let (_, session) = stream.get_ref();
+        Some(Self(match session.protocol_version()? {
+            ProtocolVersion::TLSv1_3 => "1.3",
+            ProtocolVersion::TLSv1_2 => "1.2",
+            _ => "other",
+        }))
+    }
+}
+
+/// The labels of one request.
+#[derive(Clone, Debug)]
+pub struct ClientAttributes {
+    pub user_agent: &'static str,
+    pub subnet: Arc<str>,
+    pub tls_version: &'static str,
+}
+
+impl ClientAttributes {
+    /// The server span attributes.
+    #[cfg(feature = "traces")]
+    pub fn key_values(&self) -> [KeyValue; 3] {
+        [
+            KeyValue::new("user_agent.name", self.user_agent),
+            KeyValue::new("client.subnet", self.subnet.clone()),
+            KeyValue::new("tls.protocol.version", self.tls_version),
+        ]
+    }
+}
+
+/// The interned subnet names and `http_server_requests_by_client_total`.
+#[derive(Clone, Debug)]
+pub struct ClientLabels {
+    subnets: Vec<(Cidr, Arc<str>)>,
+    classes: [Arc<str>; SUBNET_CLASSES.len()],
+    requests: IntCounterVec,
+}
+
+impl ClientLabels {
+    pub fn new(registry: &Registry, subnets: &[ClientSubnet]) -> prometheus::Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "http_server_requests_by_client_total",
+                "HTTP requests by user agent family, client subnet and TLS version",
+            ),
+            &["user_agent", "subnet", "tls_version"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        // Entries sharing a name share its string.
+        let mut names: Vec<Arc<str>> = Vec::new();
+        let subnets = subnets
+            .iter()
+            .map(|subnet| {
+                let name = match names.iter().find(|name| ***name == *subnet.name) {
+                    Some(name) => name.clone(),
+                    None => {
+                        names.push(Arc::from(subnet.name.as_str()));
+                        names[names.len() - 1].clone()
+                    }
+                };
+                (subnet.cidr, name)
+            })
+            .collect();
+        Ok(Self { subnets, classes: SUBNET_CLASSES.map(Arc::from), requests })
+    }
+
+    pub fn classify(&self, req: &ServiceRequest) -> ClientAttributes {
+        let user_agent = match req.headers().get(header::USER_AGENT).map(|value| value.to_str()) {
+            None => "none",
+            Some(Ok(agent)) => USER_AGENT_FAMILIES
+                .iter()
+                .find(|(needle, _)| agent.contains(needle))
+                .map_or("other", |(_, family)| family),
+            Some(Err(_)) => "other",
+        };
+        let subnet = self.subnet(req.peer_addr().map(|peer| peer.ip())).clone();
+        let tls_version = req.conn_data::<TlsVersion>().map_or("none", |version| version.0);
+        ClientAttributes { user_agent, subnet, tls_version }
+    }
+
+    /// Counts a request of `client`.
+    pub fn observe(&self, client: &ClientAttributes) {
+        self.requests.with_label_values(&[client.user_agent, &client.subnet, client.tls_version]).inc();
+    }
+
+    fn subnet(&self, peer: Option<IpAddr>) -> &Arc<str> {
+        let Some(peer) = peer else {
+            return &self.classes[4];
+        };
+        if let Some((_, name)) = self.subnets.iter().find(|(cidr, _)| cidr.contains(peer)) {
+            return name;
+        }
+        let peer = match peer {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(peer, IpAddr::V4),
+            v4 => v4,
+        };
+        let class = match peer {
+            IpAddr::V4(v4) if v4.is_loopback() => 0,
+            IpAddr::V4(v4) if v4.is_private() => 1,
+            IpAddr::V4(v4) if v4.is_link_local() => 2,
+            IpAddr::V6(v6) if v6.is_loopback() => 0,
+            IpAddr::V6(v6) if v6.is_unique_local() => 1,
+            IpAddr::V6(v6) if v6.is_unicast_link_local() => 2,
+            _ => 3,
+        };
+        &self.classes[class]
+    }
+}
@@ -0,0 +1,2261 @@
+//! Effective runtime configuration.
+//!
+//! Values are resolved in layers: built-in defaults, then the JSON
+//! `CONFIG_FILE` if one is given, then environment variables, then
+//! command-line flags (see `cli::Overrides`). `config_reload` applies edits
+//! of the file at runtime.
+//!
+//! Applications embedding the server can also build a `TelemetryConfig`
+//! themselves, with the `with_*` setters or by deserializing it: fields
+//! missing from the document keep their defaults. Values use the syntax of
+//! their environment variables (`"http://0.0.0.0:8888"`, `"logs:2000"`,
+//! `"5xx"`, ...) and durations are strings such as `"250ms"` or `"5s"`.
+//! Serializing writes secrets (`admin_token`, credentials, exporter headers)
+//! in clear.
+
+use crate::{
+    apdex::{ApdexThresholds, RouteApdex},
+    app_auth::{AppToken, DEFAULT_PRINCIPAL_CLAIM},
+    auth::{Cidr, ScrapeCredentials},
+    client_labels::ClientSubnet,
+    baggage,
+    cardinality::DEFAULT_MAX_LABEL_SETS,
+    cli::Overrides,
+    federation::FederateTarget,
+    file_sink::Rotation,
+    listener::{parse_listeners, Listener, Scheme},
+    log_format::LogFormat,
+    log_metrics::{parse_rules, LogMetricRule},
+    rate_limit::RateLimit,
+    request_size::RequestSizeLimit,
+    response_cache::ResponseCacheRule,
+    tenant::TenantSource,
+    timeout::RequestTimeout,
+    metric_views::{parse_views, MetricTemporality, MetricView},
+    multiprocess::GaugeMode,
+    native_histogram::{NativeHistogramOpts, MAX_SCHEMA, MIN_SCHEMA},
+    otlp_exporter::{parse_headers, ExporterSettings, OtlpCompression, OtlpProtocol},
+    pipeline::Signal,
+    redact::{Redactor, DEFAULT_REDACT_FIELDS},
+    shutdown::{parse_flush_order, FlushStep, DEFAULT_FLUSH_TIMEOUT},
+    sli::{parse_status_list, StatusMatcher},
+    slo::SloRule,
+    system_log::{Facility, SyslogTarget},
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+const DEFAULT_SERVER_ADDR: &str = "0.0.0.0:8888";
+const DEFAULT_SERVER_KEEP_ALIVE_SECS: u64 = 5;
+const DEFAULT_SERVER_CLIENT_REQUEST_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_SERVER_MAX_CONNECTIONS: usize = 25_000;
+const DEFAULT_SERVER_BACKLOG: u32 = 1024;
+const DEFAULT_OTLP_ENDPOINT: &str = "http://otel-collector:4318";
+const DEFAULT_SERVICE_NAME: &str = "otlp-actix-http-example";
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_SYSTEM_METRICS_INTERVAL_SECS: u64 = 5;
+const DEFAULT_EVENT_LOOP_PROBE_INTERVAL_MS: u64 = 100;
+const DEFAULT_EVENT_LOOP_BLOCKED_THRESHOLD_MS: u64 = 1_000;
+const DEFAULT_SLI_LATENCY_THRESHOLD_MS: u64 = 300;
+const DEFAULT_APDEX_THRESHOLD_MS: u64 = 500;
+const DEFAULT_LOG_FILE_MAX_SIZE_MB: u64 = 100;
+const DEFAULT_LOG_FILE_MAX_FILES: usize = 7;
+const DEFAULT_BATCH_MAX_QUEUE_SIZE: usize = 2048;
+const DEFAULT_BATCH_MAX_EXPORT_BATCH_SIZE: usize = 512;
+const DEFAULT_BATCH_SCHEDULED_DELAY_MS: u64 = 5_000;
+const DEFAULT_EXPORT_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_EXPORT_CIRCUIT_BREAKER_OPEN_SECS: u64 = 30;
+const DEFAULT_METRIC_EXPORT_INTERVAL_MS: u64 = 60_000;
+const DEFAULT_FLAGS_POLL_INTERVAL_SECS: u64 = 30;
+const DEFAULT_REMOTE_SAMPLING_INTERVAL_SECS: u64 = 60;
+/// The OTel specification's default `initialSamplingRate`.
+const DEFAULT_REMOTE_SAMPLING_INITIAL_RATIO: f64 = 0.001;
+const DEFAULT_FEDERATE_INTERVAL_SECS: u64 = 15;
+const DEFAULT_PYROSCOPE_UPLOAD_INTERVAL_SECS: u64 = 10;
+const DEFAULT_DEBUG_TAP_BUFFER: usize = 1000;
+const DEFAULT_TAIL_SAMPLING_WINDOW_MS: u64 = 10_000;
+const DEFAULT_TAIL_SAMPLING_LATENCY_THRESHOLD_MS: u64 = 1_000;
+const DEFAULT_TAIL_SAMPLING_MAX_TRACES: usize = 10_000;
+const DEFAULT_RESPONSE_CACHE_MAX_ENTRIES: usize = 1_000;
+const DEFAULT_TELEMETRY_EXCLUDED_ROUTES: &[&str] = &["/metrics", "/healthz", "/readyz"];
+const DEFAULT_METRICS_RESOURCE_LABELS: &[&str] = &["service.instance.id", "deployment.environment.name"];
+const DEFAULT_METRICS_MULTIPROCESS_INTERVAL_MS: u64 = 1_000;
+const DEFAULT_METRICS_NATIVE_HISTOGRAM_SCHEMA: i32 = 3;
+/// The Go client's default `NativeHistogramMaxBucketNumber`.
+const DEFAULT_METRICS_NATIVE_HISTOGRAM_MAX_BUCKETS: usize = 160;
+
+/// Exporter settings fields by signal, for validation and display.
+const EXPORTERS: [(&str, Signal); 3] =
+    [("trace_exporter", Signal::Traces), ("log_exporter", Signal::Logs), ("metric_exporter", Signal::Metrics)];
+
+/// Resource attributes `metrics_resource_labels` can name.
+pub const CORRELATION_ATTRIBUTES: &[&str] =
+    &["service.name", "service.version", "service.instance.id", "deployment.environment.name"];
+
+/// Tuning for a span or log record batch processor and its exporter. The
+/// defaults are the SDK's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSettings {
+    /// Items buffered before new ones are dropped.
+    pub max_queue_size: usize,
+    /// Items sent per export request.
+    pub max_export_batch_size: usize,
+    /// Delay between two exports when the batch isn't full.
+    #[serde(with = "duration")]
+    pub scheduled_delay: Duration,
+    /// Timeout of a single export request.
+    #[serde(with = "duration")]
+    pub export_timeout: Duration,
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        Self {
+            max_queue_size: DEFAULT_BATCH_MAX_QUEUE_SIZE,
+            max_export_batch_size: DEFAULT_BATCH_MAX_EXPORT_BATCH_SIZE,
+            scheduled_delay: Duration::from_millis(DEFAULT_BATCH_SCHEDULED_DELAY_MS),
+            export_timeout: Duration::from_millis(DEFAULT_EXPORT_TIMEOUT_MS),
+        }
+    }
+}
+
+impl BatchSettings {
+    /// Overrides from the standard `<prefix>_*` variables (`OTEL_BSP` for
+    /// spans, `OTEL_BLRP` for logs); durations are in milliseconds.
+    fn apply_env(&mut self, prefix: &str) -> Result<(), ConfigError> {
+        if let Some(size) = env_int(&format!("{prefix}_MAX_QUEUE_SIZE"))? {
+            self.max_queue_size = size;
+        }
+        if let Some(size) = env_int(&format!("{prefix}_MAX_EXPORT_BATCH_SIZE"))? {
+            self.max_export_batch_size = size;
+        }
+        if let Some(ms) = env_int(&format!("{prefix}_SCHEDULE_DELAY"))? {
+            self.scheduled_delay = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_int(&format!("{prefix}_EXPORT_TIMEOUT"))? {
+            self.export_timeout = Duration::from_millis(ms);
+        }
+        Ok(())
+    }
+
+    fn validate(&self, name: &str) -> Result<(), ConfigError> {
+        if self.max_queue_size == 0 || self.max_export_batch_size == 0 {
+            return Err(ConfigError(format!("{name}: queue and batch sizes must be greater than zero")));
+        }
+        if self.max_export_batch_size > self.max_queue_size {
+            return Err(ConfigError(format!(
+                "{name}: max_export_batch_size {} exceeds max_queue_size {}",
+                self.max_export_batch_size, self.max_queue_size
+            )));
+        }
+        if self.scheduled_delay.is_zero() || self.export_timeout.is_zero() {
+            return Err(ConfigError(format!("{name}: scheduled_delay and export_timeout must be greater than zero")));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for BatchSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "queue {}, batch {}, delay {}ms, timeout {}ms",
+            self.max_queue_size,
+            self.max_export_batch_size,
+            self.scheduled_delay.as_millis(),
+            self.export_timeout.as_millis()
+        )
+    }
+}
+
+/// Tuning of the actix-web HTTP server. The defaults are actix-web's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerTuning {
+    /// Worker threads, one per CPU by default.
+    pub workers: usize,
+    /// How long an idle connection is kept open; zero disables keep-alive.
+    #[serde(with = "duration")]
+    pub keep_alive: Duration,
+    /// Time a client has to send the request head; zero disables the
+    /// timeout.
+    #[serde(with = "duration")]
+    pub client_request_timeout: Duration,
+    /// Concurrent connections per worker before it stops accepting new ones.
+    pub max_connections: usize,
+    /// Pending connection queue of each TCP listener.
+    pub backlog: u32,
+}
+
+impl Default for ServerTuning {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get),
+            keep_alive: Duration::from_secs(DEFAULT_SERVER_KEEP_ALIVE_SECS),
+            client_request_timeout: Duration::from_millis(DEFAULT_SERVER_CLIENT_REQUEST_TIMEOUT_MS),
+            max_connections: DEFAULT_SERVER_MAX_CONNECTIONS,
+            backlog: DEFAULT_SERVER_BACKLOG,
+        }
+    }
+}
+
+impl ServerTuning {
+    /// Overrides from the `SERVER_*` variables.
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(workers) = env_int("SERVER_WORKERS")? {
+            self.workers = workers;
+        }
+        if let Some(secs) = env_int("SERVER_KEEP_ALIVE_SECS")? {
+            self.keep_alive = Duration::from_secs(secs);
+        }
+        if let Some(ms) = env_int("SERVER_CLIENT_REQUEST_TIMEOUT_MS")? {
+            self.client_request_timeout = Duration::from_millis(ms);
+        }
+        if let Some(max) = env_int("SERVER_MAX_CONNECTIONS")? {
+            self.max_connections = max;
+        }
+        if let Some(backlog) = env_int("SERVER_BACKLOG")? {
+            self.backlog = backlog;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.workers == 0 || self.max_connections == 0 || self.backlog == 0 {
+            return Err(ConfigError(
+                "server_tuning: workers, max_connections and backlog must be greater than zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ServerTuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "workers {}, keep-alive {}s, client request timeout {}ms, max connections {}/worker, backlog {}",
+            self.workers,
+            self.keep_alive.as_secs(),
+            self.client_request_timeout.as_millis(),
+            self.max_connections,
+            self.backlog
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// JSON document the configuration is read from, under the environment
+    /// variables and flags, and reloaded from on `SIGHUP` or when it changes
+    /// (`CONFIG_FILE`, unset by default, see `config_reload`).
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+    /// Flags given on the command line, applied again over a reloaded
+    /// `config_file`.
+    #[serde(skip)]
+    pub cli_overrides: Option<Arc<Overrides>>,
+    /// Addresses the HTTP server listens on, each with its scheme
+    /// (`SERVER_ADDR`, comma-separated, see `listener`).
+    pub server_addrs: Vec<Listener>,
+    /// Path prefix of the built-in endpoints (`/metrics`, `/admin`, ...),
+    /// empty to mount them at the root (`ENDPOINT_PREFIX`, e.g.
+    /// `/internal/telemetry`).
+    pub endpoint_prefix: String,
+    /// Workers, timeouts and connection limits of the HTTP server
+    /// (`SERVER_WORKERS`, `SERVER_KEEP_ALIVE_SECS`,
+    /// `SERVER_CLIENT_REQUEST_TIMEOUT_MS`, `SERVER_MAX_CONNECTIONS`,
+    /// `SERVER_BACKLOG`).
+    pub server_tuning: ServerTuning,
+    /// Skips every OTLP exporter, the `/metrics` endpoint and the local log
+    /// outputs still working, e.g. for `cargo run` without a collector
+    /// (`OTEL_SDK_DISABLED`).
+    pub telemetry_disabled: bool,
+    /// Base URL of the OTLP collector (`OTEL_EXPORTER_OTLP_ENDPOINT`).
+    pub otlp_endpoint: String,
+    /// Unix domain socket of the collector, through which every OTLP/HTTP
+    /// export is sent, the endpoint URLs only giving paths and `Host`
+    /// (`OTLP_UNIX_SOCKET`, Unix only).
+    pub otlp_unix_socket: Option<PathBuf>,
+    /// Endpoint, protocol and headers of the span exporter
+    /// (`OTEL_EXPORTER_OTLP_TRACES_*`, see `otlp_exporter`).
+    pub trace_exporter: ExporterSettings,
+    /// Same for the log exporter (`OTEL_EXPORTER_OTLP_LOGS_*`).
+    pub log_exporter: ExporterSettings,
+    /// Same for the metric exporter (`OTEL_EXPORTER_OTLP_METRICS_*`).
+    pub metric_exporter: ExporterSettings,
+    /// Base URL of a second collector every signal is also exported to,
+    /// e.g. the backend being migrated to; off when unset
+    /// (`OTEL_EXPORTER_OTLP_SHADOW_ENDPOINT`, see `shadow_export`).
+    pub shadow_endpoint: Option<String>,
+    /// Protocol, compression and headers of the shadow exporters
+    /// (`OTEL_EXPORTER_OTLP_SHADOW_{PROTOCOL,COMPRESSION,HEADERS}`), which
+    /// don't inherit the primary ones; `endpoint` must be unset.
+    pub shadow_exporter: ExporterSettings,
+    /// `service.name` resource attribute (`OTEL_SERVICE_NAME`).
+    pub service_name: String,
+    /// `service.version` resource attribute (`SERVICE_VERSION`, the crate
+    /// version by default).
+    pub service_version: String,
+    /// `service.instance.id` resource attribute (`SERVICE_INSTANCE_ID`, the
+    /// host name by default, i.e. the pod name on Kubernetes).
+    pub service_instance_id: String,
+    /// `deployment.environment.name` resource attribute, left out when unset
+    /// (`DEPLOYMENT_ENVIRONMENT`).
+    pub deployment_environment: Option<String>,
+    /// Span batch processor tuning (`OTEL_BSP_*`).
+    pub trace_batch: BatchSettings,
+    /// Log record batch processor tuning (`OTEL_BLRP_*`).
+    pub log_batch: BatchSettings,
+    /// Interval of the periodic metric reader (`OTEL_METRIC_EXPORT_INTERVAL`,
+    /// milliseconds).
+    #[serde(with = "duration")]
+    pub metric_export_interval: Duration,
+    /// Timeout of a single metric export (`OTEL_METRIC_EXPORT_TIMEOUT`,
+    /// milliseconds).
+    #[serde(with = "duration")]
+    pub metric_export_timeout: Duration,
+    /// Failed export batches in a row after which a signal's circuit opens,
+    /// disabled when unset (`EXPORT_CIRCUIT_BREAKER_THRESHOLD`, see
+    /// `circuit_breaker`).
+    pub export_circuit_breaker_threshold: Option<u32>,
+    /// How long an open circuit drops batches before probing the collector
+    /// (`EXPORT_CIRCUIT_BREAKER_OPEN_SECS`).
+    #[serde(with = "duration")]
+    pub export_circuit_breaker_open: Duration,
+    /// Temporality requested from the OTLP metric exporter
+    /// (`OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE`).
+    pub metric_temporality: MetricTemporality,
+    /// Views applied to OTel instruments (`METRIC_VIEWS`, `;`-separated, see
+    /// `metric_views`).
+    pub metric_views: Vec<MetricView>,
+    /// Filter directive for local and OTLP logs (`RUST_LOG`).
+    pub log_level: String,
+    /// `text` or `json` for the stdout log output (`LOG_FORMAT`).
+    pub log_format: LogFormat,
+    /// Also write logs to this file, in `log_format` without colors
+    /// (`LOG_FILE`, unset by default).
+    pub log_file: Option<PathBuf>,
+    /// Rotate the log file once it reaches this size; 0 disables size based
+    /// rotation (`LOG_FILE_MAX_SIZE_MB`).
+    pub log_file_max_size_mb: u64,
+    /// `never`, `hourly` or `daily` time based rotation (`LOG_FILE_ROTATION`).
+    pub log_file_rotation: Rotation,
+    /// Rotated log files kept besides the active one (`LOG_FILE_MAX_FILES`).
+    pub log_file_max_files: usize,
+    /// Write logs to stdout (`LOG_STDOUT`, default true); off where another
+    /// sink already gets them, e.g. journald capturing a service's stdout.
+    pub log_stdout: bool,
+    /// Also send logs to syslog, `unix:<path>` or `udp:<host>:<port>`
+    /// (`LOG_SYSLOG`, unset by default, see `system_log`).
+    pub log_syslog: Option<SyslogTarget>,
+    /// Facility of the syslog messages (`LOG_SYSLOG_FACILITY`).
+    pub log_syslog_facility: Facility,
+    /// Also send logs to systemd-journald (`LOG_JOURNALD`, Unix only).
+    pub log_journald: bool,
+    /// Field and attribute names whose values are redacted from logs and
+    /// spans (`REDACT_FIELDS`, comma-separated).
+    pub redact_fields: Vec<String>,
+    /// Regexes, or the presets `email` and `card_number`, whose matches are
+    /// redacted from string values (`REDACT_PATTERNS`, whitespace-separated).
+    pub redact_patterns: Vec<String>,
+    /// Rules counting matching log events in `log_rule_matches_total`
+    /// (`LOG_METRICS`, `;`-separated, see `log_metrics`).
+    pub log_metrics: Vec<LogMetricRule>,
+    /// Log events exported per second from one callsite at most, unlimited
+    /// when unset (`LOG_RATE_LIMIT_PER_SEC`, requires the `logs` feature, see
+    /// `log_sampling`).
+    pub log_rate_limit: Option<u32>,
+    /// Share of the `debug` and `trace` log events exported
+    /// (`LOG_DEBUG_SAMPLE_RATIO`, requires the `logs` feature).
+    pub log_debug_sample_ratio: f64,
+    /// Export `audit!` events on a log stream of their own, unsampled and
+    /// exported one by one (`AUDIT_LOGS`, requires the `logs` feature, see
+    /// `audit`).
+    pub audit_logs: bool,
+    /// How often process CPU/memory gauges are refreshed
+    /// (`SYSTEM_METRICS_INTERVAL_SECS`).
+    #[serde(with = "duration")]
+    pub system_metrics_interval: Duration,
+    /// How often the event loop probes wake to measure runtime lag, 0 to
+    /// disable them (`EVENT_LOOP_PROBE_INTERVAL_MS`, see `event_loop`).
+    #[serde(with = "duration")]
+    pub event_loop_probe_interval: Duration,
+    /// A runtime whose probe hasn't woken for this long counts as blocked
+    /// (`EVENT_LOOP_BLOCKED_THRESHOLD_MS`).
+    #[serde(with = "duration")]
+    pub event_loop_blocked_threshold: Duration,
+    /// Requests still in flight this long are flagged, `0` disables the
+    /// watchdog (`SLOW_REQUEST_THRESHOLD_MS`, see `slow_requests`).
+    #[serde(with = "duration")]
+    pub slow_request_threshold: Duration,
+    /// Profile the worker of each flagged request (`SLOW_REQUEST_PROFILE`,
+    /// requires the `pprof` feature).
+    pub slow_request_profile: bool,
+    /// RSS from which telemetry is shed, disabled when unset
+    /// (`MEMORY_PRESSURE_THRESHOLD_MB`).
+    pub memory_pressure_threshold_mb: Option<u64>,
+    /// Requests slower than this are not "good" for the SLI
+    /// (`SLI_LATENCY_THRESHOLD_MS`).
+    #[serde(with = "duration")]
+    pub sli_latency_threshold: Duration,
+    /// Status codes or classes that count against the SLI, e.g. `5xx,429`
+    /// (`SLI_BAD_STATUSES`).
+    pub sli_bad_statuses: Vec<StatusMatcher>,
+    /// Apdex target: requests up to this latency are satisfied, up to four
+    /// times it tolerated (`APDEX_THRESHOLD_MS`).
+    #[serde(with = "duration")]
+    pub apdex_threshold: Duration,
+    /// Per-route Apdex thresholds as `/route=satisfied_ms[:tolerating_ms]`
+    /// (`APDEX_ROUTE_THRESHOLDS`, comma-separated).
+    pub apdex_route_thresholds: Vec<RouteApdex>,
+    /// Per-route SLOs as `/route=target` (availability) or
+    /// `/route=target:latency_ms`, the target a percentage (`SLOS`,
+    /// comma-separated).
+    pub slos: Vec<SloRule>,
+    /// Route patterns excluded from request metrics, e.g. `/metrics`
+    /// (`METRICS_EXCLUDED_ROUTES`, comma-separated).
+    pub metrics_excluded_routes: Vec<String>,
+    /// Route patterns whose requests produce no telemetry at all: no spans,
+    /// no OTLP logs and no request metrics (`TELEMETRY_EXCLUDED_ROUTES`,
+    /// comma-separated).
+    pub telemetry_excluded_routes: Vec<String>,
+    /// Token-bucket limits as `/route=requests/period[:burst][@key]`
+    /// (`RATE_LIMITS`, comma-separated, see `rate_limit`).
+    pub rate_limits: Vec<RateLimit>,
+    /// Handler deadlines as `/route=ms[:status]` (`REQUEST_TIMEOUTS`,
+    /// comma-separated, see `timeout`).
+    pub request_timeouts: Vec<RequestTimeout>,
+    /// Request body limits as `/route=size` (`REQUEST_SIZE_LIMITS`,
+    /// comma-separated, see `request_size`).
+    pub request_size_limits: Vec<RequestSizeLimit>,
+    /// Response TTLs as `/route=ms` (`RESPONSE_CACHE`, comma-separated, see
+    /// `response_cache`).
+    pub response_cache: Vec<ResponseCacheRule>,
+    /// Responses held by the response cache before the one expiring first
+    /// is dropped (`RESPONSE_CACHE_MAX_ENTRIES`).
+    pub response_cache_max_entries: usize,
+    /// Distinct label sets allowed per labeled metric before new ones are
+    /// folded into `other` (`METRICS_MAX_LABEL_SETS`).
+    pub metrics_max_label_sets: usize,
+    /// Resource attributes added to every Prometheus series as constant
+    /// labels, with dots replaced by underscores, so series join with traces
+    /// (`METRICS_RESOURCE_LABELS`, comma-separated).
+    pub metrics_resource_labels: Vec<String>,
+    /// Write the metric catalog (see `catalog`) to this file on startup
+    /// (`METRICS_CATALOG_PATH`, unset by default).
+    pub metrics_catalog_path: Option<PathBuf>,
+    /// Serve the same encoded `/metrics` response for this long, 0 to encode
+    /// it on every scrape (`METRICS_CACHE_TTL_MS`, see `scrape_cache`).
+    #[serde(with = "duration")]
+    pub metrics_cache_ttl: Duration,
+    /// Encode `/metrics` family by family into a chunked response instead of
+    /// one buffer, for very large registries (`METRICS_STREAMING`). Can't be
+    /// combined with `metrics_cache_ttl`.
+    pub metrics_streaming: bool,
+    /// Reject collectors registered through `AppMetrics::register` that
+    /// break the naming conventions instead of logging them
+    /// (`METRICS_NAMING_STRICT`, see `naming`).
+    pub metrics_naming_strict: bool,
+    /// Directory shared by the worker processes of the service, each writing
+    /// its metrics there for `/metrics` to serve them summed
+    /// (`METRICS_MULTIPROCESS_DIR`, unset by default, see `multiprocess`).
+    pub metrics_multiprocess_dir: Option<PathBuf>,
+    /// How often this process writes its metrics to
+    /// `metrics_multiprocess_dir` (`METRICS_MULTIPROCESS_INTERVAL_MS`).
+    #[serde(with = "duration")]
+    pub metrics_multiprocess_interval: Duration,
+    /// Whether the gauges of the processes are summed or the highest one is
+    /// served (`METRICS_MULTIPROCESS_GAUGE_MODE`, `sum` or `max`).
+    pub metrics_multiprocess_gauge_mode: GaugeMode,
+    /// Also track request latency as a native histogram in the protobuf
+    /// exposition, mirrored as an exponential histogram over OTLP
+    /// (`METRICS_NATIVE_HISTOGRAMS`, see `native_histogram`).
+    pub metrics_native_histograms: bool,
+    /// Starting resolution of native histograms, from -4 to 8
+    /// (`METRICS_NATIVE_HISTOGRAM_SCHEMA`).
+    pub metrics_native_histogram_schema: i32,
+    /// Buckets a native histogram can have before its resolution is lowered
+    /// (`METRICS_NATIVE_HISTOGRAM_MAX_BUCKETS`).
+    pub metrics_native_histogram_max_buckets: usize,
+    /// PEM certificate chain; together with `tls_key_path` switches the
+    /// server to HTTPS (`TLS_CERT_PATH`, requires the `tls` feature).
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key for `tls_cert_path` (`TLS_KEY_PATH`).
+    pub tls_key_path: Option<PathBuf>,
+    /// Credentials required to scrape `/metrics` (`METRICS_BEARER_TOKEN` or
+    /// `METRICS_BASIC_AUTH` as `user:password`).
+    pub metrics_credentials: Option<ScrapeCredentials>,
+    /// Networks allowed to scrape `/metrics`; empty allows any peer
+    /// (`METRICS_ALLOWED_IPS`, comma-separated CIDRs).
+    pub metrics_allowed_ips: Vec<Cidr>,
+    /// Accept OTLP/HTTP on `/v1/*` and forward it to `otlp_endpoint`
+    /// (`OTLP_RECEIVER`, requires the `gateway` feature).
+    pub otlp_receiver: bool,
+    /// Networks allowed to send to the OTLP receiver; empty allows any peer
+    /// (`OTLP_RECEIVER_ALLOWED_IPS`, comma-separated CIDRs, loopback by default).
+    pub otlp_receiver_allowed_ips: Vec<Cidr>,
+    /// Return a W3C `traceresponse` header next to `X-Trace-Id`
+    /// (`TRACE_RESPONSE_HEADER`).
+    pub trace_response_header: bool,
+    /// W3C baggage keys copied onto span attributes and the labels of
+    /// `http_requests_baggage_total` (`BAGGAGE_KEYS`, comma-separated,
+    /// requires the `traces` feature).
+    pub baggage_keys: Vec<String>,
+    /// Label requests with their client's user agent family, subnet and TLS
+    /// version (`CLIENT_LABELS`), see `client_labels`.
+    pub client_labels: bool,
+    /// Named client networks as `name=cidr`, labeling the requests from
+    /// them (`CLIENT_SUBNETS`, comma-separated).
+    pub client_subnets: Vec<ClientSubnet>,
+    /// Where the tenant of a request comes from, `header:<name>`,
+    /// `claim:<name>` or `path:<index>` (`TENANT_SOURCE`, see `tenant`).
+    pub tenant_source: Option<TenantSource>,
+    /// The tenants labeled as themselves in the tenant metrics, the others
+    /// as `other` (`TENANT_ALLOWLIST`, comma-separated).
+    pub tenant_allowlist: Vec<String>,
+    /// Label tenants with one of this many hash buckets instead
+    /// (`TENANT_HASH_BUCKETS`).
+    pub tenant_hash_buckets: Option<u32>,
+    /// Derive `traces_span_metrics_*` RED metrics from server and client
+    /// spans (`SPAN_METRICS`, requires the `traces` feature).
+    pub span_metrics: bool,
+    /// Export only the traces with an error or a slow span, decided once
+    /// their spans have ended (`TAIL_SAMPLING`, requires the `traces`
+    /// feature, see `tail_sampling`).
+    pub tail_sampling: bool,
+    /// Time the spans of a trace are held before it is decided, from its
+    /// first ended span (`TAIL_SAMPLING_WINDOW_MS`).
+    #[serde(with = "duration")]
+    pub tail_sampling_window: Duration,
+    /// Traces with a span lasting at least this long are exported
+    /// (`TAIL_SAMPLING_LATENCY_THRESHOLD_MS`).
+    #[serde(with = "duration")]
+    pub tail_sampling_latency_threshold: Duration,
+    /// Traces held at once; past it the oldest is decided early
+    /// (`TAIL_SAMPLING_MAX_TRACES`).
+    pub tail_sampling_max_traces: usize,
+    /// Also export the Prometheus registry through the OTLP metric pipeline
+    /// (`METRICS_OTLP_BRIDGE`, requires the `metrics` feature, see
+    /// `otlp_bridge`).
+    pub metrics_otlp_bridge: bool,
+    /// Bearer token for the `/admin` API, which is only mounted when set
+    /// (`ADMIN_TOKEN`).
+    pub admin_token: Option<String>,
+    /// Address of the gRPC health service, disabled when unset
+    /// (`GRPC_HEALTH_ADDR`, requires the `grpc-health` feature).
+    pub grpc_health_addr: Option<String>,
+    /// Local Prometheus endpoints scraped and served on `/metrics` with a
+    /// `source` label (`FEDERATE_TARGETS`, comma-separated `source=url`,
+    /// requires the `federation` feature, see `federation`).
+    pub federate_targets: Vec<FederateTarget>,
+    /// Delay between two scrapes of the federated targets, also their
+    /// timeout (`FEDERATE_INTERVAL_SECS`).
+    #[serde(with = "duration")]
+    pub federate_interval: Duration,
+    /// UDP address accepting StatsD/DogStatsD lines, disabled when unset
+    /// (`STATSD_ADDR`, see `statsd`).
+    pub statsd_addr: Option<String>,
+    /// Stream recently finished spans and log events on the `/debug/tap`
+    /// WebSocket (`DEBUG_TAP`, requires `admin_token`, see `debug_tap`).
+    pub debug_tap: bool,
+    /// Spans and log events kept for new tap clients (`DEBUG_TAP_BUFFER`).
+    pub debug_tap_buffer: usize,
+    /// Share of traces and log events sent to the tap, from 0 to 1
+    /// (`DEBUG_TAP_SAMPLE_RATIO`).
+    pub debug_tap_sample_ratio: f64,
+    /// Base URL of a LaunchDarkly-compatible flag service driving the
+    /// telemetry toggles, disabled when unset (`FLAGS_URL`, requires the
+    /// `flags` feature).
+    pub flags_url: Option<String>,
+    /// SDK key sent to `flags_url` (`FLAGS_SDK_KEY`).
+    pub flags_sdk_key: Option<String>,
+    /// Delay between two polls of `flags_url` (`FLAGS_POLL_INTERVAL_SECS`).
+    #[serde(with = "duration")]
+    pub flags_poll_interval: Duration,
+    /// Jaeger remote sampling endpoint the trace sampling strategy is polled
+    /// from, disabled when unset (`REMOTE_SAMPLING_URL`, requires the
+    /// `remote-sampling` feature, see `remote_sampling`).
+    pub remote_sampling_url: Option<String>,
+    /// Delay between two polls of `remote_sampling_url`, also their timeout
+    /// (`REMOTE_SAMPLING_INTERVAL_SECS`).
+    #[serde(with = "duration")]
+    pub remote_sampling_interval: Duration,
+    /// Share of the traces sampled until a strategy is fetched
+    /// (`REMOTE_SAMPLING_INITIAL_RATIO`).
+    pub remote_sampling_initial_ratio: f64,
+    /// Pyroscope server receiving continuous CPU profiles, disabled when
+    /// unset (`PYROSCOPE_URL`, requires the `pyroscope` feature).
+    pub pyroscope_url: Option<String>,
+    /// Credentials sent to `pyroscope_url` (`PYROSCOPE_AUTH_TOKEN` or
+    /// `PYROSCOPE_BASIC_AUTH` as `user:password`).
+    pub pyroscope_credentials: Option<ScrapeCredentials>,
+    /// Length of each uploaded profile (`PYROSCOPE_UPLOAD_INTERVAL_SECS`).
+    #[serde(with = "duration")]
+    pub pyroscope_upload_interval: Duration,
+    /// Order in which the pipelines are shut down at exit, each with the
+    /// deadline of its final flush (`SHUTDOWN_FLUSH_ORDER`, comma-separated
+    /// `signal[:ms]`, see `shutdown`). Signals left out go last.
+    pub shutdown_flush_order: Vec<FlushStep>,
+    /// Static bearer tokens accepted on the application routes, each naming
+    /// its principal (`APP_AUTH_TOKENS`, comma-separated `principal=token`,
+    /// see `app_auth`).
+    pub app_auth_tokens: Vec<AppToken>,
+    /// JWKS the bearer JWTs of the application routes are verified against,
+    /// disabled when unset (`APP_AUTH_JWKS_URL`, requires the `oidc`
+    /// feature).
+    pub app_auth_jwks_url: Option<String>,
+    /// `iss` the JWTs must carry, any when unset (`APP_AUTH_ISSUER`).
+    pub app_auth_issuer: Option<String>,
+    /// Audience the JWTs must list in `aud`, any when unset
+    /// (`APP_AUTH_AUDIENCE`).
+    pub app_auth_audience: Option<String>,
+    /// Claim of the JWTs naming the principal (`APP_AUTH_PRINCIPAL_CLAIM`).
+    pub app_auth_principal_claim: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            config_file: None,
+            cli_overrides: None,
+            server_addrs: parse_listeners(DEFAULT_SERVER_ADDR).unwrap(),
+            endpoint_prefix: String::new(),
+            server_tuning: ServerTuning::default(),
+            telemetry_disabled: false,
+            otlp_endpoint: DEFAULT_OTLP_ENDPOINT.to_string(),
+            otlp_unix_socket: None,
+            trace_exporter: ExporterSettings::default(),
+            log_exporter: ExporterSettings::default(),
+            metric_exporter: ExporterSettings::default(),
+            shadow_endpoint: None,
+            shadow_exporter: ExporterSettings::default(),
+            service_name: DEFAULT_SERVICE_NAME.to_string(),
+            service_version: env!("CARGO_PKG_VERSION").to_string(),
+            service_instance_id: sysinfo::System::host_name().unwrap_or_else(|| "unknown".to_string()),
+            deployment_environment: None,
+            trace_batch: BatchSettings::default(),
+            log_batch: BatchSettings::default(),
+            metric_export_interval: Duration::from_millis(DEFAULT_METRIC_EXPORT_INTERVAL_MS),
+            metric_export_timeout: Duration::from_millis(DEFAULT_EXPORT_TIMEOUT_MS),
+            export_circuit_breaker_threshold: None,
+            export_circuit_breaker_open: Duration::from_secs(DEFAULT_EXPORT_CIRCUIT_BREAKER_OPEN_SECS),
+            metric_temporality: MetricTemporality::default(),
+            metric_views: Vec::new(),
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            log_format: LogFormat::default(),
+            log_file: None,
+            log_file_max_size_mb: DEFAULT_LOG_FILE_MAX_SIZE_MB,
+            log_file_rotation: Rotation::Daily,
+            log_file_max_files: DEFAULT_LOG_FILE_MAX_FILES,
+            log_stdout: true,
+            log_syslog: None,
+            log_syslog_facility: Facility::default(),
+            log_journald: false,
+            redact_fields: DEFAULT_REDACT_FIELDS.iter().map(|f| f.to_string()).collect(),
+            redact_patterns: Vec::new(),
+            log_metrics: Vec::new(),
+            log_rate_limit: None,
+            log_debug_sample_ratio: 1.0,
+            audit_logs: false,
+            system_metrics_interval: Duration::from_secs(DEFAULT_SYSTEM_METRICS_INTERVAL_SECS),
+            event_loop_probe_interval: Duration::from_millis(DEFAULT_EVENT_LOOP_PROBE_INTERVAL_MS),
+            event_loop_blocked_threshold: Duration::from_millis(DEFAULT_EVENT_LOOP_BLOCKED_THRESHOLD_MS),
+            slow_request_threshold: Duration::ZERO,
+            slow_request_profile: false,
+            memory_pressure_threshold_mb: None,
+            sli_latency_threshold: Duration::from_millis(DEFAULT_SLI_LATENCY_THRESHOLD_MS),
+            sli_bad_statuses: vec![StatusMatcher::Class(5)],
+            apdex_threshold: Duration::from_millis(DEFAULT_APDEX_THRESHOLD_MS),
+            apdex_route_thresholds: Vec::new(),
+            slos: Vec::new(),
+            metrics_excluded_routes: vec!["/metrics".to_string()],
+            telemetry_excluded_routes: DEFAULT_TELEMETRY_EXCLUDED_ROUTES.iter().map(ToString::to_string).collect(),
+            rate_limits: Vec::new(),
+            request_timeouts: Vec::new(),
+            request_size_limits: Vec::new(),
+            response_cache: Vec::new(),
+            response_cache_max_entries: DEFAULT_RESPONSE_CACHE_MAX_ENTRIES,
+            metrics_max_label_sets: DEFAULT_MAX_LABEL_SETS,
+            metrics_resource_labels: DEFAULT_METRICS_RESOURCE_LABELS.iter().map(ToString::to_string).collect(),
+            metrics_catalog_path: None,
+            metrics_cache_ttl: Duration::ZERO,
+            metrics_streaming: false,
+            metrics_naming_strict: false,
+            metrics_multiprocess_dir: None,
+            metrics_multiprocess_interval: Duration::from_millis(DEFAULT_METRICS_MULTIPROCESS_INTERVAL_MS),
+            metrics_multiprocess_gauge_mode: GaugeMode::default(),
+            metrics_native_histograms: false,
+            metrics_native_histogram_schema: DEFAULT_METRICS_NATIVE_HISTOGRAM_SCHEMA,
+            metrics_native_histogram_max_buckets: DEFAULT_METRICS_NATIVE_HISTOGRAM_MAX_BUCKETS,
+            tls_cert_path: None,
+            tls_key_path: None,
+            metrics_credentials: None,
+            metrics_allowed_ips: Vec::new(),
+            otlp_receiver: false,
+            otlp_receiver_allowed_ips: vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+            trace_response_header: false,
+            baggage_keys: Vec::new(),
+            client_labels: false,
+            client_subnets: Vec::new(),
+            tenant_source: None,
+            tenant_allowlist: Vec::new(),
+            tenant_hash_buckets: None,
+            span_metrics: false,
+            tail_sampling: false,
+            tail_sampling_window: Duration::from_millis(DEFAULT_TAIL_SAMPLING_WINDOW_MS),
+            tail_sampling_latency_threshold: Duration::from_millis(DEFAULT_TAIL_SAMPLING_LATENCY_THRESHOLD_MS),
+            tail_sampling_max_traces: DEFAULT_TAIL_SAMPLING_MAX_TRACES,
+            metrics_otlp_bridge: false,
+            admin_token: None,
+            grpc_health_addr: None,
+            federate_targets: Vec::new(),
+            federate_interval: Duration::from_secs(DEFAULT_FEDERATE_INTERVAL_SECS),
+            statsd_addr: None,
+            debug_tap: false,
+            debug_tap_buffer: DEFAULT_DEBUG_TAP_BUFFER,
+            debug_tap_sample_ratio: 1.0,
+            flags_url: None,
+            flags_sdk_key: None,
+            flags_poll_interval: Duration::from_secs(DEFAULT_FLAGS_POLL_INTERVAL_SECS),
+            remote_sampling_url: None,
+            remote_sampling_interval: Duration::from_secs(DEFAULT_REMOTE_SAMPLING_INTERVAL_SECS),
+            remote_sampling_initial_ratio: DEFAULT_REMOTE_SAMPLING_INITIAL_RATIO,
+            pyroscope_url: None,
+            pyroscope_credentials: None,
+            pyroscope_upload_interval: Duration::from_secs(DEFAULT_PYROSCOPE_UPLOAD_INTERVAL_SECS),
+            shutdown_flush_order: [Signal::Logs, Signal::Traces, Signal::Metrics]
+                .map(|signal| FlushStep { signal, timeout: DEFAULT_FLUSH_TIMEOUT })
+                .to_vec(),
+            app_auth_tokens: Vec::new(),
+            app_auth_jwks_url: None,
+            app_auth_issuer: None,
+            app_auth_audience: None,
+            app_auth_principal_claim: DEFAULT_PRINCIPAL_CLAIM.to_string(),
+        }
+    }
+}
+
+/// Reads an integer variable; `None` when it is unset.
+fn env_int<T: std::str::FromStr>(name: &str) -> Result<Option<T>, ConfigError> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError(format!("{name} must be an integer, got {value:?}"))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a decimal variable; `None` when it is unset.
+fn env_f64(name: &str) -> Result<Option<f64>, ConfigError> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError(format!("{name} must be a number, got {value:?}"))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses `true`/`false` (also `1`/`0`) as used by boolean variables.
+fn parse_bool(name: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(ConfigError(format!("{name} must be true or false, got {value:?}"))),
+    }
+}
+
+/// Splits a comma-separated list, dropping empty entries.
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Overrides from `OTEL_EXPORTER_OTLP_<SIGNAL>_{ENDPOINT,PROTOCOL,COMPRESSION,HEADERS}`.
+fn apply_exporter_env(exporter: &mut ExporterSettings, signal: Signal) -> Result<(), ConfigError> {
+    let prefix = format!("OTEL_EXPORTER_OTLP_{}", signal.as_str().to_ascii_uppercase());
+    if let Ok(endpoint) = env::var(format!("{prefix}_ENDPOINT")) {
+        exporter.endpoint = Some(endpoint).filter(|e| !e.is_empty());
+    }
+    apply_transport_env(exporter, &prefix)
+}
+
+/// Overrides from `<prefix>_{PROTOCOL,COMPRESSION,HEADERS}`.
+fn apply_transport_env(exporter: &mut ExporterSettings, prefix: &str) -> Result<(), ConfigError> {
+    if let Ok(protocol) = env::var(format!("{prefix}_PROTOCOL")) {
+        exporter.protocol = protocol.parse().map_err(|e| ConfigError(format!("{prefix}_PROTOCOL: {e}")))?;
+    }
+    if let Ok(compression) = env::var(format!("{prefix}_COMPRESSION")) {
+        exporter.compression =
+            compression.parse().map_err(|e| ConfigError(format!("{prefix}_COMPRESSION: {e}")))?;
+    }
+    if let Ok(headers) = env::var(format!("{prefix}_HEADERS")) {
+        exporter.headers = parse_headers(&headers).map_err(|e| ConfigError(format!("{prefix}_HEADERS: {e}")))?;
+    }
+    Ok(())
+}
+
+/// Fails unless the directory `path` would be created in exists.
+fn check_parent_dir(setting: &str, path: &Path) -> Result<(), ConfigError> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if !dir.is_dir() {
+        return Err(ConfigError(format!(
+            "{setting} {}: directory {} does not exist",
+            path.display(),
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// The name embedding applications know `Config` by.
+pub type TelemetryConfig = Config;
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Defaults overridden by any of the supported environment variables.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_env_over(Self::default())
+    }
+
+    /// The JSON document at `path`, as deserialized (see the module docs).
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError(format!("CONFIG_FILE {}: {e}", path.display())))?;
+        serde_json::from_str(&text).map_err(|e| ConfigError(format!("CONFIG_FILE {}: {e}", path.display())))
+    }
+
+    /// The configuration of `main`: `config_file` if set, the environment
+    /// and then `overrides`, validated.
+    pub fn load(overrides: Overrides) -> Result<Self, ConfigError> {
+        let mut config = Self::from_env()?;
+        overrides.clone().apply(&mut config);
+        config.cli_overrides = Some(Arc::new(overrides));
+        if config.config_file.is_some() {
+            return config.reload();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads `config_file` again, under the current environment variables
+    /// and `cli_overrides`, and validates the result.
+    pub fn reload(&self) -> Result<Self, ConfigError> {
+        let Some(path) = &self.config_file else {
+            return Err(ConfigError("no CONFIG_FILE to reload".to_string()));
+        };
+        let mut config = Self::from_env_over(Self::from_file(path)?)?;
+        config.config_file = Some(path.clone());
+        config.cli_overrides = self.cli_overrides.clone();
+        if let Some(overrides) = &config.cli_overrides {
+            Overrides::clone(overrides).apply(&mut config);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// `config` overridden by any of the supported environment variables.
+    fn from_env_over(mut config: Self) -> Result<Self, ConfigError> {
+        if let Ok(path) = env::var("CONFIG_FILE") {
+            config.config_file = Some(path.into());
+        }
+        if let Ok(fields) = env::var("REDACT_FIELDS") {
+            config.redact_fields = split_list(&fields);
+        }
+        if let Ok(patterns) = env::var("REDACT_PATTERNS") {
+            // Whitespace-separated, since regexes commonly contain commas.
+            config.redact_patterns = patterns.split_whitespace().map(str::to_string).collect();
+        }
+        if let Ok(rules) = env::var("LOG_METRICS") {
+            config.log_metrics = parse_rules(&rules).map_err(|e| ConfigError(format!("LOG_METRICS: {e}")))?;
+        }
+        if let Some(limit) = env_int("LOG_RATE_LIMIT_PER_SEC")? {
+            config.log_rate_limit = Some(limit);
+        }
+        if let Some(ratio) = env_f64("LOG_DEBUG_SAMPLE_RATIO")? {
+            config.log_debug_sample_ratio = ratio;
+        }
+        if let Ok(enabled) = env::var("AUDIT_LOGS") {
+            config.audit_logs = parse_bool("AUDIT_LOGS", &enabled)?;
+        }
+        if let Ok(addrs) = env::var("SERVER_ADDR") {
+            config.server_addrs = parse_listeners(&addrs).map_err(|e| ConfigError(format!("SERVER_ADDR: {e}")))?;
+        }
+        if let Ok(prefix) = env::var("ENDPOINT_PREFIX") {
+            config.endpoint_prefix = prefix;
+        }
+        config.server_tuning.apply_env()?;
+        if let Ok(disabled) = env::var("OTEL_SDK_DISABLED") {
+            config.telemetry_disabled = parse_bool("OTEL_SDK_DISABLED", &disabled)?;
+        }
+        if let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            config.otlp_endpoint = endpoint;
+        }
+        if let Ok(path) = env::var("OTLP_UNIX_SOCKET") {
+            config.otlp_unix_socket = Some(PathBuf::from(path));
+        }
+        if let Ok(protocol) = env::var("OTEL_EXPORTER_OTLP_PROTOCOL") {
+            let protocol: OtlpProtocol =
+                protocol.parse().map_err(|e| ConfigError(format!("OTEL_EXPORTER_OTLP_PROTOCOL: {e}")))?;
+            for signal in Signal::ALL {
+                config.exporter_mut(signal).protocol = protocol;
+            }
+        }
+        if let Ok(compression) = env::var("OTEL_EXPORTER_OTLP_COMPRESSION") {
+            let compression: OtlpCompression =
+                compression.parse().map_err(|e| ConfigError(format!("OTEL_EXPORTER_OTLP_COMPRESSION: {e}")))?;
+            for signal in Signal::ALL {
+                config.exporter_mut(signal).compression = compression;
+            }
+        }
+        if let Ok(headers) = env::var("OTEL_EXPORTER_OTLP_HEADERS") {
+            let headers = parse_headers(&headers).map_err(|e| ConfigError(format!("OTEL_EXPORTER_OTLP_HEADERS: {e}")))?;
+            for signal in Signal::ALL {
+                config.exporter_mut(signal).headers = headers.clone();
+            }
+        }
+        for signal in Signal::ALL {
+            apply_exporter_env(config.exporter_mut(signal), signal)?;
+        }
+        if let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_SHADOW_ENDPOINT") {
+            config.shadow_endpoint = Some(endpoint).filter(|e| !e.is_empty());
+        }
+        apply_transport_env(&mut config.shadow_exporter, "OTEL_EXPORTER_OTLP_SHADOW")?;
+        if let Ok(name) = env::var("OTEL_SERVICE_NAME") {
+            config.service_name = name;
+        }
+        if let Ok(version) = env::var("SERVICE_VERSION") {
+            config.service_version = version;
+        }
+        if let Ok(id) = env::var("SERVICE_INSTANCE_ID") {
+            config.service_instance_id = id;
+        }
+        if let Ok(environment) = env::var("DEPLOYMENT_ENVIRONMENT") {
+            config.deployment_environment = Some(environment).filter(|e| !e.is_empty());
+        }
+        config.trace_batch.apply_env("OTEL_BSP")?;
+        config.log_batch.apply_env("OTEL_BLRP")?;
+        if let Some(ms) = env_int("OTEL_METRIC_EXPORT_INTERVAL")? {
+            config.metric_export_interval = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_int("OTEL_METRIC_EXPORT_TIMEOUT")? {
+            config.metric_export_timeout = Duration::from_millis(ms);
+        }
+        if let Some(threshold) = env_int("EXPORT_CIRCUIT_BREAKER_THRESHOLD")? {
+            config.export_circuit_breaker_threshold = Some(threshold);
+        }
+        if let Some(secs) = env_int("EXPORT_CIRCUIT_BREAKER_OPEN_SECS")? {
+            config.export_circuit_breaker_open = Duration::from_secs(secs);
+        }
+        if let Ok(temporality) = env::var("OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE") {
+            config.metric_temporality = temporality
+                .parse()
+                .map_err(|e| ConfigError(format!("OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE: {e}")))?;
+        }
+        if let Ok(views) = env::var("METRIC_VIEWS") {
+            config.metric_views = parse_views(&views).map_err(|e| ConfigError(format!("METRIC_VIEWS: {e}")))?;
+        }
+        if let Ok(level) = env::var("RUST_LOG") {
+            config.log_level = level;
+        }
+        if let Ok(format) = env::var("LOG_FORMAT") {
+            config.log_format = format.parse().map_err(|e| ConfigError(format!("LOG_FORMAT: {e}")))?;
+        }
+        if let Ok(path) = env::var("LOG_FILE") {
+            config.log_file = Some(path.into());
+        }
+        if let Ok(mb) = env::var("LOG_FILE_MAX_SIZE_MB") {
+            config.log_file_max_size_mb = mb
+                .parse()
+                .map_err(|_| ConfigError(format!("LOG_FILE_MAX_SIZE_MB must be an integer, got {mb:?}")))?;
+        }
+        if let Ok(rotation) = env::var("LOG_FILE_ROTATION") {
+            config.log_file_rotation =
+                rotation.parse().map_err(|e| ConfigError(format!("LOG_FILE_ROTATION: {e}")))?;
+        }
+        if let Ok(max) = env::var("LOG_FILE_MAX_FILES") {
+            config.log_file_max_files = max
+                .parse()
+                .map_err(|_| ConfigError(format!("LOG_FILE_MAX_FILES must be an integer, got {max:?}")))?;
+        }
+        if let Ok(enabled) = env::var("LOG_STDOUT") {
+            config.log_stdout = parse_bool("LOG_STDOUT", &enabled)?;
+        }
+        if let Ok(target) = env::var("LOG_SYSLOG") {
+            config.log_syslog = Some(target.parse().map_err(|e| ConfigError(format!("LOG_SYSLOG: {e}")))?);
+        }
+        if let Ok(facility) = env::var("LOG_SYSLOG_FACILITY") {
+            config.log_syslog_facility =
+                facility.parse().map_err(|e| ConfigError(format!("LOG_SYSLOG_FACILITY: {e}")))?;
+        }
+        if let Ok(enabled) = env::var("LOG_JOURNALD") {
+            config.log_journald = parse_bool("LOG_JOURNALD", &enabled)?;
+        }
+        if let Ok(secs) = env::var("SYSTEM_METRICS_INTERVAL_SECS") {
+            let secs = secs
+                .parse()
+                .map_err(|_| ConfigError(format!("SYSTEM_METRICS_INTERVAL_SECS must be an integer, got {secs:?}")))?;
+            config.system_metrics_interval = Duration::from_secs(secs);
+        }
+        if let Some(ms) = env_int("EVENT_LOOP_PROBE_INTERVAL_MS")? {
+            config.event_loop_probe_interval = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_int("EVENT_LOOP_BLOCKED_THRESHOLD_MS")? {
+            config.event_loop_blocked_threshold = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_int("SLOW_REQUEST_THRESHOLD_MS")? {
+            config.slow_request_threshold = Duration::from_millis(ms);
+        }
+        if let Ok(enabled) = env::var("SLOW_REQUEST_PROFILE") {
+            config.slow_request_profile = parse_bool("SLOW_REQUEST_PROFILE", &enabled)?;
+        }
+        if let Some(mb) = env_int("MEMORY_PRESSURE_THRESHOLD_MB")? {
+            config.memory_pressure_threshold_mb = Some(mb);
+        }
+        if let Ok(ms) = env::var("SLI_LATENCY_THRESHOLD_MS") {
+            let ms = ms
+                .parse()
+                .map_err(|_| ConfigError(format!("SLI_LATENCY_THRESHOLD_MS must be an integer, got {ms:?}")))?;
+            config.sli_latency_threshold = Duration::from_millis(ms);
+        }
+        if let Ok(statuses) = env::var("SLI_BAD_STATUSES") {
+            config.sli_bad_statuses =
+                parse_status_list(&statuses).map_err(|e| ConfigError(format!("SLI_BAD_STATUSES: {e}")))?;
+        }
+        if let Some(ms) = env_int("APDEX_THRESHOLD_MS")? {
+            config.apdex_threshold = Duration::from_millis(ms);
+        }
+        if let Ok(routes) = env::var("APDEX_ROUTE_THRESHOLDS") {
+            config.apdex_route_thresholds = split_list(&routes)
+                .iter()
+                .map(|route| route.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("APDEX_ROUTE_THRESHOLDS: {e}")))?;
+        }
+        if let Ok(slos) = env::var("SLOS") {
+            config.slos = split_list(&slos)
+                .iter()
+                .map(|slo| slo.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("SLOS: {e}")))?;
+        }
+        if let Ok(routes) = env::var("METRICS_EXCLUDED_ROUTES") {
+            config.metrics_excluded_routes = split_list(&routes);
+        }
+        if let Ok(routes) = env::var("TELEMETRY_EXCLUDED_ROUTES") {
+            config.telemetry_excluded_routes = split_list(&routes);
+        }
+        if let Ok(limits) = env::var("RATE_LIMITS") {
+            config.rate_limits = split_list(&limits)
+                .iter()
+                .map(|limit| limit.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("RATE_LIMITS: {e}")))?;
+        }
+        if let Ok(timeouts) = env::var("REQUEST_TIMEOUTS") {
+            config.request_timeouts = split_list(&timeouts)
+                .iter()
+                .map(|timeout| timeout.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("REQUEST_TIMEOUTS: {e}")))?;
+        }
+        if let Ok(limits) = env::var("REQUEST_SIZE_LIMITS") {
+            config.request_size_limits = split_list(&limits)
+                .iter()
+                .map(|limit| limit.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("REQUEST_SIZE_LIMITS: {e}")))?;
+        }
+        if let Ok(rules) = env::var("RESPONSE_CACHE") {
+            config.response_cache = split_list(&rules)
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("RESPONSE_CACHE: {e}")))?;
+        }
+        if let Some(entries) = env_int("RESPONSE_CACHE_MAX_ENTRIES")? {
+            config.response_cache_max_entries = entries;
+        }
+        if let Ok(max) = env::var("METRICS_MAX_LABEL_SETS") {
+            config.metrics_max_label_sets = max
+                .parse()
+                .map_err(|_| ConfigError(format!("METRICS_MAX_LABEL_SETS must be an integer, got {max:?}")))?;
+        }
+        if let Ok(labels) = env::var("METRICS_RESOURCE_LABELS") {
+            config.metrics_resource_labels = split_list(&labels);
+        }
+        if let Ok(path) = env::var("METRICS_CATALOG_PATH") {
+            config.metrics_catalog_path = Some(path.into());
+        }
+        if let Some(ms) = env_int("METRICS_CACHE_TTL_MS")? {
+            config.metrics_cache_ttl = Duration::from_millis(ms);
+        }
+        if let Ok(enabled) = env::var("METRICS_STREAMING") {
+            config.metrics_streaming = parse_bool("METRICS_STREAMING", &enabled)?;
+        }
+        if let Ok(strict) = env::var("METRICS_NAMING_STRICT") {
+            config.metrics_naming_strict = parse_bool("METRICS_NAMING_STRICT", &strict)?;
+        }
+        if let Ok(dir) = env::var("METRICS_MULTIPROCESS_DIR") {
+            config.metrics_multiprocess_dir = Some(dir.into());
+        }
+        if let Some(ms) = env_int("METRICS_MULTIPROCESS_INTERVAL_MS")? {
+            config.metrics_multiprocess_interval = Duration::from_millis(ms);
+        }
+        if let Ok(mode) = env::var("METRICS_MULTIPROCESS_GAUGE_MODE") {
+            config.metrics_multiprocess_gauge_mode =
+                mode.parse().map_err(|e| ConfigError(format!("METRICS_MULTIPROCESS_GAUGE_MODE: {e}")))?;
+        }
+        if let Ok(enabled) = env::var("METRICS_NATIVE_HISTOGRAMS") {
+            config.metrics_native_histograms = parse_bool("METRICS_NATIVE_HISTOGRAMS", &enabled)?;
+        }
+        if let Some(schema) = env_int("METRICS_NATIVE_HISTOGRAM_SCHEMA")? {
+            config.metrics_native_histogram_schema = schema;
+        }
+        if let Some(max) = env_int("METRICS_NATIVE_HISTOGRAM_MAX_BUCKETS")? {
+            config.metrics_native_histogram_max_buckets = max;
+        }
+        if let Ok(path) = env::var("TLS_CERT_PATH") {
+            config.tls_cert_path = Some(path.into());
+        }
+        if let Ok(path) = env::var("TLS_KEY_PATH") {
+            config.tls_key_path = Some(path.into());
+        }
+        match (env::var("METRICS_BEARER_TOKEN"), env::var("METRICS_BASIC_AUTH")) {
+            (Ok(_), Ok(_)) => {
+                return Err(ConfigError(
+                    "METRICS_BEARER_TOKEN and METRICS_BASIC_AUTH are mutually exclusive".to_string(),
+                ));
+            }
+            (Ok(token), Err(_)) => config.metrics_credentials = Some(ScrapeCredentials::Bearer(token)),
+            (Err(_), Ok(basic)) => {
+                config.metrics_credentials = Some(
+                    ScrapeCredentials::basic(&basic).map_err(|e| ConfigError(format!("METRICS_BASIC_AUTH: {e}")))?,
+                );
+            }
+            (Err(_), Err(_)) => {}
+        }
+        if let Ok(ips) = env::var("METRICS_ALLOWED_IPS") {
+            config.metrics_allowed_ips = split_list(&ips)
+                .iter()
+                .map(|cidr| cidr.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("METRICS_ALLOWED_IPS: {e}")))?;
+        }
+        if let Ok(enabled) = env::var("OTLP_RECEIVER") {
+            config.otlp_receiver = parse_bool("OTLP_RECEIVER", &enabled)?;
+        }
+        if let Ok(ips) = env::var("OTLP_RECEIVER_ALLOWED_IPS") {
+            config.otlp_receiver_allowed_ips = split_list(&ips)
+                .iter()
+                .map(|cidr| cidr.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("OTLP_RECEIVER_ALLOWED_IPS: {e}")))?;
+        }
+        if let Ok(enabled) = env::var("TRACE_RESPONSE_HEADER") {
+            config.trace_response_header = parse_bool("TRACE_RESPONSE_HEADER", &enabled)?;
+        }
+        if let Ok(keys) = env::var("BAGGAGE_KEYS") {
+            config.baggage_keys = split_list(&keys);
+        }
+        if let Ok(enabled) = env::var("CLIENT_LABELS") {
+            config.client_labels = parse_bool("CLIENT_LABELS", &enabled)?;
+        }
+        if let Ok(subnets) = env::var("CLIENT_SUBNETS") {
+            config.client_subnets = split_list(&subnets)
+                .iter()
+                .map(|subnet| subnet.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("CLIENT_SUBNETS: {e}")))?;
+        }
+        if let Ok(source) = env::var("TENANT_SOURCE") {
+            config.tenant_source = Some(source.parse().map_err(|e| ConfigError(format!("TENANT_SOURCE: {e}")))?);
+        }
+        if let Ok(tenants) = env::var("TENANT_ALLOWLIST") {
+            config.tenant_allowlist = split_list(&tenants);
+        }
+        if let Some(buckets) = env_int("TENANT_HASH_BUCKETS")? {
+            config.tenant_hash_buckets = Some(buckets);
+        }
+        if let Ok(enabled) = env::var("SPAN_METRICS") {
+            config.span_metrics = parse_bool("SPAN_METRICS", &enabled)?;
+        }
+        if let Ok(enabled) = env::var("TAIL_SAMPLING") {
+            config.tail_sampling = parse_bool("TAIL_SAMPLING", &enabled)?;
+        }
+        if let Some(ms) = env_int("TAIL_SAMPLING_WINDOW_MS")? {
+            config.tail_sampling_window = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_int("TAIL_SAMPLING_LATENCY_THRESHOLD_MS")? {
+            config.tail_sampling_latency_threshold = Duration::from_millis(ms);
+        }
+        if let Some(traces) = env_int("TAIL_SAMPLING_MAX_TRACES")? {
+            config.tail_sampling_max_traces = traces;
+        }
+        if let Ok(enabled) = env::var("METRICS_OTLP_BRIDGE") {
+            config.metrics_otlp_bridge = parse_bool("METRICS_OTLP_BRIDGE", &enabled)?;
+        }
+        if let Ok(token) = env::var("ADMIN_TOKEN") {
+            config.admin_token = Some(token);
+        }
+        if let Ok(addr) = env::var("GRPC_HEALTH_ADDR") {
+            config.grpc_health_addr = Some(addr);
+        }
+        if let Ok(targets) = env::var("FEDERATE_TARGETS") {
+            config.federate_targets = split_list(&targets)
+                .iter()
+                .map(|target| target.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("FEDERATE_TARGETS: {e}")))?;
+        }
+        if let Some(secs) = env_int("FEDERATE_INTERVAL_SECS")? {
+            config.federate_interval = Duration::from_secs(secs);
+        }
+        if let Ok(addr) = env::var("STATSD_ADDR") {
+            config.statsd_addr = Some(addr);
+        }
+        if let Ok(enabled) = env::var("DEBUG_TAP") {
+            config.debug_tap = parse_bool("DEBUG_TAP", &enabled)?;
+        }
+        if let Some(buffer) = env_int("DEBUG_TAP_BUFFER")? {
+            config.debug_tap_buffer = buffer;
+        }
+        if let Some(ratio) = env_f64("DEBUG_TAP_SAMPLE_RATIO")? {
+            config.debug_tap_sample_ratio = ratio;
+        }
+        if let Ok(url) = env::var("FLAGS_URL") {
+            config.flags_url = Some(url);
+        }
+        if let Ok(key) = env::var("FLAGS_SDK_KEY") {
+            config.flags_sdk_key = Some(key);
+        }
+        if let Some(secs) = env_int("FLAGS_POLL_INTERVAL_SECS")? {
+            config.flags_poll_interval = Duration::from_secs(secs);
+        }
+        // The OTel specification's way; the variables below take precedence.
+        if env::var("OTEL_TRACES_SAMPLER").is_ok_and(|sampler| sampler.ends_with("jaeger_remote")) {
+            let arg = env::var("OTEL_TRACES_SAMPLER_ARG").unwrap_or_default();
+            for (key, value) in arg.split(',').filter_map(|pair| pair.split_once('=')) {
+                let value = value.trim();
+                let invalid = || ConfigError(format!("OTEL_TRACES_SAMPLER_ARG: invalid {} {value:?}", key.trim()));
+                match key.trim() {
+                    "endpoint" => config.remote_sampling_url = Some(value.to_string()),
+                    "pollingIntervalMs" => {
+                        config.remote_sampling_interval = Duration::from_millis(value.parse().map_err(|_| invalid())?);
+                    }
+                    "initialSamplingRate" => {
+                        config.remote_sampling_initial_ratio = value.parse().map_err(|_| invalid())?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Ok(url) = env::var("REMOTE_SAMPLING_URL") {
+            config.remote_sampling_url = Some(url);
+        }
+        if let Some(secs) = env_int("REMOTE_SAMPLING_INTERVAL_SECS")? {
+            config.remote_sampling_interval = Duration::from_secs(secs);
+        }
+        if let Some(ratio) = env_f64("REMOTE_SAMPLING_INITIAL_RATIO")? {
+            config.remote_sampling_initial_ratio = ratio;
+        }
+        if let Ok(url) = env::var("PYROSCOPE_URL") {
+            config.pyroscope_url = Some(url);
+        }
+        match (env::var("PYROSCOPE_AUTH_TOKEN"), env::var("PYROSCOPE_BASIC_AUTH")) {
+            (Ok(_), Ok(_)) => {
+                return Err(ConfigError(
+                    "PYROSCOPE_AUTH_TOKEN and PYROSCOPE_BASIC_AUTH are mutually exclusive".to_string(),
+                ));
+            }
+            (Ok(token), Err(_)) => config.pyroscope_credentials = Some(ScrapeCredentials::Bearer(token)),
+            (Err(_), Ok(basic)) => {
+                config.pyroscope_credentials = Some(
+                    ScrapeCredentials::basic(&basic).map_err(|e| ConfigError(format!("PYROSCOPE_BASIC_AUTH: {e}")))?,
+                );
+            }
+            (Err(_), Err(_)) => {}
+        }
+        if let Some(secs) = env_int("PYROSCOPE_UPLOAD_INTERVAL_SECS")? {
+            config.pyroscope_upload_interval = Duration::from_secs(secs);
+        }
+        if let Ok(order) = env::var("SHUTDOWN_FLUSH_ORDER") {
+            config.shutdown_flush_order =
+                parse_flush_order(&order).map_err(|e| ConfigError(format!("SHUTDOWN_FLUSH_ORDER: {e}")))?;
+        }
+        if let Ok(tokens) = env::var("APP_AUTH_TOKENS") {
+            config.app_auth_tokens = split_list(&tokens)
+                .iter()
+                .map(|token| token.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError(format!("APP_AUTH_TOKENS: {e}")))?;
+        }
+        if let Ok(url) = env::var("APP_AUTH_JWKS_URL") {
+            config.app_auth_jwks_url = Some(url);
+        }
+        if let Ok(issuer) = env::var("APP_AUTH_ISSUER") {
+            config.app_auth_issuer = Some(issuer);
+        }
+        if let Ok(audience) = env::var("APP_AUTH_AUDIENCE") {
+            config.app_auth_audience = Some(audience);
+        }
+        if let Ok(claim) = env::var("APP_AUTH_PRINCIPAL_CLAIM") {
+            config.app_auth_principal_claim = claim;
+        }
+        Ok(config)
+    }
+
+    /// Endpoint, protocol and headers of `signal`'s exporter.
+    pub fn exporter(&self, signal: Signal) -> &ExporterSettings {
+        match signal {
+            Signal::Traces => &self.trace_exporter,
+            Signal::Logs => &self.log_exporter,
+            Signal::Metrics => &self.metric_exporter,
+        }
+    }
+
+    pub fn exporter_mut(&mut self, signal: Signal) -> &mut ExporterSettings {
+        match signal {
+            Signal::Traces => &mut self.trace_exporter,
+            Signal::Logs => &mut self.log_exporter,
+            Signal::Metrics => &mut self.metric_exporter,
+        }
+    }
+
+    /// URL `signal` is exported to.
+    pub fn exporter_endpoint(&self, signal: Signal) -> String {
+        self.exporter(signal).endpoint(&self.otlp_endpoint, signal)
+    }
+
+    /// URL `signal` is also exported to, when shadow export is on.
+    pub fn shadow_exporter_endpoint(&self, signal: Signal) -> Option<String> {
+        self.shadow_endpoint.as_ref().map(|base| self.shadow_exporter.endpoint(base, signal))
+    }
+
+    /// `shutdown_flush_order` followed by the signals it leaves out, with the
+    /// default deadline.
+    pub fn flush_steps(&self) -> Vec<FlushStep> {
+        let mut steps = self.shutdown_flush_order.clone();
+        for signal in Signal::ALL {
+            if !steps.iter().any(|step| step.signal == signal) {
+                steps.push(FlushStep { signal, timeout: DEFAULT_FLUSH_TIMEOUT });
+            }
+        }
+        steps
+    }
+
+    /// Whether the server should terminate TLS itself.
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert_path.is_some()
+    }
+
+    /// The `CORRELATION_ATTRIBUTES` that have a value, as put on the OTel
+    /// resource.
+    pub fn resource_attributes(&self) -> Vec<(&'static str, String)> {
+        let mut attributes = vec![
+            ("service.name", self.service_name.clone()),
+            ("service.version", self.service_version.clone()),
+            ("service.instance.id", self.service_instance_id.clone()),
+        ];
+        if let Some(environment) = &self.deployment_environment {
+            attributes.push(("deployment.environment.name", environment.clone()));
+        }
+        attributes
+    }
+
+    /// Whether the application routes require a bearer token.
+    pub fn app_auth_enabled(&self) -> bool {
+        !self.app_auth_tokens.is_empty() || self.app_auth_jwks_url.is_some()
+    }
+
+    /// Whether log events are rate-limited or sampled before OTLP export.
+    pub fn log_sampling(&self) -> bool {
+        self.log_rate_limit.is_some() || self.log_debug_sample_ratio < 1.0
+    }
+
+    /// Settings of the native request latency histogram, `None` unless
+    /// `metrics_native_histograms` is set.
+    pub fn native_histograms(&self) -> Option<NativeHistogramOpts> {
+        self.metrics_native_histograms.then_some(NativeHistogramOpts {
+            schema: self.metrics_native_histogram_schema,
+            max_buckets: self.metrics_native_histogram_max_buckets,
+        })
+    }
+
+    /// Constant labels for the Prometheus registry: the attributes named in
+    /// `metrics_resource_labels` that have a value.
+    pub fn metric_const_labels(&self) -> HashMap<String, String> {
+        self.resource_attributes()
+            .into_iter()
+            .filter(|(key, _)| self.metrics_resource_labels.iter().any(|label| label == key))
+            .map(|(key, value)| (key.replace('.', "_"), value))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server_addrs.is_empty() {
+            return Err(ConfigError("server_addrs must not be empty".to_string()));
+        }
+        if !self.endpoint_prefix.is_empty()
+            && (!self.endpoint_prefix.starts_with('/') || self.endpoint_prefix.ends_with('/'))
+        {
+            return Err(ConfigError(format!(
+                "endpoint_prefix {:?} must start with `/` and not end with one",
+                self.endpoint_prefix
+            )));
+        }
+        self.server_tuning.validate()?;
+        if !self.otlp_endpoint.starts_with("http://") && !self.otlp_endpoint.starts_with("https://") {
+            return Err(ConfigError(format!(
+                "otlp_endpoint {:?} must be an http(s) URL",
+                self.otlp_endpoint
+            )));
+        }
+        for (name, signal) in EXPORTERS {
+            let exporter = self.exporter(signal);
+            if let Some(endpoint) = &exporter.endpoint
+                && !endpoint.starts_with("http://")
+                && !endpoint.starts_with("https://")
+            {
+                return Err(ConfigError(format!("{name} endpoint {endpoint:?} must be an http(s) URL")));
+            }
+            if exporter.protocol == OtlpProtocol::Grpc && !cfg!(feature = "otlp-grpc") {
+                return Err(ConfigError(format!("{name} uses gRPC but this build lacks the `otlp-grpc` feature")));
+            }
+            if exporter.compression == OtlpCompression::Zstd && !cfg!(feature = "zstd") {
+                return Err(ConfigError(format!("{name} uses zstd but this build lacks the `zstd` feature")));
+            }
+            if self.otlp_unix_socket.is_some() && exporter.protocol == OtlpProtocol::Grpc {
+                return Err(ConfigError(format!("{name} uses gRPC, which otlp_unix_socket doesn't carry")));
+            }
+            if self.otlp_receiver && exporter.protocol == OtlpProtocol::Grpc {
+                return Err(ConfigError(format!("{name} uses gRPC, which the OTLP receiver can't forward over")));
+            }
+        }
+        if let Some(endpoint) = &self.shadow_endpoint
+            && !endpoint.starts_with("http://")
+            && !endpoint.starts_with("https://")
+        {
+            return Err(ConfigError(format!("shadow_endpoint {endpoint:?} must be an http(s) URL")));
+        }
+        if self.shadow_exporter.endpoint.is_some() {
+            return Err(ConfigError("shadow_exporter takes its endpoint from shadow_endpoint".to_string()));
+        }
+        if self.shadow_exporter.protocol == OtlpProtocol::Grpc && !cfg!(feature = "otlp-grpc") {
+            return Err(ConfigError("shadow_exporter uses gRPC but this build lacks the `otlp-grpc` feature".to_string()));
+        }
+        if self.shadow_exporter.compression == OtlpCompression::Zstd && !cfg!(feature = "zstd") {
+            return Err(ConfigError("shadow_exporter uses zstd but this build lacks the `zstd` feature".to_string()));
+        }
+        if self.otlp_unix_socket.is_some() && !cfg!(unix) {
+            return Err(ConfigError("otlp_unix_socket is only supported on Unix".to_string()));
+        }
+        if self.service_name.is_empty() {
+            return Err(ConfigError("service_name must not be empty".to_string()));
+        }
+        if self.service_instance_id.is_empty() {
+            return Err(ConfigError("service_instance_id must not be empty".to_string()));
+        }
+        self.trace_batch.validate("trace_batch")?;
+        self.log_batch.validate("log_batch")?;
+        if self.metric_export_interval.is_zero() || self.metric_export_timeout.is_zero() {
+            return Err(ConfigError(
+                "metric_export_interval and metric_export_timeout must be greater than zero".to_string(),
+            ));
+        }
+        if self.export_circuit_breaker_threshold == Some(0) {
+            return Err(ConfigError("export_circuit_breaker_threshold must be greater than zero".to_string()));
+        }
+        if self.export_circuit_breaker_threshold.is_some() && self.export_circuit_breaker_open.is_zero() {
+            return Err(ConfigError("export_circuit_breaker_open must be greater than zero".to_string()));
+        }
+        tracing_subscriber::EnvFilter::builder()
+            .parse(&self.log_level)
+            .map_err(|e| ConfigError(format!("log_level {:?}: {e}", self.log_level)))?;
+        if let Some(path) = &self.log_file {
+            check_parent_dir("log_file", path)?;
+        }
+        if self.log_journald && !cfg!(unix) {
+            return Err(ConfigError("log_journald is not supported on this platform".to_string()));
+        }
+        Redactor::from_config(self).map_err(|e| ConfigError(format!("redact_patterns: {e}")))?;
+        for (i, rule) in self.log_metrics.iter().enumerate() {
+            if self.log_metrics[..i].iter().any(|other| other.name == rule.name) {
+                return Err(ConfigError(format!("log_metrics: rule {:?} is defined twice", rule.name)));
+            }
+        }
+        if self.log_rate_limit == Some(0) {
+            return Err(ConfigError("log_rate_limit must be at least 1".to_string()));
+        }
+        if !(0.0..=1.0).contains(&self.log_debug_sample_ratio) {
+            return Err(ConfigError(format!(
+                "log_debug_sample_ratio must be between 0 and 1, got {}",
+                self.log_debug_sample_ratio
+            )));
+        }
+        if self.log_sampling() && !cfg!(feature = "logs") {
+            return Err(ConfigError(
+                "log_rate_limit or log_debug_sample_ratio is set but this build lacks the `logs` feature".to_string(),
+            ));
+        }
+        if self.audit_logs && !cfg!(feature = "logs") {
+            return Err(ConfigError("audit_logs is enabled but this build lacks the `logs` feature".to_string()));
+        }
+        if self.metrics_max_label_sets == 0 {
+            return Err(ConfigError("metrics_max_label_sets must be greater than zero".to_string()));
+        }
+        if let Some(label) = self.metrics_resource_labels.iter().find(|l| !CORRELATION_ATTRIBUTES.contains(&l.as_str())) {
+            return Err(ConfigError(format!(
+                "metrics_resource_labels: unknown attribute {label:?}, expected one of {}",
+                CORRELATION_ATTRIBUTES.join(", ")
+            )));
+        }
+        if !self.baggage_keys.is_empty() && !cfg!(feature = "traces") {
+            return Err(ConfigError("baggage_keys are set but this build lacks the `traces` feature".to_string()));
+        }
+        if self.tenant_source.is_none() && (!self.tenant_allowlist.is_empty() || self.tenant_hash_buckets.is_some()) {
+            return Err(ConfigError("tenant_allowlist or tenant_hash_buckets is set without tenant_source".to_string()));
+        }
+        if !self.tenant_allowlist.is_empty() && self.tenant_hash_buckets.is_some() {
+            return Err(ConfigError("tenant_allowlist and tenant_hash_buckets can't be combined".to_string()));
+        }
+        if self.tenant_hash_buckets == Some(0) {
+            return Err(ConfigError("tenant_hash_buckets must be at least 1".to_string()));
+        }
+        if self.span_metrics && !cfg!(feature = "traces") {
+            return Err(ConfigError("span_metrics is enabled but this build lacks the `traces` feature".to_string()));
+        }
+        if self.tail_sampling && !cfg!(feature = "traces") {
+            return Err(ConfigError("tail_sampling is enabled but this build lacks the `traces` feature".to_string()));
+        }
+        if self.tail_sampling_window.is_zero() {
+            return Err(ConfigError("tail_sampling_window must be greater than zero".to_string()));
+        }
+        if self.tail_sampling_max_traces == 0 {
+            return Err(ConfigError("tail_sampling_max_traces must be at least 1".to_string()));
+        }
+        if self.metrics_otlp_bridge && !cfg!(feature = "metrics") {
+            return Err(ConfigError(
+                "metrics_otlp_bridge is enabled but this build lacks the `metrics` feature".to_string(),
+            ));
+        }
+        let const_labels = self.metric_const_labels();
+        let mut baggage_labels = vec!["method".to_string(), "route".to_string()];
+        if !self.client_subnets.is_empty() && !self.client_labels {
+            return Err(ConfigError("client_subnets are set but client_labels is off".to_string()));
+        }
+        for key in &self.baggage_keys {
+            let label = baggage::label_name(key);
+            if label.starts_with("__") || baggage_labels.contains(&label) || const_labels.contains_key(&label) {
+                return Err(ConfigError(format!(
+                    "baggage_keys: {key:?} can't be used as the label {label:?}, which is reserved or taken"
+                )));
+            }
+            baggage_labels.push(label);
+        }
+        if self.metrics_streaming && !self.metrics_cache_ttl.is_zero() {
+            return Err(ConfigError("metrics_streaming and metrics_cache_ttl can't be combined".to_string()));
+        }
+        if self.metrics_multiprocess_dir.is_some() && self.metrics_multiprocess_interval.is_zero() {
+            return Err(ConfigError("metrics_multiprocess_interval must be greater than zero".to_string()));
+        }
+        if !(MIN_SCHEMA..=MAX_SCHEMA).contains(&self.metrics_native_histogram_schema) {
+            return Err(ConfigError(format!(
+                "metrics_native_histogram_schema must be between {MIN_SCHEMA} and {MAX_SCHEMA}"
+            )));
+        }
+        if self.metrics_native_histogram_max_buckets == 0 {
+            return Err(ConfigError("metrics_native_histogram_max_buckets must be greater than zero".to_string()));
+        }
+        if let Some(path) = &self.metrics_catalog_path {
+            check_parent_dir("metrics_catalog_path", path)?;
+        }
+        if self.system_metrics_interval.is_zero() {
+            return Err(ConfigError("system_metrics_interval must be greater than zero".to_string()));
+        }
+        if !self.event_loop_probe_interval.is_zero() && self.event_loop_blocked_threshold <= self.event_loop_probe_interval {
+            return Err(ConfigError(
+                "event_loop_blocked_threshold must be longer than event_loop_probe_interval".to_string(),
+            ));
+        }
+        if self.slow_request_profile && !cfg!(feature = "pprof") {
+            return Err(ConfigError("slow_request_profile is enabled but this build lacks the `pprof` feature".to_string()));
+        }
+        if self.slow_request_profile && self.slow_request_threshold.is_zero() {
+            return Err(ConfigError("slow_request_profile is enabled but slow_request_threshold is not set".to_string()));
+        }
+        if self.memory_pressure_threshold_mb == Some(0) {
+            return Err(ConfigError("memory_pressure_threshold_mb must be greater than zero".to_string()));
+        }
+        if self.apdex_threshold.is_zero() {
+            return Err(ConfigError("apdex_threshold must be greater than zero".to_string()));
+        }
+        for route in &self.apdex_route_thresholds {
+            let ApdexThresholds { satisfied, tolerating } = route.thresholds;
+            if satisfied.is_zero() || tolerating < satisfied {
+                return Err(ConfigError(format!(
+                    "apdex_route_thresholds {route}: thresholds must be positive and tolerating at least satisfied"
+                )));
+            }
+        }
+        for (i, slo) in self.slos.iter().enumerate() {
+            let kind = slo.objective.as_str();
+            if self.slos[..i].iter().any(|other| other.route == slo.route && other.objective.as_str() == kind) {
+                return Err(ConfigError(format!("slos: route {:?} has two {kind} objectives", slo.route)));
+            }
+        }
+        for (i, limit) in self.rate_limits.iter().enumerate() {
+            if self.rate_limits[..i].iter().any(|other| other.route == limit.route) {
+                return Err(ConfigError(format!("rate_limits: route {:?} has two limits", limit.route)));
+            }
+        }
+        for (i, timeout) in self.request_timeouts.iter().enumerate() {
+            if self.request_timeouts[..i].iter().any(|other| other.route == timeout.route) {
+                return Err(ConfigError(format!("request_timeouts: route {:?} has two deadlines", timeout.route)));
+            }
+        }
+        for (i, limit) in self.request_size_limits.iter().enumerate() {
+            if self.request_size_limits[..i].iter().any(|other| other.route == limit.route) {
+                return Err(ConfigError(format!("request_size_limits: route {:?} has two limits", limit.route)));
+            }
+        }
+        for (i, rule) in self.response_cache.iter().enumerate() {
+            if self.response_cache[..i].iter().any(|other| other.route == rule.route) {
+                return Err(ConfigError(format!("response_cache: route {:?} has two TTLs", rule.route)));
+            }
+        }
+        if self.response_cache_max_entries == 0 {
+            return Err(ConfigError("response_cache_max_entries must be at least 1".to_string()));
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return Err(ConfigError("tls_cert_path and tls_key_path must be set together".to_string()));
+        }
+        if let Some(listener) = self.server_addrs.iter().find(|l| l.scheme == Scheme::Https && !self.tls_enabled()) {
+            return Err(ConfigError(format!("server_addrs {listener}: HTTPS needs tls_cert_path and tls_key_path")));
+        }
+        if let Some(ScrapeCredentials::Bearer(token)) = &self.metrics_credentials
+            && token.is_empty()
+        {
+            return Err(ConfigError("metrics bearer token must not be empty".to_string()));
+        }
+        if self.otlp_receiver && !cfg!(feature = "gateway") {
+            return Err(ConfigError("the OTLP receiver is enabled but this build lacks the `gateway` feature".to_string()));
+        }
+        if self.admin_token.as_ref().is_some_and(String::is_empty) {
+            return Err(ConfigError("admin_token must not be empty".to_string()));
+        }
+        if let Some(addr) = &self.grpc_health_addr {
+            if !cfg!(feature = "grpc-health") {
+                return Err(ConfigError(
+                    "the gRPC health service is configured but this build lacks the `grpc-health` feature".to_string(),
+                ));
+            }
+            addr.parse::<SocketAddr>()
+                .map_err(|e| ConfigError(format!("grpc_health_addr {addr:?}: {e}")))?;
+        }
+        if !self.federate_targets.is_empty() {
+            if !cfg!(feature = "federation") {
+                return Err(ConfigError(
+                    "federate targets are configured but this build lacks the `federation` feature".to_string(),
+                ));
+            }
+            for (i, target) in self.federate_targets.iter().enumerate() {
+                if self.federate_targets[..i].iter().any(|other| other.source == target.source) {
+                    return Err(ConfigError(format!("federate_targets: source {:?} is listed twice", target.source)));
+                }
+            }
+            if self.federate_interval.is_zero() {
+                return Err(ConfigError("federate_interval must be greater than zero".to_string()));
+            }
+        }
+        if let Some(addr) = &self.statsd_addr {
+            addr.parse::<SocketAddr>().map_err(|e| ConfigError(format!("statsd_addr {addr:?}: {e}")))?;
+        }
+        if self.debug_tap && self.admin_token.is_none() {
+            return Err(ConfigError("debug_tap requires admin_token".to_string()));
+        }
+        if self.debug_tap_buffer == 0 {
+            return Err(ConfigError("debug_tap_buffer must be at least 1".to_string()));
+        }
+        if !(0.0..=1.0).contains(&self.debug_tap_sample_ratio) {
+            return Err(ConfigError(format!(
+                "debug_tap_sample_ratio must be between 0 and 1, got {}",
+                self.debug_tap_sample_ratio
+            )));
+        }
+        if let Some(url) = &self.flags_url {
+            if !cfg!(feature = "flags") {
+                return Err(ConfigError(
+                    "a flag service is configured but this build lacks the `flags` feature".to_string(),
+                ));
+            }
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(ConfigError(format!("flags_url must be an http(s) URL, got {url:?}")));
+            }
+            if self.flags_sdk_key.as_ref().is_none_or(String::is_empty) {
+                return Err(ConfigError("flags_sdk_key is required when flags_url is set".to_string()));
+            }
+            if self.flags_poll_interval.is_zero() {
+                return Err(ConfigError("flags_poll_interval must be greater than zero".to_string()));
+            }
+        }
+        if let Some(url) = &self.remote_sampling_url {
+            if !cfg!(feature = "remote-sampling") {
+                return Err(ConfigError(
+                    "remote sampling is configured but this build lacks the `remote-sampling` feature".to_string(),
+                ));
+            }
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(ConfigError(format!("remote_sampling_url must be an http(s) URL, got {url:?}")));
+            }
+            if self.remote_sampling_interval.is_zero() {
+                return Err(ConfigError("remote_sampling_interval must be greater than zero".to_string()));
+            }
+            if !(0.0..=1.0).contains(&self.remote_sampling_initial_ratio) {
+                return Err(ConfigError(format!(
+                    "remote_sampling_initial_ratio must be between 0 and 1, got {}",
+                    self.remote_sampling_initial_ratio
+                )));
+            }
+        }
+        if let Some(url) = &self.pyroscope_url {
+            if !cfg!(feature = "pyroscope") {
+                return Err(ConfigError(
+                    "Pyroscope is configured but this build lacks the `pyroscope` feature".to_string(),
+                ));
+            }
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(ConfigError(format!("pyroscope_url must be an http(s) URL, got {url:?}")));
+            }
+            if self.pyroscope_upload_interval.is_zero() {
+                return Err(ConfigError("pyroscope_upload_interval must be greater than zero".to_string()));
+            }
+        }
+        for (i, step) in self.shutdown_flush_order.iter().enumerate() {
+            if step.timeout.is_zero() {
+                return Err(ConfigError(format!("shutdown_flush_order {step}: the deadline must be greater than zero")));
+            }
+            if self.shutdown_flush_order[..i].iter().any(|earlier| earlier.signal == step.signal) {
+                return Err(ConfigError(format!(
+                    "shutdown_flush_order lists {} more than once",
+                    step.signal.as_str()
+                )));
+            }
+        }
+        for (i, token) in self.app_auth_tokens.iter().enumerate() {
+            if self.app_auth_tokens[..i].iter().any(|other| other.token == token.token) {
+                return Err(ConfigError(format!(
+                    "app_auth_tokens: the token of {:?} is listed twice",
+                    token.principal
+                )));
+            }
+        }
+        if let Some(url) = &self.app_auth_jwks_url {
+            if !cfg!(feature = "oidc") {
+                return Err(ConfigError(
+                    "JWT authentication is configured but this build lacks the `oidc` feature".to_string(),
+                ));
+            }
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(ConfigError(format!("app_auth_jwks_url must be an http(s) URL, got {url:?}")));
+            }
+            if self.app_auth_principal_claim.is_empty() {
+                return Err(ConfigError("app_auth_principal_claim must not be empty".to_string()));
+            }
+        }
+        if self.tls_enabled() && !cfg!(feature = "tls") {
+            return Err(ConfigError("TLS is configured but this build lacks the `tls` feature".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.config_file {
+            Some(path) => writeln!(f, "config_file = {}", path.display())?,
+            None => writeln!(f, "config_file = off")?,
+        }
+        let addrs: Vec<String> = self.server_addrs.iter().map(ToString::to_string).collect();
+        writeln!(f, "server_addrs = {}", addrs.join(","))?;
+        writeln!(f, "endpoint_prefix = {}", self.endpoint_prefix)?;
+        writeln!(f, "server_tuning = {}", self.server_tuning)?;
+        writeln!(f, "telemetry_disabled = {}", self.telemetry_disabled)?;
+        writeln!(f, "otlp_endpoint = {}", self.otlp_endpoint)?;
+        match &self.otlp_unix_socket {
+            Some(path) => writeln!(f, "otlp_unix_socket = {}", path.display())?,
+            None => writeln!(f, "otlp_unix_socket = off")?,
+        }
+        for (name, signal) in EXPORTERS {
+            writeln!(f, "{name} = {} ({})", self.exporter_endpoint(signal), self.exporter(signal))?;
+        }
+        match &self.shadow_endpoint {
+            Some(endpoint) => writeln!(f, "shadow_export = {endpoint} ({})", self.shadow_exporter)?,
+            None => writeln!(f, "shadow_export = off")?,
+        }
+        writeln!(f, "service_name = {}", self.service_name)?;
+        writeln!(f, "service_version = {}", self.service_version)?;
+        writeln!(f, "service_instance_id = {}", self.service_instance_id)?;
+        writeln!(f, "deployment_environment = {}", self.deployment_environment.as_deref().unwrap_or("unset"))?;
+        writeln!(f, "trace_batch = {}", self.trace_batch)?;
+        writeln!(f, "log_batch = {}", self.log_batch)?;
+        writeln!(
+            f,
+            "metric_export = every {}ms, timeout {}ms",
+            self.metric_export_interval.as_millis(),
+            self.metric_export_timeout.as_millis()
+        )?;
+        match self.export_circuit_breaker_threshold {
+            Some(threshold) => writeln!(
+                f,
+                "export_circuit_breaker = after {threshold} failed batches, open {}s",
+                self.export_circuit_breaker_open.as_secs()
+            )?,
+            None => writeln!(f, "export_circuit_breaker = off")?,
+        }
+        writeln!(f, "metric_temporality = {}", self.metric_temporality)?;
+        let views: Vec<String> = self.metric_views.iter().map(ToString::to_string).collect();
+        writeln!(f, "metric_views = {}", views.join(";"))?;
+        writeln!(f, "log_level = {}", self.log_level)?;
+        writeln!(f, "log_format = {}", self.log_format)?;
+        match &self.log_file {
+            Some(path) => writeln!(
+                f,
+                "log_file = {} (rotation {}, max {} MB, keep {})",
+                path.display(),
+                self.log_file_rotation,
+                self.log_file_max_size_mb,
+                self.log_file_max_files
+            )?,
+            None => writeln!(f, "log_file = off")?,
+        }
+        writeln!(f, "log_stdout = {}", self.log_stdout)?;
+        match &self.log_syslog {
+            Some(target) => writeln!(f, "log_syslog = {target} (facility {})", self.log_syslog_facility)?,
+            None => writeln!(f, "log_syslog = off")?,
+        }
+        writeln!(f, "log_journald = {}", self.log_journald)?;
+        writeln!(f, "redact_fields = {}", self.redact_fields.join(","))?;
+        writeln!(f, "redact_patterns = {}", self.redact_patterns.join(" "))?;
+        let rules: Vec<String> = self.log_metrics.iter().map(ToString::to_string).collect();
+        writeln!(f, "log_metrics = {}", rules.join(";"))?;
+        match self.log_rate_limit {
+            Some(limit) => writeln!(f, "log_rate_limit = {limit}/s per callsite")?,
+            None => writeln!(f, "log_rate_limit = off")?,
+        }
+        writeln!(f, "log_debug_sample_ratio = {}", self.log_debug_sample_ratio)?;
+        writeln!(f, "audit_logs = {}", self.audit_logs)?;
+        writeln!(f, "system_metrics_interval = {}s", self.system_metrics_interval.as_secs())?;
+        if self.event_loop_probe_interval.is_zero() {
+            writeln!(f, "event_loop_probe = off")?;
+        } else {
+            writeln!(
+                f,
+                "event_loop_probe = every {}ms, blocked after {}ms",
+                self.event_loop_probe_interval.as_millis(),
+                self.event_loop_blocked_threshold.as_millis()
+            )?;
+        }
+        if self.slow_request_threshold.is_zero() {
+            writeln!(f, "slow_requests = off")?;
+        } else {
+            writeln!(
+                f,
+                "slow_requests = after {}ms, profile {}",
+                self.slow_request_threshold.as_millis(),
+                self.slow_request_profile
+            )?;
+        }
+        match self.memory_pressure_threshold_mb {
+            Some(mb) => writeln!(f, "memory_pressure_threshold = {mb} MB")?,
+            None => writeln!(f, "memory_pressure_threshold = off")?,
+        }
+        writeln!(f, "sli_latency_threshold = {}ms", self.sli_latency_threshold.as_millis())?;
+        let bad: Vec<String> = self.sli_bad_statuses.iter().map(ToString::to_string).collect();
+        writeln!(f, "sli_bad_statuses = {}", bad.join(","))?;
+        writeln!(f, "apdex_threshold = {}ms", self.apdex_threshold.as_millis())?;
+        let routes: Vec<String> = self.apdex_route_thresholds.iter().map(ToString::to_string).collect();
+        writeln!(f, "apdex_route_thresholds = {}", routes.join(","))?;
+        let slos: Vec<String> = self.slos.iter().map(ToString::to_string).collect();
+        writeln!(f, "slos = {}", slos.join(","))?;
+        writeln!(f, "metrics_excluded_routes = {}", self.metrics_excluded_routes.join(","))?;
+        writeln!(f, "telemetry_excluded_routes = {}", self.telemetry_excluded_routes.join(","))?;
+        let limits: Vec<String> = self.rate_limits.iter().map(ToString::to_string).collect();
+        writeln!(f, "rate_limits = {}", limits.join(","))?;
+        let timeouts: Vec<String> = self.request_timeouts.iter().map(ToString::to_string).collect();
+        writeln!(f, "request_timeouts = {}", timeouts.join(","))?;
+        let limits: Vec<String> = self.request_size_limits.iter().map(ToString::to_string).collect();
+        writeln!(f, "request_size_limits = {}", limits.join(","))?;
+        let rules: Vec<String> = self.response_cache.iter().map(ToString::to_string).collect();
+        writeln!(f, "response_cache = {} (max {} entries)", rules.join(","), self.response_cache_max_entries)?;
+        writeln!(f, "metrics_max_label_sets = {}", self.metrics_max_label_sets)?;
+        writeln!(f, "metrics_resource_labels = {}", self.metrics_resource_labels.join(","))?;
+        match &self.metrics_catalog_path {
+            Some(path) => writeln!(f, "metrics_catalog_path = {}", path.display())?,
+            None => writeln!(f, "metrics_catalog_path = off")?,
+        }
+        if self.metrics_cache_ttl.is_zero() {
+            writeln!(f, "metrics_cache_ttl = off")?;
+        } else {
+            writeln!(f, "metrics_cache_ttl = {}ms", self.metrics_cache_ttl.as_millis())?;
+        }
+        writeln!(f, "metrics_streaming = {}", self.metrics_streaming)?;
+        writeln!(f, "metrics_naming_strict = {}", self.metrics_naming_strict)?;
+        match &self.metrics_multiprocess_dir {
+            Some(dir) => writeln!(
+                f,
+                "metrics_multiprocess = {} (every {}ms, gauges {})",
+                dir.display(),
+                self.metrics_multiprocess_interval.as_millis(),
+                self.metrics_multiprocess_gauge_mode
+            )?,
+            None => writeln!(f, "metrics_multiprocess = off")?,
+        }
+        match self.native_histograms() {
+            Some(opts) => writeln!(
+                f,
+                "metrics_native_histograms = schema {}, at most {} buckets",
+                opts.schema, opts.max_buckets
+            )?,
+            None => writeln!(f, "metrics_native_histograms = off")?,
+        }
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert), Some(key)) => writeln!(f, "tls = cert {}, key {}", cert.display(), key.display()),
+            _ => writeln!(f, "tls = off"),
+        }?;
+        match &self.metrics_credentials {
+            Some(credentials) => writeln!(f, "metrics_auth = {credentials}")?,
+            None => writeln!(f, "metrics_auth = none")?,
+        }
+        let ips: Vec<String> = self.metrics_allowed_ips.iter().map(ToString::to_string).collect();
+        writeln!(f, "metrics_allowed_ips = {}", ips.join(","))?;
+        if self.otlp_receiver {
+            let ips: Vec<String> = self.otlp_receiver_allowed_ips.iter().map(ToString::to_string).collect();
+            writeln!(f, "otlp_receiver = on (allowed {})", ips.join(","))?;
+        } else {
+            writeln!(f, "otlp_receiver = off")?;
+        }
+        writeln!(f, "trace_response_header = {}", self.trace_response_header)?;
+        writeln!(f, "baggage_keys = {}", self.baggage_keys.join(","))?;
+        writeln!(f, "client_labels = {}", self.client_labels)?;
+        let subnets: Vec<String> = self.client_subnets.iter().map(ToString::to_string).collect();
+        writeln!(f, "client_subnets = {}", subnets.join(","))?;
+        match &self.tenant_source {
+            Some(source) => writeln!(f, "tenant_source = {source}")?,
+            None => writeln!(f, "tenant_source = off")?,
+        }
+        writeln!(f, "tenant_allowlist = {}", self.tenant_allowlist.join(","))?;
+        match self.tenant_hash_buckets {
+            Some(buckets) => writeln!(f, "tenant_hash_buckets = {buckets}")?,
+            None => writeln!(f, "tenant_hash_buckets = off")?,
+        }
+        writeln!(f, "span_metrics = {}", self.span_metrics)?;
+        if self.tail_sampling {
+            writeln!(
+                f,
+                "tail_sampling = on (window {}ms, latency threshold {}ms, max {} traces)",
+                self.tail_sampling_window.as_millis(),
+                self.tail_sampling_latency_threshold.as_millis(),
+                self.tail_sampling_max_traces
+            )?;
+        } else {
+            writeln!(f, "tail_sampling = off")?;
+        }
+        writeln!(f, "metrics_otlp_bridge = {}", self.metrics_otlp_bridge)?;
+        writeln!(f, "admin_api = {}", if self.admin_token.is_some() { "on" } else { "off" })?;
+        writeln!(f, "grpc_health_addr = {}", self.grpc_health_addr.as_deref().unwrap_or("off"))?;
+        if self.federate_targets.is_empty() {
+            writeln!(f, "federation = off")?;
+        } else {
+            let targets: Vec<String> = self.federate_targets.iter().map(ToString::to_string).collect();
+            writeln!(f, "federation = {} (every {}s)", targets.join(","), self.federate_interval.as_secs())?;
+        }
+        writeln!(f, "statsd_addr = {}", self.statsd_addr.as_deref().unwrap_or("off"))?;
+        if self.debug_tap {
+            writeln!(
+                f,
+                "debug_tap = on (buffer {}, sample ratio {})",
+                self.debug_tap_buffer, self.debug_tap_sample_ratio
+            )?;
+        } else {
+            writeln!(f, "debug_tap = off")?;
+        }
+        match &self.remote_sampling_url {
+            Some(url) => writeln!(
+                f,
+                "remote_sampling = {url} (every {}s, initially {})",
+                self.remote_sampling_interval.as_secs_f64(),
+                self.remote_sampling_initial_ratio
+            ),
+            None => writeln!(f, "remote_sampling = off"),
+        }?;
+        let steps: Vec<String> = self.flush_steps().iter().map(ToString::to_string).collect();
+        writeln!(f, "shutdown_flush_order = {}", steps.join(","))?;
+        if self.app_auth_enabled() {
+            let tokens: Vec<String> = self.app_auth_tokens.iter().map(ToString::to_string).collect();
+            write!(f, "app_auth = tokens [{}]", tokens.join(","))?;
+            if let Some(url) = &self.app_auth_jwks_url {
+                write!(f, ", jwt {url} (principal {}", self.app_auth_principal_claim)?;
+                if let Some(issuer) = &self.app_auth_issuer {
+                    write!(f, ", issuer {issuer}")?;
+                }
+                if let Some(audience) = &self.app_auth_audience {
+                    write!(f, ", audience {audience}")?;
+                }
+                write!(f, ")")?;
+            }
+            writeln!(f)?;
+        } else {
+            writeln!(f, "app_auth = off")?;
+        }
+        match &self.flags_url {
+            Some(url) => write!(f, "flags = {url} (every {}s)", self.flags_poll_interval.as_secs()),
+            None => writeln!(f, "flags = off"),
+        }?;
+        match (&self.pyroscope_url, &self.pyroscope_credentials) {
+            (Some(url), Some(credentials)) => write!(
+                f,
+                "pyroscope = {url} (every {}s, {credentials})",
+                self.pyroscope_upload_interval.as_secs()
+            ),
+            (Some(url), None) => write!(f, "pyroscope = {url} (every {}s)", self.pyroscope_upload_interval.as_secs()),
+            (None, _) => write!(f, "pyroscope = off"),
+        }
+    }
+}
+
+/// Defines chainable `with_<field>` setters, for building a configuration in
+/// code: `TelemetryConfig::default().with_service_name("checkout")`. They
+/// don't validate; call `Config::validate` once done.
+macro_rules! setters {
+    ($($setter:ident => $field:ident: $ty:ty,)*) => {
+        impl Config {
+            $(
+                #[doc = concat!("Sets `", stringify!($field), "`.")]
+                pub fn $setter(mut self, value: impl Into<$ty>) -> Self {
+                    self.$field = value.into();
+                    self
+                }
+            )*
+        }
+    };
+}
+
+setters! {
+    with_config_file => config_file: Option<PathBuf>,
+    with_server_addrs => server_addrs: Vec<Listener>,
+    with_endpoint_prefix => endpoint_prefix: String,
+    with_server_tuning => server_tuning: ServerTuning,
+    with_telemetry_disabled => telemetry_disabled: bool,
+    with_otlp_endpoint => otlp_endpoint: String,
+    with_otlp_unix_socket => otlp_unix_socket: Option<PathBuf>,
+    with_trace_exporter => trace_exporter: ExporterSettings,
+    with_log_exporter => log_exporter: ExporterSettings,
+    with_metric_exporter => metric_exporter: ExporterSettings,
+    with_shadow_endpoint => shadow_endpoint: Option<String>,
+    with_shadow_exporter => shadow_exporter: ExporterSettings,
+    with_service_name => service_name: String,
+    with_service_version => service_version: String,
+    with_service_instance_id => service_instance_id: String,
+    with_deployment_environment => deployment_environment: Option<String>,
+    with_trace_batch => trace_batch: BatchSettings,
+    with_log_batch => log_batch: BatchSettings,
+    with_metric_export_interval => metric_export_interval: Duration,
+    with_metric_export_timeout => metric_export_timeout: Duration,
+    with_export_circuit_breaker_threshold => export_circuit_breaker_threshold: Option<u32>,
+    with_export_circuit_breaker_open => export_circuit_breaker_open: Duration,
+    with_metric_temporality => metric_temporality: MetricTemporality,
+    with_metric_views => metric_views: Vec<MetricView>,
+    with_log_level => log_level: String,
+    with_log_format => log_format: LogFormat,
+    with_log_file => log_file: Option<PathBuf>,
+    with_log_file_max_size_mb => log_file_max_size_mb: u64,
+    with_log_file_rotation => log_file_rotation: Rotation,
+    with_log_file_max_files => log_file_max_files: usize,
+    with_log_stdout => log_stdout: bool,
+    with_log_syslog => log_syslog: Option<SyslogTarget>,
+    with_log_syslog_facility => log_syslog_facility: Facility,
+    with_log_journald => log_journald: bool,
+    with_redact_fields => redact_fields: Vec<String>,
+    with_redact_patterns => redact_patterns: Vec<String>,
+    with_log_metrics => log_metrics: Vec<LogMetricRule>,
+    with_log_rate_limit => log_rate_limit: Option<u32>,
+    with_log_debug_sample_ratio => log_debug_sample_ratio: f64,
+    with_audit_logs => audit_logs: bool,
+    with_system_metrics_interval => system_metrics_interval: Duration,
+    with_event_loop_probe_interval => event_loop_probe_interval: Duration,
+    with_event_loop_blocked_threshold => event_loop_blocked_threshold: Duration,
+    with_slow_request_threshold => slow_request_threshold: Duration,
+    with_slow_request_profile => slow_request_profile: bool,
+    with_memory_pressure_threshold_mb => memory_pressure_threshold_mb: Option<u64>,
+    with_sli_latency_threshold => sli_latency_threshold: Duration,
+    with_sli_bad_statuses => sli_bad_statuses: Vec<StatusMatcher>,
+    with_apdex_threshold => apdex_threshold: Duration,
+    with_apdex_route_thresholds => apdex_route_thresholds: Vec<RouteApdex>,
+    with_slos => slos: Vec<SloRule>,
+    with_metrics_excluded_routes => metrics_excluded_routes: Vec<String>,
+    with_telemetry_excluded_routes => telemetry_excluded_routes: Vec<String>,
+    with_rate_limits => rate_limits: Vec<RateLimit>,
+    with_request_timeouts => request_timeouts: Vec<RequestTimeout>,
+    with_request_size_limits => request_size_limits: Vec<RequestSizeLimit>,
+    with_response_cache => response_cache: Vec<ResponseCacheRule>,
+    with_response_cache_max_entries => response_cache_max_entries: usize,
+    with_metrics_max_label_sets => metrics_max_label_sets: usize,
+    with_metrics_resource_labels => metrics_resource_labels: Vec<String>,
+    with_metrics_catalog_path => metrics_catalog_path: Option<PathBuf>,
+    with_metrics_cache_ttl => metrics_cache_ttl: Duration,
+    with_metrics_streaming => metrics_streaming: bool,
+    with_metrics_naming_strict => metrics_naming_strict: bool,
+    with_metrics_multiprocess_dir => metrics_multiprocess_dir: Option<PathBuf>,
+    with_metrics_multiprocess_interval => metrics_multiprocess_interval: Duration,
+    with_metrics_multiprocess_gauge_mode => metrics_multiprocess_gauge_mode: GaugeMode,
+    with_metrics_native_histograms => metrics_native_histograms: bool,
+    with_metrics_native_histogram_schema => metrics_native_histogram_schema: i32,
+    with_metrics_native_histogram_max_buckets => metrics_native_histogram_max_buckets: usize,
+    with_tls_cert_path => tls_cert_path: Option<PathBuf>,
+    with_tls_key_path => tls_key_path: Option<PathBuf>,
+    with_metrics_credentials => metrics_credentials: Option<ScrapeCredentials>,
+    with_metrics_allowed_ips => metrics_allowed_ips: Vec<Cidr>,
+    with_otlp_receiver => otlp_receiver: bool,
+    with_otlp_receiver_allowed_ips => otlp_receiver_allowed_ips: Vec<Cidr>,
+    with_trace_response_header => trace_response_header: bool,
+    with_baggage_keys => baggage_keys: Vec<String>,
+    with_client_labels => client_labels: bool,
+    with_client_subnets => client_subnets: Vec<ClientSubnet>,
+    with_tenant_source => tenant_source: Option<TenantSource>,
+    with_tenant_allowlist => tenant_allowlist: Vec<String>,
+    with_tenant_hash_buckets => tenant_hash_buckets: Option<u32>,
+    with_span_metrics => span_metrics: bool,
+    with_tail_sampling => tail_sampling: bool,
+    with_tail_sampling_window => tail_sampling_window: Duration,
+    with_tail_sampling_latency_threshold => tail_sampling_latency_threshold: Duration,
+    with_tail_sampling_max_traces => tail_sampling_max_traces: usize,
+    with_metrics_otlp_bridge => metrics_otlp_bridge: bool,
+    with_admin_token => admin_token: Option<String>,
+    with_grpc_health_addr => grpc_health_addr: Option<String>,
+    with_federate_targets => federate_targets: Vec<FederateTarget>,
+    with_federate_interval => federate_interval: Duration,
+    with_statsd_addr => statsd_addr: Option<String>,
+    with_debug_tap => debug_tap: bool,
+    with_debug_tap_buffer => debug_tap_buffer: usize,
+    with_debug_tap_sample_ratio => debug_tap_sample_ratio: f64,
+    with_flags_url => flags_url: Option<String>,
+    with_flags_sdk_key => flags_sdk_key: Option<String>,
+    with_flags_poll_interval => flags_poll_interval: Duration,
+    with_remote_sampling_url => remote_sampling_url: Option<String>,
+    with_remote_sampling_interval => remote_sampling_interval: Duration,
+    with_remote_sampling_initial_ratio => remote_sampling_initial_ratio: f64,
+    with_pyroscope_url => pyroscope_url: Option<String>,
+    with_pyroscope_credentials => pyroscope_credentials: Option<ScrapeCredentials>,
+    with_pyroscope_upload_interval => pyroscope_upload_interval: Duration,
+    with_shutdown_flush_order => shutdown_flush_order: Vec<FlushStep>,
+    with_app_auth_tokens => app_auth_tokens: Vec<AppToken>,
+    with_app_auth_jwks_url => app_auth_jwks_url: Option<String>,
+    with_app_auth_issuer => app_auth_issuer: Option<String>,
+    with_app_auth_audience => app_auth_audience: Option<String>,
+    with_app_auth_principal_claim => app_auth_principal_claim: String,
+}
+
+/// (De)serializes each type through its `Display` and `FromStr`
+/// implementations, i.e. in the syntax of its environment variable.
+macro_rules! serde_as_str {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl Serialize for $ty {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+                }
+            }
+        )*
+    };
+}
+
+serde_as_str!(
+    Listener,
+    OtlpProtocol,
+    OtlpCompression,
+    MetricTemporality,
+    MetricView,
+    GaugeMode,
+    LogFormat,
+    Rotation,
+    SyslogTarget,
+    Facility,
+    LogMetricRule,
+    StatusMatcher,
+    RouteApdex,
+    SloRule,
+    Cidr,
+    ClientSubnet,
+    FlushStep,
+    RateLimit,
+    RequestTimeout,
+    RequestSizeLimit,
+    ResponseCacheRule,
+    TenantSource,
+    FederateTarget,
+);
+
+/// Durations as `250ms`, `5s`, `10m` or `1h`; serialized in whole seconds
+/// when possible, milliseconds otherwise.
+mod duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        let ms = duration.as_millis();
+        if ms.is_multiple_of(1000) {
+            serializer.collect_str(&format_args!("{}s", ms / 1000))
+        } else {
+            serializer.collect_str(&format_args!("{ms}ms"))
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse(&s).ok_or_else(|| de::Error::custom(format!("invalid duration {s:?}, expected e.g. `250ms` or `5s`")))
+    }
+
+    fn parse(s: &str) -> Option<Duration> {
+        let s = s.trim();
+        let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit())?);
+        let value: u64 = value.parse().ok()?;
+        match unit.trim() {
+            "ms" => Some(Duration::from_millis(value)),
+            "s" => Some(Duration::from_secs(value)),
+            "m" => value.checked_mul(60).map(Duration::from_secs),
+            "h" => value.checked_mul(3600).map(Duration::from_secs),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Configuration reload (`CONFIG_FILE`).
+//!
+//! With `CONFIG_FILE` set, the file is read again when the process receives
+//! `SIGHUP` or when its modification time changes (checked every 5 s), under
+//! the environment variables and flags of the startup. The settings below
+//! take effect right away:
+//!
+//! - `log_level`: the filter of every log output;
+//! - `log_rate_limit` and `log_debug_sample_ratio`: the OTLP log sampler
+//!   (`logs` feature);
+//! - `system_metrics_interval`: the system metrics sampler;
+//! - `metrics_credentials` and `metrics_allowed_ips`: access to `/metrics`.
+//!
+//! Other settings that changed are logged as needing a restart and keep
+//! their running values. Applied changes are recorded in the audit trail
+//! with the `config_file` source (see `audit`). Each reload is logged and
+//! counted in `config_reloads_total{outcome}`: `failure` when the file can't
+//! be read, doesn't validate or a setting can't be applied, `success`
+//! otherwise. A file that fails to load changes nothing.
+
+use crate::{
+    audit::{ChangeSource, ConfigChanges},
+    auth::EndpointAuth,
+    config::Config,
+    registry::Registry,
+    telemetry::LogFilterHandle,
+};
+#[cfg(feature = "logs")]
+use crate::log_sampling::LogSampler;
+use prometheus::{IntCounterVec, Opts};
+use serde_json::{Map, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How often the modification time of the file is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a reload acts on.
+#[derive(Clone, Debug)]
+pub struct ReloadTargets {
+    pub log_filter: LogFilterHandle,
+    #[cfg(feature = "logs")]
+    pub log_sampler: Option<LogSampler>,
+    pub system_metrics_interval: watch::Sender<Duration>,
+    pub metrics_auth: EndpointAuth,
+}
+
+impl ReloadTargets {
+    /// Applies `setting` of `new`; `Ok(false)` if it needs a restart.
+    fn apply(&self, setting: &str, new: &Config) -> Result<bool, String> {
+        match setting {
+            "log_level" => self.log_filter.reload(&new.log_level)?,
+            #[cfg(feature = "logs")]
+            "log_rate_limit" | "log_debug_sample_ratio" => match &self.log_sampler {
+                Some(sampler) => sampler.set(new.log_rate_limit, new.log_debug_sample_ratio),
+                None => return Ok(false),
+            },
+            "system_metrics_interval" => {
+                self.system_metrics_interval.send_replace(new.system_metrics_interval);
+            }
+            "metrics_credentials" | "metrics_allowed_ips" => {
+                self.metrics_auth.set(new.metrics_credentials.clone(), new.metrics_allowed_ips.clone());
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// The running configuration and how to change it.
+#[derive(Debug)]
+pub struct ConfigReloader {
+    config: Config,
+    /// The serialized settings in effect, updated as changes are applied.
+    running: Map<String, Value>,
+    targets: ReloadTargets,
+    changes: ConfigChanges,
+    reloads: IntCounterVec,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        registry: &Registry,
+        config: Config,
+        targets: ReloadTargets,
+        changes: ConfigChanges,
+    ) -> prometheus::Result<Self> {
+        let reloads = IntCounterVec::new(
+            Opts::new("config_reloads_total", "Reloads of the configuration file by outcome"),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(reloads.clone()))?;
+        for outcome in ["success", "failure"] {
+            reloads.with_label_values(&[outcome]);
+        }
+        Ok(Self { running: settings(&config), config, targets, changes, reloads })
+    }
+
+    /// Reads the file again and applies the settings that changed.
+    pub fn reload(&mut self) {
+        let new = match self.config.reload() {
+            Ok(new) => new,
+            Err(e) => {
+                self.reloads.with_label_values(&["failure"]).inc();
+                warn!(error = %e, "Keeping the current configuration");
+                return;
+            }
+        };
+        let (mut applied, mut restart, mut failed) = (0, Vec::new(), 0);
+        for (setting, value) in settings(&new) {
+            let old = self.running.get(&setting).unwrap_or(&Value::Null);
+            if *old == value {
+                continue;
+            }
+            match self.targets.apply(&setting, &new) {
+                Ok(true) => {
+                    self.changes.record(ChangeSource::ConfigFile, &setting, &old.to_string(), &value.to_string());
+                    self.running.insert(setting, value);
+                    applied += 1;
+                }
+                Ok(false) => restart.push(setting),
+                Err(e) => {
+                    warn!(setting, error = %e, "Cannot apply the reloaded setting");
+                    failed += 1;
+                }
+            }
+        }
+        if !restart.is_empty() {
+            warn!(settings = restart.join(","), "Changed settings need a restart to take effect");
+        }
+        let outcome = if failed == 0 { "success" } else { "failure" };
+        self.reloads.with_label_values(&[outcome]).inc();
+        info!(path = %self.path().display(), applied, outcome, "Configuration reloaded");
+        self.config = new;
+    }
+
+    fn path(&self) -> &Path {
+        self.config.config_file.as_deref().unwrap_or(Path::new(""))
+    }
+}
+
+/// The settings of `config` by name, as serialized.
+fn settings(config: &Config) -> Map<String, Value> {
+    match serde_json::to_value(config) {
+        Ok(Value::Object(settings)) => settings,
+        _ => Map::new(),
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reloads on `SIGHUP` (Unix) and whenever the file's modification time
+/// changes.
+pub async fn watch(mut reloader: ConfigReloader) {
+    let path: PathBuf = reloader.path().to_path_buf();
+    let mut last_modified = modified(&path);
+    let mut hangup = Hangup::new();
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let now = modified(&path);
+                if now == last_modified {
+                    continue;
+                }
+                last_modified = now;
+            }
+            () = hangup.recv() => {}
+        }
+        reloader.reload();
+    }
+}
+
+/// `SIGHUP` where there are signals.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let signal = signal(SignalKind::hangup())
+                .inspect_err(|e| warn!("Cannot listen for SIGHUP, reloading the config file on changes only: {e}"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    /// Resolves on the next `SIGHUP`, never without signals.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal
+            && signal.recv().await.is_some()
+        {
+            return;
+        }
+        std::future::pending().await
+    }
+}
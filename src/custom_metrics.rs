@@ -0,0 +1,373 @@
+//! Metrics created by name where they are used.
+//!
+//! `AppMetrics::timer`, `counter_vec` and `gauge_vec` return builders that
+//! register their family in the app registry the first time a name is
+//! asked for and hand out the same family afterwards, so application code
+//! needs neither a `metric_set!` struct nor raw `prometheus` handles:
+//!
+//! ```ignore
+//! async fn run_job(metrics: &AppMetrics, job: Job) -> Result<(), JobError> {
+//!     // Observed when dropped, on every return path.
+//!     let _timer = metrics.timer("job_duration_seconds").help("How long jobs ran").start();
+//!     let failures = metrics.counter_vec("job_failures_total").help("Failed jobs").labels(&["reason"]).build()?;
+//!     job.prepare().await.inspect_err(|_| failures.inc(&["prepare"]))?;
+//!     job.run().await.inspect_err(|_| failures.inc(&["run"]))
+//! }
+//! ```
+//!
+//! Families are registered with `AppMetrics::register`, so the naming
+//! conventions apply, and label values go through the cardinality limiter.
+//! Without `help` the help text is the name; timers use the `prometheus`
+//! default buckets unless given `buckets`, and `METRICS_NATIVE_HISTOGRAMS`
+//! like the built-in histograms. Asking for a name again with another kind
+//! or other labels is an error; `TimerBuilder::start`, which can't return
+//! one, logs it and times into a histogram outside the registry instead.
+
+use crate::{
+    cardinality::CardinalityLimiter,
+    metrics::AppMetrics,
+    native_histogram::NativeHistogramOpts,
+    sharded::{HistogramTimer, ShardedHistogramVec},
+};
+use prometheus::{core::Collector, GaugeVec, HistogramOpts, IntCounterVec, Opts, DEFAULT_BUCKETS};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+#[derive(Clone, Debug)]
+enum Family {
+    Timer(Timer),
+    Counter(CounterHandle),
+    Gauge(GaugeHandle),
+}
+
+impl Family {
+    fn kind(&self) -> &'static str {
+        match self {
+            Family::Timer(_) => "timer",
+            Family::Counter(_) => "counter",
+            Family::Gauge(_) => "gauge",
+        }
+    }
+}
+
+/// A family created through the builders, with its label names.
+type Entry = (Vec<String>, Family);
+
+/// The families created so far, by name; cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct CustomMetrics {
+    families: Arc<Mutex<HashMap<String, Entry>>>,
+    /// Every name leaked for a family, registered or not, so a name whose
+    /// registration keeps failing is leaked only once.
+    names: Arc<Mutex<HashMap<String, &'static str>>>,
+    native: Option<NativeHistogramOpts>,
+}
+
+impl CustomMetrics {
+    pub fn new(native: Option<NativeHistogramOpts>) -> Self {
+        Self { families: Arc::default(), names: Arc::default(), native }
+    }
+}
+
+/// What the three builders share.
+struct Spec<'a> {
+    metrics: &'a AppMetrics,
+    name: String,
+    help: Option<String>,
+    labels: Vec<String>,
+}
+
+impl<'a> Spec<'a> {
+    fn new(metrics: &'a AppMetrics, name: &str) -> Self {
+        Self { metrics, name: name.to_string(), help: None, labels: Vec::new() }
+    }
+
+    fn opts(&self) -> Opts {
+        Opts::new(self.name.as_str(), self.help.as_deref().unwrap_or(&self.name))
+    }
+
+    fn label_names(&self) -> Vec<&str> {
+        self.labels.iter().map(String::as_str).collect()
+    }
+
+    /// The family registered under the name, or the one `create` makes,
+    /// registered now. `create` gets the name with a static lifetime, which
+    /// the cardinality limiter wants; it is leaked once per name, even when
+    /// registering fails.
+    fn get_or_register<C>(&self, kind: &'static str, create: C) -> prometheus::Result<Family>
+    where
+        C: FnOnce(&'static str) -> prometheus::Result<(Family, Box<dyn Collector>)>,
+    {
+        let custom = &self.metrics.custom_metrics;
+        let mut families = custom.families.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((labels, family)) = families.get(&self.name) {
+            if family.kind() != kind || *labels != self.labels {
+                return Err(prometheus::Error::Msg(format!(
+                    "{} is already a {} with labels {labels:?}, not a {kind} with labels {:?}",
+                    self.name,
+                    family.kind(),
+                    self.labels
+                )));
+            }
+            return Ok(family.clone());
+        }
+        let name = *custom
+            .names
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(self.name.clone())
+            .or_insert_with(|| Box::leak(self.name.clone().into_boxed_str()));
+        let (family, collector) = create(name)?;
+        self.metrics.register(collector)?;
+        families.insert(self.name.clone(), (self.labels.clone(), family.clone()));
+        Ok(family)
+    }
+}
+
+/// Builder of a duration histogram, see `AppMetrics::timer`.
+pub struct TimerBuilder<'a> {
+    spec: Spec<'a>,
+    buckets: Option<Vec<f64>>,
+}
+
+impl<'a> TimerBuilder<'a> {
+    pub(crate) fn new(metrics: &'a AppMetrics, name: &str) -> Self {
+        Self { spec: Spec::new(metrics, name), buckets: None }
+    }
+
+    pub fn help(mut self, help: &str) -> Self {
+        self.spec.help = Some(help.to_string());
+        self
+    }
+
+    pub fn labels(mut self, labels: &[&str]) -> Self {
+        self.spec.labels = labels.iter().map(|label| label.to_string()).collect();
+        self
+    }
+
+    /// Upper bounds in seconds; only used when the family is created.
+    pub fn buckets(mut self, buckets: &[f64]) -> Self {
+        self.buckets = Some(buckets.to_vec());
+        self
+    }
+
+    /// The histogram, registered if it wasn't yet.
+    pub fn build(self) -> prometheus::Result<Timer> {
+        let native = self.spec.metrics.custom_metrics.native;
+        let opts = HistogramOpts::from(self.spec.opts())
+            .buckets(self.buckets.clone().unwrap_or_else(|| DEFAULT_BUCKETS.to_vec()));
+        let labels = self.spec.label_names();
+        let cardinality = self.spec.metrics.cardinality.clone();
+        let family = self.spec.get_or_register("timer", |name| {
+            let histograms = ShardedHistogramVec::new(opts, &labels)?.with_native(native);
+            let timer = Timer { name, histograms, cardinality };
+            Ok((Family::Timer(timer.clone()), Box::new(timer.histograms.clone())))
+        })?;
+        match family {
+            Family::Timer(timer) => Ok(timer),
+            _ => unreachable!("checked by get_or_register"),
+        }
+    }
+
+    /// Starts timing into the histogram, which must have no labels. If it
+    /// can't be registered the error is logged and the duration observed
+    /// into a histogram no scrape sees.
+    pub fn start(self) -> HistogramTimer {
+        let name = self.spec.name.clone();
+        match self.build() {
+            Ok(timer) => timer.start(),
+            Err(e) => {
+                warn!(metric = %name, error = %e, "Failed to register a timer");
+                ShardedHistogramVec::new(HistogramOpts::new("unregistered_seconds", "Unregistered timer"), &[])
+                    .expect("the fallback histogram is valid")
+                    .with_label_values(&[])
+                    .start_timer()
+            }
+        }
+    }
+}
+
+/// A duration histogram created by `AppMetrics::timer`; cheap to clone.
+#[derive(Clone)]
+pub struct Timer {
+    name: &'static str,
+    histograms: ShardedHistogramVec,
+    cardinality: CardinalityLimiter,
+}
+
+impl Timer {
+    /// A guard observing the elapsed seconds when dropped.
+    ///
+    /// # Panics
+    ///
+    /// If the timer has labels.
+    pub fn start(&self) -> HistogramTimer {
+        self.histograms.start_timer_with_labels(&[])
+    }
+
+    /// Like `start`, into the child of `values`.
+    ///
+    /// # Panics
+    ///
+    /// If `values` doesn't match the labels.
+    pub fn start_with_labels(&self, values: &[&str]) -> HistogramTimer {
+        let values = self.cardinality.limit_vec(self.name, values.to_vec());
+        self.histograms.start_timer_with_labels(&values)
+    }
+
+    /// Runs `fut` and observes how long it took from its first poll, see
+    /// `ShardedHistogram::time`.
+    pub fn time<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> + use<F> {
+        self.histograms.time_with_labels(&[], fut)
+    }
+
+    /// Like `time`, into the child of `values`.
+    pub fn time_with_labels<F: Future>(&self, values: &[&str], fut: F) -> impl Future<Output = F::Output> + use<F> {
+        let values = self.cardinality.limit_vec(self.name, values.to_vec());
+        self.histograms.time_with_labels(&values, fut)
+    }
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timer").field("name", &self.name).finish()
+    }
+}
+
+/// Builder of a labeled counter, see `AppMetrics::counter_vec`.
+pub struct CounterVecBuilder<'a> {
+    spec: Spec<'a>,
+}
+
+impl<'a> CounterVecBuilder<'a> {
+    pub(crate) fn new(metrics: &'a AppMetrics, name: &str) -> Self {
+        Self { spec: Spec::new(metrics, name) }
+    }
+
+    pub fn help(mut self, help: &str) -> Self {
+        self.spec.help = Some(help.to_string());
+        self
+    }
+
+    pub fn labels(mut self, labels: &[&str]) -> Self {
+        self.spec.labels = labels.iter().map(|label| label.to_string()).collect();
+        self
+    }
+
+    /// The counter, registered if it wasn't yet.
+    pub fn build(self) -> prometheus::Result<CounterHandle> {
+        let opts = self.spec.opts();
+        let labels = self.spec.label_names();
+        let cardinality = self.spec.metrics.cardinality.clone();
+        let family = self.spec.get_or_register("counter", |name| {
+            let counters = IntCounterVec::new(opts, &labels)?;
+            let handle = CounterHandle { name, counters, cardinality };
+            Ok((Family::Counter(handle.clone()), Box::new(handle.counters.clone())))
+        })?;
+        match family {
+            Family::Counter(handle) => Ok(handle),
+            _ => unreachable!("checked by get_or_register"),
+        }
+    }
+}
+
+/// A counter created by `AppMetrics::counter_vec`; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct CounterHandle {
+    name: &'static str,
+    counters: IntCounterVec,
+    cardinality: CardinalityLimiter,
+}
+
+impl CounterHandle {
+    /// Adds one to the child of `values`.
+    ///
+    /// # Panics
+    ///
+    /// If `values` doesn't match the labels.
+    pub fn inc(&self, values: &[&str]) {
+        self.inc_by(values, 1);
+    }
+
+    /// Adds `n` to the child of `values`.
+    ///
+    /// # Panics
+    ///
+    /// Like `inc`.
+    pub fn inc_by(&self, values: &[&str], n: u64) {
+        let values = self.cardinality.limit_vec(self.name, values.to_vec());
+        self.counters.with_label_values(&values).inc_by(n);
+    }
+}
+
+/// Builder of a labeled gauge, see `AppMetrics::gauge_vec`.
+pub struct GaugeVecBuilder<'a> {
+    spec: Spec<'a>,
+}
+
+impl<'a> GaugeVecBuilder<'a> {
+    pub(crate) fn new(metrics: &'a AppMetrics, name: &str) -> Self {
+        Self { spec: Spec::new(metrics, name) }
+    }
+
+    pub fn help(mut self, help: &str) -> Self {
+        self.spec.help = Some(help.to_string());
+        self
+    }
+
+    pub fn labels(mut self, labels: &[&str]) -> Self {
+        self.spec.labels = labels.iter().map(|label| label.to_string()).collect();
+        self
+    }
+
+    /// The gauge, registered if it wasn't yet.
+    pub fn build(self) -> prometheus::Result<GaugeHandle> {
+        let opts = self.spec.opts();
+        let labels = self.spec.label_names();
+        let cardinality = self.spec.metrics.cardinality.clone();
+        let family = self.spec.get_or_register("gauge", |name| {
+            let gauges = GaugeVec::new(opts, &labels)?;
+            let handle = GaugeHandle { name, gauges, cardinality };
+            Ok((Family::Gauge(handle.clone()), Box::new(handle.gauges.clone())))
+        })?;
+        match family {
+            Family::Gauge(handle) => Ok(handle),
+            _ => unreachable!("checked by get_or_register"),
+        }
+    }
+}
+
+/// A gauge created by `AppMetrics::gauge_vec`; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct GaugeHandle {
+    name: &'static str,
+    gauges: GaugeVec,
+    cardinality: CardinalityLimiter,
+}
+
+impl GaugeHandle {
+    /// Sets the child of `values` to `value`.
+    ///
+    /// # Panics
+    ///
+    /// If `values` doesn't match the labels.
+    pub fn set(&self, values: &[&str], value: f64) {
+        let values = self.cardinality.limit_vec(self.name, values.to_vec());
+        self.gauges.with_label_values(&values).set(value);
+    }
+
+    /// Adds `delta`, which may be negative, to the child of `values`.
+    ///
+    /// # Panics
+    ///
+    /// Like `set`.
+    pub fn add(&self, values: &[&str], delta: f64) {
+        let values = self.cardinality.limit_vec(self.name, values.to_vec());
+        self.gauges.with_label_values(&values).add(delta);
+    }
+}
@@ -0,0 +1,337 @@
+//! Time-boxed debug sessions started through `POST /admin/debug-session`.
+//!
+//! A session names a set of route templates, a log level and a trace sampling
+//! ratio. While it lasts, requests to those routes run with the session as a
+//! task-local (see `track_requests`): `DebugLevelFilter` lets their events
+//! through down to the session level and `DebugSampler` samples their traces
+//! at least at the session ratio. Sessions expire on their own; each one is
+//! exported as a `debug_session` span covering its lifetime.
+
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{Link, SamplingDecision, SamplingResult, Span as _, SpanKind, TraceId, Tracer},
+    Context, KeyValue,
+};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+use serde_json::Value;
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, level_filters::LevelFilter, subscriber::Interest, Level, Metadata};
+use tracing_subscriber::layer::{Context as LayerContext, Filter};
+
+/// Upper bound for `duration`, so a forgotten session can't leave debug
+/// logging on for long.
+pub const MAX_DURATION: Duration = Duration::from_secs(60 * 60);
+
+tokio::task_local! {
+    static CURRENT: Arc<DebugSession>;
+}
+
+/// Parameters of a debug session, as posted to the admin API.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugSessionRequest {
+    pub duration: Duration,
+    /// Route templates as registered, e.g. `/users/{id}`.
+    pub routes: Vec<String>,
+    /// Minimum trace sampling ratio for matching requests.
+    pub sample: f64,
+    /// Most verbose level logged for matching requests.
+    pub level: Level,
+}
+
+impl DebugSessionRequest {
+    /// Parses `{"duration": "5m", "routes": [...], "sample": 1.0, "level": "debug"}`.
+    /// `duration` is seconds or a number suffixed with `s`, `m` or `h`;
+    /// `sample` and `level` are optional.
+    pub fn from_json(body: &[u8]) -> Result<Self, String> {
+        let body: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {e}"))?;
+        let duration = match body.get("duration") {
+            Some(Value::Number(secs)) => secs
+                .as_u64()
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("duration must be a whole number of seconds, got {secs}"))?,
+            Some(Value::String(s)) => parse_duration(s)?,
+            _ => return Err("duration is required".to_string()),
+        };
+        if duration.is_zero() || duration > MAX_DURATION {
+            return Err(format!("duration must be between 1s and {}s", MAX_DURATION.as_secs()));
+        }
+        let routes: Vec<String> = match body.get("routes") {
+            Some(Value::Array(routes)) => routes
+                .iter()
+                .map(|route| route.as_str().map(str::to_string).ok_or("routes must be strings"))
+                .collect::<Result<_, _>>()?,
+            _ => return Err("routes must be a non-empty array of route templates".to_string()),
+        };
+        if routes.is_empty() {
+            return Err("routes must be a non-empty array of route templates".to_string());
+        }
+        let sample = match body.get("sample") {
+            None => 1.0,
+            Some(sample) => sample
+                .as_f64()
+                .filter(|sample| (0.0..=1.0).contains(sample))
+                .ok_or_else(|| format!("sample must be a number between 0 and 1, got {sample}"))?,
+        };
+        let level = match body.get("level") {
+            None => Level::DEBUG,
+            Some(Value::String(level)) => level.parse().map_err(|_| format!("invalid level {level:?}"))?,
+            Some(level) => return Err(format!("level must be a string, got {level}")),
+        };
+        Ok(Self { duration, routes, sample, level })
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration like 90, 90s, 5m or 1h, got {s:?}");
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => s.split_at(at),
+        None => (s, "s"),
+    };
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+#[derive(Debug)]
+pub struct DebugSession {
+    pub id: u64,
+    pub routes: Vec<String>,
+    pub sample: f64,
+    pub level: Level,
+    pub expires_at: Instant,
+    /// Requests that ran under this session so far.
+    matched: AtomicU64,
+}
+
+impl DebugSession {
+    pub fn is_active(&self) -> bool {
+        Instant::now() < self.expires_at
+    }
+
+    pub fn matched_requests(&self) -> u64 {
+        self.matched.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for DebugSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} routes={} level={} sample={}",
+            self.id,
+            self.routes.join(","),
+            self.level,
+            self.sample
+        )
+    }
+}
+
+/// Registry of running debug sessions, shared by the admin API, the request
+/// middleware and `DebugLevelFilter`.
+#[derive(Clone, Debug, Default)]
+pub struct DebugSessions {
+    sessions: Arc<RwLock<Vec<Arc<DebugSession>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl DebugSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a session and schedules its end. Must be called from within a
+    /// Tokio runtime.
+    pub fn start(&self, request: DebugSessionRequest) -> Arc<DebugSession> {
+        let session = Arc::new(DebugSession {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            routes: request.routes,
+            sample: request.sample,
+            level: request.level,
+            expires_at: Instant::now() + request.duration,
+            matched: AtomicU64::new(0),
+        });
+        self.sessions.write().unwrap().push(session.clone());
+        // Callsites below the configured level were cached as disabled.
+        tracing::callsite::rebuild_interest_cache();
+        info!(session = %session, duration_secs = request.duration.as_secs(), "Debug session started");
+
+        #[cfg(feature = "traces")]
+        let mut span = {
+            let tracer = global::tracer("prom_otel");
+            tracer
+                .span_builder("debug_session")
+                .with_attributes([
+                    KeyValue::new("debug_session.id", session.id as i64),
+                    KeyValue::new("debug_session.routes", session.routes.join(",")),
+                    KeyValue::new("debug_session.level", session.level.to_string()),
+                    KeyValue::new("debug_session.sample", session.sample),
+                    KeyValue::new("debug_session.duration_secs", request.duration.as_secs() as i64),
+                ])
+                .start(&tracer)
+        };
+        let sessions = self.clone();
+        let ended = session.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(request.duration).await;
+            sessions.remove(ended.id);
+            info!(session = %ended, matched_requests = ended.matched_requests(), "Debug session ended");
+            #[cfg(feature = "traces")]
+            {
+                span.set_attribute(KeyValue::new("debug_session.matched_requests", ended.matched_requests() as i64));
+                span.end();
+            }
+        });
+        session
+    }
+
+    fn remove(&self, id: u64) {
+        self.sessions.write().unwrap().retain(|session| session.id != id);
+        tracing::callsite::rebuild_interest_cache();
+    }
+
+    /// The active session covering `route`, if any; the most recent one wins.
+    pub fn for_route(&self, route: &str) -> Option<Arc<DebugSession>> {
+        let sessions = self.sessions.read().unwrap();
+        if sessions.is_empty() {
+            return None;
+        }
+        sessions
+            .iter()
+            .rev()
+            .find(|session| session.is_active() && session.routes.iter().any(|r| r == route))
+            .cloned()
+    }
+
+    pub fn active(&self) -> Vec<Arc<DebugSession>> {
+        self.sessions.read().unwrap().iter().filter(|s| s.is_active()).cloned().collect()
+    }
+
+    /// The most verbose level any active session enables.
+    fn max_level(&self) -> Option<LevelFilter> {
+        self.active().iter().map(|session| LevelFilter::from_level(session.level)).max()
+    }
+}
+
+/// Runs `fut` (a request) under `session`.
+pub async fn scope<F: Future>(session: Arc<DebugSession>, fut: F) -> F::Output {
+    session.matched.fetch_add(1, Ordering::Relaxed);
+    CURRENT.scope(session, fut).await
+}
+
+/// The active session the current request runs under, if any.
+pub fn current() -> Option<Arc<DebugSession>> {
+    CURRENT.try_with(Arc::clone).ok().filter(|session| session.is_active())
+}
+
+/// Wraps a per-layer filter so events of requests in a debug session are
+/// enabled down to the session level, whatever `inner` decides.
+pub struct DebugLevelFilter<F> {
+    inner: F,
+    sessions: DebugSessions,
+}
+
+impl<F> DebugLevelFilter<F> {
+    pub fn new(inner: F, sessions: DebugSessions) -> Self {
+        Self { inner, sessions }
+    }
+}
+
+impl<S, F: Filter<S>> Filter<S> for DebugLevelFilter<F> {
+    fn enabled(&self, meta: &Metadata<'_>, cx: &LayerContext<'_, S>) -> bool {
+        self.inner.enabled(meta, cx) || current().is_some_and(|session| *meta.level() <= session.level)
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let interest = self.inner.callsite_enabled(meta);
+        match self.sessions.max_level() {
+            Some(max) if !interest.is_always() && *meta.level() <= max => Interest::sometimes(),
+            _ => interest,
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let inner = self.inner.max_level_hint()?;
+        Some(self.sessions.max_level().map_or(inner, |max| inner.max(max)))
+    }
+
+    fn event_enabled(&self, event: &tracing::Event<'_>, cx: &LayerContext<'_, S>) -> bool {
+        self.inner.event_enabled(event, cx)
+    }
+
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, cx: LayerContext<'_, S>) {
+        self.inner.on_new_span(attrs, id, cx);
+    }
+
+    fn on_record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>, cx: LayerContext<'_, S>) {
+        self.inner.on_record(id, values, cx);
+    }
+
+    fn on_enter(&self, id: &tracing::span::Id, cx: LayerContext<'_, S>) {
+        self.inner.on_enter(id, cx);
+    }
+
+    fn on_exit(&self, id: &tracing::span::Id, cx: LayerContext<'_, S>) {
+        self.inner.on_exit(id, cx);
+    }
+
+    fn on_close(&self, id: tracing::span::Id, cx: LayerContext<'_, S>) {
+        self.inner.on_close(id, cx);
+    }
+}
+
+/// Delegates to `base`, but gives requests in a debug session at least the
+/// session's sampling ratio and tags their sampled spans with the session id.
+#[cfg(feature = "traces")]
+#[derive(Clone, Debug)]
+pub struct DebugSampler {
+    base: Box<dyn ShouldSample>,
+}
+
+#[cfg(feature = "traces")]
+impl DebugSampler {
+    pub fn new(base: Box<dyn ShouldSample>) -> Self {
+        Self { base }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl ShouldSample for DebugSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let result = self.base.should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+        let Some(session) = current() else {
+            return result;
+        };
+        let mut result = match result.decision {
+            SamplingDecision::RecordAndSample => result,
+            _ => Sampler::TraceIdRatioBased(session.sample)
+                .should_sample(parent_context, trace_id, name, span_kind, attributes, links),
+        };
+        if result.decision == SamplingDecision::RecordAndSample {
+            result.attributes.push(KeyValue::new("debug_session.id", session.id as i64));
+        }
+        result
+    }
+}
@@ -0,0 +1,370 @@
+//! Live spans and log events on the `/debug/tap` WebSocket (`DEBUG_TAP`),
+//! mounted only when `ADMIN_TOKEN` is set and protected by it.
+//!
+//! Developers can watch a pod's traffic as it happens without access to the
+//! tracing backend, e.g. with `websocat -H 'Authorization: Bearer ...'
+//! ws://pod:8888/debug/tap`. Each text message is one JSON object:
+//!
+//! ```json
+//! {"type": "span", "trace_id": "4bf9...", "span_id": "00f0...", "parent_span_id": "53ab...",
+//!  "name": "GET /", "kind": "server", "start_time_ms": 1760500000000, "duration_ms": 1.25,
+//!  "status": "unset", "attributes": {"http.route": "/"}, "events": []}
+//! {"type": "log", "timestamp_ms": 1760500000001, "level": "INFO", "target": "app",
+//!  "fields": {"message": "App is ready"}, "trace_id": "4bf9...", "span_id": "00f0..."}
+//! ```
+//!
+//! Spans (with the `traces` feature) are tapped as they end, log events as
+//! `LOG_LEVEL` lets them through; values are redacted like in the exports.
+//! `DEBUG_TAP_SAMPLE_RATIO` keeps that share of traces, decided on the trace
+//! id so a kept trace comes with its logs, and of the log events outside a
+//! trace. The last `DEBUG_TAP_BUFFER` items are replayed to each new client
+//! before live ones; a client too slow to keep up gets a `{"type": "lagged",
+//! "skipped": n}` message in place of the items it missed. Messages from the
+//! client other than pings and close are ignored.
+
+use crate::{auth::EndpointAuth, log_format::FieldVisitor, redact::Redactor};
+use actix_http::ws::{self, CloseCode, OpCode, Parser};
+use actix_web::{
+    http::header::{self, HeaderValue},
+    web::{self, Bytes, BytesMut},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{SpanKind, Status},
+    Context as OtelContext, KeyValue,
+};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    trace::{Span, SpanData, SpanProcessor},
+};
+
+/// Largest frame accepted from a client, which only sends control frames.
+const MAX_CLIENT_FRAME: usize = 64 * 1024;
+
+/// Recently finished spans and log events, as JSON, and their live clients.
+#[derive(Clone, Debug)]
+pub struct DebugTap {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    recent: Mutex<VecDeque<Arc<str>>>,
+    capacity: usize,
+    sender: broadcast::Sender<Arc<str>>,
+    sample_ratio: f64,
+    /// Log events seen outside a trace, for their sampling.
+    untraced: AtomicU64,
+}
+
+impl DebugTap {
+    pub fn new(capacity: usize, sample_ratio: f64) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            inner: Arc::new(Inner {
+                recent: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+                sender: broadcast::channel(capacity).0,
+                sample_ratio,
+                untraced: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Whether the trace with this id, or with `None` the next log event
+    /// outside a trace, is tapped.
+    fn sampled(&self, trace_id: Option<u128>) -> bool {
+        let ratio = self.inner.sample_ratio;
+        if ratio >= 1.0 {
+            return true;
+        }
+        match trace_id {
+            // The low half of the id, like the SDK's ratio sampler.
+            Some(id) => ((id as u64) as f64) < ratio * u64::MAX as f64,
+            None => {
+                let seen = self.inner.untraced.fetch_add(1, Ordering::Relaxed) as f64;
+                ((seen + 1.0) * ratio).floor() > (seen * ratio).floor()
+            }
+        }
+    }
+
+    fn publish(&self, item: Value) {
+        let item: Arc<str> = item.to_string().into();
+        let mut recent = self.inner.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if recent.len() == self.inner.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(item.clone());
+        // Sent under the lock, so `subscribe` neither misses nor repeats it.
+        let _ = self.inner.sender.send(item);
+    }
+
+    /// The buffered items and a receiver for the ones that follow them.
+    fn subscribe(&self) -> (Vec<Arc<str>>, broadcast::Receiver<Arc<str>>) {
+        let recent = self.inner.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        (recent.iter().cloned().collect(), self.inner.sender.subscribe())
+    }
+
+    /// A layer tapping log events, with field values passed through
+    /// `redactor`.
+    pub(crate) fn layer(&self, redactor: Redactor) -> DebugTapLayer {
+        DebugTapLayer { tap: self.clone(), redactor }
+    }
+
+    /// A span processor tapping spans as they end, with attribute values
+    /// passed through `redactor`.
+    #[cfg(feature = "traces")]
+    pub(crate) fn span_processor(&self, redactor: Redactor) -> DebugTapProcessor {
+        DebugTapProcessor { tap: self.clone(), redactor }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Taps log events into `DebugTap`, see `DebugTap::layer`.
+pub struct DebugTapLayer {
+    tap: DebugTap,
+    redactor: Redactor,
+}
+
+impl<S: Subscriber> Layer<S> for DebugTapLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        #[cfg(feature = "traces")]
+        let span_context = {
+            use opentelemetry::trace::TraceContextExt;
+
+            let cx = OtelContext::current();
+            let span_context = cx.span().span_context().clone();
+            span_context.is_valid().then_some(span_context)
+        };
+        #[cfg(feature = "traces")]
+        let trace_id = span_context.as_ref().map(|span_context| u128::from_be_bytes(span_context.trace_id().to_bytes()));
+        #[cfg(not(feature = "traces"))]
+        let trace_id = None;
+        if !self.tap.sampled(trace_id) {
+            return;
+        }
+
+        let metadata = event.metadata();
+        let mut fields = FieldVisitor::new(&self.redactor);
+        event.record(&mut fields);
+        #[cfg_attr(not(feature = "traces"), allow(unused_mut))]
+        let mut item = json!({
+            "type": "log",
+            "timestamp_ms": now_ms(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.into_fields(),
+        });
+        #[cfg(feature = "traces")]
+        if let Some(span_context) = span_context {
+            item["trace_id"] = span_context.trace_id().to_string().into();
+            item["span_id"] = span_context.span_id().to_string().into();
+        }
+        self.tap.publish(item);
+    }
+}
+
+/// Taps spans into `DebugTap` as they end, see `DebugTap::span_processor`.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct DebugTapProcessor {
+    tap: DebugTap,
+    redactor: Redactor,
+}
+
+#[cfg(feature = "traces")]
+impl DebugTapProcessor {
+    fn attributes(&self, attributes: &[KeyValue]) -> Value {
+        attributes
+            .iter()
+            .map(|attribute| {
+                let key = attribute.key.as_str();
+                let value = match &attribute.value {
+                    _ if self.redactor.is_sensitive_field(key) => crate::redact::REDACTED.into(),
+                    opentelemetry::Value::Bool(value) => (*value).into(),
+                    opentelemetry::Value::I64(value) => (*value).into(),
+                    opentelemetry::Value::F64(value) => (*value).into(),
+                    value => self.redactor.redact_patterns(&value.to_string()).into_owned().into(),
+                };
+                (key.to_string(), value)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+#[cfg(feature = "traces")]
+impl SpanProcessor for DebugTapProcessor {
+    fn on_start(&self, _span: &mut Span, _cx: &OtelContext) {}
+
+    fn on_end(&self, span: SpanData) {
+        let span_context = &span.span_context;
+        if !self.tap.sampled(Some(u128::from_be_bytes(span_context.trace_id().to_bytes()))) {
+            return;
+        }
+        let kind = match span.span_kind {
+            SpanKind::Client => "client",
+            SpanKind::Server => "server",
+            SpanKind::Producer => "producer",
+            SpanKind::Consumer => "consumer",
+            SpanKind::Internal => "internal",
+        };
+        let (status, status_message) = match &span.status {
+            Status::Unset => ("unset", None),
+            Status::Ok => ("ok", None),
+            Status::Error { description } => ("error", Some(description.to_string())),
+        };
+        let events: Vec<Value> = span
+            .events
+            .iter()
+            .map(|event| json!({ "name": event.name, "attributes": self.attributes(&event.attributes) }))
+            .collect();
+        let mut item = json!({
+            "type": "span",
+            "trace_id": span_context.trace_id().to_string(),
+            "span_id": span_context.span_id().to_string(),
+            "name": span.name,
+            "kind": kind,
+            "start_time_ms": span.start_time.duration_since(UNIX_EPOCH).map_or(0, |start| start.as_millis() as u64),
+            "duration_ms": span.end_time.duration_since(span.start_time).unwrap_or_default().as_secs_f64() * 1000.0,
+            "status": status,
+            "attributes": self.attributes(&span.attributes),
+            "events": events,
+        });
+        if span.parent_span_id != opentelemetry::trace::SpanId::INVALID {
+            item["parent_span_id"] = span.parent_span_id.to_string().into();
+        }
+        if let Some(message) = status_message {
+            item["status_message"] = message.into();
+        }
+        self.tap.publish(item);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: std::time::Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+fn frame(op: OpCode, payload: impl AsRef<[u8]>) -> Result<Bytes, Infallible> {
+    let mut frame = BytesMut::new();
+    Parser::write_message(&mut frame, payload, op, true, false);
+    Ok(frame.freeze())
+}
+
+fn close(code: CloseCode) -> Result<Bytes, Infallible> {
+    let mut frame = BytesMut::new();
+    Parser::write_close(&mut frame, Some(code.into()), false);
+    Ok(frame.freeze())
+}
+
+/// One client: the buffered items still to send, then the live ones.
+struct Session {
+    replay: std::vec::IntoIter<Arc<str>>,
+    receiver: broadcast::Receiver<Arc<str>>,
+    payload: web::Payload,
+    read: BytesMut,
+    closed: bool,
+}
+
+/// The server's frames of `session`: tapped items, pongs and a final close.
+fn frames(session: Session) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    stream::unfold(session, |mut session| async move {
+        if session.closed {
+            return None;
+        }
+        if let Some(item) = session.replay.next() {
+            return Some((frame(OpCode::Text, item.as_bytes()), session));
+        }
+        loop {
+            match Parser::parse(&mut session.read, true, MAX_CLIENT_FRAME) {
+                Ok(Some((_, OpCode::Ping, payload))) => {
+                    return Some((frame(OpCode::Pong, payload.unwrap_or_default()), session));
+                }
+                Ok(Some((_, OpCode::Close, _))) => {
+                    session.closed = true;
+                    return Some((close(CloseCode::Normal), session));
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => {}
+                Err(_) => {
+                    session.closed = true;
+                    return Some((close(CloseCode::Protocol), session));
+                }
+            }
+            tokio::select! {
+                item = session.receiver.recv() => match item {
+                    Ok(item) => return Some((frame(OpCode::Text, item.as_bytes()), session)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        let lagged = json!({ "type": "lagged", "skipped": skipped }).to_string();
+                        return Some((frame(OpCode::Text, lagged), session));
+                    }
+                    Err(RecvError::Closed) => {
+                        session.closed = true;
+                        return Some((close(CloseCode::Away), session));
+                    }
+                },
+                chunk = session.payload.next() => match chunk {
+                    Some(Ok(chunk)) => session.read.extend_from_slice(&chunk),
+                    // The client went away without a close frame.
+                    Some(Err(_)) | None => return None,
+                },
+            }
+        }
+    })
+}
+
+/// `GET /debug/tap`: upgrades to a WebSocket streaming the tapped items.
+async fn tap(
+    req: HttpRequest,
+    payload: web::Payload,
+    auth: web::Data<EndpointAuth>,
+    tap: web::Data<DebugTap>,
+) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    if let Err(e) = ws::verify_handshake(req.head()) {
+        return e.error_response();
+    }
+    let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY) else {
+        return ws::HandshakeError::BadWebsocketKey.error_response();
+    };
+    let accept = ws::hash_key(key.as_bytes());
+    let (recent, receiver) = tap.subscribe();
+    let session = Session { replay: recent.into_iter(), receiver, payload, read: BytesMut::new(), closed: false };
+    HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, HeaderValue::from_bytes(&accept).expect("base64 is a valid header")))
+        .streaming(frames(session))
+}
+
+/// Methods and route templates of `resource`.
+pub const ROUTES: &[(&str, &str)] = &[("GET", "/debug/tap")];
+
+/// The `/debug/tap` resource, guarded by the admin credentials in `auth`.
+pub fn resource(auth: web::Data<EndpointAuth>, debug_tap: web::Data<DebugTap>) -> actix_web::Resource {
+    web::resource("/debug/tap").app_data(auth).app_data(debug_tap).route(web::get().to(tap))
+}
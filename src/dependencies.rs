@@ -0,0 +1,320 @@
+//! Health of the downstream dependencies this service calls.
+//!
+//! Outbound calls, whatever client makes them (reqwest, a tonic channel, a
+//! database driver), are recorded per dependency, usually the host called.
+//! A call spans every attempt, retries included:
+//!
+//! ```ignore
+//! let response = metrics
+//!     .dependencies
+//!     .call_with_retries("payments.internal", "POST /charges", 3, Duration::from_millis(100), || {
+//!         client.post(url).json(&charge).send().and_then(|response| async { response.error_for_status() })
+//!     })
+//!     .await?;
+//! ```
+//!
+//! or, to decide what to retry and how to wait, by hand:
+//!
+//! ```ignore
+//! let mut call = metrics.dependencies.start("payments.internal", "POST /charges");
+//! let response = loop {
+//!     match send().with_context(call.context()).await {
+//!         Ok(response) => break Ok(response),
+//!         Err(e) if e.is_timeout() && call.attempts() < 3 => call.retry(&e.to_string()),
+//!         Err(e) => break Err(e),
+//!     }
+//! };
+//! call.finish(response.as_ref().err().map(|_| "http"));
+//! ```
+//!
+//! Each call is counted in `dependency_requests_total{dependency,result}`
+//! (`success` or `error`) and timed in
+//! `dependency_request_duration_seconds{dependency}`; each retry is counted
+//! in `dependency_retries_total{dependency}`.
+//! `dependency_availability_ratio{dependency}` is the share of successful
+//! calls over the last five minutes, computed when scraped, and 1 without
+//! calls. Together they make a health panel for every downstream, and
+//! `emit-alerts` adds a `DependencyDegraded` alert on the gauge.
+//!
+//! With the `traces` feature a call is a client span named after the
+//! operation, with `server.address` set to the dependency, a child of the
+//! current context; each retry adds a `retry` event with `retry.attempt`
+//! and the error message, and a failed call ends with `error.type` and the
+//! `Error` status. `context` holds the span, so the client's own spans and
+//! its propagated trace headers follow it.
+
+use crate::cardinality::CardinalityLimiter;
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Seconds covered by one bucket of the availability window.
+const BUCKET_SECS: u64 = 10;
+/// Buckets in the availability window, five minutes.
+const WINDOW_BUCKETS: usize = 30;
+
+/// Successful and failed calls of one bucket.
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    slot: u64,
+    success: u64,
+    error: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    retries: IntCounterVec,
+    availability: GaugeVec,
+    started: Instant,
+    /// Availability windows by (limited) dependency label, indexed by slot
+    /// modulo `WINDOW_BUCKETS`.
+    windows: Mutex<HashMap<String, [Bucket; WINDOW_BUCKETS]>>,
+}
+
+impl Inner {
+    fn slot(&self) -> u64 {
+        self.started.elapsed().as_secs() / BUCKET_SECS
+    }
+}
+
+/// The `dependency_*` families; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct DependencyMetrics {
+    inner: Arc<Inner>,
+    cardinality: CardinalityLimiter,
+}
+
+impl DependencyMetrics {
+    pub fn new(registry: &Registry, cardinality: CardinalityLimiter) -> prometheus::Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new("dependency_requests_total", "Calls to downstream dependencies by result, retries included"),
+            &["dependency", "result"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new("dependency_request_duration_seconds", "Latency of calls to downstream dependencies"),
+            &["dependency"],
+        )?;
+        let retries = IntCounterVec::new(
+            Opts::new("dependency_retries_total", "Retried attempts of calls to downstream dependencies"),
+            &["dependency"],
+        )?;
+        let availability = GaugeVec::new(
+            Opts::new("dependency_availability_ratio", "Share of successful calls to a dependency over the last 5 minutes"),
+            &["dependency"],
+        )?;
+        let metrics = Self {
+            inner: Arc::new(Inner {
+                requests,
+                duration,
+                retries,
+                availability,
+                started: Instant::now(),
+                windows: Mutex::new(HashMap::new()),
+            }),
+            cardinality,
+        };
+        registry.register(Box::new(metrics.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Starts a call of `operation` on `dependency`, in a client span with
+    /// the `traces` feature.
+    pub fn start(&self, dependency: &str, operation: &str) -> DependencyCall {
+        let [dependency] = self.cardinality.limit("dependency_requests_total", [dependency]);
+        #[cfg(feature = "traces")]
+        let context = {
+            let tracer = global::tracer("prom_otel");
+            let span = tracer
+                .span_builder(operation.to_string())
+                .with_kind(SpanKind::Client)
+                .with_attributes([KeyValue::new("server.address", dependency.to_string())])
+                .start_with_context(&tracer, &Context::current());
+            Context::current().with_span(span)
+        };
+        #[cfg(not(feature = "traces"))]
+        let _ = operation;
+        DependencyCall {
+            metrics: self.clone(),
+            dependency: dependency.to_string(),
+            started: Instant::now(),
+            attempts: 1,
+            #[cfg(feature = "traces")]
+            context,
+        }
+    }
+
+    /// Runs `attempt` up to `max_attempts` times, `backoff` apart and
+    /// doubling, until it succeeds, as one call of `operation` on
+    /// `dependency`. Every error is retried.
+    pub async fn call_with_retries<T, E, F, Fut>(
+        &self,
+        dependency: &str,
+        operation: &str,
+        max_attempts: u32,
+        mut backoff: Duration,
+        mut attempt: F,
+    ) -> Result<T, E>
+    where
+        E: Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut call = self.start(dependency, operation);
+        loop {
+            #[cfg(feature = "traces")]
+            let result = attempt().with_context(call.context()).await;
+            #[cfg(not(feature = "traces"))]
+            let result = attempt().await;
+            match result {
+                Ok(value) => {
+                    call.finish(None);
+                    return Ok(value);
+                }
+                Err(e) if call.attempts() < max_attempts => {
+                    call.retry(&e.to_string());
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    call.finish(Some(std::any::type_name::<E>()));
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn observe(&self, dependency: &str, elapsed: Duration, failed: bool) {
+        let inner = &self.inner;
+        let result = if failed { "error" } else { "success" };
+        inner.requests.with_label_values(&[dependency, result]).inc();
+        inner.duration.with_label_values(&[dependency]).observe(elapsed.as_secs_f64());
+        let slot = inner.slot();
+        let mut windows = inner.windows.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let window = windows.entry(dependency.to_string()).or_insert([Bucket::default(); WINDOW_BUCKETS]);
+        let bucket = &mut window[slot as usize % WINDOW_BUCKETS];
+        if bucket.slot != slot {
+            *bucket = Bucket { slot, success: 0, error: 0 };
+        }
+        if failed {
+            bucket.error += 1;
+        } else {
+            bucket.success += 1;
+        }
+    }
+
+    /// Sets the availability gauges to the current window.
+    fn refresh(&self) {
+        let inner = &self.inner;
+        let now = inner.slot();
+        let windows = inner.windows.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (dependency, window) in windows.iter() {
+            let (success, error) = window
+                .iter()
+                .filter(|bucket| now.saturating_sub(bucket.slot) < WINDOW_BUCKETS as u64)
+                .fold((0, 0), |(success, error), bucket| (success + bucket.success, error + bucket.error));
+            let availability = match success + error {
+                0 => 1.0,
+                total => success as f64 / total as f64,
+            };
+            inner.availability.with_label_values(&[dependency]).set(availability);
+        }
+    }
+}
+
+impl Collector for DependencyMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let inner = &self.inner;
+        inner
+            .requests
+            .desc()
+            .into_iter()
+            .chain(inner.duration.desc())
+            .chain(inner.retries.desc())
+            .chain(inner.availability.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.refresh();
+        let inner = &self.inner;
+        let mut families = inner.requests.collect();
+        families.extend(inner.duration.collect());
+        families.extend(inner.retries.collect());
+        families.extend(inner.availability.collect());
+        families
+    }
+}
+
+/// A call between `DependencyMetrics::start` and `finish`.
+#[derive(Debug)]
+pub struct DependencyCall {
+    metrics: DependencyMetrics,
+    dependency: String,
+    started: Instant,
+    attempts: u32,
+    /// Holds the client span.
+    #[cfg(feature = "traces")]
+    context: Context,
+}
+
+impl DependencyCall {
+    /// The context holding the client span, to send the request in.
+    #[cfg(feature = "traces")]
+    pub fn context(&self) -> Context {
+        self.context.clone()
+    }
+
+    /// Attempts made so far, starting at 1.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Records that the last attempt failed with `error` and another one
+    /// follows.
+    pub fn retry(&mut self, error: &str) {
+        self.metrics.inner.retries.with_label_values(&[&self.dependency]).inc();
+        #[cfg(feature = "traces")]
+        self.context.span().add_event(
+            "retry",
+            vec![
+                KeyValue::new("retry.attempt", i64::from(self.attempts)),
+                KeyValue::new("error.message", error.to_string()),
+            ],
+        );
+        #[cfg(not(feature = "traces"))]
+        let _ = error;
+        self.attempts += 1;
+    }
+
+    /// Ends the call, failed with `error_type` if given.
+    pub fn finish(self, error_type: Option<&str>) {
+        self.metrics.observe(&self.dependency, self.started.elapsed(), error_type.is_some());
+        #[cfg(feature = "traces")]
+        {
+            let span = self.context.span();
+            span.set_attribute(KeyValue::new("retry.count", i64::from(self.attempts - 1)));
+            if let Some(error_type) = error_type {
+                span.set_attribute(KeyValue::new("error.type", error_type.to_string()));
+                span.set_status(Status::error(error_type.to_string()));
+            }
+            span.end();
+        }
+    }
+}
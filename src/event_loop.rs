@@ -0,0 +1,175 @@
+//! Event loop lag of the async runtimes (`EVENT_LOOP_PROBE_INTERVAL_MS`).
+//!
+//! A task that hogs a runtime thread, blocking I/O or a long computation
+//! outside `spawn_blocking`, delays every other task on it long before
+//! request latency shows why. A probe task sleeps for the interval (100 ms
+//! by default) on each runtime, the tokio runtime of `main` and the runtime
+//! of each HTTP worker, and observes how late its timer fired in
+//! `event_loop_lag_seconds{runtime}` (`main` or `http`).
+//!
+//! A blocked runtime can't run its probe to report it, so a watchdog thread
+//! counts the probes that haven't woken for `EVENT_LOOP_BLOCKED_THRESHOLD_MS`
+//! (default 1 s) in `event_loop_blocked_workers{runtime}`.
+
+use crate::registry::Registry;
+use prometheus::{HistogramOpts, HistogramVec, IntGaugeVec, Opts};
+use std::{
+    cell::RefCell,
+    future::Future,
+    io,
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+const RUNTIMES: [&str; 2] = ["main", "http"];
+
+thread_local! {
+    /// The probe of the HTTP worker running on this thread, if any.
+    static WORKER_PROBE: RefCell<Weak<WorkerProbe>> = const { RefCell::new(Weak::new()) };
+}
+
+#[derive(Debug)]
+struct Probe {
+    runtime: &'static str,
+    /// Milliseconds since `Inner::started` of the last wake.
+    woke_ms: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    interval: Duration,
+    blocked_threshold: Duration,
+    started: Instant,
+    probes: Mutex<Vec<Arc<Probe>>>,
+    lag: HistogramVec,
+    blocked: IntGaugeVec,
+}
+
+impl Inner {
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+/// The probes and their metrics; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct EventLoop {
+    inner: Arc<Inner>,
+}
+
+impl EventLoop {
+    pub fn new(registry: &Registry, interval: Duration, blocked_threshold: Duration) -> prometheus::Result<Self> {
+        let lag = HistogramVec::new(
+            HistogramOpts::new("event_loop_lag_seconds", "How late the probe timer of the runtime fired")
+                .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+            &["runtime"],
+        )?;
+        let blocked = IntGaugeVec::new(
+            Opts::new("event_loop_blocked_workers", "Runtime threads whose probe hasn't woken for the blocked threshold"),
+            &["runtime"],
+        )?;
+        registry.register(Box::new(lag.clone()))?;
+        registry.register(Box::new(blocked.clone()))?;
+        for runtime in RUNTIMES {
+            blocked.with_label_values(&[runtime]);
+        }
+        Ok(Self {
+            inner: Arc::new(Inner {
+                interval,
+                blocked_threshold,
+                started: Instant::now(),
+                probes: Mutex::new(Vec::new()),
+                lag,
+                blocked,
+            }),
+        })
+    }
+
+    /// Probes the runtime it is spawned on, as `runtime`, until dropped.
+    pub fn probe(&self, runtime: &'static str) -> impl Future<Output = ()> + use<> {
+        let inner = self.inner.clone();
+        async move {
+            let registration = Registration::new(&inner, runtime);
+            let lag = inner.lag.with_label_values(&[runtime]);
+            loop {
+                let due = tokio::time::Instant::now() + inner.interval;
+                tokio::time::sleep_until(due).await;
+                lag.observe(due.elapsed().as_secs_f64());
+                registration.0.woke_ms.store(inner.elapsed_ms(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Spawns the probe of an HTTP worker on its runtime; call from the app
+    /// factory and keep the guard in the app, so the probe stops with the
+    /// worker. The factory runs once per listener on each worker, and every
+    /// call on a thread shares the probe of the first.
+    pub fn start_worker_probe(&self) -> Rc<WorkerProbe> {
+        WORKER_PROBE.with(|worker| {
+            if let Some(probe) = worker.borrow().upgrade() {
+                return probe;
+            }
+            let probe = Rc::new(WorkerProbe(actix_web::rt::spawn(self.probe("http"))));
+            *worker.borrow_mut() = Rc::downgrade(&probe);
+            probe
+        })
+    }
+
+    /// Starts the watchdog thread updating `event_loop_blocked_workers`.
+    pub fn start_watchdog(&self) -> io::Result<()> {
+        let inner = self.inner.clone();
+        std::thread::Builder::new().name("event-loop-watchdog".into()).spawn(move || {
+            let threshold = inner.blocked_threshold.as_millis() as u64;
+            loop {
+                std::thread::sleep(inner.interval);
+                let now = inner.elapsed_ms();
+                let mut blocked = [0; RUNTIMES.len()];
+                for probe in inner.probes.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+                    let woke = probe.woke_ms.load(Ordering::Relaxed);
+                    if now.saturating_sub(woke) > threshold
+                        && let Some(index) = RUNTIMES.iter().position(|runtime| *runtime == probe.runtime)
+                    {
+                        blocked[index] += 1;
+                    }
+                }
+                for (runtime, count) in RUNTIMES.iter().zip(blocked) {
+                    inner.blocked.with_label_values(&[runtime]).set(count);
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// A probe listed for the watchdog while its future lives.
+struct Registration(Arc<Probe>, Arc<Inner>);
+
+impl Registration {
+    fn new(inner: &Arc<Inner>, runtime: &'static str) -> Self {
+        let probe = Arc::new(Probe { runtime, woke_ms: AtomicU64::new(inner.elapsed_ms()) });
+        inner.probes.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(probe.clone());
+        Self(probe, inner.clone())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut probes = self.1.probes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        probes.retain(|probe| !Arc::ptr_eq(probe, &self.0));
+    }
+}
+
+/// Stops the probe of an HTTP worker when dropped with its app.
+#[derive(Debug)]
+pub struct WorkerProbe(JoinHandle<()>);
+
+impl Drop for WorkerProbe {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
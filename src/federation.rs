@@ -0,0 +1,402 @@
+//! In-process federation of other local Prometheus endpoints.
+//!
+//! `FEDERATE_TARGETS` lists `source=url` pairs, e.g.
+//! `sidecar=http://127.0.0.1:9100/metrics`. Every `FEDERATE_INTERVAL_SECS`
+//! each target is scraped (`federation` feature) and its families are served
+//! on `/metrics` with the local ones, every series labeled
+//! `source="<source>"`. Labels of the target's own named `source` or like
+//! one of the local constant labels (`METRICS_RESOURCE_LABELS`) are renamed
+//! `exported_<name>`, as Prometheus does. A family with the name of a local
+//! one is merged into it when their types match, and dropped otherwise. A
+//! target that fails a scrape contributes nothing until it succeeds again.
+//!
+//! `federation_target_up{source}` is `1` when the last scrape succeeded,
+//! `federation_scrape_duration_seconds{source}` is how long it took and
+//! `federation_type_conflicts_total{source}` counts the families dropped
+//! for having the name of a local family of another type.
+//! Targets are read in the text exposition format (version 0.0.4); sample
+//! timestamps are dropped, and untyped samples are served as gauges since
+//! the encoder has no untyped type.
+
+use crate::registry::Registry;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Bucket, LabelPair, Metric, MetricFamily, MetricType, Quantile},
+    GaugeVec, IntCounterVec, IntGaugeVec, Opts,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// A `source=url` pair of `FEDERATE_TARGETS`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FederateTarget {
+    /// Value of the `source` label.
+    pub source: String,
+    pub url: String,
+}
+
+impl FromStr for FederateTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (source, url) = s
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("expected `source=url`, got {s:?}"))?;
+        let (source, url) = (source.trim(), url.trim());
+        if source.is_empty() {
+            return Err(format!("federate target {s:?}: empty source"));
+        }
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("federate target {s:?}: expected an http(s) URL"));
+        }
+        Ok(Self { source: source.to_string(), url: url.to_string() })
+    }
+}
+
+impl fmt::Display for FederateTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.source, self.url)
+    }
+}
+
+/// The families last scraped from each target, registered as a collector of
+/// the local registry.
+#[derive(Clone, Debug)]
+pub struct Federation {
+    desc: Desc,
+    families: Arc<RwLock<HashMap<String, Vec<MetricFamily>>>>,
+    /// `source` and the constant labels of the registry.
+    reserved: Arc<Vec<String>>,
+    /// The local registry, to look up the types of local families.
+    registry: Registry,
+    up: IntGaugeVec,
+    duration: GaugeVec,
+    conflicts: IntCounterVec,
+}
+
+impl Federation {
+    /// Registers the federated families and the scrape metrics in `registry`,
+    /// whose constant labels are `const_labels`.
+    pub fn new(registry: &Registry, const_labels: impl IntoIterator<Item = String>) -> prometheus::Result<Self> {
+        let up = IntGaugeVec::new(
+            Opts::new("federation_target_up", "Whether the last scrape of a federated target succeeded"),
+            &["source"],
+        )?;
+        let duration = GaugeVec::new(
+            Opts::new("federation_scrape_duration_seconds", "Duration of the last scrape of a federated target"),
+            &["source"],
+        )?;
+        let conflicts = IntCounterVec::new(
+            Opts::new(
+                "federation_type_conflicts_total",
+                "Federated families dropped for having the name of a local family of another type",
+            ),
+            &["source"],
+        )?;
+        let federation = Self {
+            desc: Desc::new(
+                "federation".to_string(),
+                "Families scraped from federated targets".to_string(),
+                Vec::new(),
+                HashMap::new(),
+            )?,
+            families: Arc::default(),
+            reserved: Arc::new(std::iter::once("source".to_string()).chain(const_labels).collect()),
+            registry: registry.clone(),
+            up,
+            duration,
+            conflicts,
+        };
+        registry.register(Box::new(federation.up.clone()))?;
+        registry.register(Box::new(federation.duration.clone()))?;
+        registry.register(Box::new(federation.conflicts.clone()))?;
+        registry.register(Box::new(federation.clone()))?;
+        Ok(federation)
+    }
+
+    /// Records a scrape of `source`: its body on success, `None` on failure.
+    pub fn update(&self, source: &str, body: Option<&str>, seconds: f64) {
+        let families = body.map(|body| self.with_source(parse(body), source));
+        self.up.with_label_values(&[source]).set(i64::from(families.is_some()));
+        self.duration.with_label_values(&[source]).set(seconds);
+        let mut all = self.families.write().unwrap();
+        match families {
+            Some(families) => all.insert(source.to_string(), families),
+            None => all.remove(source),
+        };
+    }
+
+    /// Adds `source` to every series, renaming reserved labels, and drops
+    /// the families clashing with a local one of another type.
+    fn with_source(&self, mut families: Vec<MetricFamily>, source: &str) -> Vec<MetricFamily> {
+        families.retain(|family| {
+            let clashes = self.registry.family_type(family.name()).is_some_and(|kind| kind != family.get_field_type());
+            if clashes {
+                self.conflicts.with_label_values(&[source]).inc();
+            }
+            !clashes
+        });
+        for family in &mut families {
+            for metric in family.mut_metric().iter_mut() {
+                let mut labels = vec![label("source", source)];
+                for mut pair in metric.take_label() {
+                    if self.reserved.iter().any(|name| name == pair.name()) {
+                        pair.set_name(format!("exported_{}", pair.name()));
+                    }
+                    labels.push(pair);
+                }
+                metric.set_label(labels);
+            }
+        }
+        families
+    }
+}
+
+impl Collector for Federation {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.families.read().unwrap().values().flatten().cloned().collect()
+    }
+}
+
+fn label(name: &str, value: &str) -> LabelPair {
+    let mut pair = LabelPair::default();
+    pair.set_name(name.to_string());
+    pair.set_value(value.to_string());
+    pair
+}
+
+/// Parses a text exposition into families, grouping the `_bucket`, `_sum`
+/// and `_count` samples of histograms and summaries. Malformed lines are
+/// skipped.
+pub fn parse(text: &str) -> Vec<MetricFamily> {
+    let mut types: HashMap<&str, MetricType> = HashMap::new();
+    let mut helps: HashMap<&str, String> = HashMap::new();
+    let mut families: Vec<MetricFamily> = Vec::new();
+    let mut family_index: HashMap<String, usize> = HashMap::new();
+    let mut series_index: HashMap<(usize, String), usize> = HashMap::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("TYPE"), Some(name), Some(kind)) => {
+                    let kind = match kind.trim() {
+                        "counter" => MetricType::COUNTER,
+                        "gauge" => MetricType::GAUGE,
+                        "histogram" => MetricType::HISTOGRAM,
+                        "summary" => MetricType::SUMMARY,
+                        _ => MetricType::GAUGE,
+                    };
+                    types.insert(name, kind);
+                }
+                (Some("HELP"), Some(name), help) => {
+                    helps.insert(name, unescape(help.unwrap_or_default()));
+                }
+                _ => {}
+            }
+            continue;
+        }
+        let Some((name, mut labels, value)) = parse_sample(line) else {
+            continue;
+        };
+
+        // The family and what the sample is to it.
+        let (family_name, kind, suffix) = match types.get(name) {
+            Some(kind) => (name, *kind, ""),
+            None => ["_bucket", "_sum", "_count"]
+                .into_iter()
+                .find_map(|suffix| {
+                    let base = name.strip_suffix(suffix)?;
+                    let kind = *types.get(base)?;
+                    matches!(kind, MetricType::HISTOGRAM | MetricType::SUMMARY).then_some((base, kind, suffix))
+                })
+                .unwrap_or((name, MetricType::GAUGE, "")),
+        };
+        let bound = match (kind, suffix) {
+            (MetricType::HISTOGRAM, "_bucket") => take_label(&mut labels, "le"),
+            (MetricType::HISTOGRAM, "") => continue,
+            (MetricType::SUMMARY, "") => take_label(&mut labels, "quantile"),
+            _ => None,
+        };
+
+        let index = *family_index.entry(family_name.to_string()).or_insert_with(|| {
+            let mut family = MetricFamily::default();
+            family.set_name(family_name.to_string());
+            family.set_help(helps.get(family_name).cloned().unwrap_or_default());
+            family.set_field_type(kind);
+            families.push(family);
+            families.len() - 1
+        });
+        let key = labels.iter().map(|(name, value)| format!("{name}={value:?}")).collect::<Vec<_>>().join(",");
+        let metrics = families[index].mut_metric();
+        let metric_index = *series_index.entry((index, key)).or_insert_with(|| {
+            let mut metric = Metric::default();
+            metric.set_label(labels.iter().map(|(name, value)| label(name, value)).collect());
+            metrics.push(metric);
+            metrics.len() - 1
+        });
+        let metric = &mut metrics[metric_index];
+
+        match (kind, suffix) {
+            (MetricType::COUNTER, _) => metric.counter.mut_or_insert_default().set_value(value),
+            (MetricType::GAUGE, _) => metric.gauge.mut_or_insert_default().set_value(value),
+            (MetricType::HISTOGRAM, "_bucket") => match bound.and_then(|le| parse_value(&le)) {
+                // Implied by the count when encoded.
+                Some(le) if le == f64::INFINITY => {}
+                Some(le) => {
+                    let mut bucket = Bucket::default();
+                    bucket.set_upper_bound(le);
+                    bucket.set_cumulative_count(value as u64);
+                    metric.histogram.mut_or_insert_default().bucket.push(bucket);
+                }
+                None => {}
+            },
+            (MetricType::HISTOGRAM, "_sum") => metric.histogram.mut_or_insert_default().set_sample_sum(value),
+            (MetricType::HISTOGRAM, _) => metric.histogram.mut_or_insert_default().set_sample_count(value as u64),
+            (MetricType::SUMMARY, "_sum") => metric.summary.mut_or_insert_default().set_sample_sum(value),
+            (MetricType::SUMMARY, "_count") => metric.summary.mut_or_insert_default().set_sample_count(value as u64),
+            (MetricType::SUMMARY, _) => {
+                if let Some(q) = bound.and_then(|q| parse_value(&q)) {
+                    let mut quantile = Quantile::default();
+                    quantile.set_quantile(q);
+                    quantile.set_value(value);
+                    metric.summary.mut_or_insert_default().quantile.push(quantile);
+                }
+            }
+            (MetricType::UNTYPED, _) => {}
+        }
+    }
+    families
+}
+
+/// Label names and values of a sample, in order.
+type Labels = Vec<(String, String)>;
+
+fn take_label(labels: &mut Labels, name: &str) -> Option<String> {
+    let position = labels.iter().position(|(label, _)| label == name)?;
+    Some(labels.remove(position).1)
+}
+
+/// `name{label="value",...} value [timestamp]`.
+fn parse_sample(line: &str) -> Option<(&str, Labels, f64)> {
+    if line.is_empty() {
+        return None;
+    }
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let (name, mut rest) = line.split_at(name_end);
+    let mut labels = Vec::new();
+    if let Some(body) = rest.strip_prefix('{') {
+        let mut chars = body.char_indices();
+        loop {
+            let (start, _) = chars.by_ref().find(|(_, c)| !c.is_whitespace() && *c != ',')?;
+            if body[start..].starts_with('}') {
+                rest = &body[start + 1..];
+                break;
+            }
+            let (eq, _) = chars.by_ref().find(|(_, c)| *c == '=')?;
+            let label_name = body[start..eq].trim().to_string();
+            if chars.next()?.1 != '"' {
+                return None;
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next()?.1 {
+                    '"' => break,
+                    '\\' => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    c => value.push(c),
+                }
+            }
+            labels.push((label_name, value));
+        }
+    }
+    let value = parse_value(rest.split_whitespace().next()?)?;
+    Some((name, labels, value))
+}
+
+fn parse_value(s: &str) -> Option<f64> {
+    match s {
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        s => s.parse().ok(),
+    }
+}
+
+/// Undoes the `\\` and `\n` escapes of `# HELP` lines.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            (c, _) => out.push(c),
+        }
+    }
+    out
+}
+
+/// Scrapes every target every `interval`, each within `interval`. Failures
+/// are logged when a target goes down, not on every scrape.
+#[cfg(feature = "federation")]
+pub async fn watch(federation: Federation, targets: Vec<FederateTarget>, interval: std::time::Duration) {
+    let client = match reqwest::Client::builder().timeout(interval).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::warn!(error = %e, "Cannot build the federation HTTP client, federation disabled");
+            return;
+        }
+    };
+    let mut up = vec![true; targets.len()];
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let scrapes = targets.iter().map(|target| async {
+            let started = std::time::Instant::now();
+            let body = scrape(&client, &target.url).await;
+            federation.update(&target.source, body.as_deref().ok(), started.elapsed().as_secs_f64());
+            body
+        });
+        let results = futures_util::future::join_all(scrapes).await;
+        for ((target, result), up) in targets.iter().zip(results).zip(&mut up) {
+            match &result {
+                Err(e) if *up => {
+                    tracing::warn!(source = %target.source, url = %target.url, error = %e, "Federated target down");
+                }
+                Ok(_) if !*up => tracing::info!(source = %target.source, "Federated target back up"),
+                _ => {}
+            }
+            *up = result.is_ok();
+        }
+    }
+}
+
+#[cfg(feature = "federation")]
+async fn scrape(client: &reqwest::Client, url: &str) -> Result<String, reqwest::Error> {
+    client
+        .get(url)
+        .header(reqwest::header::ACCEPT, "text/plain;version=0.0.4")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+}
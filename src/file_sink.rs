@@ -0,0 +1,145 @@
+//! Local log file sink with size/time based rotation.
+//!
+//! The active file keeps its configured name; on rotation it becomes
+//! `<name>.1`, older files shift up to `<name>.<max_files>` and anything
+//! beyond that is deleted. Writes reach the file through
+//! `tracing_appender::non_blocking`, so slow disks don't stall request
+//! handlers.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Time-based rotation period, aligned to UTC hour/day boundaries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    fn period_secs(self) -> Option<u64> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(60 * 60),
+            Rotation::Daily => Some(24 * 60 * 60),
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            _ => Err(format!("unknown rotation {s:?}, expected `never`, `hourly` or `daily`")),
+        }
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Never => "never",
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+        })
+    }
+}
+
+/// Rotation and retention settings for `RotatingFile`.
+#[derive(Clone, Debug)]
+pub struct FileSinkOptions {
+    pub path: PathBuf,
+    /// Rotate once the active file reaches this size; 0 disables size rotation.
+    pub max_bytes: u64,
+    pub rotation: Rotation,
+    /// Rotated files to keep next to the active one.
+    pub max_files: usize,
+}
+
+/// An `io::Write` appending to `options.path` and rotating it as configured.
+#[derive(Debug)]
+pub struct RotatingFile {
+    options: FileSinkOptions,
+    file: File,
+    written: u64,
+    period: Option<u64>,
+}
+
+impl RotatingFile {
+    pub fn open(options: FileSinkOptions) -> io::Result<Self> {
+        let file = open_append(&options.path)?;
+        let written = file.metadata()?.len();
+        let period = current_period(options.rotation);
+        Ok(Self { options, file, written, period })
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        let size_exceeded = self.options.max_bytes > 0
+            && self.written > 0
+            && self.written + incoming as u64 > self.options.max_bytes;
+        size_exceeded || current_period(self.options.rotation) != self.period
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let path = &self.options.path;
+        if self.options.max_files == 0 {
+            fs::remove_file(path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(path, self.options.max_files));
+            for n in (1..self.options.max_files).rev() {
+                let from = rotated_path(path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(path, n + 1))?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+        self.file = open_append(path)?;
+        self.written = 0;
+        self.period = current_period(self.options.rotation);
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn current_period(rotation: Rotation) -> Option<u64> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    rotation.period_secs().map(|period| secs / period)
+}
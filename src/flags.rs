@@ -0,0 +1,232 @@
+//! Telemetry toggles driven by a feature-flag service.
+//!
+//! A `FlagProvider` returns the current value of every flag; `watch` polls it
+//! and applies the flags below as they change, without a restart:
+//!
+//! - `telemetry.trace-sample-ratio`: a number between 0 and 1 replacing the
+//!   configured trace sampler (parent-based), or `null`/`false` to go back to
+//!   it;
+//! - `telemetry.debug-capture`: a debug session request as accepted by
+//!   `POST /admin/debug-session`, started whenever the value changes.
+//!
+//! Every applied change is recorded in the audit trail (see `audit`), so it
+//! shows up next to the telemetry it affects.
+//! `LaunchDarkly` (`flags` feature) reads flags from a LaunchDarkly-compatible
+//! REST endpoint.
+
+use crate::{
+    audit::{ChangeSource, ConfigChanges},
+    debug_session::{DebugSessionRequest, DebugSessions},
+};
+use futures_util::future::BoxFuture;
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{Link, SamplingResult, SpanKind, TraceId},
+    Context, KeyValue,
+};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+pub const SAMPLE_RATIO_FLAG: &str = "telemetry.trace-sample-ratio";
+pub const DEBUG_CAPTURE_FLAG: &str = "telemetry.debug-capture";
+
+/// Source of flag values, keyed by flag name.
+pub trait FlagProvider: Send + Sync + fmt::Debug {
+    fn fetch(&self) -> BoxFuture<'_, Result<HashMap<String, Value>, String>>;
+}
+
+/// Trace sampling ratio set at runtime; unset until a flag provides one.
+#[derive(Clone, Debug)]
+pub struct SampleRatio(Arc<AtomicU64>);
+
+impl Default for SampleRatio {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU64::new(f64::NAN.to_bits())))
+    }
+}
+
+impl SampleRatio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<f64> {
+        Some(f64::from_bits(self.0.load(Ordering::Relaxed))).filter(|ratio| !ratio.is_nan())
+    }
+
+    pub fn set(&self, ratio: Option<f64>) {
+        self.0.store(ratio.unwrap_or(f64::NAN).to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Samples with the ratio in `ratio`, honoring the parent's decision, while
+/// one is set; delegates to `base` otherwise.
+#[cfg(feature = "traces")]
+#[derive(Clone, Debug)]
+pub struct LiveSampler {
+    base: Box<dyn ShouldSample>,
+    ratio: SampleRatio,
+}
+
+#[cfg(feature = "traces")]
+impl LiveSampler {
+    pub fn new(base: Box<dyn ShouldSample>, ratio: SampleRatio) -> Self {
+        Self { base, ratio }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl ShouldSample for LiveSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        match self.ratio.get() {
+            Some(ratio) => Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)))
+                .should_sample(parent_context, trace_id, name, span_kind, attributes, links),
+            None => self.base.should_sample(parent_context, trace_id, name, span_kind, attributes, links),
+        }
+    }
+}
+
+/// What the flags act on.
+#[derive(Clone, Debug)]
+pub struct FlagTargets {
+    pub sample_ratio: SampleRatio,
+    pub debug_sessions: DebugSessions,
+}
+
+impl FlagTargets {
+    fn apply(&self, flag: &str, value: &Value) -> Result<(), String> {
+        match flag {
+            SAMPLE_RATIO_FLAG => {
+                let ratio = match value {
+                    Value::Null | Value::Bool(false) => None,
+                    value => Some(
+                        value
+                            .as_f64()
+                            .filter(|ratio| (0.0..=1.0).contains(ratio))
+                            .ok_or_else(|| format!("expected a number between 0 and 1, got {value}"))?,
+                    ),
+                };
+                self.sample_ratio.set(ratio);
+            }
+            DEBUG_CAPTURE_FLAG => match value {
+                Value::Null | Value::Bool(false) => {}
+                value => {
+                    let request = DebugSessionRequest::from_json(value.to_string().as_bytes())?;
+                    self.debug_sessions.start(request);
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Polls `provider` every `interval` and applies the flags that changed
+/// since the previous poll. A flag missing from the response counts as
+/// `null`. Failed polls keep the current values; applied changes are
+/// recorded in `changes`.
+pub async fn watch(provider: Arc<dyn FlagProvider>, interval: Duration, targets: FlagTargets, changes: ConfigChanges) {
+    let mut current: HashMap<&str, Value> = HashMap::new();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let flags = match provider.fetch().await {
+            Ok(flags) => flags,
+            Err(e) => {
+                warn!(error = %e, "Failed to fetch feature flags");
+                continue;
+            }
+        };
+        for flag in [SAMPLE_RATIO_FLAG, DEBUG_CAPTURE_FLAG] {
+            let new = flags.get(flag).cloned().unwrap_or(Value::Null);
+            let old = current.get(flag).unwrap_or(&Value::Null);
+            if *old == new {
+                continue;
+            }
+            match targets.apply(flag, &new) {
+                Ok(()) => changes.record(ChangeSource::Flags, flag, &old.to_string(), &new.to_string()),
+                Err(e) => warn!(flag, value = %new, error = %e, "Ignoring invalid flag value"),
+            }
+            // Remembered even when invalid, so it is reported once.
+            current.insert(flag, new);
+        }
+    }
+}
+
+/// Reads flags from a LaunchDarkly-compatible server-side polling endpoint
+/// (`GET {url}/sdk/latest-flags`, authenticated with the SDK key), such as
+/// LaunchDarkly itself or the Relay Proxy.
+///
+/// Flags are evaluated without a context: the fallthrough variation when the
+/// flag is on, the off variation otherwise. Targeting rules and percentage
+/// rollouts aren't evaluated; flags whose fallthrough is a rollout are
+/// skipped.
+#[cfg(feature = "flags")]
+#[derive(Clone, Debug)]
+pub struct LaunchDarkly {
+    client: reqwest::Client,
+    url: String,
+    sdk_key: String,
+}
+
+#[cfg(feature = "flags")]
+impl LaunchDarkly {
+    pub fn new(url: &str, sdk_key: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            sdk_key: sdk_key.to_string(),
+        }
+    }
+
+    fn evaluate(flag: &Value) -> Option<Value> {
+        let variation = if flag.get("on")?.as_bool()? {
+            flag.get("fallthrough")?.get("variation")?
+        } else {
+            flag.get("offVariation")?
+        };
+        flag.get("variations")?.get(variation.as_u64()? as usize).cloned()
+    }
+}
+
+#[cfg(feature = "flags")]
+impl FlagProvider for LaunchDarkly {
+    fn fetch(&self) -> BoxFuture<'_, Result<HashMap<String, Value>, String>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(format!("{}/sdk/latest-flags", self.url))
+                .header("Authorization", &self.sdk_key)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| e.to_string())?;
+            let body = response.bytes().await.map_err(|e| e.to_string())?;
+            let flags: HashMap<String, Value> =
+                serde_json::from_slice(&body).map_err(|e| format!("invalid flag payload: {e}"))?;
+            Ok(flags
+                .iter()
+                .filter_map(|(key, flag)| Some((key.clone(), Self::evaluate(flag)?)))
+                .collect())
+        })
+    }
+}
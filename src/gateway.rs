@@ -0,0 +1,316 @@
+//! OTLP/HTTP receiver for co-located processes (`gateway` feature).
+//!
+//! `POST /v1/traces`, `/v1/logs` and `/v1/metrics` accept protobuf-encoded
+//! export requests, add this service's resource attributes to every resource
+//! in them and forward them to the same collector our own exporters use, so
+//! a sidecar only needs to know `localhost`.
+//!
+//! Each signal is forwarded the way its exporter sends: to its endpoint,
+//! with its headers (e.g. the collector's credentials) and compression,
+//! within its export timeout, measured in `otlp_export_sent_bytes_total`
+//! and `otlp_export_request_duration_seconds`. `Config::validate` rejects
+//! the receiver next to a gRPC exporter, as it only speaks OTLP/HTTP. The
+//! collector's response, including any partial-success details, is relayed
+//! to the sender.
+//!
+//! Payloads the exporter wouldn't send are accepted and dropped: all of them
+//! with `OTEL_SDK_DISABLED`, spans and logs while the admin switches turn
+//! their export off, and, with an export circuit breaker, any while the
+//! signal's circuit is open, counted as shed. The forwarded requests count
+//! towards the breaker like exported batches, but nothing is shed from them.
+//!
+//! Attributes the sender already set win over ours, and our
+//! `telemetry.sdk.*` attributes are never added since they describe this
+//! process's SDK, not the sender's.
+
+use crate::{
+    auth::EndpointAuth,
+    circuit_breaker::{CircuitBreaker, CircuitBreakers},
+    config::Config,
+    otlp_exporter::{OtlpCompression, OtlpHeader},
+    pipeline::{PipelineStats, Signal},
+    switches::TelemetrySwitches,
+};
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use opentelemetry_proto::tonic::{
+    collector::{
+        logs::v1::ExportLogsServiceRequest, metrics::v1::ExportMetricsServiceRequest,
+        trace::v1::ExportTraceServiceRequest,
+    },
+    common::v1::KeyValue,
+    resource::v1::Resource,
+};
+use opentelemetry_sdk::Resource as SdkResource;
+use prost::Message;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const PROTOBUF: &str = "application/x-protobuf";
+/// Largest accepted export request body.
+const MAX_BODY_BYTES: usize = 8 * 1024 * 1024;
+
+/// Receiver state, registered as app data on the `/v1` scope.
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    client: reqwest::Client,
+    targets: Vec<Target>,
+    attributes: Vec<KeyValue>,
+    /// `OTEL_SDK_DISABLED`.
+    disabled: bool,
+    switches: TelemetrySwitches,
+    stats: PipelineStats,
+}
+
+/// Where and how the payloads of one signal are forwarded.
+#[derive(Clone, Debug)]
+struct Target {
+    signal: Signal,
+    url: String,
+    /// `host:port` of `url`, the `destination` of the export metrics.
+    destination: String,
+    headers: Vec<OtlpHeader>,
+    compression: OtlpCompression,
+    timeout: Duration,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl Gateway {
+    /// Forwards with the exporter settings of `config`, recording into
+    /// `stats` and honoring `switches` and `breakers` like the exporters.
+    pub fn new(
+        config: &Config,
+        stats: PipelineStats,
+        switches: TelemetrySwitches,
+        breakers: Option<CircuitBreakers>,
+    ) -> Self {
+        let resource = SdkResource::builder()
+            .with_attributes(
+                config
+                    .resource_attributes()
+                    .into_iter()
+                    .map(|(key, value)| opentelemetry::KeyValue::new(key, value)),
+            )
+            .build();
+        let attributes = resource
+            .iter()
+            .filter(|(key, _)| !key.as_str().starts_with("telemetry.sdk."))
+            .map(|(key, value)| KeyValue { key: key.to_string(), value: Some(value.clone().into()) })
+            .collect();
+        let targets = Signal::ALL
+            .into_iter()
+            .map(|signal| {
+                let settings = config.exporter(signal);
+                let url = settings.endpoint(&config.otlp_endpoint, signal);
+                let destination =
+                    reqwest::Url::parse(&url).map_or_else(|_| String::new(), |url| url.authority().to_string());
+                let timeout = match signal {
+                    Signal::Traces => config.trace_batch.export_timeout,
+                    Signal::Logs => config.log_batch.export_timeout,
+                    Signal::Metrics => config.metric_export_timeout,
+                };
+                Target {
+                    signal,
+                    url,
+                    destination,
+                    headers: settings.headers.clone(),
+                    compression: settings.compression,
+                    timeout,
+                    breaker: breakers.as_ref().map(|breakers| breakers.get(signal)),
+                }
+            })
+            .collect();
+        Self {
+            client: reqwest::Client::new(),
+            targets,
+            attributes,
+            disabled: config.telemetry_disabled,
+            switches,
+            stats,
+        }
+    }
+
+    fn target(&self, signal: Signal) -> &Target {
+        self.targets.iter().find(|target| target.signal == signal).expect("every signal has a target")
+    }
+
+    /// Whether our own exporter of `signal` currently exports.
+    fn exports(&self, signal: Signal) -> bool {
+        !self.disabled
+            && match signal {
+                Signal::Traces => self.switches.traces_export(),
+                Signal::Logs => self.switches.logs_export(),
+                Signal::Metrics => true,
+            }
+    }
+
+    /// Adds our attributes that `resource` doesn't have yet.
+    fn merge_into(&self, resource: &mut Option<Resource>) {
+        let resource = resource.get_or_insert_with(Resource::default);
+        for attribute in &self.attributes {
+            if !resource.attributes.iter().any(|existing| existing.key == attribute.key) {
+                resource.attributes.push(attribute.clone());
+            }
+        }
+    }
+
+    /// Merges the resource into a decoded request and re-encodes it, with
+    /// the number of spans, log records or metrics in it.
+    fn enrich(&self, signal: Signal, body: &[u8]) -> Result<(Vec<u8>, usize), prost::DecodeError> {
+        Ok(match signal {
+            Signal::Traces => {
+                let mut request = ExportTraceServiceRequest::decode(body)?;
+                for spans in &mut request.resource_spans {
+                    self.merge_into(&mut spans.resource);
+                }
+                let items = request.resource_spans.iter().flat_map(|r| &r.scope_spans).map(|s| s.spans.len()).sum();
+                (request.encode_to_vec(), items)
+            }
+            Signal::Logs => {
+                let mut request = ExportLogsServiceRequest::decode(body)?;
+                for logs in &mut request.resource_logs {
+                    self.merge_into(&mut logs.resource);
+                }
+                let items =
+                    request.resource_logs.iter().flat_map(|r| &r.scope_logs).map(|s| s.log_records.len()).sum();
+                (request.encode_to_vec(), items)
+            }
+            Signal::Metrics => {
+                let mut request = ExportMetricsServiceRequest::decode(body)?;
+                for metrics in &mut request.resource_metrics {
+                    self.merge_into(&mut metrics.resource);
+                }
+                let items =
+                    request.resource_metrics.iter().flat_map(|r| &r.scope_metrics).map(|s| s.metrics.len()).sum();
+                (request.encode_to_vec(), items)
+            }
+        })
+    }
+
+    /// Forwards `body`, holding `items`, through the circuit breaker of
+    /// `signal`, if any.
+    async fn forward(&self, signal: Signal, body: Vec<u8>, items: usize) -> Result<HttpResponse, ForwardError> {
+        let target = self.target(signal);
+        let Some(breaker) = &target.breaker else {
+            return self.send(target, body).await;
+        };
+        if breaker.admit().is_none() {
+            breaker.shed("circuit_open", items);
+            return Ok(accepted());
+        }
+        let result = self.send(target, body).await;
+        breaker.record(result.as_ref().is_ok_and(|response| response.status().is_success()));
+        result
+    }
+
+    async fn send(&self, target: &Target, body: Vec<u8>) -> Result<HttpResponse, ForwardError> {
+        let request =
+            self.client.post(&target.url).header(header::CONTENT_TYPE.as_str(), PROTOBUF).timeout(target.timeout);
+        let (request, body) = match target.compression.content_encoding() {
+            Some(encoding) => {
+                let body = target.compression.compress(&body).map_err(ForwardError::Compress)?;
+                (request.header(header::CONTENT_ENCODING.as_str(), encoding), body)
+            }
+            None => (request, body),
+        };
+        let bytes = body.len();
+        let started = Instant::now();
+        let response = target
+            .headers
+            .iter()
+            .fold(request, |request, header| request.header(&header.name, &header.value))
+            .body(body)
+            .send()
+            .await;
+        self.stats.record_request(target.signal, &target.destination, bytes, started.elapsed());
+        let response = response.map_err(ForwardError::Send)?;
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+        let content_type = response.headers().get(header::CONTENT_TYPE.as_str()).cloned();
+        let body = response.bytes().await.map_err(ForwardError::Send)?;
+        let mut relayed = HttpResponse::build(status);
+        if let Some(content_type) = content_type.and_then(|v| v.to_str().map(str::to_string).ok()) {
+            relayed.content_type(content_type);
+        }
+        Ok(relayed.body(body))
+    }
+}
+
+/// Why a payload couldn't be forwarded.
+#[derive(Debug)]
+enum ForwardError {
+    Compress(std::io::Error),
+    Send(reqwest::Error),
+}
+
+impl std::fmt::Display for ForwardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardError::Compress(e) => write!(f, "compressing the payload failed: {e}"),
+            ForwardError::Send(e) => write!(f, "forwarding to the collector failed: {e}"),
+        }
+    }
+}
+
+/// The answer to a payload dropped rather than forwarded: an empty export
+/// response, full success.
+fn accepted() -> HttpResponse {
+    HttpResponse::Ok().content_type(PROTOBUF).finish()
+}
+
+async fn receive(
+    signal: Signal,
+    req: HttpRequest,
+    body: web::Bytes,
+    gateway: web::Data<Gateway>,
+    auth: web::Data<EndpointAuth>,
+) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("otlp");
+    }
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if !content_type.is_some_and(|ct| ct.starts_with(PROTOBUF)) {
+        return HttpResponse::UnsupportedMediaType().body("only application/x-protobuf is supported");
+    }
+    if req.headers().get(header::CONTENT_ENCODING).is_some_and(|v| v != "identity") {
+        return HttpResponse::UnsupportedMediaType().body("compressed payloads are not supported");
+    }
+    let (enriched, items) = match gateway.enrich(signal, &body) {
+        Ok(enriched) => enriched,
+        Err(e) => return HttpResponse::BadRequest().body(format!("invalid OTLP {} payload: {e}", signal.as_str())),
+    };
+    if !gateway.exports(signal) {
+        return accepted();
+    }
+    match gateway.forward(signal, enriched, items).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!(signal = signal.as_str(), error = %e, "Failed to forward OTLP payload");
+            HttpResponse::BadGateway().body(e.to_string())
+        }
+    }
+}
+
+/// Methods and route templates of `scope`.
+pub const ROUTES: &[(&str, &str)] = &[("POST", "/v1/traces"), ("POST", "/v1/logs"), ("POST", "/v1/metrics")];
+
+/// The `/v1` receiver routes. `auth` restricts which peers may send.
+pub fn scope(gateway: web::Data<Gateway>, auth: web::Data<EndpointAuth>) -> actix_web::Scope {
+    web::scope("/v1")
+        .app_data(gateway)
+        .app_data(auth)
+        .app_data(web::PayloadConfig::new(MAX_BODY_BYTES))
+        .route(
+            "/traces",
+            web::post().to(|req, body, gateway, auth| receive(Signal::Traces, req, body, gateway, auth)),
+        )
+        .route(
+            "/logs",
+            web::post().to(|req, body, gateway, auth| receive(Signal::Logs, req, body, gateway, auth)),
+        )
+        .route(
+            "/metrics",
+            web::post().to(|req, body, gateway, auth| receive(Signal::Metrics, req, body, gateway, auth)),
+        )
+}
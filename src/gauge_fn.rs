@@ -0,0 +1,81 @@
+//! Gauges computed when the registry is gathered.
+//!
+//! Some values (queue depths, pool sizes, cache entry counts) are cheap to
+//! read but awkward to keep a gauge in sync with. A `GaugeFn` calls its
+//! callback on every gather instead, so the scraped value is never stale and
+//! no timer is needed.
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Gauge, Metric, MetricFamily, MetricType},
+};
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// A gauge whose value is the result of a callback, evaluated at gather time.
+#[derive(Clone)]
+pub struct GaugeFn {
+    desc: Desc,
+    callback: Arc<dyn Fn() -> f64 + Send + Sync>,
+}
+
+impl fmt::Debug for GaugeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GaugeFn").field("name", &self.desc.fq_name).finish_non_exhaustive()
+    }
+}
+
+impl GaugeFn {
+    pub fn new<S1, S2, F>(name: S1, help: S2, callback: F) -> prometheus::Result<Self>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        Self::with_const_labels(name, help, HashMap::new(), callback)
+    }
+
+    /// Like `new`, with constant labels, so that several callbacks can
+    /// report the same gauge, e.g. one per connection pool.
+    pub fn with_const_labels<S1, S2, F>(
+        name: S1,
+        help: S2,
+        const_labels: HashMap<String, String>,
+        callback: F,
+    ) -> prometheus::Result<Self>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        Ok(Self {
+            desc: Desc::new(name.into(), help.into(), Vec::new(), const_labels)?,
+            callback: Arc::new(callback),
+        })
+    }
+
+    /// Runs the callback.
+    pub fn get(&self) -> f64 {
+        (self.callback)()
+    }
+}
+
+impl Collector for GaugeFn {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut gauge = Gauge::default();
+        gauge.set_value(self.get());
+        let mut metric = Metric::default();
+        metric.set_label(self.desc.const_label_pairs.clone());
+        metric.set_gauge(gauge);
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(vec![metric]);
+        vec![family]
+    }
+}
@@ -0,0 +1,86 @@
+//! `grpc.health.v1.Health` service for gRPC health probes (`grpc-health`
+//! feature).
+//!
+//! Actix only speaks HTTP/2 over TLS, so the service runs on its own port
+//! (`GRPC_HEALTH_ADDR`). The overall (`""`) status and the one of
+//! `prom_otel` follow `/readyz` (see `readiness`): `SERVING` while it
+//! answers 200, `NOT_SERVING` otherwise, so until the HTTP server is bound,
+//! as soon as a stop signal arrives, for meshes to stop routing traffic
+//! while requests drain, and while an export circuit is open. Changes of
+//! the server are reported at once, the circuits are checked every second.
+
+use crate::readiness::Readiness;
+use std::{error::Error, net::SocketAddr, time::Duration};
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+use tonic::transport::{server::TcpIncoming, Server};
+use tonic_health::{server::HealthReporter, ServingStatus};
+use tracing::{info, warn};
+
+/// Service name reported besides the overall status.
+pub const SERVICE_NAME: &str = "prom_otel";
+/// How often the export circuits are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+async fn set_serving(reporter: &HealthReporter, serving: bool) {
+    let status = if serving { ServingStatus::Serving } else { ServingStatus::NotServing };
+    reporter.set_service_status("", status).await;
+    reporter.set_service_status(SERVICE_NAME, status).await;
+}
+
+/// Reports the readiness of `readiness` through `reporter` whenever it
+/// changes.
+async fn follow(reporter: HealthReporter, readiness: Readiness) {
+    let mut changes = readiness.subscribe();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    let mut serving = None;
+    loop {
+        let ready = readiness.is_ready();
+        if serving != Some(ready) {
+            set_serving(&reporter, ready).await;
+            serving = Some(ready);
+        }
+        tokio::select! {
+            changed = changes.changed() => if changed.is_err() {
+                return;
+            },
+            _ = poll.tick() => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GrpcHealth {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+    follower: JoinHandle<()>,
+}
+
+impl GrpcHealth {
+    /// Binds `addr` and starts serving the readiness of `readiness`.
+    pub async fn start(addr: SocketAddr, readiness: Readiness) -> Result<Self, Box<dyn Error + Send + Sync + 'static>> {
+        let (reporter, service) = tonic_health::server::health_reporter();
+        set_serving(&reporter, false).await;
+        let listener = TcpListener::bind(addr).await?;
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let server = Server::builder()
+            .add_service(service)
+            .serve_with_incoming_shutdown(TcpIncoming::from(listener), async {
+                let _ = stopped.await;
+            });
+            if let Err(e) = server.await {
+                warn!(error = %e, "gRPC health server failed");
+            }
+        });
+        let follower = tokio::spawn(follow(reporter, readiness));
+        info!("gRPC health service listening on {addr}");
+        Ok(Self { stop, task, follower })
+    }
+
+    /// Stops the gRPC server and waits for it to exit.
+    pub async fn stop(self) {
+        self.follower.abort();
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
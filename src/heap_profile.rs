@@ -0,0 +1,278 @@
+//! Heap profiles (`jemalloc` feature), mounted only when `ADMIN_TOKEN` is set
+//! and protected by it.
+//!
+//! - `GET /debug/pprof/heap` dumps jemalloc's sampled heap profile, for
+//!   `jeprof --svg ./prom_otel heap.prof`; two dumps compare with `--base`.
+//! - `GET /debug/allocations` summarizes the same profile as JSON: the
+//!   estimated live bytes and objects of the top allocation sites, the first
+//!   frames outside the allocator and the standard library, with `?limit=N` (default 20, at most
+//!   `MAX_SITES`) of them.
+//!
+//! The binary samples one allocation per 512 KiB on average, see `main.rs`;
+//! `_RJEM_MALLOC_CONF=prof_active:false` turns sampling off at startup.
+//! Embedders of the library set `malloc_conf` themselves, otherwise both
+//! endpoints answer `409 Conflict`.
+
+use crate::auth::EndpointAuth;
+use actix_web::{
+    dev::HttpServiceFactory,
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    ffi::{c_void, CString},
+    fs, process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tikv_jemalloc_ctl::{profiling, raw};
+use tracing::info;
+
+pub const DEFAULT_SITES: usize = 20;
+pub const MAX_SITES: usize = 500;
+/// Frames kept in the stack shown for each site.
+const STACK_DEPTH: usize = 16;
+
+/// Distinguishes the temporary files of concurrent dumps.
+static DUMPS: AtomicU64 = AtomicU64::new(0);
+
+fn error(status: StatusCode, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": message.into() }))
+}
+
+/// Whether jemalloc was started with profiling, which can't be turned on
+/// later.
+fn enabled() -> bool {
+    profiling::prof::read().unwrap_or(false)
+}
+
+/// Whether allocations are being sampled.
+fn active() -> bool {
+    // SAFETY: `prof.active` is a bool.
+    unsafe { raw::read::<bool>(b"prof.active\0") }.unwrap_or(false)
+}
+
+/// Has jemalloc write its profile to a temporary file and reads it back.
+fn dump() -> Result<Vec<u8>, String> {
+    let path = std::env::temp_dir().join(format!(
+        "prom_otel-{}-{}.heap",
+        process::id(),
+        DUMPS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let name = CString::new(path.to_string_lossy().into_owned()).map_err(|e| e.to_string())?;
+    // SAFETY: `prof.dump` takes a NUL-terminated path, which outlives the call.
+    unsafe { raw::write(b"prof.dump\0", name.as_ptr()) }.map_err(|e| format!("prof.dump: {e}"))?;
+    let profile = fs::read(&path).map_err(|e| format!("reading {}: {e}", path.display()));
+    let _ = fs::remove_file(&path);
+    profile
+}
+
+/// One sampled stack of a `heap_v2` profile.
+#[derive(Debug)]
+struct Stack {
+    addresses: Vec<usize>,
+    objects: f64,
+    bytes: f64,
+}
+
+/// The sampling period and stacks of a `heap_v2` profile, with their counts
+/// unbiased like `jeprof` does: a sample of `bytes / objects` per object
+/// stands for `1 / (1 - e^(-size / period))` of them.
+fn parse(profile: &str) -> Result<(u64, Vec<Stack>), String> {
+    let mut lines = profile.lines();
+    let period: u64 = lines
+        .next()
+        .and_then(|header| header.strip_prefix("heap_v2/"))
+        .and_then(|period| period.trim().parse().ok())
+        .ok_or("not a heap_v2 profile")?;
+    let mut stacks = Vec::new();
+    let mut addresses: Option<Vec<usize>> = None;
+    for line in lines {
+        if line.starts_with("MAPPED_LIBRARIES:") {
+            break;
+        }
+        if let Some(frames) = line.strip_prefix("@ ") {
+            addresses = Some(
+                frames
+                    .split_whitespace()
+                    .filter_map(|frame| usize::from_str_radix(frame.trim_start_matches("0x"), 16).ok())
+                    .collect(),
+            );
+            continue;
+        }
+        // `t*: objects: bytes [...]` totals the threads of the stack above.
+        let (Some(totals), Some(addresses)) = (line.trim_start().strip_prefix("t*:"), addresses.take()) else {
+            continue;
+        };
+        let mut fields = totals.split(':').map(|field| field.split_whitespace().next().unwrap_or(""));
+        let (Some(Ok(objects)), Some(Ok(bytes))) = (fields.next().map(str::parse::<f64>), fields.next().map(str::parse::<f64>))
+        else {
+            continue;
+        };
+        if objects == 0.0 || bytes == 0.0 {
+            continue;
+        }
+        let scale = 1.0 / (1.0 - (-(bytes / objects) / period as f64).exp());
+        stacks.push(Stack { addresses, objects: objects * scale, bytes: bytes * scale });
+    }
+    Ok((period, stacks))
+}
+
+/// Function names by address; one address may appear in many stacks.
+#[derive(Default)]
+struct Symbols(HashMap<usize, String>);
+
+impl Symbols {
+    fn name(&mut self, address: usize) -> &str {
+        self.0.entry(address).or_insert_with(|| {
+            let mut name = None;
+            // Return addresses point past the call; look up the call itself.
+            backtrace::resolve(address.saturating_sub(1) as *mut c_void, |symbol| {
+                if name.is_none() {
+                    name = symbol.name().map(|n| format!("{n:#}"));
+                }
+            });
+            name.unwrap_or_else(|| format!("{address:#x}"))
+        })
+    }
+}
+
+/// Frames of jemalloc and of the standard library, above the code that
+/// allocated: a site is where a `Vec` grew, not `RawVec::grow_one`.
+fn is_allocator(name: &str) -> bool {
+    const PREFIXES: &[&str] = &["_rjem_", "je_", "prof_", "imalloc", "__rustc::", "__rust_", "__rdl_", "alloc::", "core::", "std::", "hashbrown::raw::"];
+    let name = name.trim_start_matches('<');
+    PREFIXES.iter().any(|prefix| name.starts_with(prefix)) || name.contains("jemalloc")
+}
+
+#[derive(Default)]
+struct Site {
+    bytes: f64,
+    objects: f64,
+    /// The site's heaviest stack and its bytes.
+    stack: (f64, Vec<String>),
+}
+
+/// The `limit` sites holding the most bytes, as JSON.
+fn summarize(period: u64, stacks: &[Stack], limit: usize) -> Value {
+    let mut symbols = Symbols::default();
+    let mut sites: HashMap<String, Site> = HashMap::new();
+    for stack in stacks {
+        let frames: Vec<String> = stack
+            .addresses
+            .iter()
+            .map(|&address| symbols.name(address).to_string())
+            .skip_while(|name| is_allocator(name))
+            .collect();
+        let name = frames.first().cloned().unwrap_or_else(|| "unknown".to_string());
+        let site = sites.entry(name).or_default();
+        site.bytes += stack.bytes;
+        site.objects += stack.objects;
+        if stack.bytes > site.stack.0 {
+            site.stack = (stack.bytes, frames.into_iter().take(STACK_DEPTH).collect());
+        }
+    }
+    let total_bytes: f64 = sites.values().map(|site| site.bytes).sum();
+    let total_objects: f64 = sites.values().map(|site| site.objects).sum();
+    let mut sites: Vec<(String, Site)> = sites.into_iter().collect();
+    sites.sort_by(|a, b| b.1.bytes.total_cmp(&a.1.bytes));
+    let top: Vec<Value> = sites
+        .into_iter()
+        .take(limit)
+        .map(|(name, site)| {
+            json!({
+                "site": name,
+                "bytes": site.bytes.round() as u64,
+                "objects": site.objects.round() as u64,
+                "share": if total_bytes > 0.0 { site.bytes / total_bytes } else { 0.0 },
+                "stack": site.stack.1,
+            })
+        })
+        .collect();
+    json!({
+        "sample_period_bytes": period,
+        "active": active(),
+        "bytes": total_bytes.round() as u64,
+        "objects": total_objects.round() as u64,
+        "sites": top,
+    })
+}
+
+fn parse_limit(query: &str) -> Result<usize, String> {
+    let mut limit = DEFAULT_SITES;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        if key == "limit" {
+            limit = value
+                .parse()
+                .ok()
+                .filter(|limit| (1..=MAX_SITES).contains(limit))
+                .ok_or_else(|| format!("limit must be between 1 and {MAX_SITES}, got {value:?}"))?;
+        }
+    }
+    Ok(limit)
+}
+
+/// `GET /debug/pprof/heap`: the current heap profile.
+async fn heap(req: HttpRequest, auth: web::Data<EndpointAuth>) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    if !enabled() {
+        return error(StatusCode::CONFLICT, "jemalloc was started without profiling (`prof:true`)");
+    }
+    match web::block(dump).await {
+        Ok(Ok(profile)) => {
+            info!(bytes = profile.len(), "heap profile dumped");
+            HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"heap.prof\""))
+                .body(profile)
+        }
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// `GET /debug/allocations`: the top allocation sites of a heap profile.
+async fn allocations(req: HttpRequest, auth: web::Data<EndpointAuth>) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    let limit = match parse_limit(req.query_string()) {
+        Ok(limit) => limit,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    if !enabled() {
+        return error(StatusCode::CONFLICT, "jemalloc was started without profiling (`prof:true`)");
+    }
+    // Symbolizing parses the binary's debug info, tens of megabytes that are
+    // dropped afterwards rather than showing up in the next summary; keep it
+    // off the workers.
+    let summary = web::block(move || {
+        let profile = dump()?;
+        let (period, stacks) = parse(&String::from_utf8_lossy(&profile))?;
+        let summary = summarize(period, &stacks, limit);
+        backtrace::clear_symbol_cache();
+        Ok::<_, String>(summary)
+    })
+    .await;
+    match summary {
+        Ok(Ok(summary)) => HttpResponse::Ok().json(summary),
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Methods and route templates of `resources`.
+pub const ROUTES: &[(&str, &str)] = &[("GET", "/debug/pprof/heap"), ("GET", "/debug/allocations")];
+
+/// The heap profile resources, guarded by the admin credentials in `auth`.
+/// Resources rather than a scope, so they can sit next to the `pprof`
+/// feature's `/debug/pprof` scope when registered before it.
+pub fn resources(auth: web::Data<EndpointAuth>) -> impl HttpServiceFactory {
+    (
+        web::resource("/debug/pprof/heap").app_data(auth.clone()).route(web::get().to(heap)),
+        web::resource("/debug/allocations").app_data(auth).route(web::get().to(allocations)),
+    )
+}
@@ -0,0 +1,398 @@
+//! Instrumentation of servers running next to the Actix one.
+//!
+//! `track_requests` covers the Actix routes. A tonic gRPC server or an
+//! async-graphql endpoint in the same process records its operations
+//! through an `Instrumentation` instead, into the same `AppMetrics` registry
+//! and tracer:
+//!
+//! ```ignore
+//! let grpc = ServerInstrumentation::new(&metrics, "grpc");
+//!
+//! let mut operation = grpc.start("users.Users/GetUser", "unary");
+//! grpc.record_payload(&operation, Direction::Request, request.get_ref().encoded_len() as u64);
+//! let result = users.get_user(request).with_context(operation.context()).await;
+//! let status = match &result {
+//!     Ok(response) => {
+//!         grpc.record_payload(&operation, Direction::Response, response.get_ref().encoded_len() as u64);
+//!         "Ok".to_string()
+//!     }
+//!     Err(status) => {
+//!         grpc.record_error(&mut operation, &format!("{:?}", status.code()), status.message());
+//!         format!("{:?}", status.code())
+//!     }
+//! };
+//! grpc.end(operation, &status);
+//! ```
+//!
+//! An operation has a low-cardinality `name` (a route template, a gRPC
+//! method, a GraphQL operation name) and a `kind` (an HTTP method, `unary`
+//! or `streaming`, `query` or `mutation`). With the `traces` feature,
+//! `start` opens a server span `<kind> <name>` as a child of the current
+//! context, so extract the caller's context and attach it first.
+//! `record_error` adds `error.type` and an `exception` event to it, and
+//! `end` marks it failed.
+//!
+//! `ServerInstrumentation` records every protocol in the same families,
+//! told apart by their `protocol` label:
+//!
+//! - `server_operation_duration_seconds{protocol,operation,kind}`;
+//! - `server_operations_total{protocol,operation,kind,status}`, `status`
+//!   being whatever `end` is given, e.g. the gRPC status code;
+//! - `server_operation_payload_bytes{protocol,operation,direction}`;
+//! - `server_operation_errors_total{protocol,operation,error_type}`.
+//!
+//! `HttpInstrumentation` records into the `http_*` families of
+//! `track_requests`, for HTTP servers other than Actix. `track_requests`
+//! isn't built on it: it's a separate implementation that resolves the
+//! handles of the app's routes at startup, counts bodies as they stream and
+//! traces through the Actix request. Both record the per-route families
+//! through the same functions: `http_request_duration_seconds`,
+//! `http_responses_total`, `http_request_size_bytes`,
+//! `http_response_size_bytes`, `http_server_errors_total`, the SLI, apdex
+//! and SLO families and the OpenTelemetry request duration histogram. Only
+//! the client and baggage labels, which need the request, and
+//! `errors_total`, which needs an `actix_web::Error`, are Actix-only.
+
+use crate::{
+    cardinality::CardinalityLimiter,
+    metrics::AppMetrics,
+    middleware::RequestTracking,
+    registry::Registry,
+    responses::status_class,
+};
+use opentelemetry::KeyValue;
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context,
+};
+use prometheus::{exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Whether a payload was received or sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Request => "request",
+            Direction::Response => "response",
+        }
+    }
+}
+
+/// An operation between `Instrumentation::start` and `end`.
+#[derive(Debug)]
+pub struct Operation {
+    pub name: String,
+    pub kind: String,
+    started: Instant,
+    error_type: Option<String>,
+    /// Holds the server span.
+    #[cfg(feature = "traces")]
+    context: Context,
+}
+
+impl Operation {
+    /// Starts `kind` `name`, in a server span `span_name` with `attributes`
+    /// as a child of the current context with the `traces` feature.
+    pub fn start(name: &str, kind: &str, span_name: String, attributes: Vec<KeyValue>) -> Self {
+        #[cfg(feature = "traces")]
+        let context = {
+            let tracer = global::tracer("prom_otel");
+            let span = tracer
+                .span_builder(span_name)
+                .with_kind(SpanKind::Server)
+                .with_attributes(attributes)
+                .start_with_context(&tracer, &Context::current());
+            Context::current().with_span(span)
+        };
+        #[cfg(not(feature = "traces"))]
+        let _ = (span_name, attributes);
+        Self {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            started: Instant::now(),
+            error_type: None,
+            #[cfg(feature = "traces")]
+            context,
+        }
+    }
+
+    /// The context holding the server span, to run the operation in.
+    #[cfg(feature = "traces")]
+    pub fn context(&self) -> Context {
+        self.context.clone()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The `error.type` of the last `fail`, if any.
+    pub fn error_type(&self) -> Option<&str> {
+        self.error_type.as_deref()
+    }
+
+    /// Sets `attribute` on the server span.
+    pub fn set_attribute(&self, attribute: KeyValue) {
+        #[cfg(feature = "traces")]
+        self.context.span().set_attribute(attribute);
+        #[cfg(not(feature = "traces"))]
+        let _ = attribute;
+    }
+
+    /// Records an error of type `error_type` on the server span.
+    pub fn fail(&mut self, error_type: &str, message: &str) {
+        #[cfg(feature = "traces")]
+        {
+            let span = self.context.span();
+            span.set_attribute(KeyValue::new("error.type", error_type.to_string()));
+            span.add_event(
+                "exception",
+                vec![
+                    KeyValue::new("exception.type", error_type.to_string()),
+                    KeyValue::new("exception.message", message.to_string()),
+                ],
+            );
+        }
+        #[cfg(not(feature = "traces"))]
+        let _ = message;
+        self.error_type = Some(error_type.to_string());
+    }
+
+    /// Ends the server span, with the `Error` status if `failed`.
+    pub fn finish(self, failed: bool) {
+        #[cfg(feature = "traces")]
+        {
+            let span = self.context.span();
+            if failed {
+                span.set_status(Status::error(self.error_type.unwrap_or_default()));
+            }
+            span.end();
+        }
+        #[cfg(not(feature = "traces"))]
+        let _ = failed;
+    }
+}
+
+/// Hooks a server records its operations through; see the module
+/// documentation.
+pub trait Instrumentation: Send + Sync {
+    /// `http`, `grpc`, `graphql`, ...
+    fn protocol(&self) -> &'static str;
+
+    /// Starts an operation; the span is named `<kind> <name>`.
+    fn start(&self, name: &str, kind: &str) -> Operation {
+        let attributes = vec![KeyValue::new("network.protocol.name", self.protocol())];
+        Operation::start(name, kind, format!("{kind} {name}"), attributes)
+    }
+
+    /// Records a payload of `bytes` received or sent by `operation`.
+    fn record_payload(&self, operation: &Operation, direction: Direction, bytes: u64);
+
+    /// Records that `operation` failed with an error of type `error_type`.
+    fn record_error(&self, operation: &mut Operation, error_type: &str, message: &str) {
+        operation.fail(error_type, message);
+    }
+
+    /// Ends `operation` with `status`.
+    fn end(&self, operation: Operation, status: &str);
+}
+
+/// The `server_operation_*` families, shared by every protocol.
+#[derive(Clone, Debug)]
+pub struct OperationMetrics {
+    duration: HistogramVec,
+    operations: IntCounterVec,
+    payloads: HistogramVec,
+    errors: IntCounterVec,
+}
+
+impl OperationMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let duration = HistogramVec::new(
+            HistogramOpts::new("server_operation_duration_seconds", "Latency of operations of non-Actix servers"),
+            &["protocol", "operation", "kind"],
+        )?;
+        let operations = IntCounterVec::new(
+            Opts::new("server_operations_total", "Operations of non-Actix servers by status"),
+            &["protocol", "operation", "kind", "status"],
+        )?;
+        // 64 B to 16 MiB, like the HTTP body sizes.
+        let payloads = HistogramVec::new(
+            HistogramOpts::new("server_operation_payload_bytes", "Payload sizes of operations of non-Actix servers")
+                .buckets(exponential_buckets(64.0, 4.0, 10)?),
+            &["protocol", "operation", "direction"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("server_operation_errors_total", "Errors of operations of non-Actix servers by type"),
+            &["protocol", "operation", "error_type"],
+        )?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(operations.clone()))?;
+        registry.register(Box::new(payloads.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        Ok(Self { duration, operations, payloads, errors })
+    }
+}
+
+/// Instrumentation recording into the `server_operation_*` families.
+#[derive(Clone, Debug)]
+pub struct ServerInstrumentation {
+    protocol: &'static str,
+    metrics: OperationMetrics,
+    cardinality: CardinalityLimiter,
+}
+
+impl ServerInstrumentation {
+    pub fn new(metrics: &AppMetrics, protocol: &'static str) -> Self {
+        Self { protocol, metrics: metrics.operations.clone(), cardinality: metrics.cardinality.clone() }
+    }
+}
+
+impl Instrumentation for ServerInstrumentation {
+    fn protocol(&self) -> &'static str {
+        self.protocol
+    }
+
+    fn record_payload(&self, operation: &Operation, direction: Direction, bytes: u64) {
+        let labels = self
+            .cardinality
+            .limit("server_operation_payload_bytes", [self.protocol, &operation.name, direction.as_str()]);
+        self.metrics.payloads.with_label_values(&labels).observe(bytes as f64);
+    }
+
+    fn record_error(&self, operation: &mut Operation, error_type: &str, message: &str) {
+        let labels = self.cardinality.limit("server_operation_errors_total", [self.protocol, &operation.name, error_type]);
+        self.metrics.errors.with_label_values(&labels).inc();
+        operation.fail(error_type, message);
+    }
+
+    fn end(&self, operation: Operation, status: &str) {
+        let labels = self
+            .cardinality
+            .limit("server_operation_duration_seconds", [self.protocol, &operation.name, &operation.kind]);
+        self.metrics.duration.with_label_values(&labels).observe(operation.elapsed().as_secs_f64());
+        let labels = self
+            .cardinality
+            .limit("server_operations_total", [self.protocol, &operation.name, &operation.kind, status]);
+        self.metrics.operations.with_label_values(&labels).inc();
+        let failed = operation.error_type.is_some();
+        operation.finish(failed);
+    }
+}
+
+/// Instrumentation recording into the `http_*` families of
+/// `track_requests`: `name` is the route template, `kind` the method and
+/// the status the response status code. See the module documentation for
+/// what it doesn't record.
+#[derive(Clone, Debug)]
+pub struct HttpInstrumentation {
+    metrics: Arc<AppMetrics>,
+    tracking: RequestTracking,
+}
+
+impl HttpInstrumentation {
+    pub fn new(metrics: Arc<AppMetrics>, tracking: RequestTracking) -> Self {
+        Self { metrics, tracking }
+    }
+
+    /// Records a request to `method` `route` answered with `status` after
+    /// `elapsed`.
+    pub(crate) fn observe(
+        metrics: &AppMetrics,
+        tracking: &RequestTracking,
+        method: &str,
+        route: &str,
+        status: u16,
+        elapsed: Duration,
+    ) {
+        let labels = metrics.cardinality.limit("http_request_duration_seconds", [method, route]);
+        metrics.http_request_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+        let labels = metrics.cardinality.limit("http_responses_total", [method, route, status_class(status)]);
+        metrics.responses.observe(labels);
+        let [sli_route] = metrics.cardinality.limit("sli_requests_total", [route]);
+        metrics.sli.observe(&tracking.sli, sli_route, status, elapsed);
+        let failed = tracking.sli.bad_statuses.iter().any(|m| m.matches(status));
+        let [apdex_route] = metrics.cardinality.limit("apdex_total", [route]);
+        metrics.apdex.observe(&tracking.apdex, apdex_route, elapsed, failed);
+    }
+
+    /// Records what a request to `method` `route` counts beyond its route's
+    /// families, with or without resolved handles: the OpenTelemetry
+    /// duration histogram and the SLOs.
+    pub(crate) fn observe_outcome(
+        metrics: &AppMetrics,
+        tracking: &RequestTracking,
+        method: &str,
+        route: &str,
+        status: u16,
+        elapsed: Duration,
+    ) {
+        #[cfg(feature = "metrics")]
+        if let Some(histogram) = &tracking.request_duration {
+            histogram.record(
+                elapsed.as_secs_f64(),
+                &[KeyValue::new("http.request.method", method.to_string()), KeyValue::new("http.route", route.to_string())],
+            );
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = method;
+        if let Some(slos) = &metrics.slos {
+            slos.observe(route, status, elapsed, &tracking.sli.bad_statuses);
+        }
+    }
+}
+
+impl Instrumentation for HttpInstrumentation {
+    fn protocol(&self) -> &'static str {
+        "http"
+    }
+
+    fn start(&self, name: &str, kind: &str) -> Operation {
+        let attributes = vec![
+            KeyValue::new("http.request.method", kind.to_string()),
+            KeyValue::new("http.route", name.to_string()),
+        ];
+        Operation::start(name, kind, format!("{kind} {name}"), attributes)
+    }
+
+    fn record_payload(&self, operation: &Operation, direction: Direction, bytes: u64) {
+        let labels = match direction {
+            Direction::Request => {
+                self.metrics.cardinality.limit("http_request_size_bytes", [operation.kind.as_str(), &operation.name])
+            }
+            Direction::Response => {
+                self.metrics.cardinality.limit("http_response_size_bytes", [operation.kind.as_str(), &operation.name])
+            }
+        };
+        let histogram = match direction {
+            Direction::Request => self.metrics.body_sizes.request_histogram(&labels),
+            Direction::Response => self.metrics.body_sizes.response_histogram(&labels),
+        };
+        histogram.observe(bytes as f64);
+    }
+
+    /// `status` is the response status code; anything else counts as 500.
+    fn end(&self, operation: Operation, status: &str) {
+        let status = status.parse().unwrap_or(500);
+        let elapsed = operation.elapsed();
+        Self::observe(&self.metrics, &self.tracking, &operation.kind, &operation.name, status, elapsed);
+        Self::observe_outcome(&self.metrics, &self.tracking, &operation.kind, &operation.name, status, elapsed);
+        // Actix counts these in `responses::count_server_error`.
+        if status >= 500 {
+            self.metrics.responses.server_error();
+        }
+        operation.set_attribute(KeyValue::new("http.response.status_code", i64::from(status)));
+        operation.finish(status >= 500);
+    }
+}
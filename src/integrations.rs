@@ -0,0 +1,8 @@
+//! Instrumentation of third-party libraries, each behind its own feature.
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "sqlx")]
+pub mod sqlx;
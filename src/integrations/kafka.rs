@@ -0,0 +1,298 @@
+//! Kafka producers and consumers of rdkafka (`kafka` feature).
+//!
+//! `InstrumentedProducer` wraps a `FutureProducer`. With the `traces`
+//! feature, each `send` runs in a producer span (`send <topic>`) whose
+//! context, and the current baggage, travel in the message headers
+//! (`traceparent`, `tracestate`, `baggage`). On the consuming side, `process`
+//! runs the handling of a message in a consumer span (`process <topic>`)
+//! continuing that trace:
+//!
+//! ```ignore
+//! let producer = InstrumentedProducer::new(&metrics, ClientConfig::new().set(...).create()?);
+//! producer.send(FutureRecord::to("orders").key(&id).payload(&body), Duration::from_secs(5)).await?;
+//!
+//! let consumer: StreamConsumer<KafkaContext> = ClientConfig::new()
+//!     .set("statistics.interval.ms", "10000")
+//!     .create_with_context(KafkaContext::new(&metrics))?;
+//! let message = consumer.recv().await?;
+//! kafka::process(&metrics, &message, handle(&message)).await?;
+//! ```
+//!
+//! Spans carry `messaging.system`, `messaging.operation.type`,
+//! `messaging.destination.name`, `messaging.destination.partition.id` and
+//! `messaging.kafka.offset`. The metrics are
+//!
+//! - `kafka_produced_messages_total{topic}` and `kafka_produced_bytes_total{topic}`
+//!   for delivered messages, `kafka_delivery_errors_total{topic}` for the others;
+//! - `kafka_consumed_messages_total{topic}` and `kafka_consumed_bytes_total{topic}`
+//!   for messages passed to `process`;
+//! - `kafka_consumer_lag{topic,partition}`, the messages between the
+//!   consumer's position and the end of each assigned partition, updated from
+//!   librdkafka's statistics when the client is created with `KafkaContext`
+//!   and `statistics.interval.ms` is set.
+//!
+//! Byte counts are payload sizes, keys and headers excluded.
+
+use crate::{metrics::AppMetrics, registry::Registry};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector, TextMapCompositePropagator, TextMapPropagator},
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::propagation::{BaggagePropagator, TraceContextPropagator};
+use prometheus::{IntCounterVec, IntGaugeVec, Opts};
+#[cfg(feature = "traces")]
+use rdkafka::message::{Header, Headers, OwnedHeaders};
+use rdkafka::{
+    client::ClientContext,
+    consumer::ConsumerContext,
+    message::{Message, ToBytes},
+    producer::{future_producer::OwnedDeliveryResult, FutureProducer, FutureRecord},
+    statistics::Statistics,
+    util::Timeout,
+};
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    sync::Mutex,
+};
+
+/// The Kafka metrics, shared by all clients.
+#[derive(Clone, Debug)]
+pub struct KafkaMetrics {
+    pub produced_messages: IntCounterVec,
+    pub produced_bytes: IntCounterVec,
+    pub delivery_errors: IntCounterVec,
+    pub consumed_messages: IntCounterVec,
+    pub consumed_bytes: IntCounterVec,
+    pub consumer_lag: IntGaugeVec,
+}
+
+impl KafkaMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let counter = |name: &str, help: &str| IntCounterVec::new(Opts::new(name, help), &["topic"]);
+        let produced_messages = counter("kafka_produced_messages_total", "Messages delivered to Kafka")?;
+        let produced_bytes = counter("kafka_produced_bytes_total", "Payload bytes delivered to Kafka")?;
+        let delivery_errors = counter("kafka_delivery_errors_total", "Messages Kafka failed to deliver")?;
+        let consumed_messages = counter("kafka_consumed_messages_total", "Messages consumed from Kafka")?;
+        let consumed_bytes = counter("kafka_consumed_bytes_total", "Payload bytes consumed from Kafka")?;
+        let consumer_lag = IntGaugeVec::new(
+            Opts::new("kafka_consumer_lag", "Messages between the consumer position and the end of the partition"),
+            &["topic", "partition"],
+        )?;
+        registry.register(Box::new(produced_messages.clone()))?;
+        registry.register(Box::new(produced_bytes.clone()))?;
+        registry.register(Box::new(delivery_errors.clone()))?;
+        registry.register(Box::new(consumed_messages.clone()))?;
+        registry.register(Box::new(consumed_bytes.clone()))?;
+        registry.register(Box::new(consumer_lag.clone()))?;
+        Ok(Self { produced_messages, produced_bytes, delivery_errors, consumed_messages, consumed_bytes, consumer_lag })
+    }
+}
+
+/// Client context updating `kafka_consumer_lag` from librdkafka's
+/// statistics. Partitions no longer assigned drop out of the gauge.
+pub struct KafkaContext {
+    metrics: KafkaMetrics,
+    partitions: Mutex<HashSet<(String, i32)>>,
+}
+
+impl KafkaContext {
+    pub fn new(metrics: &AppMetrics) -> Self {
+        Self { metrics: metrics.kafka.clone(), partitions: Mutex::new(HashSet::new()) }
+    }
+}
+
+impl ClientContext for KafkaContext {
+    fn stats(&self, statistics: Statistics) {
+        let mut current = HashSet::new();
+        for (topic, stats) in &statistics.topics {
+            // Partition -1 is librdkafka's internal unassigned partition; a
+            // lag of -1 means it is unknown, e.g. not consumed by this client.
+            for (&partition, stats) in stats.partitions.iter().filter(|(p, s)| **p >= 0 && s.consumer_lag >= 0) {
+                self.metrics.consumer_lag.with_label_values(&[topic, &partition.to_string()]).set(stats.consumer_lag);
+                current.insert((topic.clone(), partition));
+            }
+        }
+        let mut partitions = self.partitions.lock().unwrap();
+        for (topic, partition) in partitions.difference(&current) {
+            let _ = self.metrics.consumer_lag.remove_label_values(&[topic, &partition.to_string()]);
+        }
+        *partitions = current;
+    }
+}
+
+impl ConsumerContext for KafkaContext {}
+
+/// A `FutureProducer` with producer spans, trace propagation and metrics.
+pub struct InstrumentedProducer<C: ClientContext + 'static = rdkafka::client::DefaultClientContext> {
+    producer: FutureProducer<C>,
+    metrics: KafkaMetrics,
+}
+
+impl<C: ClientContext + 'static> InstrumentedProducer<C> {
+    pub fn new(metrics: &AppMetrics, producer: FutureProducer<C>) -> Self {
+        Self { producer, metrics: metrics.kafka.clone() }
+    }
+
+    pub fn producer(&self) -> &FutureProducer<C> {
+        &self.producer
+    }
+
+    /// Sends `record` like `FutureProducer::send`, in a producer span whose
+    /// context is added to the record's headers.
+    pub async fn send<K, P>(&self, record: FutureRecord<'_, K, P>, queue_timeout: impl Into<Timeout>) -> OwnedDeliveryResult
+    where
+        K: ToBytes + ?Sized,
+        P: ToBytes + ?Sized,
+    {
+        let topic = record.topic.to_string();
+        let bytes = record.payload.map_or(0, |payload| payload.to_bytes().len()) as u64;
+
+        #[cfg(feature = "traces")]
+        let mut record = record;
+        #[cfg(feature = "traces")]
+        let cx = {
+            let tracer = global::tracer("prom_otel");
+            let mut attributes = vec![
+                KeyValue::new("messaging.system", "kafka"),
+                KeyValue::new("messaging.operation.type", "send"),
+                KeyValue::new("messaging.destination.name", topic.clone()),
+            ];
+            if let Some(partition) = record.partition {
+                attributes.push(KeyValue::new("messaging.destination.partition.id", partition.to_string()));
+            }
+            let span = tracer
+                .span_builder(format!("send {topic}"))
+                .with_kind(SpanKind::Producer)
+                .with_attributes(attributes)
+                .start(&tracer);
+            let cx = Context::current_with_span(span);
+            record.headers = Some(inject(&cx, record.headers.take().unwrap_or_default()));
+            cx
+        };
+        let send = self.producer.send(record, queue_timeout);
+        #[cfg(feature = "traces")]
+        let result = send.with_context(cx.clone()).await;
+        #[cfg(not(feature = "traces"))]
+        let result = send.await;
+
+        match &result {
+            Ok(_) => {
+                self.metrics.produced_messages.with_label_values(&[&topic]).inc();
+                self.metrics.produced_bytes.with_label_values(&[&topic]).inc_by(bytes);
+            }
+            Err(_) => self.metrics.delivery_errors.with_label_values(&[&topic]).inc(),
+        }
+        #[cfg(feature = "traces")]
+        {
+            let span = cx.span();
+            match &result {
+                Ok(delivery) => {
+                    span.set_attribute(KeyValue::new(
+                        "messaging.destination.partition.id",
+                        delivery.partition.to_string(),
+                    ));
+                    span.set_attribute(KeyValue::new("messaging.kafka.offset", delivery.offset));
+                }
+                Err((e, _)) => span.set_status(Status::error(e.to_string())),
+            }
+            span.end();
+        }
+        result
+    }
+}
+
+/// Runs `handler`, the processing of `message`, in a consumer span that
+/// continues the trace found in the message headers, and counts the message.
+/// An `Err` marks the span failed.
+pub async fn process<M, F, T, E>(metrics: &AppMetrics, message: &M, handler: F) -> Result<T, E>
+where
+    M: Message,
+    F: Future<Output = Result<T, E>>,
+    E: fmt::Display,
+{
+    let topic = message.topic();
+    metrics.kafka.consumed_messages.with_label_values(&[topic]).inc();
+    metrics.kafka.consumed_bytes.with_label_values(&[topic]).inc_by(message.payload().map_or(0, <[u8]>::len) as u64);
+
+    #[cfg(feature = "traces")]
+    {
+        let parent = message.headers().map(extract).unwrap_or_default();
+        let tracer = global::tracer("prom_otel");
+        let span = tracer
+            .span_builder(format!("process {topic}"))
+            .with_kind(SpanKind::Consumer)
+            .with_attributes([
+                KeyValue::new("messaging.system", "kafka"),
+                KeyValue::new("messaging.operation.type", "process"),
+                KeyValue::new("messaging.destination.name", topic.to_string()),
+                KeyValue::new("messaging.destination.partition.id", message.partition().to_string()),
+                KeyValue::new("messaging.kafka.offset", message.offset()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        let result = handler.with_context(cx.clone()).await;
+
+        let span = cx.span();
+        if let Err(e) = &result {
+            span.set_status(Status::error(e.to_string()));
+        }
+        span.end();
+        result
+    }
+    #[cfg(not(feature = "traces"))]
+    handler.await
+}
+
+#[cfg(feature = "traces")]
+fn propagator() -> TextMapCompositePropagator {
+    TextMapCompositePropagator::new(vec![Box::new(TraceContextPropagator::new()), Box::new(BaggagePropagator::new())])
+}
+
+/// Adds the trace context and baggage of `cx` to `headers`.
+#[cfg(feature = "traces")]
+pub fn inject(cx: &Context, headers: OwnedHeaders) -> OwnedHeaders {
+    let mut injector = HeaderInjector(Some(headers));
+    propagator().inject_context(cx, &mut injector);
+    injector.0.unwrap_or_default()
+}
+
+/// The context carried by message headers, on top of the current one.
+#[cfg(feature = "traces")]
+pub fn extract<H: Headers>(headers: &H) -> Context {
+    propagator().extract(&HeaderExtractor(headers))
+}
+
+#[cfg(feature = "traces")]
+struct HeaderInjector(Option<OwnedHeaders>);
+
+#[cfg(feature = "traces")]
+impl Injector for HeaderInjector {
+    fn set(&mut self, key: &str, value: String) {
+        // An empty `tracestate` carries nothing.
+        if value.is_empty() {
+            return;
+        }
+        self.0 = self.0.take().map(|headers| headers.insert(Header { key, value: Some(&value) }));
+    }
+}
+
+#[cfg(feature = "traces")]
+struct HeaderExtractor<'a, H>(&'a H);
+
+#[cfg(feature = "traces")]
+impl<H: Headers> Extractor for HeaderExtractor<'_, H> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|header| header.key == key)?.value.and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|header| header.key).collect()
+    }
+}
@@ -0,0 +1,204 @@
+//! redis connections (`redis` feature).
+//!
+//! `InstrumentedConnection` wraps anything implementing
+//! `redis::aio::ConnectionLike`, e.g. a `ConnectionManager` or a
+//! `deadpool_redis::Connection`, and is one itself, so commands and
+//! pipelines run through it unchanged:
+//!
+//! ```ignore
+//! let mut conn = InstrumentedConnection::new(&metrics, ConnectionManager::new(client).await?);
+//! let hits: i64 = conn.incr("hits", 1).await?;
+//! ```
+//!
+//! Every command is observed in `redis_command_duration_seconds{command}`
+//! and, when it fails or the server answers with an error, counted in
+//! `redis_command_errors_total{command}`; pipelines count as one `PIPELINE`
+//! command, failed if any of their commands did. With the `traces` feature
+//! each one also runs in a client span named after the command, with
+//! `db.system.name`, `db.operation.name`, `db.namespace` (the database
+//! index) and `db.redis.key_count`, or `db.operation.batch.size` for
+//! pipelines. Arguments are never recorded, so values stay out of traces.
+
+use crate::{metrics::AppMetrics, registry::Registry};
+use ::redis::{aio::ConnectionLike, Arg, Cmd, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
+use std::{future::Future, time::Instant};
+
+/// `redis_command_duration_seconds` and `redis_command_errors_total`,
+/// shared by all connections.
+#[derive(Clone, Debug)]
+pub struct RedisMetrics {
+    pub duration: HistogramVec,
+    pub errors: IntCounterVec,
+}
+
+impl RedisMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let duration = HistogramVec::new(
+            HistogramOpts::new("redis_command_duration_seconds", "Latency of redis commands, failed ones included")
+                .buckets(vec![0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+            &["command"],
+        )?;
+        let errors = IntCounterVec::new(Opts::new("redis_command_errors_total", "Failed redis commands"), &["command"])?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        Ok(Self { duration, errors })
+    }
+}
+
+/// A redis connection whose commands are measured and traced.
+#[derive(Clone)]
+pub struct InstrumentedConnection<C> {
+    inner: C,
+    metrics: RedisMetrics,
+}
+
+impl<C: ConnectionLike> InstrumentedConnection<C> {
+    pub fn new(metrics: &AppMetrics, inner: C) -> Self {
+        Self { inner, metrics: metrics.redis.clone() }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: ConnectionLike + Send> ConnectionLike for InstrumentedConnection<C> {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let args: Vec<&[u8]> = cmd
+            .args_iter()
+            .filter_map(|arg| match arg {
+                Arg::Simple(arg) => Some(arg),
+                Arg::Cursor => None,
+            })
+            .collect();
+        let command =
+            args.first().map_or_else(|| "UNKNOWN".to_string(), |name| String::from_utf8_lossy(name).to_ascii_uppercase());
+        let keys = key_count(&command, &args[1.min(args.len())..]);
+        let (metrics, db) = (self.metrics.clone(), self.inner.get_db());
+        let future = self.inner.req_packed_command(cmd);
+        Box::pin(observe(metrics, db, command, Size::Keys(keys), future))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let (metrics, db) = (self.metrics.clone(), self.inner.get_db());
+        let future = self.inner.req_packed_commands(pipeline, offset, count);
+        Box::pin(observe(metrics, db, "PIPELINE".to_string(), Size::Batch(pipeline.len()), future))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner.get_db()
+    }
+}
+
+#[cfg_attr(not(feature = "traces"), allow(dead_code))]
+enum Size {
+    Keys(usize),
+    Batch(usize),
+}
+
+/// A reply as connections return it: server errors (`WRONGTYPE ...`) are
+/// still values at that point, turned into `Err`s later by the caller.
+trait Reply {
+    fn server_error(&self) -> Option<RedisError>;
+}
+
+impl Reply for Value {
+    fn server_error(&self) -> Option<RedisError> {
+        match self {
+            Value::ServerError(e) => Some(e.clone().into()),
+            _ => None,
+        }
+    }
+}
+
+impl Reply for Vec<Value> {
+    fn server_error(&self) -> Option<RedisError> {
+        self.iter().find_map(Reply::server_error)
+    }
+}
+
+async fn observe<T: Reply>(
+    metrics: RedisMetrics,
+    db: i64,
+    command: String,
+    size: Size,
+    future: impl Future<Output = RedisResult<T>>,
+) -> RedisResult<T> {
+    #[cfg(feature = "traces")]
+    let cx = {
+        let tracer = global::tracer("prom_otel");
+        let size = match size {
+            Size::Keys(keys) => KeyValue::new("db.redis.key_count", keys as i64),
+            Size::Batch(commands) => KeyValue::new("db.operation.batch.size", commands as i64),
+        };
+        let span = tracer
+            .span_builder(command.clone())
+            .with_kind(SpanKind::Client)
+            .with_attributes([
+                KeyValue::new("db.system.name", "redis"),
+                KeyValue::new("db.operation.name", command.clone()),
+                KeyValue::new("db.namespace", db.to_string()),
+                size,
+            ])
+            .start(&tracer);
+        Context::current_with_span(span)
+    };
+    #[cfg(not(feature = "traces"))]
+    let _ = (db, size);
+
+    let started = Instant::now();
+    #[cfg(feature = "traces")]
+    let result = future.with_context(cx.clone()).await;
+    #[cfg(not(feature = "traces"))]
+    let result = future.await;
+    metrics.duration.with_label_values(&[&command]).observe(started.elapsed().as_secs_f64());
+
+    let server_error = result.as_ref().ok().and_then(Reply::server_error);
+    let error = result.as_ref().err().or(server_error.as_ref());
+    if error.is_some() {
+        metrics.errors.with_label_values(&[&command]).inc();
+    }
+    #[cfg(feature = "traces")]
+    {
+        let span = cx.span();
+        if let Some(e) = error {
+            if let Some(code) = e.code() {
+                span.set_attribute(KeyValue::new("db.response.status_code", code.to_string()));
+            }
+            span.set_status(Status::error(e.to_string()));
+        }
+        span.end();
+    }
+    result
+}
+
+/// Number of keys `command` touches, given its arguments. Commands not
+/// listed take a single key.
+fn key_count(command: &str, args: &[&[u8]]) -> usize {
+    match command {
+        "PING" | "ECHO" | "INFO" | "AUTH" | "HELLO" | "SELECT" | "DBSIZE" | "TIME" | "FLUSHDB" | "FLUSHALL"
+        | "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" | "SCAN" | "KEYS" | "RANDOMKEY" | "CLIENT" | "CONFIG"
+        | "COMMAND" | "SCRIPT" | "FUNCTION" | "PUBLISH" | "SUBSCRIBE" | "PSUBSCRIBE" | "UNSUBSCRIBE"
+        | "PUNSUBSCRIBE" | "WAIT" | "QUIT" | "READONLY" | "CLUSTER" | "UNKNOWN" => 0,
+        "DEL" | "UNLINK" | "EXISTS" | "TOUCH" | "MGET" | "WATCH" | "SINTER" | "SUNION" | "SDIFF" | "PFCOUNT" => {
+            args.len()
+        }
+        "MSET" | "MSETNX" => args.len() / 2,
+        "EVAL" | "EVALSHA" | "EVAL_RO" | "EVALSHA_RO" | "FCALL" | "FCALL_RO" => {
+            args.get(1).and_then(|n| std::str::from_utf8(n).ok()?.parse().ok()).unwrap_or(0)
+        }
+        _ => usize::from(!args.is_empty()),
+    }
+}
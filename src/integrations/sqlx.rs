@@ -0,0 +1,179 @@
+//! Postgres connection pools of sqlx (`sqlx` feature).
+//!
+//! `InstrumentedPool::new` registers, per pool:
+//!
+//! - `db_client_connections{pool,state}`: open connections, `state` being
+//!   `idle` or `active`;
+//! - `db_client_connections_max{pool}`: the pool's `max_connections`;
+//! - `db_client_connection_wait_time_seconds{pool}`: how long
+//!   `InstrumentedPool::acquire` waited for a connection.
+//!
+//! The gauges are read from the pool on every scrape. Queries run straight
+//! on `&PgPool` acquire their connection internally, so only acquisitions
+//! through `InstrumentedPool::acquire` are timed.
+//!
+//! With the `traces` feature, `InstrumentedPool::traced` runs a query in a
+//! client span named after its operation (`SELECT`, `INSERT`, ...) with the
+//! `db.*` and `server.*` semantic attributes, and marks the span failed when
+//! the query returns an error:
+//!
+//! ```ignore
+//! let db = InstrumentedPool::new(&metrics, "main", pool)?;
+//! let mut conn = db.acquire().await?;
+//! let sql = "SELECT id FROM users WHERE email = $1";
+//! let user = db.traced(sql, sqlx::query(sql).bind(email).fetch_optional(&mut *conn)).await?;
+//! ```
+//!
+//! Bind parameters are not recorded, only the statement text.
+
+use crate::{gauge_fn::GaugeFn, metrics::AppMetrics, registry::Registry};
+use ::sqlx::{pool::PoolConnection, PgPool, Postgres};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use prometheus::{Histogram, HistogramOpts, HistogramVec};
+use std::{collections::HashMap, future::Future, time::Instant};
+
+/// `db_client_connection_wait_time_seconds`, shared by all pools.
+#[derive(Clone, Debug)]
+pub struct SqlxMetrics {
+    pub wait_time: HistogramVec,
+}
+
+impl SqlxMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let wait_time = HistogramVec::new(
+            HistogramOpts::new("db_client_connection_wait_time_seconds", "Time spent waiting for a pooled connection")
+                .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["pool"],
+        )?;
+        registry.register(Box::new(wait_time.clone()))?;
+        Ok(Self { wait_time })
+    }
+}
+
+/// A `PgPool` with metrics and query spans.
+#[derive(Clone, Debug)]
+pub struct InstrumentedPool {
+    pool: PgPool,
+    wait_time: Histogram,
+    #[cfg(feature = "traces")]
+    attributes: Vec<KeyValue>,
+}
+
+impl InstrumentedPool {
+    /// Registers the gauges of `pool` under the label `pool="<name>"`. The
+    /// gauges keep a handle on the pool, so names must be unique.
+    pub fn new(metrics: &AppMetrics, name: &str, pool: PgPool) -> prometheus::Result<Self> {
+        let labels = |extra: &[(&str, &str)]| -> HashMap<String, String> {
+            [("pool", name)].iter().chain(extra).map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let connections_help = "Open connections of the pool, by state";
+        let idle = pool.clone();
+        metrics.registry.register(Box::new(GaugeFn::with_const_labels(
+            "db_client_connections",
+            connections_help,
+            labels(&[("state", "idle")]),
+            move || idle.num_idle() as f64,
+        )?))?;
+        let active = pool.clone();
+        metrics.registry.register(Box::new(GaugeFn::with_const_labels(
+            "db_client_connections",
+            connections_help,
+            labels(&[("state", "active")]),
+            move || active.size().saturating_sub(active.num_idle() as u32) as f64,
+        )?))?;
+        let max = pool.options().get_max_connections();
+        metrics.registry.register(Box::new(GaugeFn::with_const_labels(
+            "db_client_connections_max",
+            "Maximum number of open connections of the pool",
+            labels(&[]),
+            move || f64::from(max),
+        )?))?;
+
+        #[cfg(feature = "traces")]
+        let attributes = {
+            let options = pool.connect_options();
+            let mut attributes = vec![
+                KeyValue::new("db.system.name", "postgresql"),
+                KeyValue::new("server.address", options.get_host().to_string()),
+                KeyValue::new("server.port", i64::from(options.get_port())),
+            ];
+            if let Some(database) = options.get_database() {
+                attributes.push(KeyValue::new("db.namespace", database.to_string()));
+            }
+            attributes
+        };
+        Ok(Self {
+            wait_time: metrics.sqlx.wait_time.with_label_values(&[name]),
+            pool,
+            #[cfg(feature = "traces")]
+            attributes,
+        })
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Acquires a connection, observing the wait in
+    /// `db_client_connection_wait_time_seconds`, failed attempts included.
+    pub async fn acquire(&self) -> Result<PoolConnection<Postgres>, ::sqlx::Error> {
+        let started = Instant::now();
+        let connection = self.pool.acquire().await;
+        self.wait_time.observe(started.elapsed().as_secs_f64());
+        connection
+    }
+
+    /// Runs `query`, the execution of `statement`, in a client span.
+    /// Without the `traces` feature this only awaits `query`.
+    pub async fn traced<T>(
+        &self,
+        statement: &str,
+        query: impl Future<Output = Result<T, ::sqlx::Error>>,
+    ) -> Result<T, ::sqlx::Error> {
+        #[cfg(feature = "traces")]
+        {
+            let operation = operation_name(statement);
+            let tracer = global::tracer("prom_otel");
+            let mut attributes = self.attributes.clone();
+            attributes.push(KeyValue::new("db.query.text", statement.to_string()));
+            if let Some(operation) = &operation {
+                attributes.push(KeyValue::new("db.operation.name", operation.clone()));
+            }
+            let span = tracer
+                .span_builder(operation.unwrap_or_else(|| "postgresql".to_string()))
+                .with_kind(SpanKind::Client)
+                .with_attributes(attributes)
+                .start(&tracer);
+            let cx = Context::current_with_span(span);
+
+            let result = query.with_context(cx.clone()).await;
+
+            let span = cx.span();
+            if let Err(e) = &result {
+                if let Some(code) = e.as_database_error().and_then(|e| e.code()) {
+                    span.set_attribute(KeyValue::new("db.response.status_code", code.into_owned()));
+                }
+                span.set_status(Status::error(e.to_string()));
+            }
+            span.end();
+            result
+        }
+        #[cfg(not(feature = "traces"))]
+        {
+            let _ = statement;
+            query.await
+        }
+    }
+}
+
+/// First keyword of `statement`, upper-cased, e.g. `SELECT`.
+#[cfg(feature = "traces")]
+fn operation_name(statement: &str) -> Option<String> {
+    let keyword = statement.split_whitespace().next()?;
+    keyword.chars().all(|c| c.is_ascii_alphabetic()).then(|| keyword.to_ascii_uppercase())
+}
@@ -0,0 +1,108 @@
+//! Actix web service instrumented with OpenTelemetry (traces, metrics, logs
+//! over OTLP/HTTP) and a Prometheus `/metrics` endpoint.
+
+pub mod admin;
+pub mod alerts;
+#[cfg(feature = "jemalloc")]
+pub mod allocator;
+pub mod apdex;
+pub mod app_auth;
+pub mod audit;
+pub mod auth;
+pub mod baggage;
+pub mod body_size;
+pub mod capabilities;
+pub mod cardinality;
+pub mod catalog;
+pub mod cgroup;
+pub mod circuit_breaker;
+pub mod cli;
+pub mod client_labels;
+pub mod config;
+pub mod config_reload;
+pub mod custom_metrics;
+pub mod debug_session;
+pub mod debug_tap;
+pub mod dependencies;
+pub mod event_loop;
+pub mod federation;
+pub mod file_sink;
+pub mod flags;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+pub mod gauge_fn;
+#[cfg(feature = "grpc-health")]
+pub mod grpc_health;
+#[cfg(feature = "jemalloc")]
+pub mod heap_profile;
+pub mod instrumentation;
+pub mod integrations;
+pub mod lifecycle;
+pub mod listener;
+pub mod log_format;
+pub mod log_metrics;
+#[cfg(feature = "logs")]
+pub mod log_sampling;
+pub mod memory_pressure;
+pub mod metric_filter;
+pub mod metric_set;
+pub mod metric_snapshot;
+pub mod metric_stream;
+pub mod metric_views;
+pub mod metrics;
+pub mod middleware;
+pub mod multiprocess;
+pub mod named_registry;
+pub mod naming;
+pub mod native_histogram;
+pub mod observed;
+pub mod observed_error;
+#[cfg(feature = "metrics")]
+pub mod otlp_bridge;
+pub mod otlp_exporter;
+pub mod panics;
+pub mod pipeline;
+pub mod platform;
+#[cfg(feature = "pprof")]
+pub mod profiling;
+#[cfg(feature = "pyroscope")]
+pub mod pyroscope;
+pub mod rate_limit;
+pub mod readiness;
+pub mod redact;
+pub mod registry;
+#[cfg(feature = "remote-sampling")]
+pub mod remote_sampling;
+pub mod request_size;
+pub mod response_cache;
+pub mod responses;
+pub mod route_handles;
+pub mod route_rules;
+pub mod scrape_cache;
+#[cfg(feature = "traces")]
+pub mod selftest;
+pub mod server;
+pub mod server_metrics;
+pub mod shadow_export;
+pub mod sharded;
+pub mod shutdown;
+pub mod sli;
+pub mod slo;
+pub mod slow_requests;
+pub mod span_links;
+pub mod span_metrics;
+pub mod statsd;
+pub mod switches;
+pub mod system;
+pub mod system_log;
+pub mod tail_sampling;
+pub mod tasks;
+pub mod telemetry;
+pub mod tenant;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(all(unix, any(feature = "traces", feature = "metrics", feature = "logs")))]
+pub mod unix_http;
@@ -0,0 +1,197 @@
+//! Startup and shutdown phases.
+//!
+//! `begin` times a phase of the process's life, `config_load`,
+//! `exporter_init`, `server_bind`, `server_drain` or `telemetry_shutdown`,
+//! until `Phase::end`. Each finished phase is logged with its duration,
+//! exported as a span of its own name covering it, and its duration set in
+//! `app_lifecycle_phase_seconds{phase}`.
+//!
+//! Phases that end before the telemetry pipelines are installed are held
+//! back until `telemetry_installed`. Startup phases are exported once the
+//! server is bound (`ready`), as children of a `startup` span running from
+//! the process start; `app_start_timestamp_seconds` and
+//! `app_ready_timestamp_seconds` give both ends of it, so a slow startup
+//! shows which phase took the time. `telemetry_shutdown` comes after the
+//! exporters are gone and is only logged locally.
+
+use crate::registry::Registry;
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{Span as _, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use prometheus::{Gauge, GaugeVec, Opts};
+use std::{
+    sync::{LazyLock, Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+static STARTED: OnceLock<SystemTime> = OnceLock::new();
+static STATE: Mutex<State> = Mutex::new(State { telemetry: false, ready: false, unlogged: Vec::new(), startup: Vec::new() });
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// When the process started, or rather when this was first called: call
+/// it first thing in `main`.
+pub fn process_started() -> SystemTime {
+    *STARTED.get_or_init(SystemTime::now)
+}
+
+#[derive(Clone, Debug)]
+struct Finished {
+    name: &'static str,
+    #[cfg_attr(not(feature = "traces"), allow(dead_code))]
+    start: SystemTime,
+    duration: Duration,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Whether the telemetry pipelines are installed.
+    telemetry: bool,
+    /// Whether the `startup` span was exported.
+    ready: bool,
+    /// Phases finished before the telemetry was installed.
+    unlogged: Vec<Finished>,
+    /// Phases finished before `ready`.
+    startup: Vec<Finished>,
+}
+
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[derive(Clone, Debug)]
+struct Metrics {
+    start: Gauge,
+    ready: Gauge,
+    phases: GaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Self {
+            start: Gauge::with_opts(Opts::new("app_start_timestamp_seconds", "When the process started, in Unix time"))
+                .unwrap(),
+            ready: Gauge::with_opts(Opts::new(
+                "app_ready_timestamp_seconds",
+                "When the server was bound and ready to serve, in Unix time",
+            ))
+            .unwrap(),
+            phases: GaugeVec::new(
+                Opts::new("app_lifecycle_phase_seconds", "How long each startup and shutdown phase took"),
+                &["phase"],
+            )
+            .unwrap(),
+        };
+        metrics.start.set(unix_seconds(process_started()));
+        metrics
+    }
+}
+
+/// Registers the lifecycle metrics, shared by every registry, in `registry`.
+pub fn register(registry: &Registry) -> prometheus::Result<()> {
+    registry.register(Box::new(METRICS.start.clone()))?;
+    registry.register(Box::new(METRICS.ready.clone()))?;
+    registry.register(Box::new(METRICS.phases.clone()))
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+/// A phase being timed; a phase dropped without `end` isn't recorded.
+#[derive(Debug)]
+#[must_use = "a phase is only recorded by `end`"]
+pub struct Phase {
+    name: &'static str,
+    start: SystemTime,
+    timer: Instant,
+}
+
+/// Starts timing the phase `name`.
+pub fn begin(name: &'static str) -> Phase {
+    process_started();
+    Phase { name, start: SystemTime::now(), timer: Instant::now() }
+}
+
+impl Phase {
+    /// Records the phase as finished now and returns its duration.
+    pub fn end(&self) -> Duration {
+        let finished = Finished { name: self.name, start: self.start, duration: self.timer.elapsed() };
+        METRICS.phases.with_label_values(&[self.name]).set(finished.duration.as_secs_f64());
+        let mut state = state();
+        if state.telemetry {
+            log(&finished);
+        } else {
+            state.unlogged.push(finished.clone());
+        }
+        if state.ready {
+            drop(state);
+            #[cfg(feature = "traces")]
+            export(&finished, &Context::new());
+        } else {
+            state.startup.push(finished.clone());
+        }
+        finished.duration
+    }
+}
+
+fn log(phase: &Finished) {
+    info!(phase = phase.name, duration_ms = phase.duration.as_millis() as u64, "Lifecycle phase finished");
+}
+
+#[cfg(feature = "traces")]
+fn export(phase: &Finished, parent: &Context) {
+    let tracer = global::tracer("prom_otel");
+    tracer
+        .span_builder(phase.name)
+        .with_start_time(phase.start)
+        .with_attributes([KeyValue::new("app.lifecycle.phase", phase.name)])
+        .start_with_context(&tracer, parent)
+        .end_with_timestamp(phase.start + phase.duration);
+}
+
+/// Logs the phases held back until the telemetry pipelines were installed.
+pub fn telemetry_installed() {
+    let mut state = state();
+    state.telemetry = true;
+    for phase in std::mem::take(&mut state.unlogged) {
+        log(&phase);
+    }
+}
+
+/// Marks the server as ready: sets `app_ready_timestamp_seconds` and exports
+/// the `startup` span with the phases so far.
+pub fn ready() {
+    let now = SystemTime::now();
+    METRICS.ready.set(unix_seconds(now));
+    let started = process_started();
+    let startup_ms = now.duration_since(started).unwrap_or_default().as_millis() as u64;
+    let mut state = state();
+    state.ready = true;
+    let phases = std::mem::take(&mut state.startup);
+    drop(state);
+    #[cfg(feature = "traces")]
+    {
+        let tracer = global::tracer("prom_otel");
+        let span = tracer
+            .span_builder("startup")
+            .with_start_time(started)
+            .with_attributes([KeyValue::new("app.startup", true)])
+            .start(&tracer);
+        let cx = Context::current_with_span(span);
+        for phase in &phases {
+            export(phase, &cx);
+        }
+        let _guard = cx.clone().attach();
+        info!(startup_ms, "App is ready");
+        cx.span().end_with_timestamp(now);
+    }
+    #[cfg(not(feature = "traces"))]
+    {
+        let _ = phases;
+        info!(startup_ms, "App is ready");
+    }
+}
@@ -0,0 +1,156 @@
+//! Addresses the HTTP server listens on.
+//!
+//! `SERVER_ADDR` takes a comma-separated list, e.g.
+//! `0.0.0.0:8888,[::]:8888` for dual-stack or one entry per interface. An
+//! entry can be prefixed with `http://` or `https://` to choose its scheme;
+//! without a prefix it serves HTTPS when a certificate is configured
+//! (`TLS_CERT_PATH`) and HTTP otherwise.
+//!
+//! An IPv6 wildcard such as `[::]:8888` also accepts IPv4 where the OS maps
+//! it, unless an IPv4 listener shares its port: then it is made IPv6-only so
+//! both can bind.
+//!
+//! On Unix, an entry `unix:<path>` serves plain HTTP on a Unix domain socket,
+//! e.g. `unix:/run/app/http.sock` for a sidecar on the same pod, without
+//! opening a TCP port. A socket file left by a previous run is replaced.
+
+use socket2::{Domain, Socket, Type};
+use std::{
+    fmt, io,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    str::FromStr,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// HTTPS when a certificate is configured.
+    Auto,
+    Http,
+    Https,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listener {
+    pub addr: ListenAddr,
+    pub scheme: Scheme,
+}
+
+impl Listener {
+    /// Whether this listener terminates TLS, given whether a certificate is
+    /// configured.
+    pub fn uses_tls(&self, tls_configured: bool) -> bool {
+        match self.scheme {
+            Scheme::Auto => tls_configured,
+            Scheme::Http => false,
+            Scheme::Https => true,
+        }
+    }
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err(format!("{s:?}: Unix domain sockets are not supported on this platform"));
+            }
+            if path.is_empty() {
+                return Err(format!("{s:?}: empty socket path"));
+            }
+            return Ok(Self { addr: ListenAddr::Unix(PathBuf::from(path)), scheme: Scheme::Http });
+        }
+        let (scheme, addr) = match (s.strip_prefix("http://"), s.strip_prefix("https://")) {
+            (Some(addr), _) => (Scheme::Http, addr),
+            (_, Some(addr)) => (Scheme::Https, addr),
+            _ => (Scheme::Auto, s),
+        };
+        let addr = addr.parse().map_err(|e| format!("{s:?}: {e}"))?;
+        Ok(Self { addr: ListenAddr::Tcp(addr), scheme })
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.addr, self.scheme) {
+            (ListenAddr::Unix(_), _) | (_, Scheme::Auto) => write!(f, "{}", self.addr),
+            (_, Scheme::Http) => write!(f, "http://{}", self.addr),
+            (_, Scheme::Https) => write!(f, "https://{}", self.addr),
+        }
+    }
+}
+
+/// A bound listener socket.
+#[derive(Debug)]
+pub enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Binds every listener in `listeners`, in order, with a pending connection
+/// queue of `backlog` for TCP ones.
+pub fn bind_all(listeners: &[Listener], backlog: u32) -> io::Result<Vec<Bound>> {
+    listeners
+        .iter()
+        .map(|listener| {
+            let addr = match &listener.addr {
+                ListenAddr::Tcp(addr) => *addr,
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => return bind_unix(path).map(Bound::Unix),
+                #[cfg(not(unix))]
+                ListenAddr::Unix(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, listener.to_string())),
+            };
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            if addr.is_ipv6() {
+                let shares_port = listeners.iter().any(|other| match other.addr {
+                    ListenAddr::Tcp(other) => other.is_ipv4() && other.port() == addr.port(),
+                    ListenAddr::Unix(_) => false,
+                });
+                socket.set_only_v6(shares_port)?;
+            }
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            socket
+                .bind(&addr.into())
+                .and_then(|()| socket.listen(backlog.try_into().unwrap_or(i32::MAX)))
+                .map_err(|e| io::Error::new(e.kind(), format!("{listener}: {e}")))?;
+            Ok(Bound::Tcp(socket.into()))
+        })
+        .collect()
+}
+
+/// Binds a Unix domain socket at `path`, removing a stale socket file first.
+/// Any other kind of file at `path` is left alone and fails the bind.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    std::os::unix::net::UnixListener::bind(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("unix:{}: {e}", path.display())))
+}
+
+/// Parses a comma-separated list of listeners, dropping empty entries.
+pub fn parse_listeners(s: &str) -> Result<Vec<Listener>, String> {
+    s.split(',').filter(|part| !part.trim().is_empty()).map(str::parse).collect()
+}
@@ -0,0 +1,155 @@
+//! Formats for the local (stdout) log output.
+//!
+//! `text` is the human-readable `tracing_subscriber` default. `json` writes
+//! one object per line with `timestamp`, `level`, `target`, `thread`,
+//! `fields` and, inside an OpenTelemetry span, `trace_id`/`span_id`, so log
+//! shippers can parse it without regexes and join it with traces.
+
+use crate::redact::{Redactor, REDACTED};
+use serde_json::{Map, Value};
+use std::{fmt, str::FromStr};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields,
+    },
+    registry::LookupSpan,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format {s:?}, expected `text` or `json`")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// `FormatEvent` writing one JSON object per event, with field values passed
+/// through `redactor`.
+#[derive(Clone, Debug, Default)]
+pub struct JsonFormat {
+    redactor: Redactor,
+}
+
+impl JsonFormat {
+    pub fn new(redactor: Redactor) -> Self {
+        Self { redactor }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = FieldVisitor::new(&self.redactor);
+        event.record(&mut fields);
+
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("level".into(), metadata.level().as_str().into());
+        line.insert("target".into(), metadata.target().into());
+        if let Some(name) = std::thread::current().name() {
+            line.insert("thread".into(), name.into());
+        }
+        line.insert("fields".into(), Value::Object(fields.into_fields()));
+        if let Some(span) = ctx.lookup_current() {
+            line.insert("span".into(), span.name().into());
+        }
+        #[cfg(feature = "traces")]
+        {
+            use opentelemetry::trace::TraceContextExt;
+
+            let cx = opentelemetry::Context::current();
+            let span_context = cx.span().span_context().clone();
+            if span_context.is_valid() {
+                line.insert("trace_id".into(), span_context.trace_id().to_string().into());
+                line.insert("span_id".into(), span_context.span_id().to_string().into());
+            }
+        }
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+/// Collects an event's fields as JSON values, redacted like in logs.
+pub(crate) struct FieldVisitor<'a> {
+    fields: Map<String, Value>,
+    redactor: &'a Redactor,
+}
+
+impl<'a> FieldVisitor<'a> {
+    pub(crate) fn new(redactor: &'a Redactor) -> Self {
+        Self { fields: Map::new(), redactor }
+    }
+
+    pub(crate) fn into_fields(self) -> Map<String, Value> {
+        self.fields
+    }
+
+    fn insert(&mut self, field: &Field, value: Value) {
+        let value = if self.redactor.is_sensitive_field(field.name()) {
+            REDACTED.into()
+        } else if let Value::String(s) = &value {
+            self.redactor.redact_patterns(s).into_owned().into()
+        } else {
+            value
+        };
+        self.fields.insert(field.name().into(), value);
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
@@ -0,0 +1,177 @@
+//! Counters derived from log records (`LOG_METRICS`).
+//!
+//! Some conditions are only ever logged, e.g. a `warn!(reason = "card_declined",
+//! "Payment declined")` deep in legacy code. A rule counts the events that
+//! match it in `log_rule_matches_total{rule}`, so they can be graphed and
+//! alerted on without touching that code.
+//!
+//! Rules are written as `rule:condition,condition,...` and separated by `;`
+//! in `LOG_METRICS`. An event must meet every condition:
+//!
+//! - `target=payments` matches the target and its submodules
+//!   (`payments::card`);
+//! - `level=warn` matches that level and more severe ones;
+//! - `message=<regex>` matches the message;
+//! - `field.<name>=<regex>` matches the named field, formatted like in logs.
+//!
+//! For example `payment_declined:target=payments,level=warn,field.reason=declined`.
+//! Regexes are unanchored and can't contain `,` or `;`. Events are counted
+//! whether or not `LOG_LEVEL` lets them through, and every rule's series
+//! starts at zero.
+
+use crate::registry::Registry;
+use prometheus::{IntCounter, IntCounterVec, Opts};
+use regex::Regex;
+use std::{fmt, str::FromStr, sync::Arc};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// One rule, named after the `rule` label value it counts under.
+#[derive(Clone, Debug)]
+pub struct LogMetricRule {
+    pub name: String,
+    /// Target or parent module of matching events.
+    pub target: Option<String>,
+    /// Least severe matching level.
+    pub level: Option<Level>,
+    pub message: Option<Regex>,
+    pub fields: Vec<(String, Regex)>,
+}
+
+impl LogMetricRule {
+    fn matches_metadata(&self, target: &str, level: &Level) -> bool {
+        self.target.as_deref().is_none_or(|prefix| {
+            target.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        }) && self.level.is_none_or(|least| *level <= least)
+    }
+
+    fn matches_fields(&self, fields: &[(&'static str, String)]) -> bool {
+        let value = |name: &str| fields.iter().find(|(field, _)| *field == name).map(|(_, value)| value.as_str());
+        self.message.as_ref().is_none_or(|pattern| value("message").is_some_and(|v| pattern.is_match(v)))
+            && self.fields.iter().all(|(name, pattern)| value(name).is_some_and(|v| pattern.is_match(v)))
+    }
+}
+
+impl FromStr for LogMetricRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, conditions) = s.split_once(':').unwrap_or((s, ""));
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("log metric rule {s:?} doesn't have a name"));
+        }
+        let mut rule =
+            LogMetricRule { name: name.to_string(), target: None, level: None, message: None, fields: Vec::new() };
+        let regex = |pattern: &str| {
+            Regex::new(pattern.trim()).map_err(|e| format!("log metric rule {name:?}: invalid regex {pattern:?}: {e}"))
+        };
+        for condition in conditions.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            match condition.split_once('=') {
+                Some(("target", target)) if !target.trim().is_empty() => rule.target = Some(target.trim().to_string()),
+                Some(("level", level)) => {
+                    rule.level = Some(level.trim().parse().map_err(|_| {
+                        format!("log metric rule {name:?}: unknown level {level:?}, expected `error`, `warn`, `info`, `debug` or `trace`")
+                    })?);
+                }
+                Some(("message", pattern)) => rule.message = Some(regex(pattern)?),
+                Some((key, pattern)) if key.strip_prefix("field.").is_some_and(|field| !field.is_empty()) => {
+                    rule.fields.push((key["field.".len()..].to_string(), regex(pattern)?));
+                }
+                _ => {
+                    return Err(format!(
+                        "log metric rule {name:?}: unknown condition {condition:?}, expected `target=`, `level=`, `message=` or `field.<name>=`"
+                    ));
+                }
+            }
+        }
+        if rule.target.is_none() && rule.level.is_none() && rule.message.is_none() && rule.fields.is_empty() {
+            return Err(format!("log metric rule {name:?} has no condition"));
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for LogMetricRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut conditions = Vec::new();
+        if let Some(target) = &self.target {
+            conditions.push(format!("target={target}"));
+        }
+        if let Some(level) = &self.level {
+            conditions.push(format!("level={}", level.as_str().to_ascii_lowercase()));
+        }
+        if let Some(pattern) = &self.message {
+            conditions.push(format!("message={pattern}"));
+        }
+        for (name, pattern) in &self.fields {
+            conditions.push(format!("field.{name}={pattern}"));
+        }
+        write!(f, "{}:{}", self.name, conditions.join(","))
+    }
+}
+
+/// Parses the `;`-separated rules of `LOG_METRICS`.
+pub fn parse_rules(s: &str) -> Result<Vec<LogMetricRule>, String> {
+    s.split(';').map(str::trim).filter(|r| !r.is_empty()).map(str::parse).collect()
+}
+
+/// `log_rule_matches_total`, registered when `LOG_METRICS` is set.
+#[derive(Clone, Debug)]
+pub struct LogMetrics {
+    rules: Arc<Vec<(LogMetricRule, IntCounter)>>,
+}
+
+impl LogMetrics {
+    pub fn new(registry: &Registry, rules: &[LogMetricRule]) -> prometheus::Result<Self> {
+        let matches = IntCounterVec::new(
+            Opts::new("log_rule_matches_total", "Log events matching each `LOG_METRICS` rule"),
+            &["rule"],
+        )?;
+        registry.register(Box::new(matches.clone()))?;
+        let rules = rules.iter().map(|rule| (rule.clone(), matches.with_label_values(&[&rule.name]))).collect();
+        Ok(Self { rules: Arc::new(rules) })
+    }
+
+    /// A layer counting the events matching the rules.
+    pub fn layer(&self) -> LogMetricsLayer {
+        LogMetricsLayer { metrics: self.clone() }
+    }
+}
+
+/// Counts events into `LogMetrics`, see `LogMetrics::layer`.
+pub struct LogMetricsLayer {
+    metrics: LogMetrics,
+}
+
+impl<S: Subscriber> Layer<S> for LogMetricsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut candidates =
+            self.metrics.rules.iter().filter(|(rule, _)| rule.matches_metadata(metadata.target(), metadata.level())).peekable();
+        if candidates.peek().is_none() {
+            return;
+        }
+        let mut visitor = FieldVisitor(Vec::new());
+        event.record(&mut visitor);
+        for (_, counter) in candidates.filter(|(rule, _)| rule.matches_fields(&visitor.0)) {
+            counter.inc();
+        }
+    }
+}
+
+/// Collects an event's fields as they appear in logs.
+struct FieldVisitor(Vec<(&'static str, String)>);
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{value:?}")));
+    }
+}
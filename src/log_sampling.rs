@@ -0,0 +1,176 @@
+//! Log sampling and rate limiting in front of the OTLP log bridge.
+//!
+//! A log storm, e.g. the same `warn!` firing for every request while a
+//! dependency is down, can flood the collector. `LogSampler` filters the
+//! events reaching `OpenTelemetryTracingBridge`:
+//!
+//! - `LOG_RATE_LIMIT_PER_SEC` caps the events exported per second from one
+//!   callsite (one `info!`, `warn!`, ... in the code), whatever their fields;
+//! - `LOG_DEBUG_SAMPLE_RATIO` keeps that share of the `debug` and `trace`
+//!   events, by trace like the trace sampler so a trace keeps all its
+//!   events or none, and every n-th one outside traces. Requests in a debug
+//!   session are not sampled.
+//!
+//! Events held back are counted in `logs_suppressed_total{reason}`
+//! (`rate_limited` or `sampled`). stdout, `LOG_FILE`, `LOG_METRICS` and the
+//! debug tap still see every event.
+
+use crate::{debug_session, registry::Registry};
+use prometheus::{IntCounterVec, Opts};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tracing::{callsite::Identifier, level_filters::LevelFilter, subscriber::Interest, Level, Metadata};
+use tracing_subscriber::layer::{Context, Filter};
+
+#[derive(Debug)]
+struct Window {
+    /// Seconds since `Inner::started`.
+    second: u64,
+    events: u32,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// 0 when unlimited.
+    rate_limit: AtomicU32,
+    /// Bits of the `f64` ratio.
+    debug_ratio: AtomicU64,
+    started: Instant,
+    windows: Mutex<HashMap<Identifier, Window>>,
+    /// `debug` and `trace` events seen outside a trace.
+    untraced: AtomicU64,
+    suppressed: IntCounterVec,
+}
+
+/// Shared sampling state; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct LogSampler {
+    inner: Arc<Inner>,
+}
+
+impl LogSampler {
+    /// At most `rate_limit` events per second and callsite, and
+    /// `debug_ratio` of the `debug` and `trace` events.
+    pub fn new(registry: &Registry, rate_limit: Option<u32>, debug_ratio: f64) -> prometheus::Result<Self> {
+        let suppressed = IntCounterVec::new(
+            Opts::new("logs_suppressed_total", "Log events not exported over OTLP, by reason"),
+            &["reason"],
+        )?;
+        registry.register(Box::new(suppressed.clone()))?;
+        for reason in ["rate_limited", "sampled"] {
+            suppressed.with_label_values(&[reason]);
+        }
+        Ok(Self {
+            inner: Arc::new(Inner {
+                rate_limit: AtomicU32::new(rate_limit.unwrap_or(0)),
+                debug_ratio: AtomicU64::new(debug_ratio.to_bits()),
+                started: Instant::now(),
+                windows: Mutex::new(HashMap::new()),
+                untraced: AtomicU64::new(0),
+                suppressed,
+            }),
+        })
+    }
+
+    /// Replaces the limit and ratio given to `new`, e.g. on a config reload.
+    pub fn set(&self, rate_limit: Option<u32>, debug_ratio: f64) {
+        self.inner.rate_limit.store(rate_limit.unwrap_or(0), Ordering::Relaxed);
+        self.inner.debug_ratio.store(debug_ratio.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Whether a `debug` or `trace` event of the trace with this id, or with
+    /// `None` the next one outside a trace, is kept.
+    fn sampled(&self, trace_id: Option<u128>) -> bool {
+        let ratio = f64::from_bits(self.inner.debug_ratio.load(Ordering::Relaxed));
+        if ratio >= 1.0 || debug_session::current().is_some() {
+            return true;
+        }
+        match trace_id {
+            // The low half of the id, like the SDK's ratio sampler.
+            Some(id) => ((id as u64) as f64) < ratio * u64::MAX as f64,
+            None => {
+                let seen = self.inner.untraced.fetch_add(1, Ordering::Relaxed) as f64;
+                ((seen + 1.0) * ratio).floor() > (seen * ratio).floor()
+            }
+        }
+    }
+
+    /// Whether the callsite of `metadata` is still under the rate limit this
+    /// second, counting the event if so.
+    fn within_rate(&self, metadata: &Metadata<'_>) -> bool {
+        let limit = self.inner.rate_limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return true;
+        }
+        let second = self.inner.started.elapsed().as_secs();
+        let mut windows = self.inner.windows.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let window = windows.entry(metadata.callsite()).or_insert(Window { second, events: 0 });
+        if window.second != second {
+            *window = Window { second, events: 0 };
+        }
+        window.events += 1;
+        window.events <= limit
+    }
+
+    fn keep(&self, metadata: &Metadata<'_>) -> bool {
+        if *metadata.level() >= Level::DEBUG && !self.sampled(current_trace_id()) {
+            self.inner.suppressed.with_label_values(&["sampled"]).inc();
+            return false;
+        }
+        if !self.within_rate(metadata) {
+            self.inner.suppressed.with_label_values(&["rate_limited"]).inc();
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(feature = "traces")]
+fn current_trace_id() -> Option<u128> {
+    use opentelemetry::trace::TraceContextExt;
+
+    let cx = opentelemetry::Context::current();
+    let span_context = cx.span().span_context().clone();
+    span_context.is_valid().then(|| u128::from_be_bytes(span_context.trace_id().to_bytes()))
+}
+
+#[cfg(not(feature = "traces"))]
+fn current_trace_id() -> Option<u128> {
+    None
+}
+
+/// Per-layer filter dropping the events `sampler` holds back, or none
+/// without one. Combine it after the level filter so only events that would
+/// be exported are counted.
+pub struct LogSamplingFilter {
+    sampler: Option<LogSampler>,
+}
+
+impl LogSamplingFilter {
+    pub fn new(sampler: Option<LogSampler>) -> Self {
+        Self { sampler }
+    }
+}
+
+// Decided in `enabled` rather than `event_enabled`: neither the rate limit
+// nor the sampling look at fields, and tracing-subscriber 0.3 leaves its
+// per-layer filter state dirty when `event_enabled` rejects an event.
+impl<S> Filter<S> for LogSamplingFilter {
+    fn enabled(&self, meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        meta.is_span() || self.sampler.as_ref().is_none_or(|sampler| sampler.keep(meta))
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if self.sampler.is_some() && meta.is_event() { Interest::sometimes() } else { Interest::always() }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::TRACE)
+    }
+}
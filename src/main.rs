@@ -1,202 +1,79 @@
-use actix_web::{web::{self, get}, App, HttpResponse, HttpServer, Responder};
-use opentelemetry::{
-    global,
-    trace::{Tracer, TraceContextExt},
-    KeyValue,
+use clap::Parser;
+use prom_otel::{
+    alerts,
+    capabilities,
+    catalog,
+    cli::{Cli, Command},
+    config::Config,
+    lifecycle,
+    metrics::AppMetrics,
+    server,
+    system::SystemSampler,
 };
-use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
-use opentelemetry_otlp::{LogExporter, MetricExporter, Protocol, SpanExporter, WithExportConfig};
-use opentelemetry_sdk::{
-    logs::SdkLoggerProvider, metrics::SdkMeterProvider, trace::SdkTracerProvider, Resource,
-};
-use prometheus::{Encoder, IntCounter, Gauge, Registry, TextEncoder};
-use std::{error::Error, sync::OnceLock};
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::info;
-use tracing_subscriber::{prelude::*, EnvFilter};
-use sysinfo::{ProcessesToUpdate, System,  get_current_pid};
-
-static RESOURCE: OnceLock<Resource> = OnceLock::new();
-
-fn get_resource() -> Resource {
-    RESOURCE
-    .get_or_init(|| {
-        Resource::builder()
-        .with_service_name("otlp-actix-http-example")
-        .build()
-    })
-    .clone()
-}
-
-
-fn init_logs() -> SdkLoggerProvider {
-    let exporter = LogExporter::builder()
-    .with_http()
-    .with_endpoint("http://otel-collector:4318/v1/logs")        .with_protocol(Protocol::HttpBinary)
-    .build()
-    .expect("Failed to create log exporter");
-    
-    SdkLoggerProvider::builder()
-    .with_batch_exporter(exporter)
-    .with_resource(get_resource())
-    .build()
-}
+#[cfg(feature = "traces")]
+use prom_otel::selftest;
+use std::error::Error;
 
-fn init_traces() -> SdkTracerProvider {
-    let exporter = SpanExporter::builder()
-    .with_http()
-    .with_endpoint("http://otel-collector:4318/v1/traces")
-    .with_protocol(Protocol::HttpBinary)
-    .build()
-    .expect("Failed to create trace exporter");
-    
-    SdkTracerProvider::builder()
-    .with_batch_exporter(exporter)
-    .with_resource(get_resource())
-    .build()
-}
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-fn init_metrics() -> SdkMeterProvider {
-    let exporter = MetricExporter::builder()
-    .with_http()
-    .with_endpoint("http://otel-collector:4318")
-    .with_protocol(Protocol::HttpBinary)
-    .build()
-    .expect("Failed to create metric exporter");
-    
-    SdkMeterProvider::builder()
-    .with_periodic_exporter(exporter)
-    .with_resource(get_resource())
-    .build()
-}
+/// Samples one allocation per 512 KiB on average for `heap_profile`, cheap
+/// enough to leave on. Read by jemalloc before `main`; `_RJEM_MALLOC_CONF`
+/// overrides it.
+#[cfg(feature = "jemalloc")]
+#[unsafe(export_name = "_rjem_malloc_conf")]
+pub static MALLOC_CONF: &[u8; 45] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
 
-#[derive(Debug)]
-struct AppMetrics {
-    registry: Registry,
-    request_counter: IntCounter,
-    memory_gauge: Gauge,
-    cpu_gauge: Gauge,
+fn load_config(cli: Cli) -> Result<(Config, Command), Box<dyn Error + Send + Sync + 'static>> {
+    let phase = lifecycle::begin("config_load");
+    let config = Config::load(cli.overrides)?;
+    phase.end();
+    Ok((config, cli.command.unwrap_or(Command::Serve)))
 }
 
-impl AppMetrics {
-    fn new() -> Self {
-        let registry = Registry::new();
-        
-        let request_counter = IntCounter::new("http_requests_total", "Number of HTTP requests").unwrap();
-        let memory_gauge = Gauge::new("app_memory_bytes", "Memory used by the app in bytes").unwrap();
-        let cpu_gauge = Gauge::new("app_cpu_percent", "CPU usage percent of the app").unwrap();
-        
-        registry.register(Box::new(request_counter.clone())).unwrap();
-        registry.register(Box::new(memory_gauge.clone())).unwrap();
-        registry.register(Box::new(cpu_gauge.clone())).unwrap();
-        
-        Self {
-            registry,
-            request_counter,
-            memory_gauge,
-            cpu_gauge,
-        }
-    }
-}
-
-async fn metrics_handler(data: web::Data<Arc<Mutex<AppMetrics>>>) -> impl Responder {
-    let encoder = TextEncoder::new();
-    let metrics = data.lock().await;
-    let metric_families = metrics.registry.gather();
-    
-    let mut buffer = Vec::new();
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    
-    HttpResponse::Ok()
-    .content_type("text/plain; version=0.0.4")
-    .body(String::from_utf8(buffer).unwrap())
+fn print_metrics(config: &Config) -> prometheus::Result<()> {
+    let metrics = AppMetrics::from_config(config);
+    SystemSampler::new().sample(&metrics);
+    print!("{}", metrics.render()?);
+    Ok(())
 }
 
-async fn index(metrics: web::Data<Arc<Mutex<AppMetrics>>>) -> impl Responder {
-    // Increment request count
-    {
-        let  metrics = metrics.lock().await;
-        metrics.request_counter.inc();
+fn print_metric_catalog(config: &Config) {
+    let metrics = AppMetrics::from_config(config);
+    for (method, route) in server::routes(config) {
+        metrics.prime_route(method, &route);
     }
-    
-    HttpResponse::Ok().body("Hello! This request was counted.")
+    println!("{:#}", catalog::catalog(&metrics.registry));
 }
 
-async fn update_system_metrics(metrics: Arc<Mutex<AppMetrics>>) {
-    let mut sys = System::new_all();
-    let pid = get_current_pid().unwrap().as_u32();
-    let get_pid= get_current_pid().unwrap();
-    let pid_array= [get_pid];
-    
-    loop {
-        sys.refresh_processes(ProcessesToUpdate::Some(&pid_array), true);
-        sys.refresh_cpu_all();
-        sys.refresh_memory();
-        
-        if let Some(proc) = sys.process(sysinfo::Pid::from_u32(pid)) {
-            let  metrics = metrics.lock().await;
-            metrics.memory_gauge.set(proc.memory() as f64 / 1048576.0); // Bytes → Mb
-            metrics.cpu_gauge.set(proc.cpu_usage() as f64);
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    lifecycle::process_started();
+    let (config, command) = load_config(Cli::parse())?;
+    match command {
+        Command::Serve => server::serve(config).await,
+        Command::ValidateConfig => {
+            println!("{config}");
+            Ok(())
+        }
+        Command::PrintMetrics => Ok(print_metrics(&config)?),
+        Command::PrintMetricCatalog => {
+            print_metric_catalog(&config);
+            Ok(())
+        }
+        Command::EmitAlerts => {
+            print!("{}", alerts::rules(&config));
+            Ok(())
         }
-        
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        Command::Capabilities => {
+            println!("{:#}", capabilities::report());
+            Ok(())
+        }
+        #[cfg(feature = "traces")]
+        Command::Selftest(args) => selftest::run(&config, args).await,
+        #[cfg(not(feature = "traces"))]
+        Command::Selftest(_) => Err("selftest needs the `traces` feature".into()),
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let logger_provider = init_logs();
-    let otel_layer = OpenTelemetryTracingBridge::new(&logger_provider);
-    let otel_layer = otel_layer.with_filter(
-        EnvFilter::new("info")
-        .add_directive("hyper=off".parse().unwrap())
-        .add_directive("tonic=off".parse().unwrap())
-        .add_directive("h2=off".parse().unwrap())
-        .add_directive("reqwest=off".parse().unwrap()),
-    );
-    
-    let fmt_layer = tracing_subscriber::fmt::layer()
-    .with_thread_names(true)
-    .with_filter(EnvFilter::new("info"));
-    
-    tracing_subscriber::registry()
-    .with(otel_layer)
-    .with(fmt_layer)
-    .init();
-    
-    let tracer_provider = init_traces();
-    global::set_tracer_provider(tracer_provider.clone());
-    
-    let meter_provider = init_metrics();
-    global::set_meter_provider(meter_provider.clone());
-    
-    let app_metrics = Arc::new(Mutex::new(AppMetrics::new()));
-    let metrics_clone = app_metrics.clone();
-    tokio::spawn(update_system_metrics(metrics_clone));
-    
-    let tracer = global::tracer("example");
-    tracer.in_span("startup", |cx| {
-        let span = cx.span();
-        span.set_attribute(KeyValue::new("app.startup", true));
-        info!("App is starting...");
-    });
-    
-    info!("Server running at http://0.0.0.0:8888");
-    
-    HttpServer::new(move || {
-        App::new()
-        .app_data(web::Data::new(app_metrics.clone()))
-        .route("/", web::get().to(index))
-        .route("/metrics", web::get().to(metrics_handler))
-    })
-    .bind(("0.0.0.0", 8888))?
-    .run()
-    .await?;
-    
-    tracer_provider.shutdown()?;
-    meter_provider.shutdown()?;
-    logger_provider.shutdown()?;
-    
-    Ok(())
-}
@@ -0,0 +1,208 @@
+//! Telemetry shedding under memory pressure.
+//!
+//! With `MEMORY_PRESSURE_THRESHOLD_MB` set, `watch` compares the process RSS
+//! against the threshold and raises the degradation level step by step
+//! before the OOM killer acts:
+//!
+//! 1. from 80% of the threshold, `debug` and `trace` events are dropped;
+//! 2. from 90%, only `REDUCED_SAMPLE_RATIO` of the traces that would be
+//!    sampled still are;
+//! 3. from 100%, the system sampler is paused as well.
+//!
+//! A level is left once RSS is `HYSTERESIS` of the threshold below where it
+//! starts, so the level doesn't flap around a boundary. The current level is
+//! exported as `telemetry_degradation_level`.
+
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{Link, SamplingDecision, SamplingResult, SpanKind, TraceId, TraceState},
+    Context, KeyValue,
+};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+use prometheus::IntGauge;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use sysinfo::{get_current_pid, ProcessRefreshKind, ProcessesToUpdate, System};
+use tracing::{info, subscriber::Interest, warn, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+
+/// Share of otherwise sampled traces kept from `ReduceSampling` on.
+pub const REDUCED_SAMPLE_RATIO: f64 = 0.1;
+/// Share of the threshold RSS must fall below a level's start to leave it.
+pub const HYSTERESIS: f64 = 0.05;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum DegradationLevel {
+    #[default]
+    Normal = 0,
+    DropDebugLogs = 1,
+    ReduceSampling = 2,
+    PauseSampler = 3,
+}
+
+impl DegradationLevel {
+    const ALL: [DegradationLevel; 4] = [
+        DegradationLevel::Normal,
+        DegradationLevel::DropDebugLogs,
+        DegradationLevel::ReduceSampling,
+        DegradationLevel::PauseSampler,
+    ];
+
+    /// RSS, as a share of the threshold, from which this level applies.
+    fn starts_at(self) -> f64 {
+        match self {
+            DegradationLevel::Normal => 0.0,
+            DegradationLevel::DropDebugLogs => 0.8,
+            DegradationLevel::ReduceSampling => 0.9,
+            DegradationLevel::PauseSampler => 1.0,
+        }
+    }
+
+    /// The level for RSS at `usage` (a share of the threshold) coming from
+    /// `current`.
+    pub fn for_usage(usage: f64, current: DegradationLevel) -> Self {
+        let reached = Self::ALL.into_iter().rev().find(|level| usage >= level.starts_at()).unwrap_or_default();
+        if reached >= current {
+            return reached;
+        }
+        // Step down only past the hysteresis band below each level.
+        Self::ALL
+            .into_iter()
+            .rev()
+            .find(|level| *level <= current && (*level == reached || usage >= level.starts_at() - HYSTERESIS))
+            .unwrap_or(reached)
+    }
+}
+
+impl fmt::Display for DegradationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DegradationLevel::Normal => "normal",
+            DegradationLevel::DropDebugLogs => "drop_debug_logs",
+            DegradationLevel::ReduceSampling => "reduce_sampling",
+            DegradationLevel::PauseSampler => "pause_sampler",
+        })
+    }
+}
+
+/// Current degradation level, shared by `watch` and the parts it sheds;
+/// cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryPressure(Arc<AtomicU8>);
+
+impl MemoryPressure {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn level(&self) -> DegradationLevel {
+        DegradationLevel::ALL[self.0.load(Ordering::Relaxed) as usize]
+    }
+
+    fn set(&self, level: DegradationLevel) {
+        self.0.store(level as u8, Ordering::Relaxed);
+    }
+
+    /// Whether the system sampler should skip its work.
+    pub fn sampler_paused(&self) -> bool {
+        self.level() >= DegradationLevel::PauseSampler
+    }
+
+    /// A layer dropping `debug` and `trace` events from `DropDebugLogs` on.
+    pub fn layer(&self) -> PressureLayer {
+        PressureLayer { pressure: self.clone() }
+    }
+}
+
+/// Global filter for `debug` and `trace` events, see `MemoryPressure::layer`.
+pub struct PressureLayer {
+    pressure: MemoryPressure,
+}
+
+impl<S: Subscriber> Layer<S> for PressureLayer {
+    fn register_callsite(&self, meta: &'static Metadata<'static>) -> Interest {
+        // The level changes at runtime, so verbose callsites can't be cached.
+        if *meta.level() > Level::INFO { Interest::sometimes() } else { Interest::always() }
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, _ctx: LayerContext<'_, S>) -> bool {
+        *meta.level() <= Level::INFO || self.pressure.level() < DegradationLevel::DropDebugLogs
+    }
+}
+
+/// Delegates to `inner`, keeping only `REDUCED_SAMPLE_RATIO` of its sampled
+/// traces from `ReduceSampling` on.
+#[cfg(feature = "traces")]
+#[derive(Clone, Debug)]
+pub struct PressureSampler {
+    inner: Box<dyn ShouldSample>,
+    pressure: MemoryPressure,
+}
+
+#[cfg(feature = "traces")]
+impl PressureSampler {
+    pub fn new(inner: Box<dyn ShouldSample>, pressure: MemoryPressure) -> Self {
+        Self { inner, pressure }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl ShouldSample for PressureSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let result = self.inner.should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+        if result.decision != SamplingDecision::RecordAndSample || self.pressure.level() < DegradationLevel::ReduceSampling {
+            return result;
+        }
+        let kept = Sampler::TraceIdRatioBased(REDUCED_SAMPLE_RATIO)
+            .should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+        if kept.decision == SamplingDecision::RecordAndSample {
+            result
+        } else {
+            SamplingResult { decision: SamplingDecision::Drop, attributes: Vec::new(), trace_state: TraceState::default() }
+        }
+    }
+}
+
+/// Samples RSS every `interval` and updates `pressure` and `gauge`.
+pub async fn watch(pressure: MemoryPressure, threshold_bytes: u64, interval: Duration, gauge: IntGauge) {
+    let Ok(pid) = get_current_pid() else {
+        warn!("Cannot find the current process, memory pressure monitoring disabled");
+        return;
+    };
+    let mut sys = System::new();
+    loop {
+        sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing().with_memory());
+        if let Some(proc) = sys.process(pid) {
+            let rss = proc.memory();
+            let current = pressure.level();
+            let level = DegradationLevel::for_usage(rss as f64 / threshold_bytes as f64, current);
+            if level != current {
+                pressure.set(level);
+                gauge.set(level as i64);
+                let rss_mb = rss / (1024 * 1024);
+                if level > current {
+                    warn!(%level, rss_mb, threshold_mb = threshold_bytes / (1024 * 1024), "Memory pressure rising, shedding telemetry");
+                } else {
+                    info!(%level, rss_mb, threshold_mb = threshold_bytes / (1024 * 1024), "Memory pressure easing");
+                }
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
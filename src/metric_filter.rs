@@ -0,0 +1,74 @@
+//! Scrape-time selection of metric families on `/metrics`.
+//!
+//! `name[]` (or `name`) keeps a family by its exact name and can be repeated,
+//! `prefix` keeps the families whose name starts with one of its
+//! comma-separated values, and `regex` those whose whole name matches it:
+//!
+//! ```text
+//! GET /metrics?name[]=http_requests_total&name[]=app_cpu_percent
+//! GET /metrics?prefix=http_,process_
+//! GET /metrics?regex=app_(cpu|memory)_.*
+//! ```
+//!
+//! A family is kept when any of them selects it, and a request without them
+//! gets every family. Histograms and summaries go by their family name, e.g.
+//! `http_request_duration_seconds` rather than its `_bucket` series.
+//! Filtered responses are encoded for each request, bypassing
+//! `METRICS_CACHE_TTL_MS`.
+
+use regex::{Regex, RegexBuilder};
+use std::collections::HashSet;
+
+/// Compiled size limit of `regex`, which clients choose.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// The families selected by a `/metrics` query; see the module documentation.
+#[derive(Debug, Default)]
+pub struct MetricFilter {
+    names: HashSet<String>,
+    prefixes: Vec<String>,
+    regex: Option<Regex>,
+}
+
+impl MetricFilter {
+    /// Reads the filter from the decoded query parameters, ignoring the
+    /// others.
+    pub fn from_query(params: &[(String, String)]) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for (key, value) in params {
+            match key.as_str() {
+                "name[]" | "name" => {
+                    filter.names.insert(value.trim().to_string());
+                }
+                "prefix" => filter.prefixes.extend(
+                    value.split(',').map(str::trim).filter(|prefix| !prefix.is_empty()).map(str::to_string),
+                ),
+                "regex" => {
+                    if filter.regex.is_some() {
+                        return Err("regex can only be given once".to_string());
+                    }
+                    let regex = RegexBuilder::new(&format!("^(?:{value})$"))
+                        .size_limit(REGEX_SIZE_LIMIT)
+                        .build()
+                        .map_err(|e| format!("regex {value:?}: {e}"))?;
+                    filter.regex = Some(regex);
+                }
+                _ => {}
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether the filter keeps every family.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.prefixes.is_empty() && self.regex.is_none()
+    }
+
+    /// Whether the family `name` is kept.
+    pub fn matches(&self, name: &str) -> bool {
+        self.is_empty()
+            || self.names.contains(name)
+            || self.prefixes.iter().any(|prefix| name.starts_with(prefix.as_str()))
+            || self.regex.as_ref().is_some_and(|regex| regex.is_match(name))
+    }
+}
@@ -0,0 +1,148 @@
+//! Structs of Prometheus metrics declared in one place.
+//!
+//! `metric_set!` declares a struct whose fields are metrics, each with its
+//! name, help text and optionally labels and histogram buckets, and a
+//! `new(&Registry) -> prometheus::Result<Self>` that creates and registers
+//! them all, returning the first error instead of panicking:
+//!
+//! ```ignore
+//! prom_otel::metric_set! {
+//!     /// Metrics of the job runner.
+//!     #[derive(Clone, Debug)]
+//!     pub struct JobMetrics {
+//!         pub jobs: IntCounter { name: "jobs_total", help: "Jobs run" },
+//!         pub failures: IntCounterVec { name: "job_failures_total", help: "Failed jobs by reason", labels: ["reason"] },
+//!         pub queued: IntGauge { name: "jobs_queued", help: "Jobs waiting to run" },
+//!         pub duration: Histogram { name: "job_duration_seconds", help: "How long jobs ran", buckets: [0.1, 1.0, 10.0] },
+//!     }
+//! }
+//!
+//! let jobs = JobMetrics::new(&app_metrics.registry)?;
+//! ```
+//!
+//! Fields can be any of the `prometheus` counters, gauges and histograms,
+//! plain or `Vec`; labels are required by and only allowed on the `Vec`
+//! ones, buckets only on histograms (the `prometheus` defaults otherwise).
+
+use prometheus::{
+    core::Collector, Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts,
+};
+
+#[doc(hidden)]
+pub use prometheus as __prometheus;
+
+/// A metric as declared in `metric_set!`.
+#[derive(Clone, Debug)]
+pub struct MetricSpec<'a> {
+    pub name: &'a str,
+    pub help: &'a str,
+    pub labels: &'a [&'a str],
+    pub buckets: Option<Vec<f64>>,
+}
+
+impl MetricSpec<'_> {
+    fn opts(&self, labeled: bool) -> prometheus::Result<Opts> {
+        if self.buckets.is_some() {
+            return Err(self.invalid("only histograms take buckets"));
+        }
+        self.check_labels(labeled)?;
+        Ok(Opts::new(self.name, self.help))
+    }
+
+    fn histogram_opts(&self, labeled: bool) -> prometheus::Result<HistogramOpts> {
+        self.check_labels(labeled)?;
+        let opts = HistogramOpts::new(self.name, self.help);
+        Ok(match &self.buckets {
+            Some(buckets) => opts.buckets(buckets.clone()),
+            None => opts,
+        })
+    }
+
+    fn check_labels(&self, labeled: bool) -> prometheus::Result<()> {
+        match (labeled, self.labels.is_empty()) {
+            (true, true) => Err(self.invalid("labeled metrics need labels")),
+            (false, false) => Err(self.invalid("only labeled (`Vec`) metrics take labels")),
+            _ => Ok(()),
+        }
+    }
+
+    fn invalid(&self, reason: &str) -> prometheus::Error {
+        prometheus::Error::Msg(format!("metric {}: {reason}", self.name))
+    }
+}
+
+/// A metric type `metric_set!` fields can have.
+pub trait FromSpec: Collector + Clone + Sized + 'static {
+    fn from_spec(spec: &MetricSpec<'_>) -> prometheus::Result<Self>;
+}
+
+macro_rules! from_spec {
+    (plain: $($ty:ident => $opts:ident),*) => {
+        $(
+            impl FromSpec for $ty {
+                fn from_spec(spec: &MetricSpec<'_>) -> prometheus::Result<Self> {
+                    $ty::with_opts(spec.$opts(false)?)
+                }
+            }
+        )*
+    };
+    (labeled: $($ty:ident => $opts:ident),*) => {
+        $(
+            impl FromSpec for $ty {
+                fn from_spec(spec: &MetricSpec<'_>) -> prometheus::Result<Self> {
+                    $ty::new(spec.$opts(true)?, spec.labels)
+                }
+            }
+        )*
+    };
+}
+
+from_spec!(plain: Counter => opts, IntCounter => opts, Gauge => opts, IntGauge => opts, Histogram => histogram_opts);
+from_spec!(labeled: CounterVec => opts, IntCounterVec => opts, GaugeVec => opts, IntGaugeVec => opts, HistogramVec => histogram_opts);
+
+/// Declares a struct of metrics and its `new(&Registry)`, see `metric_set`.
+#[macro_export]
+macro_rules! metric_set {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident: $ty:ty {
+                    name: $metric:literal,
+                    help: $help:literal
+                    $(, labels: [$($label:literal),* $(,)?])?
+                    $(, buckets: [$($bucket:expr),* $(,)?])?
+                    $(,)?
+                }
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $name {
+            /// Creates the metrics and registers them in `registry`.
+            pub fn new(registry: &$crate::registry::Registry) -> $crate::metric_set::__prometheus::Result<Self> {
+                let metrics = Self {
+                    $(
+                        $field: <$ty as $crate::metric_set::FromSpec>::from_spec(&$crate::metric_set::MetricSpec {
+                            name: $metric,
+                            help: $help,
+                            labels: &[$($($label),*)?],
+                            buckets: None$(.or(Some(vec![$($bucket),*])))?,
+                        })?,
+                    )*
+                };
+                $(registry.register(Box::new(metrics.$field.clone()))?;)*
+                Ok(metrics)
+            }
+        }
+    };
+}
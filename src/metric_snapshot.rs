@@ -0,0 +1,198 @@
+//! Typed snapshots of the metrics with their rate of change.
+//!
+//! `AppMetrics::snapshot` returns the current value of every series as a
+//! `MetricSnapshot`, which serializes to JSON; `MetricSnapshot::with_rates`
+//! fills in how fast each series changed per second since an earlier one.
+//! Status pages of an embedding service render these instead of parsing the
+//! exposition format:
+//!
+//! ```ignore
+//! let before = metrics.snapshot();
+//! tokio::time::sleep(Duration::from_secs(5)).await;
+//! let now = metrics.snapshot().with_rates(&before);
+//! for series in &now.family("http_requests_total").unwrap().series {
+//!     println!("{:?}: {:.1} req/s", series.labels, series.rate.unwrap_or_default());
+//! }
+//! ```
+//!
+//! `GET /metrics/json` (same credentials as `/metrics`, and the family
+//! selection of `metric_filter`) serves `AppMetrics::delta_snapshot`, with
+//! rates since the previous call to it:
+//!
+//! ```json
+//! {"timestamp_ms": 1760500000000, "window_seconds": 5.0, "metrics": [
+//!   {"name": "http_requests_total", "type": "counter", "help": "Number of HTTP requests", "series": [
+//!     {"labels": {}, "value": 42.0, "rate": 1.6}]},
+//!   {"name": "http_request_duration_seconds", "type": "histogram", "help": "...", "series": [
+//!     {"labels": {"method": "GET", "route": "/"}, "count": 42, "sum": 0.21, "rate": 1.6, "mean": 0.004}]}]}
+//! ```
+//!
+//! `rate` is the change per second of `value` for counters and gauges, and
+//! of `count` for histograms and summaries, whose `mean` is that of the
+//! observations made in the window. A counter that went down was reset and
+//! its rate is taken from zero. A series without an earlier value has
+//! neither; `window_seconds` is `null` on the first call. Callers share the
+//! previous call, so with several of them the window is the time since any
+//! of them last asked.
+
+use crate::{catalog::type_name, metric_filter::MetricFilter};
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The value of a series.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Sample {
+    /// Of a counter, gauge or untyped series.
+    Value { value: f64 },
+    /// Of a histogram or summary.
+    Distribution { count: u64, sum: f64 },
+}
+
+/// One series of a family.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SeriesSnapshot {
+    pub labels: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub sample: Sample,
+    /// Change per second since the earlier snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate: Option<f64>,
+    /// Mean of the observations since the earlier snapshot, for histograms
+    /// and summaries that had some.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mean: Option<f64>,
+}
+
+/// One metric family.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FamilySnapshot {
+    pub name: String,
+    /// `counter`, `gauge`, `histogram`, `summary` or `untyped`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub help: String,
+    pub series: Vec<SeriesSnapshot>,
+}
+
+/// The metrics at one point in time; see the module documentation.
+#[derive(Clone, Debug, Serialize)]
+pub struct MetricSnapshot {
+    pub timestamp_ms: u64,
+    /// Seconds since the earlier snapshot the rates were computed against.
+    pub window_seconds: Option<f64>,
+    #[serde(rename = "metrics")]
+    pub families: Vec<FamilySnapshot>,
+    #[serde(skip)]
+    taken: Instant,
+}
+
+impl MetricSnapshot {
+    /// The snapshot of `families`, without rates.
+    pub fn new(families: &[MetricFamily]) -> Self {
+        let families = families
+            .iter()
+            .map(|family| FamilySnapshot {
+                name: family.name().to_string(),
+                kind: type_name(family.get_field_type()),
+                help: family.help().to_string(),
+                series: family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| {
+                        let sample = match family.get_field_type() {
+                            MetricType::COUNTER => Sample::Value { value: metric.get_counter().value() },
+                            MetricType::GAUGE => Sample::Value { value: metric.get_gauge().value() },
+                            MetricType::UNTYPED => Sample::Value { value: metric.untyped.value() },
+                            MetricType::HISTOGRAM => Sample::Distribution {
+                                count: metric.get_histogram().sample_count(),
+                                sum: metric.get_histogram().sample_sum(),
+                            },
+                            MetricType::SUMMARY => Sample::Distribution {
+                                count: metric.get_summary().sample_count(),
+                                sum: metric.get_summary().sample_sum(),
+                            },
+                        };
+                        let labels = metric
+                            .get_label()
+                            .iter()
+                            .map(|label| (label.name().to_string(), label.value().to_string()))
+                            .collect();
+                        SeriesSnapshot { labels, sample, rate: None, mean: None }
+                    })
+                    .collect(),
+            })
+            .collect();
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64);
+        Self { timestamp_ms, window_seconds: None, families, taken: Instant::now() }
+    }
+
+    /// The family `name`, if it has series.
+    pub fn family(&self, name: &str) -> Option<&FamilySnapshot> {
+        self.families.iter().find(|family| family.name == name)
+    }
+
+    /// Fills in the rates since `earlier`; see the module documentation.
+    pub fn with_rates(mut self, earlier: &MetricSnapshot) -> Self {
+        let window = self.taken.saturating_duration_since(earlier.taken).as_secs_f64();
+        if window <= 0.0 {
+            return self;
+        }
+        let previous: HashMap<(&str, &BTreeMap<String, String>), &Sample> = earlier
+            .families
+            .iter()
+            .flat_map(|family| family.series.iter().map(|series| ((family.name.as_str(), &series.labels), &series.sample)))
+            .collect();
+        for family in &mut self.families {
+            let counter = family.kind == "counter";
+            for series in &mut family.series {
+                let Some(before) = previous.get(&(family.name.as_str(), &series.labels)) else {
+                    continue;
+                };
+                match (&series.sample, before) {
+                    (Sample::Value { value }, Sample::Value { value: before }) => {
+                        let before = if counter && value < before { 0.0 } else { *before };
+                        series.rate = Some((value - before) / window);
+                    }
+                    (Sample::Distribution { count, sum }, Sample::Distribution { count: before, sum: sum_before }) => {
+                        let (before, sum_before) = if count < before { (0, 0.0) } else { (*before, *sum_before) };
+                        let observations = count - before;
+                        series.rate = Some(observations as f64 / window);
+                        series.mean = (observations > 0).then(|| (sum - sum_before) / observations as f64);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.window_seconds = Some(window);
+        self
+    }
+
+    /// Keeps the families `filter` selects.
+    pub fn filtered(mut self, filter: &MetricFilter) -> Self {
+        self.families.retain(|family| filter.matches(&family.name));
+        self
+    }
+}
+
+/// The snapshot of the previous `AppMetrics::delta_snapshot`.
+#[derive(Debug, Default)]
+pub struct SnapshotBaseline(Mutex<Option<MetricSnapshot>>);
+
+impl SnapshotBaseline {
+    /// `current` with rates since the previous one, which it replaces.
+    pub fn advance(&self, current: MetricSnapshot) -> MetricSnapshot {
+        let mut previous = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let current = match previous.as_ref() {
+            Some(previous) => current.with_rates(previous),
+            None => current,
+        };
+        *previous = Some(current.clone());
+        current
+    }
+}
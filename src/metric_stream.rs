@@ -0,0 +1,135 @@
+//! Live metric snapshots over Server-Sent Events.
+//!
+//! `GET /metrics/stream` (same credentials as `/metrics`) sends a `snapshot`
+//! event right away and then every `interval` seconds (default 5, 1 to 300),
+//! each a JSON gather of the registry, so dashboards and terminal UIs don't
+//! have to parse the text format. `prefix` keeps the families whose name
+//! starts with one of its comma-separated values, e.g.
+//! `/metrics/stream?prefix=http_,app_memory&interval=2`:
+//!
+//! ```json
+//! {"timestamp_ms": 1760500000000, "metrics": [
+//!   {"name": "http_requests_total", "type": "counter", "samples": [{"labels": {}, "value": 42}]},
+//!   {"name": "http_request_duration_seconds", "type": "histogram", "samples": [
+//!     {"labels": {"method": "GET", "route": "/"}, "count": 42, "sum": 0.21, "buckets": {"0.005": 40, ...}}]}]}
+//! ```
+//!
+//! Histogram buckets are cumulative and keyed by their upper bound, the
+//! `+Inf` one being `count`; summary quantiles are under `quantiles`. The
+//! stream ends when the client disconnects.
+
+use crate::{catalog::type_name, registry::Registry};
+use actix_web::web::Bytes;
+use futures_util::stream::{self, Stream};
+use prometheus::{
+    proto::{Metric, MetricFamily, MetricType},
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{
+    convert::Infallible,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 5;
+const MAX_INTERVAL_SECS: u64 = 300;
+
+/// Query of `/metrics/stream`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamQuery {
+    /// Comma-separated name prefixes; every family when unset.
+    pub prefix: Option<String>,
+    /// Seconds between two snapshots.
+    pub interval: Option<u64>,
+}
+
+impl StreamQuery {
+    /// The name prefixes and interval, or why they are invalid.
+    pub fn parse(&self) -> Result<(Vec<String>, Duration), String> {
+        let prefixes = self
+            .prefix
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|prefix| !prefix.is_empty())
+            .map(str::to_string)
+            .collect();
+        let interval = self.interval.unwrap_or(DEFAULT_INTERVAL_SECS);
+        if !(1..=MAX_INTERVAL_SECS).contains(&interval) {
+            return Err(format!("interval must be between 1 and {MAX_INTERVAL_SECS} seconds"));
+        }
+        Ok((prefixes, Duration::from_secs(interval)))
+    }
+}
+
+/// One snapshot of the families of `registry` matching `prefixes`.
+pub fn snapshot(registry: &Registry, prefixes: &[String]) -> Value {
+    let metrics: Vec<Value> = registry
+        .gather()
+        .iter()
+        .filter(|family| prefixes.is_empty() || prefixes.iter().any(|prefix| family.name().starts_with(prefix.as_str())))
+        .map(|family| {
+            json!({
+                "name": family.name(),
+                "type": type_name(family.get_field_type()),
+                "samples": family.get_metric().iter().map(|metric| sample(family, metric)).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64);
+    json!({ "timestamp_ms": timestamp_ms, "metrics": metrics })
+}
+
+fn sample(family: &MetricFamily, metric: &Metric) -> Value {
+    let labels: Map<String, Value> =
+        metric.get_label().iter().map(|label| (label.name().to_string(), label.value().into())).collect();
+    match family.get_field_type() {
+        MetricType::COUNTER => json!({ "labels": labels, "value": metric.get_counter().value() }),
+        MetricType::GAUGE => json!({ "labels": labels, "value": metric.get_gauge().value() }),
+        MetricType::UNTYPED => json!({ "labels": labels, "value": metric.untyped.value() }),
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            let buckets: Map<String, Value> = histogram
+                .get_bucket()
+                .iter()
+                .map(|bucket| (bucket.upper_bound().to_string(), bucket.cumulative_count().into()))
+                .collect();
+            json!({
+                "labels": labels,
+                "count": histogram.sample_count(),
+                "sum": histogram.sample_sum(),
+                "buckets": buckets,
+            })
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            let quantiles: Map<String, Value> = summary
+                .get_quantile()
+                .iter()
+                .map(|quantile| (quantile.quantile().to_string(), quantile.value().into()))
+                .collect();
+            json!({
+                "labels": labels,
+                "count": summary.sample_count(),
+                "sum": summary.sample_sum(),
+                "quantiles": quantiles,
+            })
+        }
+    }
+}
+
+/// `snapshot` events, the first one immediately, then every `interval`.
+pub fn events(
+    registry: Registry,
+    prefixes: Vec<String>,
+    interval: Duration,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let ticker = tokio::time::interval(interval);
+    stream::unfold((ticker, registry, prefixes), |(mut ticker, registry, prefixes)| async move {
+        ticker.tick().await;
+        let event = format!("event: snapshot\ndata: {}\n\n", snapshot(&registry, &prefixes));
+        Some((Ok(Bytes::from(event)), (ticker, registry, prefixes)))
+    })
+}
@@ -0,0 +1,177 @@
+//! Temporality and views for the OTLP metric pipeline.
+//!
+//! Views are written as `instrument:option,option,...` and separated by `;`
+//! in `METRIC_VIEWS`. The options are:
+//!
+//! - `name=new_name` renames the exported stream;
+//! - `attributes=key|key` keeps only the listed attributes (an empty list
+//!   drops them all), which is how the SDK drops attributes;
+//! - `buckets=0.005|0.05|0.5` overrides histogram bucket boundaries;
+//! - `drop` stops exporting the instrument altogether.
+//!
+//! For example `http.server.duration:name=http_latency,attributes=http.route,buckets=0.05|0.25|1`.
+//! They only apply to OTel instruments; the Prometheus registry behind
+//! `/metrics` is unaffected.
+
+use std::{fmt, str::FromStr};
+
+#[cfg(feature = "metrics")]
+use opentelemetry::Key;
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::{Aggregation, Instrument, Stream, Temporality};
+
+/// Aggregation temporality requested from the OTLP metric exporter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MetricTemporality {
+    #[default]
+    Cumulative,
+    /// Required by Datadog-style backends.
+    Delta,
+    /// Delta for synchronous counters and histograms, cumulative otherwise.
+    LowMemory,
+}
+
+impl FromStr for MetricTemporality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cumulative" => Ok(Self::Cumulative),
+            "delta" => Ok(Self::Delta),
+            "lowmemory" => Ok(Self::LowMemory),
+            _ => Err(format!("unknown temporality {s:?}, expected `cumulative`, `delta` or `lowmemory`")),
+        }
+    }
+}
+
+impl fmt::Display for MetricTemporality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cumulative => "cumulative",
+            Self::Delta => "delta",
+            Self::LowMemory => "lowmemory",
+        })
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl From<MetricTemporality> for Temporality {
+    fn from(temporality: MetricTemporality) -> Self {
+        match temporality {
+            MetricTemporality::Cumulative => Temporality::Cumulative,
+            MetricTemporality::Delta => Temporality::Delta,
+            MetricTemporality::LowMemory => Temporality::LowMemory,
+        }
+    }
+}
+
+/// One view, matching an instrument by exact name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricView {
+    pub instrument: String,
+    pub rename: Option<String>,
+    /// Attribute keys to keep; `None` keeps all of them.
+    pub attributes: Option<Vec<String>>,
+    pub buckets: Option<Vec<f64>>,
+    pub drop: bool,
+}
+
+impl FromStr for MetricView {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (instrument, options) = s.split_once(':').unwrap_or((s, ""));
+        let instrument = instrument.trim();
+        if instrument.is_empty() {
+            return Err(format!("view {s:?} doesn't name an instrument"));
+        }
+        let mut view = MetricView { instrument: instrument.to_string(), ..Default::default() };
+        for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                None if option == "drop" => view.drop = true,
+                Some(("name", name)) if !name.trim().is_empty() => view.rename = Some(name.trim().to_string()),
+                Some(("attributes", keys)) => {
+                    view.attributes = Some(
+                        keys.split('|').map(str::trim).filter(|k| !k.is_empty()).map(str::to_string).collect(),
+                    );
+                }
+                Some(("buckets", bounds)) => {
+                    let bounds: Vec<f64> = bounds
+                        .split('|')
+                        .map(|b| b.trim().parse::<f64>().ok().filter(|b| b.is_finite()))
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| format!("view {instrument:?}: buckets must be numbers, got {bounds:?}"))?;
+                    if bounds.windows(2).any(|w| w[0] >= w[1]) {
+                        return Err(format!("view {instrument:?}: buckets must be strictly increasing"));
+                    }
+                    view.buckets = Some(bounds);
+                }
+                _ => {
+                    return Err(format!(
+                        "view {instrument:?}: unknown option {option:?}, expected `name=`, `attributes=`, `buckets=` or `drop`"
+                    ));
+                }
+            }
+        }
+        if view.drop && (view.rename.is_some() || view.attributes.is_some() || view.buckets.is_some()) {
+            return Err(format!("view {instrument:?}: `drop` can't be combined with other options"));
+        }
+        Ok(view)
+    }
+}
+
+impl fmt::Display for MetricView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut options = Vec::new();
+        if let Some(name) = &self.rename {
+            options.push(format!("name={name}"));
+        }
+        if let Some(keys) = &self.attributes {
+            options.push(format!("attributes={}", keys.join("|")));
+        }
+        if let Some(bounds) = &self.buckets {
+            let bounds: Vec<String> = bounds.iter().map(ToString::to_string).collect();
+            options.push(format!("buckets={}", bounds.join("|")));
+        }
+        if self.drop {
+            options.push("drop".to_string());
+        }
+        write!(f, "{}:{}", self.instrument, options.join(","))
+    }
+}
+
+/// Parses the `;`-separated views of `METRIC_VIEWS`.
+pub fn parse_views(s: &str) -> Result<Vec<MetricView>, String> {
+    s.split(';').map(str::trim).filter(|v| !v.is_empty()).map(str::parse).collect()
+}
+
+#[cfg(feature = "metrics")]
+impl MetricView {
+    /// The stream `instrument` should produce, or `None` if this view
+    /// doesn't match it.
+    pub fn stream(&self, instrument: &Instrument) -> Option<Stream> {
+        if instrument.name() != self.instrument {
+            return None;
+        }
+        let mut stream = Stream::builder();
+        if self.drop {
+            stream = stream.with_aggregation(Aggregation::Drop);
+        }
+        if let Some(name) = &self.rename {
+            stream = stream.with_name(name.clone());
+        }
+        if let Some(keys) = &self.attributes {
+            stream = stream.with_allowed_attribute_keys(keys.iter().cloned().map(Key::from));
+        }
+        if let Some(bounds) = &self.buckets {
+            stream = stream.with_aggregation(Aggregation::ExplicitBucketHistogram {
+                boundaries: bounds.clone(),
+                record_min_max: true,
+            });
+        }
+        stream
+            .build()
+            .inspect_err(|e| tracing::warn!(view = %self, error = %e, "Ignoring invalid metric view"))
+            .ok()
+    }
+}
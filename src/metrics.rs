@@ -0,0 +1,619 @@
+//! Prometheus registry exposed on `/metrics`.
+
+use crate::{
+    apdex::ApdexMetrics,
+    audit::ConfigChanges,
+    baggage::BaggageMetrics,
+    body_size::BodySizeMetrics,
+    cardinality::{CardinalityLimiter, DEFAULT_MAX_LABEL_SETS},
+    cgroup::{Cgroup, CgroupMetrics},
+    client_labels::ClientLabels,
+    config::Config,
+    dependencies::DependencyMetrics,
+    event_loop::EventLoop,
+    gauge_fn::GaugeFn,
+    instrumentation::OperationMetrics,
+    lifecycle,
+    log_metrics::{LogMetricRule, LogMetrics},
+    metric_filter::MetricFilter,
+    metric_snapshot::{MetricSnapshot, SnapshotBaseline},
+    multiprocess::Multiprocess,
+    named_registry::NamedRegistry,
+    naming,
+    native_histogram::NativeHistogramOpts,
+    observed::HandlerMetrics,
+    observed_error::ErrorMetrics,
+    pipeline::PipelineStats,
+    rate_limit::RateLimitMetrics,
+    registry::Registry,
+    custom_metrics::{CounterVecBuilder, CustomMetrics, GaugeVecBuilder, TimerBuilder},
+    request_size::RequestSizeMetrics,
+    response_cache::ResponseCacheMetrics,
+    slow_requests::SlowRequests,
+    timeout::TimeoutMetrics,
+    responses::ResponseMetrics,
+    route_handles::{RouteHandles, RouteTable},
+    server_metrics::ServerMetrics,
+    sharded::{ShardedCounter, ShardedHistogramVec},
+    sli::SliMetrics,
+    slo::Slos,
+    span_metrics::SpanMetrics,
+    system::{HostMetrics, IoMetrics},
+    tail_sampling::TailSamplingMetrics,
+    tasks::{TaskMetrics, TaskOutput},
+};
+#[cfg(feature = "jemalloc")]
+use crate::allocator::AllocatorMetrics;
+#[cfg(feature = "kafka")]
+use crate::integrations::kafka::KafkaMetrics;
+#[cfg(feature = "redis")]
+use crate::integrations::redis::RedisMetrics;
+#[cfg(feature = "sqlx")]
+use crate::integrations::sqlx::SqlxMetrics;
+use prometheus::{
+    core::Collector, proto::MetricFamily, Encoder, Gauge, HistogramOpts, IntCounter, IntGauge, ProtobufEncoder, TextEncoder,
+    PROTOBUF_FORMAT,
+};
+use std::{collections::HashMap, future::Future, sync::OnceLock};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+#[derive(Debug)]
+pub struct AppMetrics {
+    pub registry: Registry,
+    pub request_counter: ShardedCounter,
+    pub http_request_duration: ShardedHistogramVec,
+    pub memory_gauge: Gauge,
+    pub memory_peak_gauge: Gauge,
+    pub cpu_gauge: Gauge,
+    pub panics: IntCounter,
+    /// `/metrics` requests that failed because the registry couldn't be encoded.
+    pub encode_failures: IntCounter,
+    /// See `memory_pressure::DegradationLevel`; 0 unless a threshold is set.
+    pub degradation_level: IntGauge,
+    pub io: IoMetrics,
+    /// CPU, load and memory of the host, sampled with the process.
+    pub host: HostMetrics,
+    /// Container limits and usage; `None` when no cgroup filesystem is found.
+    pub cgroup: Option<CgroupMetrics>,
+    #[cfg(feature = "jemalloc")]
+    pub allocator: AllocatorMetrics,
+    /// Messages of `integrations::kafka` producers and consumers.
+    #[cfg(feature = "kafka")]
+    pub kafka: KafkaMetrics,
+    /// Commands of `integrations::redis::InstrumentedConnection`s.
+    #[cfg(feature = "redis")]
+    pub redis: RedisMetrics,
+    /// Connection wait times of `integrations::sqlx::InstrumentedPool`s.
+    #[cfg(feature = "sqlx")]
+    pub sqlx: SqlxMetrics,
+    pub pipeline: PipelineStats,
+    pub sli: SliMetrics,
+    pub responses: ResponseMetrics,
+    /// Request and response body sizes of tracked requests.
+    pub body_sizes: BodySizeMetrics,
+    /// Latency of handlers wrapped with `observed::observed`.
+    pub handlers: HandlerMetrics,
+    /// Operations of servers other than Actix, see `instrumentation`.
+    pub operations: OperationMetrics,
+    /// Outbound calls to downstream dependencies, see `dependencies`.
+    pub dependencies: DependencyMetrics,
+    /// `ObservedError`s returned by handlers, by code.
+    pub errors: ErrorMetrics,
+    pub apdex: ApdexMetrics,
+    /// Decisions of the `RATE_LIMITS` rules.
+    pub rate_limit: RateLimitMetrics,
+    /// Requests past their `REQUEST_TIMEOUTS` deadline.
+    pub timeouts: TimeoutMetrics,
+    /// Lookups and entries of the `RESPONSE_CACHE`.
+    pub response_cache: ResponseCacheMetrics,
+    /// Tasks spawned with `spawn_instrumented`.
+    pub tasks: TaskMetrics,
+    /// Tuning, workers and connections of the HTTP server.
+    pub server: ServerMetrics,
+    pub cardinality: CardinalityLimiter,
+    pub config_changes: ConfigChanges,
+    /// Requests by selected baggage entries; `None` unless `BAGGAGE_KEYS` is set.
+    pub baggage: Option<BaggageMetrics>,
+    /// Metrics derived from spans; `None` unless `SPAN_METRICS` is set.
+    pub span_metrics: Option<SpanMetrics>,
+    /// Tail sampling decisions; `None` unless `TAIL_SAMPLING` is set.
+    pub tail_sampling: Option<TailSamplingMetrics>,
+    /// Log events matching `LOG_METRICS` rules; `None` unless rules are set.
+    pub log_metrics: Option<LogMetrics>,
+    /// The worker processes whose metrics `/metrics` serves summed; `None`
+    /// unless `METRICS_MULTIPROCESS_DIR` is set.
+    pub multiprocess: Option<Multiprocess>,
+    /// Runtime lag probes; `None` when `EVENT_LOOP_PROBE_INTERVAL_MS` is 0.
+    pub event_loop: Option<EventLoop>,
+    /// Watchdog of in-flight requests; `None` unless
+    /// `SLOW_REQUEST_THRESHOLD_MS` is set.
+    pub slow_requests: Option<SlowRequests>,
+    /// User agent, subnet and TLS version labels; `None` unless
+    /// `CLIENT_LABELS` is set.
+    pub client: Option<ClientLabels>,
+    /// Sliding windows and burn rates of the `SLOS`; `None` unless rules are
+    /// set.
+    pub slos: Option<Slos>,
+    /// Rejections and limits of the `REQUEST_SIZE_LIMITS`; `None` unless
+    /// rules are set.
+    pub request_size: Option<RequestSizeMetrics>,
+    /// Whether `register` rejects collectors breaking the naming
+    /// conventions (`METRICS_NAMING_STRICT`).
+    pub strict_naming: bool,
+    /// Families made by `timer`, `counter_vec` and `gauge_vec`.
+    pub(crate) custom_metrics: CustomMetrics,
+    /// Children of the routes known at startup, see `resolve_routes`.
+    route_handles: OnceLock<RouteTable>,
+    /// The snapshot of the previous `delta_snapshot`.
+    snapshot_baseline: SnapshotBaseline,
+}
+
+impl AppMetrics {
+    pub fn new() -> Self {
+        Self::with_max_label_sets(DEFAULT_MAX_LABEL_SETS)
+    }
+    
+    /// Like `new`, with a custom cap on distinct label sets per labeled metric.
+    pub fn with_max_label_sets(max_label_sets: usize) -> Self {
+        Self::build(max_label_sets, HashMap::new(), &[], false, false, &[], None)
+    }
+
+    /// With the label cap, constant resource labels (see
+    /// `Config::metric_const_labels`), baggage keys, span metrics, tail
+    /// sampling, log metric rules, native histograms, multiprocess
+    /// directory, event loop probes, slow request watchdog, SLOs, client
+    /// labels and naming strictness of `config`.
+    pub fn from_config(config: &Config) -> Self {
+        let metrics = Self::build(
+            config.metrics_max_label_sets,
+            config.metric_const_labels(),
+            &config.baggage_keys,
+            config.span_metrics,
+            config.tail_sampling,
+            &config.log_metrics,
+            config.native_histograms(),
+        );
+        let multiprocess = config.metrics_multiprocess_dir.clone().map(|dir| {
+            Multiprocess::new(dir, config.metrics_multiprocess_interval, config.metrics_multiprocess_gauge_mode)
+        });
+        let event_loop = (!config.event_loop_probe_interval.is_zero()).then(|| {
+            EventLoop::new(&metrics.registry, config.event_loop_probe_interval, config.event_loop_blocked_threshold)
+                .unwrap()
+        });
+        let slow_requests = (!config.slow_request_threshold.is_zero()).then(|| {
+            SlowRequests::new(
+                &metrics.registry,
+                metrics.cardinality.clone(),
+                config.slow_request_threshold,
+                config.slow_request_profile,
+            )
+            .unwrap()
+        });
+        let slos = (!config.slos.is_empty())
+            .then(|| Slos::new(&metrics.registry, &config.slos, &config.endpoint_prefix).unwrap());
+        let client = config
+            .client_labels
+            .then(|| ClientLabels::new(&metrics.registry, &config.client_subnets).unwrap());
+        let request_size = (!config.request_size_limits.is_empty())
+            .then(|| RequestSizeMetrics::new(&metrics.registry, &config.request_size_limits).unwrap());
+        Self {
+            multiprocess,
+            event_loop,
+            slow_requests,
+            client,
+            slos,
+            request_size,
+            strict_naming: config.metrics_naming_strict,
+            ..metrics
+        }
+    }
+
+    fn build(
+        max_label_sets: usize,
+        const_labels: HashMap<String, String>,
+        baggage_keys: &[String],
+        span_metrics: bool,
+        tail_sampling: bool,
+        log_metric_rules: &[LogMetricRule],
+        native_histograms: Option<NativeHistogramOpts>,
+    ) -> Self {
+        let const_labels = Some(const_labels).filter(|labels| !labels.is_empty());
+        let registry = Registry::new_custom(None, const_labels).unwrap();
+        
+        let request_counter = ShardedCounter::new("http_requests_total", "Number of HTTP requests").unwrap();
+        let memory_gauge = Gauge::new("app_memory_bytes", "Memory used by the app in bytes").unwrap();
+        let memory_peak_gauge =
+            Gauge::new("app_memory_peak_bytes", "Highest memory usage of the app seen so far, in bytes").unwrap();
+        let cpu_gauge = Gauge::new("app_cpu_percent", "CPU usage percent of the app").unwrap();
+        let panics = IntCounter::new("app_panics_total", "Panics caught by the panic hook").unwrap();
+        let encode_failures =
+            IntCounter::new("metrics_encode_failures_total", "Scrapes that failed because the registry couldn't be encoded")
+                .unwrap();
+        let degradation_level = IntGauge::new(
+            "telemetry_degradation_level",
+            "Telemetry shed because of memory pressure: 0 none, 1 debug logs, 2 sampling, 3 system sampler",
+        )
+        .unwrap();
+        let http_request_duration = ShardedHistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route template"),
+            &["method", "route"],
+        ).unwrap().with_native(native_histograms);
+        
+        registry.register(Box::new(request_counter.clone())).unwrap();
+        registry.register(Box::new(memory_gauge.clone())).unwrap();
+        registry.register(Box::new(memory_peak_gauge.clone())).unwrap();
+        registry.register(Box::new(cpu_gauge.clone())).unwrap();
+        registry.register(Box::new(http_request_duration.clone())).unwrap();
+        registry.register(Box::new(panics.clone())).unwrap();
+        registry.register(Box::new(encode_failures.clone())).unwrap();
+        registry.register(Box::new(degradation_level.clone())).unwrap();
+        // process_open_fds, process_max_fds, process_threads, ... (only implemented on
+        // Linux; `SystemSampler` provides the thread and fd gauges elsewhere)
+        #[cfg(target_os = "linux")]
+        registry.register(Box::new(prometheus::process_collector::ProcessCollector::for_self())).unwrap();
+        
+        let io = IoMetrics::new(&registry).unwrap();
+        let host = HostMetrics::new(&registry).unwrap();
+        let cgroup = Cgroup::detect().map(|_| CgroupMetrics::new(&registry).unwrap());
+        #[cfg(feature = "jemalloc")]
+        let allocator = AllocatorMetrics::new(&registry).unwrap();
+        #[cfg(feature = "kafka")]
+        let kafka = KafkaMetrics::new(&registry).unwrap();
+        #[cfg(feature = "redis")]
+        let redis = RedisMetrics::new(&registry).unwrap();
+        #[cfg(feature = "sqlx")]
+        let sqlx = SqlxMetrics::new(&registry).unwrap();
+        let pipeline = PipelineStats::new(&registry).unwrap();
+        let sli = SliMetrics::new(&registry).unwrap();
+        let responses = ResponseMetrics::new(&registry).unwrap();
+        let body_sizes = BodySizeMetrics::new(&registry).unwrap();
+        let handlers = HandlerMetrics::new(&registry).unwrap();
+        let operations = OperationMetrics::new(&registry).unwrap();
+        let errors = ErrorMetrics::new(&registry).unwrap();
+        let apdex = ApdexMetrics::new(&registry).unwrap();
+        let rate_limit = RateLimitMetrics::new(&registry).unwrap();
+        let timeouts = TimeoutMetrics::new(&registry).unwrap();
+        let response_cache = ResponseCacheMetrics::new(&registry).unwrap();
+        let tasks = TaskMetrics::new(&registry).unwrap();
+        let server = ServerMetrics::new(&registry).unwrap();
+        let cardinality = CardinalityLimiter::new(&registry, max_label_sets).unwrap();
+        let dependencies = DependencyMetrics::new(&registry, cardinality.clone()).unwrap();
+        let config_changes = ConfigChanges::new(&registry).unwrap();
+        lifecycle::register(&registry).unwrap();
+        let baggage = (!baggage_keys.is_empty()).then(|| BaggageMetrics::new(&registry, baggage_keys).unwrap());
+        let span_metrics = span_metrics.then(|| SpanMetrics::new(&registry).unwrap());
+        let tail_sampling = tail_sampling.then(|| TailSamplingMetrics::new(&registry).unwrap());
+        let log_metrics =
+            (!log_metric_rules.is_empty()).then(|| LogMetrics::new(&registry, log_metric_rules).unwrap());
+        
+        Self {
+            registry,
+            request_counter,
+            http_request_duration,
+            memory_gauge,
+            memory_peak_gauge,
+            cpu_gauge,
+            panics,
+            encode_failures,
+            degradation_level,
+            io,
+            host,
+            cgroup,
+            #[cfg(feature = "jemalloc")]
+            allocator,
+            #[cfg(feature = "kafka")]
+            kafka,
+            #[cfg(feature = "redis")]
+            redis,
+            #[cfg(feature = "sqlx")]
+            sqlx,
+            pipeline,
+            sli,
+            responses,
+            body_sizes,
+            handlers,
+            operations,
+            dependencies,
+            errors,
+            apdex,
+            rate_limit,
+            timeouts,
+            response_cache,
+            tasks,
+            server,
+            cardinality,
+            config_changes,
+            baggage,
+            span_metrics,
+            tail_sampling,
+            log_metrics,
+            multiprocess: None,
+            event_loop: None,
+            slow_requests: None,
+            client: None,
+            slos: None,
+            request_size: None,
+            strict_naming: false,
+            route_handles: OnceLock::new(),
+            custom_metrics: CustomMetrics::new(native_histograms),
+            snapshot_baseline: SnapshotBaseline::default(),
+        }
+    }
+    
+    /// Creates the request series of `method` and `route` at zero, so they
+    /// are scraped before the route's first request and `increase()` or
+    /// `absent()` don't misfire right after a deploy. Primed label sets count
+    /// against the cardinality budget like observed ones.
+    pub fn prime_route(&self, method: &str, route: &str) {
+        RouteHandles::new(self, method, route);
+    }
+
+    /// Primes the series of `routes` like `prime_route` and keeps their
+    /// children, so `track_requests` records their requests without looking
+    /// labels up (see `route_handles`). Only the first call's children are
+    /// kept; later ones just prime.
+    pub fn resolve_routes<'a>(&self, routes: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        let table = RouteTable::resolve(self, routes);
+        let _ = self.route_handles.set(table);
+    }
+
+    /// The children of `method` and `route`, if `resolve_routes` resolved them.
+    pub fn route_handles(&self, method: &str, route: &str) -> Option<&RouteHandles> {
+        self.route_handles.get()?.get(method, route)
+    }
+    
+    /// Registers a gauge whose value is computed by `callback` on every
+    /// gather, e.g. `metrics.register_gauge_fn("queue_depth", "Jobs waiting", move || q.len() as f64)`.
+    /// The callback runs on the scrape path, so it should be quick and must
+    /// not gather the registry itself.
+    pub fn register_gauge_fn<F>(&self, name: &str, help: &str, callback: F) -> prometheus::Result<()>
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.register(Box::new(GaugeFn::new(name, help, callback)?))
+    }
+
+    /// A histogram of durations in seconds named `name`, registered on first
+    /// use: `metrics.timer("job_duration_seconds").start()` returns a guard
+    /// observing when dropped, see `custom_metrics`.
+    pub fn timer(&self, name: &str) -> TimerBuilder<'_> {
+        TimerBuilder::new(self, name)
+    }
+
+    /// A counter named `name`, registered on first use, e.g.
+    /// `metrics.counter_vec("job_failures_total").labels(&["reason"]).build()?`.
+    pub fn counter_vec(&self, name: &str) -> CounterVecBuilder<'_> {
+        CounterVecBuilder::new(self, name)
+    }
+
+    /// A gauge named `name`, registered on first use, like `counter_vec`.
+    pub fn gauge_vec(&self, name: &str) -> GaugeVecBuilder<'_> {
+        GaugeVecBuilder::new(self, name)
+    }
+
+    /// Registers `collector` after checking its families against the naming
+    /// conventions (see `naming`): violations are logged, or with
+    /// `strict_naming` returned as an error and the collector not
+    /// registered.
+    pub fn register(&self, collector: Box<dyn Collector>) -> prometheus::Result<()> {
+        let violations = naming::check_collector(collector.as_ref());
+        if self.strict_naming && !violations.is_empty() {
+            let messages: Vec<&str> = violations.iter().map(|violation| violation.message.as_str()).collect();
+            return Err(prometheus::Error::Msg(format!("naming conventions: {}", messages.join("; "))));
+        }
+        for violation in &violations {
+            warn!(metric = %violation.metric, rule = violation.rule, "{}", violation.message);
+        }
+        self.registry.register(collector)
+    }
+    
+    /// Spawns `future` on tokio like `tokio::spawn`, tracking it as the
+    /// background task `name` in the `background_task*` metrics and, with the
+    /// `traces` feature, in a span (see `tasks`).
+    pub fn spawn_instrumented<F>(&self, name: &'static str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: TaskOutput + Send + 'static,
+    {
+        self.tasks.spawn(name, future)
+    }
+    
+    /// Merges the families of `registry`, e.g. an embedded library's, into
+    /// this one at gather time, renamed `<prefix>_<name>` and with
+    /// `const_labels` added to their series (see `named_registry`). Each
+    /// `name` can be merged once.
+    pub fn merge_registry(
+        &self,
+        name: &str,
+        registry: prometheus::Registry,
+        prefix: Option<&str>,
+        const_labels: HashMap<String, String>,
+    ) -> prometheus::Result<()> {
+        self.registry.register(Box::new(NamedRegistry::new(name, registry, prefix, const_labels)?))
+    }
+
+    /// Like `merge_registry`, with a new registry, returned for the caller
+    /// to register its metrics in.
+    pub fn add_registry(
+        &self,
+        name: &str,
+        prefix: Option<&str>,
+        const_labels: HashMap<String, String>,
+    ) -> prometheus::Result<prometheus::Registry> {
+        let registry = prometheus::Registry::new();
+        self.merge_registry(name, registry.clone(), prefix, const_labels)?;
+        Ok(registry)
+    }
+
+    /// Gathers the registry and encodes it in the Prometheus text format.
+    /// Failures are counted in `encode_failures`.
+    pub fn render(&self) -> prometheus::Result<String> {
+        let buffer = self.encode(Exposition::Text)?;
+        String::from_utf8(buffer)
+            .map_err(|e| prometheus::Error::Msg(e.to_string()))
+            .inspect_err(|_| self.encode_failures.inc())
+    }
+
+    /// Like `render`, in `format`.
+    pub fn encode(&self, format: Exposition) -> prometheus::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        format.encode(&self.gather(), &mut buffer).inspect_err(|_| self.encode_failures.inc())?;
+        Ok(buffer)
+    }
+
+    /// Like `encode`, keeping the families `filter` selects.
+    pub fn encode_filtered(&self, format: Exposition, filter: &MetricFilter) -> prometheus::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        format.encode(&self.gather_filtered(filter), &mut buffer).inspect_err(|_| self.encode_failures.inc())?;
+        Ok(buffer)
+    }
+
+    /// The families served on `/metrics`: the registry's, summed with those
+    /// of the other worker processes with `multiprocess`.
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        match &self.multiprocess {
+            Some(multiprocess) => multiprocess.aggregate(self.registry.gather()),
+            None => self.registry.gather(),
+        }
+    }
+
+    /// The families of `gather` that `filter` selects.
+    pub fn gather_filtered(&self, filter: &MetricFilter) -> Vec<MetricFamily> {
+        let mut families = self.gather();
+        families.retain(|family| filter.matches(family.name()));
+        families
+    }
+
+    /// The current value of every series served on `/metrics`, see
+    /// `metric_snapshot`.
+    pub fn snapshot(&self) -> MetricSnapshot {
+        MetricSnapshot::new(&self.gather())
+    }
+
+    /// Like `snapshot`, with rates since the previous call and keeping the
+    /// families `filter` selects.
+    pub fn delta_snapshot(&self, filter: &MetricFilter) -> MetricSnapshot {
+        self.snapshot_baseline.advance(self.snapshot()).filtered(filter)
+    }
+
+    /// Like `render`, but encodes lazily in chunks of about `CHUNK_BYTES`, so
+    /// the whole exposition is never held in memory at once. A failure ends
+    /// the chunks.
+    pub fn render_chunks(&self) -> EncodeChunks {
+        self.encode_chunks(Exposition::Text)
+    }
+
+    /// Like `render_chunks`, in `format`.
+    pub fn encode_chunks(&self, format: Exposition) -> EncodeChunks {
+        EncodeChunks {
+            format,
+            families: self.gather().into_iter(),
+            failures: self.encode_failures.clone(),
+            failed: false,
+        }
+    }
+
+    /// Like `encode_chunks`, keeping the families `filter` selects.
+    pub fn encode_chunks_filtered(&self, format: Exposition, filter: &MetricFilter) -> EncodeChunks {
+        EncodeChunks {
+            format,
+            families: self.gather_filtered(filter).into_iter(),
+            failures: self.encode_failures.clone(),
+            failed: false,
+        }
+    }
+}
+
+/// Format of a `/metrics` response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Exposition {
+    /// The text format, version 0.0.4.
+    #[default]
+    Text,
+    /// Length-delimited `io.prometheus.client.MetricFamily` messages.
+    Protobuf,
+}
+
+impl Exposition {
+    /// The format preferred by the `Accept` header `accept`: protobuf when
+    /// it is offered with a quality at least as high as any other type's,
+    /// text otherwise (including OpenMetrics requests).
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let (mut protobuf, mut other) = (0.0f32, 0.0f32);
+        for range in accept.unwrap_or_default().split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default();
+            if media_type.is_empty() {
+                continue;
+            }
+            let (mut quality, mut message, mut encoding) = (1.0, None, None);
+            for (name, value) in parts.filter_map(|param| param.split_once('=')) {
+                let value = value.trim().trim_matches('"');
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "q" => quality = value.parse().unwrap_or(0.0),
+                    "proto" => message = Some(value),
+                    "encoding" => encoding = Some(value),
+                    _ => {}
+                }
+            }
+            let is_protobuf = media_type.eq_ignore_ascii_case("application/vnd.google.protobuf")
+                && message.is_none_or(|message| message == "io.prometheus.client.MetricFamily")
+                && encoding.is_none_or(|encoding| encoding == "delimited");
+            let best = if is_protobuf { &mut protobuf } else { &mut other };
+            *best = best.max(quality);
+        }
+        if protobuf > 0.0 && protobuf >= other { Self::Protobuf } else { Self::Text }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Text => "text/plain; version=0.0.4",
+            Self::Protobuf => PROTOBUF_FORMAT,
+        }
+    }
+
+    fn encode(self, families: &[MetricFamily], buffer: &mut Vec<u8>) -> prometheus::Result<()> {
+        match self {
+            Self::Text => TextEncoder::new().encode(families, buffer),
+            Self::Protobuf => ProtobufEncoder::new().encode(families, buffer),
+        }
+    }
+}
+
+/// Size from which `EncodeChunks` starts a new chunk.
+pub const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Iterator returned by `AppMetrics::render_chunks`.
+pub struct EncodeChunks {
+    format: Exposition,
+    families: std::vec::IntoIter<MetricFamily>,
+    failures: IntCounter,
+    failed: bool,
+}
+
+impl Iterator for EncodeChunks {
+    type Item = prometheus::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let mut chunk = Vec::with_capacity(CHUNK_BYTES);
+        while chunk.len() < CHUNK_BYTES {
+            let Some(family) = self.families.next() else { break };
+            if let Err(e) = self.format.encode(&[family], &mut chunk) {
+                self.failures.inc();
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        (!chunk.is_empty()).then_some(Ok(chunk))
+    }
+}
+
+impl Default for AppMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,377 @@
+//! Request middleware recording per-route HTTP metrics and server spans.
+//!
+//! Metrics are labeled with the matched route template (`/users/{id}`), never
+//! the raw path, so path parameters can't explode label cardinality.
+//!
+//! With the `traces` feature every request runs in a server span whose trace
+//! ID is returned in the `X-Trace-Id` response header (and optionally in a
+//! W3C `traceresponse` header), so an ID from a bug report leads straight to
+//! the trace. The span, and the handler, run in the context of the
+//! request's W3C baggage (see `baggage`).
+//!
+//! Requests to `telemetry_excluded_routes` (scrapes, health checks) run with
+//! OpenTelemetry suppressed instead: the SDK creates no spans and exports no
+//! logs for them, and they aren't counted in request metrics.
+//!
+//! A request whose handler (or an inner middleware) fails with an
+//! `actix_web::Error`, or that gets a 5xx, is recorded as failed: the server
+//! span gets an `error.type` attribute (the error's type, or the status code
+//! without an error), an `exception` event with the error's type and message,
+//! and, for a 5xx, the `Error` status; a 4xx is the client's failure and
+//! leaves the status unset, as the semantic conventions ask. A `Request
+//! failed` log with the method, route, status and error follows, at `error`
+//! for a 5xx and `warn` otherwise, inside the span so it carries its trace
+//! ID. Panics are left to the panic hook, which already recorded them.
+
+use crate::{
+    apdex::{ApdexCriteria, ApdexThresholds},
+    body_size::{count_request_into, SizedBody},
+    config::Config,
+    debug_session::{self, DebugSessions},
+    instrumentation::HttpInstrumentation,
+    metrics::AppMetrics,
+    observed_error::ObservedError,
+    panics,
+    sli::SliCriteria,
+};
+#[cfg(feature = "traces")]
+use crate::{baggage, client_labels::ClientAttributes};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    http::StatusCode,
+    web, Error,
+};
+#[cfg(feature = "traces")]
+use actix_web::http::header::{HeaderName, HeaderValue};
+#[cfg(feature = "traces")]
+use std::future::Future;
+#[cfg(all(feature = "logs", not(feature = "traces")))]
+use opentelemetry::{context::FutureExt, Context};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    baggage::BaggageExt,
+    global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context,
+};
+#[cfg(feature = "traces")]
+use opentelemetry::KeyValue;
+#[cfg(feature = "metrics")]
+use opentelemetry::metrics::Histogram;
+use std::time::Instant;
+use tracing::{error, warn};
+
+/// Label used for requests that didn't match any registered route, so
+/// unknown paths can't create new series.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Settings for `track_requests`, registered as app data.
+#[derive(Clone, Debug)]
+pub struct RequestTracking {
+    pub sli: SliCriteria,
+    pub apdex: ApdexCriteria,
+    /// Route patterns, as registered, whose requests are left out of request
+    /// metrics. Each also matches under `Config::endpoint_prefix`.
+    pub excluded_routes: Vec<String>,
+    /// Route patterns whose requests run with telemetry suppressed; also
+    /// part of `excluded_routes`.
+    pub suppressed_routes: Vec<String>,
+    /// Also return the W3C `traceresponse` header.
+    pub trace_response_header: bool,
+    /// Request latency mirrored over OTLP; `None` unless
+    /// `Config::metrics_native_histograms` is set (see `native_histogram`).
+    #[cfg(feature = "metrics")]
+    pub request_duration: Option<Histogram<f64>>,
+}
+
+impl RequestTracking {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            sli: SliCriteria {
+                latency_threshold: config.sli_latency_threshold,
+                bad_statuses: config.sli_bad_statuses.clone(),
+            },
+            apdex: ApdexCriteria {
+                default: ApdexThresholds::from_target(config.apdex_threshold),
+                routes: config.apdex_route_thresholds.clone(),
+            },
+            excluded_routes: config
+                .metrics_excluded_routes
+                .iter()
+                .chain(&config.telemetry_excluded_routes)
+                .flat_map(|route| [route.clone(), format!("{}{route}", config.endpoint_prefix)])
+                .collect(),
+            suppressed_routes: config
+                .telemetry_excluded_routes
+                .iter()
+                .flat_map(|route| [route.clone(), format!("{}{route}", config.endpoint_prefix)])
+                .collect(),
+            trace_response_header: config.trace_response_header,
+            #[cfg(feature = "metrics")]
+            request_duration: config.native_histograms().map(|opts| opts.request_duration()),
+        }
+    }
+
+    pub fn is_excluded(&self, route: &str) -> bool {
+        self.excluded_routes.iter().any(|r| r == route)
+    }
+
+    pub fn is_suppressed(&self, route: &str) -> bool {
+        self.suppressed_routes.iter().any(|r| r == route)
+    }
+}
+
+/// The route template the request matched, or `unmatched`.
+pub fn route_label(req: &ServiceRequest) -> String {
+    req.match_pattern().unwrap_or_else(|| UNMATCHED_ROUTE.to_string())
+}
+
+pub async fn track_requests(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let route = route_label(&req);
+    let tracking = req.app_data::<web::Data<RequestTracking>>().cloned();
+    let all_metrics = req.app_data::<web::Data<AppMetrics>>().cloned();
+    let metrics = match tracking {
+        Some(ref tracking) if tracking.is_excluded(&route) => None,
+        _ => all_metrics.clone(),
+    };
+    let method = req.method().as_str().to_string();
+    let session = req
+        .app_data::<web::Data<DebugSessions>>()
+        .and_then(|sessions| sessions.for_route(&route));
+    // Resolved at startup for the routes the app serves, see `route_handles`.
+    let handles = metrics.as_deref().and_then(|metrics| metrics.route_handles(&method, &route));
+    match (&metrics, handles) {
+        (Some(metrics), Some(handles)) => count_request_into(&mut req, handles.request_size(metrics).clone()),
+        (Some(metrics), None) => {
+            let labels = metrics.cardinality.limit("http_request_size_bytes", [method.as_str(), route.as_str()]);
+            metrics.body_sizes.count_request(&mut req, &labels);
+        }
+        (None, _) => {}
+    }
+    let client = metrics.as_deref().and_then(|metrics| metrics.client.as_ref()).map(|labels| labels.classify(&req));
+
+    #[cfg(any(feature = "traces", feature = "logs"))]
+    let suppressed = tracking.as_ref().is_some_and(|t| t.is_suppressed(&route));
+    #[cfg(feature = "traces")]
+    let traceresponse = tracking.as_ref().is_some_and(|t| t.trace_response_header);
+    #[cfg(feature = "traces")]
+    let path = req.path().to_string();
+    // A suppressed parent makes the server span, and every span and log
+    // record below it, a no-op.
+    #[cfg(feature = "traces")]
+    let parent = match baggage::extract(req.headers()) {
+        parent if suppressed => parent.with_telemetry_suppressed(),
+        parent => parent,
+    };
+    let call = panics::catch(next.call(req));
+    #[cfg(all(feature = "logs", not(feature = "traces")))]
+    let call = call.with_context(if suppressed {
+        Context::current().with_telemetry_suppressed()
+    } else {
+        Context::current()
+    });
+    #[cfg(feature = "traces")]
+    let call = call_traced(call, parent.clone(), &method, &route, path, client.as_ref(), traceresponse);
+    // The span has to start inside the session so `DebugSampler` sees it.
+    let result = match session {
+        Some(session) => debug_session::scope(session, call).await,
+        None => call.await,
+    };
+
+    let status = match &result {
+        Ok(res) => res.status().as_u16(),
+        Err(err) => err.as_response_error().status_code().as_u16(),
+    };
+    #[cfg(not(feature = "traces"))]
+    record_failure(&result, &method, &route);
+    // 5xx responses are counted by `responses::count_server_error`.
+    if result.is_err() && status >= 500 && let Some(metrics) = &all_metrics {
+        metrics.responses.server_error();
+    }
+    if let Some(metrics) = &all_metrics
+        && let Some(error) = match &result {
+            Ok(res) => res.response().error(),
+            Err(err) => Some(err),
+        }
+    {
+        metrics.errors.observe(error);
+    }
+    let result = result.map(|res| match (&metrics, handles) {
+        (Some(metrics), Some(handles)) => {
+            let histogram = handles.response_size(metrics).clone();
+            res.map_body(|_, body| SizedBody::counted(body, histogram))
+        }
+        (Some(metrics), None) => {
+            let labels = metrics.cardinality.limit("http_response_size_bytes", [method.as_str(), route.as_str()]);
+            res.map_body(|_, body| metrics.body_sizes.count_response(body, &labels))
+        }
+        (None, _) => res.map_body(|_, body| SizedBody::uncounted(body)),
+    });
+    if let (Some(metrics), Some(tracking)) = (&metrics, &tracking) {
+        let elapsed = started.elapsed();
+        match handles {
+            Some(handles) => handles.observe(metrics, tracking, status, elapsed),
+            None => HttpInstrumentation::observe(metrics, tracking, &method, &route, status, elapsed),
+        }
+        HttpInstrumentation::observe_outcome(metrics, tracking, &method, &route, status, elapsed);
+        if let (Some(labels), Some(client)) = (&metrics.client, &client) {
+            labels.observe(client);
+        }
+        #[cfg(feature = "traces")]
+        if let Some(baggage_metrics) = &metrics.baggage {
+            baggage_metrics.observe(&metrics.cardinality, &method, &route, parent.baggage());
+        }
+    }
+    result
+}
+
+#[cfg(feature = "traces")]
+const X_TRACE_ID: HeaderName = HeaderName::from_static("x-trace-id");
+#[cfg(feature = "traces")]
+const TRACERESPONSE: HeaderName = HeaderName::from_static("traceresponse");
+
+/// Runs `call`, the rest of the chain, in a server span named after the
+/// route template, child of `parent`, and adds the trace ID headers to the
+/// response.
+#[cfg(feature = "traces")]
+async fn call_traced<B: MessageBody>(
+    call: impl Future<Output = Result<ServiceResponse<B>, Error>>,
+    parent: Context,
+    method: &str,
+    route: &str,
+    path: String,
+    client: Option<&ClientAttributes>,
+    traceresponse: bool,
+) -> Result<ServiceResponse<B>, Error> {
+    let tracer = global::tracer("prom_otel");
+    let name = if route == UNMATCHED_ROUTE { method.to_string() } else { format!("{method} {route}") };
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_attributes(
+            [
+                KeyValue::new("http.request.method", method.to_string()),
+                KeyValue::new("http.route", route.to_string()),
+                KeyValue::new("url.path", path),
+            ]
+            .into_iter()
+            .chain(client.into_iter().flat_map(ClientAttributes::key_values)),
+        )
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let mut result = call.with_context(cx.clone()).await;
+
+    let span = cx.span();
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    span.set_attribute(KeyValue::new("http.response.status_code", i64::from(status.as_u16())));
+    {
+        let _guard = cx.clone().attach();
+        record_failure(&result, method, route);
+    }
+    let span_context = span.span_context().clone();
+    if let Ok(res) = &mut result
+        && span_context.is_valid()
+    {
+        let headers = res.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&span_context.trace_id().to_string()) {
+            headers.insert(X_TRACE_ID, value);
+        }
+        if traceresponse {
+            let value = format!(
+                "00-{}-{}-{:02x}",
+                span_context.trace_id(),
+                span_context.span_id(),
+                span_context.trace_flags().to_u8()
+            );
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(TRACERESPONSE, value);
+            }
+        }
+    }
+    span.end();
+    result
+}
+
+/// `error.type` of a failed request: the code of an `ObservedError`, else
+/// the name of the error's type when its `Debug` output starts with one, the
+/// status code otherwise.
+fn error_type(status: StatusCode, error: Option<&Error>) -> String {
+    if let Some(error) = error.and_then(|e| e.as_error::<ObservedError>()) {
+        return error.code().to_string();
+    }
+    error
+        .map(|e| format!("{:?}", e.as_response_error()))
+        .and_then(|debug| {
+            let name = debug.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).next()?;
+            name.starts_with(|c: char| c.is_ascii_uppercase()).then(|| name.to_string())
+        })
+        .unwrap_or_else(|| status.as_str().to_string())
+}
+
+/// Records a request that failed with an error or a 5xx on the active span
+/// and in a log, see the module documentation.
+fn record_failure<B>(result: &Result<ServiceResponse<B>, Error>, method: &str, route: &str) {
+    let (status, error) = match result {
+        Ok(res) => (res.status(), res.response().error()),
+        Err(err) => (err.as_response_error().status_code(), Some(err)),
+    };
+    if error.is_none() && !status.is_server_error() {
+        return;
+    }
+    let message = error.map_or_else(|| status.canonical_reason().unwrap_or_default().to_string(), ToString::to_string);
+    #[cfg(feature = "traces")]
+    let cx = Context::current();
+    #[cfg(feature = "traces")]
+    let span = cx.span();
+    #[cfg(feature = "traces")]
+    if status.is_server_error() {
+        span.set_status(Status::error(message.clone()));
+    }
+    if error.is_some_and(panics::is_panic) {
+        return;
+    }
+    let error_type = error_type(status, error);
+    #[cfg(feature = "traces")]
+    {
+        span.set_attribute(KeyValue::new("error.type", error_type.clone()));
+        // An `ObservedError` recorded itself when it was created.
+        if error.is_some_and(|e| e.as_error::<ObservedError>().is_none()) {
+            span.add_event(
+                "exception",
+                vec![
+                    KeyValue::new("exception.type", error_type.clone()),
+                    KeyValue::new("exception.message", message.clone()),
+                ],
+            );
+        }
+    }
+    if status.is_server_error() {
+        error!(
+            http.request.method = method,
+            http.route = route,
+            http.response.status_code = status.as_u16(),
+            "error.type" = error_type,
+            error.message = message,
+            "Request failed"
+        );
+    } else {
+        warn!(
+            http.request.method = method,
+            http.route = route,
+            http.response.status_code = status.as_u16(),
+            "error.type" = error_type,
+            error.message = message,
+            "Request failed"
+        );
+    }
+}
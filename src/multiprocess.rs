@@ -0,0 +1,239 @@
+//! Metrics aggregated across worker processes (`METRICS_MULTIPROCESS_DIR`).
+//!
+//! When a supervisor forks several copies of the service behind one port,
+//! a scrape reaches a single process and sees only its share of the traffic.
+//! With `METRICS_MULTIPROCESS_DIR` set, every process writes its registry to
+//! `<dir>/<pid>-<start time>.prom` every `METRICS_MULTIPROCESS_INTERVAL_MS`
+//! (default 1 s), and `/metrics` on any of them serves its own series merged
+//! with those of the other files:
+//!
+//! - counters, and the counts, sums and buckets of histograms and summaries,
+//!   are summed; summary quantiles can't be and are dropped;
+//! - gauges are summed or, with `METRICS_MULTIPROCESS_GAUGE_MODE=max`, the
+//!   highest value is served.
+//!
+//! A file not rewritten for 3 intervals belongs to a process that exited:
+//! its counters, histograms and summaries still count, so totals don't go
+//! backwards when a worker is replaced, but its gauges don't. As with
+//! Python's multiprocess mode, the supervisor should empty the directory
+//! before starting the workers. A native histogram summed over several
+//! processes is served as a classic one. `/metrics/catalog`, `/metrics/stream` and
+//! the OTLP bridge only see the local registry.
+
+use crate::{federation, registry::Registry};
+use prometheus::{
+    proto::{MetricFamily, MetricType},
+    Encoder, TextEncoder,
+};
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Files not rewritten for this many intervals are of exited processes.
+const STALE_INTERVALS: u32 = 3;
+
+/// How the gauges of several processes are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GaugeMode {
+    #[default]
+    Sum,
+    Max,
+}
+
+impl FromStr for GaugeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sum" => Ok(Self::Sum),
+            "max" => Ok(Self::Max),
+            _ => Err(format!("unknown gauge mode {s:?}, expected `sum` or `max`")),
+        }
+    }
+}
+
+impl fmt::Display for GaugeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sum => "sum",
+            Self::Max => "max",
+        })
+    }
+}
+
+/// This process's file in the shared directory and how to read the others.
+#[derive(Clone, Debug)]
+pub struct Multiprocess {
+    dir: PathBuf,
+    file: PathBuf,
+    interval: Duration,
+    gauge_mode: GaugeMode,
+}
+
+impl Multiprocess {
+    pub fn new(dir: PathBuf, interval: Duration, gauge_mode: GaugeMode) -> Self {
+        // The start time keeps a recycled pid from overwriting the file of
+        // an exited process.
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis());
+        let file = dir.join(format!("{}-{started}.prom", std::process::id()));
+        Self { dir, file, interval, gauge_mode }
+    }
+
+    /// Writes the families of `registry` to this process's file, through a
+    /// temporary one so other processes never read half of it.
+    pub fn publish(&self, registry: &Registry) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&registry.gather(), &mut buffer).map_err(io::Error::other)?;
+        let partial = self.file.with_extension("tmp");
+        fs::write(&partial, buffer)?;
+        fs::rename(partial, &self.file)
+    }
+
+    /// `local`, this process's families, merged with those of the other
+    /// processes' files. Unreadable files are skipped.
+    pub fn aggregate(&self, local: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        let mut merged = Merged { gauge_mode: self.gauge_mode, ..Merged::default() };
+        merged.add(local, true);
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return merged.families;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path == self.file || path.extension().is_none_or(|extension| extension != "prom") {
+                continue;
+            }
+            let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) else {
+                continue;
+            };
+            let Ok(text) = fs::read_to_string(&path) else {
+                continue;
+            };
+            // A clock step back makes the file look fresh, not stale.
+            let live = modified.elapsed().unwrap_or_default() < self.interval * STALE_INTERVALS;
+            merged.add(federation::parse(&text), live);
+        }
+        merged.families.sort_by(|a, b| a.name().cmp(b.name()));
+        merged.families
+    }
+}
+
+#[derive(Default)]
+struct Merged {
+    gauge_mode: GaugeMode,
+    families: Vec<MetricFamily>,
+    family_index: HashMap<String, usize>,
+    /// Series by family index and sorted label pairs.
+    series_index: HashMap<(usize, Vec<(String, String)>), usize>,
+}
+
+impl Merged {
+    /// Adds the series of `families`, skipping their gauges unless `live`.
+    /// A family whose type differs from the one seen first is skipped.
+    fn add(&mut self, families: Vec<MetricFamily>, live: bool) {
+        for mut family in families {
+            let kind = match family.get_field_type() {
+                // Files read them back as gauges, see `federation::parse`.
+                MetricType::UNTYPED => {
+                    for metric in family.mut_metric().iter_mut() {
+                        let value = metric.untyped.value();
+                        metric.gauge.mut_or_insert_default().set_value(value);
+                        metric.untyped.clear();
+                    }
+                    MetricType::GAUGE
+                }
+                kind => kind,
+            };
+            if kind == MetricType::GAUGE && !live {
+                continue;
+            }
+            let index = *self.family_index.entry(family.name().to_string()).or_insert_with(|| {
+                let mut empty = MetricFamily::default();
+                empty.set_name(family.name().to_string());
+                empty.set_help(family.help().to_string());
+                empty.set_field_type(kind);
+                self.families.push(empty);
+                self.families.len() - 1
+            });
+            if self.families[index].get_field_type() != kind {
+                continue;
+            }
+            for mut metric in family.take_metric() {
+                metric.clear_timestamp_ms();
+                if let Some(summary) = metric.summary.as_mut() {
+                    summary.quantile.clear();
+                }
+                let mut key: Vec<(String, String)> =
+                    metric.get_label().iter().map(|pair| (pair.name().to_string(), pair.value().to_string())).collect();
+                key.sort();
+                let metrics = self.families[index].mut_metric();
+                let Some(&position) = self.series_index.get(&(index, key.clone())) else {
+                    self.series_index.insert((index, key), metrics.len());
+                    metrics.push(metric);
+                    continue;
+                };
+                let total = &mut metrics[position];
+                match kind {
+                    MetricType::COUNTER => {
+                        let counter = total.counter.mut_or_insert_default();
+                        counter.set_value(counter.value() + metric.get_counter().value());
+                    }
+                    MetricType::GAUGE | MetricType::UNTYPED => {
+                        let gauge = total.gauge.mut_or_insert_default();
+                        let value = metric.get_gauge().value();
+                        gauge.set_value(match self.gauge_mode {
+                            GaugeMode::Sum => gauge.value() + value,
+                            GaugeMode::Max => gauge.value().max(value),
+                        });
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = total.histogram.mut_or_insert_default();
+                        let other = metric.get_histogram();
+                        // Only the local histograms are native, see
+                        // `native_histogram`; a sum of series can't be.
+                        histogram.special_fields.mut_unknown_fields().clear();
+                        histogram.set_sample_count(histogram.sample_count() + other.sample_count());
+                        histogram.set_sample_sum(histogram.sample_sum() + other.sample_sum());
+                        // Processes of one binary share their bucket layouts;
+                        // a bound only one of them has is kept as is.
+                        for bucket in other.get_bucket() {
+                            match histogram.bucket.iter_mut().find(|own| own.upper_bound() == bucket.upper_bound()) {
+                                Some(own) => own.set_cumulative_count(own.cumulative_count() + bucket.cumulative_count()),
+                                None => histogram.bucket.push(bucket.clone()),
+                            }
+                        }
+                        histogram.bucket.sort_by(|a, b| a.upper_bound().total_cmp(&b.upper_bound()));
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = total.summary.mut_or_insert_default();
+                        let other = metric.get_summary();
+                        summary.set_sample_count(summary.sample_count() + other.sample_count());
+                        summary.set_sample_sum(summary.sample_sum() + other.sample_sum());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Publishes `registry` every interval. Failures are logged when writing
+/// starts failing, not on every attempt.
+pub async fn publish(multiprocess: Multiprocess, registry: Registry) {
+    let mut ticker = tokio::time::interval(multiprocess.interval);
+    let mut failing = false;
+    loop {
+        ticker.tick().await;
+        let result = multiprocess.publish(&registry);
+        match &result {
+            Err(e) if !failing => {
+                tracing::warn!(path = %multiprocess.file.display(), error = %e, "Cannot write the multiprocess metrics file");
+            }
+            Ok(()) if failing => tracing::info!(path = %multiprocess.file.display(), "Multiprocess metrics file written again"),
+            _ => {}
+        }
+        failing = result.is_err();
+    }
+}
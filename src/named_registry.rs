@@ -0,0 +1,99 @@
+//! Registries of embedded libraries merged into `/metrics`.
+//!
+//! A library keeping its metrics in its own `Registry` can contribute them
+//! through `AppMetrics::merge_registry`, or get a fresh one from
+//! `AppMetrics::add_registry`. At gather time its families are renamed
+//! `<prefix>_<name>` and its series get the registry's constant labels (e.g.
+//! `component="ingest"`) on top of the resource labels, so two libraries
+//! both exporting `requests_total` don't collide. Families that still end up
+//! with the same name as another one are merged into it, so prefixes should
+//! be distinct.
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{LabelPair, MetricFamily},
+    Registry,
+};
+use std::{collections::HashMap, fmt};
+
+/// A registry gathered as part of another one, see the module documentation.
+#[derive(Clone)]
+pub struct NamedRegistry {
+    /// `registry_<name>`, without series; it makes registering the same name
+    /// twice fail.
+    desc: Desc,
+    registry: Registry,
+    prefix: Option<String>,
+    const_labels: Vec<LabelPair>,
+}
+
+impl fmt::Debug for NamedRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedRegistry").field("name", &self.desc.fq_name).field("prefix", &self.prefix).finish_non_exhaustive()
+    }
+}
+
+impl NamedRegistry {
+    pub fn new(
+        name: &str,
+        registry: Registry,
+        prefix: Option<&str>,
+        const_labels: HashMap<String, String>,
+    ) -> prometheus::Result<Self> {
+        if let Some(prefix) = prefix
+            && !is_valid_prefix(prefix)
+        {
+            return Err(prometheus::Error::Msg(format!("{prefix:?} is not a valid metric name prefix")));
+        }
+        // Also validates the name and label names.
+        let desc = Desc::new(
+            format!("registry_{name}"),
+            format!("Families merged from registry {name}"),
+            Vec::new(),
+            const_labels.clone(),
+        )?;
+        let mut const_labels: Vec<LabelPair> = const_labels
+            .into_iter()
+            .map(|(name, value)| {
+                let mut label = LabelPair::default();
+                label.set_name(name);
+                label.set_value(value);
+                label
+            })
+            .collect();
+        const_labels.sort_by(|a, b| a.name().cmp(b.name()));
+        Ok(Self { desc, registry, prefix: prefix.map(str::to_string), const_labels })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+}
+
+impl Collector for NamedRegistry {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.registry.gather();
+        for family in &mut families {
+            if let Some(prefix) = &self.prefix {
+                let name = format!("{prefix}_{}", family.name());
+                family.set_name(name);
+            }
+            for metric in family.mut_metric().iter_mut() {
+                let mut labels = self.const_labels.clone();
+                labels.append(&mut metric.take_label());
+                metric.set_label(labels);
+            }
+        }
+        families
+    }
+}
+
+/// `[a-zA-Z_:][a-zA-Z0-9_:]*`, like metric names.
+fn is_valid_prefix(prefix: &str) -> bool {
+    !prefix.is_empty()
+        && prefix.chars().enumerate().all(|(i, c)| c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit()))
+}
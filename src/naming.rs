@@ -0,0 +1,154 @@
+//! Prometheus naming conventions for metric families.
+//!
+//! A family violates them when
+//!
+//! - `invalid_name`: its name isn't `[a-zA-Z_][a-zA-Z0-9_]*`; colons are
+//!   left to recording rules;
+//! - `counter_total`: it is a counter whose name doesn't end in `_total`;
+//! - `total_suffix`: it isn't a counter but its name ends in `_total`;
+//! - `base_unit`: its unit suffix isn't a base unit, e.g. `_ms` or
+//!   `_megabytes` rather than `_seconds` or `_bytes`;
+//! - `reserved_label`: it has a label Prometheus reserves (`__*`, `le`,
+//!   `quantile`) or sets on scrape (`job`, `instance`).
+//!
+//! `AppMetrics::register` checks collectors before registering them:
+//! violations are logged as warnings, or with `METRICS_NAMING_STRICT`
+//! returned as an error. `GET /metrics/naming` lists the violations of every
+//! family served on `/metrics`, including those registered on the registry
+//! directly or merged from another one, so CI can fail on them.
+
+use crate::catalog::type_name;
+use prometheus::{
+    core::Collector,
+    proto::{MetricFamily, MetricType},
+};
+use serde_json::{json, Value};
+
+/// Labels a family may not have.
+const RESERVED_LABELS: &[&str] = &["le", "quantile", "job", "instance"];
+
+/// Unit suffixes that aren't base units, and the base unit to use instead.
+const NON_BASE_UNITS: &[(&str, &str)] = &[
+    ("ms", "seconds"),
+    ("millis", "seconds"),
+    ("milliseconds", "seconds"),
+    ("us", "seconds"),
+    ("micros", "seconds"),
+    ("microseconds", "seconds"),
+    ("ns", "seconds"),
+    ("nanos", "seconds"),
+    ("nanoseconds", "seconds"),
+    ("secs", "seconds"),
+    ("minutes", "seconds"),
+    ("hours", "seconds"),
+    ("days", "seconds"),
+    ("kb", "bytes"),
+    ("kib", "bytes"),
+    ("kilobytes", "bytes"),
+    ("mb", "bytes"),
+    ("mib", "bytes"),
+    ("megabytes", "bytes"),
+    ("gb", "bytes"),
+    ("gib", "bytes"),
+    ("gigabytes", "bytes"),
+    ("bits", "bytes"),
+];
+
+/// One broken convention of a family.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub metric: String,
+    /// `invalid_name`, `counter_total`, `total_suffix`, `base_unit` or
+    /// `reserved_label`.
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Violation {
+    fn new(metric: &str, rule: &'static str, message: String) -> Self {
+        Self { metric: metric.to_string(), rule, message }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "metric": self.metric, "rule": self.rule, "message": self.message })
+    }
+}
+
+/// The violations of a family `name` of type `kind` with `labels`. Type
+/// rules are skipped for untyped families.
+pub fn check<'a>(name: &str, kind: MetricType, labels: impl IntoIterator<Item = &'a str>) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        violations.push(Violation::new(name, "invalid_name", format!("{name:?} isn't a valid metric name")));
+    }
+    match kind {
+        MetricType::COUNTER if !name.ends_with("_total") => {
+            violations.push(Violation::new(name, "counter_total", format!("counter {name} should end in `_total`")));
+        }
+        MetricType::GAUGE | MetricType::HISTOGRAM | MetricType::SUMMARY if name.ends_with("_total") => {
+            violations.push(Violation::new(
+                name,
+                "total_suffix",
+                format!("{} {name} shouldn't end in `_total`, which is for counters", type_name(kind)),
+            ));
+        }
+        _ => {}
+    }
+    let unit = name.strip_suffix("_total").unwrap_or(name).rsplit('_').next().unwrap_or_default().to_ascii_lowercase();
+    if let Some((_, base)) = NON_BASE_UNITS.iter().find(|(suffix, _)| *suffix == unit) {
+        violations.push(Violation::new(
+            name,
+            "base_unit",
+            format!("{name} is in `{unit}`; use the base unit, `_{base}`"),
+        ));
+    }
+    for label in labels {
+        if label.starts_with("__") || RESERVED_LABELS.contains(&label) {
+            violations.push(Violation::new(name, "reserved_label", format!("{name} uses the reserved label {label:?}")));
+        }
+    }
+    violations
+}
+
+/// The violations of the family `family`, with the labels of its first
+/// series.
+pub fn check_family(family: &MetricFamily) -> Vec<Violation> {
+    let labels = family.get_metric().first().map(|metric| metric.get_label()).unwrap_or_default();
+    check(family.name(), family.get_field_type(), labels.iter().map(|label| label.name()))
+}
+
+/// The violations of the families `collector` describes, typed by what it
+/// collects.
+pub fn check_collector(collector: &dyn Collector) -> Vec<Violation> {
+    let families = collector.collect();
+    collector
+        .desc()
+        .iter()
+        .flat_map(|desc| {
+            let kind = families
+                .iter()
+                .find(|family| family.name() == desc.fq_name)
+                .map_or(MetricType::UNTYPED, MetricFamily::get_field_type);
+            let labels = desc
+                .variable_labels
+                .iter()
+                .map(String::as_str)
+                .chain(desc.const_label_pairs.iter().map(|pair| pair.name()));
+            check(&desc.fq_name, kind, labels)
+        })
+        .collect()
+}
+
+/// `{"strict", "violations": [{"metric", "rule", "message"}, ...]}` for
+/// `families`, sorted by metric.
+pub fn report(families: &[MetricFamily], strict: bool) -> Value {
+    let mut violations: Vec<Violation> = families.iter().flat_map(check_family).collect();
+    violations.sort_by(|a, b| a.metric.cmp(&b.metric));
+    json!({
+        "strict": strict,
+        "violations": violations.iter().map(Violation::to_json).collect::<Vec<_>>(),
+    })
+}
@@ -0,0 +1,227 @@
+//! Prometheus native histograms (`METRICS_NATIVE_HISTOGRAMS`).
+//!
+//! Classic buckets make us choose between resolution and series: a native
+//! histogram has exponential buckets, `2^(2^-schema)` wide, of which only
+//! the ones that got observations are tracked, so the latency of a route is
+//! resolved to a few percent whatever its range. Schema 3 (the default)
+//! splits each power of two into 8 buckets. When a histogram gets more than
+//! `METRICS_NATIVE_HISTOGRAM_MAX_BUCKETS` buckets its schema is lowered,
+//! merging neighbouring buckets pairwise, down to -4.
+//!
+//! The `prometheus` crate's protobuf messages predate native histograms, so
+//! `encode` adds their fields to the `Histogram` message as unknown fields,
+//! with the numbers of `io.prometheus.client`'s `metrics.proto`. Only the
+//! protobuf exposition carries them; the text format keeps the classic
+//! buckets, which are still tracked.
+//!
+//! With the `metrics` feature, request latency is also recorded in the OTel
+//! `http.server.request.duration` histogram, aggregated as a base-2
+//! exponential histogram with the same schema (OTel's scale) and bucket
+//! limit, so both backends see the same resolution.
+
+#[cfg(feature = "metrics")]
+use opentelemetry::metrics;
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::{Aggregation, Instrument, Stream};
+use prometheus::proto::Histogram;
+use protobuf::CodedOutputStream;
+use std::collections::BTreeMap;
+
+/// The lowest and highest schemas Prometheus accepts.
+pub const MIN_SCHEMA: i32 = -4;
+pub const MAX_SCHEMA: i32 = 8;
+
+/// Observations this close to zero go to the zero bucket; the Go client's
+/// default.
+const ZERO_THRESHOLD: f64 = 2.938735877055719e-39; // 2^-128
+
+// Field numbers of `io.prometheus.client.Histogram` and `BucketSpan`.
+const SCHEMA_FIELD: u32 = 5;
+const ZERO_THRESHOLD_FIELD: u32 = 6;
+const ZERO_COUNT_FIELD: u32 = 7;
+const NEGATIVE_SPAN_FIELD: u32 = 9;
+const NEGATIVE_DELTA_FIELD: u32 = 10;
+const POSITIVE_SPAN_FIELD: u32 = 12;
+const POSITIVE_DELTA_FIELD: u32 = 13;
+const SPAN_OFFSET_FIELD: u32 = 1;
+const SPAN_LENGTH_FIELD: u32 = 2;
+
+/// The OTel instrument request latency is mirrored to, in seconds.
+pub const OTEL_REQUEST_DURATION: &str = "http.server.request.duration";
+
+/// Starting resolution and bucket limit of native histograms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NativeHistogramOpts {
+    pub schema: i32,
+    pub max_buckets: usize,
+}
+
+#[cfg(feature = "metrics")]
+impl NativeHistogramOpts {
+    /// The OTel histogram to record request latency in. Create it once the
+    /// meter provider is installed.
+    pub fn request_duration(&self) -> metrics::Histogram<f64> {
+        opentelemetry::global::meter("prom_otel.http")
+            .f64_histogram(OTEL_REQUEST_DURATION)
+            .with_unit("s")
+            .with_description("HTTP request latency by route template")
+            .build()
+    }
+
+    /// The exponential histogram stream of `OTEL_REQUEST_DURATION`, `None`
+    /// for other instruments.
+    pub fn stream(&self, instrument: &Instrument) -> Option<Stream> {
+        if instrument.name() != OTEL_REQUEST_DURATION {
+            return None;
+        }
+        Stream::builder()
+            .with_aggregation(Aggregation::Base2ExponentialHistogram {
+                max_size: self.max_buckets.try_into().unwrap_or(u32::MAX),
+                max_scale: self.schema as i8,
+                record_min_max: true,
+            })
+            .build()
+            .inspect_err(|e| tracing::warn!(error = %e, "Ignoring the exponential request latency view"))
+            .ok()
+    }
+}
+
+/// The sparse buckets of one native histogram.
+#[derive(Clone, Debug)]
+pub struct NativeBuckets {
+    schema: i32,
+    zero_count: u64,
+    /// Counts by bucket index; bucket `i` holds `(base^(i-1), base^i]`.
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+}
+
+impl NativeBuckets {
+    pub fn new(schema: i32) -> Self {
+        Self {
+            schema: schema.clamp(MIN_SCHEMA, MAX_SCHEMA),
+            zero_count: 0,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+        }
+    }
+
+    /// Counts `v`, lowering the schema while there are more than
+    /// `max_buckets` buckets. NaN isn't counted.
+    pub fn observe(&mut self, v: f64, max_buckets: usize) {
+        if v.is_nan() {
+            return;
+        }
+        if v.abs() <= ZERO_THRESHOLD {
+            self.zero_count += 1;
+            return;
+        }
+        let index = bucket_index(v.abs(), self.schema);
+        let buckets = if v > 0.0 { &mut self.positive } else { &mut self.negative };
+        *buckets.entry(index).or_default() += 1;
+        self.limit(max_buckets);
+    }
+
+    /// Adds the counts of `other`, at the lower of both schemas.
+    pub fn merge(&mut self, other: &NativeBuckets, max_buckets: usize) {
+        while self.schema > other.schema {
+            self.halve();
+        }
+        let mut other = other.clone();
+        while other.schema > self.schema {
+            other.halve();
+        }
+        self.zero_count += other.zero_count;
+        for (index, count) in other.positive {
+            *self.positive.entry(index).or_default() += count;
+        }
+        for (index, count) in other.negative {
+            *self.negative.entry(index).or_default() += count;
+        }
+        self.limit(max_buckets);
+    }
+
+    fn limit(&mut self, max_buckets: usize) {
+        while self.positive.len() + self.negative.len() > max_buckets && self.schema > MIN_SCHEMA {
+            self.halve();
+        }
+    }
+
+    /// Lowers the schema by one: bucket `i` becomes bucket `ceil(i / 2)`.
+    fn halve(&mut self) {
+        self.schema -= 1;
+        for buckets in [&mut self.positive, &mut self.negative] {
+            let mut halved = BTreeMap::new();
+            for (index, count) in std::mem::take(buckets) {
+                *halved.entry((index + 1) >> 1).or_default() += count;
+            }
+            *buckets = halved;
+        }
+    }
+
+    /// Sets the native histogram fields of `histogram`.
+    pub fn encode(&self, histogram: &mut Histogram) {
+        let fields = histogram.special_fields.mut_unknown_fields();
+        fields.add_varint(SCHEMA_FIELD, zigzag(self.schema.into()));
+        fields.add_fixed64(ZERO_THRESHOLD_FIELD, ZERO_THRESHOLD.to_bits());
+        fields.add_varint(ZERO_COUNT_FIELD, self.zero_count);
+        for (buckets, span_field, delta_field) in [
+            (&self.negative, NEGATIVE_SPAN_FIELD, NEGATIVE_DELTA_FIELD),
+            (&self.positive, POSITIVE_SPAN_FIELD, POSITIVE_DELTA_FIELD),
+        ] {
+            for (offset, length) in spans(buckets) {
+                fields.add_length_delimited(span_field, encode_span(offset, length));
+            }
+            let mut previous = 0i64;
+            for &count in buckets.values() {
+                let count = count as i64;
+                fields.add_varint(delta_field, zigzag(count - previous));
+                previous = count;
+            }
+        }
+        // Without a span or zero observation, scrapers would take an empty
+        // histogram for a classic one.
+        if self.positive.is_empty() && self.negative.is_empty() && self.zero_count == 0 {
+            fields.add_length_delimited(POSITIVE_SPAN_FIELD, encode_span(0, 0));
+        }
+    }
+}
+
+/// The index of the bucket `v` (positive) falls in: `ceil(log2(v) * 2^schema)`.
+fn bucket_index(v: f64, schema: i32) -> i32 {
+    (v.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+/// Runs of consecutive bucket indexes as `(offset, length)`: the first
+/// offset is the index of the first bucket, the others the gap since the
+/// end of the previous run.
+fn spans(buckets: &BTreeMap<i32, u64>) -> Vec<(i32, u32)> {
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut next = 0;
+    for &index in buckets.keys() {
+        match spans.last_mut() {
+            Some((_, length)) if index == next => *length += 1,
+            last => {
+                let offset = if last.is_some() { index - next } else { index };
+                spans.push((offset, 1));
+            }
+        }
+        next = index + 1;
+    }
+    spans
+}
+
+fn encode_span(offset: i32, length: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut stream = CodedOutputStream::vec(&mut bytes);
+    // Writing to a `Vec` can't fail.
+    let _ = stream.write_sint32(SPAN_OFFSET_FIELD, offset);
+    let _ = stream.write_uint32(SPAN_LENGTH_FIELD, length);
+    let _ = stream.flush();
+    drop(stream);
+    bytes
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
@@ -0,0 +1,152 @@
+//! Per-handler instrumentation.
+//!
+//! `track_requests` already gives every request a server span and route
+//! metrics. Wrapping a handler with `observed` adds what only the handler
+//! knows about, without code in the handler itself:
+//!
+//! ```ignore
+//! App::new().route("/users/{id}", web::get().to(observed(&metrics, "get_user", get_user)))
+//! ```
+//!
+//! - the request is counted in `http_requests_total`;
+//! - its latency, from the handler's start to its response, is observed in
+//!   `http_handler_duration_seconds{handler,outcome}`, where `outcome` is
+//!   `error` when the handler returned an `Err` or a 5xx response, `ok`
+//!   otherwise;
+//! - with the `traces` feature the handler runs in an internal span named
+//!   after it, child of the server span, with `code.function.name`,
+//!   `http.request.method`, `http.route` and `http.response.status_code`. An
+//!   `Err` is recorded on it as an `exception` event and marks it failed, as
+//!   does a 5xx response.
+//!
+//! Handler names label metrics and should be a fixed set, hence
+//! `&'static str`. A panicking handler is handled by `track_requests`.
+
+use crate::{metrics::AppMetrics, registry::Registry, sharded::ShardedCounter};
+#[cfg(feature = "traces")]
+use crate::observed_error::is_observed;
+use actix_web::{body::BoxBody, FromRequest, Handler, HttpRequest, HttpResponse, Responder};
+use futures_util::future::LocalBoxFuture;
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use prometheus::{HistogramOpts, HistogramVec};
+use std::time::Instant;
+
+/// `http_handler_duration_seconds`, shared by all observed handlers.
+#[derive(Clone, Debug)]
+pub struct HandlerMetrics {
+    pub duration: HistogramVec,
+}
+
+impl HandlerMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let duration = HistogramVec::new(
+            HistogramOpts::new("http_handler_duration_seconds", "Latency of handlers wrapped with `observed`"),
+            &["handler", "outcome"],
+        )?;
+        registry.register(Box::new(duration.clone()))?;
+        Ok(Self { duration })
+    }
+}
+
+/// Wraps `handler` so its requests are counted, timed and traced under
+/// `name`; see the module documentation.
+pub fn observed<F>(metrics: &AppMetrics, name: &'static str, handler: F) -> Observed<F> {
+    Observed { handler, name, metrics: metrics.handlers.clone(), requests: metrics.request_counter.clone() }
+}
+
+/// A handler wrapped by `observed`.
+#[derive(Clone)]
+pub struct Observed<F> {
+    handler: F,
+    name: &'static str,
+    metrics: HandlerMetrics,
+    requests: ShardedCounter,
+}
+
+impl<F, Args> Handler<Args> for Observed<F>
+where
+    F: Handler<Args>,
+    F::Output: Responder,
+    Args: FromRequest + 'static,
+{
+    type Output = ObservedResponse<F::Output>;
+    type Future = LocalBoxFuture<'static, Self::Output>;
+
+    fn call(&self, args: Args) -> Self::Future {
+        let Observed { handler, name, metrics, requests } = self.clone();
+        Box::pin(async move {
+            requests.inc();
+            let started = Instant::now();
+            #[cfg(feature = "traces")]
+            {
+                let tracer = global::tracer("prom_otel");
+                let span = tracer
+                    .span_builder(name)
+                    .with_kind(SpanKind::Internal)
+                    .with_attributes([KeyValue::new("code.function.name", name)])
+                    .start(&tracer);
+                let cx = Context::current_with_span(span);
+                let inner = handler.call(args).with_context(cx.clone()).await;
+                ObservedResponse { inner, name, metrics, started, cx }
+            }
+            #[cfg(not(feature = "traces"))]
+            {
+                let inner = handler.call(args).await;
+                ObservedResponse { inner, name, metrics, started }
+            }
+        })
+    }
+}
+
+/// The response of an observed handler, recorded once it is turned into an
+/// `HttpResponse`.
+pub struct ObservedResponse<R> {
+    inner: R,
+    name: &'static str,
+    metrics: HandlerMetrics,
+    started: Instant,
+    #[cfg(feature = "traces")]
+    cx: Context,
+}
+
+impl<R: Responder> Responder for ObservedResponse<R> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let res = self.inner.respond_to(req).map_into_boxed_body();
+        let status = res.status();
+        let outcome = if res.error().is_some() || status.is_server_error() { "error" } else { "ok" };
+        self.metrics
+            .duration
+            .with_label_values(&[self.name, outcome])
+            .observe(self.started.elapsed().as_secs_f64());
+
+        #[cfg(feature = "traces")]
+        {
+            let span = self.cx.span();
+            span.set_attribute(KeyValue::new("http.request.method", req.method().to_string()));
+            if let Some(route) = req.match_pattern() {
+                span.set_attribute(KeyValue::new("http.route", route));
+            }
+            span.set_attribute(KeyValue::new("http.response.status_code", i64::from(status.as_u16())));
+            if let Some(e) = res.error() {
+                // An `ObservedError` recorded itself when it was created.
+                if !is_observed(e) {
+                    span.add_event("exception", vec![KeyValue::new("exception.message", e.to_string())]);
+                }
+                span.set_status(Status::error(e.to_string()));
+            } else if status.is_server_error() {
+                span.set_status(Status::error(status.canonical_reason().unwrap_or_default()));
+            }
+            span.end();
+        }
+        #[cfg(not(feature = "traces"))]
+        let _ = req;
+        res
+    }
+}
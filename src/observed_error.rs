@@ -0,0 +1,199 @@
+//! Handler errors that report themselves.
+//!
+//! An `ObservedError` carries a stable error code next to its status and
+//! message. Returned from a handler, it
+//!
+//! - is recorded on the active span when it is created, the handler's span
+//!   of an `observed` handler or else the request's server span, as an
+//!   `exception` event with the code as `exception.type`, its message and
+//!   source, and the backtrace when `RUST_BACKTRACE` enables them, and sets
+//!   `error.type` to the code; a 5xx also marks the span failed;
+//! - is counted in `errors_total{code}` by `track_requests`;
+//! - renders as an RFC 9457 `application/problem+json` body with the code and
+//!   the trace ID, which users can quote to find the trace:
+//!
+//! ```ignore
+//! async fn get_user(path: web::Path<u64>) -> Result<HttpResponse, ObservedError> {
+//!     let user = load_user(*path)
+//!         .await
+//!         .map_err(|e| ObservedError::internal("user_store_unavailable", e))?
+//!         .ok_or_else(|| ObservedError::new(StatusCode::NOT_FOUND, "user_not_found", "no such user"))?;
+//!     Ok(HttpResponse::Ok().json(user))
+//! }
+//! ```
+//!
+//! Codes label a metric and should be a fixed set, hence `&'static str`. The
+//! source is recorded but never sent to the client.
+
+use crate::registry::Registry;
+use actix_web::{http::StatusCode, Error, HttpResponse, ResponseError};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{get_active_span, Status},
+    KeyValue,
+};
+use prometheus::{IntCounterVec, Opts};
+use serde_json::json;
+#[cfg(feature = "traces")]
+use std::backtrace::BacktraceStatus;
+use std::{backtrace::Backtrace, error::Error as StdError, fmt};
+
+/// `errors_total`.
+#[derive(Clone, Debug)]
+pub struct ErrorMetrics {
+    pub errors: IntCounterVec,
+}
+
+impl ErrorMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let errors =
+            IntCounterVec::new(Opts::new("errors_total", "Requests that failed with an `ObservedError`, by code"), &["code"])?;
+        registry.register(Box::new(errors.clone()))?;
+        Ok(Self { errors })
+    }
+
+    /// Counts `error` when it is an `ObservedError`.
+    pub fn observe(&self, error: &Error) {
+        if let Some(error) = error.as_error::<ObservedError>() {
+            self.errors.with_label_values(&[error.code]).inc();
+        }
+    }
+}
+
+/// An error with a code, recorded on the active span when created; see the
+/// module documentation.
+#[derive(Debug)]
+pub struct ObservedError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    source: Option<Box<dyn StdError + Send + Sync>>,
+    backtrace: Backtrace,
+    /// Of the span active at creation, as the response may be rendered
+    /// outside of it.
+    trace_id: Option<String>,
+}
+
+impl ObservedError {
+    /// An error answered with `status`, the client seeing `message`.
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self::build(status, code, message.into(), None)
+    }
+
+    /// A `400 Bad Request`.
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    /// A `500 Internal Server Error` caused by `source`, which is recorded but
+    /// not shown to the client.
+    pub fn internal(code: &'static str, source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        let status = StatusCode::INTERNAL_SERVER_ERROR;
+        Self::build(status, code, status.canonical_reason().unwrap_or_default().to_string(), Some(source.into()))
+    }
+
+    /// An error answered with `status` and caused by `source`.
+    pub fn with_source(
+        status: StatusCode,
+        code: &'static str,
+        message: impl Into<String>,
+        source: impl Into<Box<dyn StdError + Send + Sync>>,
+    ) -> Self {
+        Self::build(status, code, message.into(), Some(source.into()))
+    }
+
+    fn build(status: StatusCode, code: &'static str, message: String, source: Option<Box<dyn StdError + Send + Sync>>) -> Self {
+        let mut error = Self { status, code, message, source, backtrace: Backtrace::capture(), trace_id: None };
+        error.record();
+        error
+    }
+
+    /// Adds the error to the active span and keeps its trace ID.
+    fn record(&mut self) {
+        #[cfg(feature = "traces")]
+        get_active_span(|span| {
+            let context = span.span_context();
+            if context.is_valid() {
+                self.trace_id = Some(context.trace_id().to_string());
+            }
+            let mut attributes = vec![
+                KeyValue::new("exception.type", self.code),
+                KeyValue::new("exception.message", self.message.clone()),
+            ];
+            if let Some(source) = &self.source {
+                attributes.push(KeyValue::new("exception.cause", source.to_string()));
+            }
+            if self.backtrace.status() == BacktraceStatus::Captured {
+                attributes.push(KeyValue::new("exception.stacktrace", self.backtrace.to_string()));
+            }
+            span.add_event("exception", attributes);
+            span.set_attribute(KeyValue::new("error.type", self.code));
+            if self.status.is_server_error() {
+                span.set_status(Status::error(self.message.clone()));
+            }
+        });
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Captured when `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` enables it.
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+
+    /// The trace the error was created in, with the `traces` feature.
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+}
+
+impl fmt::Display for ObservedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)?;
+        if let Some(source) = &self.source {
+            write!(f, ": {source}")?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for ObservedError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source.as_deref().map(|source| source as &(dyn StdError + 'static))
+    }
+}
+
+impl ResponseError for ObservedError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": self.status.canonical_reason().unwrap_or_default(),
+            "status": self.status.as_u16(),
+            "detail": self.message,
+            "code": self.code,
+        });
+        if let Some(trace_id) = &self.trace_id {
+            problem["trace_id"] = json!(trace_id);
+        }
+        HttpResponse::build(self.status).content_type("application/problem+json").body(problem.to_string())
+    }
+}
+
+/// Whether `error` is an `ObservedError`, already recorded on its span.
+pub fn is_observed(error: &Error) -> bool {
+    error.as_error::<ObservedError>().is_some()
+}
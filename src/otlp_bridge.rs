@@ -0,0 +1,175 @@
+//! Export of the Prometheus registry through the OTLP metric pipeline.
+//!
+//! The families of `AppMetrics` normally only leave the process when
+//! `/metrics` is scraped. With `METRICS_OTLP_BRIDGE` on, each family also
+//! becomes an observable OTel instrument of the meter
+//! `prom_otel.prometheus_bridge`, read on every OTLP metric export:
+//!
+//! - counters become monotonic counters and gauges gauges, with the same
+//!   name and description and their labels as attributes;
+//! - the OTel API has no observable histogram, so histograms are exported
+//!   as counters `<name>_bucket` (with an `le` attribute), `<name>_sum` and
+//!   `<name>_count`, and summaries as a gauge `<name>` (with a `quantile`
+//!   attribute) and counters `<name>_sum` and `<name>_count`.
+//!
+//! The registry's constant labels are dropped, since the resource already
+//! carries them. Families are looked for every `OTEL_METRIC_EXPORT_INTERVAL`,
+//! so labeled ones are bridged once they have a series.
+
+use crate::registry::Registry;
+use opentelemetry::{global, metrics::AsyncInstrument, KeyValue};
+use prometheus::proto::{MetricFamily, MetricType};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a gather serves the callbacks of one export.
+const SNAPSHOT_TTL: Duration = Duration::from_secs(1);
+
+/// Families by name.
+type Families = Arc<HashMap<String, MetricFamily>>;
+
+/// The last gather of the registry, shared by all the callbacks so an export
+/// gathers once rather than once per instrument.
+#[derive(Debug)]
+struct Snapshot {
+    registry: Registry,
+    last: Mutex<Option<(Instant, Families)>>,
+}
+
+impl Snapshot {
+    fn get(&self) -> Families {
+        let mut last = self.last.lock().unwrap();
+        match &*last {
+            Some((taken, families)) if taken.elapsed() < SNAPSHOT_TTL => families.clone(),
+            _ => {
+                let families: Families = Arc::new(
+                    self.registry.gather().into_iter().map(|family| (family.name().to_string(), family)).collect(),
+                );
+                *last = Some((Instant::now(), families.clone()));
+                families
+            }
+        }
+    }
+}
+
+/// What an instrument reports of its family.
+#[derive(Clone, Copy, Debug)]
+enum Part {
+    /// The value of a counter, gauge or summary quantile.
+    Value,
+    Bucket,
+    Sum,
+    Count,
+}
+
+/// Bridges the families of `registry` until the process exits, looking for
+/// new ones every `interval`. `const_labels` are the registry's constant
+/// label names.
+pub async fn run(registry: Registry, const_labels: Vec<String>, interval: Duration) {
+    let snapshot = Arc::new(Snapshot { registry, last: Mutex::new(None) });
+    let const_labels = Arc::new(const_labels);
+    let mut bridged = HashSet::new();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for family in snapshot.get().values() {
+            if bridged.insert(family.name().to_string()) {
+                bridge(family, &snapshot, &const_labels);
+            }
+        }
+    }
+}
+
+fn bridge(family: &MetricFamily, snapshot: &Arc<Snapshot>, const_labels: &Arc<Vec<String>>) {
+    // Prometheus allows `:` in names, OTel doesn't.
+    let name = family.name().replace(':', "_");
+    let help = family.help().to_string();
+    let counter = |instrument: String, part| {
+        let callback = callback(family.name(), part, snapshot, const_labels);
+        global::meter("prom_otel.prometheus_bridge")
+            .f64_observable_counter(instrument)
+            .with_description(help.clone())
+            .with_callback(move |observer| callback(observer))
+            .build();
+    };
+    let gauge = |instrument: String| {
+        let callback = callback(family.name(), Part::Value, snapshot, const_labels);
+        global::meter("prom_otel.prometheus_bridge")
+            .f64_observable_gauge(instrument)
+            .with_description(help.clone())
+            .with_callback(move |observer| callback(observer))
+            .build();
+    };
+    match family.get_field_type() {
+        MetricType::COUNTER => counter(name, Part::Value),
+        MetricType::GAUGE | MetricType::UNTYPED => gauge(name),
+        MetricType::HISTOGRAM => {
+            counter(format!("{name}_bucket"), Part::Bucket);
+            counter(format!("{name}_sum"), Part::Sum);
+            counter(format!("{name}_count"), Part::Count);
+        }
+        MetricType::SUMMARY => {
+            gauge(name.clone());
+            counter(format!("{name}_sum"), Part::Sum);
+            counter(format!("{name}_count"), Part::Count);
+        }
+    }
+}
+
+/// Observes `part` of every series of the family `name` in the snapshot.
+fn callback(
+    name: &str,
+    part: Part,
+    snapshot: &Arc<Snapshot>,
+    const_labels: &Arc<Vec<String>>,
+) -> impl Fn(&dyn AsyncInstrument<f64>) + Send + Sync + 'static {
+    let (name, snapshot, const_labels) = (name.to_string(), snapshot.clone(), const_labels.clone());
+    move |observer| {
+        let families = snapshot.get();
+        let Some(family) = families.get(&name) else {
+            return;
+        };
+        for metric in family.get_metric() {
+            let attributes: Vec<KeyValue> = metric
+                .get_label()
+                .iter()
+                .filter(|label| !const_labels.iter().any(|name| name == label.name()))
+                .map(|label| KeyValue::new(label.name().to_string(), label.value().to_string()))
+                .collect();
+            // Bounds as Prometheus writes them, e.g. `le="+Inf"`.
+            let with = |key: &'static str, value: f64| {
+                let value = if value == f64::INFINITY { "+Inf".to_string() } else { value.to_string() };
+                let mut attributes = attributes.clone();
+                attributes.push(KeyValue::new(key, value));
+                attributes
+            };
+            match (family.get_field_type(), part) {
+                (MetricType::COUNTER, _) => observer.observe(metric.get_counter().value(), &attributes),
+                (MetricType::GAUGE, _) => observer.observe(metric.get_gauge().value(), &attributes),
+                (MetricType::UNTYPED, _) => observer.observe(metric.untyped.value(), &attributes),
+                (MetricType::HISTOGRAM, Part::Bucket) => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        let count = bucket.cumulative_count() as f64;
+                        observer.observe(count, &with("le", bucket.upper_bound()));
+                    }
+                    observer.observe(histogram.sample_count() as f64, &with("le", f64::INFINITY));
+                }
+                (MetricType::SUMMARY, Part::Value) => {
+                    for quantile in metric.get_summary().get_quantile() {
+                        observer.observe(quantile.value(), &with("quantile", quantile.quantile()));
+                    }
+                }
+                (MetricType::HISTOGRAM, Part::Sum) => observer.observe(metric.get_histogram().sample_sum(), &attributes),
+                (MetricType::SUMMARY, Part::Sum) => observer.observe(metric.get_summary().sample_sum(), &attributes),
+                (MetricType::HISTOGRAM, _) => {
+                    observer.observe(metric.get_histogram().sample_count() as f64, &attributes);
+                }
+                (MetricType::SUMMARY, _) => observer.observe(metric.get_summary().sample_count() as f64, &attributes),
+            }
+        }
+    }
+}
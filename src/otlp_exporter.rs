@@ -0,0 +1,210 @@
+//! Per-signal OTLP exporter settings.
+//!
+//! Traces, logs and metrics can each go to their own backend. By default a
+//! signal is exported over OTLP/HTTP with protobuf to
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` plus `/v1/<signal>`;
+//! `OTEL_EXPORTER_OTLP_<SIGNAL>_ENDPOINT` replaces that URL, signal path
+//! included, as the OpenTelemetry specification describes.
+//! The protocol (`http/protobuf`, `http/json` or `grpc`), the compression
+//! (`none`, `gzip` or `zstd`) and the headers sent with every export are set
+//! for all signals with `OTEL_EXPORTER_OTLP_PROTOCOL`,
+//! `OTEL_EXPORTER_OTLP_COMPRESSION` and `OTEL_EXPORTER_OTLP_HEADERS`, and per
+//! signal with the `OTEL_EXPORTER_OTLP_<SIGNAL>_*` variables, which win. Export
+//! timeouts are per signal already (`OTEL_BSP_EXPORT_TIMEOUT`,
+//! `OTEL_BLRP_EXPORT_TIMEOUT`, `OTEL_METRIC_EXPORT_TIMEOUT`).
+//!
+//! The OTLP/HTTP exporter can't compress, so `MonitoredHttpClient` does,
+//! setting `Content-Encoding`; gRPC compresses with tonic's codecs. zstd
+//! needs the `zstd` feature.
+//!
+//! gRPC needs the `otlp-grpc` feature. Its exports bypass
+//! `MonitoredHttpClient`, so they don't show up in
+//! `otlp_export_sent_bytes_total` and `otlp_export_request_duration_seconds`.
+
+use crate::pipeline::Signal;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    #[default]
+    HttpProtobuf,
+    HttpJson,
+    /// Requires the `otlp-grpc` feature.
+    Grpc,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "http/protobuf" => Ok(Self::HttpProtobuf),
+            "http/json" => Ok(Self::HttpJson),
+            "grpc" => Ok(Self::Grpc),
+            _ => Err(format!("unknown protocol {s:?}, expected `http/protobuf`, `http/json` or `grpc`")),
+        }
+    }
+}
+
+impl fmt::Display for OtlpProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::HttpProtobuf => "http/protobuf",
+            Self::HttpJson => "http/json",
+            Self::Grpc => "grpc",
+        })
+    }
+}
+
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+impl From<OtlpProtocol> for opentelemetry_otlp::Protocol {
+    fn from(protocol: OtlpProtocol) -> Self {
+        match protocol {
+            OtlpProtocol::HttpProtobuf => Self::HttpBinary,
+            OtlpProtocol::HttpJson => Self::HttpJson,
+            OtlpProtocol::Grpc => Self::Grpc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OtlpCompression {
+    #[default]
+    None,
+    Gzip,
+    /// Requires the `zstd` feature.
+    Zstd,
+}
+
+impl OtlpCompression {
+    /// `Content-Encoding` of compressed request bodies.
+    pub fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gzip"),
+            Self::Zstd => Some("zstd"),
+        }
+    }
+
+    /// Compresses an export request body.
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs", feature = "gateway"))]
+    pub fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Write;
+        match self {
+            Self::None => Ok(body.to_vec()),
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::encode_all(body, 0),
+            #[cfg(not(feature = "zstd"))]
+            Self::Zstd => Err(std::io::Error::other("this build lacks the `zstd` feature")),
+        }
+    }
+}
+
+impl FromStr for OtlpCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" | "" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("unknown compression {s:?}, expected `none`, `gzip` or `zstd`")),
+        }
+    }
+}
+
+impl fmt::Display for OtlpCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+/// A header sent with every export, e.g. an API key, parsed from
+/// `name=value`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OtlpHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for OtlpHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=').ok_or_else(|| format!("{s:?}: expected `name=value`"))?;
+        let name = name.trim();
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+            return Err(format!("{name:?} is not a valid header name"));
+        }
+        let value = value.trim();
+        if !value.bytes().all(|b| b == b'\t' || (b' '..=b'~').contains(&b)) {
+            return Err(format!("the value of header {name:?} must be printable ASCII"));
+        }
+        Ok(Self { name: name.to_ascii_lowercase(), value: value.to_string() })
+    }
+}
+
+/// Only the name: header values are usually credentials.
+impl fmt::Debug for OtlpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=***", self.name)
+    }
+}
+
+/// Parses a comma-separated list of headers, dropping empty entries.
+pub fn parse_headers(s: &str) -> Result<Vec<OtlpHeader>, String> {
+    s.split(',').filter(|part| !part.trim().is_empty()).map(str::parse).collect()
+}
+
+/// Where and how one signal is exported.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExporterSettings {
+    /// Full URL, signal path included; `None` derives it from the shared
+    /// endpoint (see `endpoint`).
+    pub endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    pub compression: OtlpCompression,
+    pub headers: Vec<OtlpHeader>,
+}
+
+impl ExporterSettings {
+    /// URL exports of `signal` go to: the configured one, else `base` plus
+    /// `/v1/<signal>` over HTTP and `base` itself over gRPC.
+    pub fn endpoint(&self, base: &str, signal: Signal) -> String {
+        match (&self.endpoint, self.protocol) {
+            (Some(endpoint), _) => endpoint.clone(),
+            (None, OtlpProtocol::Grpc) => base.to_string(),
+            (None, _) => format!("{}/v1/{}", base.trim_end_matches('/'), signal.as_str()),
+        }
+    }
+
+    /// Headers as the HTTP exporter takes them.
+    pub fn header_map(&self) -> std::collections::HashMap<String, String> {
+        self.headers.iter().map(|header| (header.name.clone(), header.value.clone())).collect()
+    }
+}
+
+impl fmt::Display for ExporterSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.protocol)?;
+        if self.compression != OtlpCompression::None {
+            write!(f, ", {}", self.compression)?;
+        }
+        if !self.headers.is_empty() {
+            let names: Vec<&str> = self.headers.iter().map(|header| header.name.as_str()).collect();
+            write!(f, ", headers {}", names.join(","))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! Panic capture.
+//!
+//! `install_hook` replaces the default stderr-only panic hook: every panic,
+//! in a handler or a background task, increments `app_panics_total`, is
+//! recorded as an `exception` event on the active span and is logged at
+//! `error` (so it also reaches the OTLP log bridge). `catch` turns a panic in
+//! a request handler into a 500 response instead of a dropped connection.
+
+use actix_web::{Error, ResponseError};
+use futures_util::FutureExt;
+#[cfg(feature = "traces")]
+use opentelemetry::{trace::get_active_span, KeyValue};
+use prometheus::IntCounter;
+use std::{backtrace::Backtrace, fmt, future::Future, panic::{AssertUnwindSafe, PanicHookInfo}};
+use tracing::error;
+
+/// The panic payload as text, when it is a string (as with `panic!`).
+fn message<'a>(info: &'a PanicHookInfo<'_>) -> &'a str {
+    let payload = info.payload();
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// Installs the process-wide panic hook. Call it after the `tracing`
+/// subscriber is set up, otherwise the log record goes nowhere.
+pub fn install_hook(panics: IntCounter) {
+    std::panic::set_hook(Box::new(move |info| {
+        panics.inc();
+        let message = message(info);
+        let location = info.location().map(ToString::to_string).unwrap_or_default();
+        let backtrace = Backtrace::force_capture().to_string();
+
+        #[cfg(feature = "traces")]
+        get_active_span(|span| {
+            span.add_event(
+                "exception",
+                vec![
+                    KeyValue::new("exception.type", "panic"),
+                    KeyValue::new("exception.message", message.to_string()),
+                    KeyValue::new("exception.stacktrace", backtrace.clone()),
+                    KeyValue::new("code.location", location.clone()),
+                ],
+            );
+        });
+
+        let thread = std::thread::current();
+        error!(
+            panic.message = message,
+            panic.location = %location,
+            panic.thread = thread.name().unwrap_or("<unnamed>"),
+            panic.backtrace = %backtrace,
+            "Panic"
+        );
+    }));
+}
+
+/// The 500 error a caught panic resolves to.
+#[derive(Debug)]
+struct Panicked;
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("internal server error")
+    }
+}
+
+impl ResponseError for Panicked {}
+
+/// Resolves to a 500 error instead of unwinding when `fut` panics. The hook
+/// has already recorded the panic by then.
+pub async fn catch<T>(fut: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    AssertUnwindSafe(fut).catch_unwind().await.unwrap_or_else(|_| Err(Panicked.into()))
+}
+
+/// Whether `error` is a panic caught by `catch`, already recorded by the hook.
+pub fn is_panic(error: &Error) -> bool {
+    error.as_error::<Panicked>().is_some()
+}
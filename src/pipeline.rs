@@ -0,0 +1,618 @@
+//! Self-monitoring for the OTLP export pipeline.
+//!
+//! The exporters built in `init_traces`, `init_logs` and `init_metrics` are
+//! wrapped in `Monitored*Exporter` types that count exported items, failures
+//! and retries per signal. Queue drops happen inside the SDK batch processors
+//! and are only reported through their internal diagnostics, so
+//! `QueueDropLayer` turns those events into counter increments.
+//!
+//! The exporters send through `MonitoredHttpClient`, which measures the
+//! network cost of exporting: request body bytes in
+//! `otlp_export_sent_bytes_total{signal,destination}` and request latency in
+//! `otlp_export_request_duration_seconds{signal,destination}`, where
+//! `destination` is the collector's `host:port`.
+//!
+//! A collector may also accept an export but reject some of its items (a
+//! partial success), answering 200 with the rejected count and a message in
+//! the response body. The OTLP exporters ignore response bodies, so
+//! `MonitoredHttpClient` decodes them (protobuf or, for `http/json`, JSON), counts the rejected items in
+//! `otlp_export_rejected_total{signal}` and logs the message. Partial
+//! successes aren't retried, as the collector would reject the same items
+//! again, and the items still count as exported.
+
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{LogBatch, LogExporter};
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use opentelemetry_sdk::Resource;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use crate::otlp_exporter::OtlpCompression;
+use crate::registry::Registry;
+use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    warn, Event, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Number of attempts made for a single batch before it is counted as failed.
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+const EXPORT_MAX_ATTEMPTS: u32 = 3;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+const EXPORT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Traces,
+    Logs,
+    Metrics,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 3] = [Signal::Traces, Signal::Logs, Signal::Metrics];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Signal::Traces => "traces",
+            Signal::Logs => "logs",
+            Signal::Metrics => "metrics",
+        }
+    }
+}
+
+/// Point-in-time view of the counters for one signal.
+#[derive(Clone, Debug, Default)]
+pub struct SignalSnapshot {
+    pub exported: u64,
+    pub failures: u64,
+    pub retries: u64,
+    pub queue_dropped: u64,
+    /// Items the collector rejected from exports it otherwise accepted.
+    pub rejected: u64,
+    /// Unix timestamp (seconds) of the last successful export, 0 if none yet.
+    pub last_success: f64,
+}
+
+/// Export pipeline counters, shared between the exporters, `/metrics` and
+/// any in-process consumer that wants to check pipeline health.
+#[derive(Clone, Debug)]
+pub struct PipelineStats {
+    exported: IntCounterVec,
+    failures: IntCounterVec,
+    retries: IntCounterVec,
+    queue_dropped: IntCounterVec,
+    rejected: IntCounterVec,
+    // Only written by the exporters' HTTP clients.
+    #[cfg_attr(not(any(feature = "traces", feature = "metrics", feature = "logs")), allow(dead_code))]
+    sent_bytes: IntCounterVec,
+    #[cfg_attr(not(any(feature = "traces", feature = "metrics", feature = "logs")), allow(dead_code))]
+    request_duration: HistogramVec,
+    last_success: GaugeVec,
+    // Drops already accounted for per signal, so the shutdown total reported
+    // by the SDK only adds what the first-drop warning didn't cover.
+    dropped_seen: Arc<[AtomicU64; 3]>,
+}
+
+impl PipelineStats {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let labels = &["signal"];
+        let exported = IntCounterVec::new(
+            Opts::new("otel_exporter_exported_total", "Telemetry items successfully exported"),
+            labels,
+        )?;
+        let failures = IntCounterVec::new(
+            Opts::new("otel_exporter_failures_total", "Export batches that failed after all retries"),
+            labels,
+        )?;
+        let retries = IntCounterVec::new(
+            Opts::new("otel_exporter_retries_total", "Export attempts retried after a failure"),
+            labels,
+        )?;
+        let queue_dropped = IntCounterVec::new(
+            Opts::new("otel_exporter_queue_dropped_total", "Telemetry items dropped because the export queue was full"),
+            labels,
+        )?;
+        let rejected = IntCounterVec::new(
+            Opts::new("otlp_export_rejected_total", "Telemetry items the collector rejected in a partial success"),
+            labels,
+        )?;
+        // Destinations are only known once the exporters resolve their
+        // endpoints, so these aren't primed.
+        let sent_bytes = IntCounterVec::new(
+            Opts::new("otlp_export_sent_bytes_total", "Bytes of OTLP export request bodies sent to the collector"),
+            &["signal", "destination"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("otlp_export_request_duration_seconds", "Latency of OTLP export requests to the collector"),
+            &["signal", "destination"],
+        )?;
+        let last_success = GaugeVec::new(
+            Opts::new("otel_exporter_last_success_timestamp_seconds", "Unix time of the last successful export"),
+            labels,
+        )?;
+
+        registry.register(Box::new(exported.clone()))?;
+        registry.register(Box::new(failures.clone()))?;
+        registry.register(Box::new(retries.clone()))?;
+        registry.register(Box::new(queue_dropped.clone()))?;
+        registry.register(Box::new(rejected.clone()))?;
+        registry.register(Box::new(sent_bytes.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(last_success.clone()))?;
+
+        // Make every signal show up on the first scrape, even at zero.
+        for signal in Signal::ALL {
+            let s = signal.as_str();
+            exported.with_label_values(&[s]);
+            failures.with_label_values(&[s]);
+            retries.with_label_values(&[s]);
+            queue_dropped.with_label_values(&[s]);
+            rejected.with_label_values(&[s]);
+            last_success.with_label_values(&[s]);
+        }
+
+        Ok(Self {
+            exported,
+            failures,
+            retries,
+            queue_dropped,
+            rejected,
+            sent_bytes,
+            request_duration,
+            last_success,
+            dropped_seen: Arc::new([AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)]),
+        })
+    }
+
+    pub fn snapshot(&self, signal: Signal) -> SignalSnapshot {
+        let s = [signal.as_str()];
+        SignalSnapshot {
+            exported: self.exported.with_label_values(&s).get(),
+            failures: self.failures.with_label_values(&s).get(),
+            retries: self.retries.with_label_values(&s).get(),
+            queue_dropped: self.queue_dropped.with_label_values(&s).get(),
+            rejected: self.rejected.with_label_values(&s).get(),
+            last_success: self.last_success.with_label_values(&s).get(),
+        }
+    }
+
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+    fn record_success(&self, signal: Signal, items: usize) {
+        let s = [signal.as_str()];
+        self.exported.with_label_values(&s).inc_by(items as u64);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.last_success.with_label_values(&s).set(now);
+    }
+
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+    fn record_failure(&self, signal: Signal) {
+        self.failures.with_label_values(&[signal.as_str()]).inc();
+    }
+
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+    fn record_retry(&self, signal: Signal) {
+        self.retries.with_label_values(&[signal.as_str()]).inc();
+    }
+
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+    fn record_rejected(&self, signal: Signal, items: u64) {
+        self.rejected.with_label_values(&[signal.as_str()]).inc_by(items);
+    }
+
+    /// Records one export request of `bytes` to `destination`, successful
+    /// or not.
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs", feature = "gateway"))]
+    pub(crate) fn record_request(&self, signal: Signal, destination: &str, bytes: usize, elapsed: Duration) {
+        let labels = [signal.as_str(), destination];
+        self.sent_bytes.with_label_values(&labels).inc_by(bytes as u64);
+        self.request_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+
+    /// Raises the drop counter for `signal` to at least `total`.
+    fn record_dropped_total(&self, signal: Signal, total: u64) {
+        let seen = &self.dropped_seen[signal as usize];
+        let previous = seen.fetch_max(total, Ordering::Relaxed);
+        if total > previous {
+            self.queue_dropped
+                .with_label_values(&[signal.as_str()])
+                .inc_by(total - previous);
+        }
+    }
+
+    /// Runs `attempt` up to `EXPORT_MAX_ATTEMPTS` times and records the outcome.
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+    async fn export_with_retry<F, Fut>(&self, signal: Signal, items: usize, mut attempt: F) -> OTelSdkResult
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = OTelSdkResult>,
+    {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            match attempt().await {
+                Ok(()) => {
+                    self.record_success(signal, items);
+                    return Ok(());
+                }
+                // Nothing to retry once the exporter is gone.
+                Err(err @ OTelSdkError::AlreadyShutdown) => {
+                    self.record_failure(signal);
+                    return Err(err);
+                }
+                Err(err) if tries >= EXPORT_MAX_ATTEMPTS => {
+                    self.record_failure(signal);
+                    return Err(err);
+                }
+                Err(_) => {
+                    self.record_retry(signal);
+                    // Exports run on the SDK's own worker threads, not on the
+                    // Tokio runtime, so a blocking sleep is what's available.
+                    std::thread::sleep(EXPORT_RETRY_BACKOFF * 2u32.pow(tries - 1));
+                }
+            }
+        }
+    }
+}
+
+/// Periodically compares pipeline snapshots and logs a warning for every
+/// signal that failed or dropped data since the previous check.
+pub async fn watch(stats: PipelineStats, interval: Duration) {
+    let mut previous: Vec<SignalSnapshot> = Signal::ALL.iter().map(|s| stats.snapshot(*s)).collect();
+    loop {
+        tokio::time::sleep(interval).await;
+        for (signal, prev) in Signal::ALL.iter().zip(previous.iter_mut()) {
+            let current = stats.snapshot(*signal);
+            let failures = current.failures - prev.failures;
+            let dropped = current.queue_dropped - prev.queue_dropped;
+            if failures > 0 || dropped > 0 {
+                warn!(
+                    signal = signal.as_str(),
+                    failures,
+                    dropped,
+                    retries = current.retries - prev.retries,
+                    exported = current.exported - prev.exported,
+                    last_success = current.last_success,
+                    "Telemetry export pipeline is losing data"
+                );
+            }
+            *prev = current;
+        }
+    }
+}
+
+/// HTTP client of the OTLP exporter of one signal, compressing and measuring
+/// its requests and reading partial success out of the collector's
+/// responses.
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+#[derive(Debug)]
+pub struct MonitoredHttpClient {
+    inner: reqwest::blocking::Client,
+    signal: Signal,
+    stats: PipelineStats,
+    compression: OtlpCompression,
+    timeout: Duration,
+    /// Sends through this socket rather than TCP (`OTLP_UNIX_SOCKET`).
+    unix_socket: Option<std::path::PathBuf>,
+}
+
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+impl MonitoredHttpClient {
+    /// `timeout` bounds each request, like the exporter's own timeout.
+    pub fn new(signal: Signal, stats: PipelineStats, timeout: Duration) -> Self {
+        // The blocking client can't be built on a Tokio runtime thread, and
+        // the pipelines are built on one.
+        let inner = std::thread::spawn(move || {
+            reqwest::blocking::Client::builder()
+                .timeout(timeout)
+                .build()
+                .unwrap_or_else(|_| reqwest::blocking::Client::new())
+        })
+        .join()
+        .expect("Failed to create the OTLP HTTP client");
+        Self { inner, signal, stats, compression: OtlpCompression::None, timeout, unix_socket: None }
+    }
+
+    /// Compresses request bodies and sets `Content-Encoding` accordingly.
+    pub fn with_compression(mut self, compression: OtlpCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sends requests to the collector listening on `path`, if any, instead
+    /// of the host of their URL. Unix only, which `Config::validate` checks.
+    pub fn with_unix_socket(mut self, path: Option<std::path::PathBuf>) -> Self {
+        self.unix_socket = path;
+        self
+    }
+
+    /// Rejected item count and message of an export response, if it has a
+    /// partial success.
+    fn partial_success(&self, body: &[u8]) -> Option<(i64, String)> {
+        use prost::Message;
+        match self.signal {
+            #[cfg(feature = "traces")]
+            Signal::Traces => {
+                use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceResponse;
+                let partial = ExportTraceServiceResponse::decode(body).ok()?.partial_success?;
+                Some((partial.rejected_spans, partial.error_message))
+            }
+            #[cfg(feature = "logs")]
+            Signal::Logs => {
+                use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceResponse;
+                let partial = ExportLogsServiceResponse::decode(body).ok()?.partial_success?;
+                Some((partial.rejected_log_records, partial.error_message))
+            }
+            #[cfg(feature = "metrics")]
+            Signal::Metrics => {
+                use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceResponse;
+                let partial = ExportMetricsServiceResponse::decode(body).ok()?.partial_success?;
+                Some((partial.rejected_data_points, partial.error_message))
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Same for an OTLP/JSON response, where the count may also be a string
+    /// as the protobuf JSON mapping writes 64-bit integers.
+    fn partial_success_json(&self, body: &[u8]) -> Option<(i64, String)> {
+        let field = match self.signal {
+            Signal::Traces => "rejectedSpans",
+            Signal::Logs => "rejectedLogRecords",
+            Signal::Metrics => "rejectedDataPoints",
+        };
+        let response: serde_json::Value = serde_json::from_slice(body).ok()?;
+        let partial = response.get("partialSuccess")?;
+        let rejected = match partial.get(field) {
+            Some(serde_json::Value::String(count)) => count.parse().ok()?,
+            Some(count) => count.as_i64()?,
+            None => 0,
+        };
+        let message = partial.get("errorMessage").and_then(serde_json::Value::as_str).unwrap_or_default();
+        Some((rejected, message.to_string()))
+    }
+}
+
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+#[async_trait::async_trait]
+impl HttpClient for MonitoredHttpClient {
+    async fn send_bytes(&self, mut request: Request<Bytes>) -> Result<Response<Bytes>, HttpError> {
+        if let Some(encoding) = self.compression.content_encoding() {
+            let body = self.compression.compress(request.body())?;
+            *request.body_mut() = body.into();
+            request.headers_mut().insert("content-encoding", encoding.parse()?);
+        }
+        let destination = match &self.unix_socket {
+            Some(path) => format!("unix:{}", path.display()),
+            None => request.uri().authority().map_or_else(String::new, ToString::to_string),
+        };
+        let bytes = request.body().len();
+        let started = std::time::Instant::now();
+        let response = match &self.unix_socket {
+            #[cfg(unix)]
+            Some(path) => crate::unix_http::send(path, request, self.timeout),
+            _ => self.inner.send_bytes(request).await,
+        };
+        self.stats.record_request(self.signal, &destination, bytes, started.elapsed());
+        let response = response?;
+        // An error response carries a `google.rpc.Status`, not an export
+        // response, and is reported by the exporter itself.
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+        let signal = self.signal.as_str();
+        let json = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.starts_with("application/json"));
+        let partial = if json { self.partial_success_json(response.body()) } else { self.partial_success(response.body()) };
+        match partial {
+            Some((rejected, message)) if rejected > 0 => {
+                self.stats.record_rejected(self.signal, rejected as u64);
+                warn!(signal, rejected, error_message = %message, "Collector rejected part of an export");
+            }
+            // A message without rejections is a warning from the collector.
+            Some((_, message)) if !message.is_empty() => {
+                warn!(signal, error_message = %message, "Collector accepted an export with a warning");
+            }
+            _ => {}
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct MonitoredSpanExporter<E> {
+    inner: E,
+    stats: PipelineStats,
+}
+
+#[cfg(feature = "traces")]
+impl<E> MonitoredSpanExporter<E> {
+    pub fn new(inner: E, stats: PipelineStats) -> Self {
+        Self { inner, stats }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl<E: SpanExporter> SpanExporter for MonitoredSpanExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let items = batch.len();
+        self.stats
+            .export_with_retry(Signal::Traces, items, || self.inner.export(batch.clone()))
+            .await
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(feature = "logs")]
+#[derive(Debug)]
+pub struct MonitoredLogExporter<E> {
+    inner: E,
+    stats: PipelineStats,
+}
+
+#[cfg(feature = "logs")]
+impl<E> MonitoredLogExporter<E> {
+    pub fn new(inner: E, stats: PipelineStats) -> Self {
+        Self { inner, stats }
+    }
+}
+
+#[cfg(feature = "logs")]
+impl<E: LogExporter> LogExporter for MonitoredLogExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        // `LogBatch` is consumed by each attempt, so keep the borrowed
+        // records around to rebuild it on retry.
+        let records: Vec<_> = batch.iter().collect();
+        self.stats
+            .export_with_retry(Signal::Logs, records.len(), || {
+                self.inner.export(LogBatch::new(&records))
+            })
+            .await
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub struct MonitoredMetricExporter<E> {
+    inner: E,
+    stats: PipelineStats,
+}
+
+#[cfg(feature = "metrics")]
+impl<E> MonitoredMetricExporter<E> {
+    pub fn new(inner: E, stats: PipelineStats) -> Self {
+        Self { inner, stats }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<E> fmt::Debug for MonitoredMetricExporter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MonitoredMetricExporter").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<E: PushMetricExporter> PushMetricExporter for MonitoredMetricExporter<E> {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        let items = metrics.scope_metrics().map(|scope| scope.metrics().count()).sum();
+        self.stats
+            .export_with_retry(Signal::Metrics, items, || self.inner.export(metrics))
+            .await
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.inner.temporality()
+    }
+}
+
+/// Watches the SDK's internal warnings for dropped spans and log records.
+///
+/// The batch processors warn once when they start dropping and report the
+/// exact total at shutdown, so the counter is exact after shutdown and at
+/// least 1 while drops are ongoing.
+pub struct QueueDropLayer {
+    stats: PipelineStats,
+}
+
+impl QueueDropLayer {
+    pub fn new(stats: PipelineStats) -> Self {
+        Self { stats }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for QueueDropLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if metadata.target() != "opentelemetry_sdk" {
+            return;
+        }
+        let (signal, count_field) = match metadata.name() {
+            "BatchSpanProcessor.SpanDroppingStarted" => (Signal::Traces, None),
+            "BatchSpanProcessor.SpansDropped" => (Signal::Traces, Some("dropped_span_count")),
+            "BatchLogProcessor.LogDroppingStarted" => (Signal::Logs, None),
+            "BatchLogProcessor.LogsDropped" => (Signal::Logs, Some("dropped_logs_count")),
+            _ => return,
+        };
+        let total = match count_field {
+            Some(field) => {
+                let mut visitor = CountVisitor { field, value: None };
+                event.record(&mut visitor);
+                visitor.value.unwrap_or(1)
+            }
+            None => 1,
+        };
+        self.stats.record_dropped_total(signal, total);
+    }
+}
+
+struct CountVisitor {
+    field: &'static str,
+    value: Option<u64>,
+}
+
+impl Visit for CountVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == self.field {
+            self.value = Some(value);
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == self.field {
+            self.value = Some(value.max(0) as u64);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == self.field {
+            self.value = format!("{value:?}").parse().ok();
+        }
+    }
+}
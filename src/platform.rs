@@ -0,0 +1,270 @@
+//! Per-platform backends of the system metrics.
+//!
+//! sysinfo samples CPU, memory, disk and network usage the same way
+//! everywhere; what it doesn't cover is read from each platform's own
+//! interfaces:
+//!
+//! - Linux: `/proc`, for the CPU steal time, the peak RSS (`VmHWM`) and the
+//!   major page faults; `ProcessCollector` reads the threads and file
+//!   descriptors.
+//! - Windows: `GetProcessMemoryInfo` for the peak working set and the page
+//!   faults, `GetProcessHandleCount` for the open handles (exported as
+//!   `process_open_fds`), and the PDH counter `\System\Processor Queue
+//!   Length`, exported as `system_processor_queue_length` since Windows has
+//!   no load average.
+//! - macOS: libproc's `proc_pidinfo` for the threads, page faults and open
+//!   file descriptors, `getrusage` for the peak RSS and `getrlimit` for the
+//!   descriptor limit.
+//!
+//! Other platforms only get what sysinfo reads. Each value is optional: a
+//! series is only registered once its platform reports it, so a missing
+//! interface or permission leaves it out instead of exporting a zero.
+//! `GET /admin/capabilities` lists the series the running platform adds.
+
+#[cfg(windows)]
+use windows_sys::Win32::System::{
+    Performance::{
+        PdhAddEnglishCounterW, PdhCloseQuery, PdhCollectQueryData, PdhGetFormattedCounterValue, PdhOpenQueryW,
+        PDH_FMT_COUNTERVALUE, PDH_FMT_DOUBLE,
+    },
+    ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+    Threading::{GetCurrentProcess, GetProcessHandleCount},
+};
+
+/// Series the backend of this platform adds to sysinfo's.
+#[cfg(target_os = "linux")]
+pub const PLATFORM_METRICS: &[&str] = &[
+    "process_threads",
+    "process_open_fds",
+    "process_max_fds",
+    "process_page_faults_total",
+    "system_cpu_steal_seconds_total",
+];
+#[cfg(windows)]
+pub const PLATFORM_METRICS: &[&str] = &["process_open_fds", "process_page_faults_total", "system_processor_queue_length"];
+#[cfg(target_os = "macos")]
+pub const PLATFORM_METRICS: &[&str] =
+    &["process_threads", "process_open_fds", "process_max_fds", "process_page_faults_total"];
+#[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+pub const PLATFORM_METRICS: &[&str] = &[];
+
+/// What the platform reports about the current process; `None` for what
+/// it can't.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub threads: Option<u64>,
+    /// Open file descriptors, or handles on Windows.
+    pub open_fds: Option<u64>,
+    pub max_fds: Option<u64>,
+    /// Highest resident set size (peak working set on Windows) so far.
+    pub peak_resident_bytes: Option<u64>,
+    /// Major page faults on Linux, all of them elsewhere.
+    pub page_faults: Option<u64>,
+}
+
+/// The backend of the running platform.
+#[derive(Debug)]
+pub struct Platform {
+    #[cfg(windows)]
+    processor_queue: Option<ProcessorQueue>,
+}
+
+impl Platform {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(windows)]
+            processor_queue: ProcessorQueue::open(),
+        }
+    }
+
+    /// `linux`, `windows`, `macos`, ...
+    pub fn name() -> &'static str {
+        std::env::consts::OS
+    }
+
+    /// Whether the host has a load average.
+    pub fn has_load_average() -> bool {
+        !cfg!(windows)
+    }
+
+    /// Whether `cpu_steal_seconds` can report anything.
+    pub fn has_cpu_steal() -> bool {
+        cfg!(target_os = "linux")
+    }
+
+    /// Steal time of all cores from the `cpu` line of `/proc/stat`, the 8th
+    /// value, in `USER_HZ` ticks (100 per second on every Linux port).
+    #[cfg(target_os = "linux")]
+    pub fn cpu_steal_seconds(&mut self) -> Option<f64> {
+        let stat = std::fs::read_to_string("/proc/stat").ok()?;
+        let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+        let ticks: u64 = line.split_whitespace().nth(8)?.parse().ok()?;
+        Some(ticks as f64 / 100.0)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn cpu_steal_seconds(&mut self) -> Option<f64> {
+        None
+    }
+
+    /// Threads waiting for a processor, on Windows.
+    #[cfg(windows)]
+    pub fn processor_queue_length(&mut self) -> Option<f64> {
+        self.processor_queue.as_ref().and_then(ProcessorQueue::read)
+    }
+
+    #[cfg(not(windows))]
+    pub fn processor_queue_length(&mut self) -> Option<f64> {
+        None
+    }
+
+    /// Peak RSS from `VmHWM` in `/proc/self/status` and major faults from
+    /// the 12th field of `/proc/self/stat`; `ProcessCollector` has the rest.
+    #[cfg(target_os = "linux")]
+    pub fn process(&mut self) -> ProcessInfo {
+        let peak_resident_bytes = std::fs::read_to_string("/proc/self/status").ok().and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+            let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb * 1024)
+        });
+        // The command name in parentheses may contain spaces; fields are
+        // counted from the state, the 3rd.
+        let page_faults = std::fs::read_to_string("/proc/self/stat").ok().and_then(|stat| {
+            let (_, fields) = stat.rsplit_once(')')?;
+            fields.split_whitespace().nth(12 - 3)?.parse().ok()
+        });
+        ProcessInfo { peak_resident_bytes, page_faults, ..ProcessInfo::default() }
+    }
+
+    #[cfg(windows)]
+    pub fn process(&mut self) -> ProcessInfo {
+        let mut info = ProcessInfo::default();
+        // SAFETY: the pseudo handle of the current process needs no closing,
+        // and both calls only write to the out parameters they are given.
+        unsafe {
+            let process = GetCurrentProcess();
+            let mut counters: PROCESS_MEMORY_COUNTERS = std::mem::zeroed();
+            counters.cb = size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+            if GetProcessMemoryInfo(process, &mut counters, counters.cb) != 0 {
+                info.peak_resident_bytes = Some(counters.PeakWorkingSetSize as u64);
+                info.page_faults = Some(u64::from(counters.PageFaultCount));
+            }
+            let mut handles = 0u32;
+            if GetProcessHandleCount(process, &mut handles) != 0 {
+                info.open_fds = Some(u64::from(handles));
+            }
+        }
+        info
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn process(&mut self) -> ProcessInfo {
+        use std::{ffi::c_void, ptr};
+
+        let mut info = ProcessInfo::default();
+        let pid = std::process::id() as libc::c_int;
+        // SAFETY: every buffer handed to libproc and libc is sized by the
+        // length passed with it, and only read back up to what was written.
+        unsafe {
+            let mut task: libc::proc_taskinfo = std::mem::zeroed();
+            let size = size_of::<libc::proc_taskinfo>() as libc::c_int;
+            if libc::proc_pidinfo(pid, libc::PROC_PIDTASKINFO, 0, (&raw mut task).cast::<c_void>(), size) == size {
+                info.threads = Some(task.pti_threadnum as u64);
+                info.page_faults = Some(task.pti_faults as u64);
+            }
+            // The first call sizes the buffer, with room to spare.
+            let needed = libc::proc_pidinfo(pid, libc::PROC_PIDLISTFDS, 0, ptr::null_mut(), 0);
+            if needed > 0 {
+                let mut fds: Vec<libc::proc_fdinfo> =
+                    Vec::with_capacity(needed as usize / size_of::<libc::proc_fdinfo>());
+                let written = libc::proc_pidinfo(
+                    pid,
+                    libc::PROC_PIDLISTFDS,
+                    0,
+                    fds.as_mut_ptr().cast::<c_void>(),
+                    (fds.capacity() * size_of::<libc::proc_fdinfo>()) as libc::c_int,
+                );
+                if written > 0 {
+                    info.open_fds = Some(written as u64 / size_of::<libc::proc_fdinfo>() as u64);
+                }
+            }
+            let mut usage: libc::rusage = std::mem::zeroed();
+            // `ru_maxrss` is in bytes on macOS, unlike Linux.
+            if libc::getrusage(libc::RUSAGE_SELF, &mut usage) == 0 {
+                info.peak_resident_bytes = Some(usage.ru_maxrss as u64);
+            }
+            let mut limit: libc::rlimit = std::mem::zeroed();
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 && limit.rlim_cur != libc::RLIM_INFINITY {
+                info.max_fds = Some(limit.rlim_cur);
+            }
+        }
+        info
+    }
+
+    #[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+    pub fn process(&mut self) -> ProcessInfo {
+        ProcessInfo::default()
+    }
+}
+
+/// A PDH query of `\System\Processor Queue Length`.
+#[cfg(windows)]
+#[derive(Debug)]
+struct ProcessorQueue {
+    query: isize,
+    counter: isize,
+}
+
+#[cfg(windows)]
+impl ProcessorQueue {
+    /// `None` when the counter isn't available, e.g. in some containers.
+    fn open() -> Option<Self> {
+        let path: Vec<u16> = "\\System\\Processor Queue Length".encode_utf16().chain([0]).collect();
+        let (mut query, mut counter) = (0, 0);
+        // SAFETY: `path` is NUL-terminated and outlives the call; the query is
+        // closed on failure here and by `Drop` otherwise.
+        unsafe {
+            if PdhOpenQueryW(std::ptr::null(), 0, &mut query) != 0 {
+                return None;
+            }
+            if PdhAddEnglishCounterW(query, path.as_ptr(), 0, &mut counter) != 0 {
+                PdhCloseQuery(query);
+                return None;
+            }
+        }
+        Some(Self { query, counter })
+    }
+
+    fn read(&self) -> Option<f64> {
+        // SAFETY: the handles stay open until `Drop`, and the union is only
+        // read as the double `PDH_FMT_DOUBLE` asked for.
+        unsafe {
+            if PdhCollectQueryData(self.query) != 0 {
+                return None;
+            }
+            let mut value: PDH_FMT_COUNTERVALUE = std::mem::zeroed();
+            if PdhGetFormattedCounterValue(self.counter, PDH_FMT_DOUBLE, std::ptr::null_mut(), &mut value) != 0
+                || value.CStatus != 0
+            {
+                return None;
+            }
+            Some(value.Anonymous.doubleValue)
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessorQueue {
+    fn drop(&mut self) {
+        // SAFETY: the query is open and closed only here.
+        unsafe {
+            PdhCloseQuery(self.query);
+        }
+    }
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
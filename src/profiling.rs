@@ -0,0 +1,142 @@
+//! CPU profiles on `GET /debug/pprof/profile` (`pprof` feature), compatible
+//! with `go tool pprof`, mounted only when `ADMIN_TOKEN` is set and
+//! protected by it.
+//!
+//! `?seconds=N` (default 30, at most `MAX_SECONDS`) sets how long to sample;
+//! the response is a pprof protobuf, or an SVG flame graph with
+//! `?format=flamegraph`. Only one profile runs at a time, including the
+//! continuous ones of `pyroscope`.
+
+use crate::auth::EndpointAuth;
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use pprof::{protos::Message, ProfilerGuard, ProfilerGuardBuilder};
+use serde_json::json;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::info;
+
+pub const DEFAULT_SECONDS: u64 = 30;
+pub const MAX_SECONDS: u64 = 300;
+/// Samples per second; not 100, so sampling doesn't run in lockstep with
+/// periodic work.
+pub const FREQUENCY: i32 = 99;
+
+/// Set while a profile is being taken.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Exclusive right to run the profiler, which is process-wide; released on
+/// drop.
+pub(crate) struct ProfilerLock(());
+
+impl ProfilerLock {
+    /// `None` while another profile is being taken.
+    pub(crate) fn try_acquire() -> Option<Self> {
+        (!RUNNING.swap(true, Ordering::Acquire)).then_some(Self(()))
+    }
+
+    /// Starts sampling until the returned guard is dropped.
+    pub(crate) fn start(&self) -> pprof::Result<ProfilerGuard<'static>> {
+        ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+    }
+}
+
+impl Drop for ProfilerLock {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Pprof,
+    Flamegraph,
+}
+
+fn parse_query(query: &str) -> Result<(Duration, Format), String> {
+    let mut seconds = DEFAULT_SECONDS;
+    let mut format = Format::Pprof;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "seconds" => {
+                seconds = value
+                    .parse()
+                    .ok()
+                    .filter(|secs| (1..=MAX_SECONDS).contains(secs))
+                    .ok_or_else(|| format!("seconds must be between 1 and {MAX_SECONDS}, got {value:?}"))?;
+            }
+            "format" => {
+                format = match value {
+                    "pprof" | "proto" => Format::Pprof,
+                    "flamegraph" => Format::Flamegraph,
+                    _ => return Err(format!("format must be `pprof` or `flamegraph`, got {value:?}")),
+                };
+            }
+            _ => {}
+        }
+    }
+    Ok((Duration::from_secs(seconds), format))
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": message.into() }))
+}
+
+/// `GET /debug/pprof/profile`: samples the CPU for the requested time.
+async fn profile(req: HttpRequest, auth: web::Data<EndpointAuth>) -> HttpResponse {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("admin");
+    }
+    let (duration, format) = match parse_query(req.query_string()) {
+        Ok(parsed) => parsed,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let Some(lock) = ProfilerLock::try_acquire() else {
+        return error(StatusCode::CONFLICT, "a profile is already running");
+    };
+
+    let guard = match lock.start() {
+        Ok(guard) => guard,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    info!(seconds = duration.as_secs(), ?format, "CPU profile started");
+    tokio::time::sleep(duration).await;
+    let report = match guard.report().build() {
+        Ok(report) => report,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    drop(guard);
+    info!(seconds = duration.as_secs(), "CPU profile finished");
+
+    match format {
+        Format::Pprof => match report.pprof() {
+            Ok(profile) => HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"profile.pb\""))
+                .body(profile.encode_to_vec()),
+            Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        },
+        Format::Flamegraph => {
+            let mut svg = Vec::new();
+            match report.flamegraph(&mut svg) {
+                Ok(()) => HttpResponse::Ok().content_type("image/svg+xml").body(svg),
+                Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        }
+    }
+}
+
+/// Methods and route templates of `scope`.
+pub const ROUTES: &[(&str, &str)] = &[("GET", "/debug/pprof/profile")];
+
+/// The `/debug/pprof` scope, guarded by the admin credentials in `auth`.
+pub fn scope(auth: web::Data<EndpointAuth>) -> actix_web::Scope {
+    web::scope("/debug/pprof").app_data(auth).route("/profile", web::get().to(profile))
+}
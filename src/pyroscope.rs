@@ -0,0 +1,144 @@
+//! Continuous CPU profiling shipped to Pyroscope (`pyroscope` feature).
+//!
+//! The profiler runs back to back in windows of `PYROSCOPE_UPLOAD_INTERVAL_SECS`;
+//! each window is sent to the Pyroscope `/ingest` HTTP API in folded format.
+//! Profiles are named after `service.name` and tagged with the other
+//! attributes of the shared OTel resource, so they line up with this
+//! service's traces, metrics and logs.
+//!
+//! The profiler is process-wide: while a window is being sampled,
+//! `/debug/pprof/profile` answers 409, and a window is skipped while an
+//! on-demand profile runs.
+
+use crate::{auth::ScrapeCredentials, config::Config, profiling, telemetry};
+use std::{
+    fmt::Write as _,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+/// Uploads profiles of this process to a Pyroscope server.
+#[derive(Clone, Debug)]
+pub struct Pyroscope {
+    client: reqwest::Client,
+    url: String,
+    credentials: Option<ScrapeCredentials>,
+    /// Application name with tags, e.g. `checkout.cpu{service_version=1.2}`.
+    name: String,
+    interval: Duration,
+}
+
+/// Pyroscope tag names are `[a-zA-Z_][a-zA-Z0-9_]*`; values can't contain
+/// the characters delimiting tags.
+fn sanitize(s: &str, value: bool) -> String {
+    s.chars()
+        .map(|c| match c {
+            ',' | '{' | '}' | '=' => '_',
+            c if value || c.is_ascii_alphanumeric() => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// `service.cpu{key=value,...}` from the resource attributes.
+fn app_name(config: &Config) -> String {
+    let resource = telemetry::get_resource(config);
+    let tags: Vec<String> = resource
+        .iter()
+        .filter(|(key, _)| key.as_str() != "service.name")
+        .map(|(key, value)| format!("{}={}", sanitize(key.as_str(), false), sanitize(&value.to_string(), true)))
+        .collect();
+    format!("{}.cpu{{{}}}", sanitize(&config.service_name, true), tags.join(","))
+}
+
+/// One line per distinct stack, root first: `thread;outer;...;inner count`.
+fn folded(report: &pprof::Report) -> String {
+    let mut body = String::new();
+    for (frames, count) in &report.data {
+        body.push_str(&frames.thread_name_or_id());
+        for symbol in frames.frames.iter().rev().flat_map(|frame| frame.iter().rev()) {
+            let _ = write!(body, ";{symbol}");
+        }
+        let _ = writeln!(body, " {count}");
+    }
+    body
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl Pyroscope {
+    /// `None` unless `PYROSCOPE_URL` is set.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let url = config.pyroscope_url.as_ref()?;
+        Some(Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            credentials: config.pyroscope_credentials.clone(),
+            name: app_name(config),
+            interval: config.pyroscope_upload_interval,
+        })
+    }
+
+    async fn upload(&self, from: SystemTime, until: SystemTime, body: String) -> Result<(), reqwest::Error> {
+        let mut request = self
+            .client
+            .post(format!("{}/ingest", self.url))
+            .query(&[
+                ("name", self.name.as_str()),
+                ("from", &unix_secs(from).to_string()),
+                ("until", &unix_secs(until).to_string()),
+                ("format", "folded"),
+                ("sampleRate", &profiling::FREQUENCY.to_string()),
+                ("spyName", "pprof-rs"),
+                ("units", "samples"),
+                ("aggregationType", "sum"),
+            ])
+            .body(body);
+        if let Some(credentials) = &self.credentials {
+            request = request.header("Authorization", credentials.expected_header());
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Profiles and uploads one window after the other, forever.
+    pub async fn run(self) {
+        loop {
+            let Some(lock) = profiling::ProfilerLock::try_acquire() else {
+                debug!("Skipping continuous profile, another profile is running");
+                tokio::time::sleep(self.interval).await;
+                continue;
+            };
+            let guard = match lock.start() {
+                Ok(guard) => guard,
+                Err(e) => {
+                    warn!(error = %e, "Failed to start the CPU profiler");
+                    drop(lock);
+                    tokio::time::sleep(self.interval).await;
+                    continue;
+                }
+            };
+            let from = SystemTime::now();
+            tokio::time::sleep(self.interval).await;
+            let until = SystemTime::now();
+            let report = guard.report().build();
+            drop(guard);
+            drop(lock);
+            let body = match report {
+                Ok(report) => folded(&report),
+                Err(e) => {
+                    warn!(error = %e, "Failed to build CPU profile");
+                    continue;
+                }
+            };
+            if body.is_empty() {
+                continue;
+            }
+            if let Err(e) = self.upload(from, until, body).await {
+                warn!(error = %e, "Failed to upload CPU profile to Pyroscope");
+            }
+        }
+    }
+}
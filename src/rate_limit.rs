@@ -0,0 +1,297 @@
+//! Token-bucket rate limiting (`RATE_LIMITS`).
+//!
+//! A rule gives each client of a route template a bucket of `burst` tokens,
+//! refilled at `requests` per `period`; a request takes a token or is
+//! answered `429 Too Many Requests` with a `Retry-After` header. Rules are
+//! written `/route=requests/period[:burst][@key]` and separated by commas:
+//!
+//! - `period` is `s`, `m` or `h`, and `burst` defaults to `requests`;
+//! - `key` is `ip` (the default), the peer address, or `header:<name>`, the
+//!   value of that request header, e.g. an API key; requests without it are
+//!   keyed by peer address;
+//! - route `*` applies to every route without a rule of its own.
+//!
+//! For example `/search=5/s:10,*=600/m@header:x-api-key`. Every decision is
+//! counted in `rate_limit_requests_total{route,decision}` (`allowed` or
+//! `denied`) and, with the `traces` feature, added to the server span as a
+//! `rate_limit` event. Buckets live in memory, per instance. Past
+//! `MAX_BUCKETS` clients, the buckets that have refilled are dropped, at most
+//! once a second. Until that makes room, new clients are keyed by peer
+//! address, since a caller can send any number of header values but not of
+//! addresses, and only past `MAX_BUCKETS` more do they share one bucket per
+//! rule.
+
+use crate::{
+    cardinality::CardinalityLimiter,
+    config::Config,
+    metrics::AppMetrics,
+    middleware::route_label,
+    registry::Registry,
+    route_rules::{self, RouteRule, RouteRules},
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, Error, HttpResponse,
+};
+#[cfg(feature = "traces")]
+use opentelemetry::{trace::get_active_span, KeyValue};
+use prometheus::{IntCounterVec, Opts};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Clients tracked across all rules before idle buckets are dropped.
+pub const MAX_BUCKETS: usize = 100_000;
+/// Minimum time between two sweeps of the refilled buckets, so a full table
+/// isn't scanned on every request from a new client.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What identifies a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RateLimitKey {
+    Ip,
+    /// A request header, lowercased.
+    Header(String),
+}
+
+/// One rule, see the module documentation for its syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Route template, or `*` for every route.
+    pub route: String,
+    pub requests: u32,
+    /// One second, minute or hour.
+    pub period: Duration,
+    pub burst: u32,
+    pub key: RateLimitKey,
+}
+
+impl RateLimit {
+    /// Tokens added per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("expected `/route=requests/period[:burst][@key]`, got {s:?}");
+        let (spec, key) = match s.rsplit_once('@') {
+            Some((spec, key)) => (spec, key.trim()),
+            None => (s, "ip"),
+        };
+        let key = match key.split_once(':') {
+            None if key == "ip" => RateLimitKey::Ip,
+            Some(("header", name)) if !name.trim().is_empty() => RateLimitKey::Header(name.trim().to_ascii_lowercase()),
+            _ => return Err(format!("rate limit {s:?}: unknown key {key:?}, expected `ip` or `header:<name>`")),
+        };
+        let (route, limit) = route_rules::split(spec).ok_or_else(invalid)?;
+        let (rate, burst) = match limit.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (limit, None),
+        };
+        let (requests, period) = rate.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let period = match period.trim() {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => return Err(format!("rate limit {s:?}: unknown period {period:?}, expected `s`, `m` or `h`")),
+        };
+        let burst = match burst {
+            Some(burst) => burst.trim().parse().map_err(|_| invalid())?,
+            None => requests,
+        };
+        if requests == 0 || burst == 0 {
+            return Err(format!("rate limit {s:?}: requests and burst must be greater than zero"));
+        }
+        Ok(Self { route: route.to_string(), requests, period, burst, key })
+    }
+}
+
+impl RouteRule for RateLimit {
+    fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let period = match self.period.as_secs() {
+            1 => "s",
+            60 => "m",
+            _ => "h",
+        };
+        write!(f, "{}={}/{period}:{}", self.route, self.requests, self.burst)?;
+        match &self.key {
+            RateLimitKey::Ip => write!(f, "@ip"),
+            RateLimitKey::Header(name) => write!(f, "@header:{name}"),
+        }
+    }
+}
+
+/// `rate_limit_requests_total`.
+#[derive(Clone, Debug)]
+pub struct RateLimitMetrics {
+    pub requests: IntCounterVec,
+}
+
+impl RateLimitMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new("rate_limit_requests_total", "Requests checked against a rate limit, by decision"),
+            &["route", "decision"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        Ok(Self { requests })
+    }
+
+    fn observe(&self, cardinality: &CardinalityLimiter, route: &str, decision: &str) {
+        let labels = cardinality.limit("rate_limit_requests_total", [route, decision]);
+        self.requests.with_label_values(&labels).inc();
+    }
+}
+
+/// The outcome of taking a token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// Whole tokens left in the bucket.
+    Allowed { remaining: u32 },
+    /// Time until the next token.
+    Denied { retry_after: Duration },
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The rules and buckets, registered as app data; without rules every
+/// request passes.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rules: RouteRules<RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    /// By rule index and client; `None` is the bucket shared past twice
+    /// `MAX_BUCKETS`.
+    by_client: HashMap<(usize, Option<String>), Bucket>,
+    last_sweep: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RateLimit>, endpoint_prefix: &str) -> Self {
+        Self { rules: RouteRules::new(rules, endpoint_prefix), buckets: Mutex::default() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.rate_limits.clone(), &config.endpoint_prefix)
+    }
+
+    /// Takes a token from `client`'s bucket of rule `index`, or from its
+    /// `peer` address's when the table is full.
+    fn take(&self, index: usize, client: String, peer: String, now: Instant) -> Decision {
+        let rule = &self.rules[index];
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Buckets { by_client, last_sweep } = &mut *buckets;
+        let mut key = (index, Some(client));
+        if !by_client.contains_key(&key) && by_client.len() >= MAX_BUCKETS {
+            if last_sweep.is_none_or(|swept| now.saturating_duration_since(swept) >= SWEEP_INTERVAL) {
+                by_client.retain(|(index, _), bucket| {
+                    let rule = &self.rules[*index];
+                    bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rule.rate()
+                        < f64::from(rule.burst)
+                });
+                *last_sweep = Some(now);
+            }
+            if by_client.len() >= MAX_BUCKETS {
+                key.1 = Some(peer);
+                if !by_client.contains_key(&key) && by_client.len() >= 2 * MAX_BUCKETS {
+                    key.1 = None;
+                }
+            }
+        }
+        let bucket = by_client.entry(key).or_insert(Bucket { tokens: f64::from(rule.burst), updated: now });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rule.rate();
+        bucket.tokens = (bucket.tokens + refill).min(f64::from(rule.burst));
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allowed { remaining: bucket.tokens as u32 }
+        } else {
+            Decision::Denied { retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rule.rate()) }
+        }
+    }
+}
+
+/// Middleware applying the `RateLimiter` registered as app data, if any.
+/// Runs inside `track_requests`, so denials are traced and counted like
+/// other responses.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+    let route = route_label(&req);
+    let Some((limiter, index)) = limiter.and_then(|limiter| limiter.rules.position(&route).map(|index| (limiter, index))) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let rule = &limiter.rules[index];
+    let peer = req.peer_addr().map_or_else(|| "unknown".to_string(), |peer| peer.ip().to_string());
+    let client = match &rule.key {
+        RateLimitKey::Ip => peer.clone(),
+        RateLimitKey::Header(name) => match req.headers().get(name).and_then(|value| value.to_str().ok()) {
+            Some(value) => format!("{name}:{value}"),
+            None => peer.clone(),
+        },
+    };
+    let decision = limiter.take(index, client, peer, Instant::now());
+
+    let name = match decision {
+        Decision::Allowed { .. } => "allowed",
+        Decision::Denied { .. } => "denied",
+    };
+    if let Some(metrics) = req.app_data::<web::Data<AppMetrics>>() {
+        metrics.rate_limit.observe(&metrics.cardinality, &route, name);
+    }
+    #[cfg(feature = "traces")]
+    get_active_span(|span| {
+        let mut attributes = vec![
+            KeyValue::new("rate_limit.decision", name),
+            KeyValue::new("rate_limit.rule", rule.to_string()),
+        ];
+        match decision {
+            Decision::Allowed { remaining } => {
+                attributes.push(KeyValue::new("rate_limit.remaining", i64::from(remaining)));
+            }
+            Decision::Denied { retry_after } => {
+                attributes.push(KeyValue::new("rate_limit.retry_after_ms", retry_after.as_millis() as i64));
+            }
+        }
+        span.add_event("rate_limit", attributes);
+    });
+
+    match decision {
+        Decision::Allowed { .. } => Ok(next.call(req).await?.map_into_left_body()),
+        Decision::Denied { retry_after } => {
+            let response = HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.as_secs_f64().ceil().max(1.0).to_string()))
+                .finish();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Readiness of the service, served on `/readyz` and, with the
+//! `grpc-health` feature, by the gRPC health service.
+//!
+//! The service is ready while both of its checks pass:
+//!
+//! - `server`: the HTTP server is bound and no stop signal has arrived, so
+//!   probes see the drain before in-flight requests finish;
+//! - `exporters`: with an export circuit breaker, no signal's circuit is
+//!   open (see `circuit_breaker`); without one it always passes.
+//!
+//! `/readyz` answers 200 when ready and 503 otherwise, with a
+//! `<check>: ok` or `<check>: <reason>` line per check.
+
+use crate::{
+    circuit_breaker::{CircuitBreakers, CircuitState},
+    pipeline::Signal,
+};
+use actix_web::{web, HttpResponse, Responder};
+use std::{fmt::Write, sync::Arc};
+use tokio::sync::watch;
+
+/// Where the HTTP server is in its life.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ServerState {
+    #[default]
+    Starting,
+    Serving,
+    Draining,
+}
+
+/// The readiness state; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    server: Arc<watch::Sender<ServerState>>,
+    breakers: Option<CircuitBreakers>,
+}
+
+impl Readiness {
+    /// `breakers` are those of the exporters, if any.
+    pub fn new(breakers: Option<CircuitBreakers>) -> Self {
+        Self { server: Arc::new(watch::Sender::new(ServerState::Starting)), breakers }
+    }
+
+    /// The server is bound and accepting requests.
+    pub fn set_serving(&self) {
+        self.server.send_replace(ServerState::Serving);
+    }
+
+    /// A stop signal arrived and requests are draining.
+    pub fn set_draining(&self) {
+        self.server.send_replace(ServerState::Draining);
+    }
+
+    /// Notified when the server state changes; the exporters check can
+    /// change at any time without notice.
+    pub fn subscribe(&self) -> watch::Receiver<ServerState> {
+        self.server.subscribe()
+    }
+
+    /// Each check with `Err` and the reason when it fails.
+    pub fn checks(&self) -> [(&'static str, Result<(), String>); 2] {
+        let server = match *self.server.borrow() {
+            ServerState::Starting => Err("starting".to_string()),
+            ServerState::Serving => Ok(()),
+            ServerState::Draining => Err("draining".to_string()),
+        };
+        let open: Vec<&str> = self
+            .breakers
+            .iter()
+            .flat_map(|breakers| Signal::ALL.map(|signal| (signal, breakers.get(signal))))
+            .filter(|(_, breaker)| breaker.circuit() == CircuitState::Open)
+            .map(|(signal, _)| signal.as_str())
+            .collect();
+        let exporters = if open.is_empty() { Ok(()) } else { Err(format!("circuit open for {}", open.join(","))) };
+        [("server", server), ("exporters", exporters)]
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.checks().iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+/// `GET /readyz`.
+pub async fn handler(readiness: web::Data<Readiness>) -> impl Responder {
+    let checks = readiness.checks();
+    let mut body = String::new();
+    for (name, result) in &checks {
+        let _ = match result {
+            Ok(()) => writeln!(body, "{name}: ok"),
+            Err(reason) => writeln!(body, "{name}: {reason}"),
+        };
+    }
+    let mut response = if checks.iter().all(|(_, result)| result.is_ok()) {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response.content_type("text/plain").body(body)
+}
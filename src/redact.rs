@@ -0,0 +1,365 @@
+//! Scrubbing of sensitive values before logs and spans leave the process.
+//!
+//! A `Redactor` replaces the value of any field whose name is in the
+//! configured list (case-insensitive, also matching the last segment of
+//! dotted names such as `http.request.header.authorization`), and every
+//! match of the configured patterns inside the remaining string values.
+//!
+//! It is applied at each sink: the stdout/file formatters (`RedactingFields`,
+//! `JsonFormat`), the OTLP log bridge (`RedactingLoggerProvider`) and the
+//! span exporter (`RedactingSpanExporter`).
+
+use regex::Regex;
+use std::{borrow::Cow, fmt, sync::Arc};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{format::Writer, FormatFields},
+};
+
+/// Replacement for redacted values.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Field names redacted by default (`REDACT_FIELDS`).
+pub const DEFAULT_REDACT_FIELDS: &[&str] = &["authorization", "cookie", "set-cookie", "password"];
+
+/// Named patterns accepted in `REDACT_PATTERNS` next to raw regexes.
+const PRESETS: &[(&str, &str)] = &[
+    ("email", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}"),
+    ("card_number", r"\b(?:\d[ -]?){12,18}\d\b"),
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    fields: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// `patterns` may be preset names (`email`, `card_number`) or regexes.
+    pub fn new<F, P>(fields: F, patterns: P) -> Result<Self, regex::Error>
+    where
+        F: IntoIterator,
+        F::Item: AsRef<str>,
+        P: IntoIterator,
+        P::Item: AsRef<str>,
+    {
+        let patterns = patterns
+            .into_iter()
+            .map(|pattern| {
+                let pattern = pattern.as_ref();
+                let regex = PRESETS
+                    .iter()
+                    .find(|(name, _)| *name == pattern)
+                    .map_or(pattern, |(_, regex)| regex);
+                Regex::new(regex)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                fields: fields.into_iter().map(|f| f.as_ref().to_ascii_lowercase()).collect(),
+                patterns,
+            }),
+        })
+    }
+
+    pub fn from_config(config: &crate::config::Config) -> Result<Self, regex::Error> {
+        Self::new(&config.redact_fields, &config.redact_patterns)
+    }
+
+    /// Whether values of `field` are replaced entirely.
+    pub fn is_sensitive_field(&self, field: &str) -> bool {
+        let field = field.to_ascii_lowercase();
+        let last_segment = field.rsplit('.').next().unwrap_or(&field);
+        self.inner.fields.iter().any(|f| *f == field || f == last_segment)
+    }
+
+    /// The value to record for `field`.
+    pub fn redact<'a>(&self, field: &str, value: &'a str) -> Cow<'a, str> {
+        if self.is_sensitive_field(field) {
+            return Cow::Borrowed(REDACTED);
+        }
+        self.redact_patterns(value)
+    }
+
+    /// `value` with every pattern match replaced.
+    pub fn redact_patterns<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        let mut value = Cow::Borrowed(value);
+        for pattern in &self.inner.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&value, REDACTED) {
+                value = Cow::Owned(replaced);
+            }
+        }
+        value
+    }
+}
+
+/// `FormatFields` for the text formatter that redacts like `DefaultFields`
+/// formats: the message first as-is, then `name=value` pairs.
+#[derive(Debug)]
+pub struct RedactingFields {
+    redactor: Redactor,
+}
+
+impl RedactingFields {
+    pub fn new(redactor: Redactor) -> Self {
+        Self { redactor }
+    }
+}
+
+impl<'writer> FormatFields<'writer> for RedactingFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = TextVisitor {
+            writer,
+            redactor: &self.redactor,
+            result: Ok(()),
+            first: true,
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+}
+
+struct TextVisitor<'w, 'r> {
+    writer: Writer<'w>,
+    redactor: &'r Redactor,
+    result: fmt::Result,
+    first: bool,
+}
+
+impl TextVisitor<'_, '_> {
+    fn write(&mut self, field: &Field, value: &str, quote: bool) {
+        if self.result.is_err() {
+            return;
+        }
+        let separator = if self.first { "" } else { " " };
+        self.first = false;
+        let value = self.redactor.redact(field.name(), value);
+        self.result = match (field.name(), quote) {
+            ("message", _) => write!(self.writer, "{separator}{value}"),
+            (name, true) => write!(self.writer, "{separator}{name}={value:?}"),
+            (name, false) => write!(self.writer, "{separator}{name}={value}"),
+        };
+    }
+}
+
+impl Visit for TextVisitor<'_, '_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.write(field, value, true);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.write(field, &format!("{value:?}"), false);
+    }
+}
+
+#[cfg(feature = "logs")]
+pub use self::logs::RedactingLoggerProvider;
+
+#[cfg(feature = "logs")]
+mod logs {
+    use super::Redactor;
+    use opentelemetry::{
+        logs::{AnyValue, LogRecord, Logger, LoggerProvider, Severity},
+        trace::{SpanId, TraceFlags, TraceId},
+        InstrumentationScope, Key,
+    };
+    use opentelemetry_sdk::logs::{SdkLogRecord, SdkLogger, SdkLoggerProvider};
+    use std::{borrow::Cow, time::SystemTime};
+
+    /// Logger provider for `OpenTelemetryTracingBridge` that redacts bodies
+    /// and attributes as the bridge records them. SDK log records can't be
+    /// modified once an attribute is added, so this is the last point where
+    /// values can still be replaced.
+    #[derive(Debug)]
+    pub struct RedactingLoggerProvider {
+        inner: SdkLoggerProvider,
+        redactor: Redactor,
+    }
+
+    impl RedactingLoggerProvider {
+        pub fn new(inner: SdkLoggerProvider, redactor: Redactor) -> Self {
+            Self { inner, redactor }
+        }
+    }
+
+    impl LoggerProvider for RedactingLoggerProvider {
+        type Logger = RedactingLogger;
+
+        fn logger_with_scope(&self, scope: InstrumentationScope) -> Self::Logger {
+            RedactingLogger {
+                inner: self.inner.logger_with_scope(scope),
+                redactor: self.redactor.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct RedactingLogger {
+        inner: SdkLogger,
+        redactor: Redactor,
+    }
+
+    impl Logger for RedactingLogger {
+        type LogRecord = RedactingLogRecord;
+
+        fn create_log_record(&self) -> Self::LogRecord {
+            RedactingLogRecord {
+                inner: self.inner.create_log_record(),
+                redactor: self.redactor.clone(),
+            }
+        }
+
+        fn emit(&self, record: Self::LogRecord) {
+            self.inner.emit(record.inner);
+        }
+    }
+
+    pub struct RedactingLogRecord {
+        inner: SdkLogRecord,
+        redactor: Redactor,
+    }
+
+    impl RedactingLogRecord {
+        fn redact_value(&self, key: &Key, value: AnyValue) -> AnyValue {
+            if self.redactor.is_sensitive_field(key.as_str()) {
+                return AnyValue::from(super::REDACTED);
+            }
+            match value {
+                AnyValue::String(s) => match self.redactor.redact_patterns(s.as_str()) {
+                    Cow::Borrowed(_) => AnyValue::String(s),
+                    Cow::Owned(redacted) => AnyValue::from(redacted),
+                },
+                other => other,
+            }
+        }
+    }
+
+    impl LogRecord for RedactingLogRecord {
+        fn set_event_name(&mut self, name: &'static str) {
+            self.inner.set_event_name(name);
+        }
+
+        fn set_target<T>(&mut self, target: T)
+        where
+            T: Into<Cow<'static, str>>,
+        {
+            self.inner.set_target(target);
+        }
+
+        fn set_timestamp(&mut self, timestamp: SystemTime) {
+            self.inner.set_timestamp(timestamp);
+        }
+
+        fn set_observed_timestamp(&mut self, timestamp: SystemTime) {
+            self.inner.set_observed_timestamp(timestamp);
+        }
+
+        fn set_severity_text(&mut self, text: &'static str) {
+            self.inner.set_severity_text(text);
+        }
+
+        fn set_severity_number(&mut self, number: Severity) {
+            self.inner.set_severity_number(number);
+        }
+
+        fn set_body(&mut self, body: AnyValue) {
+            let body = self.redact_value(&Key::from_static_str("message"), body);
+            self.inner.set_body(body);
+        }
+
+        fn add_attributes<I, K, V>(&mut self, attributes: I)
+        where
+            I: IntoIterator<Item = (K, V)>,
+            K: Into<Key>,
+            V: Into<AnyValue>,
+        {
+            for (key, value) in attributes {
+                self.add_attribute(key, value);
+            }
+        }
+
+        fn add_attribute<K, V>(&mut self, key: K, value: V)
+        where
+            K: Into<Key>,
+            V: Into<AnyValue>,
+        {
+            let key = key.into();
+            let value = self.redact_value(&key, value.into());
+            self.inner.add_attribute(key, value);
+        }
+
+        fn set_trace_context(&mut self, trace_id: TraceId, span_id: SpanId, trace_flags: Option<TraceFlags>) {
+            self.inner.set_trace_context(trace_id, span_id, trace_flags);
+        }
+    }
+}
+
+#[cfg(feature = "traces")]
+pub use self::traces::RedactingSpanExporter;
+
+#[cfg(feature = "traces")]
+mod traces {
+    use super::Redactor;
+    use opentelemetry::{KeyValue, StringValue, Value};
+    use opentelemetry_sdk::{
+        error::OTelSdkResult,
+        trace::{SpanData, SpanExporter},
+        Resource,
+    };
+    use std::{borrow::Cow, time::Duration};
+
+    /// Redacts span and span event attributes before handing the batch on.
+    #[derive(Debug)]
+    pub struct RedactingSpanExporter<E> {
+        inner: E,
+        redactor: Redactor,
+    }
+
+    impl<E> RedactingSpanExporter<E> {
+        pub fn new(inner: E, redactor: Redactor) -> Self {
+            Self { inner, redactor }
+        }
+
+        fn redact_attributes(&self, attributes: &mut [KeyValue]) {
+            for attribute in attributes {
+                if self.redactor.is_sensitive_field(attribute.key.as_str()) {
+                    attribute.value = Value::from(super::REDACTED);
+                } else if let Value::String(s) = &attribute.value
+                    && let Cow::Owned(redacted) = self.redactor.redact_patterns(s.as_str())
+                {
+                    attribute.value = Value::String(StringValue::from(redacted));
+                }
+            }
+        }
+    }
+
+    impl<E: SpanExporter> SpanExporter for RedactingSpanExporter<E> {
+        async fn export(&self, mut batch: Vec<SpanData>) -> OTelSdkResult {
+            for span in &mut batch {
+                self.redact_attributes(&mut span.attributes);
+                for event in span.events.events.iter_mut() {
+                    self.redact_attributes(&mut event.attributes);
+                }
+            }
+            self.inner.export(batch).await
+        }
+
+        fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+            self.inner.shutdown_with_timeout(timeout)
+        }
+
+        fn force_flush(&mut self) -> OTelSdkResult {
+            self.inner.force_flush()
+        }
+
+        fn set_resource(&mut self, resource: &Resource) {
+            self.inner.set_resource(resource);
+        }
+    }
+}
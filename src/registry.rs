@@ -0,0 +1,120 @@
+//! The registry behind `/metrics`, remembering the families it holds.
+//!
+//! A `prometheus::Registry` only hands out what its collectors gather, and
+//! drops the families without series, so a labeled family nothing used yet
+//! can't be listed. `Registry` wraps one and records, when a collector is
+//! registered, the families its `desc()` describes: name, help, labels and
+//! type, the type taken from what the collector collects, as
+//! `naming::check_collector` does. Descriptors the collector doesn't
+//! collect a family for, like the placeholders of `Federation` and
+//! `NamedRegistry`, aren't recorded; their families are only known from a
+//! gather.
+//!
+//! It derefs to the `prometheus` registry for gathering, but collectors
+//! registered through that aren't recorded.
+
+use prometheus::{
+    core::Collector,
+    proto::{MetricFamily, MetricType},
+    Result,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Deref,
+    sync::{Arc, RwLock},
+};
+
+/// A family as its collector describes it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Described {
+    pub name: String,
+    pub help: String,
+    pub kind: MetricType,
+    /// Variable and constant label names, sorted, the registry's constant
+    /// labels included.
+    pub labels: Vec<String>,
+}
+
+/// See the module documentation; cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    inner: prometheus::Registry,
+    const_labels: Vec<String>,
+    described: Arc<RwLock<BTreeMap<String, Described>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like `prometheus::Registry::new_custom`.
+    pub fn new_custom(prefix: Option<String>, labels: Option<HashMap<String, String>>) -> Result<Self> {
+        let const_labels = labels.iter().flatten().map(|(name, _)| name.clone()).collect();
+        Ok(Self { inner: prometheus::Registry::new_custom(prefix, labels)?, const_labels, described: Arc::default() })
+    }
+
+    /// Registers `collector` and records the families it describes.
+    pub fn register(&self, collector: Box<dyn Collector>) -> Result<()> {
+        let described = self.describe(collector.as_ref());
+        self.inner.register(collector)?;
+        let mut families = self.described.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for family in described {
+            families.entry(family.name.clone()).or_insert(family);
+        }
+        Ok(())
+    }
+
+    /// Unregisters `collector` and forgets the families it describes.
+    pub fn unregister(&self, collector: Box<dyn Collector>) -> Result<()> {
+        let names: Vec<String> = collector.desc().iter().map(|desc| desc.fq_name.clone()).collect();
+        self.inner.unregister(collector)?;
+        let mut families = self.described.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for name in names {
+            families.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// The recorded families, sorted by name.
+    pub fn described(&self) -> Vec<Described> {
+        self.described.read().unwrap_or_else(|poisoned| poisoned.into_inner()).values().cloned().collect()
+    }
+
+    /// The type of the recorded family `name`, if any.
+    pub fn family_type(&self, name: &str) -> Option<MetricType> {
+        self.described.read().unwrap_or_else(|poisoned| poisoned.into_inner()).get(name).map(|family| family.kind)
+    }
+
+    fn describe(&self, collector: &dyn Collector) -> Vec<Described> {
+        let families = collector.collect();
+        collector
+            .desc()
+            .iter()
+            .filter_map(|desc| {
+                let kind = families
+                    .iter()
+                    .find(|family| family.name() == desc.fq_name)
+                    .map(MetricFamily::get_field_type)?;
+                let mut labels: Vec<String> = desc
+                    .variable_labels
+                    .iter()
+                    .cloned()
+                    .chain(desc.const_label_pairs.iter().map(|pair| pair.name().to_string()))
+                    .chain(self.const_labels.iter().cloned())
+                    .collect();
+                labels.sort();
+                labels.dedup();
+                Some(Described { name: desc.fq_name.clone(), help: desc.help.clone(), kind, labels })
+            })
+            .collect()
+    }
+}
+
+impl Deref for Registry {
+    type Target = prometheus::Registry;
+
+    fn deref(&self) -> &prometheus::Registry {
+        &self.inner
+    }
+}
@@ -0,0 +1,313 @@
+//! Trace sampling strategies fetched from a Jaeger remote sampling endpoint
+//! (`REMOTE_SAMPLING_URL`, `remote-sampling` feature).
+//!
+//! `watch` polls `GET {url}?service=<OTEL_SERVICE_NAME>` every
+//! `REMOTE_SAMPLING_INTERVAL_SECS`, e.g. the Jaeger agent's or collector's
+//! `:5778/sampling` or the collector's `jaeger_remote_sampling` extension, and
+//! `RemoteSampler` applies the strategy it returns to root spans:
+//!
+//! - `PROBABILISTIC`: `probabilisticSampling.samplingRate` of the traces;
+//! - `RATE_LIMITING`: at most `rateLimitingSampling.maxTracesPerSecond`;
+//! - `operationSampling`, which takes precedence: the rate of the
+//!   `perOperationStrategies` entry whose `operation` is the span name, else
+//!   `defaultSamplingProbability`, and at least
+//!   `defaultLowerBoundTracesPerSecond` traces per operation.
+//!
+//! So the ops team can retune trace volume across the fleet from one place.
+//! Until the first strategy arrives `REMOTE_SAMPLING_INITIAL_RATIO` (default
+//! `0.001`) of the traces are sampled; a failed poll keeps the current
+//! strategy. Like the `parentbased_*` samplers, spans with a parent follow
+//! its decision. Sampled root spans get `sampler.type` and `sampler.param`
+//! attributes, as with Jaeger clients, and every new strategy is recorded in
+//! the audit trail.
+//!
+//! `OTEL_TRACES_SAMPLER=jaeger_remote` (or `parentbased_jaeger_remote`) with
+//! `OTEL_TRACES_SAMPLER_ARG=endpoint=...,pollingIntervalMs=...,initialSamplingRate=...`
+//! configures the same.
+
+use crate::audit::{ChangeSource, ConfigChanges};
+use opentelemetry::{
+    trace::{Link, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::trace::ShouldSample;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Operations given a lower bound bucket at most, as in Jaeger clients.
+const MAX_OPERATIONS: usize = 2000;
+
+/// A sampling strategy, as served by the remote endpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Strategy {
+    /// Share of the traces sampled.
+    Probabilistic(f64),
+    /// Traces sampled per second at most.
+    RateLimiting(f64),
+    PerOperation {
+        /// Share of the traces sampled for operations without their own.
+        default_ratio: f64,
+        /// Traces sampled per second and operation at least.
+        lower_bound: f64,
+        /// Share of the traces sampled, by operation (span name).
+        operations: BTreeMap<String, f64>,
+    },
+}
+
+impl Strategy {
+    /// Parses a Jaeger sampling strategy response, with `strategyType` as a
+    /// name or as its Thrift number.
+    pub fn from_json(body: &[u8]) -> Result<Self, String> {
+        let response: Value = serde_json::from_slice(body).map_err(|e| format!("invalid strategy: {e}"))?;
+        let ratio = |value: Option<&Value>, field: &str| {
+            value
+                .and_then(Value::as_f64)
+                .filter(|ratio| (0.0..=1.0).contains(ratio))
+                .ok_or_else(|| format!("{field} must be a number between 0 and 1"))
+        };
+        if let Some(operation) = response.get("operationSampling").filter(|value| !value.is_null()) {
+            let default_ratio = ratio(operation.get("defaultSamplingProbability"), "defaultSamplingProbability")?;
+            let lower_bound = operation
+                .get("defaultLowerBoundTracesPerSecond")
+                .map_or(Some(0.0), Value::as_f64)
+                .filter(|rate| *rate >= 0.0)
+                .ok_or("defaultLowerBoundTracesPerSecond must be a positive number")?;
+            let operations = operation
+                .get("perOperationStrategies")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .map(|strategy| {
+                    let name = strategy.get("operation").and_then(Value::as_str).ok_or("operation must be a string")?;
+                    let rate = ratio(
+                        strategy.get("probabilisticSampling").and_then(|sampling| sampling.get("samplingRate")),
+                        "samplingRate",
+                    )?;
+                    Ok((name.to_string(), rate))
+                })
+                .collect::<Result<_, String>>()?;
+            return Ok(Strategy::PerOperation { default_ratio, lower_bound, operations });
+        }
+        let strategy_type = response.get("strategyType");
+        let is_rate_limiting = strategy_type.and_then(Value::as_str) == Some("RATE_LIMITING")
+            || strategy_type.and_then(Value::as_u64) == Some(1);
+        if is_rate_limiting {
+            let rate = response
+                .get("rateLimitingSampling")
+                .and_then(|sampling| sampling.get("maxTracesPerSecond"))
+                .and_then(Value::as_f64)
+                .filter(|rate| *rate >= 0.0)
+                .ok_or("maxTracesPerSecond must be a positive number")?;
+            return Ok(Strategy::RateLimiting(rate));
+        }
+        let rate = ratio(
+            response.get("probabilisticSampling").and_then(|sampling| sampling.get("samplingRate")),
+            "samplingRate",
+        )?;
+        Ok(Strategy::Probabilistic(rate))
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Probabilistic(ratio) => write!(f, "probabilistic {ratio}"),
+            Strategy::RateLimiting(rate) => write!(f, "rate_limiting {rate}/s"),
+            Strategy::PerOperation { default_ratio, lower_bound, operations } => {
+                write!(f, "per_operation default {default_ratio}, lower bound {lower_bound}/s")?;
+                for (operation, ratio) in operations {
+                    write!(f, ", {operation}={ratio}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Token bucket allowing `rate` decisions per second, with a burst of at
+/// least one.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self { tokens: rate.max(1.0), updated: now }
+    }
+
+    fn take(&mut self, rate: f64, now: Instant) -> bool {
+        let refill = now.duration_since(self.updated).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate.max(1.0));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    strategy: Strategy,
+    /// The `RateLimiting` bucket.
+    limit: Bucket,
+    /// The `PerOperation` lower bound buckets, by operation.
+    lower_bounds: HashMap<String, Bucket>,
+}
+
+/// The current strategy, shared by `watch` and `RemoteSampler`; cheap to
+/// clone.
+#[derive(Clone, Debug)]
+pub struct RemoteStrategy(Arc<Mutex<State>>);
+
+impl RemoteStrategy {
+    /// Samples `initial_ratio` of the traces until `set` is called.
+    pub fn new(initial_ratio: f64) -> Self {
+        let now = Instant::now();
+        Self(Arc::new(Mutex::new(State {
+            strategy: Strategy::Probabilistic(initial_ratio),
+            limit: Bucket::new(0.0, now),
+            lower_bounds: HashMap::new(),
+        })))
+    }
+
+    pub fn get(&self) -> Strategy {
+        self.0.lock().unwrap().strategy.clone()
+    }
+
+    /// Replaces the strategy, starting its buckets afresh.
+    pub fn set(&self, strategy: Strategy) {
+        let mut state = self.0.lock().unwrap();
+        let now = Instant::now();
+        state.limit = Bucket::new(if let Strategy::RateLimiting(rate) = strategy { rate } else { 0.0 }, now);
+        state.lower_bounds.clear();
+        state.strategy = strategy;
+    }
+}
+
+/// Whether `trace_id` falls within `ratio`, as decided by the SDK's
+/// `TraceIdRatioBased` sampler.
+fn within_ratio(trace_id: TraceId, ratio: f64) -> bool {
+    let bytes = trace_id.to_bytes();
+    let low = u64::from_be_bytes(bytes[8..].try_into().unwrap());
+    (low >> 1) < (ratio.max(0.0) * (1u64 << 63) as f64) as u64
+}
+
+/// Samples root spans with the `RemoteStrategy`; spans with a parent follow
+/// its decision. Not wrapped in `Sampler::ParentBased`, which drops the
+/// attributes of its delegate.
+#[derive(Clone, Debug)]
+pub struct RemoteSampler {
+    strategy: RemoteStrategy,
+}
+
+impl RemoteSampler {
+    pub fn new(strategy: RemoteStrategy) -> Self {
+        Self { strategy }
+    }
+
+    /// The decision for a root span named `name`, with the sampler type and
+    /// parameter it was made with.
+    fn decide(&self, trace_id: TraceId, name: &str) -> (bool, &'static str, f64) {
+        let mut state = self.strategy.0.lock().unwrap();
+        let now = Instant::now();
+        let State { strategy, limit, lower_bounds } = &mut *state;
+        match strategy {
+            Strategy::Probabilistic(ratio) => (within_ratio(trace_id, *ratio), "probabilistic", *ratio),
+            Strategy::RateLimiting(rate) => (limit.take(*rate, now), "ratelimiting", *rate),
+            Strategy::PerOperation { default_ratio, lower_bound, operations } => {
+                let ratio = operations.get(name).copied().unwrap_or(*default_ratio);
+                if within_ratio(trace_id, ratio) {
+                    return (true, "probabilistic", ratio);
+                }
+                if *lower_bound <= 0.0 || !lower_bounds.contains_key(name) && lower_bounds.len() >= MAX_OPERATIONS {
+                    return (false, "probabilistic", ratio);
+                }
+                let bucket = lower_bounds.entry(name.to_string()).or_insert_with(|| Bucket::new(*lower_bound, now));
+                (bucket.take(*lower_bound, now), "lowerbound", *lower_bound)
+            }
+        }
+    }
+}
+
+impl ShouldSample for RemoteSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        _span_kind: &SpanKind,
+        _attributes: &[KeyValue],
+        _links: &[Link],
+    ) -> SamplingResult {
+        let parent = parent_context.filter(|cx| cx.has_active_span());
+        if let Some(cx) = parent {
+            return SamplingResult {
+                decision: if cx.span().span_context().is_sampled() {
+                    SamplingDecision::RecordAndSample
+                } else {
+                    SamplingDecision::Drop
+                },
+                attributes: Vec::new(),
+                trace_state: cx.span().span_context().trace_state().clone(),
+            };
+        }
+        let (sampled, kind, param) = self.decide(trace_id, name);
+        SamplingResult {
+            decision: if sampled { SamplingDecision::RecordAndSample } else { SamplingDecision::Drop },
+            attributes: if sampled {
+                vec![KeyValue::new("sampler.type", kind), KeyValue::new("sampler.param", param)]
+            } else {
+                Vec::new()
+            },
+            trace_state: Default::default(),
+        }
+    }
+}
+
+/// Fetches the strategy of `service` from `url`.
+async fn fetch(client: &reqwest::Client, url: &str, service: &str) -> Result<Strategy, String> {
+    let response = client
+        .get(url)
+        .query(&[("service", service)])
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| e.to_string())?;
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    Strategy::from_json(&body)
+}
+
+/// Polls `url` for the strategy of `service` every `interval`, each request
+/// bounded by it, and applies new strategies to `strategy`, recording them in
+/// `changes`. Failed polls keep the current strategy.
+pub async fn watch(strategy: RemoteStrategy, url: String, service: String, interval: Duration, changes: ConfigChanges) {
+    let client = reqwest::Client::builder().timeout(interval).build().unwrap_or_default();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let new = match fetch(&client, &url, &service).await {
+            Ok(new) => new,
+            Err(e) => {
+                warn!(url, error = %e, "Failed to fetch the trace sampling strategy");
+                continue;
+            }
+        };
+        let old = strategy.get();
+        if old != new {
+            changes.record(ChangeSource::RemoteSampling, "trace_sampling_strategy", &old.to_string(), &new.to_string());
+            strategy.set(new);
+        }
+    }
+}
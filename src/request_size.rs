@@ -0,0 +1,215 @@
+//! Per-route request body limits (`REQUEST_SIZE_LIMITS`).
+//!
+//! A rule caps the request bodies of a route template. A request declaring a
+//! larger `Content-Length` is answered `413 Payload Too Large` before its
+//! handler runs; a body without one (chunked) fails with the same status as
+//! soon as the handler reads past the limit. Rules are written
+//! `/route=size` and separated by commas, the size in bytes or in KiB or MiB
+//! with a `k` or `m` suffix; route `*` applies to every route without a rule
+//! of its own. For example `/upload=10m,*=64k`.
+//!
+//! Rejections are counted in `http_rejected_requests_total{reason}`,
+//! `content_length` or `body_size`, and, with the `traces` feature, added to
+//! the server span as a `request_size` event. The limit of each rule is
+//! exported as `http_request_size_limit_bytes{route}`, so dashboards can put
+//! the rejections next to what was configured.
+
+use crate::{
+    config::Config,
+    metrics::AppMetrics,
+    middleware::route_label,
+    registry::Registry,
+    route_rules::{self, RouteRule, RouteRules},
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::header,
+    middleware::Next,
+    web::{self, Bytes},
+    Error, HttpMessage, HttpResponse,
+};
+use futures_util::Stream;
+#[cfg(feature = "traces")]
+use opentelemetry::{trace::get_active_span, KeyValue};
+use prometheus::{IntCounterVec, IntGaugeVec, Opts};
+use std::{
+    fmt,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+/// One rule, see the module documentation for its syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestSizeLimit {
+    /// Route template, or `*` for every route.
+    pub route: String,
+    pub max_bytes: u64,
+}
+
+impl FromStr for RequestSizeLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("expected `/route=bytes`, `/route=<KiB>k` or `/route=<MiB>m`, got {s:?}");
+        let (route, size) = route_rules::split(s).ok_or_else(invalid)?;
+        let (number, unit) = match size.strip_suffix(['k', 'K']) {
+            Some(number) => (number, 1024),
+            None => match size.strip_suffix(['m', 'M']) {
+                Some(number) => (number, 1024 * 1024),
+                None => (size, 1),
+            },
+        };
+        let max_bytes = number
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit))
+            .ok_or_else(invalid)?;
+        if max_bytes == 0 {
+            return Err(format!("request size limit {s:?}: the limit must be greater than zero"));
+        }
+        Ok(Self { route: route.to_string(), max_bytes })
+    }
+}
+
+impl RouteRule for RequestSizeLimit {
+    fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+impl fmt::Display for RequestSizeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.route, self.max_bytes)
+    }
+}
+
+/// `http_rejected_requests_total` and `http_request_size_limit_bytes`.
+#[derive(Clone, Debug)]
+pub struct RequestSizeMetrics {
+    pub rejected: IntCounterVec,
+    pub limits: IntGaugeVec,
+}
+
+impl RequestSizeMetrics {
+    pub fn new(registry: &Registry, rules: &[RequestSizeLimit]) -> prometheus::Result<Self> {
+        let rejected = IntCounterVec::new(
+            Opts::new("http_rejected_requests_total", "Requests rejected before or while their body was read, by reason"),
+            &["reason"],
+        )?;
+        let limits = IntGaugeVec::new(
+            Opts::new("http_request_size_limit_bytes", "Largest request body accepted on a route template"),
+            &["route"],
+        )?;
+        for rule in rules {
+            limits.with_label_values(&[rule.route.as_str()]).set(rule.max_bytes.min(i64::MAX as u64) as i64);
+        }
+        registry.register(Box::new(rejected.clone()))?;
+        registry.register(Box::new(limits.clone()))?;
+        Ok(Self { rejected, limits })
+    }
+}
+
+/// The rules, registered as app data; without rules every body is accepted.
+#[derive(Debug)]
+pub struct RequestSizeLimits {
+    rules: RouteRules<RequestSizeLimit>,
+}
+
+impl RequestSizeLimits {
+    pub fn new(rules: Vec<RequestSizeLimit>, endpoint_prefix: &str) -> Self {
+        Self { rules: RouteRules::new(rules, endpoint_prefix) }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.request_size_limits.clone(), &config.endpoint_prefix)
+    }
+}
+
+/// Counts a rejection for `reason` and adds it to the active span.
+fn reject(metrics: Option<&RequestSizeMetrics>, rule: &RequestSizeLimit, reason: &'static str, bytes: u64) {
+    if let Some(metrics) = metrics {
+        metrics.rejected.with_label_values(&[reason]).inc();
+    }
+    #[cfg(feature = "traces")]
+    get_active_span(|span| {
+        span.add_event(
+            "request_size",
+            vec![
+                KeyValue::new("request_size.reason", reason),
+                KeyValue::new("request_size.bytes", bytes.min(i64::MAX as u64) as i64),
+                KeyValue::new("request_size.rule", rule.to_string()),
+            ],
+        );
+    });
+    #[cfg(not(feature = "traces"))]
+    let _ = (rule, bytes);
+}
+
+/// Middleware applying the `RequestSizeLimits` registered as app data, if
+/// any. Runs inside `track_requests`, so rejections are traced and counted
+/// like other responses.
+pub async fn guard(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let limits = req.app_data::<web::Data<RequestSizeLimits>>().cloned();
+    let route = route_label(&req);
+    let Some(rule) = limits.as_ref().and_then(|limits| limits.rules.get(&route)).cloned() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let metrics = req.app_data::<web::Data<AppMetrics>>().and_then(|metrics| metrics.request_size.clone());
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    match length {
+        Some(length) if length > rule.max_bytes => {
+            reject(metrics.as_ref(), &rule, "content_length", length);
+            let response = HttpResponse::PayloadTooLarge().finish();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+        Some(_) => {}
+        None => {
+            let payload = req.take_payload();
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(LimitedPayload { payload, bytes: 0, rule, metrics, exceeded: false }),
+            });
+        }
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// A request payload failing with `PayloadError::Overflow`, a 413, once more
+/// than the rule's limit was read from it.
+struct LimitedPayload {
+    payload: Payload,
+    bytes: u64,
+    rule: RequestSizeLimit,
+    metrics: Option<RequestSizeMetrics>,
+    exceeded: bool,
+}
+
+impl Stream for LimitedPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.exceeded {
+            return Poll::Ready(None);
+        }
+        let polled = Pin::new(&mut self.payload).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &polled {
+            self.bytes += chunk.len() as u64;
+            if self.bytes > self.rule.max_bytes {
+                self.exceeded = true;
+                reject(self.metrics.as_ref(), &self.rule, "body_size", self.bytes);
+                return Poll::Ready(Some(Err(PayloadError::Overflow)));
+            }
+        }
+        polled
+    }
+}
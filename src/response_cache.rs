@@ -0,0 +1,316 @@
+//! In-memory response caching (`RESPONSE_CACHE`).
+//!
+//! A rule caches the responses of a route template for a TTL: while a
+//! response is fresh, the same `GET` (path, query and `Accept` header) is
+//! answered from memory, with an `Age` header, without calling the handler.
+//! Rules are written `/route=ms` and separated by commas; route `*` applies
+//! to every route without a rule of its own. For example
+//! `/catalog=30000,/prices=1000`.
+//!
+//! Route `*` doesn't apply to the built-in endpoints (`/metrics`, `/readyz`
+//! and the others under `ENDPOINT_PREFIX`), so readiness and drain show
+//! at once; they are only cached by a rule naming them.
+//!
+//! Only `200 OK` responses with a body of known size up to `MAX_BODY_BYTES`
+//! are stored, and not those setting a cookie, marked `Cache-Control:
+//! no-store` or `private`, or with a `Vary` naming any request header but
+//! `Accept`, which isn't part of the key. Requests carrying an
+//! `Authorization` or `Cookie` header or, with `TENANT_SOURCE`, a tenant
+//! bypass the cache, as their responses may be someone's own.
+//!
+//! Lookups are counted in `http_response_cache_lookups_total{route,result}`
+//! (`hit` or `miss`), removed entries in
+//! `http_response_cache_evictions_total{reason}` (`expired`, or `capacity`
+//! past `RESPONSE_CACHE_MAX_ENTRIES`, the one expiring first) and stored ones
+//! in `http_response_cache_entries`. With the `traces` feature each lookup
+//! is added to the server span as a `response_cache` event. Hits still go
+//! through `track_requests`, so they are traced and counted like other
+//! requests.
+
+use crate::{
+    cardinality::CardinalityLimiter,
+    config::Config,
+    metrics::AppMetrics,
+    middleware::route_label,
+    registry::Registry,
+    route_rules::{self, RouteRule, RouteRules},
+    server,
+    tenant::TenantId,
+};
+use actix_web::{
+    body::{self, BodySize, BoxBody, EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderMap},
+        Method, StatusCode,
+    },
+    middleware::Next,
+    web::{self, Bytes},
+    Error, HttpMessage, HttpResponse,
+};
+#[cfg(feature = "traces")]
+use opentelemetry::{trace::get_active_span, KeyValue};
+use prometheus::{IntCounterVec, IntGauge, Opts};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Largest body stored; bigger responses are passed through.
+pub const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// One rule, see the module documentation for its syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseCacheRule {
+    /// Route template, or `*` for every route.
+    pub route: String,
+    pub ttl: Duration,
+}
+
+impl FromStr for ResponseCacheRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("expected `/route=ms`, got {s:?}");
+        let (route, ms) = route_rules::split(s).ok_or_else(invalid)?;
+        let ms: u64 = ms.parse().map_err(|_| invalid())?;
+        if ms == 0 {
+            return Err(format!("response cache {s:?}: the TTL must be greater than zero"));
+        }
+        Ok(Self { route: route.to_string(), ttl: Duration::from_millis(ms) })
+    }
+}
+
+impl RouteRule for ResponseCacheRule {
+    fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+impl fmt::Display for ResponseCacheRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.route, self.ttl.as_millis())
+    }
+}
+
+/// `http_response_cache_*`.
+#[derive(Clone, Debug)]
+pub struct ResponseCacheMetrics {
+    pub lookups: IntCounterVec,
+    pub evictions: IntCounterVec,
+    pub entries: IntGauge,
+}
+
+impl ResponseCacheMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let lookups = IntCounterVec::new(
+            Opts::new("http_response_cache_lookups_total", "Response cache lookups by route and result (hit or miss)"),
+            &["route", "result"],
+        )?;
+        let evictions = IntCounterVec::new(
+            Opts::new("http_response_cache_evictions_total", "Responses removed from the cache, by reason"),
+            &["reason"],
+        )?;
+        let entries = IntGauge::new("http_response_cache_entries", "Responses held in the cache")?;
+        registry.register(Box::new(lookups.clone()))?;
+        registry.register(Box::new(evictions.clone()))?;
+        registry.register(Box::new(entries.clone()))?;
+        Ok(Self { lookups, evictions, entries })
+    }
+
+    fn observe(&self, cardinality: &CardinalityLimiter, route: &str, result: &str) {
+        let labels = cardinality.limit("http_response_cache_lookups_total", [route, result]);
+        self.lookups.with_label_values(&labels).inc();
+    }
+}
+
+/// A stored response.
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// The rules and the responses they stored, registered as app data; without
+/// rules nothing is cached.
+pub struct ResponseCache {
+    rules: RouteRules<ResponseCacheRule>,
+    max_entries: usize,
+    /// Routes `*` doesn't apply to.
+    builtin: Vec<String>,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseCache").field("rules", &self.rules).field("max_entries", &self.max_entries).finish()
+    }
+}
+
+impl ResponseCache {
+    /// `builtin` are the route templates `*` doesn't apply to.
+    pub fn new(rules: Vec<ResponseCacheRule>, max_entries: usize, endpoint_prefix: &str, builtin: Vec<String>) -> Self {
+        Self { rules: RouteRules::new(rules, endpoint_prefix), max_entries, builtin, entries: Mutex::default() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let builtin = server::routes(config).into_iter().map(|(_, route)| route).filter(|route| route != "/").collect();
+        Self::new(config.response_cache.clone(), config.response_cache_max_entries, &config.endpoint_prefix, builtin)
+    }
+
+    /// The rule applying to `route`, see `RouteRules::get`, unless it is the
+    /// `*` one and `route` is built in.
+    fn rule(&self, route: &str) -> Option<&ResponseCacheRule> {
+        self.rules.get(route).filter(|rule| rule.route != "*" || !self.builtin.iter().any(|r| r == route))
+    }
+
+    /// The fresh response stored under `key` and its age, removing it if it
+    /// has expired.
+    fn get(&self, key: &str, now: Instant, metrics: Option<&AppMetrics>) -> Option<(HttpResponse, Duration)> {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let entry = entries.get(key)?;
+        if entry.expires <= now {
+            entries.remove(key);
+            if let Some(metrics) = metrics {
+                metrics.response_cache.evictions.with_label_values(&["expired"]).inc();
+                metrics.response_cache.entries.set(entries.len() as i64);
+            }
+            return None;
+        }
+        let age = now.duration_since(entry.stored);
+        let mut response = HttpResponse::build(entry.status).body(entry.body.clone());
+        for (name, value) in &entry.headers {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+        Some((response, age))
+    }
+
+    /// Stores `entry` under `key`, first dropping the expired entries and
+    /// then, when still full, the one expiring first.
+    fn insert(&self, key: String, entry: Entry, metrics: Option<&AppMetrics>) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            let now = entry.stored;
+            let before = entries.len();
+            entries.retain(|_, entry| entry.expires > now);
+            let expired = before - entries.len();
+            let oldest = (entries.len() >= self.max_entries)
+                .then(|| entries.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone()))
+                .flatten();
+            if let Some(oldest) = &oldest {
+                entries.remove(oldest);
+            }
+            if let Some(metrics) = metrics {
+                metrics.response_cache.evictions.with_label_values(&["expired"]).inc_by(expired as u64);
+                if oldest.is_some() {
+                    metrics.response_cache.evictions.with_label_values(&["capacity"]).inc();
+                }
+            }
+        }
+        entries.insert(key, entry);
+        if let Some(metrics) = metrics {
+            metrics.response_cache.entries.set(entries.len() as i64);
+        }
+    }
+}
+
+/// Whether the response may be stored and served to others.
+fn storable(response: &HttpResponse<impl MessageBody>) -> bool {
+    let mut cache_control = response.headers().get_all(header::CACHE_CONTROL).filter_map(|value| value.to_str().ok());
+    let no_store = cache_control.any(|value| {
+        value.split(',').map(str::trim).any(|directive| {
+            directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("private")
+        })
+    });
+    // The key only tells requests apart by their `Accept` header.
+    let varies = response.headers().get_all(header::VARY).any(|value| {
+        value.to_str().map_or(true, |value| {
+            value.split(',').map(str::trim).any(|name| !name.is_empty() && !name.eq_ignore_ascii_case("accept"))
+        })
+    });
+    response.status() == StatusCode::OK
+        && !no_store
+        && !varies
+        && !response.headers().contains_key(header::SET_COOKIE)
+        && matches!(response.body().size(), BodySize::Sized(size) if size <= MAX_BODY_BYTES)
+}
+
+#[cfg(feature = "traces")]
+fn record(result: &'static str, rule: &ResponseCacheRule, age: Option<Duration>) {
+    get_active_span(|span| {
+        let mut attributes =
+            vec![KeyValue::new("response_cache.result", result), KeyValue::new("response_cache.rule", rule.to_string())];
+        if let Some(age) = age {
+            attributes.push(KeyValue::new("response_cache.age_ms", age.as_millis() as i64));
+        }
+        span.add_event("response_cache", attributes);
+    });
+}
+
+/// Middleware serving and storing responses for the `ResponseCache`
+/// registered as app data, if any. Runs inside `track_requests`, so hits are
+/// traced and counted like other responses.
+pub async fn serve(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let cache = req.app_data::<web::Data<ResponseCache>>().cloned();
+    let route = route_label(&req);
+    let private = req.headers().contains_key(header::AUTHORIZATION)
+        || req.headers().contains_key(header::COOKIE)
+        || req.extensions().contains::<TenantId>();
+    let rule = match &cache {
+        Some(cache) if req.method() == Method::GET && !private => cache.rule(&route).cloned(),
+        _ => None,
+    };
+    let (Some(cache), Some(rule)) = (cache, rule) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let metrics = req.app_data::<web::Data<AppMetrics>>().map(|metrics| metrics.clone().into_inner());
+    let accept = req.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok()).unwrap_or_default();
+    let key = format!("{}\n{accept}", req.uri().path_and_query().map_or("/", |path| path.as_str()));
+    let now = Instant::now();
+
+    if let Some((mut response, age)) = cache.get(&key, now, metrics.as_deref()) {
+        if let Some(metrics) = &metrics {
+            metrics.response_cache.observe(&metrics.cardinality, &route, "hit");
+        }
+        #[cfg(feature = "traces")]
+        record("hit", &rule, Some(age));
+        response.headers_mut().insert(header::AGE, age.as_secs().into());
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    if let Some(metrics) = &metrics {
+        metrics.response_cache.observe(&metrics.cardinality, &route, "miss");
+    }
+    #[cfg(feature = "traces")]
+    record("miss", &rule, None);
+
+    let response = next.call(req).await?;
+    if !storable(response.response()) {
+        return Ok(response.map_into_left_body());
+    }
+    let (req, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+    let body = body::to_bytes(body).await.map_err(|e| {
+        let e: Box<dyn std::error::Error> = e.into();
+        actix_web::error::ErrorInternalServerError(e)
+    })?;
+    let stored = Instant::now();
+    let entry = Entry {
+        status: response.status(),
+        headers: response.headers().clone(),
+        body: body.clone(),
+        stored,
+        expires: stored + rule.ttl,
+    };
+    cache.insert(key, entry, metrics.as_deref());
+    let response = response.set_body(BoxBody::new(body));
+    Ok(ServiceResponse::new(req, response).map_into_right_body())
+}
@@ -0,0 +1,77 @@
+//! Responses by status class.
+//!
+//! `http_responses_total{method,route,class}` counts tracked requests by
+//! status class (`1xx` to `5xx`). `http_server_errors_total` counts every
+//! 5xx the server sends, on excluded routes too: responses are counted by
+//! `count_server_error`, registered as an `ErrorHandlers` default, and errors
+//! that never became a response (a caught panic, a failing middleware) by
+//! `track_requests`. Together they give burn-rate alerts a numerator and a
+//! denominator without parsing logs.
+
+use crate::{metrics::AppMetrics, registry::Registry};
+use actix_web::{dev::ServiceResponse, middleware::ErrorHandlerResponse, web};
+use prometheus::{IntCounter, IntCounterVec, Opts};
+
+/// Every `class` label value, in the order of `status_class_index`.
+pub const STATUS_CLASSES: [&str; 6] = ["1xx", "2xx", "3xx", "4xx", "5xx", "other"];
+
+/// `1xx` to `5xx`; anything outside 100-599 is `other`.
+pub fn status_class(status: u16) -> &'static str {
+    STATUS_CLASSES[status_class_index(status)]
+}
+
+/// The index of `status_class(status)` in `STATUS_CLASSES`.
+pub fn status_class_index(status: u16) -> usize {
+    match status / 100 {
+        class @ 1..=5 => usize::from(class - 1),
+        _ => 5,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ResponseMetrics {
+    by_class: IntCounterVec,
+    server_errors: IntCounter,
+}
+
+impl ResponseMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let by_class = IntCounterVec::new(
+            Opts::new("http_responses_total", "HTTP responses by route template and status class"),
+            &["method", "route", "class"],
+        )?;
+        let server_errors = IntCounter::new("http_server_errors_total", "HTTP responses with a 5xx status")?;
+        registry.register(Box::new(by_class.clone()))?;
+        registry.register(Box::new(server_errors.clone()))?;
+        Ok(Self { by_class, server_errors })
+    }
+
+    /// Creates the series of `[method, route, class]` at zero; routes are
+    /// primed with class `5xx`, so error ratios are 0 rather than absent
+    /// before the first error.
+    pub fn prime(&self, labels: [&str; 3]) {
+        self.by_class.with_label_values(&labels);
+    }
+
+    pub fn observe(&self, labels: [&str; 3]) {
+        self.child(labels).inc();
+    }
+
+    /// The `http_responses_total` child of `[method, route, class]`.
+    pub fn child(&self, labels: [&str; 3]) -> IntCounter {
+        self.by_class.with_label_values(&labels)
+    }
+
+    pub fn server_error(&self) {
+        self.server_errors.inc();
+    }
+}
+
+/// `ErrorHandlers` default for 5xx responses: counts them in
+/// `http_server_errors_total` and passes them on unchanged.
+pub fn count_server_error<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    if let Some(metrics) = res.request().app_data::<web::Data<AppMetrics>>() {
+        metrics.responses.server_error();
+    }
+    Ok(ErrorHandlerResponse::Response(res.map_into_left_body()))
+}
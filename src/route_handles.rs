@@ -0,0 +1,147 @@
+//! Request metric children of the routes known at startup.
+//!
+//! Recording a request through the labeled request metrics looks its method
+//! and route up in each of them: every lookup hashes the labels, takes a
+//! read lock and first checks the cardinality budget, about a dozen times
+//! per request. `AppMetrics::resolve_routes` does those lookups once for the
+//! routes the app serves and keeps the children as `RouteHandles`, so
+//! `track_requests` records a request after a single map lookup. Requests to
+//! other routes (`unmatched`, or one registered later) go through the vecs
+//! as before.
+//!
+//! Resolving a route primes its series like `AppMetrics::prime_route`; the
+//! children it doesn't create at zero, the body sizes and the status classes
+//! other than `5xx`, are resolved on the route's first use of them instead,
+//! so resolving doesn't add empty series. `cargo bench --bench route_handles`
+//! compares both paths.
+
+use crate::{
+    apdex::ApdexHandles,
+    metrics::AppMetrics,
+    middleware::RequestTracking,
+    responses::{status_class_index, STATUS_CLASSES},
+    sharded::ShardedHistogram,
+    sli::SliHandles,
+};
+use prometheus::IntCounter;
+use std::{collections::HashMap, fmt, sync::OnceLock, time::Duration};
+
+/// The children of one method and route.
+pub struct RouteHandles {
+    method: String,
+    route: String,
+    duration: ShardedHistogram,
+    sli: SliHandles,
+    apdex: ApdexHandles,
+    /// By `status_class_index`.
+    responses: [OnceLock<IntCounter>; STATUS_CLASSES.len()],
+    request_size: OnceLock<ShardedHistogram>,
+    response_size: OnceLock<ShardedHistogram>,
+}
+
+impl fmt::Debug for RouteHandles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RouteHandles").field("method", &self.method).field("route", &self.route).finish()
+    }
+}
+
+impl RouteHandles {
+    /// Resolves the children of `method` and `route` in `metrics`, creating
+    /// the series `AppMetrics::prime_route` primes. Each label set counts
+    /// against the cardinality budget like an observed one.
+    pub fn new(metrics: &AppMetrics, method: &str, route: &str) -> Self {
+        let labels = metrics.cardinality.limit("http_request_duration_seconds", [method, route]);
+        let duration = metrics.http_request_duration.with_label_values(&labels);
+        let [sli_route] = metrics.cardinality.limit("sli_requests_total", [route]);
+        let sli = metrics.sli.handles(sli_route);
+        let [apdex_route] = metrics.cardinality.limit("apdex_total", [route]);
+        let apdex = metrics.apdex.handles(apdex_route);
+        let handles = Self {
+            method: method.to_string(),
+            route: route.to_string(),
+            duration,
+            sli,
+            apdex,
+            responses: Default::default(),
+            request_size: OnceLock::new(),
+            response_size: OnceLock::new(),
+        };
+        // Error ratios are 0 rather than absent before the first error.
+        handles.response(metrics, 500);
+        handles
+    }
+
+    /// The `http_request_duration_seconds` child.
+    pub fn duration(&self) -> &ShardedHistogram {
+        &self.duration
+    }
+
+    /// The `http_responses_total` child of `status`'s class.
+    pub fn response(&self, metrics: &AppMetrics, status: u16) -> &IntCounter {
+        let index = status_class_index(status);
+        self.responses[index].get_or_init(|| {
+            let labels = [self.method.as_str(), self.route.as_str(), STATUS_CLASSES[index]];
+            metrics.responses.child(metrics.cardinality.limit("http_responses_total", labels))
+        })
+    }
+
+    /// The `http_request_size_bytes` child.
+    pub fn request_size(&self, metrics: &AppMetrics) -> &ShardedHistogram {
+        self.request_size.get_or_init(|| {
+            let labels = [self.method.as_str(), self.route.as_str()];
+            metrics.body_sizes.request_histogram(&metrics.cardinality.limit("http_request_size_bytes", labels))
+        })
+    }
+
+    /// The `http_response_size_bytes` child.
+    pub fn response_size(&self, metrics: &AppMetrics) -> &ShardedHistogram {
+        self.response_size.get_or_init(|| {
+            let labels = [self.method.as_str(), self.route.as_str()];
+            metrics.body_sizes.response_histogram(&metrics.cardinality.limit("http_response_size_bytes", labels))
+        })
+    }
+
+    /// Records a finished request in the latency, response, SLI and Apdex
+    /// metrics, like `track_requests` does through the vecs.
+    pub fn observe(&self, metrics: &AppMetrics, tracking: &RequestTracking, status: u16, elapsed: Duration) {
+        self.duration.observe(elapsed.as_secs_f64());
+        self.response(metrics, status).inc();
+        self.sli.observe(&tracking.sli, status, elapsed);
+        let failed = tracking.sli.bad_statuses.iter().any(|m| m.matches(status));
+        self.apdex.observe(tracking.apdex.thresholds(&self.route), elapsed, failed);
+    }
+}
+
+/// The `RouteHandles` of every resolved route, by route and then method so
+/// lookups can borrow the request's labels.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    routes: HashMap<String, HashMap<String, RouteHandles>>,
+}
+
+impl RouteTable {
+    pub fn resolve<'a>(metrics: &AppMetrics, routes: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut table = Self::default();
+        for (method, route) in routes {
+            table
+                .routes
+                .entry(route.to_string())
+                .or_default()
+                .entry(method.to_string())
+                .or_insert_with(|| RouteHandles::new(metrics, method, route));
+        }
+        table
+    }
+
+    pub fn get(&self, method: &str, route: &str) -> Option<&RouteHandles> {
+        self.routes.get(route)?.get(method)
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.values().map(HashMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
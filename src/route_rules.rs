@@ -0,0 +1,58 @@
+//! Per-route rules of the middlewares configured as `/route=<spec>` lists:
+//! `RATE_LIMITS`, `REQUEST_TIMEOUTS`, `REQUEST_SIZE_LIMITS` and
+//! `RESPONSE_CACHE`.
+//!
+//! A rule names a route template, or `*` for every route without a rule of
+//! its own. Like excluded routes, rules also match under the endpoint
+//! prefix: `/search` applies to `<prefix>/search` too.
+
+use std::ops::Deref;
+
+/// A rule for a route template.
+pub trait RouteRule {
+    /// Route template, or `*` for every route.
+    fn route(&self) -> &str;
+}
+
+/// Splits `/route=<spec>` at its last `=` into the route and the spec, both
+/// trimmed; `None` without `=` or without a route.
+pub fn split(rule: &str) -> Option<(&str, &str)> {
+    let (route, spec) = rule.rsplit_once('=')?;
+    let route = route.trim();
+    (!route.is_empty()).then(|| (route, spec.trim()))
+}
+
+/// Rules looked up by the route template of a request; derefs to the rules.
+#[derive(Debug)]
+pub struct RouteRules<R> {
+    rules: Vec<R>,
+    endpoint_prefix: String,
+}
+
+impl<R: RouteRule> RouteRules<R> {
+    pub fn new(rules: Vec<R>, endpoint_prefix: &str) -> Self {
+        Self { rules, endpoint_prefix: endpoint_prefix.to_string() }
+    }
+
+    /// The index of the rule applying to `route`: its own, else the `*` one.
+    pub fn position(&self, route: &str) -> Option<usize> {
+        let unprefixed = route.strip_prefix(self.endpoint_prefix.as_str()).filter(|_| !self.endpoint_prefix.is_empty());
+        self.rules
+            .iter()
+            .position(|rule| rule.route() == route || Some(rule.route()) == unprefixed)
+            .or_else(|| self.rules.iter().position(|rule| rule.route() == "*"))
+    }
+
+    /// The rule applying to `route`, see `position`.
+    pub fn get(&self, route: &str) -> Option<&R> {
+        self.position(route).map(|index| &self.rules[index])
+    }
+}
+
+impl<R> Deref for RouteRules<R> {
+    type Target = [R];
+
+    fn deref(&self) -> &[R] {
+        &self.rules
+    }
+}
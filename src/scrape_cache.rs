@@ -0,0 +1,43 @@
+//! Short-lived cache of the encoded `/metrics` response.
+//!
+//! With several Prometheus replicas or agents scraping at once, each scrape
+//! would gather and encode the whole registry. With `METRICS_CACHE_TTL_MS`
+//! set, an encoded response younger than the TTL is served as is, and only
+//! one scrape at a time re-encodes an expired one: the others wait for it and
+//! share its result. Failed encodes aren't cached. Each exposition format is
+//! cached on its own.
+
+use crate::metrics::{AppMetrics, Exposition};
+use actix_web::web::Bytes;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+#[derive(Debug)]
+pub struct ScrapeCache {
+    ttl: Duration,
+    /// When the cached response was encoded, and the response, by
+    /// `Exposition as usize`.
+    entries: [Mutex<Option<(Instant, Bytes)>>; 2],
+}
+
+impl ScrapeCache {
+    /// A zero `ttl` disables caching.
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: Default::default() }
+    }
+
+    /// The registry encoded in `format`, from the cache while it is fresh.
+    pub async fn render(&self, metrics: &AppMetrics, format: Exposition) -> prometheus::Result<Bytes> {
+        if self.ttl.is_zero() {
+            return metrics.encode(format).map(Bytes::from);
+        }
+        // Held while encoding, so concurrent scrapes wait for this one.
+        let mut entry = self.entries[format as usize].lock().await;
+        if let Some((_, body)) = entry.as_ref().filter(|(encoded_at, _)| encoded_at.elapsed() < self.ttl) {
+            return Ok(body.clone());
+        }
+        let body = Bytes::from(metrics.encode(format)?);
+        *entry = Some((Instant::now(), body.clone()));
+        Ok(body)
+    }
+}
@@ -0,0 +1,101 @@
+//! `selftest`: a smoke test of the trace export path, e.g. after a deploy.
+//!
+//! Exports one `selftest` span through the configured pipeline, tagged with
+//! its own trace id as `selftest.id`, and fails if the exporter didn't
+//! deliver it. With `--verify` it then polls the tracing backend's query API
+//! (`GET <query-url>/api/traces/<trace id>`, served by both Tempo and Jaeger)
+//! until a response mentions the tag, so a trace lost anywhere between the
+//! collector and the backend fails the command too.
+
+use crate::{
+    cli::SelftestArgs,
+    config::Config,
+    pipeline::{PipelineStats, Signal},
+    telemetry::TelemetryBuilder,
+};
+use opentelemetry::{
+    global,
+    trace::{Span, Tracer},
+    KeyValue,
+};
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+/// Delay between two backend queries.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Timeout of a single backend query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn run(config: &Config, args: SelftestArgs) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let stats = PipelineStats::new(&crate::registry::Registry::new())?;
+    let telemetry = TelemetryBuilder::new(config, stats.clone()).init();
+
+    let tracer = global::tracer("prom_otel");
+    let mut span = tracer.start("selftest");
+    let context = span.span_context().clone();
+    let trace_id = context.trace_id().to_string();
+    span.set_attribute(KeyValue::new("selftest.id", trace_id.clone()));
+    span.end();
+    if !context.is_sampled() {
+        telemetry.shutdown()?;
+        return Err("the selftest span was not sampled, check OTEL_TRACES_SAMPLER".into());
+    }
+
+    let flushed = telemetry.force_flush();
+    if stats.snapshot(Signal::Traces).exported == 0 {
+        // The export error is the interesting one; shutting down fails the same way.
+        let _ = telemetry.shutdown();
+        let endpoint = config.exporter_endpoint(Signal::Traces);
+        return Err(match flushed {
+            Err(e) => format!("trace {trace_id} was not exported to {endpoint}: {e}"),
+            Ok(()) => format!("trace {trace_id} was not exported to {endpoint}"),
+        }
+        .into());
+    }
+    info!(trace_id, "Selftest trace exported");
+
+    let result = match &args.query_url {
+        Some(url) if args.verify => verify(url, &args, &trace_id).await,
+        _ => Ok(()),
+    };
+    telemetry.shutdown()?;
+    result
+}
+
+/// Polls `url` until it returns the trace or the timeout passes.
+async fn verify(url: &str, args: &SelftestArgs, trace_id: &str) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let client = reqwest::Client::builder().timeout(QUERY_TIMEOUT).build()?;
+    let query = format!("{}/api/traces/{trace_id}", url.trim_end_matches('/'));
+    let timeout = Duration::from_secs(args.timeout_secs);
+    let started = Instant::now();
+    loop {
+        let request = args
+            .query_header
+            .iter()
+            .fold(client.get(&query), |request, header| request.header(&header.name, &header.value));
+        let outcome = match request.send().await {
+            Ok(response) if response.status().is_success() => match response.text().await {
+                Ok(body) if body.contains(trace_id) => {
+                    info!(trace_id, elapsed_secs = started.elapsed().as_secs_f64(), "Selftest trace found in the backend");
+                    return Ok(());
+                }
+                Ok(_) => "a response without the trace".to_string(),
+                Err(e) => e.to_string(),
+            },
+            Ok(response) => format!("status {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+        debug!(trace_id, outcome, "Selftest trace not found yet");
+        if started.elapsed() + POLL_INTERVAL > timeout {
+            return Err(format!(
+                "trace {trace_id} did not show up at {query} within {}s, last query: {outcome}",
+                args.timeout_secs
+            )
+            .into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
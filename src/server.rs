@@ -0,0 +1,688 @@
+//! HTTP server and route handlers.
+
+use crate::{
+    admin,
+    app_auth::{self, AppAuth},
+    circuit_breaker::CircuitBreakers,
+    auth::EndpointAuth,
+    catalog,
+    config::Config,
+    config_reload::{self, ConfigReloader, ReloadTargets},
+    debug_session::DebugSessions,
+    debug_tap::{self, DebugTap},
+    event_loop::EventLoop,
+    lifecycle::{self, Phase},
+    flags::SampleRatio,
+    memory_pressure::{self, MemoryPressure},
+    listener::{self, Bound, ListenAddr},
+    metric_filter::MetricFilter,
+    metric_stream::{self, StreamQuery},
+    metrics::{AppMetrics, Exposition},
+    middleware::{self, RequestTracking},
+    multiprocess,
+    naming,
+    observed::observed,
+    panics,
+    pipeline,
+    rate_limit::{self, RateLimiter},
+    readiness::{self, Readiness},
+    request_size::{self, RequestSizeLimits},
+    response_cache::{self, ResponseCache},
+    responses,
+    scrape_cache::ScrapeCache,
+    shadow_export::ShadowStats,
+    shutdown::{self, ExitReason, ShutdownReport},
+    slow_requests,
+    statsd::{self, Statsd},
+    switches::TelemetrySwitches,
+    system,
+    telemetry::TelemetryBuilder,
+    tenant::{self, Tenants},
+    timeout::{self, RequestTimeouts},
+};
+#[cfg(feature = "flags")]
+use crate::flags::{self, FlagTargets, LaunchDarkly};
+#[cfg(feature = "gateway")]
+use crate::gateway::{self, Gateway};
+#[cfg(feature = "grpc-health")]
+use crate::grpc_health::GrpcHealth;
+#[cfg(feature = "jemalloc")]
+use crate::heap_profile;
+#[cfg(feature = "pprof")]
+use crate::profiling;
+#[cfg(feature = "federation")]
+use crate::federation::{self, Federation};
+#[cfg(feature = "metrics")]
+use crate::otlp_bridge;
+#[cfg(feature = "pyroscope")]
+use crate::pyroscope::Pyroscope;
+#[cfg(feature = "remote-sampling")]
+use crate::remote_sampling::{self, RemoteStrategy};
+#[cfg(feature = "tls")]
+use crate::{client_labels::TlsVersion, tls};
+#[cfg(feature = "traces")]
+use crate::span_metrics::SpanMetricsProcessor;
+#[cfg(feature = "traces")]
+use crate::tail_sampling::TailSamplingPolicy;
+#[cfg(feature = "logs")]
+use crate::log_sampling::LogSampler;
+use actix_web::{
+    http::{header, KeepAlive},
+    middleware::{from_fn, Compress, ErrorHandlers},
+    web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use futures_util::stream;
+use std::{
+    error::Error,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+/// Methods and route templates of the built-in endpoints on the app itself,
+/// mounted under `Config::endpoint_prefix`.
+const ROUTES: &[(&str, &str)] = &[
+    ("GET", "/metrics"),
+    ("GET", "/metrics/catalog"),
+    ("GET", "/metrics/json"),
+    ("GET", "/metrics/naming"),
+    ("GET", "/metrics/stream"),
+    ("GET", "/readyz"),
+];
+
+/// Methods and route templates of every route served with `config`.
+pub fn routes(config: &Config) -> Vec<(&'static str, String)> {
+    let mut builtin = ROUTES.to_vec();
+    #[cfg(feature = "gateway")]
+    if config.otlp_receiver {
+        builtin.extend_from_slice(gateway::ROUTES);
+    }
+    if config.admin_token.is_some() {
+        builtin.extend_from_slice(admin::ROUTES);
+        if config.debug_tap {
+            builtin.extend_from_slice(debug_tap::ROUTES);
+        }
+        #[cfg(feature = "jemalloc")]
+        builtin.extend_from_slice(heap_profile::ROUTES);
+        #[cfg(feature = "pprof")]
+        builtin.extend_from_slice(profiling::ROUTES);
+    }
+    let mut routes = vec![("GET", "/".to_string())];
+    routes.extend(builtin.into_iter().map(|(method, route)| (method, format!("{}{route}", config.endpoint_prefix))));
+    routes
+}
+
+/// Whether `/metrics` is streamed, see `Config::metrics_streaming`.
+#[derive(Clone, Copy, Debug)]
+struct MetricsStreaming(bool);
+
+/// The family selection of the query of `req`, or a `400 Bad Request`.
+fn metric_filter(req: &HttpRequest) -> Result<MetricFilter, HttpResponse> {
+    web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(|e| e.to_string())
+        .and_then(|query| MetricFilter::from_query(&query))
+        .map_err(|e| HttpResponse::BadRequest().content_type("text/plain").body(format!("invalid metric filter: {e}")))
+}
+
+async fn metrics_handler(
+    req: HttpRequest,
+    metrics: web::Data<AppMetrics>,
+    cache: web::Data<ScrapeCache>,
+    streaming: web::Data<MetricsStreaming>,
+    auth: web::Data<EndpointAuth>,
+) -> impl Responder {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("metrics");
+    }
+    let filter = match metric_filter(&req) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    let format = Exposition::negotiate(req.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok()));
+    if streaming.0 {
+        return HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::VARY, "Accept"))
+        .streaming(stream::iter(metrics.encode_chunks_filtered(format, &filter).map(|chunk| chunk.map(web::Bytes::from))));
+    }
+    
+    // The cache holds the whole exposition; a filtered one is encoded anew.
+    let body = if filter.is_empty() {
+        cache.render(&metrics, format).await
+    } else {
+        metrics.encode_filtered(format, &filter).map(web::Bytes::from)
+    };
+    match body {
+        Ok(body) => HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::VARY, "Accept"))
+        .body(body),
+        Err(e) => {
+            error!(error = %e, "Failed to encode metrics");
+            HttpResponse::InternalServerError()
+            .content_type("text/plain")
+            .body(format!("failed to encode metrics: {e}"))
+        }
+    }
+}
+
+async fn catalog_handler(
+    req: HttpRequest,
+    metrics: web::Data<AppMetrics>,
+    auth: web::Data<EndpointAuth>,
+) -> impl Responder {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("metrics");
+    }
+    HttpResponse::Ok().json(catalog::catalog(&metrics.registry))
+}
+
+async fn json_handler(
+    req: HttpRequest,
+    metrics: web::Data<AppMetrics>,
+    auth: web::Data<EndpointAuth>,
+) -> impl Responder {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("metrics");
+    }
+    let filter = match metric_filter(&req) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    HttpResponse::Ok().json(metrics.delta_snapshot(&filter))
+}
+
+async fn naming_handler(
+    req: HttpRequest,
+    metrics: web::Data<AppMetrics>,
+    auth: web::Data<EndpointAuth>,
+) -> impl Responder {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("metrics");
+    }
+    HttpResponse::Ok().json(naming::report(&metrics.gather(), metrics.strict_naming))
+}
+
+async fn stream_handler(
+    req: HttpRequest,
+    metrics: web::Data<AppMetrics>,
+    auth: web::Data<EndpointAuth>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    if let Err(denied) = auth.check(&req) {
+        return denied.response("metrics");
+    }
+    match query.parse() {
+        Ok((prefixes, interval)) => HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        .streaming(metric_stream::events(metrics.registry.clone(), prefixes, interval)),
+        Err(e) => HttpResponse::BadRequest().content_type("text/plain").body(e),
+    }
+}
+
+/// Counted in `http_requests_total` by `observed`.
+async fn index() -> impl Responder {
+    HttpResponse::Ok().body("Hello! This request was counted.")
+}
+
+pub async fn serve(config: Config) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    serve_with(config, |telemetry| telemetry).await
+}
+
+/// Like `serve`, but lets the caller extend the telemetry pipeline (e.g. with
+/// `TelemetryBuilder::with_span_processor` or `with_log_processor`) before it
+/// is installed.
+pub async fn serve_with<F>(config: Config, customize: F) -> Result<(), Box<dyn Error + Send + Sync + 'static>>
+where
+    F: FnOnce(TelemetryBuilder) -> TelemetryBuilder,
+{
+    let started = Instant::now();
+    let app_metrics = AppMetrics::from_config(&config);
+    let pipeline_stats = app_metrics.pipeline.clone();
+    
+    let debug_sessions = DebugSessions::new();
+    let sample_ratio = SampleRatio::new();
+    let switches = TelemetrySwitches::new();
+    let pressure = MemoryPressure::new();
+    let mut telemetry = TelemetryBuilder::new(&config, pipeline_stats.clone())
+    .with_debug_sessions(debug_sessions.clone())
+    .with_sample_ratio(sample_ratio.clone())
+    .with_switches(switches.clone());
+    if config.memory_pressure_threshold_mb.is_some() {
+        telemetry = telemetry.with_memory_pressure(pressure.clone());
+    }
+    if let Some(log_metrics) = &app_metrics.log_metrics {
+        telemetry = telemetry.with_log_metrics(log_metrics.clone());
+    }
+    let breakers = config
+        .export_circuit_breaker_threshold
+        .map(|threshold| CircuitBreakers::new(&app_metrics.registry, threshold, config.export_circuit_breaker_open))
+        .transpose()?;
+    if let Some(breakers) = &breakers {
+        telemetry = telemetry.with_circuit_breakers(breakers.clone());
+    }
+    let readiness = Readiness::new(breakers.clone());
+    if config.shadow_endpoint.is_some() {
+        telemetry = telemetry.with_shadow_export(ShadowStats::new(&app_metrics.registry)?);
+    }
+    // With a config file, sampling can be turned on by a reload.
+    #[cfg(feature = "logs")]
+    let log_sampler = if config.log_sampling() || config.config_file.is_some() {
+        Some(LogSampler::new(&app_metrics.registry, config.log_rate_limit, config.log_debug_sample_ratio)?)
+    } else {
+        None
+    };
+    #[cfg(feature = "logs")]
+    if let Some(sampler) = &log_sampler {
+        telemetry = telemetry.with_log_sampler(sampler.clone());
+    }
+    #[cfg(feature = "remote-sampling")]
+    let remote_sampling = config
+        .remote_sampling_url
+        .as_ref()
+        .filter(|_| !config.telemetry_disabled)
+        .map(|url| (url.clone(), RemoteStrategy::new(config.remote_sampling_initial_ratio)));
+    #[cfg(feature = "remote-sampling")]
+    if let Some((_, strategy)) = &remote_sampling {
+        telemetry = telemetry.with_remote_sampling(strategy.clone());
+    }
+    let debug_tap = config.debug_tap.then(|| DebugTap::new(config.debug_tap_buffer, config.debug_tap_sample_ratio));
+    if let Some(tap) = &debug_tap {
+        telemetry = telemetry.with_debug_tap(tap.clone());
+    }
+    #[cfg(feature = "traces")]
+    if let Some(span_metrics) = &app_metrics.span_metrics {
+        telemetry = telemetry
+            .with_span_processor(SpanMetricsProcessor::new(span_metrics.clone(), app_metrics.cardinality.clone()));
+    }
+    #[cfg(feature = "traces")]
+    if let Some(metrics) = &app_metrics.tail_sampling {
+        let policy = TailSamplingPolicy {
+            window: config.tail_sampling_window,
+            latency_threshold: config.tail_sampling_latency_threshold,
+            max_traces: config.tail_sampling_max_traces,
+        };
+        telemetry = telemetry.with_tail_sampling(policy, metrics.clone());
+    }
+    let phase = lifecycle::begin("exporter_init");
+    let telemetry = customize(telemetry).init();
+    phase.end();
+    lifecycle::telemetry_installed();
+    panics::install_hook(app_metrics.panics.clone());
+    
+    tokio::spawn(pipeline::watch(pipeline_stats.clone(), std::time::Duration::from_secs(30)));
+    
+    let app_metrics = web::Data::new(app_metrics);
+    let metrics_clone = app_metrics.clone().into_inner();
+    if let Some(threshold_mb) = config.memory_pressure_threshold_mb {
+        tokio::spawn(memory_pressure::watch(
+            pressure.clone(),
+            threshold_mb * 1024 * 1024,
+            config.system_metrics_interval,
+            app_metrics.degradation_level.clone(),
+        ));
+    }
+    let (system_metrics_interval, interval_updates) = watch::channel(config.system_metrics_interval);
+    app_metrics.spawn_instrumented(
+        "system_metrics",
+        system::update_system_metrics(metrics_clone, interval_updates, pressure),
+    );
+    if let Some(event_loop) = &app_metrics.event_loop {
+        event_loop.start_watchdog()?;
+        app_metrics.spawn_instrumented("event_loop_probe", event_loop.probe("main"));
+    }
+    if let Some(slow_requests) = &app_metrics.slow_requests {
+        slow_requests.start_watchdog()?;
+    }
+    let report_metrics = app_metrics.clone();
+    #[cfg(feature = "metrics")]
+    if config.metrics_otlp_bridge && !config.telemetry_disabled {
+        app_metrics.spawn_instrumented(
+            "otlp_bridge",
+            otlp_bridge::run(
+                app_metrics.registry.clone(),
+                config.metric_const_labels().into_keys().collect(),
+                config.metric_export_interval,
+            ),
+        );
+    }
+    if let (Some(multiprocess), Some(dir)) = (&app_metrics.multiprocess, &config.metrics_multiprocess_dir) {
+        std::fs::create_dir_all(dir)?;
+        app_metrics.spawn_instrumented(
+            "multiprocess",
+            multiprocess::publish(multiprocess.clone(), app_metrics.registry.clone()),
+        );
+    }
+    #[cfg(feature = "federation")]
+    if !config.federate_targets.is_empty() {
+        let federation = Federation::new(&app_metrics.registry, config.metric_const_labels().into_keys())?;
+        app_metrics.spawn_instrumented(
+            "federation",
+            federation::watch(federation, config.federate_targets.clone(), config.federate_interval),
+        );
+    }
+    if let Some(addr) = &config.statsd_addr {
+        let statsd = Statsd::new(
+            &app_metrics.registry,
+            config.metric_const_labels().into_keys(),
+            config.metrics_max_label_sets,
+        )?;
+        let socket = tokio::net::UdpSocket::bind(addr.as_str()).await?;
+        info!(addr = %socket.local_addr()?, "Accepting StatsD lines");
+        app_metrics.spawn_instrumented("statsd", statsd::listen(statsd, socket));
+    }
+    #[cfg(feature = "remote-sampling")]
+    if let Some((url, strategy)) = remote_sampling {
+        app_metrics.spawn_instrumented(
+            "remote_sampling",
+            remote_sampling::watch(
+                strategy,
+                url,
+                config.service_name.clone(),
+                config.remote_sampling_interval,
+                app_metrics.config_changes.clone(),
+            ),
+        );
+    }
+    #[cfg(feature = "pyroscope")]
+    if let Some(pyroscope) = Pyroscope::from_config(&config) {
+        tokio::spawn(pyroscope.run());
+    }
+    #[cfg(feature = "flags")]
+    if let (Some(url), Some(key)) = (&config.flags_url, &config.flags_sdk_key) {
+        let targets = FlagTargets { sample_ratio: sample_ratio.clone(), debug_sessions: debug_sessions.clone() };
+        tokio::spawn(flags::watch(
+            Arc::new(LaunchDarkly::new(url, key)),
+            config.flags_poll_interval,
+            targets,
+            app_metrics.config_changes.clone(),
+        ));
+    }
+    
+    for listener in &config.server_addrs {
+        let scheme = if listener.uses_tls(config.tls_enabled()) { "https" } else { "http" };
+        match &listener.addr {
+            ListenAddr::Tcp(addr) => info!("Server running at {scheme}://{addr}"),
+            ListenAddr::Unix(_) => info!("Server running at {}", listener.addr),
+        }
+    }
+    
+    let tracking = RequestTracking::from_config(&config);
+    // Prime the request series of every route the app serves and keep
+    // their children for `track_requests`.
+    let routes = routes(&config);
+    app_metrics.resolve_routes(
+        routes.iter().filter(|(_, route)| !tracking.is_excluded(route)).map(|(method, route)| (*method, route.as_str())),
+    );
+    if let Some(path) = &config.metrics_catalog_path {
+        let catalog = catalog::catalog(&app_metrics.registry);
+        if let Err(e) = std::fs::write(path, format!("{catalog:#}\n")) {
+            error!(path = %path.display(), error = %e, "Failed to write the metric catalog");
+        }
+    }
+    let tracking = web::Data::new(tracking);
+    let rate_limiter = web::Data::new(RateLimiter::from_config(&config));
+    let request_timeouts = web::Data::new(RequestTimeouts::from_config(&config));
+    let request_size_limits = web::Data::new(RequestSizeLimits::from_config(&config));
+    let response_cache = web::Data::new(ResponseCache::from_config(&config));
+    let metrics_auth = web::Data::new(EndpointAuth::metrics(&config));
+    let app_auth = AppAuth::from_config(&config, &app_metrics.registry)?.map(web::Data::new);
+    let tenants = Tenants::from_config(&config, &app_metrics.registry)?.map(web::Data::new);
+    if config.config_file.is_some() {
+        let targets = ReloadTargets {
+            log_filter: telemetry.log_filter(),
+            #[cfg(feature = "logs")]
+            log_sampler,
+            system_metrics_interval,
+            metrics_auth: EndpointAuth::clone(&metrics_auth),
+        };
+        let reloader =
+            ConfigReloader::new(&app_metrics.registry, config.clone(), targets, app_metrics.config_changes.clone())?;
+        app_metrics.spawn_instrumented("config_reload", config_reload::watch(reloader));
+    }
+    let scrape_cache = web::Data::new(ScrapeCache::new(config.metrics_cache_ttl));
+    let metrics_streaming = web::Data::new(MetricsStreaming(config.metrics_streaming));
+    let admin_auth = EndpointAuth::admin(&config).map(web::Data::new);
+    let debug_tap = debug_tap.map(web::Data::new);
+    let debug_sessions = web::Data::new(debug_sessions);
+    let sample_ratio = web::Data::new(sample_ratio);
+    let switches = web::Data::new(switches);
+    let readiness_data = web::Data::new(readiness.clone());
+    #[cfg(feature = "gateway")]
+    let gateway = config.otlp_receiver.then(|| {
+        let switches = TelemetrySwitches::clone(&switches);
+        (
+            web::Data::new(Gateway::new(&config, pipeline_stats.clone(), switches, breakers)),
+            web::Data::new(EndpointAuth::new(None, config.otlp_receiver_allowed_ips.clone())),
+        )
+    });
+    
+    let endpoint_prefix = config.endpoint_prefix.clone();
+    let tuning = config.server_tuning;
+    app_metrics.server.set_tuning(&tuning);
+    let server_metrics = app_metrics.server.clone();
+    let server = HttpServer::new(move || {
+        let builtin = web::scope(&endpoint_prefix)
+        // Compressed when the scraper accepts it; Prometheus asks for gzip.
+        .service(web::resource("/metrics").wrap(Compress::default()).get(metrics_handler))
+        .route("/metrics/catalog", web::get().to(catalog_handler))
+        .route("/metrics/json", web::get().to(json_handler))
+        .route("/metrics/naming", web::get().to(naming_handler))
+        .route("/metrics/stream", web::get().to(stream_handler))
+        .service(web::resource("/readyz").app_data(readiness_data.clone()).get(readiness::handler));
+        #[cfg(feature = "gateway")]
+        let builtin = match &gateway {
+            Some((gateway, auth)) => builtin.service(gateway::scope(gateway.clone(), auth.clone())),
+            None => builtin,
+        };
+        // Ahead of the `/debug/pprof` scope, which would answer 404 for them.
+        #[cfg(feature = "jemalloc")]
+        let builtin = match &admin_auth {
+            Some(auth) => builtin.service(heap_profile::resources(auth.clone())),
+            None => builtin,
+        };
+        #[cfg(feature = "pprof")]
+        let builtin = match &admin_auth {
+            Some(auth) => builtin.service(profiling::scope(auth.clone())),
+            None => builtin,
+        };
+        let builtin = match (&admin_auth, &debug_tap) {
+            (Some(auth), Some(tap)) => builtin.service(debug_tap::resource(auth.clone(), tap.clone())),
+            _ => builtin,
+        };
+        let builtin = match &admin_auth {
+            Some(auth) => builtin.service(admin::scope(auth.clone())),
+            None => builtin,
+        };
+        App::new()
+        .wrap(from_fn(timeout::enforce))
+        .wrap(from_fn(response_cache::serve))
+        .wrap(from_fn(request_size::guard))
+        .wrap(from_fn(rate_limit::limit))
+        .wrap(ErrorHandlers::new().default_handler_server(responses::count_server_error))
+        .wrap(from_fn(tenant::attribute))
+        .wrap(from_fn(slow_requests::watch))
+        .wrap(from_fn(middleware::track_requests))
+        .app_data(app_metrics.clone())
+        .app_data(tracking.clone())
+        .app_data(rate_limiter.clone())
+        .app_data(request_timeouts.clone())
+        .app_data(request_size_limits.clone())
+        .app_data(response_cache.clone())
+        .app_data(metrics_auth.clone())
+        .app_data(scrape_cache.clone())
+        .app_data(metrics_streaming.clone())
+        .app_data(debug_sessions.clone())
+        .app_data(sample_ratio.clone())
+        .app_data(switches.clone())
+        // Dropped with the app when its worker stops.
+        .app_data(app_metrics.server.worker_started())
+        .app_data(app_metrics.event_loop.as_ref().map(EventLoop::start_worker_probe))
+        .configure(|cfg| {
+            if let Some(auth) = &app_auth {
+                cfg.app_data(auth.clone());
+            }
+            if let Some(tenants) = &tenants {
+                cfg.app_data(tenants.clone());
+            }
+        })
+        .service(web::resource("/").wrap(from_fn(app_auth::authenticate)).get(observed(&app_metrics, "index", index)))
+        // Last, since an empty prefix matches every path.
+        .service(builtin)
+    });
+    
+    let server = server
+        .workers(tuning.workers)
+        .keep_alive(if tuning.keep_alive.is_zero() { KeepAlive::Disabled } else { KeepAlive::Timeout(tuning.keep_alive) })
+        .client_request_timeout(tuning.client_request_timeout)
+        .max_connections(tuning.max_connections)
+        // Connection extensions are dropped when the connection closes.
+        .on_connect(move |_connection, extensions| {
+            extensions.insert(server_metrics.connection_opened());
+            #[cfg(feature = "tls")]
+            if let Some(version) = TlsVersion::of(_connection) {
+                extensions.insert(version);
+            }
+        });
+    // Signals are handled below so the shutdown report can name the one
+    // that stopped the server.
+    let server = server.disable_signals();
+    let phase = lifecycle::begin("server_bind");
+    let bound = async {
+        #[cfg(feature = "tls")]
+        let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = Arc::new(tls::ReloadableCert::load(cert_path, key_path)?);
+                #[cfg(unix)]
+                tokio::spawn(tls::reload_on_sighup(cert.clone(), report_metrics.config_changes.clone()));
+                Some(tls::server_config(cert)?)
+            }
+            _ => None,
+        };
+        let mut server = server;
+        let sockets = listener::bind_all(&config.server_addrs, tuning.backlog)?;
+        #[cfg(feature = "tls")]
+        for (listener, socket) in config.server_addrs.iter().zip(sockets) {
+            // `Config::validate` rejects HTTPS listeners without a certificate.
+            server = match (socket, tls_config.as_ref().filter(|_| listener.uses_tls(config.tls_enabled()))) {
+                #[cfg(unix)]
+                (Bound::Unix(socket), _) => server.listen_uds(socket)?,
+                (Bound::Tcp(socket), Some(tls_config)) => server.listen_rustls_0_23(socket, tls_config.clone())?,
+                (Bound::Tcp(socket), None) => server.listen(socket)?,
+            };
+        }
+        #[cfg(not(feature = "tls"))]
+        for socket in sockets {
+            server = match socket {
+                #[cfg(unix)]
+                Bound::Unix(socket) => server.listen_uds(socket)?,
+                Bound::Tcp(socket) => server.listen(socket)?,
+            };
+        }
+        Ok::<_, Box<dyn Error + Send + Sync + 'static>>(server.run())
+    }
+    .await;
+    #[cfg(feature = "grpc-health")]
+    let (bound, grpc_health) = match (bound, &config.grpc_health_addr) {
+        (Ok(server), Some(addr)) => match GrpcHealth::start(addr.parse()?, readiness.clone()).await {
+            Ok(health) => (Ok(server), Some(health)),
+            Err(e) => {
+                server.handle().stop(false).await;
+                (Err(e), None)
+            }
+        },
+        (bound, _) => (bound, None),
+    };
+    phase.end();
+    
+    let stop_signal: Arc<OnceLock<(&'static str, Phase)>> = Arc::new(OnceLock::new());
+    let outcome = match bound {
+        Ok(server) => {
+            lifecycle::ready();
+            readiness.set_serving();
+            let handle = server.handle();
+            let stop_signal = stop_signal.clone();
+            let readiness = readiness.clone();
+            tokio::spawn(async move {
+                let (signal, graceful) = shutdown::wait_for_signal().await;
+                info!(signal, graceful, "Stopping server");
+                let _ = stop_signal.set((signal, lifecycle::begin("server_drain")));
+                // Probes should see the drain before in-flight requests finish.
+                readiness.set_draining();
+                handle.stop(graceful).await;
+            });
+            server.await.map_err(Into::into)
+        }
+        Err(e) => Err(e),
+    };
+    #[cfg(feature = "grpc-health")]
+    if let Some(health) = grpc_health {
+        health.stop().await;
+    }
+    
+    if let Some((_, drain)) = stop_signal.get() {
+        drain.end();
+    }
+    let exit_reason = match (&outcome, stop_signal.get()) {
+        (Err(e), _) => ExitReason::Error(e.to_string()),
+        (Ok(()), Some((signal, _))) => ExitReason::Signal(signal),
+        (Ok(()), None) => ExitReason::Stopped,
+    };
+    ShutdownReport::collect(&report_metrics, started.elapsed(), exit_reason).emit();
+    
+    let phase = lifecycle::begin("telemetry_shutdown");
+    let shutdown = telemetry.shutdown();
+    phase.end();
+    shutdown?;
+    
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+    use prometheus::{
+        core::{Collector, Desc},
+        proto::{Metric, MetricFamily},
+    };
+    use std::{collections::HashMap, time::Duration};
+
+    /// Collects a family without a name, which no encoder accepts.
+    struct Unencodable(Desc);
+
+    impl Collector for Unencodable {
+        fn desc(&self) -> Vec<&Desc> {
+            vec![&self.0]
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            let mut family = MetricFamily::default();
+            family.set_metric(vec![Metric::default()]);
+            vec![family]
+        }
+    }
+
+    #[actix_web::test]
+    async fn metrics_answers_500_when_the_registry_cant_be_encoded() {
+        let metrics = web::Data::new(AppMetrics::new());
+        let desc = Desc::new("unencodable".to_string(), "Never encodes".to_string(), Vec::new(), HashMap::new()).unwrap();
+        metrics.registry.register(Box::new(Unencodable(desc))).unwrap();
+        let app = test::init_service(
+            App::new()
+            .app_data(metrics.clone())
+            .app_data(web::Data::new(ScrapeCache::new(Duration::ZERO)))
+            .app_data(web::Data::new(MetricsStreaming(false)))
+            .app_data(web::Data::new(EndpointAuth::new(None, Vec::new())))
+            .route("/metrics", web::get().to(metrics_handler)),
+        )
+        .await;
+
+        let response = test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(metrics.encode_failures.get(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+//! Tuning and load of the actix-web HTTP server.
+//!
+//! The values of `SERVER_WORKERS`, `SERVER_KEEP_ALIVE_SECS`,
+//! `SERVER_CLIENT_REQUEST_TIMEOUT_MS`, `SERVER_MAX_CONNECTIONS` and
+//! `SERVER_BACKLOG` the server runs with are exported as
+//! `http_server_configured_workers`, `http_server_keep_alive_seconds`,
+//! `http_server_client_request_timeout_seconds`,
+//! `http_server_max_connections_per_worker` and `http_server_backlog`, so
+//! dashboards can put the live gauges against them:
+//!
+//! - `http_server_workers`, the workers running, counted with a
+//!   `WorkerGuard` shared by the apps of each worker thread (one per
+//!   listener);
+//! - `http_server_connections`, the connections open, counted with a
+//!   `ConnectionGuard` stored in each connection's extensions, and
+//!   `http_server_connections_total`, the connections accepted.
+
+use crate::{config::ServerTuning, registry::Registry};
+use prometheus::{Gauge, IntCounter, IntGauge};
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+thread_local! {
+    /// The guard of the worker running on this thread, if any.
+    static WORKER: RefCell<Weak<WorkerGuard>> = const { RefCell::new(Weak::new()) };
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerMetrics {
+    pub configured_workers: IntGauge,
+    pub keep_alive: Gauge,
+    pub client_request_timeout: Gauge,
+    pub max_connections: IntGauge,
+    pub backlog: IntGauge,
+    pub workers: IntGauge,
+    pub connections: IntGauge,
+    pub connections_total: IntCounter,
+}
+
+impl ServerMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = Self {
+            configured_workers: IntGauge::new("http_server_configured_workers", "Worker threads the HTTP server starts")?,
+            keep_alive: Gauge::new(
+                "http_server_keep_alive_seconds",
+                "Idle time before the HTTP server closes a keep-alive connection, 0 when disabled",
+            )?,
+            client_request_timeout: Gauge::new(
+                "http_server_client_request_timeout_seconds",
+                "Time a client has to send its request head, 0 when unlimited",
+            )?,
+            max_connections: IntGauge::new(
+                "http_server_max_connections_per_worker",
+                "Concurrent connections a worker accepts",
+            )?,
+            backlog: IntGauge::new("http_server_backlog", "Pending connection queue of each TCP listener")?,
+            workers: IntGauge::new("http_server_workers", "HTTP server workers running")?,
+            connections: IntGauge::new("http_server_connections", "HTTP connections open")?,
+            connections_total: IntCounter::new("http_server_connections_total", "HTTP connections accepted")?,
+        };
+        registry.register(Box::new(metrics.configured_workers.clone()))?;
+        registry.register(Box::new(metrics.keep_alive.clone()))?;
+        registry.register(Box::new(metrics.client_request_timeout.clone()))?;
+        registry.register(Box::new(metrics.max_connections.clone()))?;
+        registry.register(Box::new(metrics.backlog.clone()))?;
+        registry.register(Box::new(metrics.workers.clone()))?;
+        registry.register(Box::new(metrics.connections.clone()))?;
+        registry.register(Box::new(metrics.connections_total.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Records the tuning the server is started with.
+    pub fn set_tuning(&self, tuning: &ServerTuning) {
+        self.configured_workers.set(tuning.workers as i64);
+        self.keep_alive.set(tuning.keep_alive.as_secs_f64());
+        self.client_request_timeout.set(tuning.client_request_timeout.as_secs_f64());
+        self.max_connections.set(tuning.max_connections as i64);
+        self.backlog.set(i64::from(tuning.backlog));
+    }
+
+    /// Counts the worker of the calling thread until the guard is dropped
+    /// with its apps. The app factory runs once per listener on each worker,
+    /// and every call on a thread shares the guard of the first.
+    pub fn worker_started(&self) -> Rc<WorkerGuard> {
+        WORKER.with(|worker| {
+            if let Some(guard) = worker.borrow().upgrade() {
+                return guard;
+            }
+            self.workers.inc();
+            let guard = Rc::new(WorkerGuard(self.workers.clone()));
+            *worker.borrow_mut() = Rc::downgrade(&guard);
+            guard
+        })
+    }
+
+    /// Counts a connection until the guard is dropped with it.
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        self.connections_total.inc();
+        self.connections.inc();
+        ConnectionGuard(self.connections.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct WorkerGuard(IntGauge);
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard(IntGauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
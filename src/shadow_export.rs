@@ -0,0 +1,273 @@
+//! Shadow export to a second collector (`OTEL_EXPORTER_OTLP_SHADOW_ENDPOINT`).
+//!
+//! Moving from one observability backend to another usually means running
+//! both for a while and checking the new one receives everything before
+//! switching over. With a shadow endpoint set, every span, log record and
+//! metric export also goes there, `/v1/<signal>` appended like for
+//! `OTEL_EXPORTER_OTLP_ENDPOINT`, with its own protocol, compression and
+//! headers (`OTEL_EXPORTER_OTLP_SHADOW_*`): the primary's headers, usually
+//! the old vendor's API key, aren't sent to it.
+//!
+//! The shadow has batch processors and a metric reader of its own, so it
+//! has its own queues and export threads: a shadow that is slow or down
+//! drops its own batches and never holds up or fails the primary's. Its
+//! batches aren't retried, don't go through the circuit breaker and don't
+//! count in the `otel_exporter_*` metrics, which stay the primary's; its
+//! requests show in `otlp_export_sent_bytes_total` and
+//! `otlp_export_request_duration_seconds` under its own `destination`.
+//! Redaction, tail sampling and the admin export switches apply to both.
+//! The audit log stream and the OTLP receiver only go to the primary.
+//!
+//! To compare the two, `otlp_export_tee_items_total{signal,target,result}`
+//! counts the items each `target` (`primary` or `shadow`) `exported` or
+//! `failed` to export; once the shadow's rates match the primary's, the
+//! shadow can become the primary.
+
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{LogBatch, LogExporter};
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter};
+#[cfg(feature = "traces")]
+use opentelemetry::Context;
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{Span, SpanData, SpanExporter, SpanProcessor};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use opentelemetry_sdk::Resource;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use opentelemetry_sdk::error::OTelSdkResult;
+use crate::{pipeline::Signal, registry::Registry};
+use prometheus::{IntCounterVec, Opts};
+#[cfg(feature = "metrics")]
+use std::fmt;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use std::time::Duration;
+
+/// Which collector an export went to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Primary,
+    Shadow,
+}
+
+impl Target {
+    pub const ALL: [Target; 2] = [Target::Primary, Target::Shadow];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Target::Primary => "primary",
+            Target::Shadow => "shadow",
+        }
+    }
+}
+
+/// `otlp_export_tee_items_total`, shared by the exporters of both targets.
+#[derive(Clone, Debug)]
+pub struct ShadowStats {
+    // Only written by the exporters.
+    #[cfg_attr(not(any(feature = "traces", feature = "metrics", feature = "logs")), allow(dead_code))]
+    items: IntCounterVec,
+}
+
+impl ShadowStats {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let items = IntCounterVec::new(
+            Opts::new(
+                "otlp_export_tee_items_total",
+                "Telemetry items exported to the primary and shadow collectors, by result",
+            ),
+            &["signal", "target", "result"],
+        )?;
+        registry.register(Box::new(items.clone()))?;
+        for signal in Signal::ALL {
+            for target in Target::ALL {
+                for result in ["exported", "failed"] {
+                    items.with_label_values(&[signal.as_str(), target.as_str(), result]);
+                }
+            }
+        }
+        Ok(Self { items })
+    }
+
+    #[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+    fn record(&self, signal: Signal, target: Target, items: usize, result: &OTelSdkResult) {
+        let result = if result.is_ok() { "exported" } else { "failed" };
+        self.items.with_label_values(&[signal.as_str(), target.as_str(), result]).inc_by(items as u64);
+    }
+}
+
+/// Counts span batches for `target` in `stats`, if any.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct TeeSpanExporter<E> {
+    inner: E,
+    stats: Option<ShadowStats>,
+    target: Target,
+}
+
+#[cfg(feature = "traces")]
+impl<E> TeeSpanExporter<E> {
+    pub fn new(inner: E, stats: Option<ShadowStats>, target: Target) -> Self {
+        Self { inner, stats, target }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl<E: SpanExporter> SpanExporter for TeeSpanExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let items = batch.len();
+        let result = self.inner.export(batch).await;
+        if let Some(stats) = &self.stats {
+            stats.record(Signal::Traces, self.target, items, &result);
+        }
+        result
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Counts log batches for `target` in `stats`, if any.
+#[cfg(feature = "logs")]
+#[derive(Debug)]
+pub struct TeeLogExporter<E> {
+    inner: E,
+    stats: Option<ShadowStats>,
+    target: Target,
+}
+
+#[cfg(feature = "logs")]
+impl<E> TeeLogExporter<E> {
+    pub fn new(inner: E, stats: Option<ShadowStats>, target: Target) -> Self {
+        Self { inner, stats, target }
+    }
+}
+
+#[cfg(feature = "logs")]
+impl<E: LogExporter> LogExporter for TeeLogExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        let items = batch.iter().count();
+        let result = self.inner.export(batch).await;
+        if let Some(stats) = &self.stats {
+            stats.record(Signal::Logs, self.target, items, &result);
+        }
+        result
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Counts metric exports for `target` in `stats`, if any.
+#[cfg(feature = "metrics")]
+pub struct TeeMetricExporter<E> {
+    inner: E,
+    stats: Option<ShadowStats>,
+    target: Target,
+}
+
+#[cfg(feature = "metrics")]
+impl<E> TeeMetricExporter<E> {
+    pub fn new(inner: E, stats: Option<ShadowStats>, target: Target) -> Self {
+        Self { inner, stats, target }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<E> fmt::Debug for TeeMetricExporter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TeeMetricExporter").field("target", &self.target).finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<E: PushMetricExporter> PushMetricExporter for TeeMetricExporter<E> {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        let items = metrics.scope_metrics().map(|scope| scope.metrics().count()).sum();
+        let result = self.inner.export(metrics).await;
+        if let Some(stats) = &self.stats {
+            stats.record(Signal::Metrics, self.target, items, &result);
+        }
+        result
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.inner.temporality()
+    }
+}
+
+/// Hands every ended span to the primary's batch processor and, if any, the
+/// shadow's, so tail sampling in front of it decides for both. Only the
+/// primary's flush and shutdown results are returned.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct TeeSpanProcessor<P, S> {
+    primary: P,
+    shadow: Option<S>,
+}
+
+#[cfg(feature = "traces")]
+impl<P, S> TeeSpanProcessor<P, S> {
+    pub fn new(primary: P, shadow: Option<S>) -> Self {
+        Self { primary, shadow }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl<P: SpanProcessor, S: SpanProcessor> SpanProcessor for TeeSpanProcessor<P, S> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.primary.on_start(span, cx);
+        if let Some(shadow) = &self.shadow {
+            shadow.on_start(span, cx);
+        }
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if let Some(shadow) = &self.shadow {
+            shadow.on_end(span.clone());
+        }
+        self.primary.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        if let Some(shadow) = &self.shadow {
+            let _ = shadow.force_flush();
+        }
+        self.primary.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        if let Some(shadow) = &self.shadow {
+            let _ = shadow.shutdown_with_timeout(timeout);
+        }
+        self.primary.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.primary.set_resource(resource);
+        if let Some(shadow) = &mut self.shadow {
+            shadow.set_resource(resource);
+        }
+    }
+}
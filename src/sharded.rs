@@ -0,0 +1,518 @@
+//! Contention-free metric primitives for hot paths.
+//!
+//! A plain prometheus `IntCounter` is a single atomic, so at very high request
+//! rates every core fights over the same cache line. `ShardedCounter` keeps
+//! one cache-line-padded atomic per shard, lets each thread increment its own
+//! shard, and only sums the shards when the registry is gathered.
+//! `ShardedHistogramVec` applies the same idea to labeled histograms, and
+//! can also track them as native histograms (see `native_histogram`).
+
+use crate::native_histogram::{NativeBuckets, NativeHistogramOpts};
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Bucket, Counter, Histogram, LabelPair, Metric, MetricFamily, MetricType},
+    HistogramOpts,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
+    time::Instant,
+};
+
+/// Upper bound on shards; beyond this the gather-time sum costs more than the
+/// contention it saves.
+const MAX_SHARDS: usize = 64;
+
+static NEXT_THREAD_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Threads are assigned slots round-robin, so the worker threads of a
+    // runtime spread evenly over the shards.
+    static THREAD_SLOT: usize = NEXT_THREAD_SLOT.fetch_add(1, Ordering::Relaxed);
+}
+
+fn thread_slot() -> usize {
+    THREAD_SLOT.with(|slot| *slot)
+}
+
+/// Number of shards used by default: the available parallelism rounded up to
+/// a power of two.
+pub fn default_shard_count() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .next_power_of_two()
+        .min(MAX_SHARDS)
+}
+
+// Aligned to 128 bytes rather than 64 because adjacent-line prefetching on
+// x86 pulls cache lines in pairs.
+#[repr(align(128))]
+#[derive(Default)]
+struct Shard(AtomicU64);
+
+/// A monotonically increasing counter spread over per-thread shards.
+#[derive(Clone)]
+pub struct ShardedCounter {
+    desc: Desc,
+    shards: Arc<[Shard]>,
+}
+
+impl std::fmt::Debug for ShardedCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedCounter")
+            .field("name", &self.desc.fq_name)
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+impl ShardedCounter {
+    pub fn new<S1: Into<String>, S2: Into<String>>(name: S1, help: S2) -> prometheus::Result<Self> {
+        Self::with_shards(name, help, default_shard_count())
+    }
+
+    pub fn with_shards<S1: Into<String>, S2: Into<String>>(
+        name: S1,
+        help: S2,
+        shards: usize,
+    ) -> prometheus::Result<Self> {
+        let shards = shards.clamp(1, MAX_SHARDS);
+        Ok(Self {
+            desc: Desc::new(name.into(), help.into(), Vec::new(), HashMap::new())?,
+            shards: (0..shards).map(|_| Shard::default()).collect(),
+        })
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    #[inline]
+    pub fn inc_by(&self, v: u64) {
+        let shard = &self.shards[thread_slot() % self.shards.len()];
+        shard.0.fetch_add(v, Ordering::Relaxed);
+    }
+
+    /// Sum of all shards. Concurrent increments may or may not be included.
+    pub fn get(&self) -> u64 {
+        self.shards.iter().map(|s| s.0.load(Ordering::Relaxed)).sum()
+    }
+}
+
+impl Collector for ShardedCounter {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut counter = Counter::default();
+        counter.set_value(self.get() as f64);
+        let mut metric = Metric::default();
+        metric.set_counter(counter);
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::COUNTER);
+        family.set_metric(vec![metric]);
+        vec![family]
+    }
+}
+
+const BUCKETS_PER_LINE: usize = 128 / size_of::<AtomicU64>();
+
+/// Bucket counts filling one padded cache line, like `Shard`.
+#[repr(align(128))]
+#[derive(Default)]
+struct BucketLine([AtomicU64; BUCKETS_PER_LINE]);
+
+/// The bucket counts of one shard, in padded cache lines so they never
+/// share one with another shard's. Padding `HistogramShard` doesn't cover
+/// them, as they are allocated apart from it.
+struct ShardBuckets {
+    lines: Box<[BucketLine]>,
+    len: usize,
+}
+
+impl ShardBuckets {
+    fn new(len: usize) -> Self {
+        Self { lines: (0..len.div_ceil(BUCKETS_PER_LINE)).map(|_| BucketLine::default()).collect(), len }
+    }
+
+    fn get(&self, index: usize) -> &AtomicU64 {
+        &self.lines[index / BUCKETS_PER_LINE].0[index % BUCKETS_PER_LINE]
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &AtomicU64> {
+        self.lines.iter().flat_map(|line| &line.0).take(self.len)
+    }
+}
+
+#[repr(align(128))]
+struct HistogramShard {
+    /// Non-cumulative counts; the last slot is the implicit `+Inf` bucket.
+    buckets: ShardBuckets,
+    count: AtomicU64,
+    sum_bits: AtomicU64,
+    /// Held while observing, so a gather sees the classic and the native
+    /// buckets of the shard agree.
+    native: Option<Mutex<NativeBuckets>>,
+}
+
+impl HistogramShard {
+    fn new(bucket_count: usize, native: Option<NativeHistogramOpts>) -> Self {
+        Self {
+            buckets: ShardBuckets::new(bucket_count + 1),
+            count: AtomicU64::new(0),
+            sum_bits: AtomicU64::new(0f64.to_bits()),
+            native: native.map(|opts| Mutex::new(NativeBuckets::new(opts.schema))),
+        }
+    }
+
+    fn lock_native(&self) -> Option<MutexGuard<'_, NativeBuckets>> {
+        let native = self.native.as_ref()?;
+        Some(native.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+struct HistogramCore {
+    upper_bounds: Arc<[f64]>,
+    label_pairs: Vec<LabelPair>,
+    native: Option<NativeHistogramOpts>,
+    shards: Box<[HistogramShard]>,
+}
+
+/// One labeled child of a `ShardedHistogramVec`.
+#[derive(Clone)]
+pub struct ShardedHistogram {
+    core: Arc<HistogramCore>,
+}
+
+impl ShardedHistogram {
+    pub fn observe(&self, v: f64) {
+        let core = &self.core;
+        let shard = &core.shards[thread_slot() % core.shards.len()];
+        let mut native = shard.lock_native();
+        if let (Some(native), Some(opts)) = (native.as_mut(), core.native) {
+            native.observe(v, opts.max_buckets);
+        }
+        let bucket = core.upper_bounds.partition_point(|bound| *bound < v);
+        shard.buckets.get(bucket).fetch_add(1, Ordering::Relaxed);
+        // The shard is effectively owned by this thread, so the loop almost
+        // never retries.
+        let _ = shard.sum_bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + v).to_bits())
+        });
+        shard.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts a timer that observes the elapsed seconds when dropped.
+    pub fn start_timer(&self) -> HistogramTimer {
+        HistogramTimer {
+            histogram: self.clone(),
+            start: Instant::now(),
+            observed: false,
+        }
+    }
+
+    /// Runs `fut` and observes how long it took, counted from its first
+    /// poll rather than from this call, so time spent queued behind other
+    /// tasks before it starts isn't included. If `fut` is dropped before
+    /// completing, the time until then is still observed.
+    pub async fn time<F: Future>(&self, fut: F) -> F::Output {
+        // The body of an async fn only runs once it is polled.
+        let _timer = self.start_timer();
+        fut.await
+    }
+
+    /// Total number of observations across all shards.
+    pub fn get_sample_count(&self) -> u64 {
+        self.core.shards.iter().map(|s| s.count.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Sum of all observed values across all shards.
+    pub fn get_sample_sum(&self) -> f64 {
+        self.core
+            .shards
+            .iter()
+            .map(|s| f64::from_bits(s.sum_bits.load(Ordering::Relaxed)))
+            .sum()
+    }
+
+    fn metric(&self) -> Metric {
+        let core = &self.core;
+        let mut counts = vec![0u64; core.upper_bounds.len() + 1];
+        let mut sum = 0.0;
+        let mut merged: Option<NativeBuckets> = None;
+        for shard in core.shards.iter() {
+            let native = shard.lock_native();
+            if let (Some(native), Some(opts)) = (native.as_deref(), core.native) {
+                match &mut merged {
+                    Some(merged) => merged.merge(native, opts.max_buckets),
+                    None => merged = Some(native.clone()),
+                }
+            }
+            for (total, bucket) in counts.iter_mut().zip(shard.buckets.iter()) {
+                *total += bucket.load(Ordering::Relaxed);
+            }
+            sum += f64::from_bits(shard.sum_bits.load(Ordering::Relaxed));
+        }
+
+        let mut cumulative = 0;
+        let buckets = core
+            .upper_bounds
+            .iter()
+            .zip(counts.iter())
+            .map(|(bound, count)| {
+                cumulative += count;
+                let mut bucket = Bucket::default();
+                bucket.set_upper_bound(*bound);
+                bucket.set_cumulative_count(cumulative);
+                bucket
+            })
+            .collect();
+        // Derive the count from the buckets rather than the `count` atomics so
+        // a gather racing observations still yields a consistent histogram.
+        let count = counts.iter().sum();
+
+        let mut histogram = Histogram::default();
+        histogram.set_sample_count(count);
+        histogram.set_sample_sum(sum);
+        histogram.set_bucket(buckets);
+        if let Some(native) = merged {
+            native.encode(&mut histogram);
+        }
+        let mut metric = Metric::from_label(core.label_pairs.clone());
+        metric.set_histogram(histogram);
+        metric
+    }
+}
+
+/// Guard returned by `start_timer`: observes the elapsed time in seconds
+/// when dropped, unless `stop_and_discard` is called.
+#[must_use = "the timer observes when dropped; binding it to `_` drops it immediately"]
+pub struct HistogramTimer {
+    histogram: ShardedHistogram,
+    start: Instant,
+    observed: bool,
+}
+
+impl HistogramTimer {
+    /// Observes the elapsed time now and returns it in seconds.
+    pub fn observe_duration(mut self) -> f64 {
+        self.observe()
+    }
+
+    /// Stops the timer without observing, e.g. for a request that was
+    /// rejected before doing any work.
+    pub fn stop_and_discard(mut self) -> f64 {
+        self.observed = true;
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn observe(&mut self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if !self.observed {
+            self.observed = true;
+            self.histogram.observe(elapsed);
+        }
+        elapsed
+    }
+}
+
+impl Drop for HistogramTimer {
+    fn drop(&mut self) {
+        self.observe();
+    }
+}
+
+impl std::fmt::Debug for HistogramTimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HistogramTimer")
+            .field("start", &self.start)
+            .field("observed", &self.observed)
+            .finish()
+    }
+}
+
+/// A labeled histogram whose children record into per-thread shards and are
+/// merged when the registry is gathered. Mirrors the `HistogramVec` API used
+/// by the request middleware.
+#[derive(Clone)]
+pub struct ShardedHistogramVec {
+    desc: Desc,
+    upper_bounds: Arc<[f64]>,
+    native: Option<NativeHistogramOpts>,
+    shard_count: usize,
+    children: Arc<RwLock<HashMap<u64, Vec<Child>>>>,
+}
+
+/// Children are bucketed by a hash of their label values so lookups on the
+/// hot path don't allocate; the values are kept to resolve collisions.
+type Child = (Vec<String>, ShardedHistogram);
+
+impl std::fmt::Debug for ShardedHistogramVec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedHistogramVec")
+            .field("name", &self.desc.fq_name)
+            .field("shards", &self.shard_count)
+            .finish()
+    }
+}
+
+impl ShardedHistogramVec {
+    pub fn new(opts: HistogramOpts, label_names: &[&str]) -> prometheus::Result<Self> {
+        Self::with_shards(opts, label_names, default_shard_count())
+    }
+
+    pub fn with_shards(opts: HistogramOpts, label_names: &[&str], shards: usize) -> prometheus::Result<Self> {
+        if opts.buckets.is_empty() || opts.buckets.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(prometheus::Error::Msg(
+                "histogram buckets must be non-empty and strictly increasing".to_string(),
+            ));
+        }
+        let desc = Desc::new(
+            opts.common_opts.fq_name(),
+            opts.common_opts.help.clone(),
+            label_names.iter().map(|l| l.to_string()).collect(),
+            opts.common_opts.const_labels.clone(),
+        )?;
+        // `+Inf` is always implied; drop an explicit one so it isn't doubled.
+        let upper_bounds: Vec<f64> = opts.buckets.iter().copied().filter(|b| b.is_finite()).collect();
+        Ok(Self {
+            desc,
+            upper_bounds: upper_bounds.into(),
+            native: None,
+            shard_count: shards.clamp(1, MAX_SHARDS),
+            children: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /// Also tracks the children as native histograms, exposed in the
+    /// protobuf format next to the classic buckets; `None` doesn't.
+    pub fn with_native(self, native: Option<NativeHistogramOpts>) -> Self {
+        Self { native, ..self }
+    }
+
+    /// Returns the child for `values`, creating it on first use.
+    ///
+    /// # Panics
+    ///
+    /// If the number of values doesn't match the label names, like
+    /// `HistogramVec::with_label_values`.
+    pub fn with_label_values(&self, values: &[&str]) -> ShardedHistogram {
+        assert_eq!(
+            values.len(),
+            self.desc.variable_labels.len(),
+            "inconsistent label cardinality for {}",
+            self.desc.fq_name
+        );
+        let hash = hash_values(values);
+        {
+            let children = self.children.read().unwrap();
+            if let Some(child) = lookup(children.get(&hash), values) {
+                return child.clone();
+            }
+        }
+        let mut children = self.children.write().unwrap();
+        let bucket = children.entry(hash).or_default();
+        if let Some(child) = lookup(Some(bucket), values) {
+            return child.clone();
+        }
+        let child = self.new_child(values);
+        bucket.push((values.iter().map(|v| v.to_string()).collect(), child.clone()));
+        child
+    }
+
+    /// `with_label_values(values).start_timer()`: the returned guard
+    /// observes the elapsed seconds into that child when dropped.
+    ///
+    /// # Panics
+    ///
+    /// Like `with_label_values`.
+    pub fn start_timer_with_labels(&self, values: &[&str]) -> HistogramTimer {
+        self.with_label_values(values).start_timer()
+    }
+
+    /// `with_label_values(values).time(fut)`: times `fut` from its first
+    /// poll, see `ShardedHistogram::time`.
+    ///
+    /// # Panics
+    ///
+    /// Like `with_label_values`, when called rather than when polled.
+    pub fn time_with_labels<F: Future>(&self, values: &[&str], fut: F) -> impl Future<Output = F::Output> + use<F> {
+        let child = self.with_label_values(values);
+        async move { child.time(fut).await }
+    }
+
+    /// Total number of observations across all children.
+    pub fn get_sample_count(&self) -> u64 {
+        let children = self.children.read().unwrap();
+        children.values().flatten().map(|(_, child)| child.get_sample_count()).sum()
+    }
+
+    fn new_child(&self, values: &[&str]) -> ShardedHistogram {
+        let mut label_pairs: Vec<LabelPair> = self
+            .desc
+            .variable_labels
+            .iter()
+            .zip(values)
+            .map(|(name, value)| {
+                let mut pair = LabelPair::default();
+                pair.set_name(name.clone());
+                pair.set_value(value.to_string());
+                pair
+            })
+            .chain(self.desc.const_label_pairs.iter().cloned())
+            .collect();
+        label_pairs.sort();
+        ShardedHistogram {
+            core: Arc::new(HistogramCore {
+                upper_bounds: self.upper_bounds.clone(),
+                label_pairs,
+                native: self.native,
+                shards: (0..self.shard_count)
+                    .map(|_| HistogramShard::new(self.upper_bounds.len(), self.native))
+                    .collect(),
+            }),
+        }
+    }
+}
+
+fn hash_values(values: &[&str]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for value in values {
+        value.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn lookup<'a>(bucket: Option<&'a Vec<Child>>, values: &[&str]) -> Option<&'a ShardedHistogram> {
+    bucket?
+        .iter()
+        .find(|(key, _)| key.iter().map(String::as_str).eq(values.iter().copied()))
+        .map(|(_, child)| child)
+}
+
+impl Collector for ShardedHistogramVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let children = self.children.read().unwrap();
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.set_metric(children.values().flatten().map(|(_, child)| child.metric()).collect());
+        vec![family]
+    }
+}
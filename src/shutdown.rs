@@ -0,0 +1,196 @@
+//! Final summary emitted when the server exits.
+//!
+//! Scrapes and periodic exports can miss the last seconds of a pod's life, so
+//! `serve` logs one `ShutdownReport` (and exports it as a `shutdown` span)
+//! right before the telemetry pipelines are flushed.
+//!
+//! The pipelines are then shut down one after the other in the order of
+//! `SHUTDOWN_FLUSH_ORDER` (logs, traces, metrics by default), each within its
+//! own deadline, so a tight termination grace period cuts off the least
+//! useful signal rather than whichever happened to be flushed last.
+
+use crate::{metrics::AppMetrics, pipeline::Signal};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{Span as _, Tracer},
+    KeyValue,
+};
+use std::{fmt, str::FromStr, time::Duration};
+use tracing::{info, warn};
+
+/// Deadline of a flush step without an explicit one; the SDK's default.
+pub const DEFAULT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One pipeline shut down at exit and how long its final flush may take,
+/// parsed from `signal[:ms]`, e.g. `logs:2000`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlushStep {
+    pub signal: Signal,
+    pub timeout: Duration,
+}
+
+impl FromStr for FlushStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, timeout) = match s.split_once(':') {
+            Some((name, ms)) => {
+                let ms: u64 = ms.trim().parse().map_err(|_| format!("{s:?}: the deadline must be in milliseconds"))?;
+                (name.trim(), Duration::from_millis(ms))
+            }
+            None => (s, DEFAULT_FLUSH_TIMEOUT),
+        };
+        let signal = Signal::ALL
+            .into_iter()
+            .find(|signal| signal.as_str() == name)
+            .ok_or_else(|| format!("unknown signal {name:?}, expected `traces`, `logs` or `metrics`"))?;
+        Ok(Self { signal, timeout })
+    }
+}
+
+impl fmt::Display for FlushStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.signal.as_str(), self.timeout.as_millis())
+    }
+}
+
+/// Parses a comma-separated flush order, dropping empty entries.
+pub fn parse_flush_order(s: &str) -> Result<Vec<FlushStep>, String> {
+    s.split(',').filter(|part| !part.trim().is_empty()).map(str::parse).collect()
+}
+
+/// Why the server stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// A termination signal, by name (`SIGTERM`, `SIGINT`, ...).
+    Signal(&'static str),
+    /// The server stopped without a signal, e.g. through a `ServerHandle`.
+    Stopped,
+    /// Binding or running the server failed.
+    Error(String),
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Signal(name) => write!(f, "signal {name}"),
+            ExitReason::Stopped => write!(f, "stopped"),
+            ExitReason::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShutdownReport {
+    pub exit_reason: ExitReason,
+    pub uptime: Duration,
+    /// Requests recorded by the request middleware (excluded routes aren't).
+    pub requests_served: u64,
+    /// Highest memory usage seen by the system sampler.
+    pub peak_memory_bytes: u64,
+    /// Telemetry items dropped from full export queues, indexed by
+    /// `Signal as usize`.
+    pub dropped: [u64; 3],
+    /// Export batches that failed after all retries, indexed by
+    /// `Signal as usize`.
+    pub export_failures: [u64; 3],
+}
+
+impl ShutdownReport {
+    pub fn collect(metrics: &AppMetrics, uptime: Duration, exit_reason: ExitReason) -> Self {
+        let snapshots = Signal::ALL.map(|signal| metrics.pipeline.snapshot(signal));
+        Self {
+            exit_reason,
+            uptime,
+            requests_served: metrics.http_request_duration.get_sample_count(),
+            peak_memory_bytes: metrics.memory_peak_gauge.get() as u64,
+            dropped: snapshots.clone().map(|s| s.queue_dropped),
+            export_failures: snapshots.map(|s| s.failures),
+        }
+    }
+
+    /// Logs the report as one event, at `warn` unless the exit was a signal
+    /// or a clean stop, and exports it as a `shutdown` span.
+    pub fn emit(&self) {
+        let dropped = |signal: Signal| self.dropped[signal as usize];
+        let failures = |signal: Signal| self.export_failures[signal as usize];
+        macro_rules! report {
+            ($level:ident) => {
+                $level!(
+                    exit_reason = %self.exit_reason,
+                    uptime_secs = self.uptime.as_secs(),
+                    requests_served = self.requests_served,
+                    peak_memory_bytes = self.peak_memory_bytes,
+                    dropped_spans = dropped(Signal::Traces),
+                    dropped_logs = dropped(Signal::Logs),
+                    export_failures_traces = failures(Signal::Traces),
+                    export_failures_logs = failures(Signal::Logs),
+                    export_failures_metrics = failures(Signal::Metrics),
+                    "Shutdown report"
+                )
+            };
+        }
+        match self.exit_reason {
+            ExitReason::Error(_) => report!(warn),
+            _ => report!(info),
+        }
+
+        #[cfg(feature = "traces")]
+        {
+            let tracer = global::tracer("prom_otel");
+            let mut span = tracer
+                .span_builder("shutdown")
+                .with_attributes([
+                    KeyValue::new("shutdown.exit_reason", self.exit_reason.to_string()),
+                    KeyValue::new("shutdown.uptime_secs", self.uptime.as_secs() as i64),
+                    KeyValue::new("shutdown.requests_served", self.requests_served as i64),
+                    KeyValue::new("shutdown.peak_memory_bytes", self.peak_memory_bytes as i64),
+                ])
+                .start(&tracer);
+            for signal in Signal::ALL {
+                span.set_attribute(KeyValue::new(
+                    format!("shutdown.dropped.{}", signal.as_str()),
+                    dropped(signal) as i64,
+                ));
+                span.set_attribute(KeyValue::new(
+                    format!("shutdown.export_failures.{}", signal.as_str()),
+                    failures(signal) as i64,
+                ));
+            }
+            span.end();
+        }
+    }
+}
+
+/// Waits for a termination signal and returns its name together with
+/// whether in-flight requests should be drained first (`SIGTERM`), matching
+/// Actix's own signal handling.
+pub async fn wait_for_signal() -> (&'static str, bool) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (Ok(mut term), Ok(mut int), Ok(mut quit)) = (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+            signal(SignalKind::quit()),
+        ) else {
+            warn!("Failed to install signal handlers");
+            return std::future::pending().await;
+        };
+        tokio::select! {
+            _ = term.recv() => ("SIGTERM", true),
+            _ = int.recv() => ("SIGINT", false),
+            _ = quit.recv() => ("SIGQUIT", false),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if tokio::signal::ctrl_c().await.is_err() {
+            warn!("Failed to install signal handlers");
+            return std::future::pending().await;
+        }
+        ("ctrl-c", false)
+    }
+}
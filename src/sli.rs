@@ -0,0 +1,128 @@
+//! Precomputed request SLI counters.
+//!
+//! Every request is counted in `sli_requests_total{route}`; the ones that meet
+//! the configured status and latency criteria are also counted in
+//! `sli_requests_good_total{route}`, so availability/latency SLOs reduce to a
+//! ratio of two counters.
+
+use crate::registry::Registry;
+use prometheus::{IntCounter, IntCounterVec, Opts};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// A status code (`503`) or a whole class (`5xx`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusMatcher {
+    Code(u16),
+    Class(u16),
+}
+
+impl StatusMatcher {
+    pub fn matches(self, status: u16) -> bool {
+        match self {
+            StatusMatcher::Code(code) => status == code,
+            StatusMatcher::Class(class) => status / 100 == class,
+        }
+    }
+}
+
+impl FromStr for StatusMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("expected a status code or class like 503 or 5xx, got {s:?}");
+        if let Some(class) = s.strip_suffix("xx").or_else(|| s.strip_suffix("XX")) {
+            return match class.parse::<u16>() {
+                Ok(class @ 1..=5) => Ok(StatusMatcher::Class(class)),
+                _ => Err(invalid()),
+            };
+        }
+        match s.parse::<u16>() {
+            Ok(code @ 100..=599) => Ok(StatusMatcher::Code(code)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for StatusMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusMatcher::Code(code) => write!(f, "{code}"),
+            StatusMatcher::Class(class) => write!(f, "{class}xx"),
+        }
+    }
+}
+
+/// Parses a comma-separated list such as `5xx,429`.
+pub fn parse_status_list(s: &str) -> Result<Vec<StatusMatcher>, String> {
+    s.split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// What makes a request "good" for the SLI.
+#[derive(Clone, Debug)]
+pub struct SliCriteria {
+    pub latency_threshold: Duration,
+    pub bad_statuses: Vec<StatusMatcher>,
+}
+
+impl SliCriteria {
+    pub fn is_good(&self, status: u16, elapsed: Duration) -> bool {
+        elapsed <= self.latency_threshold && !self.bad_statuses.iter().any(|m| m.matches(status))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SliMetrics {
+    total: IntCounterVec,
+    good: IntCounterVec,
+}
+
+impl SliMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let total = IntCounterVec::new(
+            Opts::new("sli_requests_total", "Requests considered for the SLI"),
+            &["route"],
+        )?;
+        let good = IntCounterVec::new(
+            Opts::new("sli_requests_good_total", "Requests meeting the SLI status and latency criteria"),
+            &["route"],
+        )?;
+        registry.register(Box::new(total.clone()))?;
+        registry.register(Box::new(good.clone()))?;
+        Ok(Self { total, good })
+    }
+
+    /// Creates both series for `route` at zero.
+    pub fn prime(&self, route: &str) {
+        self.total.with_label_values(&[route]);
+        self.good.with_label_values(&[route]);
+    }
+
+    pub fn observe(&self, criteria: &SliCriteria, route: &str, status: u16, elapsed: Duration) {
+        self.handles(route).observe(criteria, status, elapsed);
+    }
+
+    /// Both children of `route`, creating them at zero.
+    pub fn handles(&self, route: &str) -> SliHandles {
+        SliHandles { total: self.total.with_label_values(&[route]), good: self.good.with_label_values(&[route]) }
+    }
+}
+
+/// The children of one route, see `route_handles`.
+#[derive(Clone, Debug)]
+pub struct SliHandles {
+    total: IntCounter,
+    good: IntCounter,
+}
+
+impl SliHandles {
+    pub fn observe(&self, criteria: &SliCriteria, status: u16, elapsed: Duration) {
+        self.total.inc();
+        if criteria.is_good(status, elapsed) {
+            self.good.inc();
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Per-route SLOs with precomputed burn rates (`SLOS`).
+//!
+//! Each rule gives a route an objective, written `/route=target` for
+//! availability (requests without one of the `SLI_BAD_STATUSES`) or
+//! `/route=target:ms` for latency (requests answered within `ms`), the
+//! target being the percentage of good requests, e.g. `/checkout=99.9` and
+//! `/checkout=99:300`. A route may have one of each. Rules also match under
+//! `Config::endpoint_prefix`.
+//!
+//! The requests of each objective are counted per minute over the last six
+//! hours, and every gather exports, per `window` (`5m`, `30m`, `1h` and
+//! `6h`, to the minute):
+//!
+//! - `slo_window_requests{route,objective,window,result}`, the `good` and
+//!   `bad` requests in the window;
+//! - `slo_burn_rate{route,objective,window}`, the share of bad requests in
+//!   the window divided by the error budget (`1 - target`): at 1 the budget
+//!   lasts exactly the SLO period, at 14.4 a 30-day budget is 2% spent in an
+//!   hour;
+//!
+//! and `slo_target_ratio{route,objective}`. Alerting then needs no recording
+//! rules, and `emit-alerts` adds the multiwindow burn-rate alerts of the
+//! Google SRE workbook.
+
+use crate::sli::StatusMatcher;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    GaugeVec, IntGaugeVec, Opts, Registry,
+};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Windows burn rates are computed over, with their length in minutes.
+pub const WINDOWS: [(&str, u64); 4] = [("5m", 5), ("30m", 30), ("1h", 60), ("6h", 360)];
+/// Minutes of history kept, the longest window.
+const HISTORY_MINUTES: usize = 360;
+
+/// What an objective counts as a good request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Objective {
+    /// Not answered with one of the `SLI_BAD_STATUSES`.
+    Availability,
+    /// Answered within the threshold.
+    Latency(Duration),
+}
+
+impl Objective {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Objective::Availability => "availability",
+            Objective::Latency(_) => "latency",
+        }
+    }
+}
+
+/// An objective of one route, parsed from `/route=target` or
+/// `/route=target:ms`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SloRule {
+    pub route: String,
+    pub objective: Objective,
+    /// Share of good requests, below 1.
+    pub target: f64,
+}
+
+impl FromStr for SloRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("expected `/route=target` or `/route=target:latency_ms`, got {s:?}");
+        let (route, objective) = s.rsplit_once('=').ok_or_else(invalid)?;
+        let (target, objective) = match objective.split_once(':') {
+            Some((target, ms)) => {
+                let ms = ms.trim().parse::<u64>().map_err(|_| invalid())?;
+                (target, Objective::Latency(Duration::from_millis(ms)))
+            }
+            None => (objective, Objective::Availability),
+        };
+        let target = target.trim().parse::<f64>().map_err(|_| invalid())?;
+        if route.trim().is_empty() {
+            return Err(invalid());
+        }
+        if !(target > 0.0 && target < 100.0) {
+            return Err(format!("{s:?}: the target must be a percentage between 0 and 100, exclusive"));
+        }
+        if objective == Objective::Latency(Duration::ZERO) {
+            return Err(format!("{s:?}: the latency threshold must be positive"));
+        }
+        Ok(Self { route: route.trim().to_string(), objective, target: target / 100.0 })
+    }
+}
+
+impl fmt::Display for SloRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.route, self.target * 100.0)?;
+        if let Objective::Latency(threshold) = self.objective {
+            write!(f, ":{}", threshold.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// Good and bad requests of one minute.
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    minute: u64,
+    good: u64,
+    bad: u64,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    rule: SloRule,
+    /// The rule's route under the endpoint prefix.
+    prefixed: String,
+    /// Indexed by minute modulo `HISTORY_MINUTES`.
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl Tracked {
+    fn matches(&self, route: &str) -> bool {
+        self.rule.route == route || self.prefixed == route
+    }
+
+    /// Good and bad requests of the last `minutes` minutes up to `now`.
+    fn window(&self, now: u64, minutes: u64) -> (u64, u64) {
+        let buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        buckets
+            .iter()
+            .filter(|bucket| bucket.good + bucket.bad > 0 && now.saturating_sub(bucket.minute) < minutes)
+            .fold((0, 0), |(good, bad), bucket| (good + bucket.good, bad + bucket.bad))
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    slos: Vec<Tracked>,
+    started: Instant,
+    window_requests: IntGaugeVec,
+    burn_rate: GaugeVec,
+    target: GaugeVec,
+}
+
+impl Inner {
+    fn minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / 60
+    }
+}
+
+/// The SLOs of `SLOS`, a collector refreshing their gauges when gathered;
+/// cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Slos {
+    inner: Arc<Inner>,
+}
+
+impl Slos {
+    pub fn new(registry: &Registry, rules: &[SloRule], endpoint_prefix: &str) -> prometheus::Result<Self> {
+        let window_requests = IntGaugeVec::new(
+            Opts::new("slo_window_requests", "Good and bad requests of an SLO in a sliding window"),
+            &["route", "objective", "window", "result"],
+        )?;
+        let burn_rate = GaugeVec::new(
+            Opts::new("slo_burn_rate", "Share of bad requests in a sliding window divided by the SLO error budget"),
+            &["route", "objective", "window"],
+        )?;
+        let target = GaugeVec::new(
+            Opts::new("slo_target_ratio", "Share of requests an SLO wants good"),
+            &["route", "objective"],
+        )?;
+        for rule in rules {
+            target.with_label_values(&[rule.route.as_str(), rule.objective.as_str()]).set(rule.target);
+        }
+        let slos = rules
+            .iter()
+            .map(|rule| Tracked {
+                rule: rule.clone(),
+                prefixed: format!("{endpoint_prefix}{}", rule.route),
+                buckets: Mutex::new(vec![Bucket::default(); HISTORY_MINUTES]),
+            })
+            .collect();
+        let slos = Self {
+            inner: Arc::new(Inner { slos, started: Instant::now(), window_requests, burn_rate, target }),
+        };
+        registry.register(Box::new(slos.clone()))?;
+        Ok(slos)
+    }
+
+    /// Counts a request to `route` answered with `status` after `elapsed`
+    /// in the objectives of the route.
+    pub fn observe(&self, route: &str, status: u16, elapsed: Duration, bad_statuses: &[StatusMatcher]) {
+        let mut slos = self.inner.slos.iter().filter(|slo| slo.matches(route)).peekable();
+        if slos.peek().is_none() {
+            return;
+        }
+        let minute = self.inner.minute();
+        for slo in slos {
+            let good = match slo.rule.objective {
+                Objective::Availability => !bad_statuses.iter().any(|m| m.matches(status)),
+                Objective::Latency(threshold) => elapsed <= threshold,
+            };
+            let mut buckets = slo.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let bucket = &mut buckets[minute as usize % HISTORY_MINUTES];
+            if bucket.minute != minute {
+                *bucket = Bucket { minute, good: 0, bad: 0 };
+            }
+            if good {
+                bucket.good += 1;
+            } else {
+                bucket.bad += 1;
+            }
+        }
+    }
+
+    /// Sets the window gauges to the current minute.
+    fn refresh(&self) {
+        let inner = &self.inner;
+        let now = inner.minute();
+        for slo in &inner.slos {
+            let (route, objective) = (slo.rule.route.as_str(), slo.rule.objective.as_str());
+            for (window, minutes) in WINDOWS {
+                let (good, bad) = slo.window(now, minutes);
+                inner.window_requests.with_label_values(&[route, objective, window, "good"]).set(good as i64);
+                inner.window_requests.with_label_values(&[route, objective, window, "bad"]).set(bad as i64);
+                let burn_rate = match good + bad {
+                    0 => 0.0,
+                    total => bad as f64 / total as f64 / (1.0 - slo.rule.target),
+                };
+                inner.burn_rate.with_label_values(&[route, objective, window]).set(burn_rate);
+            }
+        }
+    }
+}
+
+impl Collector for Slos {
+    fn desc(&self) -> Vec<&Desc> {
+        let inner = &self.inner;
+        inner
+            .window_requests
+            .desc()
+            .into_iter()
+            .chain(inner.burn_rate.desc())
+            .chain(inner.target.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.refresh();
+        let inner = &self.inner;
+        let mut families = inner.window_requests.collect();
+        families.extend(inner.burn_rate.collect());
+        families.extend(inner.target.collect());
+        families
+    }
+}
@@ -0,0 +1,274 @@
+//! Watchdog for requests still in flight past `SLOW_REQUEST_THRESHOLD_MS`.
+//!
+//! Latency histograms only see a request once it completes, too late for
+//! one that hangs. `watch` lists each request while it runs, and a watchdog
+//! thread, which keeps running when a handler blocks its worker, checks the
+//! list every `CHECK_INTERVAL`. A request past the threshold is flagged once:
+//!
+//! - a warning is logged with its method, route, path and trace ID;
+//! - `http_slow_requests_total{method,route}` is incremented;
+//! - with the `traces` feature, a `slow_request` event is added to its server
+//!   span.
+//!
+//! When it completes, its total duration is logged too. Like request
+//! deadlines, the watch ends with the response head, so streamed bodies,
+//! e.g. `/metrics/stream`, aren't flagged. With
+//! `SLOW_REQUEST_PROFILE` (`pprof` feature), the watchdog also samples the
+//! CPU for `PROFILE_DURATION` and logs the hottest stacks of the request's
+//! worker thread: a handler spinning or blocking on a lock shows where, one
+//! waiting on I/O shows nothing. Only one profile runs at a time, including
+//! those of `/debug/pprof/profile`; requests flagged meanwhile go without.
+
+use crate::{cardinality::CardinalityLimiter, metrics::AppMetrics, middleware::route_label, registry::Registry};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, Error,
+};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{TraceContextExt, TraceId},
+    Context, KeyValue,
+};
+use prometheus::{IntCounterVec, Opts};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// How often the watchdog looks for slow requests.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a slow request's worker is profiled.
+#[cfg(feature = "pprof")]
+pub const PROFILE_DURATION: Duration = Duration::from_secs(1);
+/// Stacks logged per profile, the most sampled first.
+#[cfg(feature = "pprof")]
+const PROFILE_STACKS: usize = 5;
+/// Frames logged per stack, from the innermost.
+#[cfg(feature = "pprof")]
+const PROFILE_DEPTH: usize = 12;
+
+/// A request in flight.
+#[derive(Debug)]
+struct InFlight {
+    method: String,
+    route: String,
+    path: String,
+    started: Instant,
+    /// Holds the server span, with the `traces` feature.
+    #[cfg(feature = "traces")]
+    context: Context,
+    /// `pthread_self` of the worker running the request, which it doesn't
+    /// leave, to find its samples in a profile.
+    #[cfg(feature = "pprof")]
+    thread: u64,
+    flagged: AtomicBool,
+}
+
+impl InFlight {
+    fn trace_id(&self) -> Option<String> {
+        #[cfg(feature = "traces")]
+        {
+            let trace_id = self.context.span().span_context().trace_id();
+            (trace_id != TraceId::INVALID).then(|| trace_id.to_string())
+        }
+        #[cfg(not(feature = "traces"))]
+        None
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    threshold: Duration,
+    profile: bool,
+    requests: Mutex<Vec<Arc<InFlight>>>,
+    slow: IntCounterVec,
+    cardinality: CardinalityLimiter,
+}
+
+/// The requests in flight and `http_slow_requests_total`; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct SlowRequests {
+    inner: Arc<Inner>,
+}
+
+impl SlowRequests {
+    /// Flags requests running longer than `threshold`, profiling their
+    /// worker when `profile` is set and the `pprof` feature enabled.
+    pub fn new(
+        registry: &Registry,
+        cardinality: CardinalityLimiter,
+        threshold: Duration,
+        profile: bool,
+    ) -> prometheus::Result<Self> {
+        let slow = IntCounterVec::new(
+            Opts::new("http_slow_requests_total", "Requests still in flight past the slow request threshold"),
+            &["method", "route"],
+        )?;
+        registry.register(Box::new(slow.clone()))?;
+        Ok(Self {
+            inner: Arc::new(Inner { threshold, profile, requests: Mutex::new(Vec::new()), slow, cardinality }),
+        })
+    }
+
+    /// Lists `req` until the returned guard is dropped.
+    fn register(&self, req: &ServiceRequest) -> Registration {
+        let request = Arc::new(InFlight {
+            method: req.method().to_string(),
+            route: route_label(req),
+            path: req.path().to_string(),
+            started: Instant::now(),
+            #[cfg(feature = "traces")]
+            context: Context::current(),
+            // SAFETY: `pthread_self` has no preconditions.
+            #[cfg(feature = "pprof")]
+            thread: unsafe { libc::pthread_self() } as u64,
+            flagged: AtomicBool::new(false),
+        });
+        self.inner.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(request.clone());
+        Registration(request, self.inner.clone())
+    }
+
+    /// Starts the watchdog thread.
+    pub fn start_watchdog(&self) -> io::Result<()> {
+        let inner = self.inner.clone();
+        std::thread::Builder::new().name("slow-request-watchdog".into()).spawn(move || {
+            loop {
+                std::thread::sleep(CHECK_INTERVAL);
+                let slow: Vec<Arc<InFlight>> = inner
+                    .requests
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .iter()
+                    .filter(|request| request.started.elapsed() >= inner.threshold)
+                    .filter(|request| !request.flagged.swap(true, Ordering::Relaxed))
+                    .cloned()
+                    .collect();
+                for request in slow {
+                    inner.flag(&request);
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn flag(&self, request: &InFlight) {
+        let elapsed = request.started.elapsed();
+        let trace_id = request.trace_id();
+        warn!(
+            method = %request.method,
+            route = %request.route,
+            path = %request.path,
+            trace_id = trace_id.as_deref().unwrap_or_default(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            threshold_ms = self.threshold.as_millis() as u64,
+            "Request still in flight past the slow request threshold"
+        );
+        let labels = self.cardinality.limit("http_slow_requests_total", [request.method.as_str(), &request.route]);
+        self.slow.with_label_values(&labels).inc();
+        #[cfg(feature = "traces")]
+        request.context.span().add_event(
+            "slow_request",
+            vec![
+                KeyValue::new("slow_request.elapsed_ms", elapsed.as_millis() as i64),
+                KeyValue::new("slow_request.threshold_ms", self.threshold.as_millis() as i64),
+            ],
+        );
+        #[cfg(feature = "pprof")]
+        if self.profile {
+            profile(request, trace_id.as_deref().unwrap_or_default());
+        }
+        #[cfg(not(feature = "pprof"))]
+        let _ = self.profile;
+    }
+}
+
+/// Samples the CPU for `PROFILE_DURATION` and logs the hottest stacks of
+/// `request`'s worker.
+#[cfg(feature = "pprof")]
+fn profile(request: &InFlight, trace_id: &str) {
+    use crate::profiling::ProfilerLock;
+
+    let Some(lock) = ProfilerLock::try_acquire() else {
+        info!(trace_id, "Slow request not profiled, a profile is already running");
+        return;
+    };
+    let report = lock.start().and_then(|guard| {
+        std::thread::sleep(PROFILE_DURATION);
+        guard.report().build()
+    });
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            warn!(trace_id, error = %e, "Failed to profile a slow request");
+            return;
+        }
+    };
+    let mut stacks: Vec<(isize, String)> = report
+        .data
+        .iter()
+        .filter(|(frames, _)| frames.thread_id == request.thread)
+        .map(|(frames, &count)| {
+            // The innermost frames are the profiler's own unwinding.
+            let names: Vec<String> = frames
+                .frames
+                .iter()
+                .flatten()
+                .map(|symbol| symbol.name())
+                .skip_while(|name| name.starts_with("backtrace::") || name.contains("pprof::"))
+                .take(PROFILE_DEPTH)
+                .collect();
+            (count, names.join(" < "))
+        })
+        .collect();
+    let samples: isize = stacks.iter().map(|(count, _)| count).sum();
+    stacks.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+    let stacks: Vec<String> =
+        stacks.into_iter().take(PROFILE_STACKS).map(|(count, stack)| format!("{count}x {stack}")).collect();
+    warn!(
+        route = %request.route,
+        trace_id,
+        samples,
+        stacks = ?stacks,
+        "Slow request profile of its worker"
+    );
+}
+
+/// A request listed for the watchdog while its future lives.
+struct Registration(Arc<InFlight>, Arc<Inner>);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut requests = self.1.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        requests.retain(|request| !Arc::ptr_eq(request, &self.0));
+        drop(requests);
+        if self.0.flagged.load(Ordering::Relaxed) {
+            info!(
+                method = %self.0.method,
+                route = %self.0.route,
+                trace_id = self.0.trace_id().as_deref().unwrap_or_default(),
+                duration_ms = self.0.started.elapsed().as_millis() as u64,
+                "Slow request completed"
+            );
+        }
+    }
+}
+
+/// Middleware listing requests for the `AppMetrics::slow_requests` watchdog,
+/// if any. Runs inside `track_requests`, so the server span is current.
+pub async fn watch(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let slow_requests = req.app_data::<web::Data<AppMetrics>>().and_then(|metrics| metrics.slow_requests.clone());
+    let Some(slow_requests) = slow_requests else {
+        return next.call(req).await;
+    };
+    let _registration = slow_requests.register(&req);
+    next.call(req).await
+}
@@ -0,0 +1,119 @@
+//! Traces across queues.
+//!
+//! Work handed to a channel or a background loop outlives the request that
+//! enqueued it, so processing it as a child of the request's span would make
+//! that span's trace as long as the queue. Instead `Origin::capture` takes
+//! the span context when the work is enqueued, and `in_linked_span` later
+//! processes it in a new root span with a link back to it:
+//!
+//! ```ignore
+//! let (jobs, mut queue) = tokio::sync::mpsc::channel(64);
+//!
+//! // In the handler:
+//! jobs.send(Linked::new(job)).await?;
+//!
+//! // In the background loop:
+//! while let Some(Linked { item, origin }) = queue.recv().await {
+//!     span_links::in_linked_span("process_job", [&origin], process(item)).await;
+//! }
+//! ```
+//!
+//! A batch of items is processed in one span linked to each of their
+//! origins, which is how one trace fans in from many. Items enqueued outside
+//! a span have no origin to link to. Without the `traces` feature the origin
+//! is empty and the work runs unchanged.
+
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, Link, SpanContext, SpanKind, TraceContextExt, Tracer},
+    Context,
+};
+use std::future::Future;
+
+/// The span current when work was enqueued.
+#[derive(Clone, Debug, Default)]
+pub struct Origin {
+    #[cfg(feature = "traces")]
+    context: Option<SpanContext>,
+}
+
+impl Origin {
+    /// The active span's context, if there is one.
+    pub fn capture() -> Self {
+        #[cfg(feature = "traces")]
+        {
+            let cx = Context::current();
+            let context = cx.span().span_context().clone();
+            Self { context: context.is_valid().then_some(context) }
+        }
+        #[cfg(not(feature = "traces"))]
+        Self {}
+    }
+
+    /// The trace of the enqueuing span, as hex.
+    pub fn trace_id(&self) -> Option<String> {
+        #[cfg(feature = "traces")]
+        return self.context.as_ref().map(|context| context.trace_id().to_string());
+        #[cfg(not(feature = "traces"))]
+        None
+    }
+}
+
+/// A queued item with the `Origin` of its enqueuing.
+#[derive(Clone, Debug)]
+pub struct Linked<T> {
+    pub item: T,
+    pub origin: Origin,
+}
+
+impl<T> Linked<T> {
+    /// Wraps `item`, capturing the active span.
+    pub fn new(item: T) -> Self {
+        Self { item, origin: Origin::capture() }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.item
+    }
+}
+
+/// Starts a root span `name` linked to each of `origins` and returns the
+/// context holding it; whoever ends up with the context ends the span, with
+/// `cx.span().end()` or by dropping the last clone. Origins without a span
+/// are skipped.
+#[cfg(feature = "traces")]
+pub fn start_linked<'a>(name: &'static str, origins: impl IntoIterator<Item = &'a Origin>) -> Context {
+    let links: Vec<Link> =
+        origins.into_iter().filter_map(|origin| origin.context.clone()).map(Link::with_context).collect();
+    let tracer = global::tracer("prom_otel");
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Internal)
+        .with_links(links)
+        .start_with_context(&tracer, &Context::new());
+    Context::current_with_span(span)
+}
+
+/// Runs `future` in a root span `name` linked to `origins`, ended when the
+/// future completes; see the module documentation.
+pub fn in_linked_span<'a, F: Future>(
+    name: &'static str,
+    origins: impl IntoIterator<Item = &'a Origin>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "traces")]
+    {
+        let cx = start_linked(name, origins);
+        async move {
+            let output = future.with_context(cx.clone()).await;
+            cx.span().end();
+            output
+        }
+    }
+    #[cfg(not(feature = "traces"))]
+    {
+        let _ = (name, origins);
+        future
+    }
+}
@@ -0,0 +1,108 @@
+//! RED metrics derived from spans (`SPAN_METRICS`).
+//!
+//! Code instrumented only with spans (client calls, consumers, libraries
+//! with their own tracing) otherwise has no rate, error or duration metrics
+//! until a collector's spanmetrics connector derives them. With
+//! `SPAN_METRICS` set, `SpanMetricsProcessor` does so in-process for every
+//! server and client span as it ends:
+//!
+//! - `traces_span_metrics_calls_total{span_name,span_kind,status_code}`
+//! - `traces_span_metrics_duration_seconds{span_name,span_kind,status_code}`
+//!
+//! Names and label values (`SPAN_KIND_SERVER`, `STATUS_CODE_ERROR`, ...)
+//! follow the connector's Prometheus output, so its dashboards work
+//! unchanged; the service is identified by the scrape target instead of a
+//! `service_name` label. Span names go through the cardinality limiter.
+//!
+//! Only recorded spans reach span processors, so with a sampling ratio
+//! below 1 the counts cover the sampled spans only.
+
+use crate::{cardinality::CardinalityLimiter, registry::Registry};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{SpanKind, Status},
+    Context,
+};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    trace::{Span, SpanData, SpanProcessor},
+};
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
+#[cfg(feature = "traces")]
+use std::time::Duration;
+
+const LABELS: [&str; 3] = ["span_name", "span_kind", "status_code"];
+
+/// The span-derived metrics, registered when `SPAN_METRICS` is set.
+#[derive(Clone, Debug)]
+pub struct SpanMetrics {
+    pub calls: IntCounterVec,
+    pub duration: HistogramVec,
+}
+
+impl SpanMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let calls = IntCounterVec::new(
+            Opts::new("traces_span_metrics_calls_total", "Ended server and client spans"),
+            &LABELS,
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new("traces_span_metrics_duration_seconds", "Duration of server and client spans"),
+            &LABELS,
+        )?;
+        registry.register(Box::new(calls.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        Ok(Self { calls, duration })
+    }
+
+    /// Counts one span and observes its duration.
+    pub fn observe(&self, cardinality: &CardinalityLimiter, name: &str, kind: &str, status: &str, seconds: f64) {
+        let labels = cardinality.limit("traces_span_metrics_calls_total", [name, kind, status]);
+        self.calls.with_label_values(&labels).inc();
+        self.duration.with_label_values(&labels).observe(seconds);
+    }
+}
+
+/// Records `SpanMetrics` for server and client spans as they end.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct SpanMetricsProcessor {
+    metrics: SpanMetrics,
+    cardinality: CardinalityLimiter,
+}
+
+#[cfg(feature = "traces")]
+impl SpanMetricsProcessor {
+    pub fn new(metrics: SpanMetrics, cardinality: CardinalityLimiter) -> Self {
+        Self { metrics, cardinality }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl SpanProcessor for SpanMetricsProcessor {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        let kind = match span.span_kind {
+            SpanKind::Server => "SPAN_KIND_SERVER",
+            SpanKind::Client => "SPAN_KIND_CLIENT",
+            _ => return,
+        };
+        let status = match span.status {
+            Status::Unset => "STATUS_CODE_UNSET",
+            Status::Ok => "STATUS_CODE_OK",
+            Status::Error { .. } => "STATUS_CODE_ERROR",
+        };
+        let seconds = span.end_time.duration_since(span.start_time).unwrap_or_default().as_secs_f64();
+        self.metrics.observe(&self.cardinality, &span.name, kind, status, seconds);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
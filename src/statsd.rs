@@ -0,0 +1,420 @@
+//! StatsD/DogStatsD ingestion.
+//!
+//! With `STATSD_ADDR` set, a UDP socket accepts StatsD lines
+//! (`name:value|type[|@rate][|#tag:value,...]`, several per packet separated
+//! by newlines) from processes that can't expose metrics themselves. They are
+//! aggregated into families served on `/metrics` with the local ones and,
+//! with the `metrics` feature, recorded on OTel instruments of the meter
+//! `prom_otel.statsd`:
+//!
+//! - counters (`c`) are summed, divided by their sample rate;
+//! - gauges (`g`) keep the last value, or move by it when it is signed;
+//! - timers (`ms`, converted to seconds), histograms (`h`) and distributions
+//!   (`d`) are observed on histograms with the default Prometheus buckets.
+//!
+//! Names and tag keys are sanitized into Prometheus names (`api.latency`
+//! becomes `api_latency`) and DogStatsD tags become labels; tags without a
+//! value are ignored; tags named like one of the constant labels
+//! (`METRICS_RESOURCE_LABELS`) are renamed `exported_<name>`. Sets, events
+//! and service checks aren't supported. A sample is dropped, and counted in
+//! `statsd_samples_dropped_total{reason}`, when it is malformed, unsupported,
+//! of another type than earlier samples of its name or named like a local
+//! family (`type_conflict`), or a new series (`cardinality`). Counter
+//! samples can't be negative, as a counter going down reads as a reset. Any
+//! sender can make up names, so both the families and the series of each
+//! family are capped at `METRICS_MAX_LABEL_SETS`; a sample of a new family
+//! past that is dropped as `cardinality` too.
+
+use crate::registry::Registry;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Bucket, LabelPair, Metric, MetricFamily, MetricType},
+    IntCounter, IntCounterVec, Opts, DEFAULT_BUCKETS,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tokio::net::UdpSocket;
+
+/// Label names and values of a series, sorted by name.
+type Labels = Vec<(String, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// One value of a line, already scaled (timers are in seconds).
+#[derive(Clone, Debug, PartialEq)]
+struct Sample {
+    name: String,
+    kind: Kind,
+    value: f64,
+    /// Whether a gauge value is a delta (`+1`, `-1`).
+    relative: bool,
+    rate: f64,
+    labels: Labels,
+}
+
+#[derive(Clone, Debug)]
+enum Series {
+    Value(f64),
+    Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+#[derive(Debug)]
+struct Family {
+    kind: Kind,
+    series: HashMap<Labels, Series>,
+}
+
+/// Why a sample was dropped, the `reason` label of
+/// `statsd_samples_dropped_total`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rejection {
+    Malformed,
+    Unsupported,
+    TypeConflict,
+    Cardinality,
+}
+
+impl Rejection {
+    fn as_str(self) -> &'static str {
+        match self {
+            Rejection::Malformed => "malformed",
+            Rejection::Unsupported => "unsupported",
+            Rejection::TypeConflict => "type_conflict",
+            Rejection::Cardinality => "cardinality",
+        }
+    }
+}
+
+/// The aggregated StatsD families, registered as a collector of the local
+/// registry.
+#[derive(Clone, Debug)]
+pub struct Statsd {
+    desc: Desc,
+    families: Arc<RwLock<HashMap<String, Family>>>,
+    max_series: usize,
+    /// The constant labels of the registry.
+    reserved: Arc<Vec<String>>,
+    /// The local registry, whose family names StatsD can't take.
+    registry: Registry,
+    samples: IntCounter,
+    dropped: IntCounterVec,
+    #[cfg(feature = "metrics")]
+    instruments: Arc<RwLock<HashMap<String, Instrument>>>,
+}
+
+impl Statsd {
+    /// Registers the StatsD families and the ingestion metrics in `registry`,
+    /// whose constant labels are `const_labels`, keeping at most `max_series`
+    /// families and as many series per family.
+    pub fn new(
+        registry: &Registry,
+        const_labels: impl IntoIterator<Item = String>,
+        max_series: usize,
+    ) -> prometheus::Result<Self> {
+        let samples = IntCounter::new("statsd_samples_total", "StatsD samples received")?;
+        let dropped = IntCounterVec::new(
+            Opts::new("statsd_samples_dropped_total", "StatsD samples that were not recorded"),
+            &["reason"],
+        )?;
+        let statsd = Self {
+            desc: Desc::new(
+                "statsd".to_string(),
+                "Families aggregated from StatsD samples".to_string(),
+                Vec::new(),
+                HashMap::new(),
+            )?,
+            families: Arc::default(),
+            max_series,
+            reserved: Arc::new(const_labels.into_iter().collect()),
+            registry: registry.clone(),
+            samples,
+            dropped,
+            #[cfg(feature = "metrics")]
+            instruments: Arc::default(),
+        };
+        registry.register(Box::new(statsd.samples.clone()))?;
+        registry.register(Box::new(statsd.dropped.clone()))?;
+        registry.register(Box::new(statsd.clone()))?;
+        Ok(statsd)
+    }
+
+    /// Records every line of a packet.
+    pub fn ingest(&self, packet: &str) {
+        for line in packet.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match parse_line(line) {
+                Ok(samples) => {
+                    for sample in samples {
+                        self.samples.inc();
+                        if let Err(reason) = self.record(&sample) {
+                            self.dropped.with_label_values(&[reason.as_str()]).inc();
+                        }
+                    }
+                }
+                Err(reason) => {
+                    tracing::debug!(line, reason = reason.as_str(), "Dropped StatsD line");
+                    self.samples.inc();
+                    self.dropped.with_label_values(&[reason.as_str()]).inc();
+                }
+            }
+        }
+    }
+
+    fn record(&self, sample: &Sample) -> Result<(), Rejection> {
+        // Served next to the local family, the samples would be merged into
+        // it whatever their type and labels.
+        if self.registry.family_type(&sample.name).is_some() {
+            return Err(Rejection::TypeConflict);
+        }
+        let mut families = self.families.write().unwrap();
+        if !families.contains_key(&sample.name) && families.len() >= self.max_series {
+            return Err(Rejection::Cardinality);
+        }
+        let family = families
+            .entry(sample.name.clone())
+            .or_insert_with(|| Family { kind: sample.kind, series: HashMap::new() });
+        if family.kind != sample.kind {
+            return Err(Rejection::TypeConflict);
+        }
+        if !family.series.contains_key(&sample.labels) && family.series.len() >= self.max_series {
+            return Err(Rejection::Cardinality);
+        }
+        // Observations a sampled timer or histogram stands for.
+        let times = (1.0 / sample.rate).round().max(1.0) as u64;
+        let series = family.series.entry(sample.labels.clone()).or_insert_with(|| match sample.kind {
+            Kind::Histogram => Series::Histogram { buckets: vec![0; DEFAULT_BUCKETS.len()], sum: 0.0, count: 0 },
+            _ => Series::Value(0.0),
+        });
+        match (sample.kind, series) {
+            (Kind::Counter, Series::Value(value)) => *value += sample.value / sample.rate,
+            (Kind::Gauge, Series::Value(value)) if sample.relative => *value += sample.value,
+            (Kind::Gauge, Series::Value(value)) => *value = sample.value,
+            (_, Series::Histogram { buckets, sum, count }) => {
+                for (bucket, bound) in buckets.iter_mut().zip(DEFAULT_BUCKETS) {
+                    if sample.value <= *bound {
+                        *bucket += times;
+                    }
+                }
+                *sum += sample.value * times as f64;
+                *count += times;
+            }
+            (_, Series::Value(_)) => unreachable!("histogram families only have histogram series"),
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let gauge = match family.series.get(&sample.labels) {
+                Some(Series::Value(value)) => *value,
+                _ => sample.value,
+            };
+            drop(families);
+            self.record_otel(sample, gauge, times);
+        }
+        Ok(())
+    }
+
+    /// Records `sample` on its OTel instrument; `gauge` is the new value of a
+    /// gauge.
+    #[cfg(feature = "metrics")]
+    fn record_otel(&self, sample: &Sample, gauge: f64, times: u64) {
+        let attributes: Vec<opentelemetry::KeyValue> = sample
+            .labels
+            .iter()
+            .map(|(name, value)| opentelemetry::KeyValue::new(name.clone(), value.clone()))
+            .collect();
+        if let Some(instrument) = self.instruments.read().unwrap().get(&sample.name) {
+            instrument.record(sample, gauge, times, &attributes);
+            return;
+        }
+        let mut instruments = self.instruments.write().unwrap();
+        let instrument = instruments.entry(sample.name.clone()).or_insert_with(|| Instrument::new(sample));
+        instrument.record(sample, gauge, times, &attributes);
+    }
+}
+
+impl Collector for Statsd {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let families = self.families.read().unwrap();
+        families
+            .iter()
+            .map(|(name, family)| {
+                let mut out = MetricFamily::default();
+                out.set_name(name.clone());
+                out.set_help(format!("StatsD metric {name}"));
+                out.set_field_type(match family.kind {
+                    Kind::Counter => MetricType::COUNTER,
+                    Kind::Gauge => MetricType::GAUGE,
+                    Kind::Histogram => MetricType::HISTOGRAM,
+                });
+                for (labels, series) in &family.series {
+                    let mut metric = Metric::default();
+                    metric.set_label(
+                        labels
+                            .iter()
+                            .map(|(name, value)| {
+                                if self.reserved.contains(name) {
+                                    label(&format!("exported_{name}"), value)
+                                } else {
+                                    label(name, value)
+                                }
+                            })
+                            .collect(),
+                    );
+                    match (family.kind, series) {
+                        (Kind::Counter, Series::Value(value)) => metric.counter.mut_or_insert_default().set_value(*value),
+                        (_, Series::Value(value)) => metric.gauge.mut_or_insert_default().set_value(*value),
+                        (_, Series::Histogram { buckets, sum, count }) => {
+                            let histogram = metric.histogram.mut_or_insert_default();
+                            for (cumulative, bound) in buckets.iter().zip(DEFAULT_BUCKETS) {
+                                let mut bucket = Bucket::default();
+                                bucket.set_upper_bound(*bound);
+                                bucket.set_cumulative_count(*cumulative);
+                                histogram.bucket.push(bucket);
+                            }
+                            histogram.set_sample_sum(*sum);
+                            histogram.set_sample_count(*count);
+                        }
+                    }
+                    out.mut_metric().push(metric);
+                }
+                out
+            })
+            .collect()
+    }
+}
+
+fn label(name: &str, value: &str) -> LabelPair {
+    let mut pair = LabelPair::default();
+    pair.set_name(name.to_string());
+    pair.set_value(value.to_string());
+    pair
+}
+
+/// The OTel instrument of a StatsD name.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+enum Instrument {
+    Counter(opentelemetry::metrics::Counter<f64>),
+    Gauge(opentelemetry::metrics::Gauge<f64>),
+    Histogram(opentelemetry::metrics::Histogram<f64>),
+}
+
+#[cfg(feature = "metrics")]
+impl Instrument {
+    fn new(sample: &Sample) -> Self {
+        let meter = opentelemetry::global::meter("prom_otel.statsd");
+        let name = sample.name.clone();
+        match sample.kind {
+            Kind::Counter => Instrument::Counter(meter.f64_counter(name).build()),
+            Kind::Gauge => Instrument::Gauge(meter.f64_gauge(name).build()),
+            Kind::Histogram => {
+                Instrument::Histogram(meter.f64_histogram(name).with_boundaries(DEFAULT_BUCKETS.to_vec()).build())
+            }
+        }
+    }
+
+    fn record(&self, sample: &Sample, gauge: f64, times: u64, attributes: &[opentelemetry::KeyValue]) {
+        match self {
+            Instrument::Counter(counter) => counter.add(sample.value / sample.rate, attributes),
+            Instrument::Gauge(instrument) => instrument.record(gauge, attributes),
+            Instrument::Histogram(histogram) => {
+                for _ in 0..times {
+                    histogram.record(sample.value, attributes);
+                }
+            }
+        }
+    }
+}
+
+/// Parses `name:value[:value...]|type[|@rate][|#tags]` into one sample per
+/// value.
+fn parse_line(line: &str) -> Result<Vec<Sample>, Rejection> {
+    if line.starts_with("_e{") || line.starts_with("_sc|") {
+        return Err(Rejection::Unsupported);
+    }
+    let (name, rest) = line.split_once(':').ok_or(Rejection::Malformed)?;
+    let mut fields = rest.split('|');
+    let values = fields.next().ok_or(Rejection::Malformed)?;
+    let (kind, scale) = match fields.next() {
+        Some("c") => (Kind::Counter, 1.0),
+        Some("g") => (Kind::Gauge, 1.0),
+        Some("ms") => (Kind::Histogram, 0.001),
+        Some("h" | "d") => (Kind::Histogram, 1.0),
+        Some("s") => return Err(Rejection::Unsupported),
+        _ => return Err(Rejection::Malformed),
+    };
+    let name = sanitize(name.trim(), true).ok_or(Rejection::Malformed)?;
+    let mut rate = 1.0;
+    let mut labels = Labels::new();
+    for field in fields {
+        if let Some(value) = field.strip_prefix('@') {
+            rate = value.parse::<f64>().map_err(|_| Rejection::Malformed)?;
+            if !(rate > 0.0 && rate <= 1.0) {
+                return Err(Rejection::Malformed);
+            }
+        } else if let Some(tags) = field.strip_prefix('#') {
+            for (key, value) in tags.split(',').filter_map(|tag| tag.split_once(':')) {
+                if let Some(key) = sanitize(key.trim(), false) {
+                    labels.retain(|(name, _)| *name != key);
+                    labels.push((key, value.trim().to_string()));
+                }
+            }
+        }
+        // Other DogStatsD fields (`c:` container ID, `T` timestamp) are ignored.
+    }
+    labels.sort();
+    values
+        .split(':')
+        .map(|value| {
+            let value = value.trim();
+            let parsed = value.parse::<f64>().map_err(|_| Rejection::Malformed)?;
+            if !parsed.is_finite() || (kind == Kind::Counter && parsed < 0.0) {
+                return Err(Rejection::Malformed);
+            }
+            Ok(Sample {
+                name: name.clone(),
+                kind,
+                value: parsed * scale,
+                relative: kind == Kind::Gauge && (value.starts_with('+') || value.starts_with('-')),
+                rate,
+                labels: labels.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Replaces the characters Prometheus doesn't allow in a metric (`colon`) or
+/// label name with `_`.
+fn sanitize(name: &str, colon: bool) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+    let mut out: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || (colon && c == ':') { c } else { '_' })
+        .collect();
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    Some(out)
+}
+
+/// Receives packets on `socket` until the process exits.
+pub async fn listen(statsd: Statsd, socket: UdpSocket) {
+    let mut buf = vec![0; 65535];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(len) => statsd.ingest(&String::from_utf8_lossy(&buf[..len])),
+            Err(e) => tracing::warn!(error = %e, "Cannot receive a StatsD packet"),
+        }
+    }
+}
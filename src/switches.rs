@@ -0,0 +1,189 @@
+//! Runtime switches for the OTLP export of traces and logs.
+//!
+//! During an incident the telemetry itself can be what overloads the
+//! collector. `PUT /admin/telemetry` turns span or log export off (and back
+//! on) and changes the trace sampling ratio without a restart:
+//!
+//! ```json
+//! {"traces_export": false, "logs_export": true, "sample_ratio": 0.01}
+//! ```
+//!
+//! Every key is optional; `"sample_ratio": null` goes back to the configured
+//! sampler. While a signal is switched off, the batches that reach its
+//! exporter are dropped instead of sent: spans are still created and
+//! propagated, and logs still go to stdout and `LOG_FILE`. The sample ratio
+//! is the one `telemetry.trace-sample-ratio` sets (see `flags`), so whichever
+//! changed it last wins.
+
+use crate::flags::SampleRatio;
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{LogBatch, LogExporter};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use opentelemetry_sdk::{error::OTelSdkResult, Resource};
+use serde_json::{json, Value};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use std::time::Duration;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Whether spans and logs are exported, shared by the exporters and the
+/// admin API. Both are on until switched off.
+#[derive(Clone, Debug)]
+pub struct TelemetrySwitches {
+    traces: Arc<AtomicBool>,
+    logs: Arc<AtomicBool>,
+}
+
+impl Default for TelemetrySwitches {
+    fn default() -> Self {
+        Self { traces: Arc::new(AtomicBool::new(true)), logs: Arc::new(AtomicBool::new(true)) }
+    }
+}
+
+impl TelemetrySwitches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn traces_export(&self) -> bool {
+        self.traces.load(Ordering::Relaxed)
+    }
+
+    pub fn set_traces_export(&self, enabled: bool) {
+        self.traces.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn logs_export(&self) -> bool {
+        self.logs.load(Ordering::Relaxed)
+    }
+
+    pub fn set_logs_export(&self, enabled: bool) {
+        self.logs.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// A change requested through `PUT /admin/telemetry`; `None` leaves a
+/// setting as it is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SwitchesUpdate {
+    pub traces_export: Option<bool>,
+    pub logs_export: Option<bool>,
+    /// `Some(None)` goes back to the configured sampler.
+    pub sample_ratio: Option<Option<f64>>,
+}
+
+impl SwitchesUpdate {
+    /// Parses the body described in the module documentation. Unknown keys
+    /// are rejected so a typo doesn't pass for a no-op.
+    pub fn from_json(body: &[u8]) -> Result<Self, String> {
+        let body: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {e}"))?;
+        let object = body.as_object().ok_or("expected a JSON object")?;
+        let mut update = Self::default();
+        for (key, value) in object {
+            match key.as_str() {
+                "traces_export" => {
+                    update.traces_export = Some(value.as_bool().ok_or("traces_export must be a boolean")?);
+                }
+                "logs_export" => {
+                    update.logs_export = Some(value.as_bool().ok_or("logs_export must be a boolean")?);
+                }
+                "sample_ratio" => {
+                    update.sample_ratio = Some(match value {
+                        Value::Null => None,
+                        value => Some(
+                            value
+                                .as_f64()
+                                .filter(|ratio| (0.0..=1.0).contains(ratio))
+                                .ok_or_else(|| format!("sample_ratio must be a number between 0 and 1, got {value}"))?,
+                        ),
+                    });
+                }
+                key => return Err(format!("unknown setting {key:?}")),
+            }
+        }
+        Ok(update)
+    }
+}
+
+/// The current state, as returned by the admin API.
+pub fn state_json(switches: &TelemetrySwitches, sample_ratio: &SampleRatio) -> Value {
+    json!({
+        "traces_export": switches.traces_export(),
+        "logs_export": switches.logs_export(),
+        "sample_ratio": sample_ratio.get(),
+    })
+}
+
+/// Drops span batches while trace export is switched off.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct SwitchedSpanExporter<E> {
+    inner: E,
+    switches: TelemetrySwitches,
+}
+
+#[cfg(feature = "traces")]
+impl<E> SwitchedSpanExporter<E> {
+    pub fn new(inner: E, switches: TelemetrySwitches) -> Self {
+        Self { inner, switches }
+    }
+}
+
+#[cfg(feature = "traces")]
+impl<E: SpanExporter> SpanExporter for SwitchedSpanExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        if !self.switches.traces_export() {
+            return Ok(());
+        }
+        self.inner.export(batch).await
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Drops log batches while log export is switched off.
+#[cfg(feature = "logs")]
+#[derive(Debug)]
+pub struct SwitchedLogExporter<E> {
+    inner: E,
+    switches: TelemetrySwitches,
+}
+
+#[cfg(feature = "logs")]
+impl<E> SwitchedLogExporter<E> {
+    pub fn new(inner: E, switches: TelemetrySwitches) -> Self {
+        Self { inner, switches }
+    }
+}
+
+#[cfg(feature = "logs")]
+impl<E: LogExporter> LogExporter for SwitchedLogExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        if !self.switches.logs_export() {
+            return Ok(());
+        }
+        self.inner.export(batch).await
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
@@ -0,0 +1,310 @@
+//! Process and host CPU, memory and I/O sampling via sysinfo, completed by
+//! the backend of the platform (see `platform`).
+
+use crate::{
+    cgroup::Cgroup,
+    memory_pressure::MemoryPressure,
+    metrics::AppMetrics,
+    platform::{Platform, ProcessInfo},
+    registry::Registry,
+};
+use prometheus::{Counter, Gauge, GaugeVec, IntCounter, IntCounterVec, Opts};
+use std::{sync::Arc, time::Duration};
+use sysinfo::{Networks, ProcessesToUpdate, System,  get_current_pid};
+use tokio::sync::watch;
+
+/// Network and disk I/O counters, kept in step with the totals sysinfo
+/// reports on every sample.
+#[derive(Clone, Debug)]
+pub struct IoMetrics {
+    pub network_received_bytes: IntCounterVec,
+    pub network_transmitted_bytes: IntCounterVec,
+    pub network_received_packets: IntCounterVec,
+    pub network_transmitted_packets: IntCounterVec,
+    pub disk_read_bytes: IntCounter,
+    pub disk_written_bytes: IntCounter,
+}
+
+impl IoMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let labels = &["interface"];
+        let network_received_bytes = IntCounterVec::new(
+            Opts::new("network_receive_bytes_total", "Bytes received per network interface"),
+            labels,
+        )?;
+        let network_transmitted_bytes = IntCounterVec::new(
+            Opts::new("network_transmit_bytes_total", "Bytes sent per network interface"),
+            labels,
+        )?;
+        let network_received_packets = IntCounterVec::new(
+            Opts::new("network_receive_packets_total", "Packets received per network interface"),
+            labels,
+        )?;
+        let network_transmitted_packets = IntCounterVec::new(
+            Opts::new("network_transmit_packets_total", "Packets sent per network interface"),
+            labels,
+        )?;
+        let disk_read_bytes = IntCounter::new("app_disk_read_bytes_total", "Bytes the app read from disk")?;
+        let disk_written_bytes = IntCounter::new("app_disk_written_bytes_total", "Bytes the app wrote to disk")?;
+
+        registry.register(Box::new(network_received_bytes.clone()))?;
+        registry.register(Box::new(network_transmitted_bytes.clone()))?;
+        registry.register(Box::new(network_received_packets.clone()))?;
+        registry.register(Box::new(network_transmitted_packets.clone()))?;
+        registry.register(Box::new(disk_read_bytes.clone()))?;
+        registry.register(Box::new(disk_written_bytes.clone()))?;
+
+        Ok(Self {
+            network_received_bytes,
+            network_transmitted_bytes,
+            network_received_packets,
+            network_transmitted_packets,
+            disk_read_bytes,
+            disk_written_bytes,
+        })
+    }
+}
+
+/// The host as a whole, for nodes without node_exporter: per-core CPU
+/// utilization, load averages, CPU steal time and system memory.
+#[derive(Clone, Debug)]
+pub struct HostMetrics {
+    pub cpu_utilization: GaugeVec,
+    /// `None` on Windows, which has no load average; its processor queue
+    /// length is exported instead, see `platform`.
+    pub load_average: Option<GaugeVec>,
+    /// Time the hypervisor ran other guests while this one wanted to run;
+    /// `None` outside Linux.
+    pub cpu_steal_seconds: Option<Counter>,
+    pub memory_total_bytes: Gauge,
+    pub memory_available_bytes: Gauge,
+}
+
+impl HostMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let cpu_utilization = GaugeVec::new(
+            Opts::new("system_cpu_utilization_ratio", "Utilization of each CPU core of the host, from 0 to 1"),
+            &["core"],
+        )?;
+        let load_average = Platform::has_load_average()
+            .then(|| {
+                GaugeVec::new(
+                    Opts::new("system_load_average", "Load average of the host over the period"),
+                    &["period"],
+                )
+            })
+            .transpose()?;
+        let cpu_steal_seconds = Platform::has_cpu_steal()
+            .then(|| Counter::new("system_cpu_steal_seconds_total", "CPU time stolen by the hypervisor, summed over cores"))
+            .transpose()?;
+        let memory_total_bytes = Gauge::new("system_memory_total_bytes", "Physical memory of the host")?;
+        let memory_available_bytes =
+            Gauge::new("system_memory_available_bytes", "Memory of the host available to new allocations")?;
+
+        registry.register(Box::new(cpu_utilization.clone()))?;
+        if let Some(load_average) = &load_average {
+            registry.register(Box::new(load_average.clone()))?;
+        }
+        if let Some(steal) = &cpu_steal_seconds {
+            registry.register(Box::new(steal.clone()))?;
+        }
+        registry.register(Box::new(memory_total_bytes.clone()))?;
+        registry.register(Box::new(memory_available_bytes.clone()))?;
+
+        Ok(Self { cpu_utilization, load_average, cpu_steal_seconds, memory_total_bytes, memory_available_bytes })
+    }
+
+    fn sample(&self, sys: &System, platform: &mut Platform) {
+        for (core, cpu) in sys.cpus().iter().enumerate() {
+            self.cpu_utilization.with_label_values(&[&core.to_string()]).set(cpu.cpu_usage() as f64 / 100.0);
+        }
+        if let Some(load_average) = &self.load_average {
+            let load = System::load_average();
+            load_average.with_label_values(&["1m"]).set(load.one);
+            load_average.with_label_values(&["5m"]).set(load.five);
+            load_average.with_label_values(&["15m"]).set(load.fifteen);
+        }
+        if let (Some(counter), Some(total)) = (&self.cpu_steal_seconds, platform.cpu_steal_seconds()) {
+            let current = counter.get();
+            if total > current {
+                counter.inc_by(total - current);
+            }
+        }
+        self.memory_total_bytes.set(sys.total_memory() as f64);
+        self.memory_available_bytes.set(sys.available_memory() as f64);
+    }
+}
+
+/// Advances `counter` to `total`. Totals that went backwards (an interface
+/// that was recreated) are ignored until they catch up again.
+pub(crate) fn advance(counter: &IntCounter, total: u64) {
+    let current = counter.get();
+    if total > current {
+        counter.inc_by(total - current);
+    }
+}
+
+/// The series only some platforms can fill: stand-ins for the
+/// `process_threads`, `process_open_fds` and `process_max_fds` gauges of
+/// prometheus' `ProcessCollector`, which only exists on Linux, the page
+/// faults and the Windows processor queue. Each is registered the first time
+/// the platform backend or sysinfo can fill it, so platforms that can't
+/// don't export a misleading zero.
+#[derive(Default)]
+struct ProcessGauges {
+    #[cfg(not(target_os = "linux"))]
+    threads: Option<Gauge>,
+    #[cfg(not(target_os = "linux"))]
+    open_fds: Option<Gauge>,
+    #[cfg(not(target_os = "linux"))]
+    max_fds: Option<Gauge>,
+    page_faults: Option<IntCounter>,
+    processor_queue: Option<Gauge>,
+}
+
+impl ProcessGauges {
+    fn sample(&mut self, registry: &Registry, info: &ProcessInfo, processor_queue: Option<f64>) {
+        #[cfg(not(target_os = "linux"))]
+        {
+            let help = "Number of OS threads in the process.";
+            set_or_register(&mut self.threads, registry, "process_threads", help, info.threads.map(|n| n as f64));
+            let help = "Number of open file descriptors.";
+            set_or_register(&mut self.open_fds, registry, "process_open_fds", help, info.open_fds.map(|n| n as f64));
+            let help = "Maximum number of open file descriptors.";
+            set_or_register(&mut self.max_fds, registry, "process_max_fds", help, info.max_fds.map(|n| n as f64));
+        }
+        if let Some(faults) = info.page_faults {
+            if self.page_faults.is_none() {
+                let counter = IntCounter::new(
+                    "process_page_faults_total",
+                    "Page faults of the process (major ones only on Linux)",
+                )
+                .unwrap();
+                if registry.register(Box::new(counter.clone())).is_ok() {
+                    self.page_faults = Some(counter);
+                }
+            }
+            if let Some(counter) = &self.page_faults {
+                advance(counter, faults);
+            }
+        }
+        let help = "Threads ready to run waiting for a processor (Windows)";
+        set_or_register(&mut self.processor_queue, registry, "system_processor_queue_length", help, processor_queue);
+    }
+}
+
+fn set_or_register(gauge: &mut Option<Gauge>, registry: &Registry, name: &str, help: &str, value: Option<f64>) {
+    let Some(value) = value else { return };
+    if gauge.is_none() {
+        let new = Gauge::new(name, help).unwrap();
+        if registry.register(Box::new(new.clone())).is_ok() {
+            *gauge = Some(new);
+        }
+    }
+    if let Some(gauge) = gauge {
+        gauge.set(value);
+    }
+}
+
+/// Reads CPU, memory and disk usage of the current process, those of the
+/// host and the network interface counters from sysinfo, and the container limits from
+/// the cgroup filesystem. Thread and file descriptor
+/// counts come from `ProcessCollector` on Linux and from the platform
+/// backend, or else sysinfo, elsewhere.
+pub struct SystemSampler {
+    sys: System,
+    networks: Networks,
+    pid: sysinfo::Pid,
+    cgroup: Option<Cgroup>,
+    platform: Platform,
+    process_gauges: ProcessGauges,
+}
+
+impl SystemSampler {
+    pub fn new() -> Self {
+        Self {
+            sys: System::new_all(),
+            networks: Networks::new_with_refreshed_list(),
+            pid: get_current_pid().unwrap(),
+            cgroup: Cgroup::detect(),
+            platform: Platform::new(),
+            process_gauges: ProcessGauges::default(),
+        }
+    }
+    
+    pub fn sample(&mut self, metrics: &AppMetrics) {
+        self.sys.refresh_processes(ProcessesToUpdate::Some(&[self.pid]), true);
+        self.sys.refresh_cpu_all();
+        self.sys.refresh_memory();
+        
+        #[cfg_attr(target_os = "linux", allow(unused_mut))]
+        let mut info = self.platform.process();
+        if let Some(proc) = self.sys.process(self.pid) {
+            metrics.memory_gauge.set(proc.memory() as f64 / 1048576.0); // Bytes → Mb
+            // The platform's peak also catches spikes between samples.
+            let memory = info.peak_resident_bytes.unwrap_or(0).max(proc.memory()) as f64;
+            if memory > metrics.memory_peak_gauge.get() {
+                metrics.memory_peak_gauge.set(memory);
+            }
+            metrics.cpu_gauge.set(proc.cpu_usage() as f64);
+            let disk = proc.disk_usage();
+            advance(&metrics.io.disk_read_bytes, disk.total_read_bytes);
+            advance(&metrics.io.disk_written_bytes, disk.total_written_bytes);
+            #[cfg(not(target_os = "linux"))]
+            {
+                info.threads = info.threads.or(proc.tasks().map(|tasks| tasks.len() as u64));
+                info.open_fds = info.open_fds.or(proc.open_files().map(|n| n as u64));
+                info.max_fds = info.max_fds.or(proc.open_files_limit().map(|n| n as u64));
+            }
+        }
+        let processor_queue = self.platform.processor_queue_length();
+        self.process_gauges.sample(&metrics.registry, &info, processor_queue);
+        metrics.host.sample(&self.sys, &mut self.platform);
+
+        if let (Some(cgroup), Some(cgroup_metrics)) = (&self.cgroup, &metrics.cgroup) {
+            cgroup_metrics.set(&cgroup.read());
+        }
+        #[cfg(feature = "jemalloc")]
+        metrics.allocator.sample();
+
+        self.networks.refresh(true);
+        for (interface, data) in &self.networks {
+            let io = &metrics.io;
+            advance(&io.network_received_bytes.with_label_values(&[interface]), data.total_received());
+            advance(&io.network_transmitted_bytes.with_label_values(&[interface]), data.total_transmitted());
+            advance(&io.network_received_packets.with_label_values(&[interface]), data.total_packets_received());
+            advance(
+                &io.network_transmitted_packets.with_label_values(&[interface]),
+                data.total_packets_transmitted(),
+            );
+        }
+    }
+}
+
+/// Samples every `interval`, except while `pressure` pauses the sampler. A
+/// new interval (see `config_reload`) applies right away.
+pub async fn update_system_metrics(
+    metrics: Arc<AppMetrics>,
+    mut interval: watch::Receiver<Duration>,
+    pressure: MemoryPressure,
+) {
+    let mut sampler = SystemSampler::new();
+    
+    loop {
+        if !pressure.sampler_paused() {
+            sampler.sample(&metrics);
+        }
+        
+        let period = *interval.borrow_and_update();
+        tokio::select! {
+            _ = tokio::time::sleep(period) => {}
+            Ok(()) = interval.changed() => {}
+        }
+    }
+}
+
+impl Default for SystemSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,329 @@
+//! Syslog and journald log sinks.
+//!
+//! `LOG_SYSLOG` sends every log event as an RFC 5424 message, to a local
+//! syslog daemon over a Unix datagram socket (`unix:/dev/log`) or to a
+//! remote one over UDP (`udp:logs.internal:514`), with the facility of
+//! `LOG_SYSLOG_FACILITY`. The message holds the event's text followed by its
+//! fields as `key=value` and, inside a span, `trace_id` and `span_id`.
+//!
+//! `LOG_JOURNALD` sends them to systemd-journald over its native protocol,
+//! keeping fields apart: `MESSAGE`, `PRIORITY`, `SYSLOG_IDENTIFIER`,
+//! `TARGET`, `CODE_FILE`, `CODE_LINE`, `TRACE_ID`, `SPAN_ID` and each event
+//! field upper-cased, e.g. `HTTP_ROUTE`, so `journalctl HTTP_ROUTE=/users`
+//! filters on it.
+//!
+//! Both map levels to syslog severities: `error` to `err` (3), `warn` to
+//! `warning` (4), `info` to `info` (6), `debug` and `trace` to `debug` (7).
+//! Field values are redacted like in the other outputs. Sending never
+//! blocks: an event the socket refuses, e.g. while the daemon restarts, is
+//! dropped.
+
+use crate::{log_format::FieldVisitor, redact::Redactor};
+use serde_json::Value;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+    fmt::{self, Write as _},
+    io,
+    net::UdpSocket,
+    path::PathBuf,
+    str::FromStr,
+};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, time::{FormatTime, SystemTime}},
+    layer::{Context, Layer},
+};
+
+/// Longest syslog message sent, in bytes; longer ones are cut, as most
+/// daemons would.
+const MAX_SYSLOG_MESSAGE: usize = 8 * 1024;
+/// journald's socket.
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Where `LOG_SYSLOG` sends messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyslogTarget {
+    /// Path of the daemon's Unix datagram socket.
+    Unix(PathBuf),
+    /// `host:port` of a daemon listening on UDP.
+    Udp(String),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err(format!("{s:?}: Unix domain sockets are not supported on this platform"));
+            }
+            if path.is_empty() {
+                return Err(format!("{s:?}: empty socket path"));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        match s.strip_prefix("udp:") {
+            Some(addr) if addr.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) => {
+                Ok(Self::Udp(addr.to_string()))
+            }
+            _ => Err(format!("expected `unix:<path>` or `udp:<host>:<port>`, got {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for SyslogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Udp(addr) => write!(f, "udp:{addr}"),
+        }
+    }
+}
+
+/// Syslog facility of the messages (`LOG_SYSLOG_FACILITY`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Facility {
+    #[default]
+    User,
+    Daemon,
+    /// `local0` to `local7`.
+    Local(u8),
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Local(n) => 16 + n,
+        }
+    }
+}
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        match s.as_str() {
+            "user" => Ok(Self::User),
+            "daemon" => Ok(Self::Daemon),
+            _ => s
+                .strip_prefix("local")
+                .and_then(|n| n.parse().ok())
+                .filter(|n| *n <= 7)
+                .map(Self::Local)
+                .ok_or_else(|| format!("unknown facility {s:?}, expected `user`, `daemon` or `local0` to `local7`")),
+        }
+    }
+}
+
+impl fmt::Display for Facility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User => f.write_str("user"),
+            Self::Daemon => f.write_str("daemon"),
+            Self::Local(n) => write!(f, "local{n}"),
+        }
+    }
+}
+
+/// The syslog severity of `level`.
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// The event's `message` field and its other fields, redacted.
+fn fields(event: &Event<'_>, redactor: &Redactor) -> (String, Vec<(String, String)>) {
+    let mut visitor = FieldVisitor::new(redactor);
+    event.record(&mut visitor);
+    let mut message = String::new();
+    let mut fields = Vec::new();
+    for (name, value) in visitor.into_fields() {
+        let value = match value {
+            Value::String(s) => s,
+            value => value.to_string(),
+        };
+        if name == "message" {
+            message = value;
+        } else {
+            fields.push((name, value));
+        }
+    }
+    #[cfg(feature = "traces")]
+    {
+        use opentelemetry::trace::TraceContextExt;
+
+        let cx = opentelemetry::Context::current();
+        let span_context = cx.span().span_context().clone();
+        if span_context.is_valid() {
+            fields.push(("trace_id".to_string(), span_context.trace_id().to_string()));
+            fields.push(("span_id".to_string(), span_context.span_id().to_string()));
+        }
+    }
+    (message, fields)
+}
+
+/// A connected datagram socket.
+enum Socket {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl Socket {
+    fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(socket) => socket.send(datagram),
+            Self::Udp(socket) => socket.send(datagram),
+        }
+    }
+}
+
+/// The host's name for the syslog header, `-` when unknown.
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty() && name.is_ascii() && !name.contains(' '))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Sends log events to syslog, see the module documentation.
+pub struct SyslogLayer {
+    socket: Socket,
+    facility: Facility,
+    /// `HOSTNAME APP-NAME PROCID`, the same for every message.
+    header: String,
+    redactor: Redactor,
+}
+
+impl SyslogLayer {
+    /// Connects to `target`; messages name `app_name` as their sender.
+    pub fn connect(target: &SyslogTarget, facility: Facility, app_name: &str, redactor: Redactor) -> io::Result<Self> {
+        let socket = match target {
+            #[cfg(unix)]
+            SyslogTarget::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                socket.set_nonblocking(true)?;
+                Socket::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogTarget::Unix(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, target.to_string())),
+            SyslogTarget::Udp(addr) => {
+                let socket = UdpSocket::bind(if addr.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+                socket.connect(addr)?;
+                socket.set_nonblocking(true)?;
+                Socket::Udp(socket)
+            }
+        };
+        // APP-NAME is at most 48 printable characters.
+        let app_name: String = app_name.chars().filter(|c| c.is_ascii_graphic()).take(48).collect();
+        let app_name = if app_name.is_empty() { "-".to_string() } else { app_name };
+        let header = format!("{} {app_name} {}", hostname(), std::process::id());
+        Ok(Self { socket, facility, header, redactor })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let (message, fields) = fields(event, &self.redactor);
+        let mut timestamp = String::new();
+        if SystemTime.format_time(&mut Writer::new(&mut timestamp)).is_err() {
+            timestamp = "-".to_string();
+        }
+        let priority = u16::from(self.facility.code()) * 8 + u16::from(severity(metadata.level()));
+        let mut line = format!("<{priority}>1 {timestamp} {} - - {message}", self.header);
+        for (name, value) in fields {
+            let _ = write!(line, " {name}={value}");
+        }
+        if line.len() > MAX_SYSLOG_MESSAGE {
+            let mut end = MAX_SYSLOG_MESSAGE;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+/// The journald name of field `name`: upper case letters, digits and
+/// underscores, not starting with an underscore, which journald reserves.
+#[cfg(unix)]
+fn journald_field(name: &str) -> String {
+    let name: String =
+        name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+    let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+    if name.is_empty() { "FIELD".to_string() } else { name.to_string() }
+}
+
+/// Appends `name=value` to a journald datagram, length-prefixed when the
+/// value spans lines.
+#[cfg(unix)]
+fn journald_append(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+/// Sends log events to journald, see the module documentation.
+#[cfg(unix)]
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+    identifier: String,
+    redactor: Redactor,
+}
+
+#[cfg(unix)]
+impl JournaldLayer {
+    /// Connects to journald's socket; entries name `identifier` as their
+    /// `SYSLOG_IDENTIFIER`.
+    pub fn connect(identifier: &str, redactor: Redactor) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, identifier: identifier.to_string(), redactor })
+    }
+}
+
+#[cfg(unix)]
+impl<S: Subscriber> Layer<S> for JournaldLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let (message, fields) = fields(event, &self.redactor);
+        let mut datagram = Vec::with_capacity(256);
+        journald_append(&mut datagram, "MESSAGE", &message);
+        journald_append(&mut datagram, "PRIORITY", &severity(metadata.level()).to_string());
+        journald_append(&mut datagram, "SYSLOG_IDENTIFIER", &self.identifier);
+        journald_append(&mut datagram, "TARGET", metadata.target());
+        if let Some(file) = metadata.file() {
+            journald_append(&mut datagram, "CODE_FILE", file);
+        }
+        if let Some(line) = metadata.line() {
+            journald_append(&mut datagram, "CODE_LINE", &line.to_string());
+        }
+        for (name, value) in fields {
+            journald_append(&mut datagram, &journald_field(&name), &value);
+        }
+        let _ = self.socket.send(&datagram);
+    }
+}
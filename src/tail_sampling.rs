@@ -0,0 +1,236 @@
+//! Tail sampling (`TAIL_SAMPLING`).
+//!
+//! Head sampling decides when a trace starts, so at a low ratio it mostly
+//! drops the failed and slow requests worth looking at. `TailSamplingProcessor`
+//! sits in front of the batch exporter and holds the ended spans of each
+//! trace for `TAIL_SAMPLING_WINDOW_MS` (default 10 s) from its first one,
+//! then exports them only if one of them has an error status or lasted at
+//! least `TAIL_SAMPLING_LATENCY_THRESHOLD_MS` (default 1 s); the others are
+//! dropped. Spans ending after their trace was decided follow the decision
+//! for another window. At most `TAIL_SAMPLING_MAX_TRACES` (default 10000)
+//! traces are held: past that the oldest is decided early.
+//!
+//! Decisions are counted in `tail_sampling_traces_total{decision}` (`kept`
+//! or `dropped`) and the traces held in `tail_sampling_buffered_traces`.
+//!
+//! Only sampled spans get here, so head sampling should keep every trace
+//! (the default `parentbased_always_on`). Span processors added with
+//! `TelemetryBuilder::with_span_processor`, span metrics and the debug tap
+//! still see every span.
+
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{Status, TraceId},
+    Context,
+};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    trace::{Span, SpanData, SpanProcessor},
+    Resource,
+};
+use prometheus::{IntCounterVec, IntGauge};
+#[cfg(feature = "traces")]
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, Once, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+crate::metric_set! {
+    /// The tail sampling metrics, registered when `TAIL_SAMPLING` is set.
+    #[derive(Clone, Debug)]
+    pub struct TailSamplingMetrics {
+        pub traces: IntCounterVec {
+            name: "tail_sampling_traces_total",
+            help: "Traces decided by tail sampling, by decision",
+            labels: ["decision"],
+        },
+        pub buffered: IntGauge {
+            name: "tail_sampling_buffered_traces",
+            help: "Traces held until tail sampling decides them",
+        },
+    }
+}
+
+/// What decides a trace.
+#[cfg(feature = "traces")]
+#[derive(Clone, Copy, Debug)]
+pub struct TailSamplingPolicy {
+    pub window: Duration,
+    pub latency_threshold: Duration,
+    pub max_traces: usize,
+}
+
+#[cfg(feature = "traces")]
+impl TailSamplingPolicy {
+    fn is_interesting(&self, span: &SpanData) -> bool {
+        matches!(span.status, Status::Error { .. })
+            || span.end_time.duration_since(span.start_time).is_ok_and(|elapsed| elapsed >= self.latency_threshold)
+    }
+}
+
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+struct Pending {
+    spans: Vec<SpanData>,
+    keep: bool,
+}
+
+#[cfg(feature = "traces")]
+#[derive(Debug, Default)]
+struct State {
+    pending: HashMap<TraceId, Pending>,
+    /// Pending traces by deadline, oldest first.
+    deadlines: VecDeque<(Instant, TraceId)>,
+    /// Recent decisions, for spans ending after them.
+    decided: HashMap<TraceId, bool>,
+    /// Decided traces by expiry, oldest first.
+    expiries: VecDeque<(Instant, TraceId)>,
+}
+
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+struct Shared<P> {
+    state: Mutex<State>,
+    next: P,
+    policy: TailSamplingPolicy,
+    metrics: TailSamplingMetrics,
+}
+
+#[cfg(feature = "traces")]
+impl<P: SpanProcessor> Shared<P> {
+    /// Decides the traces due at `now`, all of them with `flush`, and passes
+    /// the spans of the kept ones to `next`.
+    fn sweep(&self, now: Instant, flush: bool) {
+        let mut kept = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            while let Some(&(deadline, trace_id)) = state.deadlines.front() {
+                if !flush && deadline > now && state.pending.len() <= self.policy.max_traces {
+                    break;
+                }
+                state.deadlines.pop_front();
+                let Some(pending) = state.pending.remove(&trace_id) else {
+                    continue;
+                };
+                let decision = if pending.keep { "kept" } else { "dropped" };
+                self.metrics.traces.with_label_values(&[decision]).inc();
+                if pending.keep {
+                    kept.extend(pending.spans);
+                }
+                state.decided.insert(trace_id, pending.keep);
+                state.expiries.push_back((now + self.policy.window, trace_id));
+            }
+            while let Some(&(expiry, trace_id)) = state.expiries.front() {
+                if expiry > now && state.decided.len() <= self.policy.max_traces {
+                    break;
+                }
+                state.expiries.pop_front();
+                state.decided.remove(&trace_id);
+            }
+            self.metrics.buffered.set(state.pending.len() as i64);
+        }
+        for span in kept {
+            self.next.on_end(span);
+        }
+    }
+}
+
+/// Holds spans per trace and passes on those of the traces worth exporting
+/// to `next`, normally the batch span processor.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct TailSamplingProcessor<P> {
+    shared: Arc<Shared<P>>,
+    /// Starts the sweeper thread with the first span.
+    sweeper: Once,
+}
+
+#[cfg(feature = "traces")]
+impl<P: SpanProcessor + 'static> TailSamplingProcessor<P> {
+    pub fn new(next: P, policy: TailSamplingPolicy, metrics: TailSamplingMetrics) -> Self {
+        Self {
+            shared: Arc::new(Shared { state: Mutex::default(), next, policy, metrics }),
+            sweeper: Once::new(),
+        }
+    }
+
+    /// Decides due traces a few times per window until the processor is
+    /// dropped, so the last traces before a quiet period still go out.
+    fn start_sweeper(&self) {
+        let shared = Arc::downgrade(&self.shared);
+        let period = (self.shared.policy.window / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let _ = thread::Builder::new().name("tail-sampling".to_string()).spawn(move || sweep_every(shared, period));
+    }
+}
+
+#[cfg(feature = "traces")]
+fn sweep_every<P: SpanProcessor>(shared: Weak<Shared<P>>, period: Duration) {
+    loop {
+        thread::sleep(period);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        shared.sweep(Instant::now(), false);
+    }
+}
+
+#[cfg(feature = "traces")]
+impl<P: SpanProcessor + 'static> SpanProcessor for TailSamplingProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.shared.next.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        self.sweeper.call_once(|| self.start_sweeper());
+        let shared = &self.shared;
+        let now = Instant::now();
+        let trace_id = span.span_context.trace_id();
+        let interesting = shared.policy.is_interesting(&span);
+        let mut state = shared.state.lock().unwrap();
+        if let Some(&keep) = state.decided.get(&trace_id) {
+            drop(state);
+            if keep {
+                shared.next.on_end(span);
+            }
+            return;
+        }
+        let over_capacity = match state.pending.get_mut(&trace_id) {
+            Some(pending) => {
+                pending.keep |= interesting;
+                pending.spans.push(span);
+                false
+            }
+            None => {
+                state.pending.insert(trace_id, Pending { spans: vec![span], keep: interesting });
+                state.deadlines.push_back((now + shared.policy.window, trace_id));
+                shared.metrics.buffered.set(state.pending.len() as i64);
+                state.pending.len() > shared.policy.max_traces
+            }
+        };
+        drop(state);
+        if over_capacity {
+            shared.sweep(now, false);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.shared.sweep(Instant::now(), true);
+        self.shared.next.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.shared.sweep(Instant::now(), true);
+        self.shared.next.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        // Called while the provider is built, before the sweeper holds a
+        // reference.
+        if let Some(shared) = Arc::get_mut(&mut self.shared) {
+            shared.next.set_resource(resource);
+        }
+    }
+}
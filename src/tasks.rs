@@ -0,0 +1,163 @@
+//! Instrumented background tasks.
+//!
+//! `AppMetrics::spawn_instrumented(name, future)` spawns `future` on tokio
+//! like `tokio::spawn`, and makes it visible:
+//!
+//! - `background_tasks_running{task}`, the tasks currently running or
+//!   waiting for a runtime thread to start;
+//! - `background_tasks_completed_total{task,outcome}`, with `outcome` one of
+//!   `ok`, `error` (the task returned an `Err`), `panic` or `cancelled` (the
+//!   task was aborted or the runtime shut down);
+//! - `background_task_duration_seconds{task}`, how long finished tasks ran,
+//!   from the spawn.
+//!
+//! With the `traces` feature the task also runs in an internal span named
+//! after it, a child of the span current at spawn time, so the spans it
+//! creates are grouped under it; failed and panicked tasks mark it as an
+//! error. Task names label metrics and should be a fixed set, hence
+//! `&'static str`.
+
+use crate::registry::Registry;
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use prometheus::{exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts};
+use std::{fmt, future::Future, time::Instant};
+use tokio::task::JoinHandle;
+
+/// The metrics of tasks spawned with `AppMetrics::spawn_instrumented`.
+#[derive(Clone, Debug)]
+pub struct TaskMetrics {
+    pub running: IntGaugeVec,
+    pub completed: IntCounterVec,
+    pub duration: HistogramVec,
+}
+
+impl TaskMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let running = IntGaugeVec::new(Opts::new("background_tasks_running", "Background tasks running"), &["task"])?;
+        let completed = IntCounterVec::new(
+            Opts::new("background_tasks_completed_total", "Background tasks that ended, by outcome"),
+            &["task", "outcome"],
+        )?;
+        // From 10ms to about 45 minutes: tasks range from one-off jobs to
+        // loops running for the whole life of the process.
+        let duration = HistogramVec::new(
+            HistogramOpts::new("background_task_duration_seconds", "How long background tasks ran")
+                .buckets(exponential_buckets(0.01, 4.0, 10)?),
+            &["task"],
+        )?;
+        registry.register(Box::new(running.clone()))?;
+        registry.register(Box::new(completed.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        Ok(Self { running, completed, duration })
+    }
+
+    /// See `AppMetrics::spawn_instrumented`.
+    pub fn spawn<F>(&self, name: &'static str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: TaskOutput + Send + 'static,
+    {
+        #[cfg(feature = "traces")]
+        let cx = {
+            let tracer = global::tracer("prom_otel");
+            let span = tracer
+                .span_builder(name)
+                .with_kind(SpanKind::Internal)
+                .with_attributes([KeyValue::new("task.name", name)])
+                .start(&tracer);
+            Context::current_with_span(span)
+        };
+        // Started before spawning, so the time the task waits for a worker
+        // counts, and a task aborted before its first poll is still recorded.
+        #[cfg(feature = "traces")]
+        let run = Run::start(self.clone(), name, cx.clone());
+        #[cfg(not(feature = "traces"))]
+        let run = Run::start(self.clone(), name);
+        tokio::spawn(async move {
+            #[cfg(feature = "traces")]
+            let output = future.with_context(cx).await;
+            #[cfg(not(feature = "traces"))]
+            let output = future.await;
+            run.finish(output.error().map_or(Ok(()), Err));
+            output
+        })
+    }
+}
+
+/// What a task returned, as far as its outcome goes.
+pub trait TaskOutput {
+    /// The error the task ended with, if any.
+    fn error(&self) -> Option<String>;
+}
+
+impl TaskOutput for () {
+    fn error(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<T, E: fmt::Display> TaskOutput for Result<T, E> {
+    fn error(&self) -> Option<String> {
+        self.as_ref().err().map(ToString::to_string)
+    }
+}
+
+/// One run of a task, recorded when dropped: when the task returns, but also
+/// when it panics or is dropped unfinished.
+struct Run {
+    metrics: TaskMetrics,
+    name: &'static str,
+    started: Instant,
+    /// `None` until the task returns.
+    outcome: Option<Result<(), String>>,
+    #[cfg(feature = "traces")]
+    cx: Context,
+}
+
+impl Run {
+    fn start(metrics: TaskMetrics, name: &'static str, #[cfg(feature = "traces")] cx: Context) -> Self {
+        metrics.running.with_label_values(&[name]).inc();
+        Self {
+            metrics,
+            name,
+            started: Instant::now(),
+            outcome: None,
+            #[cfg(feature = "traces")]
+            cx,
+        }
+    }
+
+    /// Records the task's return, when `self` drops.
+    fn finish(mut self, outcome: Result<(), String>) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let (outcome, error) = match self.outcome.take() {
+            Some(Ok(())) => ("ok", None),
+            Some(Err(e)) => ("error", Some(e)),
+            None if std::thread::panicking() => ("panic", Some("task panicked".to_string())),
+            None => ("cancelled", None),
+        };
+        self.metrics.running.with_label_values(&[self.name]).dec();
+        self.metrics.completed.with_label_values(&[self.name, outcome]).inc();
+        self.metrics.duration.with_label_values(&[self.name]).observe(self.started.elapsed().as_secs_f64());
+        #[cfg(feature = "traces")]
+        {
+            let span = self.cx.span();
+            if let Some(e) = error {
+                span.set_status(Status::error(e));
+            }
+            span.end();
+        }
+        #[cfg(not(feature = "traces"))]
+        let _ = error;
+    }
+}
@@ -0,0 +1,1189 @@
+//! OpenTelemetry pipeline setup.
+//!
+//! Each signal is behind its own cargo feature (`traces`, `metrics`, `logs`);
+//! `TelemetryBuilder::init` installs whichever pipelines were compiled in and
+//! the returned `TelemetryGuard` shuts them down again.
+//!
+//! Custom span and log record processors can be added with
+//! `TelemetryBuilder::with_span_processor` / `with_log_processor`; they run
+//! before the built-in batch exporters, so changes they make to spans (in
+//! `on_start`) and log records (in `emit`) are exported, but they can't keep
+//! anything from being exported. `wrap_span_export` / `wrap_log_export` get
+//! the exporting processor itself, to wrap it (filtering or routing what
+//! reaches it) or replace it. Extra metric readers (`with_metric_reader`) are
+//! registered next to the periodic OTLP reader.
+//!
+//! Batch processor sizes and delays, the metric export interval and export
+//! timeouts come from `Config` (`OTEL_BSP_*`, `OTEL_BLRP_*`,
+//! `OTEL_METRIC_EXPORT_*`), as do the metric temporality and views (see
+//! `metric_views`). Each signal's exporter has its own endpoint, protocol and
+//! headers (see `otlp_exporter`), and may go through a circuit breaker
+//! shedding load while the collector fails (see `circuit_breaker`). Every
+//! signal can also be exported to a second collector (see `shadow_export`).
+//!
+//! All signals share the resource from `get_resource`;
+//! `with_resource_attributes` adds or overrides attributes for a single
+//! signal.
+//!
+//! The `RUST_LOG` filter of every log output can be replaced at runtime
+//! through `TelemetryGuard::log_filter`.
+//!
+//! With `OTEL_SDK_DISABLED` the providers are still installed, with the
+//! added processors and readers, but no exporter is built: nothing is sent
+//! and nothing connects, while the `tracing` outputs and the Prometheus
+//! registry behind `/metrics` work as usual.
+
+#[cfg(feature = "logs")]
+use crate::circuit_breaker::BreakerLogExporter;
+#[cfg(feature = "metrics")]
+use crate::circuit_breaker::BreakerMetricExporter;
+#[cfg(feature = "traces")]
+use crate::circuit_breaker::BreakerSpanExporter;
+#[cfg(feature = "logs")]
+use crate::pipeline::MonitoredLogExporter;
+#[cfg(feature = "logs")]
+use crate::switches::SwitchedLogExporter;
+#[cfg(feature = "traces")]
+use crate::switches::SwitchedSpanExporter;
+#[cfg(feature = "metrics")]
+use crate::pipeline::MonitoredMetricExporter;
+#[cfg(feature = "traces")]
+use crate::pipeline::MonitoredSpanExporter;
+#[cfg(feature = "logs")]
+use crate::shadow_export::TeeLogExporter;
+#[cfg(feature = "metrics")]
+use crate::shadow_export::TeeMetricExporter;
+#[cfg(feature = "traces")]
+use crate::shadow_export::{TeeSpanExporter, TeeSpanProcessor};
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use crate::shadow_export::Target;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use std::path::PathBuf;
+use crate::{
+    circuit_breaker::CircuitBreakers,
+    config::Config,
+    debug_session::{DebugLevelFilter, DebugSessions},
+    debug_tap::DebugTap,
+    file_sink::{FileSinkOptions, RotatingFile},
+    flags::SampleRatio,
+    memory_pressure::MemoryPressure,
+    log_format::{JsonFormat, LogFormat},
+    log_metrics::LogMetrics,
+    pipeline::{PipelineStats, QueueDropLayer, Signal},
+    redact::{RedactingFields, Redactor},
+    shadow_export::ShadowStats,
+    shutdown::FlushStep,
+    switches::TelemetrySwitches,
+    system_log::SyslogLayer,
+};
+#[cfg(unix)]
+use crate::system_log::JournaldLayer;
+#[cfg(feature = "logs")]
+use crate::log_sampling::{LogSampler, LogSamplingFilter};
+#[cfg(feature = "logs")]
+use tracing_subscriber::filter::{filter_fn, FilterExt};
+#[cfg(feature = "logs")]
+use crate::{audit::{self, AuditLogProcessor}, redact::RedactingLoggerProvider, tenant::TenantLogProcessor};
+#[cfg(feature = "remote-sampling")]
+use crate::remote_sampling::{RemoteSampler, RemoteStrategy};
+#[cfg(feature = "remote-sampling")]
+use opentelemetry_sdk::trace::ShouldSample;
+#[cfg(feature = "traces")]
+use crate::{
+    baggage::BaggageSpanProcessor,
+    debug_session::DebugSampler,
+    flags::LiveSampler,
+    memory_pressure::PressureSampler,
+    redact::RedactingSpanExporter,
+    tail_sampling::{TailSamplingMetrics, TailSamplingPolicy, TailSamplingProcessor},
+    tenant::TenantSpanProcessor,
+};
+#[cfg(any(feature = "traces", feature = "metrics"))]
+use opentelemetry::global;
+use opentelemetry::KeyValue;
+#[cfg(feature = "logs")]
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+#[cfg(feature = "logs")]
+use opentelemetry_otlp::LogExporter;
+#[cfg(feature = "metrics")]
+use opentelemetry_otlp::MetricExporter;
+#[cfg(feature = "traces")]
+use opentelemetry_otlp::SpanExporter;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use crate::otlp_exporter::ExporterSettings;
+#[cfg(all(feature = "otlp-grpc", any(feature = "traces", feature = "metrics", feature = "logs")))]
+use crate::otlp_exporter::{OtlpCompression, OtlpProtocol};
+#[cfg(all(feature = "otlp-grpc", any(feature = "traces", feature = "metrics", feature = "logs")))]
+use opentelemetry_otlp::{Compression, WithTonicConfig};
+#[cfg(all(feature = "otlp-grpc", any(feature = "traces", feature = "metrics", feature = "logs")))]
+use tonic::metadata::{MetadataKey, MetadataMap};
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use crate::pipeline::MonitoredHttpClient;
+#[cfg(feature = "logs")]
+use opentelemetry::InstrumentationScope;
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{
+    BatchConfigBuilder as LogBatchConfigBuilder, BatchLogProcessor, LogProcessor, SdkLogRecord, SdkLoggerProvider,
+};
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::{
+    data::ResourceMetrics,
+    reader::MetricReader,
+    Instrument, InstrumentKind, PeriodicReader, Pipeline, SdkMeterProvider, Temporality,
+};
+#[cfg(feature = "traces")]
+use opentelemetry::Context;
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{
+    BatchConfigBuilder as SpanBatchConfigBuilder, BatchSpanProcessor, SdkTracerProvider, Span, SpanData, SpanProcessor,
+};
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::error::OTelSdkError;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs", feature = "pyroscope"))]
+use opentelemetry_sdk::Resource;
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs", feature = "pyroscope"))]
+use std::sync::OnceLock;
+use std::{fmt, sync::Arc};
+use tracing::{info, warn};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{fmt::MakeWriter, prelude::*, registry::LookupSpan, reload, EnvFilter, Layer};
+
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs", feature = "pyroscope"))]
+static RESOURCE: OnceLock<Resource> = OnceLock::new();
+
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs", feature = "pyroscope"))]
+pub(crate) fn get_resource(config: &Config) -> Resource {
+    RESOURCE
+    .get_or_init(|| {
+        Resource::builder()
+        .with_attributes(
+            config
+            .resource_attributes()
+            .into_iter()
+            .map(|(key, value)| KeyValue::new(key, value)),
+        )
+        .build()
+    })
+    .clone()
+}
+
+/// Applies `settings`' headers, as gRPC metadata, and compression to a gRPC
+/// exporter builder.
+#[cfg(all(feature = "otlp-grpc", any(feature = "traces", feature = "metrics", feature = "logs")))]
+fn with_grpc_settings<B: WithTonicConfig>(builder: B, settings: &ExporterSettings) -> B {
+    let mut metadata = MetadataMap::new();
+    for header in &settings.headers {
+        let key = MetadataKey::from_bytes(header.name.as_bytes()).expect("header names are validated");
+        metadata.insert(key, header.value.parse().expect("header values are validated"));
+    }
+    let builder = builder.with_metadata(metadata);
+    match settings.compression {
+        OtlpCompression::None => builder,
+        OtlpCompression::Gzip => builder.with_compression(Compression::Gzip),
+        OtlpCompression::Zstd => builder.with_compression(Compression::Zstd),
+    }
+}
+
+/// The shared resource with `overrides` added on top; an attribute in
+/// `overrides` replaces a shared one with the same key.
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+fn signal_resource(config: &Config, overrides: &[KeyValue]) -> Resource {
+    let resource = get_resource(config);
+    if overrides.is_empty() {
+        return resource;
+    }
+    Resource::builder_empty()
+    .with_attributes(resource.iter().map(|(key, value)| KeyValue::new(key.clone(), value.clone())))
+    .with_attributes(overrides.iter().cloned())
+    .build()
+}
+
+/// Settings, URL and Unix socket of `signal`'s exporter to `target`, or
+/// `None` for the shadow when shadow export is off.
+#[cfg(any(feature = "traces", feature = "metrics", feature = "logs"))]
+fn destination(config: &Config, signal: Signal, target: Target) -> Option<(&ExporterSettings, String, Option<PathBuf>)> {
+    match target {
+        Target::Primary => {
+            Some((config.exporter(signal), config.exporter_endpoint(signal), config.otlp_unix_socket.clone()))
+        }
+        Target::Shadow => config.shadow_exporter_endpoint(signal).map(|endpoint| (&config.shadow_exporter, endpoint, None)),
+    }
+}
+
+#[cfg(feature = "logs")]
+fn log_exporter(config: &Config, stats: &PipelineStats, target: Target) -> Option<LogExporter> {
+    let (settings, endpoint, unix_socket) = destination(config, Signal::Logs, target)?;
+    let exporter = match settings.protocol {
+        #[cfg(feature = "otlp-grpc")]
+        OtlpProtocol::Grpc => with_grpc_settings(
+            LogExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .with_timeout(config.log_batch.export_timeout),
+            settings,
+        )
+        .build(),
+        protocol => LogExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .with_protocol(protocol.into())
+        .with_timeout(config.log_batch.export_timeout)
+        .with_headers(settings.header_map())
+        .with_http_client(
+            MonitoredHttpClient::new(Signal::Logs, stats.clone(), config.log_batch.export_timeout)
+                .with_compression(settings.compression)
+                .with_unix_socket(unix_socket),
+        )
+        .build(),
+    }
+    .expect("Failed to create log exporter");
+    Some(exporter)
+}
+
+#[cfg(feature = "logs")]
+#[allow(clippy::too_many_arguments)]
+fn init_logs(
+    config: &Config,
+    stats: PipelineStats,
+    processors: Vec<BoxedLogProcessor>,
+    export_wrapper: Option<LogExportWrapper>,
+    resource: Resource,
+    switches: TelemetrySwitches,
+    breakers: Option<&CircuitBreakers>,
+    shadow: Option<&ShadowStats>,
+) -> SdkLoggerProvider {
+    let batch_config = || {
+        LogBatchConfigBuilder::default()
+        .with_max_queue_size(config.log_batch.max_queue_size)
+        .with_max_export_batch_size(config.log_batch.max_export_batch_size)
+        .with_scheduled_delay(config.log_batch.scheduled_delay)
+        .build()
+    };
+    let builder = processors
+    .into_iter()
+    .fold(SdkLoggerProvider::builder(), |builder, processor| builder.with_log_processor(processor));
+    let builder = match config.tenant_source {
+        Some(_) => builder.with_log_processor(TenantLogProcessor),
+        None => builder,
+    };
+    if config.telemetry_disabled {
+        return builder.with_resource(resource).build();
+    }
+    
+    let exporter = log_exporter(config, &stats, Target::Primary).expect("the primary exporter is always configured");
+    let shadow_exporter = shadow.and_then(|_| log_exporter(config, &stats, Target::Shadow));
+    let batch: Box<dyn LogProcessor> = Box::new(
+        BatchLogProcessor::builder(SwitchedLogExporter::new(
+            BreakerLogExporter::new(
+                TeeLogExporter::new(MonitoredLogExporter::new(exporter, stats), shadow.cloned(), Target::Primary),
+                breakers.map(|breakers| breakers.get(Signal::Logs)),
+            ),
+            switches.clone(),
+        ))
+        .with_batch_config(batch_config())
+        .build(),
+    );
+    let batch = match export_wrapper {
+        Some(wrap) => wrap(batch),
+        None => batch,
+    };
+    let builder = builder.with_log_processor(BoxedLogProcessor(batch));
+    // Its own queue and export thread, see `shadow_export`.
+    let builder = match shadow_exporter {
+        Some(exporter) => builder.with_log_processor(
+            BatchLogProcessor::builder(SwitchedLogExporter::new(
+                TeeLogExporter::new(exporter, shadow.cloned(), Target::Shadow),
+                switches,
+            ))
+            .with_batch_config(batch_config())
+            .build(),
+        ),
+        None => builder,
+    };
+    
+    builder
+    .with_resource(resource)
+    .build()
+}
+
+/// The provider of the audit log stream (`AUDIT_LOGS`): same exporter
+/// settings as the other logs, but none of their processors, switches or
+/// batching.
+#[cfg(feature = "logs")]
+fn init_audit_logs(config: &Config, stats: PipelineStats, resource: Resource) -> SdkLoggerProvider {
+    let exporter = log_exporter(config, &stats, Target::Primary).expect("the primary exporter is always configured");
+    SdkLoggerProvider::builder()
+    .with_log_processor(AuditLogProcessor::new(MonitoredLogExporter::new(exporter, stats)))
+    .with_resource(resource)
+    .build()
+}
+
+/// Type-erased user processor; the SDK builder only takes concrete types.
+#[cfg(feature = "logs")]
+#[derive(Debug)]
+struct BoxedLogProcessor(Box<dyn LogProcessor>);
+
+/// See `TelemetryBuilder::wrap_log_export`.
+#[cfg(feature = "logs")]
+type LogExportWrapper = Box<dyn FnOnce(Box<dyn LogProcessor>) -> Box<dyn LogProcessor> + Send>;
+
+#[cfg(feature = "logs")]
+impl LogProcessor for BoxedLogProcessor {
+    fn emit(&self, data: &mut SdkLogRecord, instrumentation: &InstrumentationScope) {
+        self.0.emit(data, instrumentation);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: std::time::Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.0.set_resource(resource);
+    }
+}
+
+#[cfg(feature = "traces")]
+#[allow(clippy::too_many_arguments)]
+fn init_traces(
+    config: &Config,
+    stats: PipelineStats,
+    processors: Vec<BoxedSpanProcessor>,
+    export_wrapper: Option<SpanExportWrapper>,
+    resource: Resource,
+    redactor: Redactor,
+    sampler: PressureSampler,
+    switches: TelemetrySwitches,
+    tail_sampling: Option<(TailSamplingPolicy, TailSamplingMetrics)>,
+    breakers: Option<&CircuitBreakers>,
+    shadow: Option<&ShadowStats>,
+) -> SdkTracerProvider {
+    let batch_config = || {
+        SpanBatchConfigBuilder::default()
+        .with_max_queue_size(config.trace_batch.max_queue_size)
+        .with_max_export_batch_size(config.trace_batch.max_export_batch_size)
+        .with_scheduled_delay(config.trace_batch.scheduled_delay)
+        .build()
+    };
+
+    let builder = processors
+    .into_iter()
+    .fold(SdkTracerProvider::builder(), |builder, processor| builder.with_span_processor(processor));
+    let builder = if config.baggage_keys.is_empty() {
+        builder
+    } else {
+        builder.with_span_processor(BaggageSpanProcessor::new(config.baggage_keys.clone()))
+    };
+    let builder = match config.tenant_source {
+        Some(_) => builder.with_span_processor(TenantSpanProcessor),
+        None => builder,
+    };
+    if config.telemetry_disabled {
+        return builder.with_sampler(sampler).with_resource(resource).build();
+    }
+    let exporter = span_exporter(config, &stats, Target::Primary).expect("the primary exporter is always configured");
+    let shadow_exporter = shadow.and_then(|_| span_exporter(config, &stats, Target::Shadow));
+    let batch = BatchSpanProcessor::builder(SwitchedSpanExporter::new(
+        BreakerSpanExporter::new(
+            RedactingSpanExporter::new(
+                TeeSpanExporter::new(MonitoredSpanExporter::new(exporter, stats), shadow.cloned(), Target::Primary),
+                redactor.clone(),
+            ),
+            breakers.map(|breakers| breakers.get(Signal::Traces)),
+        ),
+        switches.clone(),
+    ))
+    .with_batch_config(batch_config())
+    .build();
+    // Its own queue and export thread, see `shadow_export`.
+    let shadow_batch = shadow_exporter.map(|exporter| {
+        BatchSpanProcessor::builder(SwitchedSpanExporter::new(
+            RedactingSpanExporter::new(TeeSpanExporter::new(exporter, shadow.cloned(), Target::Shadow), redactor),
+            switches,
+        ))
+        .with_batch_config(batch_config())
+        .build()
+    });
+    let batch = TeeSpanProcessor::new(batch, shadow_batch);
+    let exporting: Box<dyn SpanProcessor> = match tail_sampling {
+        Some((policy, metrics)) => Box::new(TailSamplingProcessor::new(batch, policy, metrics)),
+        None => Box::new(batch),
+    };
+    let exporting = match export_wrapper {
+        Some(wrap) => wrap(exporting),
+        None => exporting,
+    };
+    let builder = builder.with_span_processor(BoxedSpanProcessor(exporting));
+    
+    builder
+    .with_sampler(sampler)
+    .with_resource(resource)
+    .build()
+}
+
+#[cfg(feature = "traces")]
+fn span_exporter(config: &Config, stats: &PipelineStats, target: Target) -> Option<SpanExporter> {
+    let (settings, endpoint, unix_socket) = destination(config, Signal::Traces, target)?;
+    let exporter = match settings.protocol {
+        #[cfg(feature = "otlp-grpc")]
+        OtlpProtocol::Grpc => with_grpc_settings(
+            SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .with_timeout(config.trace_batch.export_timeout),
+            settings,
+        )
+        .build(),
+        protocol => SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .with_protocol(protocol.into())
+        .with_timeout(config.trace_batch.export_timeout)
+        .with_headers(settings.header_map())
+        .with_http_client(
+            MonitoredHttpClient::new(Signal::Traces, stats.clone(), config.trace_batch.export_timeout)
+                .with_compression(settings.compression)
+                .with_unix_socket(unix_socket),
+        )
+        .build(),
+    }
+    .expect("Failed to create trace exporter");
+    Some(exporter)
+}
+
+/// Type-erased user processor; the SDK builder only takes concrete types.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+struct BoxedSpanProcessor(Box<dyn SpanProcessor>);
+
+/// See `TelemetryBuilder::wrap_span_export`.
+#[cfg(feature = "traces")]
+type SpanExportWrapper = Box<dyn FnOnce(Box<dyn SpanProcessor>) -> Box<dyn SpanProcessor> + Send>;
+
+#[cfg(feature = "traces")]
+impl SpanProcessor for BoxedSpanProcessor {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.0.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        self.0.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: std::time::Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.0.set_resource(resource);
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn init_metrics(
+    config: &Config,
+    stats: PipelineStats,
+    readers: Vec<BoxedMetricReader>,
+    resource: Resource,
+    breakers: Option<&CircuitBreakers>,
+    shadow: Option<&ShadowStats>,
+) -> SdkMeterProvider {
+    let builder = readers
+    .into_iter()
+    .fold(SdkMeterProvider::builder(), |builder, reader| builder.with_reader(reader));
+    let builder = config
+    .metric_views
+    .iter()
+    .cloned()
+    .fold(builder, |builder, view| builder.with_view(move |instrument: &Instrument| view.stream(instrument)));
+    let builder = match config.native_histograms() {
+        Some(opts) => builder.with_view(move |instrument: &Instrument| opts.stream(instrument)),
+        None => builder,
+    };
+    if config.telemetry_disabled {
+        return builder.with_resource(resource).build();
+    }
+    
+    let exporter = metric_exporter(config, &stats, Target::Primary).expect("the primary exporter is always configured");
+    let shadow_exporter = shadow.and_then(|_| metric_exporter(config, &stats, Target::Shadow));
+    let builder = builder.with_reader(
+        PeriodicReader::builder(BreakerMetricExporter::new(
+            TeeMetricExporter::new(MonitoredMetricExporter::new(exporter, stats), shadow.cloned(), Target::Primary),
+            breakers.map(|breakers| breakers.get(Signal::Metrics)),
+        ))
+        .with_interval(config.metric_export_interval)
+        .build(),
+    );
+    // Its own collection cycle and export thread, see `shadow_export`.
+    let builder = match shadow_exporter {
+        Some(exporter) => builder.with_reader(
+            PeriodicReader::builder(TeeMetricExporter::new(exporter, shadow.cloned(), Target::Shadow))
+            .with_interval(config.metric_export_interval)
+            .build(),
+        ),
+        None => builder,
+    };
+    
+    builder
+    .with_resource(resource)
+    .build()
+}
+
+#[cfg(feature = "metrics")]
+fn metric_exporter(config: &Config, stats: &PipelineStats, target: Target) -> Option<MetricExporter> {
+    let (settings, endpoint, unix_socket) = destination(config, Signal::Metrics, target)?;
+    let exporter = match settings.protocol {
+        #[cfg(feature = "otlp-grpc")]
+        OtlpProtocol::Grpc => with_grpc_settings(
+            MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .with_timeout(config.metric_export_timeout),
+            settings,
+        )
+        .with_temporality(config.metric_temporality.into())
+        .build(),
+        protocol => MetricExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .with_protocol(protocol.into())
+        .with_timeout(config.metric_export_timeout)
+        .with_headers(settings.header_map())
+        .with_http_client(
+            MonitoredHttpClient::new(Signal::Metrics, stats.clone(), config.metric_export_timeout)
+                .with_compression(settings.compression)
+                .with_unix_socket(unix_socket),
+        )
+        .with_temporality(config.metric_temporality.into())
+        .build(),
+    }
+    .expect("Failed to create metric exporter");
+    Some(exporter)
+}
+
+/// Type-erased user reader; the SDK builder only takes concrete types.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+struct BoxedMetricReader(Box<dyn MetricReader>);
+
+#[cfg(feature = "metrics")]
+impl MetricReader for BoxedMetricReader {
+    fn register_pipeline(&self, pipeline: std::sync::Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline);
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: std::time::Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+/// A `fmt` layer writing to `writer` in the configured format.
+fn format_layer<S, W>(format: LogFormat, writer: W, ansi: bool, redactor: Redactor) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .fmt_fields(RedactingFields::new(redactor))
+            .with_thread_names(true)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .event_format(JsonFormat::new(redactor))
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Configures and installs the telemetry pipelines enabled at compile time.
+pub struct TelemetryBuilder {
+    config: Config,
+    stats: PipelineStats,
+    #[cfg(feature = "traces")]
+    span_processors: Vec<BoxedSpanProcessor>,
+    #[cfg(feature = "traces")]
+    span_export_wrapper: Option<SpanExportWrapper>,
+    #[cfg(feature = "logs")]
+    log_processors: Vec<BoxedLogProcessor>,
+    #[cfg(feature = "logs")]
+    log_export_wrapper: Option<LogExportWrapper>,
+    #[cfg(feature = "metrics")]
+    metric_readers: Vec<BoxedMetricReader>,
+    /// Extra resource attributes per signal, indexed by `Signal as usize`.
+    resource_overrides: [Vec<KeyValue>; 3],
+    debug_sessions: DebugSessions,
+    sample_ratio: SampleRatio,
+    switches: TelemetrySwitches,
+    memory_pressure: Option<MemoryPressure>,
+    circuit_breakers: Option<CircuitBreakers>,
+    shadow_stats: Option<ShadowStats>,
+    log_metrics: Option<LogMetrics>,
+    #[cfg(feature = "logs")]
+    log_sampler: Option<LogSampler>,
+    debug_tap: Option<DebugTap>,
+    #[cfg(feature = "remote-sampling")]
+    remote_sampling: Option<RemoteStrategy>,
+    #[cfg(feature = "traces")]
+    tail_sampling: Option<(TailSamplingPolicy, TailSamplingMetrics)>,
+}
+
+impl TelemetryBuilder {
+    pub fn new(config: &Config, stats: PipelineStats) -> Self {
+        Self {
+            config: config.clone(),
+            stats,
+            #[cfg(feature = "traces")]
+            span_processors: Vec::new(),
+            #[cfg(feature = "traces")]
+            span_export_wrapper: None,
+            #[cfg(feature = "logs")]
+            log_processors: Vec::new(),
+            #[cfg(feature = "logs")]
+            log_export_wrapper: None,
+            #[cfg(feature = "metrics")]
+            metric_readers: Vec::new(),
+            resource_overrides: Default::default(),
+            debug_sessions: DebugSessions::new(),
+            sample_ratio: SampleRatio::new(),
+            switches: TelemetrySwitches::new(),
+            memory_pressure: None,
+            circuit_breakers: None,
+            shadow_stats: None,
+            log_metrics: None,
+            #[cfg(feature = "logs")]
+            log_sampler: None,
+            debug_tap: None,
+            #[cfg(feature = "remote-sampling")]
+            remote_sampling: None,
+            #[cfg(feature = "traces")]
+            tail_sampling: None,
+        }
+    }
+
+    /// Lets `sessions` raise the log level of matching requests; without it
+    /// the builder uses a registry of its own that never has sessions.
+    pub fn with_debug_sessions(mut self, sessions: DebugSessions) -> Self {
+        self.debug_sessions = sessions;
+        self
+    }
+
+    /// Lets `ratio` override the trace sampler at runtime (see `flags`).
+    pub fn with_sample_ratio(mut self, ratio: SampleRatio) -> Self {
+        self.sample_ratio = ratio;
+        self
+    }
+
+    /// Lets `switches` turn span and log export off at runtime (see
+    /// `switches`).
+    pub fn with_switches(mut self, switches: TelemetrySwitches) -> Self {
+        self.switches = switches;
+        self
+    }
+
+    /// Sheds `debug` logs and trace sampling as `pressure` rises (see
+    /// `memory_pressure`).
+    pub fn with_memory_pressure(mut self, pressure: MemoryPressure) -> Self {
+        self.memory_pressure = Some(pressure);
+        self
+    }
+
+    /// Sends each signal's exports through its breaker in `breakers` (see
+    /// `circuit_breaker`).
+    pub fn with_circuit_breakers(mut self, breakers: CircuitBreakers) -> Self {
+        self.circuit_breakers = Some(breakers);
+        self
+    }
+
+    /// Also exports every signal to `shadow_endpoint`, if set, counting
+    /// both targets' exports in `stats` (see `shadow_export`).
+    pub fn with_shadow_export(mut self, stats: ShadowStats) -> Self {
+        self.shadow_stats = Some(stats);
+        self
+    }
+
+    /// Counts log events matching the rules of `metrics` (see `log_metrics`).
+    pub fn with_log_metrics(mut self, metrics: LogMetrics) -> Self {
+        self.log_metrics = Some(metrics);
+        self
+    }
+
+    /// Rate-limits and samples the log events exported over OTLP with
+    /// `sampler`, see `log_sampling`.
+    #[cfg(feature = "logs")]
+    pub fn with_log_sampler(mut self, sampler: LogSampler) -> Self {
+        self.log_sampler = Some(sampler);
+        self
+    }
+
+    /// Copies finished spans and log events into `tap`, see `debug_tap`.
+    pub fn with_debug_tap(mut self, tap: DebugTap) -> Self {
+        self.debug_tap = Some(tap);
+        self
+    }
+
+    /// Samples root spans with the strategy in `strategy` instead of the
+    /// `OTEL_TRACES_SAMPLER` one, see `remote_sampling`.
+    #[cfg(feature = "remote-sampling")]
+    pub fn with_remote_sampling(mut self, strategy: RemoteStrategy) -> Self {
+        self.remote_sampling = Some(strategy);
+        self
+    }
+
+    /// Holds spans per trace and exports only the traces `policy` keeps,
+    /// see `tail_sampling`.
+    #[cfg(feature = "traces")]
+    pub fn with_tail_sampling(mut self, policy: TailSamplingPolicy, metrics: TailSamplingMetrics) -> Self {
+        self.tail_sampling = Some((policy, metrics));
+        self
+    }
+
+    /// Adds a span processor (enrichment, mirroring, ...) to the tracer
+    /// provider. Processors are called in registration order, all before the
+    /// OTLP batch exporter, which still gets every span; to filter what is
+    /// exported, see `wrap_span_export`.
+    #[cfg(feature = "traces")]
+    pub fn with_span_processor<P: SpanProcessor + 'static>(mut self, processor: P) -> Self {
+        self.span_processors.push(BoxedSpanProcessor(Box::new(processor)));
+        self
+    }
+
+    /// Hands the processor exporting spans over OTLP (batching, tail
+    /// sampling and the shadow export included) to `wrap`, and installs what
+    /// it returns instead: a processor delegating to it for the spans to
+    /// export, or one replacing it. Not called with `OTEL_SDK_DISABLED`.
+    #[cfg(feature = "traces")]
+    pub fn wrap_span_export<F>(mut self, wrap: F) -> Self
+    where
+        F: FnOnce(Box<dyn SpanProcessor>) -> Box<dyn SpanProcessor> + Send + 'static,
+    {
+        self.span_export_wrapper = Some(Box::new(wrap));
+        self
+    }
+
+    /// Adds a log record processor (dedup, enrichment, ...) to the logger
+    /// provider. Processors are called in registration order, all before the
+    /// OTLP batch exporter, and may modify the record in `emit`; to filter
+    /// what is exported, see `wrap_log_export`.
+    #[cfg(feature = "logs")]
+    pub fn with_log_processor<P: LogProcessor + 'static>(mut self, processor: P) -> Self {
+        self.log_processors.push(BoxedLogProcessor(Box::new(processor)));
+        self
+    }
+
+    /// Like `wrap_span_export`, for the processor exporting log records
+    /// over OTLP. The shadow export and the audit stream are not wrapped.
+    #[cfg(feature = "logs")]
+    pub fn wrap_log_export<F>(mut self, wrap: F) -> Self
+    where
+        F: FnOnce(Box<dyn LogProcessor>) -> Box<dyn LogProcessor> + Send + 'static,
+    {
+        self.log_export_wrapper = Some(Box::new(wrap));
+        self
+    }
+
+    /// Adds a metric reader alongside the periodic OTLP reader, e.g. a
+    /// `ManualReader` for tests or a reader feeding another exporter. To
+    /// collect from it later, register a reader that shares its state (for
+    /// instance one delegating to an `Arc<ManualReader>`).
+    #[cfg(feature = "metrics")]
+    pub fn with_metric_reader<R: MetricReader>(mut self, reader: R) -> Self {
+        self.metric_readers.push(BoxedMetricReader(Box::new(reader)));
+        self
+    }
+
+    /// Adds or overrides resource attributes for one signal only, e.g. a
+    /// `logs.source` attribute on the log resource. Signals whose feature is
+    /// disabled ignore their overrides.
+    pub fn with_resource_attributes<I>(mut self, signal: Signal, attributes: I) -> Self
+    where
+        I: IntoIterator<Item = KeyValue>,
+    {
+        self.resource_overrides[signal as usize].extend(attributes);
+        self
+    }
+
+    /// Builds the providers, registers them globally and installs the
+    /// `tracing` subscriber (stdout, the optional log file, plus the OTLP log
+    /// bridge if `logs` is on).
+    pub fn init(self) -> TelemetryGuard {
+        let config = &self.config;
+        let mut reloaders = Vec::new();
+        // Patterns were checked by `Config::validate`.
+        let redactor = Redactor::from_config(config).expect("Invalid redaction pattern");
+
+        #[cfg(feature = "logs")]
+        let logger_provider = init_logs(
+            config,
+            self.stats.clone(),
+            self.log_processors,
+            self.log_export_wrapper,
+            signal_resource(config, &self.resource_overrides[Signal::Logs as usize]),
+            self.switches.clone(),
+            self.circuit_breakers.as_ref(),
+            self.shadow_stats.as_ref(),
+        );
+        #[cfg(feature = "logs")]
+        let audit_provider = (config.audit_logs && !config.telemetry_disabled).then(|| {
+            let mut overrides = self.resource_overrides[Signal::Logs as usize].clone();
+            overrides.push(KeyValue::new("log.stream", "audit"));
+            init_audit_logs(config, self.stats.clone(), signal_resource(config, &overrides))
+        });
+        #[cfg(feature = "logs")]
+        let otel_layer = {
+            let otel_layer = OpenTelemetryTracingBridge::new(&RedactingLoggerProvider::new(
+                logger_provider.clone(),
+                redactor.clone(),
+            ));
+            let audit_stream = audit_provider.is_some();
+            otel_layer.with_filter(DebugLevelFilter::new(
+                reloadable(move |level| otel_filter(level, audit_stream), &config.log_level, &mut reloaders),
+                self.debug_sessions.clone(),
+            )
+            .and(LogSamplingFilter::new(self.log_sampler.clone())))
+        };
+        // Every audit event, whatever `RUST_LOG` says.
+        #[cfg(feature = "logs")]
+        let audit_layer = audit_provider.as_ref().map(|provider| {
+            OpenTelemetryTracingBridge::new(&RedactingLoggerProvider::new(provider.clone(), redactor.clone()))
+            .with_filter(filter_fn(|meta| meta.target() == audit::TARGET))
+        });
+        #[cfg(not(feature = "logs"))]
+        let (otel_layer, audit_layer) = (tracing_subscriber::layer::Identity::new(), tracing_subscriber::layer::Identity::new());
+
+        let fmt_layer = config.log_stdout.then(|| {
+            format_layer(config.log_format, std::io::stdout, true, redactor.clone())
+            .with_filter(DebugLevelFilter::new(
+                reloadable(|level| EnvFilter::new(level), &config.log_level, &mut reloaders),
+                self.debug_sessions.clone(),
+            ))
+        });
+
+        let (file_layer, file_guard) = match &config.log_file {
+            Some(path) => {
+                let file = RotatingFile::open(FileSinkOptions {
+                    path: path.clone(),
+                    max_bytes: config.log_file_max_size_mb * 1024 * 1024,
+                    rotation: config.log_file_rotation,
+                    max_files: config.log_file_max_files,
+                })
+                .expect("Failed to open log file");
+                let (writer, guard) = tracing_appender::non_blocking(file);
+                let layer = format_layer(config.log_format, writer, false, redactor.clone())
+                .with_filter(DebugLevelFilter::new(
+                reloadable(|level| EnvFilter::new(level), &config.log_level, &mut reloaders),
+                self.debug_sessions.clone(),
+            ));
+                (Some(layer), Some(guard))
+            }
+            None => (None, None),
+        };
+
+        // A log sink that can't be reached is skipped, and reported once the
+        // other outputs are installed.
+        let mut unavailable_sinks = Vec::new();
+        let syslog_layer = config.log_syslog.as_ref().and_then(|target| {
+            SyslogLayer::connect(target, config.log_syslog_facility, &config.service_name, redactor.clone())
+            .inspect_err(|e| unavailable_sinks.push(("syslog", e.to_string())))
+            .ok()
+        })
+        .map(|layer| {
+            layer
+            .with_filter(DebugLevelFilter::new(
+                reloadable(|level| EnvFilter::new(level), &config.log_level, &mut reloaders),
+                self.debug_sessions.clone(),
+            ))
+            .boxed()
+        });
+        #[cfg(unix)]
+        let journald_layer = config.log_journald.then(|| {
+            JournaldLayer::connect(&config.service_name, redactor.clone())
+            .inspect_err(|e| unavailable_sinks.push(("journald", e.to_string())))
+            .ok()
+        })
+        .flatten()
+        .map(|layer| {
+            layer
+            .with_filter(DebugLevelFilter::new(
+                reloadable(|level| EnvFilter::new(level), &config.log_level, &mut reloaders),
+                self.debug_sessions.clone(),
+            ))
+            .boxed()
+        });
+        #[cfg(not(unix))]
+        let journald_layer = tracing_subscriber::layer::Identity::new();
+
+        tracing_subscriber::registry()
+        .with(self.memory_pressure.as_ref().map(MemoryPressure::layer))
+        .with(otel_layer)
+        .with(audit_layer)
+        .with(fmt_layer)
+        .with(file_layer)
+        .with(syslog_layer)
+        .with(journald_layer)
+        .with(QueueDropLayer::new(self.stats.clone()))
+        .with(self.log_metrics.as_ref().map(LogMetrics::layer))
+        .with(self.debug_tap.as_ref().map(|tap| {
+            tap.layer(redactor.clone())
+            .with_filter(DebugLevelFilter::new(
+                reloadable(|level| EnvFilter::new(level), &config.log_level, &mut reloaders),
+                self.debug_sessions.clone(),
+            ))
+        }))
+        .init();
+        for (sink, error) in unavailable_sinks {
+            warn!(sink, error, "Log sink unavailable, logging without it");
+        }
+        if config.telemetry_disabled {
+            info!("OTEL_SDK_DISABLED is set, no telemetry is exported");
+        }
+
+        #[cfg(feature = "traces")]
+        let mut span_processors = self.span_processors;
+        #[cfg(feature = "traces")]
+        if let Some(tap) = &self.debug_tap {
+            span_processors.push(BoxedSpanProcessor(Box::new(tap.span_processor(redactor.clone()))));
+        }
+
+        #[cfg(all(feature = "traces", not(feature = "remote-sampling")))]
+        let base_sampler = opentelemetry_sdk::trace::Config::default().sampler;
+        #[cfg(feature = "remote-sampling")]
+        let base_sampler: Box<dyn ShouldSample> = match self.remote_sampling {
+            Some(strategy) => Box::new(RemoteSampler::new(strategy)),
+            None => opentelemetry_sdk::trace::Config::default().sampler,
+        };
+        #[cfg(feature = "traces")]
+        let tracer_provider = init_traces(
+            config,
+            self.stats.clone(),
+            span_processors,
+            self.span_export_wrapper,
+            signal_resource(config, &self.resource_overrides[Signal::Traces as usize]),
+            redactor,
+            // The SDK default sampler, which honors `OTEL_TRACES_SAMPLER`, or
+            // the remote one, replaced while a flag or the admin API sets a
+            // ratio, raised for requests in a debug session and cut down
+            // under memory pressure.
+            PressureSampler::new(
+                Box::new(DebugSampler::new(Box::new(LiveSampler::new(
+                    base_sampler,
+                    self.sample_ratio,
+                )))),
+                self.memory_pressure.unwrap_or_default(),
+            ),
+            self.switches,
+            self.tail_sampling,
+            self.circuit_breakers.as_ref(),
+            self.shadow_stats.as_ref(),
+        );
+        #[cfg(feature = "traces")]
+        global::set_tracer_provider(tracer_provider.clone());
+
+        #[cfg(not(any(feature = "traces", feature = "logs")))]
+        let _ = self.switches;
+
+        #[cfg(feature = "metrics")]
+        let meter_provider = init_metrics(
+            config,
+            self.stats.clone(),
+            self.metric_readers,
+            signal_resource(config, &self.resource_overrides[Signal::Metrics as usize]),
+            self.circuit_breakers.as_ref(),
+            self.shadow_stats.as_ref(),
+        );
+        #[cfg(feature = "metrics")]
+        global::set_meter_provider(meter_provider.clone());
+
+        TelemetryGuard {
+            #[cfg(feature = "traces")]
+            tracer_provider,
+            #[cfg(feature = "metrics")]
+            meter_provider,
+            #[cfg(feature = "logs")]
+            logger_provider,
+            #[cfg(feature = "logs")]
+            audit_provider,
+            flush_steps: config.flush_steps(),
+            log_filter: LogFilterHandle { reloaders: Arc::new(reloaders) },
+            _file_guard: file_guard,
+        }
+    }
+}
+
+type Reloader = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// A per-layer filter built by `build` from the `RUST_LOG` directives
+/// `level`, rebuilt from new ones by the reloader pushed to `reloaders`.
+fn reloadable<S: 'static>(
+    build: impl Fn(&str) -> EnvFilter + Send + Sync + 'static,
+    level: &str,
+    reloaders: &mut Vec<Reloader>,
+) -> reload::Layer<EnvFilter, S> {
+    let (filter, handle) = reload::Layer::new(build(level));
+    reloaders.push(Box::new(move |level| handle.reload(build(level)).map_err(|e| e.to_string())));
+    filter
+}
+
+/// The filter of the OTLP log bridge: `level` without the crates of the
+/// export path itself, whose events would loop back into it, and without
+/// audit events when they have a stream of their own.
+#[cfg(feature = "logs")]
+fn otel_filter(level: &str, audit_stream: bool) -> EnvFilter {
+    let mut filter = EnvFilter::new(level);
+    if audit_stream {
+        filter = filter.add_directive(format!("{}=off", audit::TARGET).parse().unwrap());
+    }
+    filter
+        .add_directive("hyper=off".parse().unwrap())
+        .add_directive("tonic=off".parse().unwrap())
+        .add_directive("h2=off".parse().unwrap())
+        .add_directive("reqwest=off".parse().unwrap())
+        .add_directive("opentelemetry_sdk=off".parse().unwrap())
+}
+
+/// Replaces the `RUST_LOG` filter of every log output (stdout, `LOG_FILE`,
+/// OTLP and the debug tap); cheap to clone.
+#[derive(Clone, Default)]
+pub struct LogFilterHandle {
+    reloaders: Arc<Vec<Reloader>>,
+}
+
+impl LogFilterHandle {
+    /// Filters with `level` from now on; invalid directives are rejected
+    /// and the current filters kept.
+    pub fn reload(&self, level: &str) -> Result<(), String> {
+        EnvFilter::try_new(level).map_err(|e| e.to_string())?;
+        self.reloaders.iter().try_for_each(|reload| reload(level))
+    }
+}
+
+impl fmt::Debug for LogFilterHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFilterHandle").field("outputs", &self.reloaders.len()).finish()
+    }
+}
+
+/// Owns the installed providers; call `shutdown` before exiting so buffered
+/// telemetry is flushed.
+pub struct TelemetryGuard {
+    #[cfg(feature = "traces")]
+    tracer_provider: SdkTracerProvider,
+    #[cfg(feature = "metrics")]
+    meter_provider: SdkMeterProvider,
+    #[cfg(feature = "logs")]
+    logger_provider: SdkLoggerProvider,
+    /// The audit log stream, flushed and shut down with the other logs.
+    #[cfg(feature = "logs")]
+    audit_provider: Option<SdkLoggerProvider>,
+    /// Order and deadlines of `shutdown`.
+    flush_steps: Vec<FlushStep>,
+    log_filter: LogFilterHandle,
+    /// Flushes the log file writer when dropped.
+    _file_guard: Option<WorkerGuard>,
+}
+
+impl TelemetryGuard {
+    /// Replaces the log filter of the installed outputs, see `config_reload`.
+    pub fn log_filter(&self) -> LogFilterHandle {
+        self.log_filter.clone()
+    }
+
+    /// Exports everything buffered so far without shutting down, e.g. before
+    /// a test asserts on collector output or a short-lived job exits early.
+    /// Every provider is flushed even if an earlier one fails; the first
+    /// error is returned.
+    pub fn force_flush(&self) -> Result<(), OTelSdkError> {
+        let results: [Result<(), OTelSdkError>; 3] = [
+            #[cfg(feature = "traces")]
+            self.tracer_provider.force_flush(),
+            #[cfg(not(feature = "traces"))]
+            Ok(()),
+            #[cfg(feature = "metrics")]
+            self.meter_provider.force_flush(),
+            #[cfg(not(feature = "metrics"))]
+            Ok(()),
+            #[cfg(feature = "logs")]
+            self.logger_provider.force_flush().and(self.audit_provider.as_ref().map_or(Ok(()), SdkLoggerProvider::force_flush)),
+            #[cfg(not(feature = "logs"))]
+            Ok(()),
+        ];
+        results.into_iter().collect()
+    }
+
+    /// Shuts the pipelines down in the configured order, each within its
+    /// deadline. A pipeline that fails or runs out of time doesn't hold up
+    /// the next one; the first error is returned.
+    pub fn shutdown(self) -> Result<(), OTelSdkError> {
+        let mut result = Ok(());
+        for step in &self.flush_steps {
+            let outcome = self.shutdown_within(*step);
+            if let Err(e) = &outcome {
+                warn!(signal = step.signal.as_str(), error = %e, "Telemetry pipeline shutdown failed");
+            }
+            result = result.and(outcome);
+        }
+        result
+    }
+
+    #[cfg_attr(
+        not(any(feature = "traces", feature = "metrics", feature = "logs")),
+        allow(unreachable_code, unused_variables)
+    )]
+    fn shutdown_within(&self, step: FlushStep) -> Result<(), OTelSdkError> {
+        let timeout = step.timeout;
+        let shutdown: Box<dyn FnOnce() -> Result<(), OTelSdkError> + Send> = match step.signal {
+            #[cfg(feature = "traces")]
+            Signal::Traces => {
+                let provider = self.tracer_provider.clone();
+                Box::new(move || provider.shutdown_with_timeout(timeout))
+            }
+            #[cfg(feature = "metrics")]
+            Signal::Metrics => {
+                let provider = self.meter_provider.clone();
+                Box::new(move || provider.shutdown_with_timeout(timeout))
+            }
+            #[cfg(feature = "logs")]
+            Signal::Logs => {
+                let provider = self.logger_provider.clone();
+                let audit_provider = self.audit_provider.clone();
+                Box::new(move || {
+                    // Audit events first: they are the ones that must not be lost.
+                    let audited = audit_provider.map_or(Ok(()), |provider| provider.shutdown_with_timeout(timeout));
+                    provider.shutdown_with_timeout(timeout).and(audited)
+                })
+            }
+            #[allow(unreachable_patterns)]
+            _ => return Ok(()),
+        };
+        // The meter provider ignores its timeout, so the deadline is enforced
+        // here; a flush that overruns it is abandoned to its thread.
+        let (done, outcome) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = done.send(shutdown());
+        });
+        outcome.recv_timeout(timeout).unwrap_or(Err(OTelSdkError::Timeout(timeout)))
+    }
+}
@@ -0,0 +1,324 @@
+//! Per-tenant attribution (`TENANT_SOURCE`).
+//!
+//! A `TenantExtractor` finds the tenant of a request. The built-in ones,
+//! `TenantSource`s, are written:
+//!
+//! - `header:<name>`: the value of that request header;
+//! - `claim:<name>`: that claim of the JWT in the `Authorization: Bearer`
+//!   header, read without verifying the token (application auth does that,
+//!   see `app_auth`);
+//! - `path:<index>`: that segment of the request path, from 0, e.g. `path:1`
+//!   for `/tenants/{tenant}/...`.
+//!
+//! Applications with their own notion of tenant implement the trait and
+//! register `Tenants::new` as app data instead. `attribute` then records
+//! the tenant of every request:
+//!
+//! - in `http_requests_tenant_total{method,route,tenant}` and
+//!   `http_request_duration_tenant_seconds{tenant}`, requests without one as
+//!   `none`. Tenants come from callers, so the label is bounded: with an
+//!   allowlist (`TENANT_ALLOWLIST`) other tenants are `other`, with hash
+//!   buckets (`TENANT_HASH_BUCKETS`) each tenant is one of that many
+//!   `bucket-<n>`, and labels go through the cardinality limiter either way;
+//! - with the `traces` feature, as the `tenant.id` attribute of the server
+//!   span and, through `TenantSpanProcessor`, of every span under it;
+//! - with the `logs` feature, as the `tenant.id` attribute of the OTLP log
+//!   records emitted while handling it, through `TenantLogProcessor`.
+//!
+//! Spans and logs carry the tenant itself rather than its label.
+
+use crate::{
+    config::Config,
+    metrics::AppMetrics,
+    middleware::{route_label, RequestTracking},
+    registry::Registry,
+    sharded::ShardedHistogramVec,
+};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, Error, HttpMessage,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+#[cfg(all(feature = "logs", not(feature = "traces")))]
+use opentelemetry::context::FutureExt;
+#[cfg(feature = "logs")]
+use opentelemetry::{logs::LogRecord as _, InstrumentationScope};
+#[cfg(feature = "traces")]
+use opentelemetry::{
+    trace::{get_active_span, FutureExt, Span as _},
+    KeyValue,
+};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use opentelemetry::Context;
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{LogProcessor, SdkLogRecord};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use opentelemetry_sdk::error::OTelSdkResult;
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use prometheus::{HistogramOpts, IntCounterVec, Opts};
+use serde_json::Value;
+use std::{borrow::Cow, fmt, str::FromStr, time::Instant};
+#[cfg(any(feature = "traces", feature = "logs"))]
+use std::time::Duration;
+
+/// Label of requests without a tenant.
+pub const NO_TENANT: &str = "none";
+
+/// Finds the tenant of a request.
+pub trait TenantExtractor: fmt::Debug + Send + Sync {
+    fn extract(&self, req: &ServiceRequest) -> Option<String>;
+}
+
+/// A built-in extractor, see the module documentation for its syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TenantSource {
+    /// A request header, lowercased.
+    Header(String),
+    /// A claim of the bearer token.
+    Claim(String),
+    /// A path segment.
+    Path(usize),
+}
+
+impl FromStr for TenantSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected `header:<name>`, `claim:<name>` or `path:<index>`, got {s:?}");
+        let (kind, value) = s.trim().split_once(':').ok_or_else(invalid)?;
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(invalid());
+        }
+        match kind.trim() {
+            "header" => Ok(Self::Header(value.to_ascii_lowercase())),
+            "claim" => Ok(Self::Claim(value.to_string())),
+            "path" => value.parse().map(Self::Path).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for TenantSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header(name) => write!(f, "header:{name}"),
+            Self::Claim(name) => write!(f, "claim:{name}"),
+            Self::Path(index) => write!(f, "path:{index}"),
+        }
+    }
+}
+
+impl TenantExtractor for TenantSource {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        let tenant = match self {
+            Self::Header(name) => req.headers().get(name)?.to_str().ok()?.trim().to_string(),
+            Self::Claim(name) => {
+                let authorization = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+                let token = authorization.strip_prefix("Bearer ").or_else(|| authorization.strip_prefix("bearer "))?;
+                let payload = URL_SAFE_NO_PAD.decode(token.trim().split('.').nth(1)?).ok()?;
+                match serde_json::from_slice::<Value>(&payload).ok()?.get(name)? {
+                    Value::String(tenant) => tenant.clone(),
+                    Value::Number(tenant) => tenant.to_string(),
+                    _ => return None,
+                }
+            }
+            Self::Path(index) => req.path().split('/').filter(|segment| !segment.is_empty()).nth(*index)?.to_string(),
+        };
+        (!tenant.is_empty()).then_some(tenant)
+    }
+}
+
+/// How tenants become label values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TenantLabels {
+    /// The tenant itself, bounded only by the cardinality limiter.
+    Raw,
+    /// The listed tenants, `other` for the rest.
+    Allowlist(Vec<String>),
+    /// `bucket-<n>`, `n` the tenant's hash modulo the number of buckets.
+    Hash(u32),
+}
+
+impl TenantLabels {
+    pub fn from_config(config: &Config) -> Self {
+        match config.tenant_hash_buckets {
+            Some(buckets) => Self::Hash(buckets),
+            None if !config.tenant_allowlist.is_empty() => Self::Allowlist(config.tenant_allowlist.clone()),
+            None => Self::Raw,
+        }
+    }
+
+    pub fn label<'a>(&self, tenant: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::Raw => Cow::Borrowed(tenant),
+            Self::Allowlist(tenants) if tenants.iter().any(|allowed| allowed == tenant) => Cow::Borrowed(tenant),
+            Self::Allowlist(_) => Cow::Borrowed("other"),
+            Self::Hash(buckets) => Cow::Owned(format!("bucket-{}", fnv1a(tenant) % u64::from(*buckets))),
+        }
+    }
+}
+
+/// FNV-1a, so a tenant lands in the same bucket on every instance and
+/// release.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// `http_requests_tenant_total` and `http_request_duration_tenant_seconds`.
+#[derive(Clone, Debug)]
+pub struct TenantMetrics {
+    pub requests: IntCounterVec,
+    pub duration: ShardedHistogramVec,
+}
+
+impl TenantMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_tenant_total", "HTTP requests by route template and tenant"),
+            &["method", "route", "tenant"],
+        )?;
+        let duration = ShardedHistogramVec::new(
+            HistogramOpts::new("http_request_duration_tenant_seconds", "HTTP request latency by tenant"),
+            &["tenant"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        Ok(Self { requests, duration })
+    }
+}
+
+/// The extractor and labels, registered as app data.
+#[derive(Debug)]
+pub struct Tenants {
+    extractor: Box<dyn TenantExtractor>,
+    labels: TenantLabels,
+    metrics: TenantMetrics,
+}
+
+impl Tenants {
+    pub fn new(
+        extractor: impl TenantExtractor + 'static,
+        labels: TenantLabels,
+        registry: &Registry,
+    ) -> prometheus::Result<Self> {
+        Ok(Self { extractor: Box::new(extractor), labels, metrics: TenantMetrics::new(registry)? })
+    }
+
+    /// `None` unless `TENANT_SOURCE` is set.
+    pub fn from_config(config: &Config, registry: &Registry) -> prometheus::Result<Option<Self>> {
+        config
+            .tenant_source
+            .clone()
+            .map(|source| Self::new(source, TenantLabels::from_config(config), registry))
+            .transpose()
+    }
+}
+
+/// The tenant of the request being handled, in its extensions and in the
+/// context of its handler.
+#[derive(Clone, Debug)]
+pub struct TenantId(pub String);
+
+impl TenantId {
+    /// The tenant in the current context, if `attribute` found one.
+    #[cfg(any(feature = "traces", feature = "logs"))]
+    pub fn current() -> Option<String> {
+        Context::current().get::<TenantId>().map(|tenant| tenant.0.clone())
+    }
+}
+
+/// Middleware recording the tenant of each request with the `Tenants`
+/// registered as app data, if any. Runs inside `track_requests`, so the
+/// server span is active, and outside the other middleware, so rejected
+/// requests are attributed too.
+pub async fn attribute(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(tenants) = req.app_data::<web::Data<Tenants>>().cloned() else {
+        return next.call(req).await;
+    };
+    let started = Instant::now();
+    let tenant = tenants.extractor.extract(&req);
+    if let Some(tenant) = &tenant {
+        req.extensions_mut().insert(TenantId(tenant.clone()));
+    }
+    let route = route_label(&req);
+    let method = req.method().as_str().to_string();
+    let excluded = req.app_data::<web::Data<RequestTracking>>().is_some_and(|tracking| tracking.is_excluded(&route));
+    let metrics = req.app_data::<web::Data<AppMetrics>>().cloned().filter(|_| !excluded);
+
+    #[cfg(feature = "traces")]
+    if let Some(tenant) = &tenant {
+        get_active_span(|span| span.set_attribute(KeyValue::new("tenant.id", tenant.clone())));
+    }
+    #[cfg(any(feature = "traces", feature = "logs"))]
+    let result = match &tenant {
+        Some(tenant) => next.call(req).with_context(Context::current().with_value(TenantId(tenant.clone()))).await,
+        None => next.call(req).await,
+    };
+    #[cfg(not(any(feature = "traces", feature = "logs")))]
+    let result = next.call(req).await;
+
+    if let Some(metrics) = metrics {
+        let label = tenant.as_deref().map_or(Cow::Borrowed(NO_TENANT), |tenant| tenants.labels.label(tenant));
+        let labels = metrics.cardinality.limit("http_requests_tenant_total", [method.as_str(), route.as_str(), &label]);
+        tenants.metrics.requests.with_label_values(&labels).inc();
+        let labels = metrics.cardinality.limit("http_request_duration_tenant_seconds", [label.as_ref()]);
+        tenants.metrics.duration.with_label_values(&labels).observe(started.elapsed().as_secs_f64());
+    }
+    result
+}
+
+/// Copies the `TenantId` of a span's parent context onto the span as
+/// `tenant.id`.
+#[cfg(feature = "traces")]
+#[derive(Debug)]
+pub struct TenantSpanProcessor;
+
+#[cfg(feature = "traces")]
+impl SpanProcessor for TenantSpanProcessor {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        if let Some(tenant) = cx.get::<TenantId>() {
+            span.set_attribute(KeyValue::new("tenant.id", tenant.0.clone()));
+        }
+    }
+
+    fn on_end(&self, _span: SpanData) {}
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// Adds the current `TenantId` to log records as `tenant.id`; registered
+/// before the exporting processor so the attribute is exported.
+#[cfg(feature = "logs")]
+#[derive(Debug)]
+pub struct TenantLogProcessor;
+
+#[cfg(feature = "logs")]
+impl LogProcessor for TenantLogProcessor {
+    fn emit(&self, record: &mut SdkLogRecord, _instrumentation: &InstrumentationScope) {
+        if let Some(tenant) = Context::current().get::<TenantId>() {
+            record.add_attribute("tenant.id", tenant.0.clone());
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
@@ -0,0 +1,523 @@
+//! In-memory telemetry for tests (`testing` feature).
+//!
+//! `TelemetryTestHarness` builds tracer, logger and meter providers that
+//! export to memory instead of a collector, so instrumentation can be checked
+//! in unit and integration tests without a live collector. Spans and log
+//! records are exported as soon as they end; metrics are collected when read.
+//!
+//! `MockCollector` goes one step further for end-to-end tests: an OTLP/HTTP
+//! receiver on a random local port that decodes and keeps every export
+//! request (protobuf or JSON), so tests can point `OTEL_EXPORTER_OTLP_ENDPOINT`
+//! at it and check what the real exporters put on the wire, resource and
+//! batching included. With the `otlp-grpc` feature `MockCollector::start_grpc`
+//! receives OTLP/gRPC instead. The end-to-end tests in `tests/` run against
+//! both.
+
+use crate::{
+    baggage::BaggageSpanProcessor,
+    config::Config,
+    pipeline::Signal,
+    telemetry,
+    tenant::{TenantLogProcessor, TenantSpanProcessor},
+};
+use actix_web::{dev::ServerHandle, http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use opentelemetry::{global, logs::AnyValue, Value};
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_sdk::{
+    logs::{InMemoryLogExporter, SdkLogRecord, SdkLoggerProvider},
+    metrics::{data::ResourceMetrics, InMemoryMetricExporter, PeriodicReader, SdkMeterProvider},
+    trace::{InMemorySpanExporter, SdkTracerProvider, SpanData},
+};
+use opentelemetry_proto::tonic::{
+    collector::{
+        logs::v1::ExportLogsServiceRequest, metrics::v1::ExportMetricsServiceRequest,
+        trace::v1::ExportTraceServiceRequest,
+    },
+    common::v1::KeyValue,
+    logs::v1::LogRecord,
+    metrics::v1::Metric,
+    trace::v1::Span,
+};
+use prost::Message;
+use std::{
+    io,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing_subscriber::prelude::*;
+
+pub struct TelemetryTestHarness {
+    span_exporter: InMemorySpanExporter,
+    log_exporter: InMemoryLogExporter,
+    metric_exporter: InMemoryMetricExporter,
+    tracer_provider: SdkTracerProvider,
+    logger_provider: SdkLoggerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl TelemetryTestHarness {
+    /// Providers with the resource `TelemetryBuilder` would use for `config`,
+    /// copying its `baggage_keys` onto spans, and with `tenant_source` set the
+    /// tenant onto spans and log records.
+    pub fn new(config: &Config) -> Self {
+        let resource = telemetry::get_resource(config);
+        let span_exporter = InMemorySpanExporter::default();
+        let log_exporter = InMemoryLogExporter::default();
+        let metric_exporter = InMemoryMetricExporter::default();
+        let tracer_provider = SdkTracerProvider::builder();
+        let tracer_provider = if config.baggage_keys.is_empty() {
+            tracer_provider
+        } else {
+            tracer_provider.with_span_processor(BaggageSpanProcessor::new(config.baggage_keys.clone()))
+        };
+        let tracer_provider = match config.tenant_source {
+            Some(_) => tracer_provider.with_span_processor(TenantSpanProcessor),
+            None => tracer_provider,
+        };
+        let tracer_provider = tracer_provider
+            .with_simple_exporter(span_exporter.clone())
+            .with_resource(resource.clone())
+            .build();
+        let logger_provider = SdkLoggerProvider::builder();
+        let logger_provider = match config.tenant_source {
+            Some(_) => logger_provider.with_log_processor(TenantLogProcessor),
+            None => logger_provider,
+        };
+        let logger_provider = logger_provider
+            .with_simple_exporter(log_exporter.clone())
+            .with_resource(resource.clone())
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metric_exporter.clone()).build())
+            .with_resource(resource)
+            .build();
+        Self {
+            span_exporter,
+            log_exporter,
+            metric_exporter,
+            tracer_provider,
+            logger_provider,
+            meter_provider,
+        }
+    }
+
+    /// Makes the providers global, so `global::tracer` and `global::meter`
+    /// report here.
+    pub fn install_global(&self) {
+        global::set_tracer_provider(self.tracer_provider.clone());
+        global::set_meter_provider(self.meter_provider.clone());
+    }
+
+    /// A subscriber bridging `tracing` events to the log exporter, for
+    /// `tracing::subscriber::with_default` or `set_default`.
+    pub fn subscriber(&self) -> impl tracing::Subscriber + Send + Sync + 'static {
+        tracing_subscriber::registry().with(OpenTelemetryTracingBridge::new(&self.logger_provider))
+    }
+
+    pub fn tracer_provider(&self) -> &SdkTracerProvider {
+        &self.tracer_provider
+    }
+
+    pub fn logger_provider(&self) -> &SdkLoggerProvider {
+        &self.logger_provider
+    }
+
+    pub fn meter_provider(&self) -> &SdkMeterProvider {
+        &self.meter_provider
+    }
+
+    /// Every span ended so far.
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.span_exporter.get_finished_spans().unwrap_or_default()
+    }
+
+    pub fn spans_named(&self, name: &str) -> Vec<SpanData> {
+        self.spans().into_iter().filter(|span| span.name == name).collect()
+    }
+
+    /// Every log record emitted so far.
+    pub fn logs(&self) -> Vec<SdkLogRecord> {
+        self.log_exporter
+            .get_emitted_logs()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|log| log.record)
+            .collect()
+    }
+
+    /// Log records whose body contains `needle`.
+    pub fn logs_containing(&self, needle: &str) -> Vec<SdkLogRecord> {
+        self.logs()
+            .into_iter()
+            .filter(|record| matches!(record.body(), Some(AnyValue::String(body)) if body.as_str().contains(needle)))
+            .collect()
+    }
+
+    /// Collects the meter provider and returns everything exported so far.
+    pub fn metrics(&self) -> Vec<ResourceMetrics> {
+        let _ = self.meter_provider.force_flush();
+        self.metric_exporter.get_finished_metrics().unwrap_or_default()
+    }
+
+    /// Names of the exported metrics, deduplicated.
+    pub fn metric_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .metrics()
+            .iter()
+            .flat_map(|resource| resource.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .map(|metric| metric.name().to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The last span named `name`; panics with the names seen otherwise.
+    pub fn assert_span(&self, name: &str) -> SpanData {
+        let spans = self.spans();
+        match spans.iter().rev().find(|span| span.name == name) {
+            Some(span) => span.clone(),
+            None => {
+                let names: Vec<&str> = spans.iter().map(|span| span.name.as_ref()).collect();
+                panic!("no span named {name:?}, got {names:?}")
+            }
+        }
+    }
+
+    /// Asserts that the last span named `name` has `key` set to `value`.
+    pub fn assert_span_attribute(&self, name: &str, key: &str, value: impl Into<Value>) {
+        let span = self.assert_span(name);
+        let value = value.into();
+        let actual = span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| &kv.value);
+        assert_eq!(actual, Some(&value), "attribute {key:?} of span {name:?}");
+    }
+
+    /// Asserts that a log record containing `needle` was emitted.
+    pub fn assert_log(&self, needle: &str) -> SdkLogRecord {
+        self.logs_containing(needle)
+            .pop()
+            .unwrap_or_else(|| panic!("no log record containing {needle:?}"))
+    }
+
+    /// Asserts that a metric named `name` was exported.
+    pub fn assert_metric(&self, name: &str) {
+        let names = self.metric_names();
+        assert!(names.iter().any(|n| n == name), "no metric named {name:?}, got {names:?}");
+    }
+
+    /// Forgets everything exported so far.
+    pub fn reset(&self) {
+        self.span_exporter.reset();
+        self.log_exporter.reset();
+        self.metric_exporter.reset();
+    }
+}
+
+const PROTOBUF: &str = "application/x-protobuf";
+const JSON: &str = "application/json";
+
+/// Export requests received by a `MockCollector`, in arrival order.
+#[derive(Debug, Default)]
+struct Received {
+    traces: Vec<ExportTraceServiceRequest>,
+    logs: Vec<ExportLogsServiceRequest>,
+    metrics: Vec<ExportMetricsServiceRequest>,
+}
+
+/// How a `MockCollector` is stopped.
+enum Stop {
+    Http(ServerHandle),
+    #[cfg(feature = "otlp-grpc")]
+    Grpc(Option<tokio::sync::oneshot::Sender<()>>),
+}
+
+/// OTLP/HTTP or OTLP/gRPC receiver on `127.0.0.1`, running on its own
+/// thread until dropped. Every request is answered with an empty success
+/// response.
+pub struct MockCollector {
+    endpoint: String,
+    received: Arc<Mutex<Received>>,
+    stop: Stop,
+}
+
+async fn receive(
+    signal: web::Path<String>,
+    req: HttpRequest,
+    body: web::Bytes,
+    received: web::Data<Mutex<Received>>,
+) -> HttpResponse {
+    let Some(signal) = Signal::ALL.into_iter().find(|s| s.as_str() == signal.as_str()) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let json = match content_type {
+        Some(ct) if ct.starts_with(PROTOBUF) => false,
+        Some(ct) if ct.starts_with(JSON) => true,
+        _ => {
+            return HttpResponse::UnsupportedMediaType()
+                .body("only application/x-protobuf and application/json are supported");
+        }
+    };
+    let mut received = received.lock().unwrap();
+    let decoded = match signal {
+        Signal::Traces if json => serde_json::from_slice(&body).map(|r| received.traces.push(r)).map_err(|e| e.to_string()),
+        Signal::Traces => ExportTraceServiceRequest::decode(body).map(|r| received.traces.push(r)).map_err(|e| e.to_string()),
+        Signal::Logs if json => serde_json::from_slice(&body).map(|r| received.logs.push(r)).map_err(|e| e.to_string()),
+        Signal::Logs => ExportLogsServiceRequest::decode(body).map(|r| received.logs.push(r)).map_err(|e| e.to_string()),
+        Signal::Metrics if json => {
+            serde_json::from_slice(&body).map(|r| received.metrics.push(r)).map_err(|e| e.to_string())
+        }
+        Signal::Metrics => {
+            ExportMetricsServiceRequest::decode(body).map(|r| received.metrics.push(r)).map_err(|e| e.to_string())
+        }
+    };
+    match decoded {
+        Ok(()) if json => HttpResponse::Ok().content_type(JSON).body("{}"),
+        Ok(()) => HttpResponse::Ok().content_type(PROTOBUF).finish(),
+        Err(e) => HttpResponse::BadRequest().body(format!("invalid OTLP {} payload: {e}", signal.as_str())),
+    }
+}
+
+impl MockCollector {
+    /// Binds a random port and starts serving `POST /v1/{traces,logs,metrics}`.
+    pub fn start() -> io::Result<Self> {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let state = web::Data::from(received.clone());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            actix_web::rt::System::new().block_on(async move {
+                let server = HttpServer::new(move || {
+                    App::new()
+                        .app_data(state.clone())
+                        .app_data(web::PayloadConfig::new(64 * 1024 * 1024))
+                        .route("/v1/{signal}", web::post().to(receive))
+                })
+                .workers(1)
+                .bind(("127.0.0.1", 0));
+                let server = match server {
+                    Ok(server) => server,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                };
+                let addr = server.addrs()[0];
+                let server = server.run();
+                let _ = tx.send(Ok((addr, server.handle())));
+                let _ = server.await;
+            });
+        });
+        let (addr, handle) = rx.recv().map_err(io::Error::other)??;
+        Ok(Self { endpoint: format!("http://{addr}"), received, stop: Stop::Http(handle) })
+    }
+
+    /// Binds a random port and starts serving the OTLP/gRPC trace, logs and
+    /// metrics services, accepting gzip.
+    #[cfg(feature = "otlp-grpc")]
+    pub fn start_grpc() -> io::Result<Self> {
+        use grpc::{GrpcReceiver, LogsServiceServer, MetricsServiceServer, TraceServiceServer};
+        use tonic::codec::CompressionEncoding;
+
+        let received = Arc::new(Mutex::new(Received::default()));
+        let receiver = GrpcReceiver(received.clone());
+        let (tx, rx) = mpsc::channel();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            runtime.block_on(async move {
+                let listener = match tokio::net::TcpListener::bind(("127.0.0.1", 0)).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = tx.send(listener.local_addr());
+                let server = tonic::transport::Server::builder()
+                    .add_service(TraceServiceServer::new(receiver.clone()).accept_compressed(CompressionEncoding::Gzip))
+                    .add_service(LogsServiceServer::new(receiver.clone()).accept_compressed(CompressionEncoding::Gzip))
+                    .add_service(MetricsServiceServer::new(receiver).accept_compressed(CompressionEncoding::Gzip))
+                    .serve_with_incoming_shutdown(
+                        tonic::transport::server::TcpIncoming::from(listener),
+                        async {
+                            let _ = stopped.await;
+                        },
+                    );
+                let _ = server.await;
+            });
+        });
+        let addr = rx.recv().map_err(io::Error::other)??;
+        Ok(Self { endpoint: format!("http://{addr}"), received, stop: Stop::Grpc(Some(stop)) })
+    }
+
+    /// Base URL to use as `OTEL_EXPORTER_OTLP_ENDPOINT` / `Config::otlp_endpoint`.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Every trace export request received so far, one per exported batch.
+    pub fn trace_requests(&self) -> Vec<ExportTraceServiceRequest> {
+        self.received.lock().unwrap().traces.clone()
+    }
+
+    pub fn log_requests(&self) -> Vec<ExportLogsServiceRequest> {
+        self.received.lock().unwrap().logs.clone()
+    }
+
+    pub fn metric_requests(&self) -> Vec<ExportMetricsServiceRequest> {
+        self.received.lock().unwrap().metrics.clone()
+    }
+
+    /// Every span received, across requests and resources.
+    pub fn spans(&self) -> Vec<Span> {
+        let received = self.received.lock().unwrap();
+        received
+            .traces
+            .iter()
+            .flat_map(|request| &request.resource_spans)
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| scope.spans.iter().cloned())
+            .collect()
+    }
+
+    pub fn spans_named(&self, name: &str) -> Vec<Span> {
+        self.spans().into_iter().filter(|span| span.name == name).collect()
+    }
+
+    pub fn log_records(&self) -> Vec<LogRecord> {
+        let received = self.received.lock().unwrap();
+        received
+            .logs
+            .iter()
+            .flat_map(|request| &request.resource_logs)
+            .flat_map(|resource| &resource.scope_logs)
+            .flat_map(|scope| scope.log_records.iter().cloned())
+            .collect()
+    }
+
+    pub fn metrics(&self) -> Vec<Metric> {
+        let received = self.received.lock().unwrap();
+        received
+            .metrics
+            .iter()
+            .flat_map(|request| &request.resource_metrics)
+            .flat_map(|resource| &resource.scope_metrics)
+            .flat_map(|scope| scope.metrics.iter().cloned())
+            .collect()
+    }
+
+    /// Attributes of every resource received for `signal`, one entry per
+    /// resource.
+    pub fn resource_attributes(&self, signal: Signal) -> Vec<Vec<KeyValue>> {
+        let received = self.received.lock().unwrap();
+        let resources: Vec<_> = match signal {
+            Signal::Traces => received.traces.iter().flat_map(|r| &r.resource_spans).map(|r| &r.resource).collect(),
+            Signal::Logs => received.logs.iter().flat_map(|r| &r.resource_logs).map(|r| &r.resource).collect(),
+            Signal::Metrics => received.metrics.iter().flat_map(|r| &r.resource_metrics).map(|r| &r.resource).collect(),
+        };
+        resources
+            .into_iter()
+            .map(|resource| resource.as_ref().map(|r| r.attributes.clone()).unwrap_or_default())
+            .collect()
+    }
+
+    /// Blocks until `done` holds or `timeout` elapses, returning whether it
+    /// held. Exporters batch, so payloads arrive some time after the
+    /// telemetry is produced.
+    pub fn wait_until(&self, timeout: Duration, done: impl Fn(&Self) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if done(self) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Forgets every request received so far.
+    pub fn reset(&self) {
+        *self.received.lock().unwrap() = Received::default();
+    }
+}
+
+impl Drop for MockCollector {
+    fn drop(&mut self) {
+        // The stop command is sent right away; the server winds down on its
+        // own thread.
+        match &mut self.stop {
+            Stop::Http(handle) => drop(handle.stop(false)),
+            #[cfg(feature = "otlp-grpc")]
+            Stop::Grpc(stop) => {
+                if let Some(stop) = stop.take() {
+                    let _ = stop.send(());
+                }
+            }
+        }
+    }
+}
+
+/// The OTLP/gRPC services of `MockCollector::start_grpc`.
+#[cfg(feature = "otlp-grpc")]
+mod grpc {
+    use super::Received;
+    use opentelemetry_proto::tonic::collector::{
+        logs::v1::{logs_service_server::LogsService, ExportLogsServiceRequest, ExportLogsServiceResponse},
+        metrics::v1::{
+            metrics_service_server::MetricsService, ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+        },
+        trace::v1::{trace_service_server::TraceService, ExportTraceServiceRequest, ExportTraceServiceResponse},
+    };
+    pub use opentelemetry_proto::tonic::collector::{
+        logs::v1::logs_service_server::LogsServiceServer, metrics::v1::metrics_service_server::MetricsServiceServer,
+        trace::v1::trace_service_server::TraceServiceServer,
+    };
+    use std::sync::{Arc, Mutex};
+    use tonic::{Request, Response, Status};
+
+    #[derive(Clone)]
+    pub struct GrpcReceiver(pub Arc<Mutex<Received>>);
+
+    #[tonic::async_trait]
+    impl TraceService for GrpcReceiver {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            self.0.lock().unwrap().traces.push(request.into_inner());
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl LogsService for GrpcReceiver {
+        async fn export(
+            &self,
+            request: Request<ExportLogsServiceRequest>,
+        ) -> Result<Response<ExportLogsServiceResponse>, Status> {
+            self.0.lock().unwrap().logs.push(request.into_inner());
+            Ok(Response::new(ExportLogsServiceResponse::default()))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl MetricsService for GrpcReceiver {
+        async fn export(
+            &self,
+            request: Request<ExportMetricsServiceRequest>,
+        ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
+            self.0.lock().unwrap().metrics.push(request.into_inner());
+            Ok(Response::new(ExportMetricsServiceResponse::default()))
+        }
+    }
+}
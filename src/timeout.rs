@@ -0,0 +1,171 @@
+//! Per-route request deadlines (`REQUEST_TIMEOUTS`).
+//!
+//! A rule gives the handlers of a route template a deadline; a request whose
+//! response isn't ready by then has its handler dropped, cancelling it at its
+//! next `.await`, and is answered with the rule's status, `504 Gateway
+//! Timeout` by default or `503 Service Unavailable`. Rules are written
+//! `/route=ms[:status]` and separated by commas; route `*` applies to every
+//! route without a rule of its own. For example `/report=2000,*=10000:503`.
+//!
+//! Timeouts are counted in `http_request_timeouts_total{method,route}` and,
+//! with the `traces` feature, added to the server span as a `timeout` event.
+//! The deadline covers the handler up to its response head; streamed bodies
+//! aren't cut short.
+
+use crate::{
+    cardinality::CardinalityLimiter,
+    config::Config,
+    metrics::AppMetrics,
+    middleware::route_label,
+    registry::Registry,
+    route_rules::{self, RouteRule, RouteRules},
+};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    middleware::Next,
+    web, Error, ResponseError,
+};
+#[cfg(feature = "traces")]
+use opentelemetry::{trace::get_active_span, KeyValue};
+use prometheus::{IntCounterVec, Opts};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// One rule, see the module documentation for its syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestTimeout {
+    /// Route template, or `*` for every route.
+    pub route: String,
+    pub deadline: Duration,
+    /// `503` or `504`.
+    pub status: StatusCode,
+}
+
+impl FromStr for RequestTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("expected `/route=ms[:status]`, got {s:?}");
+        let (route, limit) = route_rules::split(s).ok_or_else(invalid)?;
+        let (ms, status) = match limit.split_once(':') {
+            Some((ms, status)) => (ms, status.trim()),
+            None => (limit, "504"),
+        };
+        let ms: u64 = ms.trim().parse().map_err(|_| invalid())?;
+        let status = match status {
+            "503" => StatusCode::SERVICE_UNAVAILABLE,
+            "504" => StatusCode::GATEWAY_TIMEOUT,
+            _ => return Err(format!("request timeout {s:?}: status must be 503 or 504, got {status:?}")),
+        };
+        if ms == 0 {
+            return Err(format!("request timeout {s:?}: the deadline must be greater than zero"));
+        }
+        Ok(Self { route: route.to_string(), deadline: Duration::from_millis(ms), status })
+    }
+}
+
+impl RouteRule for RequestTimeout {
+    fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+impl fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}:{}", self.route, self.deadline.as_millis(), self.status.as_u16())
+    }
+}
+
+/// `http_request_timeouts_total`.
+#[derive(Clone, Debug)]
+pub struct TimeoutMetrics {
+    pub timeouts: IntCounterVec,
+}
+
+impl TimeoutMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let timeouts = IntCounterVec::new(
+            Opts::new("http_request_timeouts_total", "Requests cancelled for exceeding their route's deadline"),
+            &["method", "route"],
+        )?;
+        registry.register(Box::new(timeouts.clone()))?;
+        Ok(Self { timeouts })
+    }
+
+    fn observe(&self, cardinality: &CardinalityLimiter, method: &str, route: &str) {
+        let labels = cardinality.limit("http_request_timeouts_total", [method, route]);
+        self.timeouts.with_label_values(&labels).inc();
+    }
+}
+
+/// The rules, registered as app data; without rules no request has a
+/// deadline.
+#[derive(Debug)]
+pub struct RequestTimeouts {
+    rules: RouteRules<RequestTimeout>,
+}
+
+impl RequestTimeouts {
+    pub fn new(rules: Vec<RequestTimeout>, endpoint_prefix: &str) -> Self {
+        Self { rules: RouteRules::new(rules, endpoint_prefix) }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.request_timeouts.clone(), &config.endpoint_prefix)
+    }
+}
+
+/// The error a request past its deadline resolves to.
+#[derive(Debug)]
+struct TimedOut {
+    deadline: Duration,
+    status: StatusCode,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request timed out after {}ms", self.deadline.as_millis())
+    }
+}
+
+impl ResponseError for TimedOut {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+}
+
+/// Middleware applying the `RequestTimeouts` registered as app data, if any.
+/// Runs inside `track_requests`, so timeouts are traced and counted like
+/// other errors.
+pub async fn enforce(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let timeouts = req.app_data::<web::Data<RequestTimeouts>>().cloned();
+    let route = route_label(&req);
+    let Some(rule) = timeouts.as_ref().and_then(|timeouts| timeouts.rules.get(&route)).cloned() else {
+        return next.call(req).await;
+    };
+    // The request can't be cloned before it is routed, so take what the
+    // timeout needs now.
+    let metrics = req.app_data::<web::Data<AppMetrics>>().cloned();
+    let method = req.method().clone();
+    match tokio::time::timeout(rule.deadline, next.call(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            if let Some(metrics) = metrics {
+                metrics.timeouts.observe(&metrics.cardinality, method.as_str(), &route);
+            }
+            #[cfg(feature = "traces")]
+            get_active_span(|span| {
+                span.add_event(
+                    "timeout",
+                    vec![
+                        KeyValue::new("timeout.deadline_ms", rule.deadline.as_millis() as i64),
+                        KeyValue::new("timeout.rule", rule.to_string()),
+                    ],
+                );
+            });
+            Err(TimedOut { deadline: rule.deadline, status: rule.status }.into())
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! HTTPS termination with rustls (`tls` feature).
+//!
+//! The certificate is served through `ReloadableCert`, so it can be swapped
+//! on `SIGHUP` (e.g. after cert-manager or certbot renews it) without
+//! restarting the server or dropping connections.
+
+#[cfg(unix)]
+use crate::audit::{ChangeSource, ConfigChanges};
+use rustls::{
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub struct TlsError(String);
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tls: {}", self.0)
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Certificate resolver whose key pair can be replaced at runtime.
+#[derive(Debug)]
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    /// Loads the PEM certificate chain and private key from disk.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, TlsError> {
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    /// SHA-256 fingerprint of the leaf certificate in use, as hex.
+    pub fn fingerprint(&self) -> String {
+        let key = self.current.read().unwrap().clone();
+        let sha256 = ring::cipher_suite::TLS13_AES_128_GCM_SHA256
+            .tls13()
+            .expect("TLS 1.3 suite")
+            .common
+            .hash_provider;
+        sha256.hash(key.cert[0].as_ref()).as_ref().iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Re-reads both files; on error, a key not matching the certificate
+    /// included, the previous certificate stays in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError(format!("reading certificate {}: {e}", cert_path.display())))?;
+    if chain.is_empty() {
+        return Err(TlsError(format!("no certificate found in {}", cert_path.display())));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| TlsError(format!("reading private key {}: {e}", key_path.display())))?;
+    let signing_key = ring::default_provider()
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| TlsError(format!("loading private key {}: {e}", key_path.display())))?;
+    let certified = CertifiedKey::new(chain, signing_key);
+    // Renewals write two files; a reload between the two writes must not
+    // serve a certificate with the other pair's key.
+    certified.keys_match().map_err(|e| {
+        TlsError(format!("{} doesn't match {}: {e}", key_path.display(), cert_path.display()))
+    })?;
+    Ok(certified)
+}
+
+/// Server config that resolves its certificate through `cert`. Actix adds the
+/// HTTP/2 and HTTP/1.1 ALPN protocols when binding.
+pub fn server_config(cert: Arc<ReloadableCert>) -> Result<ServerConfig, TlsError> {
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| TlsError(e.to_string()))?
+        .with_no_client_auth()
+        .with_cert_resolver(cert);
+    Ok(config)
+}
+
+/// Reloads `cert` every time the process receives `SIGHUP`; a new
+/// certificate is recorded in `changes`.
+#[cfg(unix)]
+pub async fn reload_on_sighup(cert: Arc<ReloadableCert>, changes: ConfigChanges) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, TLS certificate reload disabled: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let old = cert.fingerprint();
+        match cert.reload() {
+            Ok(()) => {
+                info!("Reloaded TLS certificate from {}", cert.cert_path.display());
+                let new = cert.fingerprint();
+                if new != old {
+                    changes.record(ChangeSource::Sighup, "tls_certificate_sha256", &old, &new);
+                }
+            }
+            Err(e) => warn!("Keeping previous TLS certificate: {e}"),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! OTLP/HTTP requests over a Unix domain socket (`OTLP_UNIX_SOCKET`).
+//!
+//! The exporters send from their own threads, outside of any Tokio runtime,
+//! so this is blocking HTTP/1.1 like reqwest's blocking client: one
+//! connection per request, closed by the collector after its response. The
+//! request URL only gives the path and `Host` header. As with reqwest, an
+//! error status fails the request.
+
+use opentelemetry_http::{Bytes, HttpError, Request, Response};
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+/// Headers written from the request itself rather than copied.
+const MANAGED_HEADERS: [&str; 3] = ["host", "content-length", "connection"];
+
+/// Sends `request` to the server listening on `path`, each read and write
+/// bounded by `timeout`.
+pub fn send(path: &Path, request: Request<Bytes>, timeout: Duration) -> Result<Response<Bytes>, HttpError> {
+    let mut stream = UnixStream::connect(path).map_err(|e| format!("unix:{}: {e}", path.display()))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let (parts, body) = request.into_parts();
+    let target = parts.uri.path_and_query().map_or("/", |target| target.as_str());
+    let host = parts.uri.authority().map_or("localhost", |authority| authority.as_str());
+    let mut head = format!(
+        "{} {target} HTTP/1.1\r\nhost: {host}\r\ncontent-length: {}\r\nconnection: close\r\n",
+        parts.method,
+        body.len()
+    )
+    .into_bytes();
+    for (name, value) in parts.headers.iter().filter(|(name, _)| !MANAGED_HEADERS.contains(&name.as_str())) {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    stream.write_all(&head)?;
+    stream.write_all(&body)?;
+
+    let response = read_response(&mut BufReader::new(stream))?;
+    if response.status().is_client_error() || response.status().is_server_error() {
+        return Err(format!("HTTP status {} from unix:{}", response.status(), path.display()).into());
+    }
+    Ok(response)
+}
+
+fn read_response(reader: &mut impl BufRead) -> Result<Response<Bytes>, HttpError> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("malformed status line {:?}", line.trim_end()))?;
+    let mut response = Response::builder().status(status);
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err("connection closed within the response headers".into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| format!("malformed header {header:?}"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<usize>()?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+        response = response.header(name, value);
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size.trim(), 16).map_err(|_| format!("malformed chunk size {size:?}"))?;
+            // The trailers, if any, are left unread with the connection.
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(length) = content_length {
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+    Ok(response.body(Bytes::from(body))?)
+}
@@ -0,0 +1,119 @@
+//! Sends requests to `/` through `track_requests` with the telemetry
+//! exporting to a `MockCollector`, and checks what arrived on the wire.
+
+use actix_web::{middleware::from_fn, rt::task, test, web, App, HttpResponse};
+use opentelemetry_proto::tonic::{
+    common::v1::{any_value, AnyValue, KeyValue},
+    trace::v1::span::SpanKind,
+};
+use prom_otel::{
+    config::Config,
+    metrics::AppMetrics,
+    middleware::{self, RequestTracking},
+    otlp_exporter::OtlpProtocol,
+    pipeline::Signal,
+    telemetry::TelemetryBuilder,
+    testing::MockCollector,
+};
+use std::time::Duration;
+
+const SERVICE_NAME: &str = "otlp-end-to-end";
+const REQUESTS: usize = 3;
+const WAIT: Duration = Duration::from_secs(10);
+
+async fn index() -> HttpResponse {
+    tracing::info!("Serving the index");
+    HttpResponse::Ok().body("Hello")
+}
+
+fn string(value: Option<&AnyValue>) -> Option<&str> {
+    match value?.value.as_ref()? {
+        any_value::Value::StringValue(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a str> {
+    string(attributes.iter().find(|kv| kv.key == key)?.value.as_ref())
+}
+
+/// Exports over `protocol` to `collector` and asserts that the spans, logs
+/// and metrics of `REQUESTS` requests to `/` arrive, each signal in a
+/// single batch with the service's resource.
+pub async fn requests_arrive(collector: MockCollector, protocol: OtlpProtocol) {
+    let mut config = Config {
+        otlp_endpoint: collector.endpoint().to_string(),
+        service_name: SERVICE_NAME.to_string(),
+        metrics_native_histograms: true,
+        ..Config::default()
+    };
+    for signal in Signal::ALL {
+        config.exporter_mut(signal).protocol = protocol;
+    }
+    // Nothing is exported before the flush, so each signal is sent as one
+    // batch.
+    config.trace_batch.scheduled_delay = Duration::from_secs(3600);
+    config.log_batch.scheduled_delay = Duration::from_secs(3600);
+    config.metric_export_interval = Duration::from_secs(3600);
+    let metrics = web::Data::new(AppMetrics::from_config(&config));
+    let telemetry = TelemetryBuilder::new(&config, metrics.pipeline.clone()).init();
+    // After `init`, so the request duration histogram gets the global meter.
+    let tracking = web::Data::new(RequestTracking::from_config(&config));
+    let app = test::init_service(
+        App::new()
+        .wrap(from_fn(middleware::track_requests))
+        .app_data(metrics.clone())
+        .app_data(tracking)
+        .route("/", web::get().to(index)),
+    )
+    .await;
+
+    for _ in 0..REQUESTS {
+        let response = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert!(response.status().is_success());
+    }
+    // Off the runtime thread: the gRPC exporter's channel runs on it.
+    let telemetry = task::spawn_blocking(move || {
+        telemetry.force_flush().unwrap();
+        telemetry
+    })
+    .await
+    .unwrap();
+
+    assert!(collector.wait_until(WAIT, |c| c.spans_named("GET /").len() == REQUESTS), "spans: {:?}", collector.spans());
+    let spans = collector.spans_named("GET /");
+    for span in &spans {
+        assert_eq!(span.kind, SpanKind::Server as i32);
+        assert_eq!(attribute(&span.attributes, "http.route"), Some("/"));
+        assert_eq!(attribute(&span.attributes, "http.request.method"), Some("GET"));
+    }
+
+    assert!(collector.wait_until(WAIT, |c| {
+        c.log_records().iter().filter(|r| string(r.body.as_ref()) == Some("Serving the index")).count() == REQUESTS
+    }));
+    // Each log record belongs to the trace of its request.
+    for record in collector.log_records().iter().filter(|r| string(r.body.as_ref()) == Some("Serving the index")) {
+        assert!(spans.iter().any(|span| span.trace_id == record.trace_id), "log outside the request spans");
+    }
+
+    assert!(collector.wait_until(WAIT, |c| c.metrics().iter().any(|m| m.name == "http.server.request.duration")));
+
+    for signal in Signal::ALL {
+        let resources = collector.resource_attributes(signal);
+        assert!(!resources.is_empty(), "no {} resource", signal.as_str());
+        for attributes in resources {
+            assert_eq!(attribute(&attributes, "service.name"), Some(SERVICE_NAME));
+        }
+    }
+    let batches = collector
+        .trace_requests()
+        .iter()
+        .filter(|request| {
+            let mut spans = request.resource_spans.iter().flat_map(|r| &r.scope_spans).flat_map(|s| &s.spans);
+            spans.any(|span| span.name == "GET /")
+        })
+        .count();
+    assert_eq!(batches, 1, "the request spans were sent in {batches} batches");
+
+    task::spawn_blocking(move || telemetry.shutdown()).await.unwrap().unwrap();
+}
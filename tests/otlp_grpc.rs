@@ -0,0 +1,10 @@
+//! End to end over OTLP/gRPC.
+
+mod common;
+
+use prom_otel::{otlp_exporter::OtlpProtocol, testing::MockCollector};
+
+#[actix_web::test]
+async fn requests_arrive_over_grpc() {
+    common::requests_arrive(MockCollector::start_grpc().unwrap(), OtlpProtocol::Grpc).await;
+}